@@ -0,0 +1,113 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The interface between the kernel and its drivers
+//!
+//! Drivers live in their own crates and describe themselves with
+//! [`register_driver!`], which places a [`Driver`] in the `.driver_registry`
+//! linker section. The kernel's linker scripts gather that section into one
+//! array, so the kernel can find every driver it was built with without a
+//! central list of them.
+
+#![no_std]
+
+/// Devices which can be matched against the `compatible` property of a device
+/// tree node
+pub trait CompatibleWith {
+    fn compatible_with() -> &'static [&'static str];
+}
+
+/// A device which can be used as the kernel console
+pub trait ConsoleDevice: 'static {
+    fn init(&mut self);
+    fn read(&self) -> u8;
+    fn write(&mut self, n: u8);
+}
+
+impl core::fmt::Write for dyn ConsoleDevice {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.as_bytes() {
+            self.write(*byte);
+        }
+
+        Ok(())
+    }
+}
+
+/// A device whose state has to be saved before the system suspends to RAM and
+/// restored once it wakes up, since it may lose power in between
+pub trait SuspendResume: Send {
+    /// Save the device's state and quiesce it. The system stays awake if this
+    /// fails, and every device suspended before this one is resumed again.
+    fn suspend(&mut self) -> Result<(), &'static str>;
+    /// Restore the state saved by the last call to [`SuspendResume::suspend`]
+    fn resume(&mut self);
+}
+
+/// What a driver is able to do with its device
+#[derive(Clone, Copy)]
+pub enum DriverKind {
+    /// Create a console device from the virtual address of the device's
+    /// registers. The address must be a valid mapping of a device this driver
+    /// is compatible with, and the returned device must not be used outside of
+    /// the kernel console.
+    Console(unsafe fn(*mut u8) -> &'static mut dyn ConsoleDevice),
+}
+
+#[derive(Clone, Copy)]
+pub struct Driver {
+    pub name: &'static str,
+    pub compatible: &'static [&'static str],
+    pub kind: DriverKind,
+}
+
+impl Driver {
+    pub fn is_compatible<'a>(&self, mut compatible: impl Iterator<Item = &'a str>) -> bool {
+        compatible.any(|c| self.compatible.contains(&c))
+    }
+}
+
+impl core::fmt::Debug for Driver {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Driver").field("name", &self.name).field("compatible", &self.compatible).finish()
+    }
+}
+
+/// Add a driver to the registry. The driver's crate also needs to be referenced
+/// by the kernel, since otherwise nothing causes it to be linked in.
+#[macro_export]
+macro_rules! register_driver {
+    ($(#[$attr:meta])* $name:ident = $driver:expr) => {
+        $(#[$attr])*
+        #[used]
+        #[link_section = ".driver_registry"]
+        pub static $name: $crate::Driver = $driver;
+    };
+}
+
+/// Every driver the kernel was built with
+pub fn drivers() -> &'static [Driver] {
+    extern "C" {
+        static __driver_registry_start: u8;
+        static __driver_registry_end: u8;
+    }
+
+    // SAFETY: the linker scripts place these symbols around the
+    // `.driver_registry` section, which only contains `Driver`s
+    unsafe {
+        let start = core::ptr::addr_of!(__driver_registry_start).cast::<Driver>();
+        let end = core::ptr::addr_of!(__driver_registry_end).cast::<Driver>();
+
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// The first registered driver which is compatible with one of the entries in
+/// `compatible`
+pub fn find_compatible<'a>(compatible: impl Iterator<Item = &'a str> + Clone) -> Option<&'static Driver> {
+    drivers().iter().find(|driver| driver.is_compatible(compatible.clone()))
+}
@@ -5,7 +5,17 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
-use crate::{drivers::CompatibleWith, io::ConsoleDevice};
+#![no_std]
+
+use driver_registry::{CompatibleWith, ConsoleDevice, Driver, DriverKind};
+
+driver_registry::register_driver! {
+    DRIVER = Driver { name: "sifive_uart", compatible: &["sifive,uart0"], kind: DriverKind::Console(console) }
+}
+
+unsafe fn console(registers: *mut u8) -> &'static mut dyn ConsoleDevice {
+    &mut *registers.cast::<SifiveUart>()
+}
 
 #[derive(Debug)]
 #[repr(C)]
@@ -69,7 +79,7 @@ impl ConsoleDevice for SifiveUart {
 
 impl CompatibleWith for SifiveUart {
     fn compatible_with() -> &'static [&'static str] {
-        &["sifive,uart0"]
+        DRIVER.compatible
     }
 }
 
@@ -5,9 +5,19 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
-use crate::drivers::CompatibleWith;
+#![no_std]
+
+use driver_registry::{CompatibleWith, ConsoleDevice, Driver, DriverKind};
 use volatile::Volatile;
 
+driver_registry::register_driver! {
+    DRIVER = Driver { name: "uart16550", compatible: &["ns16550", "ns16550a"], kind: DriverKind::Console(console) }
+}
+
+unsafe fn console(registers: *mut u8) -> &'static mut dyn ConsoleDevice {
+    &mut *registers.cast::<Uart16550>()
+}
+
 #[repr(C)]
 pub struct Uart16550 {
     data_register: Volatile<u8>,
@@ -93,7 +103,7 @@ impl core::fmt::Write for Uart16550 {
     }
 }
 
-impl crate::io::ConsoleDevice for Uart16550 {
+impl ConsoleDevice for Uart16550 {
     fn init(&mut self) {
         (&*self).init();
     }
@@ -109,6 +119,6 @@ impl crate::io::ConsoleDevice for Uart16550 {
 
 impl CompatibleWith for Uart16550 {
     fn compatible_with() -> &'static [&'static str] {
-        &["ns16550", "ns16550a"]
+        DRIVER.compatible
     }
 }
@@ -0,0 +1,109 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks the kernel's features make sense together before anything is built,
+//! and writes out a report of what it's being built with
+//!
+//! Features which can't be used together fail the build here with an error
+//! saying why, rather than with whatever the compiler makes of the missing or
+//! duplicated items later on. The report ends up next to the kernel binary as
+//! `vanadinite.config`.
+
+use std::{collections::BTreeSet, fmt::Write, path::PathBuf};
+
+/// Features where exactly one of each group has to be enabled
+const EXACTLY_ONE: &[&[&str]] = &[&["platform.virt", "platform.sifive_u"]];
+
+/// Features which can't be enabled at the same time
+const CONFLICTS: &[(&str, &str, &str)] =
+    &[("pmalloc.allocator.bitmap", "pmalloc.allocator.buddy", "only one physical memory allocator can be used")];
+
+/// Features which only work if the `rustflags` the kernel is built with
+/// contain the given flag
+const NEEDS_RUSTFLAG: &[(&str, &str)] = &[("hardening.shadow_call_stack", "-Zsanitizer=shadow-call-stack")];
+
+fn main() {
+    let features = enabled_features();
+    let rustflags = std::env::var("CARGO_ENCODED_RUSTFLAGS").unwrap_or_default();
+    let rustflags = rustflags.split('\x1f').collect::<Vec<_>>();
+
+    let mut errors = Vec::new();
+
+    for group in EXACTLY_ONE {
+        let enabled = group.iter().filter(|&&feature| features.contains(feature)).collect::<Vec<_>>();
+        if enabled.len() != 1 {
+            errors.push(format!("exactly one of {:?} has to be enabled, but found {:?}", group, enabled));
+        }
+    }
+
+    for &(a, b, why) in CONFLICTS {
+        if features.contains(a) && features.contains(b) {
+            errors.push(format!("`{}` and `{}` can't be enabled together: {}", a, b, why));
+        }
+    }
+
+    for &(feature, flag) in NEEDS_RUSTFLAG {
+        if features.contains(feature) && !rustflags.contains(&flag) {
+            errors.push(format!("`{}` needs the kernel to be built with `{}`", feature, flag));
+        }
+    }
+
+    if !errors.is_empty() {
+        for error in &errors {
+            eprintln!("error: {}", error);
+        }
+
+        panic!("invalid kernel configuration");
+    }
+
+    let mut report = String::new();
+    let _ = writeln!(report, "profile: {}", std::env::var("PROFILE").unwrap_or_default());
+    let _ = writeln!(report, "target: {}", std::env::var("TARGET").unwrap_or_default());
+    for feature in &features {
+        let _ = writeln!(report, "feature: {}", feature);
+    }
+
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    std::fs::write(out_dir.join("vanadinite.config"), &report).unwrap();
+
+    // `OUT_DIR` is `target/<triple>/<profile>/build/vanadinite-<hash>/out`
+    if let Some(profile_dir) = out_dir.ancestors().nth(3) {
+        let _ = std::fs::write(profile_dir.join("vanadinite.config"), &report);
+    }
+
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=CARGO_ENCODED_RUSTFLAGS");
+}
+
+/// Cargo only hands features to build scripts as `CARGO_FEATURE_*` variables
+/// with everything but letters and numbers replaced, so they're matched back up
+/// with the names in `Cargo.toml`
+fn enabled_features() -> BTreeSet<&'static str> {
+    const KNOWN: &[&str] = &[
+        "debug.replay",
+        "driver.dw_wdt",
+        "driver.riscv_iommu",
+        "driver.sifive_uart",
+        "driver.syscon",
+        "driver.uart16550",
+        "hardening.shadow_call_stack",
+        "platform.sifive_u",
+        "platform.virt",
+        "pmalloc.allocator.bitmap",
+        "pmalloc.allocator.buddy",
+        "vmalloc.allocator.freelist",
+    ];
+
+    let mangle = |name: &str| {
+        name.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' }).collect::<String>()
+    };
+
+    let enabled =
+        std::env::vars().filter_map(|(key, _)| key.strip_prefix("CARGO_FEATURE_").map(mangle)).collect::<BTreeSet<_>>();
+
+    KNOWN.iter().copied().filter(|&feature| enabled.contains(&mangle(feature))).collect()
+}
@@ -23,3 +23,11 @@ pub fn ra() -> *mut u8 {
     unsafe { core::arch::asm!("mv {}, ra", out(reg) ra) };
     ra as *mut u8
 }
+
+/// Always inlined, otherwise this would return its own frame pointer
+#[inline(always)]
+pub fn fp() -> *mut u8 {
+    let fp: usize;
+    unsafe { core::arch::asm!("mv {}, s0", out(reg) fp) };
+    fp as *mut u8
+}
@@ -0,0 +1,398 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checking the device tree blob the bootloader handed over before anything
+//! reads it
+//!
+//! The `fdt` crate believes whatever the header says, so a blob that was
+//! truncated or scribbled on by a flaky bootloader turns into reads well past
+//! the end of it the first time a node is looked up. Everything the crate will
+//! walk is bounds checked here first: the header's offsets and sizes, the
+//! memory reservation block, and every token of the structure block, so a bad
+//! blob stops the boot with a reason instead.
+
+use core::fmt;
+
+const FDT_MAGIC: u32 = 0xD00D_FEED;
+const HEADER_SIZE: usize = 40;
+/// Far bigger than any real device tree, but small enough that a corrupted
+/// size can't have everything after the blob treated as part of it
+const MAX_TOTAL_SIZE: usize = 16 * 1024 * 1024;
+/// Real device trees are rarely more than a handful of nodes deep
+const MAX_DEPTH: usize = 64;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+/// Something wrong with a device tree blob, with offsets from the start of it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FdtError {
+    BadMagic(u32),
+    BadTotalSize(usize),
+    /// Versions before 17 don't have the block sizes needed to check them
+    UnsupportedVersion(u32),
+    /// The named block doesn't fit inside the blob, or isn't aligned
+    BadBlock(&'static str),
+    UnterminatedReservations,
+    BadToken {
+        offset: usize,
+        token: u32,
+    },
+    UnterminatedName {
+        offset: usize,
+    },
+    BadPropertyName {
+        offset: usize,
+    },
+    PropertyOutOfBounds {
+        offset: usize,
+    },
+    TooDeep {
+        offset: usize,
+    },
+    UnbalancedNodes {
+        offset: usize,
+    },
+    MissingEnd,
+}
+
+impl fmt::Display for FdtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bad device tree: ")?;
+        match self {
+            FdtError::BadMagic(magic) => write!(f, "bad magic {:#x}", magic),
+            FdtError::BadTotalSize(size) => write!(f, "bad total size {:#x}", size),
+            FdtError::UnsupportedVersion(version) => write!(f, "unsupported version {}", version),
+            FdtError::BadBlock(block) => write!(f, "{} block is out of bounds or misaligned", block),
+            FdtError::UnterminatedReservations => write!(f, "memory reservations aren't terminated"),
+            FdtError::BadToken { offset, token } => write!(f, "unexpected token {:#x} at {:#x}", token, offset),
+            FdtError::UnterminatedName { offset } => write!(f, "node name at {:#x} isn't terminated", offset),
+            FdtError::BadPropertyName { offset } => write!(f, "property at {:#x} has a bad name offset", offset),
+            FdtError::PropertyOutOfBounds { offset } => write!(f, "property at {:#x} runs out of bounds", offset),
+            FdtError::TooDeep { offset } => write!(f, "nodes nested too deeply at {:#x}", offset),
+            FdtError::UnbalancedNodes { offset } => write!(f, "unbalanced nodes at {:#x}", offset),
+            FdtError::MissingEnd => write!(f, "structure block isn't terminated"),
+        }
+    }
+}
+
+/// Check that the device tree blob at `fdt` can be read without running off
+/// the end of it, returning its total size
+///
+/// # Safety
+///
+/// `fdt` must point to at least the size of a device tree header of readable
+/// memory, and as much as the header says the blob is if it's sane
+pub unsafe fn validate(fdt: *const u8) -> Result<usize, FdtError> {
+    let header = core::slice::from_raw_parts(fdt, HEADER_SIZE);
+    let field = |index: usize| be32(header, index * 4).unwrap_or(0);
+
+    if field(0) != FDT_MAGIC {
+        return Err(FdtError::BadMagic(field(0)));
+    }
+
+    let total_size = field(1) as usize;
+    if !(HEADER_SIZE..=MAX_TOTAL_SIZE).contains(&total_size) {
+        return Err(FdtError::BadTotalSize(total_size));
+    }
+
+    if field(5) < 17 {
+        return Err(FdtError::UnsupportedVersion(field(5)));
+    }
+
+    let blob = core::slice::from_raw_parts(fdt, total_size);
+    let (struct_offset, strings_offset, reservations_offset) =
+        (field(2) as usize, field(3) as usize, field(4) as usize);
+    let (strings_size, struct_size) = (field(8) as usize, field(9) as usize);
+
+    let structure = block(blob, struct_offset, struct_size, 4).ok_or(FdtError::BadBlock("structure"))?;
+    let strings = block(blob, strings_offset, strings_size, 1).ok_or(FdtError::BadBlock("strings"))?;
+    let reservations = block(blob, reservations_offset, total_size - reservations_offset.min(total_size), 8)
+        .ok_or(FdtError::BadBlock("memory reservation"))?;
+
+    // Each reservation is an address and a size, ending with both being zero
+    let terminated = reservations.chunks_exact(16).any(|entry| entry.iter().all(|&byte| byte == 0));
+    if !terminated {
+        return Err(FdtError::UnterminatedReservations);
+    }
+
+    validate_structure(structure, strings, struct_offset)?;
+
+    Ok(total_size)
+}
+
+/// Walk every token in the structure block, with `base` being where it starts
+/// in the blob for reporting errors
+fn validate_structure(structure: &[u8], strings: &[u8], base: usize) -> Result<(), FdtError> {
+    let mut offset = 0;
+    let mut depth = 0;
+    let mut seen_root = false;
+
+    // Every token is at least 4 bytes, so this always makes progress
+    while let Some(token) = be32(structure, offset) {
+        let at = base + offset;
+        offset += 4;
+
+        match token {
+            FDT_BEGIN_NODE => {
+                // There's only one root node
+                if depth == 0 && seen_root {
+                    return Err(FdtError::UnbalancedNodes { offset: at });
+                }
+
+                seen_root = true;
+                depth += 1;
+                if depth > MAX_DEPTH {
+                    return Err(FdtError::TooDeep { offset: at });
+                }
+
+                let name_len = structure[offset..]
+                    .iter()
+                    .position(|&byte| byte == 0)
+                    .ok_or(FdtError::UnterminatedName { offset: at })?;
+                offset = align4(offset + name_len + 1);
+            }
+            FDT_END_NODE => match depth {
+                0 => return Err(FdtError::UnbalancedNodes { offset: at }),
+                _ => depth -= 1,
+            },
+            FDT_PROP if depth > 0 => {
+                let (len, name_offset) = match (be32(structure, offset), be32(structure, offset + 4)) {
+                    (Some(len), Some(name_offset)) => (len as usize, name_offset as usize),
+                    _ => return Err(FdtError::PropertyOutOfBounds { offset: at }),
+                };
+
+                let name_terminated = strings.get(name_offset..).map_or(false, |name| name.contains(&0));
+                if !name_terminated {
+                    return Err(FdtError::BadPropertyName { offset: at });
+                }
+
+                offset += 8;
+                match offset.checked_add(len) {
+                    Some(end) if end <= structure.len() => offset = align4(end),
+                    _ => return Err(FdtError::PropertyOutOfBounds { offset: at }),
+                }
+            }
+            FDT_NOP => {}
+            FDT_END if depth == 0 && seen_root => return Ok(()),
+            FDT_END => return Err(FdtError::UnbalancedNodes { offset: at }),
+            token => return Err(FdtError::BadToken { offset: at, token }),
+        }
+    }
+
+    Err(FdtError::MissingEnd)
+}
+
+/// The `size` bytes at `offset` in `blob`, if they're in bounds and `offset` is
+/// aligned to `align`
+fn block(blob: &[u8], offset: usize, size: usize, align: usize) -> Option<&[u8]> {
+    if offset % align != 0 {
+        return None;
+    }
+
+    blob.get(offset..offset.checked_add(size)?)
+}
+
+fn be32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    const RESERVATIONS_OFFSET: usize = HEADER_SIZE;
+
+    fn words(tokens: &[u32]) -> Vec<u8> {
+        tokens.iter().flat_map(|token| token.to_be_bytes()).collect()
+    }
+
+    /// A version 17 blob with an empty memory reservation block followed by
+    /// `structure` and `strings`
+    fn blob(structure: &[u8], strings: &[u8]) -> Vec<u8> {
+        let struct_offset = RESERVATIONS_OFFSET + 16;
+        let strings_offset = struct_offset + structure.len();
+        let total_size = strings_offset + strings.len();
+
+        let header = [
+            FDT_MAGIC,
+            total_size as u32,
+            struct_offset as u32,
+            strings_offset as u32,
+            RESERVATIONS_OFFSET as u32,
+            17,
+            16,
+            0,
+            strings.len() as u32,
+            structure.len() as u32,
+        ];
+
+        let mut blob = words(&header);
+        blob.extend_from_slice(&[0; 16]);
+        blob.extend_from_slice(structure);
+        blob.extend_from_slice(strings);
+        blob
+    }
+
+    fn set_field(blob: &mut [u8], index: usize, value: u32) {
+        blob[index * 4..index * 4 + 4].copy_from_slice(&value.to_be_bytes());
+    }
+
+    fn minimal() -> Vec<u8> {
+        blob(&words(&[FDT_BEGIN_NODE, 0, FDT_END_NODE, FDT_END]), b"")
+    }
+
+    fn validate_blob(blob: &[u8]) -> Result<usize, FdtError> {
+        unsafe { validate(blob.as_ptr()) }
+    }
+
+    #[test]
+    fn accepts_a_minimal_blob() {
+        let blob = minimal();
+        assert_eq!(validate_blob(&blob), Ok(blob.len()));
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut blob = minimal();
+        set_field(&mut blob, 0, 0xFEED_D00D);
+        assert_eq!(validate_blob(&blob), Err(FdtError::BadMagic(0xFEED_D00D)));
+    }
+
+    #[test]
+    fn rejects_old_versions() {
+        let mut blob = minimal();
+        set_field(&mut blob, 5, 16);
+        assert_eq!(validate_blob(&blob), Err(FdtError::UnsupportedVersion(16)));
+    }
+
+    #[test]
+    fn rejects_truncated_blobs() {
+        // Smaller than its own header
+        let mut blob = minimal();
+        set_field(&mut blob, 1, HEADER_SIZE as u32 - 1);
+        assert_eq!(validate_blob(&blob), Err(FdtError::BadTotalSize(HEADER_SIZE - 1)));
+
+        // Cut off partway through the structure block
+        let mut blob = minimal();
+        let truncated = blob.len() - 4;
+        set_field(&mut blob, 1, truncated as u32);
+        assert_eq!(validate_blob(&blob), Err(FdtError::BadBlock("structure")));
+
+        // Strings which run past the end
+        let mut blob = minimal();
+        set_field(&mut blob, 8, 1);
+        assert_eq!(validate_blob(&blob), Err(FdtError::BadBlock("strings")));
+    }
+
+    #[test]
+    fn rejects_oversized_blobs() {
+        let mut blob = minimal();
+        set_field(&mut blob, 1, MAX_TOTAL_SIZE as u32 + 1);
+        assert_eq!(validate_blob(&blob), Err(FdtError::BadTotalSize(MAX_TOTAL_SIZE + 1)));
+    }
+
+    #[test]
+    fn rejects_misaligned_blocks() {
+        let mut blob = minimal();
+        set_field(&mut blob, 2, RESERVATIONS_OFFSET as u32 + 18);
+        set_field(&mut blob, 9, 0);
+        assert_eq!(validate_blob(&blob), Err(FdtError::BadBlock("structure")));
+
+        let mut blob = minimal();
+        set_field(&mut blob, 4, RESERVATIONS_OFFSET as u32 + 4);
+        assert_eq!(validate_blob(&blob), Err(FdtError::BadBlock("memory reservation")));
+    }
+
+    #[test]
+    fn rejects_unterminated_reservations() {
+        let mut blob = minimal();
+        blob[RESERVATIONS_OFFSET..RESERVATIONS_OFFSET + 16].fill(0xFF);
+        assert_eq!(validate_blob(&blob), Err(FdtError::UnterminatedReservations));
+    }
+
+    #[test]
+    fn rejects_unterminated_node_names() {
+        let structure = words(&[FDT_BEGIN_NODE, u32::from_be_bytes(*b"name")]);
+        assert_eq!(validate_structure(&structure, b"", 0), Err(FdtError::UnterminatedName { offset: 0 }));
+    }
+
+    #[test]
+    fn rejects_deeply_nested_nodes() {
+        let mut tokens = Vec::new();
+        for _ in 0..=MAX_DEPTH {
+            tokens.extend_from_slice(&[FDT_BEGIN_NODE, 0]);
+        }
+
+        let structure = words(&tokens);
+        assert_eq!(validate_structure(&structure, b"", 0), Err(FdtError::TooDeep { offset: MAX_DEPTH * 8 }));
+    }
+
+    #[test]
+    fn rejects_properties_running_past_the_block() {
+        let structure = words(&[FDT_BEGIN_NODE, 0, FDT_PROP, u32::MAX, 0, FDT_END_NODE, FDT_END]);
+        assert_eq!(validate_structure(&structure, b"a\0", 0), Err(FdtError::PropertyOutOfBounds { offset: 8 }));
+
+        // The length and name offset themselves are cut off
+        let structure = words(&[FDT_BEGIN_NODE, 0, FDT_PROP, 4]);
+        assert_eq!(validate_structure(&structure, b"a\0", 0), Err(FdtError::PropertyOutOfBounds { offset: 8 }));
+    }
+
+    #[test]
+    fn rejects_bad_property_names() {
+        let structure = words(&[FDT_BEGIN_NODE, 0, FDT_PROP, 0, 2, FDT_END_NODE, FDT_END]);
+        assert_eq!(validate_structure(&structure, b"a\0", 0), Err(FdtError::BadPropertyName { offset: 8 }));
+
+        let structure = words(&[FDT_BEGIN_NODE, 0, FDT_PROP, 0, 0, FDT_END_NODE, FDT_END]);
+        assert_eq!(validate_structure(&structure, b"abc", 0), Err(FdtError::BadPropertyName { offset: 8 }));
+    }
+
+    #[test]
+    fn rejects_misplaced_tokens() {
+        // Properties outside of any node
+        let structure = words(&[FDT_PROP, 0, 0, FDT_END]);
+        assert_eq!(validate_structure(&structure, b"a\0", 0), Err(FdtError::BadToken { offset: 0, token: FDT_PROP }));
+
+        let structure = words(&[FDT_BEGIN_NODE, 0, 0x7, FDT_END_NODE, FDT_END]);
+        assert_eq!(validate_structure(&structure, b"", 0), Err(FdtError::BadToken { offset: 8, token: 0x7 }));
+    }
+
+    #[test]
+    fn rejects_unbalanced_nodes() {
+        let structure = words(&[FDT_BEGIN_NODE, 0, FDT_END_NODE, FDT_END_NODE, FDT_END]);
+        assert_eq!(validate_structure(&structure, b"", 0), Err(FdtError::UnbalancedNodes { offset: 12 }));
+
+        // A second root node
+        let structure = words(&[FDT_BEGIN_NODE, 0, FDT_END_NODE, FDT_BEGIN_NODE, 0, FDT_END_NODE, FDT_END]);
+        assert_eq!(validate_structure(&structure, b"", 0), Err(FdtError::UnbalancedNodes { offset: 12 }));
+
+        // Ending before the root node is closed
+        let structure = words(&[FDT_BEGIN_NODE, 0, FDT_END]);
+        assert_eq!(validate_structure(&structure, b"", 0), Err(FdtError::UnbalancedNodes { offset: 8 }));
+    }
+
+    #[test]
+    fn rejects_missing_end() {
+        let structure = words(&[FDT_BEGIN_NODE, 0, FDT_END_NODE]);
+        assert_eq!(validate_structure(&structure, b"", 0), Err(FdtError::MissingEnd));
+    }
+
+    #[test]
+    fn reports_offsets_from_the_start_of_the_blob() {
+        let structure = words(&[FDT_BEGIN_NODE, 0, FDT_END_NODE, FDT_END_NODE]);
+        assert_eq!(validate_structure(&structure, b"", 56), Err(FdtError::UnbalancedNodes { offset: 68 }));
+    }
+}
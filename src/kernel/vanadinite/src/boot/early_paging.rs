@@ -7,18 +7,19 @@
 
 use core::sync::atomic::AtomicUsize;
 
-use fdt::Fdt;
+use fdt::{node::FdtNode, Fdt};
 use kernel_patching::kernel_section_p2v;
 
 use crate::{
     csr::satp::Satp,
     mem::{
         kernel_patching,
+        memory_map::{self, MemoryMapEntry},
         paging::{
             flags::{ACCESSED, DIRTY, EXECUTE, READ, VALID, WRITE},
-            PageSize, PageTable, PhysicalAddress, VirtualAddress, SATP_MODE,
+            PageSize, PageTable, PhysicalAddress, VirtualAddress,
         },
-        phys::{PhysicalMemoryAllocator, PHYSICAL_MEMORY_ALLOCATOR},
+        phys::{numa, PhysicalMemoryAllocator, PHYSICAL_MEMORY_ALLOCATOR},
     },
     utils::{LinkerSymbol, Units},
 };
@@ -28,21 +29,43 @@ extern "C" {
     static __bss_end: LinkerSymbol;
     static __data_start: LinkerSymbol;
     static __data_end: LinkerSymbol;
+    static __rodata_start: LinkerSymbol;
+    static __rodata_end: LinkerSymbol;
     static __text_start: LinkerSymbol;
     static __text_end: LinkerSymbol;
     static __tdata_start: LinkerSymbol;
     static __tdata_end: LinkerSymbol;
     static __tmp_stack_bottom: LinkerSymbol;
+    static __tmp_stack_guard: LinkerSymbol;
     static __tmp_stack_top: LinkerSymbol;
+    static __kernel_symbols_start: LinkerSymbol;
+    static __kernel_symbols_end: LinkerSymbol;
     pub static PHYS_OFFSET_VALUE: usize;
 }
 
 pub static BOOTSTRAP_SATP: AtomicUsize = AtomicUsize::new(0);
 
+/// How much physical memory can be mapped into the kernel's address space, in
+/// GiB. Only the gigapages which hold RAM or devices are actually mapped, which
+/// are tracked one bit per gigapage in a `u64`.
+const LINEAR_MAP_GIB: usize = 64;
+/// The most ranges of physical memory that can be kept out of the allocator
+const MAX_RESERVED_RANGES: usize = 32;
+
 /// # Safety
 /// no
 #[no_mangle]
 pub unsafe extern "C" fn early_paging(hart_id: usize, fdt: *const u8) -> ! {
+    // Bootloaders other than QEMU's can put the device tree anywhere, but it
+    // has to be at least 8 byte aligned to be read at all
+    if fdt as usize % 8 != 0 {
+        crate::platform::exit(crate::platform::ExitStatus::Error(&"device tree pointer isn't 8 byte aligned"));
+    }
+
+    if let Err(e) = super::devicetree::validate(fdt) {
+        crate::platform::exit(crate::platform::ExitStatus::Error(&e));
+    }
+
     let fdt_struct: Fdt<'static> = match fdt::Fdt::from_ptr(fdt) {
         Ok(fdt) => fdt,
         Err(e) => crate::platform::exit(crate::platform::ExitStatus::Error(&e)),
@@ -54,34 +77,109 @@ pub unsafe extern "C" fn early_paging(hart_id: usize, fdt: *const u8) -> ! {
     let kernel_start = kernel_patching::kernel_start() as usize;
     let kernel_end = kernel_patching::kernel_end() as usize;
 
-    let memory_region = fdt_struct
-        .memory()
-        .regions()
-        .find(|region| {
-            let start = region.starting_address as usize;
-            let end = region.starting_address as usize + region.size.unwrap();
+    // The kernel can be loaded anywhere, but parts of the linear map are made
+    // out of megapages right up to the edges of it
+    if kernel_start % 2.mib() != 0 {
+        crate::platform::exit(crate::platform::ExitStatus::Error(&"kernel isn't loaded at a 2 MiB aligned address"));
+    }
 
-            start <= kernel_start && kernel_end <= end
-        })
-        .expect("wtf");
+    let mut reserved = ReservedRanges::new();
+    reserved.push(kernel_start, kernel_end);
+    reserved.push(fdt as usize, fdt as usize + fdt_size as usize);
+    memory_map::record(MemoryMapEntry::KERNEL_IMAGE, kernel_start, kernel_end);
+    memory_map::record(MemoryMapEntry::DEVICE_TREE, fdt as usize, fdt as usize + fdt_size as usize);
+    for reservation in fdt_struct.memory_reservations() {
+        let start = reservation.address() as usize;
+        reserved.push(start, start + reservation.size());
+        memory_map::record(MemoryMapEntry::RESERVED, start, start + reservation.size());
+    }
 
-    let start = memory_region.starting_address as usize;
-    let size = memory_region.size.unwrap() as usize;
+    // Firmware on real boards, like OpenSBI loaded by U-Boot's SPL, describes
+    // the memory it keeps for itself here. Nodes with only a `size` are meant
+    // to be allocated by the OS, so there's nothing to keep out.
+    let reserved_memory = fdt_struct.find_node("/reserved-memory").into_iter().flat_map(|node| node.children());
+    for region in reserved_memory.flat_map(|node| node.reg().into_iter().flatten()) {
+        let start = region.starting_address as usize;
+        reserved.push(start, start + region.size.unwrap_or(0));
+        memory_map::record(MemoryMapEntry::FIRMWARE, start, start + region.size.unwrap_or(0));
+    }
 
-    let kernel_end_phys = kernel_end as *mut u8;
+    // Init is part of the kernel image, but a bootloader told to load an
+    // initrd anyway puts it somewhere in RAM and only says where in `/chosen`
+    if let Some((start, end)) = initrd(&fdt_struct) {
+        reserved.push(start, end);
+        memory_map::record(MemoryMapEntry::INITRD, start, end);
+    }
 
     let mut pf_alloc = PHYSICAL_MEMORY_ALLOCATOR.lock();
-    pf_alloc.init(kernel_end_phys, (start + size) as *mut u8);
+    let mut found_kernel = false;
+    let mut linear_map = gigapage_mask(fdt as usize, fdt as usize + fdt_size as usize);
+    let mut add_memory = |numa_node: usize, mut start: usize, end: usize| {
+        memory_map::record(MemoryMapEntry::RAM, start, end);
+
+        // Only memory which is in the linear map can be handed out
+        let end = end.min(LINEAR_MAP_GIB * 1.gib());
+        linear_map |= gigapage_mask(start, end);
+
+        // Anything before the kernel is left alone, since that's usually
+        // where the SBI implementation lives
+        if start <= kernel_start && kernel_end <= end {
+            memory_map::record(MemoryMapEntry::FIRMWARE, start, kernel_start);
+            start = kernel_end;
+            found_kernel = true;
+        }
+
+        reserved.for_each_unreserved(start, end, |start, end| {
+            pf_alloc.add_region(numa_node, start as *mut u8, end as *mut u8);
+        });
+    };
 
-    if fdt > kernel_end_phys {
-        let n_pages = fdt_size as usize / 4096 + 1;
-        for i in 0..n_pages {
-            pf_alloc.set_used(crate::mem::phys::PhysicalPage::from_ptr(fdt.add(i * 4096) as *mut _));
+    // Started through the EFI stub, the firmware's memory map says what's free
+    // rather than the memory nodes, which also cover memory the firmware keeps
+    // using at runtime
+    match super::efi::usable_memory() {
+        [] => {
+            for node in memory_nodes(&fdt_struct) {
+                let numa_node = numa::memory_node_id(&node);
+                for region in node.reg().into_iter().flatten() {
+                    let start = region.starting_address as usize;
+                    add_memory(numa_node, start, start + region.size.unwrap_or(0));
+                }
+            }
+        }
+        usable => {
+            for &(start, end) in usable {
+                let numa_node = memory_nodes(&fdt_struct)
+                    .find(|node| {
+                        node.reg().into_iter().flatten().any(|region| {
+                            let region_start = region.starting_address as usize;
+                            (region_start..region_start + region.size.unwrap_or(0)).contains(&start)
+                        })
+                    })
+                    .map_or(0, |node| numa::memory_node_id(&node));
+
+                add_memory(numa_node, start, end);
+            }
+        }
+    }
+
+    assert!(found_kernel, "wtf");
+
+    // The kernel drives some devices itself through the linear map, so the
+    // gigapages holding any MMIO registers need to be mapped too. Nodes without
+    // a size, like harts, don't describe memory.
+    for node in fdt_struct.all_nodes() {
+        for region in node.reg().into_iter().flatten() {
+            if let Some(size) = region.size {
+                let start = region.starting_address as usize;
+                linear_map |= gigapage_mask(start, start + size);
+            }
         }
     }
 
     drop(pf_alloc);
 
+    let mode = crate::mem::paging::select_mode(&fdt_struct);
     let mut root_page_table = PageTable::new_raw();
 
     let bss_start = __bss_start.as_usize();
@@ -112,8 +210,11 @@ pub unsafe extern "C" fn early_paging(hart_id: usize, fdt: *const u8) -> ! {
 
     let tmp_stack_start = __tmp_stack_bottom.as_usize();
     let tmp_stack_end = __tmp_stack_top.as_usize();
+    let tmp_stack_guard = __tmp_stack_guard.as_usize();
 
-    for addr in (tmp_stack_start..tmp_stack_end).step_by(4096) {
+    // The page between the shadow call stack and the stack is left unmapped so
+    // either one overflowing into the other faults instead
+    for addr in (tmp_stack_start..tmp_stack_end).step_by(4096).filter(|&addr| addr != tmp_stack_guard) {
         let addr = PhysicalAddress::new(addr);
         root_page_table.static_map(
             addr,
@@ -131,7 +232,33 @@ pub unsafe extern "C" fn early_paging(hart_id: usize, fdt: *const u8) -> ! {
         root_page_table.static_map(
             addr,
             crate::kernel_patching::kernel_section_p2v(addr),
-            ACCESSED | EXECUTE | VALID,
+            ACCESSED | READ | EXECUTE | VALID,
+            PageSize::Kilopage,
+        );
+    }
+
+    let rodata_start = __rodata_start.as_usize();
+    let rodata_end = __rodata_end.as_usize();
+
+    for addr in (rodata_start..rodata_end).step_by(4096) {
+        let addr = PhysicalAddress::new(addr);
+        root_page_table.static_map(
+            addr,
+            crate::kernel_patching::kernel_section_p2v(addr),
+            ACCESSED | READ | VALID,
+            PageSize::Kilopage,
+        );
+    }
+
+    let symbols_start = __kernel_symbols_start.as_usize();
+    let symbols_end = __kernel_symbols_end.as_usize();
+
+    for addr in (symbols_start..symbols_end).step_by(4096) {
+        let addr = PhysicalAddress::new(addr);
+        root_page_table.static_map(
+            addr,
+            crate::kernel_patching::kernel_section_p2v(addr),
+            ACCESSED | READ | VALID,
             PageSize::Kilopage,
         );
     }
@@ -149,20 +276,41 @@ pub unsafe extern "C" fn early_paging(hart_id: usize, fdt: *const u8) -> ! {
     //     );
     // }
 
-    for addr in 0..64 {
+    for gib in (0..LINEAR_MAP_GIB).filter(|gib| linear_map & (1 << gib) != 0) {
+        memory_map::record(MemoryMapEntry::LINEAR_MAP, gib * 1.gib(), (gib + 1) * 1.gib());
+
+        // The kernel image is only mapped through its sections above, so that
+        // a bad pointer into the linear map can't be used to patch `.text` or
+        // write to `.rodata`
+        if gib == kernel_start / 1.gib() {
+            let gigapage = gib * 1.gib()..(gib + 1) * 1.gib();
+            for addr in gigapage.step_by(2.mib()).filter(|&addr| addr + 2.mib() <= kernel_start || addr >= kernel_end) {
+                root_page_table.static_map(
+                    PhysicalAddress::new(addr),
+                    VirtualAddress::new(PHYS_OFFSET_VALUE + addr),
+                    DIRTY | ACCESSED | READ | WRITE | VALID,
+                    PageSize::Megapage,
+                );
+            }
+
+            continue;
+        }
+
         root_page_table.static_map(
-            PhysicalAddress::new(addr * 1.gib()),
-            VirtualAddress::new(PHYS_OFFSET_VALUE + addr * 1.gib()),
+            PhysicalAddress::new(gib * 1.gib()),
+            VirtualAddress::new(PHYS_OFFSET_VALUE + gib * 1.gib()),
             DIRTY | ACCESSED | READ | WRITE | VALID,
             PageSize::Gigapage,
         );
     }
 
+    crate::mem::kernel_stacks::reserve(&mut root_page_table);
+
     // Need to leak the root page table here so it doesn't drop
     let root_pt_phys = root_page_table.physical_address();
     core::mem::forget(root_page_table);
 
-    let satp = Satp { mode: SATP_MODE, asid: 0, root_page_table: root_pt_phys };
+    let satp = Satp { mode, asid: 0, root_page_table: root_pt_phys };
 
     BOOTSTRAP_SATP.store(satp.as_usize(), core::sync::atomic::Ordering::SeqCst);
 
@@ -171,7 +319,7 @@ pub unsafe extern "C" fn early_paging(hart_id: usize, fdt: *const u8) -> ! {
     crate::mem::PHYSICAL_OFFSET.store(PHYS_OFFSET_VALUE, core::sync::atomic::Ordering::Relaxed);
 
     let gp: usize;
-    core::arch::asm!("lla {}, __global_pointer$", out(reg) gp);
+    core::arch::asm!("lla {}, __boot_gp", out(reg) gp);
 
     let new_sp = kernel_section_p2v(PhysicalAddress::new(tmp_stack_end)).as_usize();
     let new_gp = kernel_section_p2v(PhysicalAddress::new(gp)).as_usize();
@@ -211,3 +359,81 @@ pub unsafe extern "C" fn early_paging(hart_id: usize, fdt: *const u8) -> ! {
         options(noreturn, nostack),
     );
 }
+
+fn memory_nodes<'a>(fdt: &'a Fdt<'static>) -> impl Iterator<Item = FdtNode<'a, 'static>> + 'a {
+    fdt.all_nodes().filter(|node| node.property("device_type").and_then(|p| p.as_str()) == Some("memory"))
+}
+
+/// Physical memory which must never be handed out by the allocator
+struct ReservedRanges {
+    ranges: [(usize, usize); MAX_RESERVED_RANGES],
+    len: usize,
+}
+
+impl ReservedRanges {
+    fn new() -> Self {
+        Self { ranges: [(0, 0); MAX_RESERVED_RANGES], len: 0 }
+    }
+
+    /// Reserve every page overlapping `start..end`
+    fn push(&mut self, start: usize, end: usize) {
+        if start >= end {
+            return;
+        }
+
+        assert!(self.len < MAX_RESERVED_RANGES, "too many reserved memory ranges");
+        self.ranges[self.len] = (start & !(4.kib() - 1), crate::utils::round_up_to_next(end, 4.kib()));
+        self.len += 1;
+    }
+
+    /// Call `f` with each page aligned piece of `start..end` which doesn't
+    /// overlap any reserved range, in order
+    fn for_each_unreserved(&self, start: usize, end: usize, mut f: impl FnMut(usize, usize)) {
+        let mut start = crate::utils::round_up_to_next(start, 4.kib());
+        let end = end & !(4.kib() - 1);
+
+        while start < end {
+            let next_reserved = self.ranges[..self.len]
+                .iter()
+                .filter(|&&(reserved_start, reserved_end)| reserved_start < end && start < reserved_end)
+                .min_by_key(|&&(reserved_start, _)| reserved_start);
+
+            match next_reserved {
+                Some(&(reserved_start, reserved_end)) => {
+                    // The allocator keeps its bitmap in the first page, so a
+                    // single page isn't worth having
+                    if reserved_start > start + 4.kib() {
+                        f(start, reserved_start);
+                    }
+
+                    start = reserved_end;
+                }
+                None => {
+                    if end > start + 4.kib() {
+                        f(start, end);
+                    }
+
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Where the bootloader loaded an initrd, from the `linux,initrd-start` and
+/// `linux,initrd-end` properties, which can be either 32 or 64 bits
+fn initrd(fdt: &Fdt<'_>) -> Option<(usize, usize)> {
+    let chosen = fdt.find_node("/chosen")?;
+    let start = chosen.property("linux,initrd-start")?.as_usize()?;
+    let end = chosen.property("linux,initrd-end")?.as_usize()?;
+
+    Some((start, end))
+}
+
+/// The bits for the gigapages of the linear map which overlap `start..end`
+fn gigapage_mask(start: usize, end: usize) -> u64 {
+    let first = start / 1.gib();
+    let last = crate::utils::round_up_to_next(end, 1.gib()) / 1.gib();
+
+    (first..last.min(LINEAR_MAP_GIB)).fold(0, |mask, gib| mask | 1 << gib)
+}
@@ -0,0 +1,316 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Booting as an EFI application
+//!
+//! The image header in [`super::entry`] doubles as a PE/COFF header, so EFI
+//! firmware like EDK2 or U-Boot's `bootefi` can start the kernel directly. The
+//! firmware calls [`efi_pe_entry`] with paging off or identity mapped, and the
+//! stub gets the device tree and boot hart from it, records which memory is
+//! free from the final memory map and exits boot services. It then copies the
+//! kernel somewhere 2 MiB aligned and jumps to `_boot` like any other
+//! bootloader would.
+//!
+//! Like [`super::early_paging`] this runs at physical addresses, so it can't
+//! use anything which needs a pointer stored in a static, like formatting.
+
+use crate::{
+    mem::kernel_patching,
+    utils::{round_up_to_next, LinkerSymbol, Units},
+};
+use core::cell::UnsafeCell;
+
+extern "C" {
+    static __kernel_file_end: LinkerSymbol;
+}
+
+/// The most ranges of free memory kept from the EFI memory map
+const MAX_USABLE_RANGES: usize = 32;
+
+type Handle = *mut u8;
+type Status = usize;
+
+const SUCCESS: Status = 0;
+const BUFFER_TOO_SMALL: Status = 1 << 63 | 5;
+const NOT_FOUND: Status = 1 << 63 | 14;
+
+const ALLOCATE_ANY_PAGES: u32 = 0;
+const LOADER_DATA: u32 = 2;
+
+const DEVICE_TREE_GUID: Guid = Guid(0xB1B6_21D5, 0xF19C, 0x41A5, [0x83, 0x0B, 0xD9, 0x15, 0x2C, 0x69, 0xAA, 0xE0]);
+const RISCV_BOOT_PROTOCOL_GUID: Guid =
+    Guid(0xCCD1_5FEC, 0x6F73, 0x4EEC, [0x83, 0x95, 0x3E, 0x69, 0xE4, 0xB9, 0x40, 0xBF]);
+
+#[derive(PartialEq)]
+#[repr(C)]
+struct Guid(u32, u16, u16, [u8; 8]);
+
+#[allow(dead_code)]
+#[repr(C)]
+struct TableHeader {
+    signature: u64,
+    revision: u32,
+    header_size: u32,
+    crc32: u32,
+    reserved: u32,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+pub struct SystemTable {
+    header: TableHeader,
+    firmware_vendor: *const u16,
+    firmware_revision: u32,
+    console_in_handle: Handle,
+    console_in: *mut u8,
+    console_out_handle: Handle,
+    console_out: *mut u8,
+    standard_error_handle: Handle,
+    standard_error: *mut u8,
+    runtime_services: *mut u8,
+    boot_services: *const BootServices,
+    number_of_table_entries: usize,
+    configuration_table: *const ConfigurationTable,
+}
+
+/// Only the boot services the stub uses are named, the rest are there to keep
+/// the layout right
+#[allow(dead_code)]
+#[repr(C)]
+struct BootServices {
+    header: TableHeader,
+    _task_priority: [usize; 2],
+    allocate_pages: unsafe extern "C" fn(u32, u32, usize, *mut u64) -> Status,
+    _free_pages: usize,
+    get_memory_map: unsafe extern "C" fn(*mut usize, *mut u8, *mut usize, *mut usize, *mut u32) -> Status,
+    allocate_pool: unsafe extern "C" fn(u32, usize, *mut *mut u8) -> Status,
+    _free_pool: usize,
+    _events: [usize; 6],
+    _protocol_handlers: [usize; 9],
+    _images: [usize; 4],
+    exit_boot_services: unsafe extern "C" fn(Handle, usize) -> Status,
+    _miscellaneous: [usize; 3],
+    _drivers: [usize; 2],
+    _open_close_protocol: [usize; 3],
+    _library: [usize; 2],
+    locate_protocol: unsafe extern "C" fn(*const Guid, *mut u8, *mut *mut u8) -> Status,
+}
+
+#[repr(C)]
+struct ConfigurationTable {
+    vendor_guid: Guid,
+    vendor_table: *const u8,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct MemoryDescriptor {
+    kind: u32,
+    physical_start: u64,
+    virtual_start: u64,
+    number_of_pages: u64,
+    attribute: u64,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct RiscvBootProtocol {
+    revision: u64,
+    get_boot_hart_id: unsafe extern "C" fn(*mut RiscvBootProtocol, *mut usize) -> Status,
+}
+
+/// Memory EFI said was free to use once boot services exited
+struct UsableMemory {
+    ranges: [(usize, usize); MAX_USABLE_RANGES],
+    len: usize,
+}
+
+impl UsableMemory {
+    fn push(&mut self, start: usize, end: usize) {
+        match self.ranges[..self.len].last_mut() {
+            Some(last) if last.1 == start => last.1 = end,
+            // Losing some memory is better than handing out memory the
+            // firmware is still using
+            _ if self.len == MAX_USABLE_RANGES => {}
+            _ => {
+                self.ranges[self.len] = (start, end);
+                self.len += 1;
+            }
+        }
+    }
+}
+
+#[repr(transparent)]
+struct StaticUsableMemory(UnsafeCell<UsableMemory>);
+
+unsafe impl Sync for StaticUsableMemory {}
+
+/// Filled in by the stub right before the kernel is copied, so it has to be in
+/// `.data` to keep `_boot` from clearing it along with `.bss`
+#[link_section = ".data.efi"]
+static USABLE_MEMORY: StaticUsableMemory =
+    StaticUsableMemory(UnsafeCell::new(UsableMemory { ranges: [(0, 0); MAX_USABLE_RANGES], len: 0 }));
+
+/// The free memory from the EFI memory map, which is empty unless the kernel
+/// was started through the stub
+///
+/// # Safety
+///
+/// Must only be called while booting, before anything is running on other
+/// harts
+pub unsafe fn usable_memory() -> &'static [(usize, usize)] {
+    let memory = &*USABLE_MEMORY.0.get();
+    &memory.ranges[..memory.len]
+}
+
+/// Where EFI firmware starts the kernel, from the PE/COFF header in
+/// [`super::entry`]
+///
+/// # Safety
+///
+/// Only EFI firmware can call this
+#[no_mangle]
+pub unsafe extern "C" fn efi_pe_entry(image: Handle, system_table: *const SystemTable) -> Status {
+    let system_table = &*system_table;
+    let boot_services = &*system_table.boot_services;
+
+    let fdt = match device_tree(system_table) {
+        Some(fdt) => fdt,
+        None => return NOT_FOUND,
+    };
+
+    let hart_id = match boot_hart_id(boot_services, fdt) {
+        Some(hart_id) => hart_id,
+        None => return NOT_FOUND,
+    };
+
+    // The firmware only loads the kernel page aligned, so it's copied
+    // somewhere it can run from
+    let kernel_start = kernel_patching::kernel_start() as usize;
+    let image_size = kernel_patching::kernel_end() as usize - kernel_start;
+    let mut allocation = 0;
+    let status = (boot_services.allocate_pages)(
+        ALLOCATE_ANY_PAGES,
+        LOADER_DATA,
+        (image_size + 2.mib()) / 4.kib(),
+        &mut allocation,
+    );
+    if status != SUCCESS {
+        return status;
+    }
+
+    let status = exit_boot_services(image, boot_services);
+    if status != SUCCESS {
+        return status;
+    }
+
+    // There's no going back to the firmware from here on
+    let destination = round_up_to_next(allocation as usize, 2.mib()) as *mut u8;
+    let file_size = __kernel_file_end.as_usize() - kernel_start;
+    core::ptr::copy_nonoverlapping(kernel_start as *const u8, destination, file_size);
+
+    #[rustfmt::skip]
+    core::arch::asm!(
+        "
+            fence.i
+            jr {destination}
+        ",
+        destination = in(reg) destination,
+        in("a0") hart_id,
+        in("a1") fdt,
+        options(noreturn, nostack),
+    );
+}
+
+unsafe fn device_tree(system_table: &SystemTable) -> Option<*const u8> {
+    let tables = core::slice::from_raw_parts(system_table.configuration_table, system_table.number_of_table_entries);
+    tables.iter().find(|table| table.vendor_guid == DEVICE_TREE_GUID).map(|table| table.vendor_table)
+}
+
+unsafe fn boot_hart_id(boot_services: &BootServices, fdt: *const u8) -> Option<usize> {
+    let mut protocol: *mut RiscvBootProtocol = core::ptr::null_mut();
+    let status = (boot_services.locate_protocol)(
+        &RISCV_BOOT_PROTOCOL_GUID,
+        core::ptr::null_mut(),
+        &mut protocol as *mut _ as *mut *mut u8,
+    );
+
+    if status == SUCCESS {
+        let mut hart_id = 0;
+        if ((*protocol).get_boot_hart_id)(protocol, &mut hart_id) == SUCCESS {
+            return Some(hart_id);
+        }
+    }
+
+    // Firmware from before the protocol existed puts it in the device tree
+    super::devicetree::validate(fdt).ok()?;
+    let fdt = fdt::Fdt::from_ptr(fdt).ok()?;
+    fdt.find_node("/chosen")?.property("boot-hartid")?.as_usize()
+}
+
+/// Exit boot services, recording which memory is free from the final memory
+/// map in [`USABLE_MEMORY`]
+unsafe fn exit_boot_services(image: Handle, boot_services: &BootServices) -> Status {
+    let mut map_size = 0;
+    let mut map_key = 0;
+    let mut descriptor_size = 0;
+    let mut descriptor_version = 0;
+    let status = (boot_services.get_memory_map)(
+        &mut map_size,
+        core::ptr::null_mut(),
+        &mut map_key,
+        &mut descriptor_size,
+        &mut descriptor_version,
+    );
+    if status != BUFFER_TOO_SMALL {
+        return status;
+    }
+
+    // Allocating the buffer can split up free memory in the map
+    let buffer_size = map_size + 4 * descriptor_size;
+    let mut buffer = core::ptr::null_mut();
+    let status = (boot_services.allocate_pool)(LOADER_DATA, buffer_size, &mut buffer);
+    if status != SUCCESS {
+        return status;
+    }
+
+    // The map can change between getting it and exiting boot services, in
+    // which case it has to be gotten again. Nothing can be allocated after the
+    // first attempt, so the same buffer is reused.
+    loop {
+        map_size = buffer_size;
+        let status = (boot_services.get_memory_map)(
+            &mut map_size,
+            buffer,
+            &mut map_key,
+            &mut descriptor_size,
+            &mut descriptor_version,
+        );
+        if status != SUCCESS {
+            return status;
+        }
+
+        if (boot_services.exit_boot_services)(image, map_key) == SUCCESS {
+            break;
+        }
+    }
+
+    let usable = &mut *USABLE_MEMORY.0.get();
+    for i in 0..map_size / descriptor_size {
+        let descriptor = &*buffer.add(i * descriptor_size).cast::<MemoryDescriptor>();
+
+        // Loader and boot services memory is free along with conventional
+        // memory once boot services have exited, everything else is either
+        // still used by the firmware or isn't RAM
+        if let 1 | 2 | 3 | 4 | 7 = descriptor.kind {
+            let start = descriptor.physical_start as usize;
+            usable.push(start, start + descriptor.number_of_pages as usize * 4.kib());
+        }
+    }
+
+    SUCCESS
+}
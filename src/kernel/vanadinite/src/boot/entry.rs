@@ -5,6 +5,14 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
+// What `gp` starts out as on the boot hart. With shadow call stacks, it's the
+// shadow call stack, since anything built with them pushes return addresses to
+// wherever `gp` points.
+#[cfg(not(feature = "hardening.shadow_call_stack"))]
+core::arch::global_asm!(".set __boot_gp, __global_pointer$");
+#[cfg(feature = "hardening.shadow_call_stack")]
+core::arch::global_asm!(".set __boot_gp, __tmp_shadow_call_stack");
+
 /// # Safety
 ///
 /// I'm the kernel, rustc
@@ -15,12 +23,104 @@
 pub unsafe extern "C" fn _boot() -> ! {
     #[rustfmt::skip]
     core::arch::asm!("
+        # A RISC-V Linux `Image` header, so bootloaders which only know how to
+        # boot Linux, like U-Boot's `booti`, take the flat binary. The kernel
+        # runs from wherever it's loaded, as long as that's 2 MiB aligned.
+        #
+        # It doubles as the DOS header of a PE/COFF image for EFI firmware,
+        # which starts at `efi_pe_entry` instead. The `MZ` magic decodes as
+        # `c.li s4, -13`, which is harmless.
+        .half 0x5A4D                    # code0: `MZ`
+        .option push
+        .option norvc
+        j header_end
+        .option pop
+        .half 0                         # code1
+        .dword 0x200000                 # text_offset
+        .dword __kernel_image_size      # image_size
+        .dword 0                        # flags: little endian
+        .word 2                         # version 0.2
+        .word 0                         # res1
+        .dword 0                        # res2
+        .ascii \"RISCV\"                # magic, deprecated
+        .zero 3
+        .ascii \"RSC\"                  # magic2
+        .byte 0x05
+        .word pe_header - _boot         # res3: where the PE header is
+
+        pe_header:
+        .ascii \"PE\"
+        .zero 2
+
+        # COFF file header
+        .half 0x5064                    # Machine: RISCV64
+        .half 1                         # NumberOfSections
+        .word 0                         # TimeDateStamp
+        .word 0                         # PointerToSymbolTable
+        .word 0                         # NumberOfSymbols
+        .half section_table - optional_header # SizeOfOptionalHeader
+        .half 0x0206                    # Characteristics: executable, stripped
+
+        optional_header:
+        .half 0x020B                    # Magic: PE32+
+        .byte 0                         # MajorLinkerVersion
+        .byte 0                         # MinorLinkerVersion
+        .word __efi_raw_size            # SizeOfCode
+        .word 0                         # SizeOfInitializedData
+        .word 0                         # SizeOfUninitializedData
+        .word __efi_entry_rva           # AddressOfEntryPoint
+        .word 0x1000                    # BaseOfCode
+
+        .dword 0                        # ImageBase
+        .word 0x1000                    # SectionAlignment
+        .word 0x1000                    # FileAlignment
+        .half 0                         # MajorOperatingSystemVersion
+        .half 0                         # MinorOperatingSystemVersion
+        .half 0                         # MajorImageVersion
+        .half 0                         # MinorImageVersion
+        .half 0                         # MajorSubsystemVersion
+        .half 0                         # MinorSubsystemVersion
+        .word 0                         # Win32VersionValue
+        .word __kernel_image_size       # SizeOfImage
+        .word 0x1000                    # SizeOfHeaders
+        .word 0                         # CheckSum
+        .half 10                        # Subsystem: EFI application
+        .half 0                         # DllCharacteristics
+        .dword 0                        # SizeOfStackReserve
+        .dword 0                        # SizeOfStackCommit
+        .dword 0                        # SizeOfHeapReserve
+        .dword 0                        # SizeOfHeapCommit
+        .word 0                         # LoaderFlags
+        .word 6                         # NumberOfRvaAndSizes
+
+        # Export, import, resource, exception, certificate and base relocation
+        # tables, none of which the kernel has
+        .zero 6 * 8
+
+        section_table:
+        .ascii \".text\"
+        .zero 3
+        .word __efi_virtual_size        # VirtualSize
+        .word 0x1000                    # VirtualAddress
+        .word __efi_raw_size            # SizeOfRawData
+        .word 0x1000                    # PointerToRawData
+        .word 0                         # PointerToRelocations
+        .word 0                         # PointerToLinenumbers
+        .half 0                         # NumberOfRelocations
+        .half 0                         # NumberOfLinenumbers
+        .word 0xE0000060                # Characteristics: code, data, RWX
+
+        # Everything from here on is the single section above
+        .balign 0x1000
+
+        header_end:
+
         csrw sie, zero
         csrci sstatus, 2
         
         .option push
         .option norelax
-        lla gp, __global_pointer$
+        lla gp, __boot_gp
         .option pop
 
         lla t0, __bss_start
@@ -5,5 +5,7 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
+pub mod devicetree;
 pub mod early_paging;
+pub mod efi;
 pub mod entry;
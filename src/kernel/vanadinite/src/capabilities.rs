@@ -9,13 +9,16 @@ use crate::{
     mem::{
         manager::AddressRegionKind,
         paging::{PhysicalAddress, VirtualAddress},
-        region::SharedPhysicalRegion,
+        shm::SharedMemory,
     },
-    syscall::channel::UserspaceChannel,
+    syscall::{channel::UserspaceChannel, pipe::PipeEnd, topic::Topic},
 };
-use alloc::collections::BTreeMap;
+use alloc::{collections::BTreeMap, sync::Arc};
 use core::ops::Range;
-use librust::capabilities::{CapabilityPtr, CapabilityRights};
+use librust::{
+    capabilities::{CapabilityPtr, CapabilityRights},
+    syscalls::mem::SealFlags,
+};
 
 #[derive(Debug, Clone, Copy)]
 pub struct Occupied;
@@ -87,6 +90,33 @@ pub struct Capability {
 #[derive(Debug, Clone)]
 pub enum CapabilityResource {
     Channel(UserspaceChannel),
-    Memory(SharedPhysicalRegion, Range<VirtualAddress>, AddressRegionKind),
+    /// A shared memory object, where it's mapped in the task holding the
+    /// capability, how it's mapped, and what the capability is sealed against.
+    /// Nothing may give a mapping of a memory capability sealed against
+    /// upgrades permissions which it doesn't already have.
+    Memory(SharedMemory, Range<VirtualAddress>, AddressRegionKind, SealFlags),
     Mmio(Range<PhysicalAddress>, Range<VirtualAddress>, alloc::vec::Vec<usize>),
+    /// Allows shutting down and rebooting the system
+    Power,
+    /// Allows snooping on other tasks' channels
+    Debug,
+    /// Allows setting the wall clock
+    Clock,
+    /// Allows subscribing to memory pressure notifications with `READ`
+    MemoryPressure,
+    /// Allows reading the console ring with `READ` and choosing the console
+    /// sinks with `WRITE`
+    Console,
+    /// Allows inflating and deflating the memory balloon with `WRITE`
+    Balloon,
+    /// Allows enabling swap with `WRITE`, after which the task that enabled it
+    /// is the only one allowed to manage it
+    Swap,
+    /// A task group, which can be joined with `READ` and signaled with `WRITE`
+    TaskGroup(usize),
+    /// A publish-subscribe topic, which can be subscribed to with `READ` and
+    /// published to with `WRITE`
+    Topic(Arc<Topic>),
+    /// One end of a pipe
+    Pipe(PipeEnd),
 }
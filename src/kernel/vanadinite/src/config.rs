@@ -0,0 +1,43 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The optional subsystems the kernel was built with
+//!
+//! Which combinations of features make sense is checked by the build script
+//! before anything is compiled, this only reports what was picked. The paging
+//! mode is picked at boot instead, but is reported the same way.
+
+use crate::csr::satp::SatpMode;
+use librust::syscalls::config::KernelConfig;
+
+const FEATURES: &[(bool, KernelConfig)] = &[
+    (cfg!(feature = "platform.virt"), KernelConfig::PLATFORM_VIRT),
+    (cfg!(feature = "platform.sifive_u"), KernelConfig::PLATFORM_SIFIVE_U),
+    (cfg!(feature = "driver.dw_wdt"), KernelConfig::DRIVER_DW_WDT),
+    (cfg!(feature = "driver.riscv_iommu"), KernelConfig::DRIVER_RISCV_IOMMU),
+    (cfg!(feature = "driver.sifive_uart"), KernelConfig::DRIVER_SIFIVE_UART),
+    (cfg!(feature = "driver.syscon"), KernelConfig::DRIVER_SYSCON),
+    (cfg!(feature = "driver.uart16550"), KernelConfig::DRIVER_UART16550),
+    (cfg!(feature = "pmalloc.allocator.bitmap"), KernelConfig::PMALLOC_BITMAP),
+    (cfg!(feature = "pmalloc.allocator.buddy"), KernelConfig::PMALLOC_BUDDY),
+    (cfg!(feature = "vmalloc.allocator.freelist"), KernelConfig::VMALLOC_FREELIST),
+    (cfg!(feature = "hardening.shadow_call_stack"), KernelConfig::HARDENING_SHADOW_CALL_STACK),
+    (cfg!(feature = "debug.replay"), KernelConfig::DEBUG_REPLAY),
+];
+
+/// Every subsystem which was enabled at build time, along with the paging mode
+pub fn kernel_config() -> KernelConfig {
+    let config = FEATURES
+        .iter()
+        .filter(|&&(enabled, _)| enabled)
+        .fold(KernelConfig::NONE, |config, &(_, feature)| config | feature);
+
+    match crate::mem::paging::satp_mode() {
+        SatpMode::Sv48 => config | KernelConfig::PAGING_SV48,
+        _ => config,
+    }
+}
@@ -0,0 +1,114 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! What the harts the kernel is running on are
+//!
+//! The CSRs which describe a hart (`misa`, `mvendorid`, `marchid` and `mimpid`)
+//! are machine-level, so reading them from S-mode traps, and the kernel is
+//! always in S-mode. Instead, the IDs come from the SBI base extension, which
+//! reads them on the kernel's behalf, and the ISA comes from the device tree.
+//! Only the extensions every hart has are counted, since tasks can end up on any
+//! of them.
+
+use fdt::Fdt;
+use sync::SpinMutex;
+
+static FEATURES: SpinMutex<CpuFeatures> = SpinMutex::new(CpuFeatures::UNKNOWN);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuFeatures {
+    pub mvendorid: usize,
+    pub marchid: usize,
+    pub mimpid: usize,
+    /// The single-letter extensions, laid out like `misa`: bit 0 is A, bit 1 is
+    /// B, and so on
+    pub extensions: usize,
+}
+
+impl CpuFeatures {
+    const UNKNOWN: Self = Self { mvendorid: 0, marchid: 0, mimpid: 0, extensions: 0 };
+
+    /// Whether every hart has the single-letter extension `extension`
+    pub fn has(&self, extension: char) -> bool {
+        extension_bit(extension).map_or(false, |bit| self.extensions & bit != 0)
+    }
+}
+
+impl core::fmt::Display for CpuFeatures {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "rv64")?;
+        for extension in ('a'..='z').filter(|&e| self.has(e)) {
+            write!(f, "{}", extension)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn init(fdt: &Fdt<'_>) {
+    let mut extensions = None;
+    for cpu in fdt.cpus() {
+        let hart_extensions = match cpu.properties().find(|p| p.name == "riscv,isa-extensions") {
+            Some(list) => list
+                .value
+                .split(|&b| b == 0)
+                .filter_map(|ext| match ext {
+                    [letter] => extension_bit(char::from(*letter)),
+                    _ => None,
+                })
+                .fold(0, |extensions, bit| extensions | bit),
+            None => match cpu.properties().find(|p| p.name == "riscv,isa").and_then(|p| p.as_str()) {
+                Some(isa) => parse_isa(isa),
+                None => {
+                    log::warn!("No ISA in the device tree for hart {}", cpu.ids().first());
+                    0
+                }
+            },
+        };
+
+        extensions = Some(extensions.map_or(hart_extensions, |extensions: usize| extensions & hart_extensions));
+    }
+
+    let features = CpuFeatures {
+        mvendorid: sbi::base::mvendorid(),
+        marchid: sbi::base::marchid(),
+        mimpid: sbi::base::mimpid(),
+        extensions: extensions.unwrap_or(0),
+    };
+
+    *FEATURES.lock() = features;
+}
+
+pub fn features() -> CpuFeatures {
+    *FEATURES.lock()
+}
+
+/// The single-letter extensions in a `riscv,isa` string like
+/// `rv64imafdc_zicsr`, which end at the first multi-letter extension
+fn parse_isa(isa: &str) -> usize {
+    let isa = isa.to_ascii_lowercase();
+    let letters = match isa.strip_prefix("rv64").or_else(|| isa.strip_prefix("rv32")) {
+        Some(letters) => letters.split('_').next().unwrap_or_default(),
+        None => return 0,
+    };
+
+    letters
+        .chars()
+        .map(|letter| match letter {
+            // G is shorthand for IMAFD, along with Zicsr and Zifencei
+            'g' => "imafd".chars().filter_map(extension_bit).fold(0, |extensions, bit| extensions | bit),
+            letter => extension_bit(letter).unwrap_or(0),
+        })
+        .fold(0, |extensions, bit| extensions | bit)
+}
+
+fn extension_bit(extension: char) -> Option<usize> {
+    match extension.to_ascii_lowercase() {
+        letter @ 'a'..='z' => Some(1 << (letter as usize - 'a' as usize)),
+        _ => None,
+    }
+}
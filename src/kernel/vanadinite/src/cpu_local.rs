@@ -8,8 +8,11 @@
 use crate::{
     mem::{paging::PageSize, phys::PhysicalMemoryAllocator},
     utils::round_up_to_next,
-    Units,
+    Units, HART_ID, N_CPUS,
 };
+use alloc::boxed::Box;
+use core::sync::atomic::Ordering;
+use sync::OnceCell;
 
 // #[repr(C)]
 // struct ControlBlock {
@@ -21,28 +24,45 @@ use crate::{
 /// This function ***must*** be called before any references to any per-hart
 /// statics are used, and failing to do so can result in undefined behavior
 pub unsafe fn init_thread_locals() {
-    use crate::utils::LinkerSymbol;
-
-    extern "C" {
-        static __tdata_start: LinkerSymbol;
-        static __tdata_end: LinkerSymbol;
-    }
-
-    let size = __tdata_end.as_usize() - __tdata_start.as_usize();
-
-    let original_thread_locals = core::slice::from_raw_parts(__tdata_start.as_ptr(), size);
     let new_thread_locals = crate::mem::phys2virt(
         crate::mem::phys::PHYSICAL_MEMORY_ALLOCATOR
             .lock()
-            .alloc_contiguous(PageSize::Kilopage, round_up_to_next(size, 4.kib()))
+            .alloc_contiguous(PageSize::Kilopage, round_up_to_next(original_thread_locals().len(), 4.kib()))
             .unwrap()
             .as_phys_address(),
     )
     .as_mut_ptr();
 
-    core::slice::from_raw_parts_mut(new_thread_locals, size).copy_from_slice(original_thread_locals);
+    reinit_thread_locals(new_thread_locals);
+}
+
+/// Like [`init_thread_locals`], but reusing the thread locals of a hart which
+/// has stopped rather than allocating new ones. Every thread local is reset.
+///
+/// # Safety
+///
+/// Same as [`init_thread_locals`], and `thread_locals` has to have come from
+/// [`tp`] on a hart which isn't running anymore
+pub unsafe fn reinit_thread_locals(thread_locals: *mut u8) {
+    let original_thread_locals = original_thread_locals();
+    core::slice::from_raw_parts_mut(thread_locals, original_thread_locals.len())
+        .copy_from_slice(original_thread_locals);
+
+    core::arch::asm!("mv tp, {}", in(reg) thread_locals);
+}
+
+fn original_thread_locals() -> &'static [u8] {
+    use crate::utils::LinkerSymbol;
+
+    extern "C" {
+        static __tdata_start: LinkerSymbol;
+        static __tdata_end: LinkerSymbol;
+    }
 
-    core::arch::asm!("mv tp, {}", in(reg) new_thread_locals);
+    unsafe {
+        let size = __tdata_end.as_usize() - __tdata_start.as_usize();
+        core::slice::from_raw_parts(__tdata_start.as_ptr(), size)
+    }
 }
 
 pub fn tp() -> *mut u8 {
@@ -51,3 +71,51 @@ pub fn tp() -> *mut u8 {
 
     val as *mut u8
 }
+
+/// A value for each hart, indexed by hart ID. Unlike `#[thread_local]`s, other
+/// harts can get at them too. The values are made the first time any of them
+/// is used, which has to be after the number of harts is known.
+pub struct PerHart<T> {
+    harts: OnceCell<Box<[T]>>,
+    f: fn() -> T,
+}
+
+impl<T> PerHart<T> {
+    pub const fn new(f: fn() -> T) -> Self {
+        Self { harts: OnceCell::new(), f }
+    }
+
+    fn harts(&self) -> &[T] {
+        self.harts.get_or_init(|| (0..N_CPUS.load(Ordering::Acquire)).map(|_| (self.f)()).collect())
+    }
+
+    /// The value of the current hart
+    pub fn current(&self) -> &T {
+        &self.harts()[HART_ID.get()]
+    }
+
+    pub fn get(&self, hart: usize) -> Option<&T> {
+        self.harts().get(hart)
+    }
+
+    pub fn iter(&self) -> core::slice::Iter<'_, T> {
+        self.harts().iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.harts().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.harts().is_empty()
+    }
+}
+
+impl<T> core::ops::Index<usize> for PerHart<T> {
+    type Output = T;
+
+    #[track_caller]
+    fn index(&self, hart: usize) -> &T {
+        &self.harts()[hart]
+    }
+}
@@ -20,6 +20,11 @@ pub mod sie {
         unsafe { asm!("csrw sie, {}", in(reg) 0x222) };
     }
 
+    #[inline(always)]
+    pub fn write(val: usize) {
+        unsafe { asm!("csrw sie, {}", in(reg) val) };
+    }
+
     #[inline(always)]
     pub fn read() -> usize {
         let val: usize;
@@ -40,6 +45,13 @@ pub mod sip {
 
         val
     }
+
+    /// Clear a pending supervisor software interrupt, the only bit of `sip`
+    /// which can be cleared from S-mode
+    #[inline(always)]
+    pub fn clear_software_interrupt() {
+        unsafe { asm!("csrc sip, {}", in(reg) 2) };
+    }
 }
 
 pub mod sstatus {
@@ -96,6 +108,11 @@ pub mod sstatus {
         unsafe { asm!("csrw sstatus, {}", in(reg) val) };
     }
 
+    /// Whether the trap being handled was taken from S-mode rather than U-mode
+    pub fn trapped_from_supervisor() -> bool {
+        (read() >> 8) & 1 == 1
+    }
+
     #[inline(always)]
     pub fn read() -> usize {
         let val: usize;
@@ -158,6 +175,19 @@ pub mod satp {
         pub fn as_usize(self) -> usize {
             ((self.mode as usize) << 60) | ((self.asid as usize) << 44) | self.root_page_table.ppn()
         }
+
+        pub fn from_usize(value: usize) -> Self {
+            let asid = ((value >> 44) & 0xFFFF) as u16;
+            let root_page_table = PhysicalAddress::new((value & ((1 << 44) - 1)) << 12);
+            let mode = match value >> 60 {
+                0 => SatpMode::Bare,
+                8 => SatpMode::Sv39,
+                9 => SatpMode::Sv48,
+                _ => unreachable!("invalid satp mode"),
+            };
+
+            Satp { mode, asid, root_page_table }
+        }
     }
 
     #[inline(always)]
@@ -165,16 +195,7 @@ pub mod satp {
         let value: usize;
         unsafe { asm!("csrr {}, satp", out(reg) value) };
 
-        let asid = ((value >> 44) & 0xFFFF) as u16;
-        let root_page_table = PhysicalAddress::new((value & ((1 << 44) - 1)) << 12);
-        let mode = match value >> 60 {
-            0 => SatpMode::Bare,
-            8 => SatpMode::Sv39,
-            9 => SatpMode::Sv48,
-            _ => unreachable!("invalid satp mode"),
-        };
-
-        Satp { mode, asid, root_page_table }
+        Satp::from_usize(value)
     }
 
     #[inline(always)]
@@ -0,0 +1,61 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Driver for the Synopsys DesignWare APB watchdog timer, which is found on a
+//! number of RISC-V SoCs
+
+use crate::{drivers::CompatibleWith, watchdog::HardwareWatchdog};
+use volatile::{Read, ReadWrite, Volatile, Write};
+
+/// Value written to the counter restart register to restart the counter
+const RESTART: u32 = 0x76;
+/// The timeout periods are `2^(16 + i)` clock cycles for `i` in `0..16`
+const MIN_PERIOD_SHIFT: u32 = 16;
+const MAX_TIMEOUT_INDEX: u32 = 15;
+
+#[repr(C)]
+pub struct DwWatchdog {
+    control: Volatile<u32, ReadWrite>,
+    timeout_range: Volatile<u32, ReadWrite>,
+    _current_value: Volatile<u32, Read>,
+    counter_restart: Volatile<u32, Write>,
+}
+
+mod control {
+    pub const ENABLE: u32 = 1 << 0;
+    /// Raise an interrupt on the first timeout instead of resetting the system
+    pub const INTERRUPT_MODE: u32 = 1 << 1;
+}
+
+impl DwWatchdog {
+    /// Start the watchdog with the shortest timeout which is at least
+    /// `timeout_us` microseconds long, returning the timeout it was actually
+    /// started with. Once started, the watchdog can't be stopped again.
+    pub fn start(&self, clock_hz: u64, timeout_us: u64) -> u64 {
+        let period_us = |index: u32| (1u64 << (MIN_PERIOD_SHIFT + index)) * 1_000_000 / clock_hz;
+        let index = (0..=MAX_TIMEOUT_INDEX).find(|&i| period_us(i) >= timeout_us).unwrap_or(MAX_TIMEOUT_INDEX);
+
+        // The initial timeout is in the upper nibble
+        self.timeout_range.write(index << 4 | index);
+        self.counter_restart.write(RESTART);
+        self.control.write((self.control.read() & !control::INTERRUPT_MODE) | control::ENABLE);
+
+        period_us(index)
+    }
+}
+
+impl HardwareWatchdog for DwWatchdog {
+    fn pet(&self) {
+        self.counter_restart.write(RESTART);
+    }
+}
+
+impl CompatibleWith for DwWatchdog {
+    fn compatible_with() -> &'static [&'static str] {
+        &["snps,dw-wdt"]
+    }
+}
@@ -0,0 +1,578 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Driver for the RISC-V IOMMU
+//!
+//! Each task which claims a device behind the IOMMU gets its own isolation
+//! domain: a first-stage page table containing only the DMA memory the task has
+//! allocated. Devices which haven't been claimed have no valid device context,
+//! so any DMA they attempt is blocked.
+
+use crate::{
+    drivers::CompatibleWith,
+    mem::{
+        dma::{DeviceAddress, DmaMapError, DmaMapper},
+        paging::{self, flags, PageSize, PageTable, PhysicalAddress, VirtualAddress},
+        phys::zalloc_page,
+        phys2virt,
+    },
+    time, utils,
+};
+use alloc::collections::BTreeMap;
+use core::time::Duration;
+use librust::task::Tid;
+use sync::SpinMutex;
+use volatile::{Read, ReadWrite, Volatile};
+
+/// Where device addresses start in each domain, so that a device address of
+/// zero is never handed out
+const IOVA_START: usize = 0x1000_0000;
+/// The end of the lower half of the smallest supported address space (Sv39)
+const IOVA_END: usize = 1 << 38;
+
+// Single page command queue of 16 byte commands
+const COMMAND_QUEUE_ENTRIES: u32 = 4096 / 16;
+// Single page fault queue of 32 byte records
+const FAULT_QUEUE_ENTRIES: u32 = 4096 / 32;
+/// How long to wait for the IOMMU to finish a mode change or process commands
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+#[repr(C)]
+pub struct IommuRegisters {
+    capabilities: Volatile<u64, Read>,
+    _fctl: Volatile<u32, ReadWrite>,
+    _reserved: u32,
+    ddtp: Volatile<u64, ReadWrite>,
+    cqb: Volatile<u64, ReadWrite>,
+    cqh: Volatile<u32, Read>,
+    cqt: Volatile<u32, ReadWrite>,
+    fqb: Volatile<u64, ReadWrite>,
+    fqh: Volatile<u32, ReadWrite>,
+    fqt: Volatile<u32, Read>,
+    _pqb: Volatile<u64, ReadWrite>,
+    _pqh: Volatile<u32, ReadWrite>,
+    _pqt: Volatile<u32, Read>,
+    cqcsr: Volatile<u32, ReadWrite>,
+    fqcsr: Volatile<u32, ReadWrite>,
+    _pqcsr: Volatile<u32, ReadWrite>,
+    ipsr: Volatile<u32, ReadWrite>,
+}
+
+mod capabilities {
+    pub const SV39: u64 = 1 << 9;
+    pub const SV48: u64 = 1 << 10;
+    pub const SV57: u64 = 1 << 11;
+    pub const MSI_FLAT: u64 = 1 << 22;
+}
+
+mod ddtp {
+    pub const MODE_OFF: u64 = 0;
+    pub const MODE_1LVL: u64 = 2;
+    pub const BUSY: u64 = 1 << 4;
+}
+
+mod cqcsr {
+    pub const CQEN: u32 = 1 << 0;
+    pub const CQMF: u32 = 1 << 8;
+    pub const CMD_TO: u32 = 1 << 9;
+    pub const CMD_ILL: u32 = 1 << 10;
+    pub const CQON: u32 = 1 << 16;
+    pub const ERRORS: u32 = CQMF | CMD_TO | CMD_ILL;
+}
+
+mod fqcsr {
+    pub const FQEN: u32 = 1 << 0;
+    pub const FIE: u32 = 1 << 1;
+    pub const FQMF: u32 = 1 << 8;
+    pub const FQOF: u32 = 1 << 9;
+    pub const FQON: u32 = 1 << 16;
+    pub const ERRORS: u32 = FQMF | FQOF;
+}
+
+mod ipsr {
+    pub const FIP: u32 = 1 << 1;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IommuError {
+    /// The IOMMU can't walk page tables in the format the kernel uses
+    UnsupportedPagingMode,
+    /// The device ID is larger than the device directory can hold
+    DeviceIdTooLarge(u32),
+    /// The device is already attached to another task's domain
+    DeviceInUse(u32),
+    /// The IOMMU didn't finish switching modes in time
+    Timeout,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Command([u64; 2]);
+
+impl Command {
+    const IOTINVAL: u64 = 1;
+    const IOFENCE: u64 = 2;
+    const IODIR: u64 = 3;
+
+    /// Invalidate all cached first-stage translations for the given process
+    /// soft-context ID
+    fn invalidate_vma(pscid: u32) -> Self {
+        const PSCV: u64 = 1 << 32;
+        Self([Self::IOTINVAL | PSCV | ((pscid as u64) << 12), 0])
+    }
+
+    /// Invalidate the cached device context for `device_id`
+    fn invalidate_ddt(device_id: u32) -> Self {
+        const DV: u64 = 1 << 33;
+        Self([Self::IODIR | DV | ((device_id as u64) << 40), 0])
+    }
+
+    /// Wait for all previous commands to complete
+    fn fence() -> Self {
+        Self([Self::IOFENCE, 0])
+    }
+}
+
+/// A record the IOMMU writes to the fault queue when it blocks a device
+/// access, see section 3.2 of the RISC-V IOMMU specification
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct FaultRecord([u64; 4]);
+
+impl FaultRecord {
+    fn cause(&self) -> u64 {
+        self.0[0] & 0xFFF
+    }
+
+    fn device_id(&self) -> u32 {
+        (self.0[0] >> 40) as u32
+    }
+
+    /// The device address which faulted, for page faults
+    fn iotval(&self) -> u64 {
+        self.0[2]
+    }
+}
+
+/// Hands out ranges of device addresses within a domain, reusing ranges which
+/// have been freed before growing the space in use
+struct IovaAllocator {
+    /// Free ranges below `next`, keyed by their start with their length,
+    /// which are merged with their neighbours as they're freed
+    free: BTreeMap<usize, usize>,
+    next: usize,
+    end: usize,
+}
+
+impl IovaAllocator {
+    fn new(start: usize, end: usize) -> Self {
+        Self { free: BTreeMap::new(), next: start, end }
+    }
+
+    fn alloc(&mut self, len: usize) -> Option<usize> {
+        let reused =
+            self.free.iter().find(|&(_, &free_len)| free_len >= len).map(|(&start, &free_len)| (start, free_len));
+        if let Some((start, free_len)) = reused {
+            self.free.remove(&start);
+            if free_len > len {
+                self.free.insert(start + len, free_len - len);
+            }
+
+            return Some(start);
+        }
+
+        let start = self.next;
+        if start.checked_add(len).map_or(true, |end| end > self.end) {
+            return None;
+        }
+
+        self.next += len;
+        Some(start)
+    }
+
+    fn free(&mut self, mut start: usize, mut len: usize) {
+        if let Some((&prev, &prev_len)) = self.free.range(..start).next_back() {
+            if prev + prev_len == start {
+                self.free.remove(&prev);
+                start = prev;
+                len += prev_len;
+            }
+        }
+
+        if let Some(next_len) = self.free.remove(&(start + len)) {
+            len += next_len;
+        }
+
+        // Give ranges at the top back to the unallocated space instead of
+        // keeping them on the free list
+        match start + len == self.next {
+            true => self.next = start,
+            false => {
+                self.free.insert(start, len);
+            }
+        }
+    }
+}
+
+struct Domain {
+    table: PageTable,
+    pscid: u32,
+    iovas: IovaAllocator,
+}
+
+struct IommuState {
+    device_directory: PhysicalAddress,
+    command_queue: PhysicalAddress,
+    command_tail: u32,
+    domains: BTreeMap<Tid, Domain>,
+    owners: BTreeMap<u32, Tid>,
+    next_pscid: u32,
+}
+
+pub struct Iommu {
+    registers: &'static IommuRegisters,
+    phandle: Option<u32>,
+    // Device contexts are twice as large when the IOMMU supports MSI
+    // translation
+    device_context_size: usize,
+    // Kept outside of the lock, since faults are drained from interrupt
+    // context
+    fault_queue: PhysicalAddress,
+    state: SpinMutex<IommuState>,
+}
+
+impl Iommu {
+    /// Take control of the IOMMU, blocking DMA from every device behind it
+    /// until the device is attached to a domain
+    ///
+    /// # Safety
+    ///
+    /// `registers` must point to the register block of a RISC-V IOMMU which
+    /// nothing else is using
+    pub unsafe fn init(registers: &'static IommuRegisters, phandle: Option<u32>) -> Result<Self, IommuError> {
+        let caps = registers.capabilities.read();
+        let paging_supported = match paging::satp_mode() {
+            crate::csr::satp::SatpMode::Sv39 => caps & capabilities::SV39 != 0,
+            crate::csr::satp::SatpMode::Sv48 => caps & capabilities::SV48 != 0,
+            crate::csr::satp::SatpMode::Sv57 => caps & capabilities::SV57 != 0,
+            _ => false,
+        };
+
+        if !paging_supported {
+            return Err(IommuError::UnsupportedPagingMode);
+        }
+
+        // Make sure the IOMMU isn't in the middle of anything before we swap
+        // its structures out from under it
+        if !time::wait_for(TIMEOUT, || registers.ddtp.read() & ddtp::BUSY == 0) {
+            return Err(IommuError::Timeout);
+        }
+        registers.ddtp.write(ddtp::MODE_OFF);
+
+        let device_directory = zalloc_page().as_phys_address();
+        let command_queue = zalloc_page().as_phys_address();
+
+        // LOG2SZ-1 in the low bits, with the PPN above them
+        let log2_size = COMMAND_QUEUE_ENTRIES.trailing_zeros() as u64;
+        registers.cqb.write(((command_queue.as_usize() as u64 >> 12) << 10) | (log2_size - 1));
+        registers.cqt.write(0);
+        registers.cqcsr.write(cqcsr::CQEN);
+        if !time::wait_for(TIMEOUT, || registers.cqcsr.read() & cqcsr::CQON != 0) {
+            return Err(IommuError::Timeout);
+        }
+
+        let fault_queue = zalloc_page().as_phys_address();
+        let log2_size = FAULT_QUEUE_ENTRIES.trailing_zeros() as u64;
+        registers.fqb.write(((fault_queue.as_usize() as u64 >> 12) << 10) | (log2_size - 1));
+        registers.fqh.write(0);
+        registers.fqcsr.write(fqcsr::FQEN | fqcsr::FIE);
+        if !time::wait_for(TIMEOUT, || registers.fqcsr.read() & fqcsr::FQON != 0) {
+            return Err(IommuError::Timeout);
+        }
+
+        registers.ddtp.write(((device_directory.as_usize() as u64 >> 12) << 10) | ddtp::MODE_1LVL);
+        if !time::wait_for(TIMEOUT, || registers.ddtp.read() & ddtp::BUSY == 0) {
+            return Err(IommuError::Timeout);
+        }
+
+        let device_context_size = match caps & capabilities::MSI_FLAT != 0 {
+            true => 64,
+            false => 32,
+        };
+
+        log::info!(
+            "IOMMU version {}.{} initialized with {} device contexts",
+            (caps >> 4) & 0xF,
+            caps & 0xF,
+            4096 / device_context_size
+        );
+
+        Ok(Self {
+            registers,
+            phandle,
+            device_context_size,
+            fault_queue,
+            state: SpinMutex::new(IommuState {
+                device_directory,
+                command_queue,
+                command_tail: 0,
+                domains: BTreeMap::new(),
+                owners: BTreeMap::new(),
+                next_pscid: 1,
+            }),
+        })
+    }
+
+    /// The phandle of the IOMMU's node in the device tree, which devices use
+    /// to refer to it
+    pub fn phandle(&self) -> Option<u32> {
+        self.phandle
+    }
+
+    /// Attach the device with the given ID to the domain of `owner`, creating
+    /// it if needed, allowing the device to access the DMA memory allocated by
+    /// the task afterwards
+    pub fn attach(&self, owner: Tid, device_id: u32) -> Result<(), IommuError> {
+        if device_id as usize >= 4096 / self.device_context_size {
+            return Err(IommuError::DeviceIdTooLarge(device_id));
+        }
+
+        let mut state = self.state.lock();
+        match state.owners.get(&device_id) {
+            Some(&tid) if tid == owner => return Ok(()),
+            Some(_) => return Err(IommuError::DeviceInUse(device_id)),
+            None => {}
+        }
+
+        if !state.domains.contains_key(&owner) {
+            let pscid = state.next_pscid;
+            state.next_pscid += 1;
+            state.domains.insert(
+                owner,
+                Domain { table: PageTable::new_raw(), pscid, iovas: IovaAllocator::new(IOVA_START, IOVA_END) },
+            );
+        }
+
+        let domain = &state.domains[&owner];
+        let (pscid, root) = (domain.pscid, domain.table.physical_address());
+
+        let context = phys2virt(state.device_directory.offset(device_id as usize * self.device_context_size))
+            .as_mut_ptr()
+            .cast::<u64>();
+
+        unsafe {
+            // `iohgatp` is left zeroed (Bare), so there's only the first
+            // stage of translation
+            context.add(2).write_volatile((pscid as u64) << 20);
+            context.add(3).write_volatile(((paging::satp_mode() as u64) << 60) | (root.as_usize() as u64 >> 12));
+            // The rest of the context has to be in memory before it's marked
+            // valid
+            core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+            context.write_volatile(1);
+        }
+
+        state.owners.insert(device_id, owner);
+        self.submit(&mut state, &[Command::invalidate_ddt(device_id), Command::fence()]);
+
+        log::debug!("Attached device {} to the IOMMU domain of task {:?}", device_id, owner);
+
+        Ok(())
+    }
+
+    /// Report and discard every record in the fault queue, returning how
+    /// many there were
+    pub fn drain_faults(&self) -> usize {
+        let queue = phys2virt(self.fault_queue).as_mut_ptr().cast::<FaultRecord>();
+
+        let status = self.registers.fqcsr.read();
+        if status & fqcsr::ERRORS != 0 {
+            log::warn!("IOMMU fault queue error, some faults weren't recorded: {:#x}", status);
+            // The error bits are cleared by writing them back, which also
+            // turns the queue back on after an overflow
+            self.registers.fqcsr.write(status);
+        }
+
+        let mut head = self.registers.fqh.read();
+        let tail = self.registers.fqt.read();
+        let mut drained = 0;
+
+        while head != tail {
+            let record = unsafe { queue.add(head as usize).read_volatile() };
+            log::warn!(
+                "IOMMU blocked an access by device {}: cause {}, address {:#x}",
+                record.device_id(),
+                record.cause(),
+                record.iotval(),
+            );
+
+            head = (head + 1) % FAULT_QUEUE_ENTRIES;
+            drained += 1;
+        }
+
+        self.registers.fqh.write(head);
+        self.registers.ipsr.write(ipsr::FIP);
+
+        drained
+    }
+
+    fn submit(&self, state: &mut IommuState, commands: &[Command]) {
+        let queue = phys2virt(state.command_queue).as_mut_ptr().cast::<Command>();
+
+        for &command in commands {
+            // The queue is full when the tail is one behind the head, so wait
+            // for the IOMMU to make room before overwriting anything
+            let next_tail = (state.command_tail + 1) % COMMAND_QUEUE_ENTRIES;
+            if self.registers.cqh.read() == next_tail {
+                self.registers.cqt.write(state.command_tail);
+                if !time::wait_for(TIMEOUT, || self.registers.cqh.read() != next_tail) {
+                    panic!("IOMMU command queue stayed full");
+                }
+            }
+
+            unsafe { queue.add(state.command_tail as usize).write_volatile(command) };
+            state.command_tail = next_tail;
+        }
+
+        // The commands need to be visible in memory before the IOMMU is told
+        // about them
+        core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+        self.registers.cqt.write(state.command_tail);
+
+        let done = time::wait_for(TIMEOUT, || {
+            let status = self.registers.cqcsr.read();
+            if status & cqcsr::ERRORS != 0 {
+                panic!("IOMMU command queue error: {:#x}", status);
+            }
+
+            self.registers.cqh.read() == state.command_tail
+        });
+
+        if !done {
+            panic!("IOMMU didn't process its command queue in time");
+        }
+    }
+}
+
+/// Report faults from the IOMMU when it doesn't have an interrupt to do so
+pub fn drain_faults_in_background() -> bool {
+    if let Some(iommu) = *crate::mem::dma::IOMMU.read() {
+        iommu.drain_faults();
+    }
+
+    false
+}
+
+impl DmaMapper for Iommu {
+    fn map(&self, owner: Tid, phys: PhysicalAddress, len: usize) -> Result<DeviceAddress, DmaMapError> {
+        let mut state = self.state.lock();
+        let domain = match state.domains.get_mut(&owner) {
+            Some(domain) => domain,
+            // The task doesn't have any devices behind the IOMMU, so any
+            // device it drives accesses physical memory directly
+            None => return Ok(DeviceAddress::new(phys.as_usize())),
+        };
+
+        let len = utils::round_up_to_next(len, 4096);
+        let iova = domain.iovas.alloc(len).ok_or(DmaMapError::OutOfAddressSpace)?;
+
+        // Device requests without a process ID are treated as user accesses,
+        // and not every IOMMU can update the accessed and dirty bits itself
+        let flags = flags::VALID | flags::READ | flags::WRITE | flags::USER | flags::ACCESSED | flags::DIRTY;
+        for offset in (0..len).step_by(4096) {
+            domain.table.map(phys.offset(offset), VirtualAddress::new(iova + offset), flags, PageSize::Kilopage);
+        }
+
+        let pscid = domain.pscid;
+        self.submit(&mut state, &[Command::invalidate_vma(pscid), Command::fence()]);
+
+        Ok(DeviceAddress::new(iova))
+    }
+
+    fn unmap(&self, owner: Tid, addr: DeviceAddress, len: usize) {
+        let mut state = self.state.lock();
+        let domain = match state.domains.get_mut(&owner) {
+            Some(domain) => domain,
+            None => return,
+        };
+
+        let len = utils::round_up_to_next(len, 4096);
+        for offset in (0..len).step_by(4096) {
+            domain.table.unmap(VirtualAddress::new(addr.as_usize() + offset));
+        }
+
+        let pscid = domain.pscid;
+        self.submit(&mut state, &[Command::invalidate_vma(pscid), Command::fence()]);
+
+        // Only hand the addresses out again once the IOMMU can no longer have
+        // the old translations cached
+        if let Some(domain) = state.domains.get_mut(&owner) {
+            domain.iovas.free(addr.as_usize(), len);
+        }
+    }
+}
+
+impl CompatibleWith for Iommu {
+    fn compatible_with() -> &'static [&'static str] {
+        &["riscv,iommu"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn iovas_are_reused() {
+        let mut iovas = IovaAllocator::new(0x1000, 0x10000);
+
+        let a = iovas.alloc(0x2000).unwrap();
+        let b = iovas.alloc(0x1000).unwrap();
+        let c = iovas.alloc(0x1000).unwrap();
+        assert_eq!((a, b, c), (0x1000, 0x3000, 0x4000));
+
+        iovas.free(a, 0x2000);
+        assert_eq!(iovas.alloc(0x1000), Some(0x1000));
+        assert_eq!(iovas.alloc(0x1000), Some(0x2000));
+        assert_eq!(iovas.alloc(0x1000), Some(0x5000));
+    }
+
+    #[test]
+    fn freed_iovas_merge() {
+        let mut iovas = IovaAllocator::new(0x1000, 0x10000);
+        let ranges = [0; 4].map(|_| iovas.alloc(0x1000).unwrap());
+
+        iovas.free(ranges[0], 0x1000);
+        iovas.free(ranges[2], 0x1000);
+        iovas.free(ranges[1], 0x1000);
+
+        // The three freed pages are one range now, so a larger allocation fits
+        assert_eq!(iovas.alloc(0x3000), Some(0x1000));
+    }
+
+    #[test]
+    fn freeing_the_top_shrinks_the_space_in_use() {
+        let mut iovas = IovaAllocator::new(0x1000, 0x4000);
+        let a = iovas.alloc(0x1000).unwrap();
+        let b = iovas.alloc(0x2000).unwrap();
+        assert_eq!(iovas.alloc(0x1000), None);
+
+        iovas.free(a, 0x1000);
+        iovas.free(b, 0x2000);
+        assert!(iovas.free.is_empty());
+        assert_eq!(iovas.alloc(0x3000), Some(0x1000));
+    }
+
+    #[test]
+    fn iova_exhaustion() {
+        let mut iovas = IovaAllocator::new(0x1000, 0x3000);
+
+        assert_eq!(iovas.alloc(0x3000), None);
+        assert_eq!(iovas.alloc(usize::MAX), None);
+        assert_eq!(iovas.alloc(0x2000), Some(0x1000));
+        assert_eq!(iovas.alloc(0x1000), None);
+    }
+}
@@ -6,6 +6,7 @@
 // obtain one at https://mozilla.org/MPL/2.0/.
 
 use crate::drivers::CompatibleWith;
+use alloc::vec::Vec;
 pub use registers::InterruptClaim;
 use volatile::{Read, ReadWrite, Volatile};
 
@@ -45,6 +46,17 @@ impl Plic {
         self.interrupt_enable[context].disable(source);
     }
 
+    /// Move every source enabled on context `from` over to context `to`
+    pub fn move_interrupts(&self, from: usize, to: usize) {
+        let mut enabled = self.interrupt_enable[to].words();
+        for (enabled, moved) in enabled.iter_mut().zip(self.interrupt_enable[from].words()) {
+            *enabled |= moved;
+        }
+
+        self.interrupt_enable[to].set_words(enabled);
+        self.interrupt_enable[from].set_words([0; 32]);
+    }
+
     pub fn set_interrupt_priority(&self, source: usize, mut priority: usize) {
         if priority > Self::max_priority() {
             log::warn!("Priority provided for source {} exceeds max priority value, setting to max", source);
@@ -65,6 +77,31 @@ impl Plic {
         self.threshold_and_claim[context].priority_threshold.set(threshold as u32)
     }
 
+    /// Save the priority of the first `n_sources` sources, and the enabled
+    /// sources and threshold of each of `contexts`
+    pub fn save(&self, n_sources: usize, contexts: impl Iterator<Item = usize>) -> PlicState {
+        PlicState {
+            priorities: (0..n_sources).map(|source| self.source_priorities[source].get()).collect(),
+            contexts: contexts
+                .map(|context| {
+                    let enabled = self.interrupt_enable[context].words();
+                    (context, enabled, self.threshold_and_claim[context].priority_threshold.get())
+                })
+                .collect(),
+        }
+    }
+
+    pub fn restore(&self, state: &PlicState) {
+        for (source, &priority) in state.priorities.iter().enumerate() {
+            self.source_priorities[source].set(priority);
+        }
+
+        for &(context, enabled, threshold) in &state.contexts {
+            self.interrupt_enable[context].set_words(enabled);
+            self.threshold_and_claim[context].priority_threshold.set(threshold);
+        }
+    }
+
     pub fn is_pending(&self, source: usize) -> bool {
         self.interrupt_pending.is_pending(source)
     }
@@ -73,6 +110,12 @@ impl Plic {
         self.threshold_and_claim[context].claim_complete.claim()
     }
 
+    /// A claim for `interrupt_id`, which must have been claimed on `context`
+    /// earlier and left incomplete, e.g. to hold off handling it for a while
+    pub fn reclaim(&self, context: usize, interrupt_id: usize) -> registers::InterruptClaim<'_> {
+        self.threshold_and_claim[context].claim_complete.reclaim(interrupt_id)
+    }
+
     pub fn complete(&self, context: usize, interrupt_id: usize) {
         self.threshold_and_claim[context].claim_complete.complete(interrupt_id);
     }
@@ -87,6 +130,15 @@ impl Plic {
     }
 }
 
+/// The configuration of the PLIC, which is lost if it's powered off while the
+/// system is suspended
+#[derive(Debug)]
+pub struct PlicState {
+    priorities: Vec<u32>,
+    /// The context, its enabled sources and its threshold
+    contexts: Vec<(usize, [u32; 32], u32)>,
+}
+
 mod registers {
     use super::*;
 
@@ -110,6 +162,10 @@ mod registers {
         pub fn set(&self, priority: u32) {
             self.0.write(priority);
         }
+
+        pub fn get(&self) -> u32 {
+            self.0.read()
+        }
     }
 
     #[derive(Debug)]
@@ -141,6 +197,21 @@ mod registers {
             let val = self.0[u32_index].read() & !(1 << bit_index);
             self.0[u32_index].write(val);
         }
+
+        pub fn words(&self) -> [u32; 32] {
+            let mut words = [0; 32];
+            for (i, word) in words.iter_mut().enumerate() {
+                *word = self.0[i].read();
+            }
+
+            words
+        }
+
+        pub fn set_words(&self, words: [u32; 32]) {
+            for (i, word) in words.into_iter().enumerate() {
+                self.0[i].write(word);
+            }
+        }
     }
 
     #[derive(Debug)]
@@ -151,6 +222,10 @@ mod registers {
         pub fn set(&self, priority: u32) {
             self.0.write(priority);
         }
+
+        pub fn get(&self) -> u32 {
+            self.0.read()
+        }
     }
 
     #[derive(Debug)]
@@ -165,6 +240,10 @@ mod registers {
             }
         }
 
+        pub(super) fn reclaim(&self, interrupt_id: usize) -> InterruptClaim<'_> {
+            InterruptClaim { interrupt_id, register: self }
+        }
+
         // Don't make this public to other consumers, they either need to
         // complete the claim as normal or go through the PLIC method explicitly
         pub(super) fn complete(&self, interrupt_id: usize) {
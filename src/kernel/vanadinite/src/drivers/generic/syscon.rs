@@ -0,0 +1,54 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Powering off and rebooting by writing to a register of a system controller,
+//! as described by the `syscon-poweroff` and `syscon-reboot` device tree nodes
+
+use crate::mem::{paging::PhysicalAddress, phys2virt};
+
+#[derive(Debug, Clone, Copy)]
+pub struct SysconReset {
+    register: *mut u32,
+    value: u32,
+    mask: u32,
+}
+
+impl SysconReset {
+    /// Find the register described by the node compatible with `compatible`,
+    /// which is either `syscon-poweroff` or `syscon-reboot`
+    pub fn find(fdt: &fdt::Fdt<'_>, compatible: &str) -> Option<Self> {
+        let node = fdt.find_compatible(&[compatible])?;
+        let regmap = node.property("regmap")?.as_usize()?;
+        let offset = node.property("offset").and_then(|p| p.as_usize()).unwrap_or(0);
+
+        // Older device trees only have a `mask`, which is used as the value
+        let (value, mask) =
+            match (node.property("value").and_then(|p| p.as_usize()), node.property("mask").and_then(|p| p.as_usize()))
+            {
+                (Some(value), Some(mask)) => (value as u32, mask as u32),
+                (Some(value), None) | (None, Some(value)) => (value as u32, u32::MAX),
+                (None, None) => return None,
+            };
+
+        let controller = fdt.all_nodes().find(|n| n.property("phandle").and_then(|p| p.as_usize()) == Some(regmap))?;
+        let base = PhysicalAddress::from_ptr(controller.reg()?.next()?.starting_address);
+        let register = phys2virt(base.offset(offset)).as_mut_ptr().cast();
+
+        Some(Self { register, value, mask })
+    }
+
+    /// # Safety
+    /// The device tree the register was found in must describe it correctly
+    pub unsafe fn reset(&self) {
+        let current = match self.mask {
+            u32::MAX => 0,
+            _ => core::ptr::read_volatile(self.register),
+        };
+
+        core::ptr::write_volatile(self.register, (current & !self.mask) | (self.value & self.mask));
+    }
+}
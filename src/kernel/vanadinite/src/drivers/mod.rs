@@ -5,20 +5,26 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
-pub mod sifive {
-    pub mod fu540_c000 {
-        pub mod uart;
-    }
-}
-
 pub mod generic {
+    #[cfg(feature = "driver.dw_wdt")]
+    pub mod dw_wdt;
+    #[cfg(feature = "driver.riscv_iommu")]
+    pub mod iommu;
     pub mod plic;
-    pub mod uart16550;
+    #[cfg(feature = "driver.syscon")]
+    pub mod syscon;
 }
 
-pub trait CompatibleWith {
-    fn compatible_with() -> &'static [&'static str];
-}
+pub use driver_registry::{drivers, find_compatible, CompatibleWith, Driver, DriverKind, SuspendResume};
+
+// Driver crates are only linked into the kernel if something references them,
+// so make sure the registration of every enabled driver ends up in the registry
+#[cfg(feature = "driver.sifive_uart")]
+#[used]
+static SIFIVE_UART: &Driver = &sifive_uart::DRIVER;
+#[cfg(feature = "driver.uart16550")]
+#[used]
+static UART16550: &Driver = &uart16550::DRIVER;
 
 pub trait InterruptServicable {
     fn isr(source: usize, private: usize) -> Result<(), &'static str>;
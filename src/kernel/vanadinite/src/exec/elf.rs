@@ -0,0 +1,211 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::{ExecutableFormat, LoadError, LoadedImage};
+use crate::{
+    mem::{
+        manager::{AddressRegionKind, FillOption, MemoryManager, RegionDescription},
+        paging::{
+            flags::{EXECUTE, READ, USER, VALID, WRITE},
+            PageSize, VirtualAddress,
+        },
+    },
+    utils::{round_up_to_next, Units},
+};
+use alloc::collections::BTreeMap;
+use elf64::{Elf, ProgramSegmentType, Relocation};
+
+const MAGIC: &[u8] = b"\x7FELF";
+
+/// Position independent, statically linked ELF executables
+#[derive(Debug, Clone, Copy)]
+pub struct ElfFormat;
+
+impl ExecutableFormat for ElfFormat {
+    fn name(&self) -> &'static str {
+        "ELF"
+    }
+
+    fn recognizes(&self, image: &[u8]) -> bool {
+        image.starts_with(MAGIC)
+    }
+
+    fn load(&self, image: &[u8], memory_manager: &mut MemoryManager) -> Result<LoadedImage, LoadError> {
+        let elf = Elf::new(image).ok_or(LoadError::Malformed("invalid ELF headers"))?;
+
+        let relocations = elf
+            .relocations()
+            .map(|reloc| match reloc {
+                Relocation::Rel(rel) => (VirtualAddress::new(rel.offset as usize), reloc),
+                Relocation::Rela(rela) => (VirtualAddress::new(rela.offset as usize), reloc),
+            })
+            .collect::<BTreeMap<VirtualAddress, Relocation>>();
+
+        // Try to estimate the size of the buffer we'll need
+        let (total_size, max_file_size) = elf.load_segments().fold((0, 0), |(sum, max), header| {
+            (
+                round_up_to_next(sum, header.align as usize)
+                    + round_up_to_next(header.memory_size as usize, header.align as usize),
+                max.max(header.file_size as usize),
+            )
+        });
+
+        // See if we have a RELRO section to fix up
+        let relro = elf
+            .program_headers()
+            .find(|header| header.r#type == ProgramSegmentType::GnuRelro)
+            .map(|header| header.vaddr as usize);
+
+        if total_size % 4.kib() != 0 {
+            return Err(LoadError::Malformed("load segments not totally whole pages"));
+        }
+
+        // FIXME: first segment load might be `2.mib()`, so prob need a `match` here
+        let task_load_base = memory_manager.find_free_region(PageSize::Kilopage, total_size / 4.kib());
+        let mut segment_offset = task_load_base;
+        let mut segment_data = alloc::vec![0; max_file_size];
+        let mut pc = None;
+        let elf_entry = VirtualAddress::new(elf.header.entry as usize);
+
+        for header in elf.load_segments() {
+            let align = header.align as usize;
+            let mem_size = header.memory_size as usize;
+            let vaddr = header.vaddr as usize;
+            let file_size = header.file_size as usize;
+            let is_relro = Some(vaddr) == relro;
+
+            if !align.is_power_of_two() {
+                return Err(LoadError::Malformed("segment alignment isn't a power of two"));
+            }
+
+            if mem_size < file_size {
+                return Err(LoadError::Malformed("segment has less data in memory than in the file"));
+            }
+
+            // Need to align-up the segment offset we were given here
+            let segment_load_base = VirtualAddress::new(round_up_to_next(segment_offset.as_usize(), align));
+            // Grab the bottom bits that we need to start writing data at
+            let segment_load_offset = vaddr & (align - 1);
+            // The total segment length we need is the size in the file + the
+            // above offset since we start at the aligned address
+            let segment_len = file_size + segment_load_offset;
+            // The total size in memory rounded up to the next alignment for the
+            // segment
+            let region_size = round_up_to_next(mem_size + segment_load_offset, align);
+
+            if segment_data.len() < segment_len {
+                segment_data.resize(segment_len, 0);
+            }
+
+            // Copy the segment data starting at the offset
+            segment_data[segment_load_offset..][..file_size].copy_from_slice(elf.program_segment_data(&header));
+
+            // We use these values to key off of some information (e.g.
+            // relocation calculations and calculating the PC)
+            let raw_segment_start = VirtualAddress::new(header.vaddr as usize);
+            let raw_segment_end = raw_segment_start.add(header.memory_size as usize);
+            let raw_segment_range = raw_segment_start..raw_segment_end;
+
+            // The real PC needs calculated from the offset, so we check to see
+            // if this is the segment that contains the entry point
+            if raw_segment_range.contains(&elf_entry) {
+                let offset = elf_entry.as_usize() - raw_segment_start.as_usize() + segment_load_offset;
+                pc = Some(segment_load_base.add(offset));
+            }
+
+            // Find any relocations and fix them up before we write the memory
+            // so we don't need to deal with the `UniquePhysicalRegion` which
+            // doesn't play nice with arbitrary indexing since the physical
+            // pages aren't guaranteed to be contiguous here so we can reuse
+            // memory
+            for (_, relocation) in relocations.range(raw_segment_start..raw_segment_end) {
+                match relocation {
+                    Relocation::Rel(_) => return Err(LoadError::Unsupported("rel relocations")),
+                    Relocation::Rela(rela) => {
+                        let offset_into = rela.offset as usize - raw_segment_start.as_usize() + segment_load_offset;
+
+                        match rela.r#type {
+                            // RELATIVE
+                            3 => {
+                                // FIXME: Should prob check for negative addends?
+                                let fixup = task_load_base.as_usize() + rela.addend as usize;
+                                segment_data[offset_into..][..8].copy_from_slice(&fixup.to_le_bytes());
+                            }
+                            n => {
+                                log::warn!("Unsupported ELF relocation type: {}", n);
+                                return Err(LoadError::Unsupported("relocation type"));
+                            }
+                        }
+                    }
+                }
+            }
+
+            // RELRO will override any other permission flags here, so check to
+            // see if the region we just processed is the RELRO segment
+            let (kind, flags) = match (is_relro, header.flags) {
+                (true, _) => (AddressRegionKind::ReadOnly, USER | READ | VALID),
+                (false, 0b101) => (AddressRegionKind::Text, USER | READ | EXECUTE | VALID),
+                (false, 0b110) => (AddressRegionKind::Data, USER | READ | WRITE | VALID),
+                (false, 0b100) => (AddressRegionKind::ReadOnly, USER | READ | VALID),
+                (false, _) => return Err(LoadError::Unsupported("segment permissions")),
+            };
+
+            memory_manager.alloc_region(
+                Some(segment_load_base),
+                RegionDescription {
+                    size: PageSize::Kilopage,
+                    len: region_size / 4.kib(),
+                    contiguous: false,
+                    flags,
+                    fill: FillOption::Data(&segment_data[..segment_len]),
+                    kind,
+                },
+            );
+
+            segment_offset = segment_load_base.add(region_size);
+        }
+
+        let tls = elf.program_headers().find(|header| header.r#type == ProgramSegmentType::Tls).map(|header| {
+            let n_pages_needed = round_up_to_next(header.memory_size as usize + 8 + 16, 4.kib()) / 4.kib();
+            let tls_base = memory_manager.find_free_region(PageSize::Kilopage, n_pages_needed);
+
+            let segment_len = header.file_size as usize + 8 + 16;
+
+            if segment_data.len() < segment_len {
+                segment_data.resize(segment_len, 0);
+            }
+
+            let segment_file_size = header.file_size as usize;
+            let tls_base_addr = tls_base.as_usize();
+            segment_data[0..][..8].copy_from_slice(&(tls_base_addr + 8).to_le_bytes()[..]); // ->|
+            segment_data[8..][..8].copy_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0]); // <-|
+            segment_data[16..][..8].copy_from_slice(&(tls_base_addr + 24).to_le_bytes()[..]);
+
+            segment_data[24..][..segment_file_size].copy_from_slice(elf.program_segment_data(&header));
+            segment_data[segment_file_size..segment_len].fill(0);
+
+            memory_manager.alloc_region(
+                Some(tls_base),
+                RegionDescription {
+                    size: PageSize::Kilopage,
+                    len: n_pages_needed,
+                    contiguous: false,
+                    flags: USER | READ | WRITE | VALID,
+                    fill: FillOption::Data(&segment_data[..segment_len]),
+                    kind: AddressRegionKind::Tls,
+                },
+            );
+
+            VirtualAddress::new(tls_base_addr + 24)
+        });
+
+        match pc {
+            Some(entry) => Ok(LoadedImage { entry, thread_pointer: tls }),
+            None => Err(LoadError::Malformed("entry point isn't in a load segment")),
+        }
+    }
+}
@@ -0,0 +1,56 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::{ExecutableFormat, LoadError, LoadedImage};
+use crate::{
+    mem::{
+        manager::{AddressRegionKind, FillOption, MemoryManager, RegionDescription},
+        paging::{
+            flags::{EXECUTE, READ, USER, VALID},
+            PageSize,
+        },
+    },
+    utils::{round_up_to_next, Units},
+};
+
+/// Raw position independent code, mapped read-execute wherever there's room
+/// and entered at its first byte. Meant for tiny test payloads which don't need
+/// writable data outside of their stack. There's no header to recognize these
+/// by, so this format accepts anything the formats before it didn't.
+#[derive(Debug, Clone, Copy)]
+pub struct FlatBinaryFormat;
+
+impl ExecutableFormat for FlatBinaryFormat {
+    fn name(&self) -> &'static str {
+        "flat binary"
+    }
+
+    fn recognizes(&self, image: &[u8]) -> bool {
+        !image.is_empty()
+    }
+
+    fn load(&self, image: &[u8], memory_manager: &mut MemoryManager) -> Result<LoadedImage, LoadError> {
+        if image.is_empty() {
+            return Err(LoadError::Malformed("empty image"));
+        }
+
+        let n_pages = round_up_to_next(image.len(), 4.kib()) / 4.kib();
+        let entry = memory_manager.alloc_region(
+            None,
+            RegionDescription {
+                size: PageSize::Kilopage,
+                len: n_pages,
+                contiguous: false,
+                flags: USER | READ | EXECUTE | VALID,
+                fill: FillOption::Data(image),
+                kind: AddressRegionKind::Text,
+            },
+        );
+
+        Ok(LoadedImage { entry: entry.start, thread_pointer: None })
+    }
+}
@@ -0,0 +1,84 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Executable image formats
+//!
+//! Loading a task from an image goes through the [`ExecutableFormat`]s
+//! registered here, which are asked in turn whether they recognize the image
+//! until one does. ELF and flat binaries are always available, and other
+//! formats can be added with [`register`] without the task loading code having
+//! to know about them.
+
+pub mod elf;
+pub mod flat;
+
+use crate::mem::{manager::MemoryManager, paging::VirtualAddress};
+use alloc::vec::Vec;
+use sync::SpinRwLock;
+
+/// Formats are tried from the front, and the flat binary format recognizes
+/// anything so it has to stay last
+static FORMATS: SpinRwLock<Vec<&'static dyn ExecutableFormat>> = SpinRwLock::new(Vec::new());
+
+pub trait ExecutableFormat: Send + Sync {
+    /// A name for the format to use in logs
+    fn name(&self) -> &'static str;
+    /// Whether `image` looks like it's in this format. This should only check
+    /// enough to rule out other formats, e.g. a magic number, and leave
+    /// validating the rest of the image to [`ExecutableFormat::load`].
+    fn recognizes(&self, image: &[u8]) -> bool;
+    /// Map `image` into `memory_manager`, which is otherwise empty
+    fn load(&self, image: &[u8], memory_manager: &mut MemoryManager) -> Result<LoadedImage, LoadError>;
+}
+
+/// Where to start running a loaded image
+#[derive(Debug, Clone, Copy)]
+pub struct LoadedImage {
+    pub entry: VirtualAddress,
+    /// The initial thread pointer, if the image has thread-local storage
+    pub thread_pointer: Option<VirtualAddress>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    /// None of the registered formats recognized the image
+    UnknownFormat,
+    /// The image was recognized but couldn't be loaded
+    Malformed(&'static str),
+    /// The image uses something the loader doesn't support yet
+    Unsupported(&'static str),
+}
+
+/// Add `format` to the formats tried when loading an image, ahead of all of
+/// the ones already registered except for ELF
+pub fn register(format: &'static dyn ExecutableFormat) {
+    let mut formats = FORMATS.write();
+    add_default_formats(&mut formats);
+
+    log::debug!("Registering executable format: {}", format.name());
+    formats.insert(1, format);
+}
+
+/// Load `image` into `memory_manager` with the first format that recognizes it
+pub fn load(image: &[u8], memory_manager: &mut MemoryManager) -> Result<LoadedImage, LoadError> {
+    let format = {
+        let mut formats = FORMATS.write();
+        add_default_formats(&mut formats);
+
+        formats.iter().copied().find(|format| format.recognizes(image)).ok_or(LoadError::UnknownFormat)?
+    };
+
+    log::debug!("Loading {} byte {} image", image.len(), format.name());
+    format.load(image, memory_manager)
+}
+
+fn add_default_formats(formats: &mut Vec<&'static dyn ExecutableFormat>) {
+    if formats.is_empty() {
+        formats.push(&elf::ElfFormat);
+        formats.push(&flat::FlatBinaryFormat);
+    }
+}
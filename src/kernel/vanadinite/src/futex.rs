@@ -0,0 +1,193 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Futexes
+//!
+//! A futex is a 32-bit word in user memory which tasks can block on until
+//! another task wakes them up. Waiters are keyed on the physical address of the
+//! word, so tasks sharing memory can use futexes in it to synchronize with each
+//! other no matter where they have it mapped.
+
+use crate::{
+    csr,
+    mem::paging::PhysicalAddress,
+    scheduler::{
+        timer::{self, Timer, TimerHandle},
+        Scheduler, WakeToken, SCHEDULER, TASKS,
+    },
+    time,
+};
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    vec::Vec,
+};
+use librust::task::Tid;
+use sync::SpinMutex;
+
+static FUTEXES: SpinMutex<Futexes> = SpinMutex::new(Futexes { queues: BTreeMap::new(), waiters: BTreeMap::new() });
+
+struct Futexes {
+    /// Tasks waiting on each futex, in the order they started waiting
+    queues: BTreeMap<PhysicalAddress, VecDeque<Tid>>,
+    waiters: BTreeMap<Tid, Waiter>,
+}
+
+impl Futexes {
+    /// Stop up to `n` of the tasks waiting on the futex at `key` from waiting,
+    /// oldest first
+    fn take(&mut self, key: PhysicalAddress, n: usize) -> Vec<(Tid, Waiter)> {
+        let queue = match self.queues.get_mut(&key) {
+            Some(queue) => queue,
+            None => return Vec::new(),
+        };
+
+        let tids = queue.drain(..n.min(queue.len())).collect::<Vec<_>>();
+        if queue.is_empty() {
+            self.queues.remove(&key);
+        }
+
+        tids.into_iter().filter_map(|tid| Some((tid, self.waiters.remove(&tid)?))).collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Waiter {
+    key: PhysicalAddress,
+    /// In `time` CSR ticks
+    deadline: Option<u64>,
+    timeout: Option<TimerHandle>,
+}
+
+/// Start waiting on the futex at `key` if `still_expected` returns `true`,
+/// giving up after `timeout_us` microseconds if it's `Some`. Returns whether
+/// the task is now waiting, in which case it has to block right away.
+///
+/// `still_expected` is called with the futex lock held, so a wake can't be
+/// missed between it checking the futex word and the task waiting.
+pub fn wait(tid: Tid, key: PhysicalAddress, still_expected: impl FnOnce() -> bool, timeout_us: Option<u64>) -> bool {
+    let mut futexes = FUTEXES.lock();
+    if !still_expected() {
+        return false;
+    }
+
+    let deadline = timeout_us.map(timer::deadline_after);
+    let timeout = deadline.map(|deadline| timer::arm_at(tid, deadline, Timer::FutexTimeout));
+    futexes.queues.entry(key).or_default().push_back(tid);
+    futexes.waiters.insert(tid, Waiter { key, deadline, timeout });
+
+    true
+}
+
+/// Wake up to `n` tasks waiting on the futex at `key`, returning how many were
+/// woken
+pub fn wake(key: PhysicalAddress, n: usize) -> usize {
+    let woken = FUTEXES.lock().take(key, n);
+    let n_woken = woken.len();
+    resume_woken(woken);
+
+    n_woken
+}
+
+/// Wake up to `n_wake` tasks waiting on the futex at `from` if
+/// `still_expected` returns `true`, and move up to `n_requeue` of the rest to
+/// wait on the futex at `to` instead. Returns how many tasks were woken and
+/// requeued, or `None` if `still_expected` returned `false`.
+///
+/// This lets e.g. a condition variable wake a single waiter and hand the rest
+/// over to a mutex, rather than waking all of them just to fight over it.
+pub fn requeue(
+    from: PhysicalAddress,
+    to: PhysicalAddress,
+    still_expected: impl FnOnce() -> bool,
+    n_wake: usize,
+    n_requeue: usize,
+) -> Option<(usize, usize)> {
+    let mut futexes = FUTEXES.lock();
+    if !still_expected() {
+        return None;
+    }
+
+    let woken = futexes.take(from, n_wake);
+    let n_requeued = match from == to {
+        true => 0,
+        false => {
+            let moved = futexes.take(from, n_requeue);
+            for &(tid, waiter) in &moved {
+                futexes.queues.entry(to).or_default().push_back(tid);
+                futexes.waiters.insert(tid, Waiter { key: to, ..waiter });
+            }
+
+            moved.len()
+        }
+    };
+    drop(futexes);
+
+    let n_woken = woken.len();
+    resume_woken(woken);
+
+    Some((n_woken, n_requeued))
+}
+
+/// Give up on `tid`'s futex wait, if it's still waiting with the timeout
+/// `timeout`. A timeout which failed to be cancelled can fire after the task
+/// has moved on to waiting on another futex.
+pub fn time_out(tid: Tid, timeout: TimerHandle) {
+    {
+        let mut futexes = FUTEXES.lock();
+        let waiter = match futexes.waiters.get(&tid) {
+            Some(waiter) if waiter.timeout == Some(timeout) => *waiter,
+            _ => return,
+        };
+        futexes.waiters.remove(&tid);
+
+        if let Some(queue) = futexes.queues.get_mut(&waiter.key) {
+            queue.retain(|&waiting| waiting != tid);
+            if queue.is_empty() {
+                futexes.queues.remove(&waiter.key);
+            }
+        }
+    }
+
+    resume(tid, false, 0);
+}
+
+fn resume_woken(woken: Vec<(Tid, Waiter)>) {
+    for (tid, waiter) in woken {
+        let remaining_us = match (waiter.deadline, waiter.timeout) {
+            (Some(deadline), Some(timeout)) => {
+                timer::cancel(timeout);
+
+                let remaining = time::duration_from_ticks(deadline.saturating_sub(csr::time::read()));
+                remaining.as_micros().min(usize::MAX as u128) as usize
+            }
+            _ => usize::MAX,
+        };
+
+        resume(tid, true, remaining_us);
+    }
+}
+
+/// Return from `tid`'s futex wait syscall with whether it was woken up and the
+/// time it had left until its timeout
+fn resume(tid: Tid, woken: bool, remaining_us: usize) {
+    // The task might have exited while it was waiting
+    if TASKS.get(tid).is_none() {
+        return;
+    }
+
+    // The waiter commits to blocking while it holds the futex lock, but could
+    // still be on its way into the scheduler on another hart
+    while !SCHEDULER.is_blocked(tid) {
+        core::hint::spin_loop();
+    }
+
+    SCHEDULER.unblock(WakeToken::new(tid, move |task| {
+        task.context.gp_regs.a0 = 0;
+        task.context.gp_regs.a1 = usize::from(woken);
+        task.context.gp_regs.a2 = remaining_us;
+    }));
+}
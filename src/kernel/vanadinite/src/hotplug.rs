@@ -0,0 +1,184 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Taking harts offline and bringing them back online at runtime
+//!
+//! Taking a hart offline stops the scheduler from putting new tasks on it and
+//! sends it an IPI. The hart then hands everything it had going on over to the
+//! harts still online, from its tasks and pinned background work to its timers
+//! and the external interrupts routed to it, and stops itself with the SBI HSM
+//! extension. Bringing it back online starts it again the same way it was
+//! started at boot.
+//!
+//! There's always at least one hart left online, though it doesn't have to be
+//! the boot hart.
+
+use crate::{
+    boot::early_paging::BOOTSTRAP_SATP,
+    csr::{self, satp::Satp},
+    drivers::generic::plic::Plic,
+    interrupts::PLIC,
+    mem, platform,
+    scheduler::{timer, Scheduler, SCHEDULER},
+    worker, HART_ID, N_CPUS,
+};
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::sync::atomic::Ordering;
+use sync::SpinMutex;
+
+const IPI_EXTENSION: usize = 0x735049;
+
+/// Harts which have never been started aren't in here, and count as offline
+static HARTS: SpinMutex<BTreeMap<usize, HartState>> = SpinMutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HartState {
+    Online,
+    /// Asked to go offline, but hasn't stopped yet
+    Stopping,
+    Offline,
+    /// Started, but hasn't made it to the scheduler yet
+    Starting,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HotplugError {
+    /// There's no hart with the given ID
+    NoSuchHart,
+    AlreadyOnline,
+    AlreadyOffline,
+    /// The hart is the only one left online
+    LastHart,
+    /// The hart is still on its way online or offline
+    Busy,
+    /// The firmware couldn't start the hart
+    StartFailed,
+}
+
+/// Record that `hart_id` has booted and is about to start scheduling tasks
+pub fn hart_online(hart_id: usize) {
+    let previous = HARTS.lock().insert(hart_id, HartState::Online);
+
+    // Every hart starts out online as far as the scheduler is concerned, so it
+    // only needs telling when one comes back
+    if previous == Some(HartState::Starting) {
+        SCHEDULER.set_hart_online(hart_id, true);
+    }
+}
+
+/// The harts which are online
+pub fn online_harts() -> Vec<usize> {
+    HARTS.lock().iter().filter(|(_, state)| **state == HartState::Online).map(|(hart, _)| *hart).collect()
+}
+
+pub fn is_online(hart_id: usize) -> bool {
+    HARTS.lock().get(&hart_id) == Some(&HartState::Online)
+}
+
+/// Ask `hart_id` to go offline. It stops as soon as it takes the IPI, which for
+/// the current hart is once it's returned to userspace.
+pub fn offline(hart_id: usize) -> Result<(), HotplugError> {
+    if hart_id >= N_CPUS.load(Ordering::Acquire) {
+        return Err(HotplugError::NoSuchHart);
+    }
+
+    let mut harts = HARTS.lock();
+    match harts.get(&hart_id).copied().unwrap_or(HartState::Offline) {
+        HartState::Online => {}
+        HartState::Offline => return Err(HotplugError::AlreadyOffline),
+        HartState::Stopping | HartState::Starting => return Err(HotplugError::Busy),
+    }
+
+    if harts.values().filter(|state| **state == HartState::Online).count() == 1 {
+        return Err(HotplugError::LastHart);
+    }
+
+    harts.insert(hart_id, HartState::Stopping);
+    drop(harts);
+
+    log::info!("Taking hart {} offline", hart_id);
+    SCHEDULER.set_hart_online(hart_id, false);
+    send_ipi(hart_id);
+
+    Ok(())
+}
+
+/// Start `hart_id` back up after it's been taken offline
+pub fn online(hart_id: usize) -> Result<(), HotplugError> {
+    if hart_id >= N_CPUS.load(Ordering::Acquire) {
+        return Err(HotplugError::NoSuchHart);
+    }
+
+    let mut harts = HARTS.lock();
+    match harts.get(&hart_id).copied().unwrap_or(HartState::Offline) {
+        HartState::Offline => {}
+        HartState::Online => return Err(HotplugError::AlreadyOnline),
+        HartState::Stopping | HartState::Starting => return Err(HotplugError::Busy),
+    }
+
+    harts.insert(hart_id, HartState::Starting);
+    drop(harts);
+
+    log::info!("Bringing hart {} online", hart_id);
+    if let Err(e) = crate::start_hart(hart_id) {
+        // This includes the hart having only just marked itself offline, and
+        // not having actually stopped yet
+        log::warn!("Failed to start hart {}: {:?}", hart_id, e);
+        HARTS.lock().insert(hart_id, HartState::Offline);
+        return Err(HotplugError::StartFailed);
+    }
+
+    Ok(())
+}
+
+/// Whether the current hart has been asked to go offline. This is checked on
+/// every supervisor software interrupt, and [`stop`] must be called if it has.
+pub fn stop_requested() -> bool {
+    HARTS.lock().get(&HART_ID.get()) == Some(&HartState::Stopping)
+}
+
+/// Hand everything on the current hart over to the other harts and stop it.
+/// The context of the task which was running must have been saved already.
+pub fn stop() -> ! {
+    let current = HART_ID.get();
+
+    // None of the tasks will be run here again, and the work being handed off
+    // could free the last one's page table
+    csr::satp::write(Satp::from_usize(BOOTSTRAP_SATP.load(Ordering::Acquire)));
+    mem::sfence(None, None);
+    mem::shootdown::set_inactive();
+
+    SCHEDULER.migrate_tasks();
+    worker::release_pinned();
+
+    let heir = online_harts()[0];
+    timer::hand_off(heir);
+
+    if let Some(plic) = &*PLIC.lock() {
+        let context = platform::current_plic_context();
+        plic.move_interrupts(context, platform::plic_context_for(heir));
+        plic.set_context_threshold(context, Plic::max_priority());
+    }
+
+    // Gets the heir to pick up the timers
+    send_ipi(heir);
+    sbi::timer::set_timer(u64::MAX).unwrap();
+
+    log::info!("Hart {} going offline", current);
+    HARTS.lock().insert(current, HartState::Offline);
+
+    sbi::hart_state_management::hart_stop().unwrap();
+    #[allow(unreachable_code)]
+    loop {}
+}
+
+pub fn send_ipi(hart_id: usize) {
+    // A mask of just the first hart, counting from `hart_id`
+    if crate::suspend::sbi_call(IPI_EXTENSION, 0, 1, hart_id, 0) != 0 {
+        log::warn!("Failed to send an IPI to hart {}", hart_id);
+    }
+}
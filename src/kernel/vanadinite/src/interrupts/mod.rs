@@ -7,7 +7,8 @@
 
 pub mod isr;
 
-use crate::drivers::generic::plic;
+use crate::drivers::{generic::plic, SuspendResume};
+use alloc::vec::Vec;
 use sync::SpinMutex;
 
 pub static PLIC: SpinMutex<Option<&'static plic::Plic>> = SpinMutex::new(None);
@@ -16,6 +17,33 @@ pub fn register_plic(plic: &'static plic::Plic) {
     *PLIC.lock() = Some(plic);
 }
 
+/// Saves and restores the PLIC's configuration around a suspend
+pub struct PlicSuspend {
+    plic: &'static plic::Plic,
+    n_sources: usize,
+    contexts: Vec<usize>,
+    saved: Option<plic::PlicState>,
+}
+
+impl PlicSuspend {
+    pub fn new(plic: &'static plic::Plic, n_sources: usize, contexts: Vec<usize>) -> Self {
+        Self { plic, n_sources, contexts, saved: None }
+    }
+}
+
+impl SuspendResume for PlicSuspend {
+    fn suspend(&mut self) -> Result<(), &'static str> {
+        self.saved = Some(self.plic.save(self.n_sources, self.contexts.iter().copied()));
+        Ok(())
+    }
+
+    fn resume(&mut self) {
+        if let Some(saved) = self.saved.take() {
+            self.plic.restore(&saved);
+        }
+    }
+}
+
 pub struct InterruptDisabler(bool);
 
 impl InterruptDisabler {
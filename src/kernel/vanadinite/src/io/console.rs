@@ -6,27 +6,14 @@
 // obtain one at https://mozilla.org/MPL/2.0/.
 
 use crate::{
-    drivers::{generic::uart16550::Uart16550, sifive::fu540_c000::uart::SifiveUart, CompatibleWith},
+    drivers::{drivers, Driver, DriverKind},
     interrupts::isr::register_isr,
 };
+use core::sync::atomic::{AtomicUsize, Ordering};
+pub use driver_registry::ConsoleDevice;
+use librust::syscalls::io::ConsoleSinks;
 use sync::SpinMutex;
 
-pub trait ConsoleDevice: 'static {
-    fn init(&mut self);
-    fn read(&self) -> u8;
-    fn write(&mut self, n: u8);
-}
-
-impl core::fmt::Write for dyn ConsoleDevice {
-    fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        for byte in s.as_bytes() {
-            self.write(*byte);
-        }
-
-        Ok(())
-    }
-}
-
 pub struct StaticConsoleDevice(Option<&'static mut dyn ConsoleDevice>);
 
 impl core::fmt::Write for StaticConsoleDevice {
@@ -67,6 +54,100 @@ unsafe impl Send for StaticConsoleDevice {}
 unsafe impl Sync for StaticConsoleDevice {}
 
 pub static CONSOLE: SpinMutex<StaticConsoleDevice> = SpinMutex::new(StaticConsoleDevice(None));
+pub static CONSOLE_RING: SpinMutex<ConsoleRing> = SpinMutex::new(ConsoleRing::new());
+static CONSOLE_SINKS: AtomicUsize = AtomicUsize::new(ConsoleSinks::DEVICE.value());
+
+const CONSOLE_RING_SIZE: usize = 16 * 1024;
+
+/// Keeps the most recent console output around, so it isn't lost on boards
+/// without a console device and can be read by tasks which hold the console
+/// capability
+pub struct ConsoleRing {
+    buffer: [u8; CONSOLE_RING_SIZE],
+    // Where the next byte will be written
+    head: usize,
+    len: usize,
+    // How many bytes have been written since boot
+    written: u64,
+}
+
+impl ConsoleRing {
+    const fn new() -> Self {
+        Self { buffer: [0; CONSOLE_RING_SIZE], head: 0, len: 0, written: 0 }
+    }
+
+    pub fn write(&mut self, n: u8) {
+        self.buffer[self.head] = n;
+        self.head = (self.head + 1) % CONSOLE_RING_SIZE;
+        self.len = (self.len + 1).min(CONSOLE_RING_SIZE);
+        self.written += 1;
+    }
+
+    /// Copy the output starting `position` bytes into it into `out`, returning
+    /// how many bytes were copied and the position following them. Anything
+    /// before `position` which has already been overwritten is skipped.
+    pub fn read_from(&self, position: u64, out: &mut [u8]) -> (usize, u64) {
+        let start = position.clamp(self.written - self.len as u64, self.written);
+        let available = (self.written - start) as usize;
+        let first = (self.head + CONSOLE_RING_SIZE - available) % CONSOLE_RING_SIZE;
+
+        let n = available.min(out.len());
+        for (i, byte) in out[..n].iter_mut().enumerate() {
+            *byte = self.buffer[(first + i) % CONSOLE_RING_SIZE];
+        }
+
+        (n, start + n as u64)
+    }
+
+    /// The buffered output, oldest first, split in two where it wraps around
+    pub fn contents(&self) -> (&[u8], &[u8]) {
+        match self.len == CONSOLE_RING_SIZE {
+            true => (&self.buffer[self.head..], &self.buffer[..self.head]),
+            false => (&self.buffer[..self.head], &[]),
+        }
+    }
+}
+
+impl core::fmt::Write for ConsoleRing {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        s.bytes().for_each(|b| self.write(b));
+        Ok(())
+    }
+}
+
+pub fn console_sinks() -> ConsoleSinks {
+    ConsoleSinks::new(CONSOLE_SINKS.load(Ordering::Relaxed))
+}
+
+/// Change where console output is sent, returning the previous sinks
+pub fn set_console_sinks(sinks: ConsoleSinks) -> ConsoleSinks {
+    ConsoleSinks::new(CONSOLE_SINKS.swap(sinks.value(), Ordering::Relaxed))
+}
+
+/// Parse a comma separated list of sinks, e.g. `device,ring`
+pub fn parse_console_sinks(list: &str) -> Option<ConsoleSinks> {
+    list.split(',').try_fold(ConsoleSinks::NONE, |sinks, sink| match sink {
+        "device" => Some(sinks | ConsoleSinks::DEVICE),
+        "ring" => Some(sinks | ConsoleSinks::RING),
+        "none" => Some(sinks),
+        _ => None,
+    })
+}
+
+/// Write raw bytes to every active console sink
+pub fn write_bytes(bytes: &[u8]) {
+    let sinks = console_sinks();
+
+    if sinks & ConsoleSinks::DEVICE {
+        let mut console = CONSOLE.lock();
+        bytes.iter().for_each(|&b| console.write(b));
+    }
+
+    if sinks & ConsoleSinks::RING {
+        let mut ring = CONSOLE_RING.lock();
+        bytes.iter().for_each(|&b| ring.write(b));
+    }
+}
 
 /// # Safety
 ///
@@ -86,37 +167,30 @@ pub fn set_console(device: &'static mut dyn ConsoleDevice) {
     *CONSOLE.lock() = StaticConsoleDevice(Some(device));
 }
 
-pub enum ConsoleDevices {
-    Uart16550,
-    SifiveUart,
-}
+/// A registered driver for the device being used as the console
+#[derive(Debug, Clone, Copy)]
+pub struct ConsoleDriver(&'static Driver);
 
-impl ConsoleDevices {
+impl ConsoleDriver {
     pub fn from_compatible(compatible: fdt::standard_nodes::Compatible<'_>) -> Option<Self> {
-        if compatible.all().any(|s| Uart16550::compatible_with().contains(&s)) {
-            Some(ConsoleDevices::Uart16550)
-        } else if compatible.all().any(|s| SifiveUart::compatible_with().contains(&s)) {
-            Some(ConsoleDevices::SifiveUart)
-        } else {
-            None
-        }
+        drivers()
+            .iter()
+            .find(|driver| matches!(driver.kind, DriverKind::Console(_)) && driver.is_compatible(compatible.all()))
+            .map(Self)
     }
 
     /// # Safety
     ///
-    /// `ptr` must be a valid instance of the device described by the variant in `self`
+    /// `ptr` must be a valid instance of a device this driver is compatible
+    /// with
     pub unsafe fn set_raw_console(&self, ptr: *mut u8) {
-        match self {
-            ConsoleDevices::Uart16550 => set_raw_console(ptr as *mut Uart16550),
-            ConsoleDevices::SifiveUart => set_raw_console(ptr as *mut SifiveUart),
+        match self.0.kind {
+            DriverKind::Console(console) => set_console(console(ptr)),
         }
     }
 
     pub fn register_isr(&self, interrupt_id: usize) {
-        match self {
-            ConsoleDevices::Uart16550 => register_isr(interrupt_id, console_interrupt),
-            ConsoleDevices::SifiveUart => register_isr(interrupt_id, console_interrupt),
-        }
+        register_isr(interrupt_id, console_interrupt);
 
         if let Some(plic) = &*crate::interrupts::PLIC.lock() {
             plic.enable_interrupt(crate::platform::current_plic_context(), interrupt_id);
@@ -132,6 +206,11 @@ fn console_interrupt(
 ) -> Result<(), &'static str> {
     let c = CONSOLE.lock().read();
     claim.complete();
+
+    if super::debug_console::feed(c) {
+        super::debug_console::run("entered from the console", true);
+    }
+
     Ok(())
 }
 
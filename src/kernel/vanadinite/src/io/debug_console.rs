@@ -0,0 +1,274 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A last resort for looking at the system when userspace is wedged
+//!
+//! Typing [`MAGIC_SEQUENCE`] (Ctrl-K three times) on the console device drops
+//! the hart which takes the interrupt into a small command prompt, and so does
+//! panicking if the kernel was booted with `debug-console-on-panic`. The
+//! console talks to the console device directly rather than going through the
+//! console sinks, and only ever tries to take locks, so it can still be used if
+//! whatever wedged the system is holding one. Other harts carry on running
+//! while it's open, though any of them which print will wait while the console
+//! is waiting for input.
+
+use super::CONSOLE;
+use crate::{
+    capabilities::CapabilityResource,
+    mem::{
+        heap::HEAP_ALLOCATOR,
+        manager, memory_map,
+        paging::page_table_pages,
+        phys::{PhysicalMemoryAllocator, Zone, PHYSICAL_MEMORY_ALLOCATOR},
+    },
+    power,
+    scheduler::TASKS,
+    stats,
+};
+use core::{
+    fmt::Write,
+    num::NonZeroUsize,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use librust::{syscalls::power::ResetKind, task::Tid};
+
+pub const MAGIC_SEQUENCE: &[u8] = b"\x0b\x0b\x0b";
+
+/// Set by the `debug-console-on-panic` kernel argument
+pub static ENTER_ON_PANIC: AtomicBool = AtomicBool::new(false);
+
+/// How much of [`MAGIC_SEQUENCE`] has been typed so far
+static MATCHED: AtomicUsize = AtomicUsize::new(0);
+
+const LINE_LENGTH: usize = 64;
+
+macro_rules! out {
+    ($($arg:tt)*) => {{
+        if let Some(mut console) = CONSOLE.try_lock() {
+            let _ = write!(console, "{}\r\n", format_args!($($arg)*));
+        }
+    }};
+}
+
+/// Feed a byte read from the console device through, returning whether it
+/// finished off the magic sequence
+pub fn feed(byte: u8) -> bool {
+    let matched = MATCHED.load(Ordering::Relaxed);
+    let matched = match byte == MAGIC_SEQUENCE[matched] {
+        true => matched + 1,
+        false => (byte == MAGIC_SEQUENCE[0]) as usize,
+    };
+
+    match matched == MAGIC_SEQUENCE.len() {
+        true => {
+            MATCHED.store(0, Ordering::Relaxed);
+            true
+        }
+        false => {
+            MATCHED.store(matched, Ordering::Relaxed);
+            false
+        }
+    }
+}
+
+/// Run the console until it's exited, which isn't allowed if `resumable` is
+/// `false`, e.g. after a panic
+pub fn run(reason: &str, resumable: bool) {
+    out!("\r\n*** debug console: {} ***", reason);
+    out!("Type `help` for a list of commands");
+
+    let mut line = [0; LINE_LENGTH];
+    loop {
+        let len = read_line(&mut line);
+        let line = match core::str::from_utf8(&line[..len]) {
+            Ok(line) => line.trim(),
+            Err(_) => {
+                out!("Not UTF-8");
+                continue;
+            }
+        };
+
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (None, _) => {}
+            (Some("help"), _) => help(),
+            (Some("tasks"), _) => tasks(),
+            (Some("mem"), _) => memory(),
+            (Some("memmap"), _) => physical_memory_map(),
+            (Some("pt"), tid) => match parse_tid(tid) {
+                Some(tid) => page_table(tid),
+                None => out!("Usage: pt <tid>"),
+            },
+            (Some("caps"), tid) => match parse_tid(tid) {
+                Some(tid) => capabilities(tid),
+                None => out!("Usage: caps <tid>"),
+            },
+            (Some("reboot"), _) => power::reset(ResetKind::Reboot),
+            (Some("exit"), _) if resumable => {
+                out!("Leaving the debug console");
+                return;
+            }
+            (Some("exit"), _) => out!("Can't resume after a panic, use `reboot`"),
+            (Some(command), _) => out!("Unknown command `{}`", command),
+        }
+    }
+}
+
+/// Read a line into `line`, echoing it back, returning how long it is
+fn read_line(line: &mut [u8; LINE_LENGTH]) -> usize {
+    if let Some(mut console) = CONSOLE.try_lock() {
+        let _ = console.write_str("kdb> ");
+    }
+
+    let mut len = 0;
+    loop {
+        // Some console devices return 0 when there's nothing to read
+        let byte = match CONSOLE.try_lock().map(|console| console.read()) {
+            Some(0) | None => continue,
+            Some(byte) => byte,
+        };
+
+        let echo: &[u8] = match byte {
+            b'\r' | b'\n' => {
+                out!("");
+                return len;
+            }
+            // Backspace and delete
+            0x08 | 0x7F if len > 0 => {
+                len -= 1;
+                b"\x08 \x08"
+            }
+            b' '..=b'~' if len < LINE_LENGTH => {
+                line[len] = byte;
+                len += 1;
+                &line[len - 1..len]
+            }
+            _ => continue,
+        };
+
+        if let Some(mut console) = CONSOLE.try_lock() {
+            echo.iter().for_each(|&b| console.write(b));
+        }
+    }
+}
+
+fn parse_tid(tid: Option<&str>) -> Option<Tid> {
+    tid?.parse().ok().and_then(NonZeroUsize::new).map(Tid::new)
+}
+
+fn help() {
+    out!("help         this list");
+    out!("tasks        list every task and what it's doing");
+    out!("mem          physical memory and kernel statistics");
+    out!("memmap       the physical memory map found at boot");
+    out!("pt <tid>     the mappings in a task's page table");
+    out!("caps <tid>   a task's capabilities");
+    out!("reboot       reboot the system");
+    out!("exit         go back to what the hart was doing");
+}
+
+fn tasks() {
+    let mut next = None;
+    while let Some((tid, task)) = TASKS.next_after(next) {
+        next = Some(tid);
+        match task.try_lock() {
+            Some(task) => out!("{:>5} {:<24} {:?}", tid.value(), task.name, task.state),
+            None => out!("{:>5} <locked>", tid.value()),
+        }
+    }
+}
+
+fn memory() {
+    match PHYSICAL_MEMORY_ALLOCATOR.try_lock() {
+        Some(mut allocator) => {
+            for zone in Zone::ALL {
+                let zone_stats = allocator.zone_stats(zone);
+                out!("{:?}: {} KiB free of {} KiB", zone, zone_stats.free_pages * 4, zone_stats.total_pages * 4,);
+            }
+        }
+        None => out!("Physical memory allocator is locked"),
+    }
+
+    match HEAP_ALLOCATOR.try_stats() {
+        Some(heap) => out!("Kernel heap: {} KiB allocated of {} KiB", heap.allocated / 1024, heap.total / 1024),
+        None => out!("Kernel heap is locked"),
+    }
+
+    out!("Page tables: {} KiB", page_table_pages() * 4);
+
+    if let Some(stats) = stats::snapshot(None) {
+        out!("Context switches: {}", stats.context_switches);
+        out!("IPC messages: {}", stats.ipc_messages);
+        out!("Page faults: {}", stats.page_faults());
+    }
+}
+
+fn physical_memory_map() {
+    let printed = memory_map::try_with(|entries| {
+        for entry in entries {
+            out!("{:#p}..{:#p} {}", entry.start as *const u8, entry.end as *const u8, entry.kind_name());
+        }
+    });
+
+    if printed.is_none() {
+        out!("Memory map is locked");
+    }
+}
+
+fn page_table(tid: Tid) {
+    let task = match TASKS.get(tid) {
+        Some(task) => task,
+        None => return out!("No task {}", tid),
+    };
+
+    let task = match task.try_lock() {
+        Some(task) => task,
+        None => return out!("Task {} is locked", tid),
+    };
+
+    if let Some(mut console) = CONSOLE.try_lock() {
+        let _ = manager::dump_page_table(&task.memory_manager, &mut *console);
+    }
+}
+
+fn capabilities(tid: Tid) {
+    let task = match TASKS.get(tid) {
+        Some(task) => task,
+        None => return out!("No task {}", tid),
+    };
+
+    let task = match task.try_lock() {
+        Some(task) => task,
+        None => return out!("Task {} is locked", tid),
+    };
+
+    for (cptr, cap) in task.cspace.all() {
+        let (cptr, rights) = (cptr.value(), cap.rights);
+        match &cap.resource {
+            CapabilityResource::Channel(channel) => match channel.sender.other_tid {
+                Some(peer) => out!("{:>5} {:?} channel to task {}", cptr, rights, peer),
+                None => out!("{:>5} {:?} channel", cptr, rights),
+            },
+            CapabilityResource::Memory(_, range, ..) => {
+                out!("{:>5} {:?} memory @ {:#p}-{:#p}", cptr, rights, range.start, range.end)
+            }
+            CapabilityResource::Mmio(range, ..) => {
+                out!("{:>5} {:?} mmio @ {:#p}-{:#p}", cptr, rights, range.start, range.end)
+            }
+            CapabilityResource::Power => out!("{:>5} {:?} power", cptr, rights),
+            CapabilityResource::Debug => out!("{:>5} {:?} debug", cptr, rights),
+            CapabilityResource::Clock => out!("{:>5} {:?} clock", cptr, rights),
+            CapabilityResource::MemoryPressure => out!("{:>5} {:?} memory pressure", cptr, rights),
+            CapabilityResource::Console => out!("{:>5} {:?} console", cptr, rights),
+            CapabilityResource::Balloon => out!("{:>5} {:?} balloon", cptr, rights),
+            CapabilityResource::Swap => out!("{:>5} {:?} swap", cptr, rights),
+            CapabilityResource::TaskGroup(group) => out!("{:>5} {:?} task group {}", cptr, rights, group),
+            CapabilityResource::Topic(_) => out!("{:>5} {:?} topic", cptr, rights),
+            CapabilityResource::Pipe(end) => out!("{:>5} {:?} pipe {:?} end", cptr, rights, end.kind()),
+        }
+    }
+}
@@ -7,6 +7,7 @@
 
 pub mod block_device;
 pub mod console;
+pub mod debug_console;
 pub mod logging;
 pub mod terminal;
 
@@ -31,5 +32,13 @@ macro_rules! println {
 
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
-    CONSOLE.lock().write_fmt(args).unwrap();
+    let sinks = console_sinks();
+
+    if sinks & librust::syscalls::io::ConsoleSinks::DEVICE {
+        CONSOLE.lock().write_fmt(args).unwrap();
+    }
+
+    if sinks & librust::syscalls::io::ConsoleSinks::RING {
+        CONSOLE_RING.lock().write_fmt(args).unwrap();
+    }
 }
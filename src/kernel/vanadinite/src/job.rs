@@ -0,0 +1,92 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Task groups and job control
+//!
+//! Every task is in a task group, which it inherits from the task that spawned
+//! it, and tasks holding a capability to a group can move into it or signal
+//! every task in it at once. Unless a task handles job control itself, the
+//! kernel kills it on [`JobSignal::Terminate`], stops scheduling it on
+//! [`JobSignal::Suspend`] and schedules it again on [`JobSignal::Continue`].
+
+use crate::{
+    scheduler::{Scheduler, WakeToken, SCHEDULER, TASKS},
+    syscall::channel::ChannelMessage,
+    task::{Task, TaskState},
+};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use librust::syscalls::{channel::KernelMessage, job::JobSignal};
+
+/// Group 0 is the one init starts out in
+static NEXT_GROUP: AtomicUsize = AtomicUsize::new(1);
+
+pub fn new_group() -> usize {
+    NEXT_GROUP.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Send `signal` to every task in `group`. `caller` is the task sending it,
+/// whose lock is already held, and which is always sent the signal as a
+/// message if it's in the group since it's in the middle of a syscall.
+pub fn signal(caller: &mut Task, group: usize, signal: JobSignal) {
+    log::debug!("Task {} sending {:?} to task group {}", caller.name, signal, group);
+
+    let mut tokens = Vec::new();
+    let mut next = None;
+    while let Some((tid, task)) = TASKS.next_after(next) {
+        next = Some(tid);
+
+        if tid == caller.tid {
+            if caller.group == group {
+                tokens.extend(notify(caller, signal));
+            }
+
+            continue;
+        }
+
+        let mut task = task.lock();
+        if task.group != group {
+            continue;
+        }
+
+        match (task.handles_job_control, signal) {
+            (true, _) => tokens.extend(notify(&task, signal)),
+            (false, JobSignal::Terminate) => {
+                log::debug!("Terminating task {} ({:?})", task.name, tid);
+                task.state = TaskState::Dead;
+                crate::task::reap_later(tid);
+            }
+            (false, JobSignal::Suspend) => {
+                if let TaskState::Running = task.state {
+                    task.state = TaskState::Suspended;
+                }
+            }
+            (false, JobSignal::Continue) => {
+                if let TaskState::Suspended = task.state {
+                    task.state = TaskState::Running;
+                }
+            }
+        }
+    }
+
+    for token in tokens {
+        SCHEDULER.unblock(token);
+    }
+}
+
+/// Queue `signal` on the task's kernel channel, returning the token to wake it
+/// up with if it's waiting on the channel
+fn notify(task: &Task, signal: JobSignal) -> Option<WakeToken> {
+    let mut send_lock = task.kernel_channel.sender.inner.write();
+    send_lock.push_urgent(ChannelMessage {
+        data: Into::into(KernelMessage::JobControl(signal)),
+        caps: Vec::new(),
+        charge: None,
+    });
+
+    task.kernel_channel.sender.wake.lock().take()
+}
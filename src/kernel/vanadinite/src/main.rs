@@ -33,26 +33,45 @@
 compile_error!("vanadinite assumes a 64-bit pointer size, cannot compile on non-64 bit systems");
 
 extern crate alloc;
+// Only needed for the `mem*` functions it exports
+extern crate memops;
 #[cfg_attr(test, macro_use)]
 extern crate vanadinite_macros;
 
 pub mod asm;
 pub mod boot;
 pub mod capabilities;
+pub mod config;
+pub mod cpu;
 pub mod cpu_local;
 pub mod csr;
 pub mod drivers;
+pub mod exec;
+pub mod futex;
+pub mod hotplug;
 pub mod interrupts;
 pub mod io;
+pub mod job;
 pub mod mem;
 pub mod platform;
+pub mod power;
+pub mod profiler;
+pub mod random;
+#[cfg(feature = "debug.replay")]
+pub mod replay;
 pub mod scheduler;
+pub mod stats;
+pub mod suspend;
+pub mod symbols;
 pub mod syscall;
 pub mod task;
 #[cfg(debug_assertions)]
 pub mod tests;
+pub mod time;
 pub mod trap;
 pub mod utils;
+pub mod watchdog;
+pub mod worker;
 
 use {
     core::sync::atomic::{AtomicUsize, Ordering},
@@ -60,8 +79,10 @@ use {
     interrupts::PLIC,
     mem::{
         kernel_patching,
+        memory_map::{self, MemoryMapEntry},
         paging::{PhysicalAddress, VirtualAddress},
-        phys2virt,
+        phys::{PhysicalMemoryAllocator, Zone, PHYSICAL_MEMORY_ALLOCATOR},
+        phys2virt, virt2phys,
     },
     utils::Units,
 };
@@ -77,10 +98,28 @@ pub use vanadinite_macros::{debug, error, info, trace, warn};
 
 static N_CPUS: AtomicUsize = AtomicUsize::new(1);
 static TIMER_FREQ: AtomicU64 = AtomicU64::new(0);
+/// Each entry is a single return address, so this is plenty for the 8 KiB
+/// kernel stacks
+const SHADOW_CALL_STACK_SIZE: usize = 4096;
 static INIT: &[u8] = include_bytes!("../../../../build/init");
 
 #[thread_local]
 static HART_ID: core::cell::Cell<usize> = core::cell::Cell::new(0);
+/// What each hart was started with by [`start_hart`], which is reused when the
+/// hart is brought back online after being taken offline
+static HART_MEMORY: cpu_local::PerHart<sync::SpinMutex<Option<HartMemory>>> =
+    cpu_local::PerHart::new(|| sync::SpinMutex::new(None));
+
+#[derive(Debug, Clone, Copy)]
+struct HartMemory {
+    /// The stack the hart boots on, which holds its `gp` at the top
+    boot_sp: *mut u8,
+    /// The hart's thread locals and the control block it takes traps with,
+    /// once it's booted
+    booted: Option<(*mut u8, *mut task::ThreadControlBlock)>,
+}
+
+unsafe impl Send for HartMemory {}
 
 #[no_mangle]
 #[repr(align(4))]
@@ -89,10 +128,14 @@ extern "C" fn kmain(hart_id: usize, fdt: *const u8) -> ! {
 
     unsafe { cpu_local::init_thread_locals() };
     HART_ID.set(hart_id);
+    hotplug::hart_online(hart_id);
 
     io::logging::init_logging();
 
     let (heap_start, heap_end) = mem::heap::HEAP_ALLOCATOR.init(64.mib());
+    let heap_phys = virt2phys(VirtualAddress::from_ptr(heap_start));
+    let heap_size = heap_end as usize - heap_start as usize;
+    memory_map::record(MemoryMapEntry::KERNEL_HEAP, heap_phys.as_usize(), heap_phys.as_usize() + heap_size);
 
     platform::FDT.store(fdt, Ordering::Release);
     let fdt: Fdt<'static> = match unsafe { Fdt::from_ptr(fdt) } {
@@ -100,16 +143,14 @@ extern "C" fn kmain(hart_id: usize, fdt: *const u8) -> ! {
         Err(e) => platform::exit(platform::ExitStatus::Error(&e)),
     };
 
-    let current_cpu = fdt.cpus().find(|cpu| cpu.ids().first() == hart_id).unwrap();
-    let timebase_frequency = current_cpu.timebase_frequency();
-    TIMER_FREQ.store(timebase_frequency as u64, Ordering::Relaxed);
+    let timebase_frequency = time::init(&fdt, hart_id);
 
     let mut stdout_interrupts = None;
     let stdout = fdt.chosen().stdout();
     if let Some((node, reg, compatible)) = stdout.and_then(|n| Some((n, n.reg()?.next()?, n.compatible()?))) {
         let stdout_addr = reg.starting_address as *mut u8;
 
-        if let Some(device) = io::ConsoleDevices::from_compatible(compatible) {
+        if let Some(device) = io::ConsoleDriver::from_compatible(compatible) {
             let stdout_phys = PhysicalAddress::from_ptr(stdout_addr);
             let ptr = phys2virt(stdout_phys);
 
@@ -138,6 +179,13 @@ extern "C" fn kmain(hart_id: usize, fdt: *const u8) -> ! {
                     None => log::warn!("No path provided for init process! Defaulting to `init`"),
                 },
                 "no-color" | "no-colour" => io::logging::USE_COLOR.store(false, Ordering::Relaxed),
+                "debug-console-on-panic" => io::debug_console::ENTER_ON_PANIC.store(true, Ordering::Relaxed),
+                "console-sinks" => match value.and_then(io::parse_console_sinks) {
+                    Some(sinks) => {
+                        io::set_console_sinks(sinks);
+                    }
+                    None => log::warn!("Bad console sink list: `{}`", value.unwrap_or_default()),
+                },
                 "console" => match value {
                     Some("sbi") => {
                         if let ExtensionAvailability::Available(_) = probe_extension(sbi::legacy::CONSOLE_PUTCHAR_EID) {
@@ -151,7 +199,7 @@ extern "C" fn kmain(hart_id: usize, fdt: *const u8) -> ! {
                         {
                             let stdout_addr = reg.starting_address as *mut u8;
 
-                            if let Some(device) = crate::io::ConsoleDevices::from_compatible(compatible) {
+                            if let Some(device) = crate::io::ConsoleDriver::from_compatible(compatible) {
                                 let stdout_phys = PhysicalAddress::from_ptr(stdout_addr);
                                 let ptr = phys2virt(stdout_phys);
 
@@ -167,6 +215,8 @@ extern "C" fn kmain(hart_id: usize, fdt: *const u8) -> ! {
                     }
                     None => {}
                 },
+                // Read by the config server
+                option if option.starts_with("config.") => {}
                 "" => {}
                 _ => log::warn!("Unknown kernel argument: `{}`", option),
             }
@@ -207,25 +257,38 @@ extern "C" fn kmain(hart_id: usize, fdt: *const u8) -> ! {
 
     let n_cpus = fdt.cpus().count();
     N_CPUS.store(n_cpus, Ordering::Release);
-    let mut first_mem_resv = true;
+    mem::phys::numa::init_hart_nodes(&fdt);
+    mem::cache::init(&fdt);
+    cpu::init(&fdt);
+    #[cfg(feature = "debug.replay")]
+    replay::init();
 
     info!("vanadinite version {#brightgreen}", env!("CARGO_PKG_VERSION"));
     info!(blue, "=== Machine Info ===");
     info!(" Device Model: {}", model);
     info!(" Total CPUs: {}", n_cpus);
+    let features = cpu::features();
+    info!(" ISA: {}", features);
+    info!(" Vendor ID: {:#x}, Arch ID: {:#x}, Impl ID: {:#x}", features.mvendorid, features.marchid, features.mimpid);
+    info!(" Paging: {:?}", mem::paging::satp_mode());
+    info!(" ASID bits: {}", mem::manager::asid::init());
+    info!(" TLB shootdowns: {}", if mem::shootdown::init() { "SBI RFENCE" } else { "IPIs" });
     info!(" RAM: {} MiB @ {:#X}", mem_size, mem_start as usize);
     info!(" Timer Clock: {}Hz", timebase_frequency);
-    for memory_reservation in fdt.memory_reservations() {
-        if first_mem_resv {
-            info!(" Reserved Memory Regions:");
-            first_mem_resv = false;
+    time::validate_against_rtc(&fdt);
+    time::init_wall_clock(&fdt);
+    for zone in Zone::ALL {
+        let stats = PHYSICAL_MEMORY_ALLOCATOR.lock().zone_stats(zone);
+        info!("   {:?} zone: {} of {} KiB free", zone, stats.free_pages * 4, stats.total_pages * 4);
+    }
+    let numa_nodes = PHYSICAL_MEMORY_ALLOCATOR.lock().nodes();
+    if numa_nodes.len() > 1 {
+        for node in numa_nodes {
+            let stats = PHYSICAL_MEMORY_ALLOCATOR.lock().node_stats(node);
+            info!("   NUMA node {}: {} of {} KiB free", node, stats.free_pages * 4, stats.total_pages * 4);
         }
-
-        let size = memory_reservation.size();
-        let start = memory_reservation.address() as usize;
-        let end = start + size;
-        info!("   {:#p}..{:#p} ({} KiB)", start as *const u8, end as *const u8, size / 4.kib());
     }
+    memory_map::log();
     info!(blue, "=== SBI Implementation ===");
     info!(" Implementor: {:?} (version: {#green'{}.{}})", sbi::base::impl_id(), impl_major, impl_minor);
     info!(" Spec Version: {#green'{}.{}}", spec_major, spec_minor);
@@ -234,6 +297,7 @@ extern "C" fn kmain(hart_id: usize, fdt: *const u8) -> ! {
     info!(" stvec_trap_shim: {:#p}", trap::stvec_trap_shim as *const u8);
     info!(" Heap region: {:#p}-{:#p}", heap_start, heap_end);
     info!(" Paging scheme: {:?}", csr::satp::read().mode);
+    info!(" Features: {}", config::kernel_config().enabled().collect::<alloc::vec::Vec<_>>().join(" "));
 
     if let Some(ic) = fdt.find_compatible(Plic::compatible_with()) {
         let reg = ic.reg().unwrap().next().unwrap();
@@ -256,19 +320,90 @@ extern "C" fn kmain(hart_id: usize, fdt: *const u8) -> ! {
                     .and_then(|p| p.as_str()?.chars().find(|c| *c == 's'))
                     .is_some()
             })
-            .map(|cpu| platform::plic_context_for(cpu.ids().first()));
+            .map(|cpu| platform::plic_context_for(cpu.ids().first()))
+            .collect::<alloc::vec::Vec<_>>();
 
         let plic = unsafe { &*ic_virt.as_ptr().cast::<Plic>() };
 
-        plic.init(ndevs, contexts);
+        plic.init(ndevs, contexts.iter().copied());
         plic.set_context_threshold(platform::current_plic_context(), 0);
         plic.enable_interrupt(platform::current_plic_context(), 8);
         plic.set_interrupt_priority(8, 7);
 
         debug!("Registering PLIC @ {:#p}", ic_virt);
         interrupts::register_plic(plic);
+        suspend::register("plic", Box::new(interrupts::PlicSuspend::new(plic, ndevs, contexts)));
+    }
+
+    #[cfg(feature = "driver.riscv_iommu")]
+    if let Some(node) = fdt.find_compatible(drivers::generic::iommu::Iommu::compatible_with()) {
+        let reg = node.reg().unwrap().next().unwrap();
+        let iommu_virt = phys2virt(PhysicalAddress::from_ptr(reg.starting_address));
+        let phandle = node.property("phandle").and_then(|p| p.as_usize()).map(|p| p as u32);
+
+        match unsafe { drivers::generic::iommu::Iommu::init(&*iommu_virt.as_ptr().cast(), phandle) } {
+            Ok(iommu) => {
+                debug!("Registering IOMMU @ {:#p}", iommu_virt);
+                let iommu: &'static _ = Box::leak(Box::new(iommu));
+                mem::dma::register_iommu(iommu);
+
+                // Faults are reported through the first interrupt, and have to
+                // be polled for when there isn't one
+                match node.interrupts().and_then(|mut interrupts| interrupts.next()) {
+                    Some(interrupt) => {
+                        interrupts::isr::register_isr(interrupt, move |_, claim, _| {
+                            iommu.drain_faults();
+                            claim.complete();
+                            Ok(())
+                        });
+
+                        if let Some(plic) = &*PLIC.lock() {
+                            plic.enable_interrupt(platform::current_plic_context(), interrupt);
+                            plic.set_interrupt_priority(interrupt, 1);
+                        }
+                    }
+                    None => worker::register_idle_job(drivers::generic::iommu::drain_faults_in_background),
+                }
+            }
+            Err(e) => log::warn!("Unable to use IOMMU, devices will have unrestricted DMA: {:?}", e),
+        }
+    }
+
+    #[cfg(feature = "driver.dw_wdt")]
+    if let Some(node) = fdt.find_compatible(drivers::generic::dw_wdt::DwWatchdog::compatible_with()) {
+        let reg = node.reg().unwrap().next().unwrap();
+        let wdt_virt = phys2virt(PhysicalAddress::from_ptr(reg.starting_address));
+
+        // The clock is usually a `fixed-clock` node referenced by `clocks`
+        let clock_hz = node.property("clock-frequency").and_then(|p| p.as_usize()).or_else(|| {
+            let phandle = u32::from_be_bytes(node.property("clocks")?.value.get(..4)?.try_into().ok()?);
+            fdt.all_nodes()
+                .find(|n| n.property("phandle").and_then(|p| p.as_usize()) == Some(phandle as usize))?
+                .property("clock-frequency")?
+                .as_usize()
+        });
+
+        match clock_hz {
+            Some(clock_hz) => {
+                let wdt = unsafe { &*wdt_virt.as_ptr().cast::<drivers::generic::dw_wdt::DwWatchdog>() };
+                let timeout = wdt.start(clock_hz as u64, watchdog::HARDWARE_TIMEOUT_US);
+
+                debug!("Registering watchdog @ {:#p} with a {}ms timeout", wdt_virt, timeout / 1000);
+                watchdog::register_hardware(wdt);
+            }
+            None => log::warn!("Unable to find the watchdog's clock frequency, not starting it"),
+        }
     }
 
+    worker::register_idle_job(mem::balloon::reclaim_in_background);
+    worker::register_idle_job(mem::phys::zeroed::refill);
+    worker::register_idle_job(mem::megapages::promote_in_background);
+    worker::register_idle_job(mem::compaction::compact_in_background);
+    worker::register_idle_job(mem::swap::swap_out_in_background);
+    worker::register_idle_job(mem::pressure::check_in_background);
+
+    random::init(fdt.find_node("/chosen").and_then(|n| n.property("rng-seed")).map(|p| p.value));
+
     if let Some((device, interrupts)) = stdout_interrupts {
         for interrupt in interrupts {
             device.register_isr(interrupt);
@@ -278,7 +413,7 @@ extern "C" fn kmain(hart_id: usize, fdt: *const u8) -> ! {
     let ptr = Box::leak(Box::new(task::ThreadControlBlock {
         kernel_stack: mem::alloc_kernel_stack(8.kib()),
         kernel_thread_local: cpu_local::tp(),
-        kernel_global_ptr: asm::gp(),
+        kernel_global_ptr: trap_global_ptr(mem::phys::numa::local_node()),
         saved_sp: 0,
         saved_tp: 0,
         saved_gp: 0,
@@ -298,20 +433,95 @@ extern "C" fn kmain(hart_id: usize, fdt: *const u8) -> ! {
 
     //scheduler::init_scheduler(Box::new(scheduler::round_robin::RoundRobinScheduler::new()));
 
-    scheduler::SCHEDULER.enqueue(task::Task::load(
-        "init",
-        &elf64::Elf::new(INIT).unwrap(),
-        init_args.into_iter().flatten(),
-    ));
-
-    let other_hart_boot_phys = unsafe { kernel_section_v2p(VirtualAddress::from_ptr(other_hart_boot as *const u8)) };
+    let mut init = match task::Task::load("init", INIT, init_args.into_iter().flatten()) {
+        Ok(init) => init,
+        Err(e) => panic!("Failed to load init: {:?}", e),
+    };
+    init.cspace
+        .mint_with_id(
+            librust::syscalls::power::POWER_CAPABILITY,
+            capabilities::Capability {
+                resource: capabilities::CapabilityResource::Power,
+                rights: librust::capabilities::CapabilityRights::READ
+                    | librust::capabilities::CapabilityRights::WRITE
+                    | librust::capabilities::CapabilityRights::GRANT,
+            },
+        )
+        .expect("[BUG] power cap already created?");
+    init.cspace
+        .mint_with_id(
+            librust::syscalls::debug::DEBUG_CAPABILITY,
+            capabilities::Capability {
+                resource: capabilities::CapabilityResource::Debug,
+                rights: librust::capabilities::CapabilityRights::READ
+                    | librust::capabilities::CapabilityRights::WRITE
+                    | librust::capabilities::CapabilityRights::GRANT,
+            },
+        )
+        .expect("[BUG] debug cap already created?");
+    init.cspace
+        .mint_with_id(
+            librust::syscalls::time::CLOCK_CAPABILITY,
+            capabilities::Capability {
+                resource: capabilities::CapabilityResource::Clock,
+                rights: librust::capabilities::CapabilityRights::READ
+                    | librust::capabilities::CapabilityRights::WRITE
+                    | librust::capabilities::CapabilityRights::GRANT,
+            },
+        )
+        .expect("[BUG] clock cap already created?");
+    init.cspace
+        .mint_with_id(
+            librust::syscalls::pressure::MEMORY_PRESSURE_CAPABILITY,
+            capabilities::Capability {
+                resource: capabilities::CapabilityResource::MemoryPressure,
+                rights: librust::capabilities::CapabilityRights::READ | librust::capabilities::CapabilityRights::GRANT,
+            },
+        )
+        .expect("[BUG] memory pressure cap already created?");
+    init.cspace
+        .mint_with_id(
+            librust::syscalls::io::CONSOLE_CAPABILITY,
+            capabilities::Capability {
+                resource: capabilities::CapabilityResource::Console,
+                rights: librust::capabilities::CapabilityRights::READ
+                    | librust::capabilities::CapabilityRights::WRITE
+                    | librust::capabilities::CapabilityRights::GRANT,
+            },
+        )
+        .expect("[BUG] console cap already created?");
+    init.cspace
+        .mint_with_id(
+            librust::syscalls::mem::BALLOON_CAPABILITY,
+            capabilities::Capability {
+                resource: capabilities::CapabilityResource::Balloon,
+                rights: librust::capabilities::CapabilityRights::WRITE | librust::capabilities::CapabilityRights::GRANT,
+            },
+        )
+        .expect("[BUG] balloon cap already created?");
+    init.cspace
+        .mint_with_id(
+            librust::syscalls::swap::SWAP_CAPABILITY,
+            capabilities::Capability {
+                resource: capabilities::CapabilityResource::Swap,
+                rights: librust::capabilities::CapabilityRights::WRITE | librust::capabilities::CapabilityRights::GRANT,
+            },
+        )
+        .expect("[BUG] swap cap already created?");
+
+    // Harts spinning on a lock have to keep up with shootdowns once there are
+    // other harts to send them
+    sync::set_spin_hook(mem::shootdown::poll_requests);
+
+    scheduler::SCHEDULER.enqueue(init);
 
     for cpu in fdt.cpus().filter(|cpu| cpu.ids().first() != hart_id) {
         let hart_id = cpu.ids().first();
-        let hart_sp = mem::alloc_kernel_stack(8.kib()) as usize;
 
-        if let Err(e) = hart_start(hart_id, other_hart_boot_phys.as_usize(), hart_sp) {
+        if let Err(e) = start_hart(hart_id) {
             error!(red, "Failed to start hart {}: {:?}", hart_id, e);
+            // Don't leave tasks waiting on a hart which will never run them
+            scheduler::SCHEDULER.set_hart_online(hart_id, false);
         }
     }
 
@@ -319,13 +529,68 @@ extern "C" fn kmain(hart_id: usize, fdt: *const u8) -> ! {
     scheduler::SCHEDULER.schedule();
 }
 
+/// Start `hart_id` with a new stack, which lands it in `kalt`
+fn start_hart(hart_id: usize) -> Result<(), sbi::SbiError> {
+    let other_hart_boot_phys = unsafe { kernel_section_v2p(VirtualAddress::from_ptr(other_hart_boot as *const u8)) };
+    let node = mem::phys::numa::hart_node(hart_id);
+    let hart_sp = HART_MEMORY[hart_id]
+        .lock()
+        .get_or_insert_with(|| {
+            let hart_sp = mem::alloc_kernel_stack_on(node, 8.kib()).wrapping_sub(16);
+
+            // The hart picks up its `gp` from the top of its stack once paging
+            // is on, see `kalt_entry`
+            unsafe { hart_sp.cast::<*mut u8>().write(boot_global_ptr(node)) };
+
+            HartMemory { boot_sp: hart_sp, booted: None }
+        })
+        .boot_sp;
+
+    hart_start(hart_id, other_hart_boot_phys.as_usize(), hart_sp as usize)
+}
+
+/// The `gp` the trap handler runs with on this hart
+fn trap_global_ptr(node: usize) -> *mut u8 {
+    boot_global_ptr(node)
+}
+
+/// The `gp` a hart runs with when it's started, which with shadow call stacks
+/// is a new shadow call stack for the hart
+fn boot_global_ptr(node: usize) -> *mut u8 {
+    match cfg!(feature = "hardening.shadow_call_stack") {
+        true => mem::alloc_shadow_call_stack_on(node, SHADOW_CALL_STACK_SIZE),
+        false => asm::gp(),
+    }
+}
+
+/// Where other harts land once paging is on. `kalt` pushes its return address
+/// to the shadow call stack, so `gp` has to be set up before it runs.
+#[naked]
 #[no_mangle]
 #[repr(align(4))]
+unsafe extern "C" fn kalt_entry(_hart_id: usize) -> ! {
+    #[rustfmt::skip]
+    core::arch::asm!("
+        ld gp, 0(sp)
+        j {}
+    ", sym kalt, options(noreturn));
+}
+
+#[no_mangle]
 extern "C" fn kalt(hart_id: usize) -> ! {
     csr::sstatus::disable_interrupts();
     csr::stvec::set(trap::stvec_trap_shim);
-    unsafe { crate::cpu_local::init_thread_locals() };
+
+    // Coming back online, the hart starts over on everything it had last time,
+    // none of which is in use now it's stopped
+    let booted = HART_MEMORY[hart_id].lock().and_then(|memory| memory.booted);
+    match booted {
+        Some((thread_locals, _)) => unsafe { crate::cpu_local::reinit_thread_locals(thread_locals) },
+        None => unsafe { crate::cpu_local::init_thread_locals() },
+    }
+
     HART_ID.set(hart_id);
+    hotplug::hart_online(hart_id);
 
     info!(brightgreen, "Hart {} successfully booted", HART_ID.get());
 
@@ -333,17 +598,28 @@ extern "C" fn kalt(hart_id: usize) -> ! {
         plic.set_context_threshold(platform::current_plic_context(), 0);
     }
 
-    let ptr = Box::leak(Box::new(task::ThreadControlBlock {
-        kernel_stack: mem::alloc_kernel_stack(8.kib()),
-        kernel_thread_local: cpu_local::tp(),
-        kernel_global_ptr: asm::gp(),
-        saved_sp: 0,
-        saved_tp: 0,
-        saved_gp: 0,
-        kernel_stack_size: 8.kib(),
-    }));
+    let ptr = match booted {
+        Some((_, tcb)) => tcb,
+        None => {
+            let tcb: *mut task::ThreadControlBlock = Box::leak(Box::new(task::ThreadControlBlock {
+                kernel_stack: mem::alloc_kernel_stack(8.kib()),
+                kernel_thread_local: cpu_local::tp(),
+                kernel_global_ptr: trap_global_ptr(mem::phys::numa::local_node()),
+                saved_sp: 0,
+                saved_tp: 0,
+                saved_gp: 0,
+                kernel_stack_size: 8.kib(),
+            }));
+
+            if let Some(memory) = &mut *HART_MEMORY[hart_id].lock() {
+                memory.booted = Some((cpu_local::tp(), tcb));
+            }
 
-    csr::sscratch::write(ptr as *mut _ as usize);
+            tcb
+        }
+    };
+
+    csr::sscratch::write(ptr as usize);
     csr::sstatus::set_fs(csr::sstatus::FloatingPointStatus::Initial);
     csr::sie::enable();
 
@@ -364,25 +640,23 @@ unsafe extern "C" fn other_hart_boot() -> ! {
             #   satp: to the physical address of the root page table
             #  stvec: to the virtual address we'll trap-trick to
             #     sp: to the stack region we receive in a1
-            #     gp: to the virtual GP pointer
-            
-
-            # Translate phys `__global_pointer$` addr to virtual
-            lla t0, __global_pointer$
+            #
+            # `gp` is left for `kalt_entry` to load from the top of the stack,
+            # since the stack isn't mapped until paging is on
 
             lla t1, PAGE_OFFSET_VALUE
             ld t1, (t1)
 
             lla t2, PAGE_OFFSET
 
-            sub t0, t0, t2
-            add t0, t0, t1
-
-            # Set up gp and sp
-            mv gp, t0
+            # Set up sp
             mv sp, a1
 
-            # Translate phys `kalt` addr to virtual
+            # Clear tp, which tells the spin hook there are no thread locals
+            # to use yet
+            mv tp, zero
+
+            # Translate phys `kalt_entry` addr to virtual
             lla t0, {}
             sub t0, t0, t2
             add t0, t0, t1
@@ -394,7 +668,7 @@ unsafe extern "C" fn other_hart_boot() -> ! {
             sfence.vma
             nop             # We fault here and fall into `kalt`
         ",
-        sym kalt,
+        sym kalt_entry,
         // FIXME: see if there's a better way to do this
         sym boot::early_paging::BOOTSTRAP_SATP,
         options(noreturn),
@@ -469,6 +743,21 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     }
 
     error!("{}", info);
+    error!("Backtrace:");
+    for (i, ra) in symbols::backtrace().enumerate() {
+        match symbols::resolve(ra) {
+            Some(resolved) => error!("  {:>2}: {:#018x} {}", i, ra, resolved),
+            None => error!("  {:>2}: {:#018x} <unknown>", i, ra),
+        }
+    }
+
+    #[cfg(feature = "debug.replay")]
+    replay::dump();
+
+    if io::debug_console::ENTER_ON_PANIC.load(Ordering::Relaxed) {
+        io::debug_console::run("kernel panic", false);
+    }
+
     error!("Shutting hart down");
 
     sbi::hart_state_management::hart_stop().unwrap();
@@ -483,5 +772,6 @@ pub extern "C" fn abort() -> ! {
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
-    panic!("out of memory: {:?}", layout)
+    let heap = mem::heap::HEAP_ALLOCATOR.stats();
+    panic!("out of memory: {:?} ({} of {} heap bytes allocated)", layout, heap.allocated, heap.total)
 }
@@ -0,0 +1,147 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Pages lent to the host through a memory balloon device
+//!
+//! The balloon driver lives in userspace, but the pages it hands to the host
+//! come out of the physical memory allocator and go back into it when the
+//! balloon is deflated. The allocator can also take pages back out of the
+//! balloon on its own when it runs out of memory, which the driver is told
+//! about the next time it deflates the balloon.
+//!
+//! Only tasks holding the balloon capability, which init hands to the balloon
+//! driver, can inflate or deflate the balloon.
+
+use super::{
+    paging::PageSize,
+    phys::{PhysicalMemoryAllocator, PhysicalPage, PHYSICAL_MEMORY_ALLOCATOR},
+};
+use alloc::vec::Vec;
+use sync::SpinMutex;
+
+/// Inflating the balloon never leaves fewer than this many pages free (4 MiB)
+const MIN_FREE_PAGES: usize = 1024;
+/// Pages are taken back from the balloon in the background once fewer than
+/// this many are free (2 MiB)
+const LOW_FREE_PAGES: usize = MIN_FREE_PAGES / 2;
+/// Pages taken back from the balloon each time the background job runs
+const RECLAIM_BATCH: usize = 32;
+
+pub static BALLOON: SpinMutex<Balloon> = SpinMutex::new(Balloon::new());
+
+pub struct Balloon {
+    /// Pages currently lent to the host
+    pages: Vec<PhysicalPage>,
+    /// Pages taken back from the balloon under memory pressure, which the
+    /// driver hasn't told the host about yet
+    reclaimed: Vec<PhysicalPage>,
+}
+
+impl Balloon {
+    pub const fn new() -> Self {
+        Self { pages: Vec::new(), reclaimed: Vec::new() }
+    }
+}
+
+/// Take up to `max` free pages from the physical memory allocator and add them
+/// to the balloon, returning the pages which were taken
+pub fn inflate(max: usize) -> Vec<PhysicalPage> {
+    // The balloon lock is never held while allocating, since the allocator
+    // takes it when reclaiming pages
+    let mut pages = Vec::with_capacity(max);
+    {
+        let mut allocator = PHYSICAL_MEMORY_ALLOCATOR.lock();
+        let available = allocator.free_pages().saturating_sub(MIN_FREE_PAGES);
+
+        for _ in 0..max.min(available) {
+            match unsafe { allocator.alloc(PageSize::Kilopage) } {
+                Some(page) => pages.push(page),
+                None => break,
+            }
+        }
+    }
+
+    BALLOON.lock().pages.extend_from_slice(&pages);
+    log::debug!("Inflated balloon by {} pages", pages.len());
+
+    pages
+}
+
+/// Remove up to `max` pages from the balloon, returning the pages which were
+/// removed. Pages which were reclaimed under memory pressure are always
+/// returned first, and are the only pages returned if `reclaimed_only` is set.
+pub fn deflate(max: usize, reclaimed_only: bool) -> Vec<PhysicalPage> {
+    let (reclaimed, released) = {
+        let mut balloon = BALLOON.lock();
+
+        let n_reclaimed = balloon.reclaimed.len().min(max);
+        let reclaimed: Vec<_> = balloon.reclaimed.drain(..n_reclaimed).collect();
+
+        let n_released = match reclaimed_only {
+            true => 0,
+            false => balloon.pages.len().min(max - n_reclaimed),
+        };
+        let at = balloon.pages.len() - n_released;
+        let released = balloon.pages.split_off(at);
+
+        (reclaimed, released)
+    };
+
+    let mut allocator = PHYSICAL_MEMORY_ALLOCATOR.lock();
+    for page in &released {
+        unsafe { allocator.dealloc(*page, PageSize::Kilopage) };
+    }
+
+    log::debug!("Deflated balloon by {} pages ({} reclaimed)", released.len() + reclaimed.len(), reclaimed.len());
+
+    reclaimed.into_iter().chain(released).collect()
+}
+
+/// Take a page back from the balloon without waiting on the driver, for when
+/// the physical memory allocator has run out of pages. The page is handed
+/// straight to the caller, and is reported to the driver the next time the
+/// balloon is deflated.
+pub fn reclaim() -> Option<PhysicalPage> {
+    let mut balloon = BALLOON.lock();
+    let page = balloon.pages.pop()?;
+    balloon.reclaimed.push(page);
+
+    log::warn!("Out of memory, reclaimed page {:#p} from the balloon", page.as_phys_address().as_ptr());
+
+    Some(page)
+}
+
+/// Take pages back from the balloon while free memory is running low, so that
+/// allocations don't have to do it themselves once it runs out. This is run as
+/// an idle job, and returns whether there's more to reclaim.
+pub fn reclaim_in_background() -> bool {
+    if PHYSICAL_MEMORY_ALLOCATOR.lock().free_pages() >= LOW_FREE_PAGES {
+        return false;
+    }
+
+    let pages = {
+        let mut balloon = BALLOON.lock();
+        let at = balloon.pages.len().saturating_sub(RECLAIM_BATCH);
+        let pages = balloon.pages.split_off(at);
+        balloon.reclaimed.extend_from_slice(&pages);
+
+        pages
+    };
+
+    if pages.is_empty() {
+        return false;
+    }
+
+    let mut allocator = PHYSICAL_MEMORY_ALLOCATOR.lock();
+    for page in &pages {
+        unsafe { allocator.dealloc(*page, PageSize::Kilopage) };
+    }
+
+    log::warn!("Low on memory, reclaimed {} pages from the balloon", pages.len());
+
+    true
+}
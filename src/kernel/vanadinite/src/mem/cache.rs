@@ -0,0 +1,123 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Cache-block maintenance
+//!
+//! Devices which don't snoop the caches only see memory the CPU wrote once it
+//! has been cleaned out of the caches, and the CPU only sees what the device
+//! wrote once its stale copy has been invalidated. The Zicbom extension
+//! provides the instructions to do that, and Zicboz can zero a whole cache
+//! block at once. Both are only used if every hart in the device tree has
+//! them, and on harts without Zicbom the platform is assumed to be coherent,
+//! so cleaning and invalidating do nothing.
+
+use super::paging::VirtualAddress;
+use crate::utils;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use fdt::Fdt;
+
+/// The size of the blocks operated on by Zicbom instructions, or zero if they
+/// aren't available
+static CBOM_BLOCK_SIZE: AtomicUsize = AtomicUsize::new(0);
+/// The size of the blocks zeroed by `cbo.zero`, or zero if it isn't available
+static CBOZ_BLOCK_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Look for Zicbom and Zicboz on every hart in the device tree
+pub fn init(fdt: &Fdt<'_>) {
+    let cbom = common_block_size(fdt, "zicbom", "riscv,cbom-block-size");
+    let cboz = common_block_size(fdt, "zicboz", "riscv,cboz-block-size");
+
+    log::debug!("Zicbom block size: {:?}, Zicboz block size: {:?}", cbom, cboz);
+
+    CBOM_BLOCK_SIZE.store(cbom.unwrap_or(0), Ordering::Release);
+    CBOZ_BLOCK_SIZE.store(cboz.unwrap_or(0), Ordering::Release);
+}
+
+/// The block size every hart supports `extension` with, if they all do
+fn common_block_size(fdt: &Fdt<'_>, extension: &str, block_size_property: &str) -> Option<usize> {
+    let mut block_size = None;
+    for cpu in fdt.cpus() {
+        let has_extension = match cpu.properties().find(|p| p.name == "riscv,isa-extensions") {
+            Some(extensions) => extensions.value.split(|&b| b == 0).any(|ext| ext == extension.as_bytes()),
+            None => cpu
+                .properties()
+                .find(|p| p.name == "riscv,isa")
+                .and_then(|p| p.as_str())
+                .map_or(false, |isa| isa.split('_').skip(1).any(|ext| ext.eq_ignore_ascii_case(extension))),
+        };
+
+        let size = cpu.properties().find(|p| p.name == block_size_property).and_then(|p| p.as_usize());
+        match (has_extension, size) {
+            (true, Some(size)) if size.is_power_of_two() => {
+                block_size = Some(block_size.map_or(size, |current: usize| current.min(size)))
+            }
+            _ => return None,
+        }
+    }
+
+    block_size
+}
+
+/// Write any dirty cache blocks covering `len` bytes at `start` back to memory
+pub fn clean(start: VirtualAddress, len: usize) {
+    for_each_block(start, len, |block| unsafe {
+        // cbo.clean
+        core::arch::asm!(".insn i 0x0F, 2, x0, {}, 1", in(reg) block);
+    });
+}
+
+/// Drop the cached copies of the `len` bytes at `start`, so the next read goes
+/// to memory. Any dirty data in them is written back first, since a partial
+/// block at either end may hold data the CPU still needs.
+pub fn invalidate(start: VirtualAddress, len: usize) {
+    for_each_block(start, len, |block| unsafe {
+        // cbo.flush
+        core::arch::asm!(".insn i 0x0F, 2, x0, {}, 2", in(reg) block);
+    });
+}
+
+fn for_each_block(start: VirtualAddress, len: usize, mut f: impl FnMut(usize)) {
+    let block_size = CBOM_BLOCK_SIZE.load(Ordering::Acquire);
+    if block_size == 0 || len == 0 {
+        return;
+    }
+
+    let end = start.as_usize() + len;
+    let mut block = start.as_usize() & !(block_size - 1);
+    while block < end {
+        f(block);
+        block += block_size;
+    }
+
+    // Make sure the operations are done before e.g. telling a device to go
+    unsafe { core::arch::asm!("fence rw, rw") };
+}
+
+/// Zero `len` bytes at `start`, using `cbo.zero` for the whole cache blocks in
+/// it when it's available
+///
+/// # Safety
+///
+/// `start` must be valid for writes of `len` bytes
+pub unsafe fn zero(start: *mut u8, len: usize) {
+    let block_size = CBOZ_BLOCK_SIZE.load(Ordering::Acquire);
+    if block_size == 0 {
+        core::ptr::write_bytes(start, 0, len);
+        return;
+    }
+
+    let end = start as usize + len;
+    let first_block = utils::round_up_to_next(start as usize, block_size).min(end);
+    let last_block = (end & !(block_size - 1)).max(first_block);
+
+    core::ptr::write_bytes(start, 0, first_block - start as usize);
+    for block in (first_block..last_block).step_by(block_size) {
+        // cbo.zero
+        core::arch::asm!(".insn i 0x0F, 2, x0, {}, 4", in(reg) block);
+    }
+    core::ptr::write_bytes(last_block as *mut u8, 0, end - last_block);
+}
@@ -0,0 +1,121 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Compacting physical memory
+//!
+//! Over a long uptime the pages still in use end up scattered across physical
+//! memory, and even with plenty of memory free there may be no megapage or
+//! large contiguous run left for DMA buffers and megapage promotion. Every so
+//! often an idle job goes through each task and moves the private pages it has
+//! in sparsely used megapage sized blocks of memory into blocks which are
+//! fuller, so the sparse blocks drain until they're entirely free. Pages only
+//! ever move into a block with more pages in use than the one they came from,
+//! so they can't bounce back and forth between blocks.
+//!
+//! Like with [`super::megapages`], only blocked tasks are looked at, since the
+//! pages are copied and can't change while that happens. Pages the kernel uses
+//! and memory shared between tasks aren't moved.
+
+use super::{
+    paging::PageSize,
+    phys::{PhysicalMemoryAllocator, PHYSICAL_MEMORY_ALLOCATOR},
+    region::KILOPAGES_PER_MEGAPAGE,
+};
+use crate::{
+    csr,
+    scheduler::{Scheduler, SCHEDULER, TASKS},
+    utils::ticks_per_us,
+};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use librust::task::Tid;
+use sync::SpinMutex;
+
+/// How long to wait between going through every task
+const SCAN_INTERVAL_US: u64 = 5_000_000;
+/// Blocks with fewer pages than this in use have their pages moved out
+const SPARSE_BLOCK_PAGES: usize = KILOPAGES_PER_MEGAPAGE / 4;
+/// The most pages moved each time the job runs, since each one is copied with
+/// interrupts disabled
+const PAGES_PER_RUN: usize = 16;
+
+/// The last task looked at in the current scan, or `None` between scans
+static CURSOR: SpinMutex<Option<Tid>> = SpinMutex::new(None);
+/// When the next scan starts, in `time` CSR ticks
+static NEXT_SCAN: AtomicU64 = AtomicU64::new(0);
+
+/// Move some of the pages of the next task of the current scan out of sparse
+/// blocks. This is run as an idle job, and returns whether the scan has more
+/// to look at.
+pub fn compact_in_background() -> bool {
+    let mut cursor = match CURSOR.try_lock() {
+        Some(cursor) => cursor,
+        None => return false,
+    };
+
+    let now = csr::time::read();
+    if cursor.is_none() && now < NEXT_SCAN.load(Ordering::Relaxed) {
+        return false;
+    }
+
+    let (tid, task) = match TASKS.next_after(*cursor) {
+        Some(next) => next,
+        None => {
+            let interval = ticks_per_us(SCAN_INTERVAL_US, crate::TIMER_FREQ.load(Ordering::Relaxed));
+            NEXT_SCAN.store(now + interval, Ordering::Relaxed);
+            *cursor = None;
+
+            return false;
+        }
+    };
+
+    // Holding the lock keeps the task from being scheduled, but it could
+    // already be running if it isn't blocked
+    let moved = match task.try_lock() {
+        Some(mut task) if SCHEDULER.is_blocked(tid) => {
+            let candidates = {
+                let mut allocator = PHYSICAL_MEMORY_ALLOCATOR.lock();
+                task.memory_manager
+                    .movable_pages()
+                    .filter(|&(_, page)| matches!(allocator.block_usage(page), Some(used) if used < SPARSE_BLOCK_PAGES))
+                    .take(PAGES_PER_RUN)
+                    .collect::<Vec<_>>()
+            };
+
+            let mut moved = 0;
+            for (at, page) in candidates {
+                // Nowhere left to move pages to in this part of memory
+                let to = match unsafe { PHYSICAL_MEMORY_ALLOCATOR.lock().alloc_denser(page) } {
+                    Some(to) => to,
+                    None => break,
+                };
+
+                let freed = match task.memory_manager.migrate(at, to) {
+                    Some(from) => from,
+                    None => to,
+                };
+
+                unsafe { PHYSICAL_MEMORY_ALLOCATOR.lock().dealloc(freed, PageSize::Kilopage) };
+                moved += usize::from(freed != to);
+            }
+
+            if moved > 0 {
+                log::debug!("Moved {} pages of task {} out of sparse blocks", moved, task.name);
+            }
+
+            moved
+        }
+        _ => 0,
+    };
+
+    // Stay on the same task until it has nothing left to move
+    if moved == 0 {
+        *cursor = Some(tid);
+    }
+
+    true
+}
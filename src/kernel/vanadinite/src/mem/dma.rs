@@ -0,0 +1,194 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::{
+    cache,
+    paging::{PageSize, PhysicalAddress, VirtualAddress},
+    phys::{self, PhysicalPage},
+    phys2virt,
+};
+#[cfg(feature = "driver.riscv_iommu")]
+use crate::drivers::generic::iommu::Iommu;
+use crate::utils;
+use alloc::vec::Vec;
+use core::ops::Range;
+use librust::task::Tid;
+use sync::SpinRwLock;
+
+pub use librust::syscalls::mem::DmaDirection;
+
+/// The mapper used to make DMA memory visible to devices, which is the
+/// [`IdentityMapper`] unless an IOMMU driver has registered itself
+pub static DMA_MAPPER: SpinRwLock<&'static dyn DmaMapper> = SpinRwLock::new(&IdentityMapper);
+#[cfg(feature = "driver.riscv_iommu")]
+pub static IOMMU: SpinRwLock<Option<&'static Iommu>> = SpinRwLock::new(None);
+
+/// An address as seen by a device doing DMA, which is only the same as the
+/// physical address when there's no IOMMU translating device accesses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(transparent)]
+pub struct DeviceAddress(usize);
+
+impl DeviceAddress {
+    pub const fn new(addr: usize) -> Self {
+        Self(addr)
+    }
+
+    pub const fn as_usize(self) -> usize {
+        self.0
+    }
+}
+
+/// DMA memory a task has allocated, kept so its device mapping can be removed
+/// when the memory is freed
+#[derive(Debug, Clone, Copy)]
+pub struct DmaAllocation {
+    pub device_addr: DeviceAddress,
+    pub len: usize,
+}
+
+/// Memory a task has pinned for DMA, kept so it can be removed from the task's
+/// devices and unpinned again
+#[derive(Debug)]
+pub struct DmaPin {
+    /// Where the memory is mapped in the task
+    pub range: Range<VirtualAddress>,
+    /// Every page pinned with [`phys::pin`]
+    pub pages: Vec<(PhysicalPage, PageSize)>,
+    pub segments: Vec<PinnedSegment>,
+}
+
+impl DmaPin {
+    /// Call `f` with each physically contiguous piece of the part of the pinned
+    /// memory at `range`, which has to lie within it
+    pub fn for_each_in(&self, range: Range<VirtualAddress>, mut f: impl FnMut(PhysicalAddress, usize)) {
+        let start = range.start.as_usize() - self.range.start.as_usize();
+        let end = range.end.as_usize() - self.range.start.as_usize();
+        let mut offset = 0;
+
+        for segment in &self.segments {
+            let (from, to) = (start.max(offset), end.min(offset + segment.len));
+            if from < to {
+                f(segment.phys.offset(from - offset), to - from);
+            }
+
+            offset += segment.len;
+        }
+    }
+
+    /// Remove the memory from the devices of `owner` and unpin it
+    pub fn release(self, owner: Tid) {
+        for segment in &self.segments {
+            let (addr, len) = segment.mapping();
+            unmap(owner, addr, len);
+        }
+
+        for (page, size) in self.pages {
+            phys::unpin(page, size);
+        }
+    }
+}
+
+/// A physically contiguous piece of a [`DmaPin`]
+#[derive(Debug, Clone, Copy)]
+pub struct PinnedSegment {
+    pub phys: PhysicalAddress,
+    pub len: usize,
+    pub device_addr: DeviceAddress,
+}
+
+impl PinnedSegment {
+    /// Map `len` bytes at `phys`, which needn't be page aligned, for the
+    /// devices of `owner`
+    pub fn map(owner: Tid, phys: PhysicalAddress, len: usize) -> Result<Self, DmaMapError> {
+        let offset = phys.offset_into_page(PageSize::Kilopage);
+        let start = PhysicalAddress::new(phys.as_usize() - offset);
+        let mapped = map(owner, start, utils::round_up_to_next(offset + len, PageSize::Kilopage.to_byte_size()))?;
+
+        Ok(Self { phys, len, device_addr: DeviceAddress::new(mapped.as_usize() + offset) })
+    }
+
+    /// The whole pages mapped for the segment
+    fn mapping(&self) -> (DeviceAddress, usize) {
+        let offset = self.phys.offset_into_page(PageSize::Kilopage);
+        let len = utils::round_up_to_next(offset + self.len, PageSize::Kilopage.to_byte_size());
+
+        (DeviceAddress::new(self.device_addr.as_usize() - offset), len)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaMapError {
+    /// There's no device address space left to map the memory into
+    OutOfAddressSpace,
+    /// The memory couldn't be mapped for the given task, e.g. because it has no
+    /// devices attached to it
+    NoDomain,
+}
+
+/// Makes physical memory accessible to the devices owned by a task
+pub trait DmaMapper: Send + Sync {
+    /// Map `len` bytes of contiguous physical memory starting at `phys` so it
+    /// can be accessed by the devices that `owner` has claimed, returning the
+    /// address the devices should use to access it
+    fn map(&self, owner: Tid, phys: PhysicalAddress, len: usize) -> Result<DeviceAddress, DmaMapError>;
+    /// Remove a mapping previously returned by [`DmaMapper::map`]
+    fn unmap(&self, owner: Tid, addr: DeviceAddress, len: usize);
+}
+
+/// Used when there's no IOMMU, where devices access physical memory directly
+#[derive(Debug, Clone, Copy)]
+pub struct IdentityMapper;
+
+impl DmaMapper for IdentityMapper {
+    fn map(&self, _: Tid, phys: PhysicalAddress, _: usize) -> Result<DeviceAddress, DmaMapError> {
+        Ok(DeviceAddress::new(phys.as_usize()))
+    }
+
+    fn unmap(&self, _: Tid, _: DeviceAddress, _: usize) {}
+}
+
+/// Replace the current DMA mapper, which should only be done before any DMA
+/// memory has been handed out, since existing mappings aren't carried over
+pub fn register_mapper(mapper: &'static dyn DmaMapper) {
+    *DMA_MAPPER.write() = mapper;
+}
+
+/// Make `iommu` responsible for DMA mappings, and for isolating the devices
+/// behind it
+#[cfg(feature = "driver.riscv_iommu")]
+pub fn register_iommu(iommu: &'static Iommu) {
+    *IOMMU.write() = Some(iommu);
+    register_mapper(iommu);
+}
+
+pub fn map(owner: Tid, phys: PhysicalAddress, len: usize) -> Result<DeviceAddress, DmaMapError> {
+    DMA_MAPPER.read().map(owner, phys, len)
+}
+
+pub fn unmap(owner: Tid, addr: DeviceAddress, len: usize) {
+    DMA_MAPPER.read().unmap(owner, addr, len)
+}
+
+/// Make the CPU's writes to `len` bytes at `phys` visible to a device before
+/// handing the memory to it. Does nothing on coherent platforms.
+pub fn sync_for_device(phys: PhysicalAddress, len: usize, direction: DmaDirection) {
+    match direction {
+        DmaDirection::ToDevice => cache::clean(phys2virt(phys), len),
+        // Nothing stale may be written back over what the device writes
+        DmaDirection::FromDevice | DmaDirection::Bidirectional => cache::invalidate(phys2virt(phys), len),
+    }
+}
+
+/// Make a device's writes to `len` bytes at `phys` visible to the CPU after
+/// taking the memory back from it. Does nothing on coherent platforms.
+pub fn sync_for_cpu(phys: PhysicalAddress, len: usize, direction: DmaDirection) {
+    match direction {
+        DmaDirection::ToDevice => {}
+        DmaDirection::FromDevice | DmaDirection::Bidirectional => cache::invalidate(phys2virt(phys), len),
+    }
+}
@@ -5,6 +5,15 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
+//! A first-fit heap allocator
+//!
+//! Free blocks are kept in a list ordered by address, so freeing a block can
+//! merge it with the free blocks on either side of it and the heap doesn't
+//! fragment into pieces too small to use. Each allocation is preceded by an
+//! [`AllocationHeader`] saying which block it was carved out of, which lets
+//! allocations be aligned to anything without knowing the alignment again when
+//! they're freed.
+
 use crate::{
     mem::{
         paging::PageSize,
@@ -13,7 +22,7 @@ use crate::{
     },
     utils::{round_up_to_next, Units},
 };
-use core::ptr::NonNull;
+use core::{alloc::Layout, ptr::NonNull};
 use sync::SpinMutex;
 
 pub struct FreeListAllocator {
@@ -22,154 +31,355 @@ pub struct FreeListAllocator {
 
 impl FreeListAllocator {
     pub const fn new() -> Self {
-        Self { inner: SpinMutex::new(FreeList { head: None }) }
+        Self { inner: SpinMutex::new(FreeList::new()) }
     }
 
     /// Returns the start and end for logging purposes
     pub fn init(&self, size: usize) -> (*mut u8, *mut u8) {
-        let n_pages = round_up_to_next(size, 4.kib()) / 4.kib();
+        let size = round_up_to_next(size, 4.kib());
         let origin = unsafe {
             phys2virt(
                 PHYSICAL_MEMORY_ALLOCATOR
                     .lock()
-                    .alloc_contiguous(PageSize::Kilopage, n_pages)
+                    .alloc_contiguous(PageSize::Kilopage, size / 4.kib())
                     .expect("unable to allocate memory for heap")
                     .as_phys_address(),
             )
             .as_mut_ptr()
         };
 
-        let mut inner = self.inner.lock();
-        inner.head = Some(NonNull::new(origin.cast()).expect("bad origin passed"));
+        unsafe { self.inner.lock().add_region(origin, size) };
 
-        unsafe {
-            *inner.head.unwrap().as_ptr() = FreeListNode { next: None, size: size - FreeListNode::struct_size() }
-        };
+        (origin, unsafe { origin.add(size) })
+    }
+
+    /// How much memory the heap has and how much of it is allocated
+    pub fn stats(&self) -> HeapStats {
+        let list = self.inner.lock();
+        HeapStats { total: list.total, allocated: list.allocated }
+    }
 
-        (origin, unsafe { origin.add(round_up_to_next(size, 4.kib())) })
+    /// Like [`Self::stats`], for when the heap might be locked forever
+    pub fn try_stats(&self) -> Option<HeapStats> {
+        let list = self.inner.try_lock()?;
+        Some(HeapStats { total: list.total, allocated: list.allocated })
     }
 }
 
+/// In bytes, with the allocated bytes including allocation headers and padding
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub total: usize,
+    pub allocated: usize,
+}
+
 unsafe impl Send for FreeListAllocator {}
 unsafe impl Sync for FreeListAllocator {}
 
-// FIXME: fragmented as heck
 unsafe impl alloc::alloc::GlobalAlloc for FreeListAllocator {
-    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        let mut this = self.inner.lock();
-
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         log::debug!("FreeListAllocator::alloc: allocating {:?}", layout);
-        let size = align_to_usize(layout.size());
+        self.inner.lock().alloc(layout)
+    }
 
-        if layout.align() > 8 {
-            todo!("FreeListAllocator::alloc: >8 byte alignment");
-        }
+    unsafe fn dealloc(&self, ptr: *mut u8, _: Layout) {
+        assert!(!ptr.is_null());
+        self.inner.lock().dealloc(ptr)
+    }
+}
 
-        let head = this.head.expect("Heap allocator wasn't initialized!").as_ptr();
+struct FreeList {
+    head: Option<NonNull<FreeListNode>>,
+    total: usize,
+    allocated: usize,
+}
 
-        let mut prev_node: Option<*mut FreeListNode> = None;
-        let mut node = head;
+unsafe impl Send for FreeList {}
 
-        log::trace!("FreeListAllocator::alloc: head={:?}", &*head);
+impl FreeList {
+    const fn new() -> Self {
+        Self { head: None, total: 0, allocated: 0 }
+    }
 
-        loop {
-            log::trace!("FreeListAllocator::alloc: checking node, node={:?}", &*node);
-            // if the node's size is large enough to fit another header + at
-            // least 8 bytes, we can split it
-            let enough_for_split = (*node).size >= size + FreeListNode::struct_size() + 8;
+    /// Hand `len` bytes starting at `start` over to the free list
+    ///
+    /// # Safety
+    ///
+    /// The memory must be valid, unused by anything else, and not overlap any
+    /// memory already in the free list
+    unsafe fn add_region(&mut self, start: *mut u8, len: usize) {
+        let aligned_start = round_up_to_next(start as usize, MIN_ALIGN);
+        let len = (len - (aligned_start - start as usize)) & !(MIN_ALIGN - 1);
+
+        if len >= MIN_BLOCK_SIZE {
+            self.insert(aligned_start, len);
+            self.total += len;
+        }
+    }
 
-            if (*node).size >= size && !enough_for_split {
-                log::trace!("FreeListAllocator::alloc: reusing node, but its not big enough to split");
+    /// The first free block which can fit `layout` is split up to make room for
+    /// it, returning null if there isn't one
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let align = layout.align().max(MIN_ALIGN);
+        let size = round_up_to_next(layout.size().max(1), MIN_ALIGN);
+
+        let mut prev: Option<*mut FreeListNode> = None;
+        let mut next = self.head;
+
+        while let Some(node) = next {
+            let node = node.as_ptr();
+            let block_start = node as usize;
+            let block_end = block_start + (*node).size;
+            next = (*node).next;
+
+            let mut data = round_up_to_next(block_start + HEADER_SIZE, align);
+            // Padding before the allocation that's too small to be a free block
+            // on its own can't be left behind, so push the allocation further
+            // along until it's either gone or big enough
+            if data - HEADER_SIZE != block_start && data - HEADER_SIZE - block_start < MIN_BLOCK_SIZE {
+                data = round_up_to_next(block_start + HEADER_SIZE + MIN_BLOCK_SIZE, align);
+            }
 
-                match prev_node {
-                    Some(prev_node) => (*prev_node).next = (*node).next,
-                    None => this.head = Some((*node).next.expect("valid next")),
+            let end = match data.checked_add(size) {
+                Some(end) if end <= block_end => end,
+                _ => {
+                    prev = Some(node);
+                    continue;
                 }
+            };
 
-                break (&*node).data();
+            log::trace!("FreeList::alloc: using block {:#x}-{:#x} for {:#x}-{:#x}", block_start, block_end, data, end);
+
+            // Unlink the block, then give back whatever's left over on either
+            // side of the allocation
+            match prev {
+                Some(prev) => (*prev).next = next,
+                None => self.head = next,
             }
 
-            if (*node).size >= size && enough_for_split {
-                log::trace!("FreeListAllocator::alloc: reusing node and splitting");
+            let mut alloc_start = block_start;
+            if data - HEADER_SIZE != block_start {
+                self.insert(block_start, data - HEADER_SIZE - block_start);
+                alloc_start = data - HEADER_SIZE;
+            }
 
-                let new_node = (&mut *node).split(size);
+            let mut alloc_end = end;
+            match block_end - end {
+                0 => {}
+                rest if rest < MIN_BLOCK_SIZE => alloc_end = block_end,
+                rest => self.insert(end, rest),
+            }
 
-                log::trace!(
-                    "FreeListAllocator::alloc: created new node, current node={:?}, new node={:?}",
-                    &*node,
-                    &*new_node.as_ptr()
-                );
+            *((data - HEADER_SIZE) as *mut AllocationHeader) =
+                AllocationHeader { start: alloc_start, size: alloc_end - alloc_start };
+            self.allocated += alloc_end - alloc_start;
 
-                match prev_node {
-                    Some(prev_node) => (*prev_node).next = Some(new_node),
-                    None => {
-                        log::trace!("Setting head to {:?}", &*new_node.as_ptr());
-                        this.head = Some(new_node);
-                    }
-                }
+            return data as *mut u8;
+        }
 
-                break (&*node).data();
-            }
+        core::ptr::null_mut()
+    }
 
-            match (*node).next {
-                Some(next) => {
-                    prev_node = Some(node);
-                    node = next.as_ptr();
-                }
-                None => return core::ptr::null_mut(),
+    /// Return the block `ptr` was allocated from to the free list
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by [`FreeList::alloc`] on this list and not
+    /// freed since
+    unsafe fn dealloc(&mut self, ptr: *mut u8) {
+        let header = *(ptr.sub(HEADER_SIZE) as *const AllocationHeader);
+        log::debug!("FreeList::dealloc: freeing {:#x}-{:#x}", header.start, header.start + header.size);
+
+        self.insert(header.start, header.size);
+        self.allocated -= header.size;
+    }
+
+    /// Insert a free block in address order, merging it with its neighbors if
+    /// they're right next to it
+    unsafe fn insert(&mut self, start: usize, size: usize) {
+        let mut prev: Option<*mut FreeListNode> = None;
+        let mut next = self.head;
+
+        while let Some(node) = next {
+            if node.as_ptr() as usize > start {
+                break;
             }
+
+            prev = Some(node.as_ptr());
+            next = (*node.as_ptr()).next;
         }
-    }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, _: core::alloc::Layout) {
-        assert!(!ptr.is_null());
+        if let Some(prev) = prev {
+            assert!(prev as usize + (*prev).size <= start, "double free of heap block @ {:#x}", start);
+        }
 
-        let mut inner = self.inner.lock();
-        let ptr = (ptr as usize - core::mem::size_of::<FreeListNode>()) as *mut FreeListNode;
+        if let Some(next) = next {
+            assert!(start + size <= next.as_ptr() as usize, "double free of heap block @ {:#x}", start);
+        }
+
+        let node = start as *mut FreeListNode;
+        *node = FreeListNode { next, size };
+
+        if let Some(next) = next {
+            if start + size == next.as_ptr() as usize {
+                (*node).size += (*next.as_ptr()).size;
+                (*node).next = (*next.as_ptr()).next;
+            }
+        }
 
-        log::debug!("Freeing {:?}, head={:?}", &*ptr, &*inner.head.unwrap().as_ptr());
-        (*ptr).next = inner.head;
-        inner.head = Some(NonNull::new_unchecked(ptr));
+        match prev {
+            Some(prev) if prev as usize + (*prev).size == start => {
+                (*prev).size += (*node).size;
+                (*prev).next = (*node).next;
+            }
+            Some(prev) => (*prev).next = Some(NonNull::new_unchecked(node)),
+            None => self.head = Some(NonNull::new_unchecked(node)),
+        }
     }
-}
 
-struct FreeList {
-    head: Option<NonNull<FreeListNode>>,
+    /// The free blocks, as `(start, size)`
+    #[cfg(test)]
+    fn blocks(&self) -> alloc::vec::Vec<(usize, usize)> {
+        let mut blocks = alloc::vec::Vec::new();
+        let mut next = self.head;
+        while let Some(node) = next {
+            blocks.push((node.as_ptr() as usize, unsafe { (*node.as_ptr()).size }));
+            next = unsafe { (*node.as_ptr()).next };
+        }
+
+        blocks
+    }
 }
 
-unsafe impl Send for FreeList {}
+const MIN_ALIGN: usize = core::mem::size_of::<usize>();
+const HEADER_SIZE: usize = core::mem::size_of::<AllocationHeader>();
+const MIN_BLOCK_SIZE: usize = core::mem::size_of::<FreeListNode>();
 
+/// Written over the start of each free block
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
 struct FreeListNode {
-    next: Option<core::ptr::NonNull<FreeListNode>>,
+    next: Option<NonNull<FreeListNode>>,
+    /// Including the node itself
+    size: usize,
+}
+
+/// Sits right before each allocation
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct AllocationHeader {
+    /// The start of the block the allocation was carved out of, which is before
+    /// the header if there was padding to align the allocation
+    start: usize,
+    /// Including the header and any padding
     size: usize,
 }
 
-impl FreeListNode {
-    fn data(&self) -> *mut u8 {
-        unsafe { (self as *const _ as *const u8 as *mut u8).add(core::mem::size_of::<Self>()) }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use vanadinite_macros::test;
+
+    const ARENA_PAGES: usize = 16;
+
+    /// Run `f` with a free list managing 64 KiB of memory taken from the
+    /// kernel's allocator
+    fn with_free_list(f: impl FnOnce(&mut FreeList, usize)) {
+        let region = unsafe { PHYSICAL_MEMORY_ALLOCATOR.lock().alloc_contiguous(PageSize::Kilopage, ARENA_PAGES) };
+        let region = region.unwrap();
+        let start = phys2virt(region.as_phys_address()).as_mut_ptr();
+
+        let mut list = FreeList::new();
+        unsafe { list.add_region(start, ARENA_PAGES * 4.kib()) };
+        f(&mut list, start as usize);
+
+        unsafe { PHYSICAL_MEMORY_ALLOCATOR.lock().dealloc_contiguous(region, PageSize::Kilopage, ARENA_PAGES) };
+    }
+
+    #[test]
+    fn freed_blocks_coalesce() {
+        with_free_list(|list, start| {
+            let layout = Layout::from_size_align(100, 8).unwrap();
+            let allocations = (0..32).map(|_| unsafe { list.alloc(layout) }).collect::<Vec<_>>();
+            assert!(allocations.iter().all(|ptr| !ptr.is_null()));
+
+            // Free every other one first so nothing can merge until the rest
+            // are freed
+            for ptr in allocations.iter().step_by(2).chain(allocations.iter().skip(1).step_by(2)) {
+                unsafe { list.dealloc(*ptr) };
+            }
+
+            assert_eq!(list.blocks(), [(start, ARENA_PAGES * 4.kib())]);
+        });
     }
 
-    fn struct_size() -> usize {
-        core::mem::size_of::<Self>()
+    #[test]
+    fn allocated_bytes_are_counted() {
+        with_free_list(|list, _| {
+            assert_eq!((list.total, list.allocated), (ARENA_PAGES * 4.kib(), 0));
+
+            let ptr = unsafe { list.alloc(Layout::from_size_align(100, 8).unwrap()) };
+            assert!(list.allocated >= 100 + HEADER_SIZE);
+
+            unsafe { list.dealloc(ptr) };
+            assert_eq!(list.allocated, 0);
+        });
     }
 
-    fn split(&mut self, mut new_size: usize) -> NonNull<FreeListNode> {
-        assert!(self.size > (new_size + Self::struct_size()), "trying to split off more than is available");
+    #[test]
+    fn large_alignments_are_respected() {
+        with_free_list(|list, start| {
+            let small = unsafe { list.alloc(Layout::from_size_align(24, 8).unwrap()) };
+            let aligned = [16, 64, 256, 4096]
+                .into_iter()
+                .map(|align| (align, unsafe { list.alloc(Layout::from_size_align(40, align).unwrap()) }))
+                .collect::<Vec<_>>();
+
+            for (align, ptr) in &aligned {
+                assert!(!ptr.is_null());
+                assert_eq!(*ptr as usize % align, 0);
+            }
 
-        new_size = align_to_usize(new_size);
+            // A page-sized, page-aligned allocation takes up a whole page
+            let page = unsafe { list.alloc(Layout::from_size_align(4096, 4096).unwrap()) };
+            assert_eq!(page as usize % 4096, 0);
+            unsafe { core::ptr::write_bytes(page, 0xAA, 4096) };
 
-        let other_size = self.size - new_size - Self::struct_size();
-        self.size = new_size;
+            for (_, ptr) in aligned {
+                unsafe { list.dealloc(ptr) };
+            }
 
-        let next_node: *mut Self = unsafe { (self as *mut _ as *mut u8).add(Self::struct_size() + self.size).cast() };
-        unsafe { *next_node = FreeListNode { next: self.next.take(), size: other_size } };
+            unsafe { list.dealloc(page) };
+            unsafe { list.dealloc(small) };
 
-        NonNull::new(next_node).unwrap()
+            assert_eq!(list.blocks(), [(start, ARENA_PAGES * 4.kib())]);
+        });
     }
-}
 
-fn align_to_usize(n: usize) -> usize {
-    (n + core::mem::size_of::<usize>() - 1) & !(core::mem::size_of::<usize>() - 1)
+    #[test]
+    fn interleaved_allocations_reuse_space() {
+        with_free_list(|list, start| {
+            let big = Layout::from_size_align(8.kib(), 8).unwrap();
+            let small = Layout::from_size_align(16, 8).unwrap();
+
+            // Without coalescing, the space between the small allocations
+            // would end up split into pieces too small for the big ones
+            for _ in 0..64 {
+                let a = unsafe { list.alloc(big) };
+                let b = unsafe { list.alloc(small) };
+                let c = unsafe { list.alloc(big) };
+                assert!(!a.is_null() && !b.is_null() && !c.is_null());
+
+                unsafe { list.dealloc(a) };
+                unsafe { list.dealloc(c) };
+                unsafe { list.dealloc(b) };
+            }
+
+            assert_eq!(list.blocks(), [(start, ARENA_PAGES * 4.kib())]);
+
+            // Too big to ever fit
+            assert!(unsafe { list.alloc(Layout::from_size_align(ARENA_PAGES * 4.kib(), 8).unwrap()) }.is_null());
+        });
+    }
 }
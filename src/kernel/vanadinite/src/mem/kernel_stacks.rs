@@ -0,0 +1,79 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Kernel stacks, mapped into a gigabyte of the kernel's half of the address
+//! space set aside for them instead of used through the linear map, so each
+//! one can have an unmapped guard page below it. Overflowing a stack then
+//! faults on the guard page instead of quietly running into whatever memory is
+//! next to it.
+//!
+//! Stacks are laid out one after the other and never unmapped, so the guard
+//! page of the next stack also sits above each one, which is what catches
+//! shadow call stacks overflowing since they grow upwards. Nothing else is
+//! mapped there, so any fault in the area is a stack overflowing.
+
+use super::{
+    paging::{
+        flags::{ACCESSED, DIRTY, READ, VALID, WRITE},
+        PageSize, PageTable, PhysicalAddress, VirtualAddress,
+    },
+    sfence,
+};
+use crate::utils::Units;
+use core::ops::Range;
+use sync::SpinMutex;
+
+/// Where the kernel stacks are mapped, past the linear map and the kernel
+/// image
+const KERNEL_STACKS_START: usize = 0xFFFF_FFE0_0000_0000;
+const KERNEL_STACKS_SIZE: usize = 1 << 30;
+
+/// How far into the area the next stack's guard page goes
+static NEXT_STACK: SpinMutex<usize> = SpinMutex::new(0);
+
+extern "C" {
+    static __tmp_stack_guard: crate::utils::LinkerSymbol;
+}
+
+fn area() -> Range<VirtualAddress> {
+    VirtualAddress::new(KERNEL_STACKS_START)..VirtualAddress::new(KERNEL_STACKS_START + KERNEL_STACKS_SIZE)
+}
+
+/// Make the tables the kernel stacks are mapped under, which has to happen
+/// before any address space other than the bootstrap one is made so they all
+/// share them
+pub fn reserve(root_page_table: &mut PageTable) {
+    root_page_table.static_reserve(VirtualAddress::new(KERNEL_STACKS_START), PageSize::Megapage);
+}
+
+/// Map the `n_pages` pages of memory at `phys` as a stack with a guard page
+/// below it, returning the top of the stack
+pub fn map(phys: PhysicalAddress, n_pages: usize) -> VirtualAddress {
+    let mut next = NEXT_STACK.lock();
+    let len = (n_pages + 1) * 4.kib();
+    assert!(*next + len <= KERNEL_STACKS_SIZE, "out of address space for kernel stacks");
+
+    let bottom = VirtualAddress::new(KERNEL_STACKS_START + *next + 4.kib());
+    let flags = DIRTY | ACCESSED | READ | WRITE | VALID;
+    for page in 0..n_pages {
+        let to = bottom.add(page * 4.kib());
+        unsafe { PageTable::map_kernel(phys.offset(page * 4.kib()), to, flags, PageSize::Kilopage) };
+        sfence(Some(to), None);
+    }
+
+    *next += len;
+
+    bottom.add(n_pages * 4.kib())
+}
+
+/// Whether `at` is in a guard page next to a kernel stack, including the one
+/// below the stack the boot hart starts on
+pub fn is_guard_page(at: VirtualAddress) -> bool {
+    let boot_guard = VirtualAddress::new(unsafe { __tmp_stack_guard.as_usize() });
+
+    area().contains(&at) || (boot_guard..boot_guard.add(4.kib())).contains(&at)
+}
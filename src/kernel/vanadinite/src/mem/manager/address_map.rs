@@ -204,6 +204,11 @@ impl AddressMap {
         self.map.range(address..).next().map(|(_, r)| r)
     }
 
+    /// Find the region containing the given [`VirtualAddress`], mutably
+    pub fn find_mut(&mut self, address: VirtualAddress) -> Option<&mut AddressRegion> {
+        self.map.range_mut(address..).next().map(|(_, r)| r)
+    }
+
     /// Returns the unoccupied regions in the address space
     pub fn unoccupied_regions(&self) -> impl Iterator<Item = &AddressRegion> {
         self.map.values().filter(|v| v.region.is_none())
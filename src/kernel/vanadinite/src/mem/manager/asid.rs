@@ -0,0 +1,169 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Address space identifiers, which tag TLB entries with the address space
+//! they belong to so switching between address spaces doesn't need a full
+//! flush
+//!
+//! ASID 0 is used by the kernel's bootstrap page table, and shared by every
+//! address space which couldn't be given its own because they've run out (or
+//! the hart doesn't implement any). Switching to an address space using it
+//! still flushes everything.
+//!
+//! A hart only flushes an address space's own entries when switching to it if
+//! its mappings have changed since the last time the hart flushed them. This
+//! also covers ASIDs being reused, since a new [`Asid`] hasn't been flushed on
+//! any hart yet. Harts running the address space while its mappings change are
+//! sent a [`shootdown`](crate::mem::shootdown) instead.
+
+use crate::{
+    csr::satp::{self, Satp},
+    mem::{
+        paging::{PageSize, VirtualAddress},
+        sfence, shootdown,
+    },
+    HART_ID, N_CPUS,
+};
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use sync::SpinMutex;
+
+/// How many of the `satp` ASID bits the harts implement
+static ASID_BITS: AtomicUsize = AtomicUsize::new(0);
+static POOL: SpinMutex<AsidPool> = SpinMutex::new(AsidPool { next: 1, freed: Vec::new() });
+/// Tells apart address spaces sharing an ASID, or reusing one, when keeping
+/// track of which harts are running which
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+struct AsidPool {
+    /// The next ASID which has never been handed out
+    next: usize,
+    freed: Vec<u16>,
+}
+
+/// Find out how many ASID bits are implemented by writing all ones to them
+/// and seeing which stick. Assumes every hart implements the same number.
+pub fn init() -> usize {
+    let current = satp::read();
+    satp::write(Satp { asid: u16::MAX, ..current });
+    let bits = satp::read().asid.count_ones() as usize;
+    satp::write(current);
+
+    ASID_BITS.store(bits, Ordering::Relaxed);
+    bits
+}
+
+pub struct Asid {
+    value: u16,
+    id: usize,
+    /// Bumped every time the address space's mappings change
+    generation: AtomicUsize,
+    /// The generation each hart's TLB entries for this ASID are up to date
+    /// with
+    synced: Box<[AtomicUsize]>,
+}
+
+impl Asid {
+    /// Allocate an unused ASID, or fall back to the shared one if there aren't
+    /// any left
+    pub fn alloc() -> Self {
+        let mut pool = POOL.lock();
+        let value = match pool.freed.pop() {
+            Some(value) => value,
+            None if pool.next < 1 << ASID_BITS.load(Ordering::Relaxed) => {
+                pool.next += 1;
+                (pool.next - 1) as u16
+            }
+            None => 0,
+        };
+
+        let synced = (0..N_CPUS.load(Ordering::Acquire)).map(|_| AtomicUsize::new(0)).collect();
+        Self { value, id: NEXT_ID.fetch_add(1, Ordering::Relaxed), generation: AtomicUsize::new(1), synced }
+    }
+
+    pub fn value(&self) -> u16 {
+        self.value
+    }
+
+    /// Whether this is the ASID shared by every address space which couldn't
+    /// be given their own
+    pub fn is_shared(&self) -> bool {
+        self.value == 0
+    }
+
+    /// Write `satp` with this ASID, flushing the TLB if it could have out of
+    /// date entries for it
+    pub fn activate(&self, satp: Satp) {
+        satp::write(Satp { asid: self.value, ..satp });
+        // Either a shootdown sees this hart running the address space, or this
+        // sees the generation it bumped
+        shootdown::set_active(self.id);
+
+        if self.is_shared() {
+            return sfence(None, None);
+        }
+
+        let generation = self.generation.load(Ordering::SeqCst);
+        match self.synced.get(HART_ID.get()) {
+            Some(synced) if synced.load(Ordering::Acquire) == generation => {}
+            Some(synced) => {
+                sfence(None, Some(self.value));
+                synced.store(generation, Ordering::Release);
+            }
+            None => sfence(None, Some(self.value)),
+        }
+    }
+
+    /// Flush the TLB entries for `vaddr`, or everything, in this address space
+    /// on every hart running it, and make every other hart flush them the next
+    /// time they switch to it
+    pub fn invalidate(&self, vaddr: Option<VirtualAddress>) {
+        self.flush(vaddr.map(|vaddr| {
+            let page = vaddr.align_down_to(PageSize::Kilopage);
+            page..page.add(PageSize::Kilopage.to_byte_size())
+        }));
+    }
+
+    /// Same as [`Self::invalidate`], for every page in `range`
+    pub fn invalidate_range(&self, range: Range<VirtualAddress>) {
+        self.flush(Some(range));
+    }
+
+    fn flush(&self, range: Option<Range<VirtualAddress>>) {
+        let asid = match self.is_shared() {
+            true => None,
+            false => Some(self.value),
+        };
+
+        shootdown::fence(range.clone(), asid);
+
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst);
+        // This hart only stays up to date if it already was
+        if let Some(synced) = self.synced.get(HART_ID.get()) {
+            let _ = synced.compare_exchange(generation, generation + 1, Ordering::AcqRel, Ordering::Relaxed);
+        }
+
+        shootdown::shootdown(self.id, range, asid);
+    }
+}
+
+impl core::fmt::Debug for Asid {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Asid({})", self.value)
+    }
+}
+
+impl Drop for Asid {
+    fn drop(&mut self) {
+        if !self.is_shared() {
+            POOL.lock().freed.push(self.value);
+        }
+    }
+}
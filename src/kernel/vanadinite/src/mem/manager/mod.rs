@@ -6,35 +6,104 @@
 // obtain one at https://mozilla.org/MPL/2.0/.
 
 mod address_map;
+pub mod asid;
+#[cfg(test)]
+mod tests;
 
 use crate::{
+    csr::satp::Satp,
     mem::{
         paging::{
+            self,
             flags::{self, Flags},
-            PageSize, PageTable, PageTableDebug, PhysicalAddress, VirtualAddress,
+            Mapping, PageSize, PageTable, PageTableDebug, PhysicalAddress, VirtualAddress,
         },
-        region::{MemoryRegion, PhysicalRegion, UniquePhysicalRegion},
-        sfence,
+        phys::{self, zeroed, PhysicalMemoryAllocator, PhysicalPage, PHYSICAL_MEMORY_ALLOCATOR},
+        region::{MemoryRegion, PhysicalRegion, UniquePhysicalRegion, KILOPAGES_PER_MEGAPAGE},
     },
     utils::{self, Units},
 };
 use address_map::AddressMap;
 pub use address_map::{AddressRegion, AddressRegionKind};
-use core::ops::Range;
-
-use super::region::SharedPhysicalRegion;
+use asid::Asid;
+use core::{cell::Cell, ops::Range};
+
+use super::{
+    region::SharedPhysicalRegion,
+    rmap::{self, AddressSpaceId},
+    shm::{without_write, SharedMemory},
+    swap::SwapSlot,
+};
 
+/// How a newly allocated region is filled. Memory is never handed out with
+/// anything left over from its previous owner, so uninitialized memory is
+/// still scrubbed, but may be filled with anything in the future.
 pub enum FillOption<'a> {
+    /// Start with `data`, followed by zeroes
     Data(&'a [u8]),
     Unitialized,
     Zeroed,
 }
 
+/// Allocate the physical memory backing a region and fill it, returning `None`
+/// if there isn't enough free memory left
+fn alloc_backing(size: PageSize, len: usize, contiguous: bool, fill: FillOption<'_>) -> Option<UniquePhysicalRegion> {
+    match fill {
+        FillOption::Data(data) => {
+            let mut backing = match contiguous {
+                true => UniquePhysicalRegion::try_alloc_contiguous(size, len)?,
+                false => UniquePhysicalRegion::try_alloc_sparse(size, len)?,
+            };
+            backing.copy_data_into(data);
+
+            Some(backing)
+        }
+        FillOption::Unitialized | FillOption::Zeroed => UniquePhysicalRegion::try_alloc_zeroed(size, len, contiguous),
+    }
+}
+
+/// Map `page` at `at`, which has just been populated, replacing the shared zero
+/// page if it was mapped there. Returns whether it was, in which case the old
+/// mapping still has to be flushed.
+fn map_populated(table: &mut PageTable, page: PhysicalPage, at: VirtualAddress, flags: Flags, size: PageSize) -> bool {
+    if table.page_size(at).is_none() {
+        table.map(page.as_phys_address(), at, flags, size);
+        return false;
+    }
+
+    // The zero page is only ever mapped as a kilopage
+    assert!(table.remap(at, page.as_phys_address()), "populated page at {:#p} wasn't mapped as a kilopage", at);
+    table.modify_page_flags(at, |_| flags);
+
+    true
+}
+
 pub enum InvalidRegion {
     NotMapped,
     InvalidPermissions,
 }
 
+/// Why [`MemoryManager::snapshot`] couldn't copy an address space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotError {
+    /// Device memory can't be copied
+    DeviceMemory,
+    OutOfMemory,
+    /// A page has been swapped out, which is recorded like it would be by
+    /// [`MemoryManager::copy_on_write`]
+    Swapped,
+}
+
+/// Memory pinned by [`MemoryManager::pin`]
+#[derive(Debug)]
+pub struct PinnedPages {
+    /// Every page pinned, which all have to be unpinned again
+    pub pages: alloc::vec::Vec<(PhysicalPage, PageSize)>,
+    /// The physical memory backing the range, in order, with physically
+    /// contiguous pieces merged
+    pub segments: alloc::vec::Vec<(PhysicalAddress, usize)>,
+}
+
 pub struct RegionDescription<'a> {
     pub size: PageSize,
     pub len: usize,
@@ -46,13 +115,25 @@ pub struct RegionDescription<'a> {
 
 #[derive(Debug)]
 pub struct MemoryManager {
+    id: AddressSpaceId,
+    asid: Asid,
     table: PageTable,
     address_map: AddressMap,
+    /// The last page found to be swapped out, not allocated yet, or shared
+    /// copy-on-write while checking memory passed in from userspace, so the
+    /// syscall can be restarted once it's usable
+    missing_access: Cell<Option<VirtualAddress>>,
 }
 
 impl MemoryManager {
     pub fn new() -> Self {
-        let mut this = Self { table: PageTable::new(), address_map: AddressMap::new() };
+        let mut this = Self {
+            id: AddressSpaceId::new(),
+            asid: Asid::alloc(),
+            table: PageTable::new(),
+            address_map: AddressMap::new(),
+            missing_access: Cell::new(None),
+        };
 
         this.guard(VirtualAddress::new(0));
 
@@ -63,30 +144,81 @@ impl MemoryManager {
     /// will choose a suitable, random address) with the given [`PageSize`], the
     /// number of required pages, with the given permission [`Flags`],
     /// optionally filled or zeroed.
+    #[track_caller]
     pub fn alloc_region(
         &mut self,
         at: Option<VirtualAddress>,
         description: RegionDescription,
     ) -> Range<VirtualAddress> {
+        self.try_alloc_region(at, description).expect("out of memory")
+    }
+
+    /// Same as [`Self::alloc_region`], returning `None` without mapping
+    /// anything if there isn't enough free memory left
+    pub fn try_alloc_region(
+        &mut self,
+        at: Option<VirtualAddress>,
+        description: RegionDescription,
+    ) -> Option<Range<VirtualAddress>> {
         let RegionDescription { size, len, contiguous, flags, fill, kind } = description;
         let at = at.unwrap_or_else(|| self.find_free_region(size, len));
 
         log::debug!("Allocating region at {:#p}: size={:?} n_pages={} flags={:?}", at, size, len, flags);
 
-        let mut backing = if contiguous {
-            UniquePhysicalRegion::alloc_contiguous(size, len)
-        } else {
-            UniquePhysicalRegion::alloc_sparse(size, len)
-        };
+        let backing = alloc_backing(size, len, contiguous, fill)?;
+        Some(self.map_region(Some(at), backing, flags, kind))
+    }
 
-        match fill {
-            FillOption::Data(data) => backing.copy_data_into(data),
-            FillOption::Zeroed => backing.zero(),
-            FillOption::Unitialized => {}
-        }
+    /// Reserve a region like [`Self::alloc_region`] without allocating any
+    /// memory for it yet. Each page is allocated and zeroed by
+    /// [`Self::populate`] the first time it's written to, and reads before
+    /// then see the shared zero page, see [`Self::map_zero_page`].
+    pub fn alloc_lazy_region(
+        &mut self,
+        at: Option<VirtualAddress>,
+        size: PageSize,
+        len: usize,
+        flags: Flags,
+        kind: AddressRegionKind,
+    ) -> Range<VirtualAddress> {
+        let at = at.unwrap_or_else(|| self.find_free_region(size, len));
+
+        log::debug!("Reserving lazy region at {:#p}: size={:?} n_pages={} flags={:?}", at, size, len, flags);
+
+        let range = at..at.add(size.to_byte_size() * len);
+        self.address_map
+            .alloc(
+                range.clone(),
+                MemoryRegion::Backed(PhysicalRegion::Unique(UniquePhysicalRegion::lazy(size, len))),
+                kind,
+                flags,
+            )
+            .expect("bad address mapping");
+
+        range
+    }
+
+    /// Map physical memory which has already been allocated, for when it needs
+    /// to be allocated in a way [`Self::alloc_region`] doesn't support. Pages
+    /// shared copy-on-write are mapped read-only, and pages which haven't been
+    /// touched yet aren't mapped at all.
+    pub fn map_region(
+        &mut self,
+        at: Option<VirtualAddress>,
+        backing: UniquePhysicalRegion,
+        flags: Flags,
+        kind: AddressRegionKind,
+    ) -> Range<VirtualAddress> {
+        let (size, len) = (backing.page_size(), backing.n_pages());
+        let at = at.unwrap_or_else(|| self.find_free_region(size, len));
+
+        let iter = backing.physical_addresses().enumerate().map(|(i, phys)| (i, phys, at.add(i * size.to_byte_size())));
+        for (i, phys_addr, virt_addr) in iter.filter(|(_, phys, _)| !phys.is_null()) {
+            let flags = match backing.is_cow(i) {
+                true => without_write(flags),
+                false => flags,
+            };
 
-        let iter = backing.physical_addresses().enumerate().map(|(i, phys)| (phys, at.add(i * size.to_byte_size())));
-        for (phys_addr, virt_addr) in iter {
             log::trace!("Mapping {:#p} -> {:#p}", phys_addr, virt_addr);
             self.table.map(phys_addr, virt_addr, flags, size);
         }
@@ -117,47 +249,50 @@ impl MemoryManager {
     /// Same as [`Self::alloc_region`] except produces a
     /// [`crate::mem::region::SharedPhysicalRegion`] which can be cheaply shared
     /// between tasks
+    #[track_caller]
     pub fn alloc_shared_region(
         &mut self,
         at: Option<VirtualAddress>,
         description: RegionDescription,
     ) -> (Range<VirtualAddress>, SharedPhysicalRegion) {
+        self.try_alloc_shared_region(at, description).expect("out of memory")
+    }
+
+    /// Same as [`Self::alloc_shared_region`], returning `None` without mapping
+    /// anything if there isn't enough free memory left
+    pub fn try_alloc_shared_region(
+        &mut self,
+        at: Option<VirtualAddress>,
+        description: RegionDescription,
+    ) -> Option<(Range<VirtualAddress>, SharedPhysicalRegion)> {
         let RegionDescription { size, len, contiguous, flags, fill, kind } = description;
         let at = at.unwrap_or_else(|| self.find_free_region(size, len));
-        let mut backing = if contiguous {
-            UniquePhysicalRegion::alloc_contiguous(size, len)
-        } else {
-            UniquePhysicalRegion::alloc_sparse(size, len)
-        };
-
-        match fill {
-            FillOption::Data(data) => backing.copy_data_into(data),
-            FillOption::Zeroed => backing.zero(),
-            FillOption::Unitialized => {}
-        }
+        let backing = alloc_backing(size, len, contiguous, fill)?;
 
         let iter = backing.physical_addresses().enumerate().map(|(i, phys)| (phys, at.add(i * size.to_byte_size())));
         for (phys_addr, virt_addr) in iter {
             self.table.map(phys_addr, virt_addr, flags, size);
-            sfence(Some(virt_addr), None);
+            self.asid.invalidate(Some(virt_addr));
         }
 
         let shared = backing.into_shared_region();
+        shared.record_mapping(self.id, at);
         let range = at..at.add(size.to_byte_size() * len);
 
         self.address_map
             .alloc(range.clone(), MemoryRegion::Backed(PhysicalRegion::Shared(shared.clone())), kind, flags)
             .unwrap();
 
-        (range, shared)
+        Some((range, shared))
     }
 
     /// # Safety
     /// This function is meant to map MMIO devices into userspace processes, and
     /// will allow aliasing physical memory if used incorrectly.
     ///
-    /// Memory regions will be mapped using kilopages (TODO: is larger
-    /// granularity necessary?)
+    /// Memory regions are mapped with the largest pages the alignment of `from`
+    /// and `to` allow, so if `to` isn't given the region is placed somewhere
+    /// aligned the same way `from` is.
     pub unsafe fn map_mmio_device(
         &mut self,
         from: PhysicalAddress,
@@ -165,29 +300,20 @@ impl MemoryManager {
         len: usize,
     ) -> Range<VirtualAddress> {
         let n_pages = crate::utils::round_up_to_next(4.kib(), len) / 4.kib();
-        let at = to.unwrap_or_else(|| self.find_free_region(PageSize::Kilopage, n_pages));
+        let len = n_pages * PageSize::Kilopage.to_byte_size();
+        let at = to.unwrap_or_else(|| {
+            let size = PageSize::largest_for(from, VirtualAddress::new(0), len);
+            self.find_free_region(size, utils::round_up_to_next(len, size.to_byte_size()) / size.to_byte_size())
+        });
 
-        log::debug!(
-            "Mapping MMIO region at {:#p}: phys={:#p} size={:?} n_pages={}",
-            at,
-            from,
-            PageSize::Kilopage,
-            n_pages
-        );
+        log::debug!("Mapping MMIO region at {:#p}: phys={:#p} n_pages={}", at, from, n_pages);
 
         let backing = UniquePhysicalRegion::mmio(from, PageSize::Kilopage, n_pages);
 
         let flags = flags::READ | flags::WRITE | flags::USER | flags::VALID;
-        let iter = backing
-            .physical_addresses()
-            .enumerate()
-            .map(|(i, phys)| (phys, at.add(i * PageSize::Kilopage.to_byte_size())));
-        for (phys_addr, virt_addr) in iter {
-            log::trace!("Mapping {:#p} -> {:#p}", phys_addr, virt_addr);
-            self.table.map(phys_addr, virt_addr, flags, PageSize::Kilopage);
-        }
+        self.table.map_range(from, at, len, flags);
 
-        let range = at..at.add(PageSize::Kilopage.to_byte_size() * n_pages);
+        let range = at..at.add(len);
         self.address_map
             .alloc(range.clone(), MemoryRegion::Backed(PhysicalRegion::Unique(backing)), AddressRegionKind::Mmio, flags)
             .expect("bad address mapping");
@@ -224,10 +350,11 @@ impl MemoryManager {
 
         for (phys_addr, virt_addr) in iter {
             self.table.map(phys_addr, virt_addr, flags, region.page_size());
-            sfence(Some(virt_addr), None);
+            self.asid.invalidate(Some(virt_addr));
         }
 
         let range = at..at.add(region.page_size().to_byte_size() * region.n_pages());
+        region.record_mapping(self.id, at);
 
         self.address_map
             .alloc(range.clone(), MemoryRegion::Backed(PhysicalRegion::Shared(region)), kind, flags)
@@ -236,6 +363,437 @@ impl MemoryManager {
         range
     }
 
+    /// Map each chunk of `shm` one after another, so the whole object is
+    /// contiguous in virtual memory. It's mapped read-only if writes to it
+    /// have been sealed, no matter what `flags` has.
+    pub fn map_shared_memory(
+        &mut self,
+        at: Option<VirtualAddress>,
+        flags: Flags,
+        shm: &SharedMemory,
+        kind: AddressRegionKind,
+    ) -> Range<VirtualAddress> {
+        let at = at.unwrap_or_else(|| self.find_free_region(shm.page_size(), shm.n_pages()));
+
+        shm.map_with(flags, |chunks, flags| {
+            let mut end = at;
+            for chunk in chunks {
+                end = self.apply_shared_region(Some(end), flags, chunk.clone(), kind).end;
+            }
+
+            at..end
+        })
+    }
+
+    /// Map private copies of each chunk of `shm` one after another, like
+    /// [`Self::map_shared_memory`], which share its memory until they're
+    /// written to. Returns `None` if writes to `shm` haven't been sealed.
+    pub fn map_shared_memory_copy(
+        &mut self,
+        at: Option<VirtualAddress>,
+        flags: Flags,
+        shm: &SharedMemory,
+        kind: AddressRegionKind,
+    ) -> Option<Range<VirtualAddress>> {
+        let copies = shm.copy_on_write()?;
+        let n_pages = copies.iter().map(UniquePhysicalRegion::n_pages).sum();
+        let at = at.unwrap_or_else(|| self.find_free_region(shm.page_size(), n_pages));
+
+        let mut end = at;
+        for copy in copies {
+            end = self.map_region(Some(end), copy, flags, kind).end;
+        }
+
+        Some(at..end)
+    }
+
+    /// Share the pages backing `range`, which has to be page aligned and lie
+    /// within a single region, so they can be mapped somewhere else without
+    /// being copied. A private region is turned into a shared one in place the
+    /// first time any of it is shared. Returns the pages along with the kind
+    /// and permissions of the region they're from.
+    pub fn share_range(
+        &mut self,
+        range: Range<VirtualAddress>,
+    ) -> Option<(SharedPhysicalRegion, AddressRegionKind, Flags)> {
+        let region = self.address_map.find_mut(range.start)?;
+        if range.end > region.span.end {
+            return None;
+        }
+
+        let page_size = region.region.as_ref()?.page_size().to_byte_size();
+        let first = (range.start.as_usize() - region.span.start.as_usize()) / page_size;
+        let n_pages = (range.end.as_usize() - range.start.as_usize()) / page_size;
+
+        // Anything the region shares copy-on-write has to be copied first, or
+        // writes through the shared region would show up in the copies
+        if let Some(MemoryRegion::Backed(PhysicalRegion::Unique(unique))) = &region.region {
+            let start = region.span.start;
+            let cow = unique.cow_pages().iter().map(|&i| start.add(i * page_size)).collect::<alloc::vec::Vec<_>>();
+            for at in cow {
+                self.unshare(at);
+            }
+        }
+
+        let region = self.address_map.find_mut(range.start)?;
+
+        // Shared memory can't be swapped out, so everything has to be brought
+        // back in first, and other tasks have to be able to map all of it
+        if let Some(MemoryRegion::Backed(PhysicalRegion::Unique(unique))) = &mut region.region {
+            if let Some(index) = unique.first_swapped() {
+                self.missing_access.set(Some(region.span.start.add(index * page_size)));
+                return None;
+            }
+
+            let start = region.span.start;
+            let flags = region.permissions;
+            let mut replaced_zero_page = false;
+            for index in 0..unique.n_pages() {
+                if let Some(page) = unique.populate(index) {
+                    let at = start.add(index * page_size);
+                    replaced_zero_page |= map_populated(&mut self.table, page, at, flags, unique.page_size());
+                }
+            }
+
+            if replaced_zero_page {
+                self.asid.invalidate_range(region.span.clone());
+            }
+        }
+
+        let shared = match region.region.take()? {
+            MemoryRegion::Backed(PhysicalRegion::Shared(shared)) => shared,
+            MemoryRegion::Backed(PhysicalRegion::Unique(unique)) => {
+                let shared = unique.into_shared_region();
+                shared.record_mapping(self.id, region.span.start);
+                shared
+            }
+            other => {
+                region.region = Some(other);
+                return None;
+            }
+        };
+
+        region.region = Some(MemoryRegion::Backed(PhysicalRegion::Shared(shared.clone())));
+
+        Some((shared.slice(first, n_pages)?, region.kind, region.permissions))
+    }
+
+    /// Share the pages backing `range` copy-on-write, which has to be page
+    /// aligned and lie within a single private region, returning a new private
+    /// region made of the same pages along with the kind and permissions of
+    /// the region they're from. Both regions map the pages read-only until
+    /// [`Self::break_cow`] copies them on the first write.
+    pub fn copy_on_write(
+        &mut self,
+        range: Range<VirtualAddress>,
+    ) -> Option<(UniquePhysicalRegion, AddressRegionKind, Flags)> {
+        let region = self.address_map.find_mut(range.start)?;
+        if range.end > region.span.end {
+            return None;
+        }
+
+        let unique = match &mut region.region {
+            Some(MemoryRegion::Backed(PhysicalRegion::Unique(unique))) => unique,
+            _ => return None,
+        };
+
+        let page_size = unique.page_size().to_byte_size();
+        let first = (range.start.as_usize() - region.span.start.as_usize()) / page_size;
+        let n_pages = (range.end.as_usize() - range.start.as_usize()) / page_size;
+
+        // Swapped out pages have to be brought back in to be shared
+        if let Some(index) = (first..first + n_pages).find(|&i| unique.swapped_slot(i).is_some()) {
+            self.missing_access.set(Some(region.span.start.add(index * page_size)));
+            return None;
+        }
+
+        let copy = unique.copy_on_write(first, n_pages)?;
+        for index in (first..first + n_pages).filter(|&i| unique.is_cow(i)) {
+            self.table.modify_page_flags(region.span.start.add(index * page_size), without_write);
+        }
+
+        self.asid.invalidate_range(range);
+
+        Some((copy, region.kind, region.permissions))
+    }
+
+    /// Pin every page backing `range` with [`phys::pin`], so they stay resident
+    /// and where they are in physical memory until they're unpinned. Pages
+    /// which aren't there yet have to be brought in first, as do those shared
+    /// copy-on-write in writable regions, since writing to them would replace
+    /// them. For them this returns `None` with the first one recorded for
+    /// [`Self::take_missing_access`], and nothing is pinned. Also returns
+    /// `None` if any of `range` isn't memory, or isn't mapped at all.
+    pub fn pin(&self, range: Range<VirtualAddress>) -> Option<PinnedPages> {
+        let mut pages = alloc::vec::Vec::new();
+        let mut segments = alloc::vec::Vec::<(PhysicalAddress, usize)>::new();
+        let mut at = range.start;
+
+        while at < range.end {
+            let region = self.address_map.find(at)?;
+            let offset = at.as_usize() - region.span.start.as_usize();
+            let (base, page_size) = match &region.region {
+                Some(MemoryRegion::Backed(_)) if region.kind == AddressRegionKind::Mmio => return None,
+                Some(MemoryRegion::Backed(PhysicalRegion::Unique(unique))) => {
+                    let index = offset / unique.page_size().to_byte_size();
+                    let cow = unique.is_cow(index) && region.permissions & flags::WRITE;
+                    if cow || unique.is_unpopulated(index) || unique.swapped_slot(index).is_some() {
+                        self.missing_access.set(Some(at.align_down_to(PageSize::Kilopage)));
+                        return None;
+                    }
+
+                    (unique.physical_address(index)?, unique.page_size())
+                }
+                Some(MemoryRegion::Backed(PhysicalRegion::Shared(shared))) => {
+                    (shared.physical_address(offset / shared.page_size().to_byte_size())?, shared.page_size())
+                }
+                _ => return None,
+            };
+
+            if base.is_null() {
+                return None;
+            }
+
+            let page = PhysicalPage::from_ptr(base.as_mut_ptr());
+            if pages.last().map(|&(last, _)| last) != Some(page) {
+                pages.push((page, page_size));
+            }
+
+            let phys = base.offset(offset % page_size.to_byte_size());
+            let len = (PageSize::Kilopage.to_byte_size() - at.offset_into_page(PageSize::Kilopage))
+                .min(range.end.as_usize() - at.as_usize());
+            match segments.last_mut() {
+                Some((start, segment_len)) if start.offset(*segment_len) == phys => *segment_len += len,
+                _ => segments.push((phys, len)),
+            }
+
+            at = at.add(len);
+        }
+
+        for &(page, _) in &pages {
+            phys::pin(page);
+        }
+
+        Some(PinnedPages { pages, segments })
+    }
+
+    /// Make a copy of the whole address space, with every region at the same
+    /// place, for checkpointing a task. Private regions share their pages
+    /// copy-on-write with the copy, except for those with promoted megapages
+    /// or pinned pages which are copied outright, and shared regions are
+    /// shared with it rather than copied.
+    pub fn snapshot(&mut self) -> Result<MemoryManager, SnapshotError> {
+        let regions = self
+            .address_map
+            .occupied_regions()
+            .map(|region| (region.span.clone(), region.kind, region.permissions))
+            .collect::<alloc::vec::Vec<_>>();
+        let mut copy = MemoryManager::new();
+
+        for (span, kind, flags) in regions {
+            match &self.address_map.find(span.start).expect("[BUG] region disappeared").region {
+                Some(MemoryRegion::GuardPage) => {
+                    for at in (span.start.as_usize()..span.end.as_usize()).step_by(4.kib()) {
+                        let at = VirtualAddress::new(at);
+                        if copy.is_unoccupied(at..at.add(4.kib())) {
+                            copy.guard(at);
+                        }
+                    }
+                }
+                Some(&MemoryRegion::Lazy { page_size, n_pages }) => {
+                    copy.address_map
+                        .alloc(span, MemoryRegion::Lazy { page_size, n_pages }, kind, flags)
+                        .expect("bad address mapping");
+                }
+                Some(MemoryRegion::Backed(PhysicalRegion::Shared(shared))) => {
+                    let shared = shared.clone();
+                    copy.apply_shared_region(Some(span.start), flags, shared, kind);
+                }
+                Some(MemoryRegion::Backed(PhysicalRegion::Unique(unique))) => {
+                    if let AddressRegionKind::Mmio | AddressRegionKind::Dma = kind {
+                        return Err(SnapshotError::DeviceMemory);
+                    }
+
+                    if let Some(index) = unique.first_swapped() {
+                        let page_size = unique.page_size().to_byte_size();
+                        self.missing_access.set(Some(span.start.add(index * page_size)));
+                        return Err(SnapshotError::Swapped);
+                    }
+
+                    // Megapages can't be shared copy-on-write, and neither can
+                    // pinned pages, which devices could still be writing to
+                    let promoted = (0..unique.n_pages()).any(|index| unique.is_promoted(index));
+                    let backing = match promoted || unique.has_pinned(0, unique.n_pages()) {
+                        true => unique.try_duplicate().ok_or(SnapshotError::OutOfMemory)?,
+                        false => self.copy_on_write(span.clone()).expect("[BUG] couldn't share region").0,
+                    };
+
+                    copy.map_region(Some(span.start), backing, flags, kind);
+                }
+                None => {}
+            }
+        }
+
+        Ok(copy)
+    }
+
+    /// Change the permissions of every region in `range` to `flags` and remap
+    /// their pages with them, returning `false` without changing anything if
+    /// `range` doesn't start and end on the boundaries of allocated regions,
+    /// or `allowed` rejects any of them. Pages shared copy-on-write and the
+    /// shared zero page are never mapped writable, writing to them still
+    /// faults into [`Self::break_cow`] and [`Self::populate`] like it would
+    /// have before.
+    pub fn modify_permissions(
+        &mut self,
+        range: Range<VirtualAddress>,
+        flags: Flags,
+        allowed: impl Fn(&AddressRegion) -> bool,
+    ) -> bool {
+        let mut at = range.start;
+        while at < range.end {
+            match self.address_map.find(at) {
+                Some(region)
+                    if region.span.start == at
+                        && region.span.end <= range.end
+                        && matches!(region.region, Some(MemoryRegion::Backed(_)))
+                        && allowed(region) =>
+                {
+                    at = region.span.end
+                }
+                _ => return false,
+            }
+        }
+
+        let kept = (flags::ACCESSED | flags::DIRTY).value();
+        let mut at = range.start;
+        while at < range.end {
+            let region = self.address_map.find_mut(at).unwrap();
+            region.permissions = flags;
+
+            let span = region.span.clone();
+            let page_size = region.region.as_ref().map_or(PageSize::Kilopage, MemoryRegion::page_size);
+            let unique = match &region.region {
+                Some(MemoryRegion::Backed(PhysicalRegion::Unique(unique))) => Some(unique),
+                _ => None,
+            };
+
+            while at < span.end {
+                // Promoted megapages are remapped as a whole, and never contain
+                // copy-on-write or untouched pages
+                let size = match self.table.page_size(at) {
+                    Some(size) => size,
+                    None => {
+                        at = at.add(page_size.to_byte_size());
+                        continue;
+                    }
+                };
+
+                let writable = match unique {
+                    Some(unique) => {
+                        let index = (at.as_usize() - span.start.as_usize()) / unique.page_size().to_byte_size();
+                        !unique.is_cow(index) && !unique.is_unpopulated(index)
+                    }
+                    None => true,
+                };
+
+                let new = match writable {
+                    true => flags,
+                    false => without_write(flags),
+                };
+
+                self.table.modify_page_flags(at, |current| Flags::new(new.value() | (current.value() & kept)));
+                at = at.add(size.to_byte_size());
+            }
+        }
+
+        self.asid.invalidate_range(range);
+
+        true
+    }
+
+    /// Give the region containing `at` its own copy of the page there if it's
+    /// shared copy-on-write and writable, and map it with the region's
+    /// permissions, returning whether it was. It's mapped as accessed and
+    /// dirty, since it's about to be written to.
+    pub fn break_cow(&mut self, at: VirtualAddress) -> bool {
+        match self.address_map.find(at) {
+            Some(region) if region.permissions & flags::WRITE => self.unshare(at),
+            _ => false,
+        }
+    }
+
+    /// [`Self::break_cow`], whether or not the region is writable
+    fn unshare(&mut self, at: VirtualAddress) -> bool {
+        let region = match self.address_map.find_mut(at) {
+            Some(region) => region,
+            None => return false,
+        };
+
+        let unique = match &mut region.region {
+            Some(MemoryRegion::Backed(PhysicalRegion::Unique(unique))) => unique,
+            _ => return false,
+        };
+
+        let size = unique.page_size();
+        let index = (at.as_usize() - region.span.start.as_usize()) / size.to_byte_size();
+        let (page, replaced) = match unique.unshare(index) {
+            Some(unshared) => unshared,
+            None => return false,
+        };
+
+        let flags = region.permissions | flags::ACCESSED | flags::DIRTY;
+        let at = region.span.start.add(index * size.to_byte_size());
+        let full_fence = match replaced {
+            Some(_) if self.table.remap(at, page.as_phys_address()) => false,
+            // Only kilopages can be remapped in place, and unmapping anything
+            // else can free the tables leading to it
+            Some(_) => {
+                self.table.unmap(at);
+                self.table.map(page.as_phys_address(), at, flags, size);
+                true
+            }
+            None => false,
+        };
+
+        self.table.modify_page_flags(at, |_| flags);
+        match full_fence {
+            true => self.asid.invalidate(None),
+            false => self.asid.invalidate(Some(at)),
+        }
+
+        // Nothing can still be using the old page once it's been flushed
+        if let Some(replaced) = replaced {
+            unsafe { phys::release(replaced, size) };
+        }
+
+        true
+    }
+
+    /// Whether the page containing `at` is shared copy-on-write
+    pub fn is_cow(&self, at: VirtualAddress) -> bool {
+        let region = match self.address_map.find(at) {
+            Some(region) => region,
+            None => return false,
+        };
+
+        match &region.region {
+            Some(MemoryRegion::Backed(PhysicalRegion::Unique(unique))) => {
+                let index = (at.as_usize() - region.span.start.as_usize()) / unique.page_size().to_byte_size();
+                unique.is_cow(index)
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether nothing is mapped anywhere in `range`
+    pub fn is_unoccupied(&self, range: Range<VirtualAddress>) -> bool {
+        match self.address_map.find(range.start) {
+            Some(region) => region.is_unoccupied() && region.span.end >= range.end,
+            None => false,
+        }
+    }
+
     /// Place a guard page at the given [`VirtualAddress`]
     pub fn guard(&mut self, at: VirtualAddress) {
         self.address_map
@@ -244,6 +802,38 @@ impl MemoryManager {
         self.table.map(PhysicalAddress::null(), at, flags::USER | flags::VALID, PageSize::Kilopage);
     }
 
+    /// Mark the writable region right below `sp` as a stack and place a guard
+    /// page beneath it, returning `false` if the page below the region is
+    /// already taken by something else
+    pub fn guard_stack(&mut self, sp: VirtualAddress) -> bool {
+        let top = match sp.checked_offset(-1) {
+            Some(top) => top,
+            None => return false,
+        };
+
+        let below = match self.address_map.find_mut(top) {
+            Some(region) if region.region.is_some() && !matches!(region.region, Some(MemoryRegion::GuardPage)) => {
+                if !(region.permissions & flags::WRITE) {
+                    return false;
+                }
+
+                region.kind = AddressRegionKind::Stack;
+                match region.span.start.as_usize().checked_sub(4.kib()) {
+                    Some(below) => VirtualAddress::new(below),
+                    None => return false,
+                }
+            }
+            _ => return false,
+        };
+
+        if self.is_unoccupied(below..below.add(4.kib())) {
+            self.guard(below);
+            return true;
+        }
+
+        matches!(self.address_map.find(below), Some(AddressRegion { region: Some(MemoryRegion::GuardPage), .. }))
+    }
+
     /// Deallocate the region specified by the given [`VirtualAddress`]
     #[track_caller]
     pub fn dealloc_region(&mut self, at: VirtualAddress) -> MemoryRegion {
@@ -251,29 +841,136 @@ impl MemoryManager {
         assert!(region.region.is_some(), "trying to dealloc an unallocated region");
 
         let span = region.span.clone();
-        let region = self.address_map.free(span).expect("tried deallocing an unmapped region");
+        let region = self.address_map.free(span.clone()).expect("tried deallocing an unmapped region");
+        self.unmap_range(span.clone());
 
-        let iter = (0..region.page_count()).map(|i| at.add(i * region.page_size().to_byte_size()));
-        for virt_addr in iter {
-            self.table.unmap(virt_addr);
-            // FIXME: this is unnecessary when unmapping from other tasks than
-            // the current one? need IPIs for that?
-            sfence(Some(virt_addr), None);
+        if let MemoryRegion::Backed(PhysicalRegion::Shared(shared)) = &region {
+            shared.forget_mapping(self.id, span.start);
         }
 
         region
     }
 
+    /// Resize the private region starting at `at` to `n_pages` of its pages,
+    /// returning where it is afterwards. Growing it extends it in place if
+    /// nothing is mapped after it, and moves it somewhere with enough room
+    /// otherwise, keeping its pages either way rather than copying them. The
+    /// pages added are untouched, like those of [`Self::alloc_lazy_region`].
+    /// Returns `None` if there isn't a private region starting at `at`.
+    pub fn resize_region(&mut self, at: VirtualAddress, n_pages: usize) -> Option<Range<VirtualAddress>> {
+        let region = self.address_map.find(at)?;
+        let (span, kind, flags) = (region.span.clone(), region.kind, region.permissions);
+        let (size, old_n_pages) = match &region.region {
+            Some(MemoryRegion::Backed(PhysicalRegion::Unique(unique))) if span.start == at && n_pages > 0 => {
+                (unique.page_size(), unique.n_pages())
+            }
+            _ => return None,
+        };
+
+        if n_pages == old_n_pages {
+            return Some(span);
+        }
+
+        let new_end = at.checked_add(n_pages * size.to_byte_size());
+        let in_place = match new_end {
+            Some(new_end) if n_pages > old_n_pages => self.is_unoccupied(span.end..new_end),
+            Some(_) => true,
+            None => false,
+        };
+
+        let range = match in_place {
+            true => at..new_end.unwrap(),
+            false => {
+                let to = self.find_free_region(size, n_pages);
+                to..to.add(n_pages * size.to_byte_size())
+            }
+        };
+
+        if n_pages < old_n_pages {
+            // A promoted megapage might be cut short
+            self.demote(range.end);
+            self.unmap_range(range.end..span.end);
+        }
+
+        if !in_place {
+            self.unmap_range(span.clone());
+        }
+
+        let mut unique = match self.address_map.free(span).expect("region disappeared") {
+            MemoryRegion::Backed(PhysicalRegion::Unique(unique)) => unique,
+            _ => unreachable!(),
+        };
+
+        match n_pages < old_n_pages {
+            true => unique.truncate(n_pages),
+            false => assert!(unique.extend(n_pages - old_n_pages), "resizing device memory"),
+        }
+
+        log::debug!("Resizing region at {:#p} to {:#p}-{:#p}", at, range.start, range.end);
+
+        match in_place {
+            true => self
+                .address_map
+                .alloc(range.clone(), MemoryRegion::Backed(PhysicalRegion::Unique(unique)), kind, flags)
+                .expect("bad address mapping"),
+            false => {
+                self.map_region(Some(range.start), unique, flags, kind);
+            }
+        }
+
+        Some(range)
+    }
+
+    /// Unmap everything mapped in `range`, which can include pages of any size
+    fn unmap_range(&mut self, range: Range<VirtualAddress>) {
+        // Parts of the region may have been promoted to megapages
+        let mut virt_addr = range.start;
+        let table_pages = self.table.table_pages();
+        while virt_addr < range.end {
+            // Pages which were swapped out aren't mapped
+            if self.table.page_size(virt_addr).is_none() {
+                virt_addr = virt_addr.add(PageSize::Kilopage.to_byte_size());
+                continue;
+            }
+
+            let size = self.table.unmap(virt_addr);
+            virt_addr = virt_addr.add(size.to_byte_size());
+        }
+
+        // Fencing an address only has to flush the leaf entry for it, so a
+        // full fence is needed for any tables which were freed
+        match self.table.table_pages() == table_pages {
+            true => self.asid.invalidate_range(range),
+            false => self.asid.invalidate(None),
+        }
+    }
+
+    /// Identifies this address space in the [`rmap`]s of the shared memory
+    /// mapped into it
+    ///
+    /// [`rmap`]: crate::mem::rmap
+    pub fn id(&self) -> AddressSpaceId {
+        self.id
+    }
+
+    /// The number of pages used by this address space's page tables
+    pub fn page_table_pages(&self) -> usize {
+        self.table.table_pages()
+    }
+
     /// Returns the [`AddressRegion`] that contains the given
     /// [`VirtualAddress`], if it exists
     pub fn region_for(&self, at: VirtualAddress) -> Option<&AddressRegion> {
         self.address_map.find(at)
     }
 
-    pub fn map_direct(&mut self, map_from: PhysicalAddress, map_to: VirtualAddress, n_pages: PageSize, flags: Flags) {
-        self.table.map(map_from, map_to, flags, n_pages);
+    /// Map `len` bytes of physically contiguous memory at `map_from` to
+    /// `map_to`, using megapages and gigapages where they fit
+    pub fn map_direct(&mut self, map_from: PhysicalAddress, map_to: VirtualAddress, len: usize, flags: Flags) {
+        self.table.map_range(map_from, map_to, len, flags);
 
-        sfence(Some(map_to), None);
+        // Fencing each page would be one fence per kilopage at worst
+        self.asid.invalidate(None);
     }
 
     /// Iterates over the given address range, returning `Ok(())` if each page
@@ -296,8 +993,22 @@ impl MemoryManager {
             }
 
             match self.page_flags(page) {
-                Some(flags) if !f(flags) => return Err((page, InvalidRegion::InvalidPermissions)),
-                None => return Err((page, InvalidRegion::NotMapped)),
+                Some(flags) if !f(flags) => {
+                    // Writing to it copies it, or replaces the shared zero
+                    // page, after which it can be used
+                    if f(flags | flags::WRITE) && (self.is_cow(page) || self.is_unpopulated(page)) {
+                        self.missing_access.set(Some(page));
+                    }
+
+                    return Err((page, InvalidRegion::InvalidPermissions));
+                }
+                None => {
+                    if self.swapped_slot(page).is_some() || self.is_unpopulated(page) {
+                        self.missing_access.set(Some(page));
+                    }
+
+                    return Err((page, InvalidRegion::NotMapped));
+                }
                 _ => {}
             }
         }
@@ -305,15 +1016,311 @@ impl MemoryManager {
         Ok(())
     }
 
+    /// Take the page which was last found to be swapped out, not allocated
+    /// yet, or shared copy-on-write by [`Self::is_user_region_valid`],
+    /// [`Self::share_range`] or [`Self::copy_on_write`], if there was one
+    /// since the last time this was called
+    pub fn take_missing_access(&self) -> Option<VirtualAddress> {
+        self.missing_access.take()
+    }
+
     /// Returns the [`Flags`] of the given [`VirtualAddress`], if it's mapped
     pub fn page_flags(&self, virt: VirtualAddress) -> Option<Flags> {
         self.table.page_flags(virt)
     }
 
     /// Modify the page flags of the given [`VirtualAddress`] mapping, returning
-    /// whether or not the mapping exists
+    /// whether or not the mapping exists. Changing anything other than the
+    /// accessed and dirty bits splits a promoted megapage back up first, so
+    /// that only the one page is changed, and flushes the old flags out of
+    /// every hart's TLB.
     pub fn modify_page_flags(&mut self, virt: VirtualAddress, f: impl FnOnce(Flags) -> Flags) -> bool {
-        self.table.modify_page_flags(virt, f)
+        let current = match self.table.page_flags(virt) {
+            Some(flags) => flags,
+            None => return false,
+        };
+
+        let new = f(current);
+        let ignored = (flags::ACCESSED | flags::DIRTY).value();
+        if current.value() & !ignored == new.value() & !ignored {
+            return self.table.modify_page_flags(virt, |_| new);
+        }
+
+        self.demote(virt);
+        self.table.modify_page_flags(virt, |_| new);
+        self.asid.invalidate(Some(virt));
+
+        true
+    }
+
+    /// Split the megapage or gigapage containing `virt` back up into
+    /// kilopages if the region it's in is made of them, which is the case for
+    /// promoted megapages and MMIO mapped with larger pages
+    pub fn demote(&mut self, virt: VirtualAddress) {
+        let kilopages = matches!(self.address_map.find(virt), Some(region) if region.region.as_ref().map(MemoryRegion::page_size) == Some(PageSize::Kilopage));
+        if !kilopages {
+            return;
+        }
+
+        while let Some(size @ (PageSize::Megapage | PageSize::Gigapage)) = self.table.page_size(virt) {
+            let at = virt.align_down_to(size);
+            self.table.demote(at);
+            self.asid.invalidate(Some(at));
+        }
+    }
+
+    /// Promote one megapage sized and aligned run of kilopages in a private
+    /// userspace allocation to a megapage, returning whether one was promoted.
+    /// The memory is copied, so the task must not be running.
+    pub fn promote_one(&mut self) -> bool {
+        let megapage = PageSize::Megapage.to_byte_size();
+        let candidates = self
+            .address_map
+            .occupied_regions()
+            .filter(|region| region.kind == AddressRegionKind::UserAllocated)
+            .flat_map(|region| {
+                let first = region.span.start.align_to_next(PageSize::Megapage).as_usize();
+                let end = region.span.end.as_usize();
+                (first..end.saturating_sub(megapage - 1)).step_by(megapage).map(VirtualAddress::new)
+            })
+            .collect::<alloc::vec::Vec<_>>();
+
+        for at in candidates {
+            if self.table.page_size(at) != Some(PageSize::Kilopage) {
+                continue;
+            }
+
+            // The pages all need the same permissions to be mapped as one
+            let flags = match self.uniform_flags(at) {
+                Some(flags) => flags,
+                None => continue,
+            };
+
+            let region = self.address_map.find_mut(at).unwrap();
+            let index = (at.as_usize() - region.span.start.as_usize()) / PageSize::Kilopage.to_byte_size();
+            let unique = match &mut region.region {
+                Some(MemoryRegion::Backed(PhysicalRegion::Unique(unique)))
+                    if !unique.is_promoted(index)
+                        && !unique.cow_pages().iter().any(|i| (index..index + KILOPAGES_PER_MEGAPAGE).contains(i))
+                        && !(index..index + KILOPAGES_PER_MEGAPAGE).any(|i| unique.is_unpopulated(i))
+                        && !unique.has_pinned(index, KILOPAGES_PER_MEGAPAGE) =>
+                {
+                    unique
+                }
+                _ => continue,
+            };
+
+            // Everything else `promote` rejects was ruled out above, so this
+            // only fails when there are no megapages left, and there's no point
+            // trying the rest
+            let (megapage, replaced) = match unique.promote(index) {
+                Some(promoted) => promoted,
+                None => return false,
+            };
+
+            self.table.promote(megapage, at, flags);
+            self.asid.invalidate(None);
+
+            let mut allocator = PHYSICAL_MEMORY_ALLOCATOR.lock();
+            for page in replaced {
+                unsafe { allocator.dealloc(page, PageSize::Kilopage) };
+            }
+
+            log::debug!("Promoted {:#p} to a megapage", at);
+            return true;
+        }
+
+        false
+    }
+
+    /// The kilopages of private memory which can be moved elsewhere in
+    /// physical memory without the task noticing, and where they're mapped
+    pub fn movable_pages(&self) -> impl Iterator<Item = (VirtualAddress, PhysicalPage)> + '_ {
+        let anonymous = |kind: AddressRegionKind| {
+            matches!(
+                kind,
+                AddressRegionKind::UserAllocated
+                    | AddressRegionKind::Stack
+                    | AddressRegionKind::Data
+                    | AddressRegionKind::Tls
+            )
+        };
+
+        self.address_map
+            .occupied_regions()
+            .filter(move |region| anonymous(region.kind))
+            .filter_map(|region| match &region.region {
+                Some(MemoryRegion::Backed(PhysicalRegion::Unique(unique))) => Some((region.span.start, unique)),
+                _ => None,
+            })
+            .flat_map(|(start, unique)| {
+                (0..unique.n_pages()).filter_map(move |i| {
+                    Some((start.add(i * PageSize::Kilopage.to_byte_size()), unique.movable_page(i)?))
+                })
+            })
+    }
+
+    /// Move the kilopage mapped at `at` to `to`, returning the page it was
+    /// moved from for the caller to free, or `None` if it isn't one of the
+    /// [`Self::movable_pages`]. The memory is copied, so the task must not be
+    /// running.
+    pub fn migrate(&mut self, at: VirtualAddress, to: PhysicalPage) -> Option<PhysicalPage> {
+        let region = self.address_map.find_mut(at)?;
+        let index = (at.as_usize() - region.span.start.as_usize()) / PageSize::Kilopage.to_byte_size();
+        let from = match &mut region.region {
+            Some(MemoryRegion::Backed(PhysicalRegion::Unique(unique))) => unique.migrate(index, to)?,
+            _ => return None,
+        };
+
+        assert!(self.table.remap(at, to.as_phys_address()), "movable page at {:#p} wasn't mapped as a kilopage", at);
+        self.asid.invalidate(Some(at));
+
+        Some(from)
+    }
+
+    /// Unmap the kilopage at `at` so it can be swapped out to `slot`, returning
+    /// the page for the caller to free once it's been written out, or `None` if
+    /// it isn't one of the [`Self::movable_pages`]
+    pub fn swap_out(&mut self, at: VirtualAddress, slot: SwapSlot) -> Option<PhysicalPage> {
+        let region = self.address_map.find_mut(at)?;
+        let index = (at.as_usize() - region.span.start.as_usize()) / PageSize::Kilopage.to_byte_size();
+        let page = match &mut region.region {
+            Some(MemoryRegion::Backed(PhysicalRegion::Unique(unique))) => unique.swap_out(index, slot)?,
+            _ => return None,
+        };
+
+        let table_pages = self.table.table_pages();
+        self.table.unmap(at);
+        match self.table.table_pages() == table_pages {
+            true => self.asid.invalidate(Some(at)),
+            false => self.asid.invalidate(None),
+        }
+
+        Some(page)
+    }
+
+    /// The slot the page at `at` was swapped out to, if it has been
+    pub fn swapped_slot(&self, at: VirtualAddress) -> Option<SwapSlot> {
+        let region = self.address_map.find(at)?;
+        let index = (at.as_usize() - region.span.start.as_usize()) / PageSize::Kilopage.to_byte_size();
+        match &region.region {
+            Some(MemoryRegion::Backed(PhysicalRegion::Unique(unique))) => unique.swapped_slot(index),
+            _ => None,
+        }
+    }
+
+    /// Map `page` back in at `at` in place of the page which was swapped out
+    /// from there. It's mapped as accessed, since it's about to be.
+    #[track_caller]
+    pub fn swap_in(&mut self, at: VirtualAddress, page: PhysicalPage) {
+        let at = at.align_down_to(PageSize::Kilopage);
+        let region = self.address_map.find_mut(at).expect("swapping into an unmapped region");
+        let index = (at.as_usize() - region.span.start.as_usize()) / PageSize::Kilopage.to_byte_size();
+        match &mut region.region {
+            Some(MemoryRegion::Backed(PhysicalRegion::Unique(unique))) => unique.swap_in(index, page),
+            _ => unreachable!("swapping into a region which isn't private"),
+        }
+
+        let flags = region.permissions | flags::ACCESSED;
+        self.table.map(page.as_phys_address(), at, flags, PageSize::Kilopage);
+        self.asid.invalidate(Some(at));
+    }
+
+    /// Whether the page containing `at` is part of a lazily allocated region
+    /// and hasn't been touched yet
+    pub fn is_unpopulated(&self, at: VirtualAddress) -> bool {
+        let region = match self.address_map.find(at) {
+            Some(region) => region,
+            None => return false,
+        };
+
+        match &region.region {
+            Some(MemoryRegion::Backed(PhysicalRegion::Unique(unique))) => {
+                let index = (at.as_usize() - region.span.start.as_usize()) / unique.page_size().to_byte_size();
+                unique.is_unpopulated(index)
+            }
+            _ => false,
+        }
+    }
+
+    /// Map the shared zero page read-only in place of the untouched kilopage
+    /// of a lazily allocated region containing `at`, returning whether there
+    /// was one that wasn't mapped yet. Reading from it doesn't need any memory
+    /// of its own, and the first write gives it some with [`Self::populate`].
+    pub fn map_zero_page(&mut self, at: VirtualAddress) -> bool {
+        let region = match self.address_map.find(at) {
+            Some(region) => region,
+            None => return false,
+        };
+
+        let unique = match &region.region {
+            Some(MemoryRegion::Backed(PhysicalRegion::Unique(unique))) => unique,
+            _ => return false,
+        };
+
+        let at = at.align_down_to(PageSize::Kilopage);
+        let index = (at.as_usize() - region.span.start.as_usize()) / PageSize::Kilopage.to_byte_size();
+        if unique.page_size() != PageSize::Kilopage
+            || !unique.is_unpopulated(index)
+            || self.table.page_size(at).is_some()
+        {
+            return false;
+        }
+
+        let flags = without_write(region.permissions) | flags::ACCESSED;
+        self.table.map(zeroed::shared_zero_page().as_phys_address(), at, flags, PageSize::Kilopage);
+        self.asid.invalidate(Some(at));
+
+        true
+    }
+
+    /// Allocate and map a zeroed page in place of the untouched page of a
+    /// lazily allocated region containing `at`, returning whether there was
+    /// one. It's mapped as accessed, since it's about to be, and replaces the
+    /// shared zero page if it was mapped there.
+    pub fn populate(&mut self, at: VirtualAddress) -> bool {
+        let region = match self.address_map.find_mut(at) {
+            Some(region) => region,
+            None => return false,
+        };
+
+        let unique = match &mut region.region {
+            Some(MemoryRegion::Backed(PhysicalRegion::Unique(unique))) => unique,
+            _ => return false,
+        };
+
+        let size = unique.page_size();
+        let index = (at.as_usize() - region.span.start.as_usize()) / size.to_byte_size();
+        let page = match unique.populate(index) {
+            Some(page) => page,
+            None => return false,
+        };
+
+        let flags = region.permissions | flags::ACCESSED;
+        let at = region.span.start.add(index * size.to_byte_size());
+        map_populated(&mut self.table, page, at, flags, size);
+        self.asid.invalidate(Some(at));
+
+        true
+    }
+
+    /// The flags shared by every kilopage in the megapage at `at`, with the
+    /// accessed and dirty bits of any of them set, or `None` if they differ
+    fn uniform_flags(&self, at: VirtualAddress) -> Option<Flags> {
+        let ignored = (flags::ACCESSED | flags::DIRTY).value();
+        let first = self.table.page_flags(at)?;
+        let mut combined = first;
+
+        for i in 1..KILOPAGES_PER_MEGAPAGE {
+            let flags = self.table.page_flags(at.add(i * PageSize::Kilopage.to_byte_size()))?;
+            if flags.value() & !ignored != first.value() & !ignored {
+                return None;
+            }
+
+            combined |= flags;
+        }
+
+        Some(combined)
     }
 
     /// Returns the `RSW` bits of the given [`VirtualAddress`] mapping, if it's
@@ -338,11 +1345,33 @@ impl MemoryManager {
         self.table.physical_address()
     }
 
+    /// Switch the current hart to this address space
+    pub fn activate(&self) {
+        self.asid.activate(Satp { mode: paging::satp_mode(), asid: 0, root_page_table: self.table.physical_address() });
+    }
+
+    /// Flush the TLB entries for `at`, or the whole address space if it's
+    /// `None`, after changing its mappings from outside of the memory manager
+    pub fn invalidate(&self, at: Option<VirtualAddress>) {
+        self.asid.invalidate(at);
+    }
+
     /// Debug printable representation of the [`PageTable`]
     pub fn page_table_debug(&self) -> PageTableDebug<'_> {
         self.table.debug()
     }
 
+    /// What's mapped into userspace, with contiguous pages merged together
+    pub fn mappings(&self) -> alloc::vec::Vec<Mapping> {
+        self.table.mappings()
+    }
+
+    /// How many bytes are mapped into userspace, counting pages shared with
+    /// other address spaces
+    pub fn resident_size(&self) -> usize {
+        self.table.mappings().iter().map(|mapping| mapping.len).sum()
+    }
+
     /// Debug printable representation of the [`AddressMap`] with an optional
     /// [`VirtualAddress`] to search for
     pub fn address_map_debug(&self, faulting_addr: Option<VirtualAddress>) -> impl core::fmt::Debug + '_ {
@@ -462,3 +1491,27 @@ impl MemoryManager {
         todo!("exhausted address space -- this should be an `Err(...)` in the future")
     }
 }
+
+impl Drop for MemoryManager {
+    fn drop(&mut self) {
+        for region in self.address_map.occupied_regions() {
+            if let Some(MemoryRegion::Backed(PhysicalRegion::Shared(shared))) = &region.region {
+                shared.forget_mapping(self.id, region.span.start);
+            }
+        }
+
+        rmap::remove_owner(self.id);
+    }
+}
+
+/// Write out what's mapped into `memory_manager`'s address space, one line per
+/// run of contiguous pages
+pub fn dump_page_table(memory_manager: &MemoryManager, out: &mut dyn core::fmt::Write) -> core::fmt::Result {
+    let mappings = memory_manager.mappings();
+    write!(out, "{} mappings:\r\n", mappings.len())?;
+    for mapping in mappings {
+        write!(out, "    {}\r\n", mapping)?;
+    }
+
+    Ok(())
+}
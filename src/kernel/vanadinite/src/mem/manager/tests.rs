@@ -0,0 +1,153 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::*;
+use crate::mem::phys2virt;
+
+fn read_write() -> Flags {
+    flags::VALID | flags::USER | flags::READ | flags::WRITE
+}
+
+fn alloc_private(memory_manager: &mut MemoryManager, n_pages: usize) -> Range<VirtualAddress> {
+    memory_manager.alloc_region(
+        None,
+        RegionDescription {
+            size: PageSize::Kilopage,
+            len: n_pages,
+            contiguous: false,
+            flags: read_write(),
+            fill: FillOption::Zeroed,
+            kind: AddressRegionKind::UserAllocated,
+        },
+    )
+}
+
+fn read(memory_manager: &MemoryManager, at: VirtualAddress) -> u8 {
+    unsafe { *phys2virt(memory_manager.resolve(at).unwrap()).as_ptr() }
+}
+
+/// Write to `at` the way a task would, faulting in a page of its own first if
+/// it has to
+fn write(memory_manager: &mut MemoryManager, at: VirtualAddress, value: u8) {
+    if memory_manager.is_cow(at) {
+        assert!(memory_manager.break_cow(at));
+    }
+
+    if memory_manager.is_unpopulated(at) {
+        assert!(memory_manager.populate(at));
+    }
+
+    unsafe { *phys2virt(memory_manager.resolve(at).unwrap()).as_mut_ptr() = value };
+}
+
+#[vanadinite_macros::test]
+fn restoring_a_checkpoint_undoes_writes() {
+    let mut memory_manager = MemoryManager::new();
+    let range = alloc_private(&mut memory_manager, 2);
+    write(&mut memory_manager, range.start, 1);
+
+    let mut checkpoint = memory_manager.snapshot().unwrap();
+    write(&mut memory_manager, range.start, 2);
+    write(&mut memory_manager, range.start.add(4096), 3);
+
+    // Restoring takes a fresh copy, so the checkpoint can be restored again
+    let restored = checkpoint.snapshot().unwrap();
+    assert_eq!(read(&restored, range.start), 1);
+    assert_eq!(read(&restored, range.start.add(4096)), 0);
+    assert_eq!(read(&memory_manager, range.start), 2);
+}
+
+#[vanadinite_macros::test]
+fn breaking_cow_leaves_the_checkpoint_alone() {
+    let mut memory_manager = MemoryManager::new();
+    let range = alloc_private(&mut memory_manager, 1);
+    write(&mut memory_manager, range.start, 1);
+
+    let checkpoint = memory_manager.snapshot().unwrap();
+    let shared = checkpoint.resolve(range.start);
+    assert!(memory_manager.is_cow(range.start));
+    assert_eq!(memory_manager.resolve(range.start), shared);
+
+    assert!(memory_manager.break_cow(range.start));
+    assert!(!memory_manager.is_cow(range.start));
+    write(&mut memory_manager, range.start, 2);
+
+    assert_ne!(memory_manager.resolve(range.start), shared);
+    assert_eq!(checkpoint.resolve(range.start), shared);
+    assert_eq!(read(&checkpoint, range.start), 1);
+}
+
+#[vanadinite_macros::test]
+fn writing_to_the_zero_page_leaves_it_zeroed() {
+    let mut memory_manager = MemoryManager::new();
+    let range =
+        memory_manager.alloc_lazy_region(None, PageSize::Kilopage, 1, read_write(), AddressRegionKind::UserAllocated);
+    let zero_page = zeroed::shared_zero_page().as_phys_address();
+
+    assert!(memory_manager.map_zero_page(range.start));
+    assert_eq!(memory_manager.resolve(range.start), Some(zero_page));
+    assert_eq!(read(&memory_manager, range.start), 0);
+
+    write(&mut memory_manager, range.start, 0xAA);
+    assert_ne!(memory_manager.resolve(range.start), Some(zero_page));
+    assert_eq!(read(&memory_manager, range.start), 0xAA);
+
+    let zeroes =
+        unsafe { core::slice::from_raw_parts(phys2virt(zero_page).as_ptr(), PageSize::Kilopage.to_byte_size()) };
+    assert!(zeroes.iter().all(|&byte| byte == 0));
+}
+
+#[vanadinite_macros::test]
+fn resizing_by_moving_keeps_the_contents() {
+    let mut memory_manager = MemoryManager::new();
+    let range = alloc_private(&mut memory_manager, 2);
+    write(&mut memory_manager, range.start, 1);
+    write(&mut memory_manager, range.start.add(4096), 2);
+
+    // Something mapped right after it means growing it has to move it
+    memory_manager.alloc_lazy_region(
+        Some(range.end),
+        PageSize::Kilopage,
+        1,
+        read_write(),
+        AddressRegionKind::UserAllocated,
+    );
+
+    let resized = memory_manager.resize_region(range.start, 4).unwrap();
+    assert_ne!(resized.start, range.start);
+    assert_eq!(resized.end, resized.start.add(4 * 4096));
+    assert_eq!(read(&memory_manager, resized.start), 1);
+    assert_eq!(read(&memory_manager, resized.start.add(4096)), 2);
+    assert!(memory_manager.is_unpopulated(resized.start.add(2 * 4096)));
+    assert!(memory_manager.is_unpopulated(resized.start.add(3 * 4096)));
+    assert!(memory_manager.is_unoccupied(range));
+}
+
+#[vanadinite_macros::test]
+fn pinned_pages_stay_put() {
+    let mut memory_manager = MemoryManager::new();
+    let range = alloc_private(&mut memory_manager, 2 * KILOPAGES_PER_MEGAPAGE);
+    let at = range.start.align_to_next(PageSize::Megapage);
+    let pinned = memory_manager.pin(at..at.add(4096)).unwrap();
+
+    assert!(!memory_manager.promote_one());
+    assert!(memory_manager.movable_pages().all(|(virt, _)| virt != at));
+    assert!(memory_manager.movable_pages().any(|(virt, _)| virt == at.add(4096)));
+
+    let to = phys::alloc_page();
+    assert!(memory_manager.migrate(at, to).is_none());
+    unsafe { PHYSICAL_MEMORY_ALLOCATOR.lock().dealloc(to, PageSize::Kilopage) };
+
+    assert!(memory_manager.swap_out(at, SwapSlot::new(0)).is_none());
+    assert_eq!(memory_manager.resolve(at), Some(pinned.pages[0].0.as_phys_address()));
+
+    for (page, size) in pinned.pages {
+        phys::unpin(page, size);
+    }
+
+    assert!(memory_manager.promote_one());
+}
@@ -0,0 +1,71 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Transparently promoting userspace allocations to megapages
+//!
+//! Every so often an idle job goes through each task looking for megapage
+//! aligned runs of kilopages in its heap allocations, and replaces them with a
+//! single megapage so they take up one TLB entry instead of 512. Only blocked
+//! tasks are looked at, since their memory is copied into the megapage and
+//! can't change while that happens. The megapages are split back up whenever
+//! part of one has its permissions changed.
+
+use crate::{
+    csr,
+    scheduler::{Scheduler, SCHEDULER, TASKS},
+    utils::ticks_per_us,
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+use librust::task::Tid;
+use sync::SpinMutex;
+
+/// How long to wait between going through every task
+const SCAN_INTERVAL_US: u64 = 1_000_000;
+
+/// The last task looked at in the current scan, or `None` between scans
+static CURSOR: SpinMutex<Option<Tid>> = SpinMutex::new(None);
+/// When the next scan starts, in `time` CSR ticks
+static NEXT_SCAN: AtomicU64 = AtomicU64::new(0);
+
+/// Promote at most one megapage in the next task of the current scan. This is
+/// run as an idle job, and returns whether the scan has more to look at.
+pub fn promote_in_background() -> bool {
+    let mut cursor = match CURSOR.try_lock() {
+        Some(cursor) => cursor,
+        None => return false,
+    };
+
+    let now = csr::time::read();
+    if cursor.is_none() && now < NEXT_SCAN.load(Ordering::Relaxed) {
+        return false;
+    }
+
+    let (tid, task) = match TASKS.next_after(*cursor) {
+        Some(next) => next,
+        None => {
+            let interval = ticks_per_us(SCAN_INTERVAL_US, crate::TIMER_FREQ.load(Ordering::Relaxed));
+            NEXT_SCAN.store(now + interval, Ordering::Relaxed);
+            *cursor = None;
+
+            return false;
+        }
+    };
+
+    // Holding the lock keeps the task from being scheduled, but it could
+    // already be running if it isn't blocked
+    let promoted = match task.try_lock() {
+        Some(mut task) if SCHEDULER.is_blocked(tid) => task.memory_manager.promote_one(),
+        _ => false,
+    };
+
+    // Stay on the same task until it has nothing left to promote
+    if !promoted {
+        *cursor = Some(tid);
+    }
+
+    true
+}
@@ -0,0 +1,92 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The physical memory map the kernel put together at boot, from the device
+//! tree, the EFI memory map, and where it was loaded itself
+//!
+//! Most of it is recorded before paging is enabled, when there's no heap, so
+//! the entries are kept in a fixed size array in address order. Boards whose
+//! device trees describe memory wrongly, or whose firmware keeps odd ranges
+//! for itself, show up here before anything goes wrong.
+
+use sync::SpinMutex;
+
+pub use librust::syscalls::debug::MemoryMapEntry;
+
+const MAX_ENTRIES: usize = 64;
+
+static MEMORY_MAP: SpinMutex<MemoryMap> = SpinMutex::new(MemoryMap::new());
+
+struct MemoryMap {
+    entries: [MemoryMapEntry; MAX_ENTRIES],
+    len: usize,
+    /// Entries which didn't fit
+    dropped: usize,
+}
+
+impl MemoryMap {
+    const fn new() -> Self {
+        Self { entries: [MemoryMapEntry { kind: 0, start: 0, end: 0 }; MAX_ENTRIES], len: 0, dropped: 0 }
+    }
+}
+
+/// Record that the physical memory in `start..end` is used for `kind`, which
+/// is one of the [`MemoryMapEntry`] kinds, extending the entry of the same kind
+/// right before it if there is one
+pub fn record(kind: usize, start: usize, end: usize) {
+    if start >= end {
+        return;
+    }
+
+    let mut map = MEMORY_MAP.lock();
+    let len = map.len;
+    if let Some(entry) = map.entries[..len].iter_mut().find(|entry| entry.kind == kind && entry.end == start) {
+        entry.end = end;
+        return;
+    }
+
+    if len == MAX_ENTRIES {
+        map.dropped += 1;
+        return;
+    }
+
+    let at = map.entries[..len].iter().position(|entry| (entry.start, entry.kind) > (start, kind)).unwrap_or(len);
+    map.entries.copy_within(at..len, at + 1);
+    map.entries[at] = MemoryMapEntry { kind, start, end };
+    map.len += 1;
+}
+
+/// Call `f` with every entry, in address order
+pub fn with<R>(f: impl FnOnce(&[MemoryMapEntry]) -> R) -> R {
+    let map = MEMORY_MAP.lock();
+    f(&map.entries[..map.len])
+}
+
+/// Like [`with`], for when the memory map might be locked forever
+pub fn try_with<R>(f: impl FnOnce(&[MemoryMapEntry]) -> R) -> Option<R> {
+    let map = MEMORY_MAP.try_lock()?;
+    Some(f(&map.entries[..map.len]))
+}
+
+pub fn log() {
+    let map = MEMORY_MAP.lock();
+
+    log::info!(" Memory Map:");
+    for entry in &map.entries[..map.len] {
+        log::info!(
+            "   {:#p}..{:#p} {:<12} ({} KiB)",
+            entry.start as *const u8,
+            entry.end as *const u8,
+            entry.kind_name(),
+            (entry.end - entry.start) / 1024,
+        );
+    }
+
+    if map.dropped > 0 {
+        log::warn!("   ...and {} more entries which didn't fit", map.dropped);
+    }
+}
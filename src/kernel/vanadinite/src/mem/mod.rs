@@ -14,10 +14,22 @@ use {
     phys::{PhysicalMemoryAllocator, PHYSICAL_MEMORY_ALLOCATOR},
 };
 
+pub mod balloon;
+pub mod cache;
+pub mod compaction;
+pub mod dma;
 pub mod heap;
+pub mod kernel_stacks;
 pub mod manager;
+pub mod megapages;
+pub mod memory_map;
 pub mod phys;
+pub mod pressure;
 pub mod region;
+pub mod rmap;
+pub mod shm;
+pub mod shootdown;
+pub mod swap;
 pub mod user;
 pub mod paging {
     mod table;
@@ -25,13 +37,38 @@ pub mod paging {
     mod tests;
 
     use crate::csr::satp::SatpMode;
+    use core::sync::atomic::{AtomicUsize, Ordering};
     pub use table::*;
 
-    #[cfg(all(not(feature = "paging.sv48"), not(feature = "paging.sv57")))]
-    pub const SATP_MODE: SatpMode = SatpMode::Sv39;
+    /// How many levels page tables have, 3 for Sv39 and 4 for Sv48
+    static LEVELS: AtomicUsize = AtomicUsize::new(3);
 
-    #[cfg(all(feature = "paging.sv48", not(feature = "paging.sv57")))]
-    pub const SATP_MODE: SatpMode = SatpMode::Sv48;
+    /// Pick Sv48 if every hart says it supports it in the device tree and
+    /// Sv39 otherwise. This has to be done before any page tables are made,
+    /// since it changes how they're laid out.
+    ///
+    /// The kernel's half of the address space is laid out to fit in Sv39's,
+    /// which is also valid in Sv48's, so only userspace gets any bigger.
+    pub fn select_mode(fdt: &fdt::Fdt<'_>) -> SatpMode {
+        let sv48 = fdt
+            .cpus()
+            .all(|cpu| matches!(cpu.property("mmu-type").and_then(|p| p.as_str()), Some("riscv,sv48" | "riscv,sv57")));
+
+        LEVELS.store(if sv48 { 4 } else { 3 }, Ordering::Relaxed);
+        satp_mode()
+    }
+
+    pub fn levels() -> usize {
+        LEVELS.load(Ordering::Relaxed)
+    }
+
+    /// The paging mode picked by [`select_mode`]
+    pub fn satp_mode() -> SatpMode {
+        match levels() {
+            4 => SatpMode::Sv48,
+            _ => SatpMode::Sv39,
+        }
+    }
 }
 
 #[inline(always)]
@@ -52,17 +89,33 @@ pub fn sfence(vaddr: Option<paging::VirtualAddress>, asid: Option<u16>) {
     }
 }
 
+/// Allocate a kernel stack for the current hart
 pub fn alloc_kernel_stack(size: usize) -> *mut u8 {
+    alloc_kernel_stack_on(phys::numa::local_node(), size)
+}
+
+/// Allocate a kernel stack, preferring memory on NUMA node `node`. The stack
+/// is mapped with an unmapped guard page below it, see [`kernel_stacks`].
+pub fn alloc_kernel_stack_on(node: usize, size: usize) -> *mut u8 {
     assert!(size.is_power_of_two());
     assert_eq!(size % 4096, 0);
 
     let total_pages = size / 4096;
-    let phys_start =
-        unsafe { PHYSICAL_MEMORY_ALLOCATOR.lock().alloc_contiguous(PageSize::Kilopage, total_pages) }.expect("oom :(");
+    let phys_start = unsafe {
+        let mut allocator = PHYSICAL_MEMORY_ALLOCATOR.lock();
+        allocator
+            .alloc_contiguous_on(node, PageSize::Kilopage, total_pages)
+            .or_else(|| allocator.alloc_contiguous(PageSize::Kilopage, total_pages))
+    }
+    .expect("oom :(");
+
+    kernel_stacks::map(phys_start.as_phys_address(), total_pages).as_mut_ptr()
+}
 
-    // FIXME: Eventually make these proper virtual address ranges so we can add
-    // guard pages which will detect stack overflowing
-    phys2virt(phys_start.as_phys_address().offset(total_pages * 4096)).as_mut_ptr()
+/// Allocate a shadow call stack, preferring memory on NUMA node `node`. Shadow
+/// call stacks grow upwards, so this returns the lowest address of the stack.
+pub fn alloc_shadow_call_stack_on(node: usize, size: usize) -> *mut u8 {
+    alloc_kernel_stack_on(node, size).wrapping_sub(size)
 }
 
 #[track_caller]
@@ -10,22 +10,35 @@ pub mod flags;
 mod repr;
 
 use crate::mem::{phys2virt, virt2phys};
-use alloc::{boxed::Box, vec::Vec};
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
 use allocator::PageTableAllocator;
-use core::ptr::NonNull;
+use core::{ptr::NonNull, sync::atomic::Ordering};
 use flags::Flags;
-pub use repr::{EntryKind, PageSize, PhysicalAddress, VirtualAddress};
+pub use repr::{EntryKind, PageSize, PhysicalAddress, VirtualAddress, Vpns, MAX_LEVELS};
+
+/// The number of pages used for page tables across the whole system
+pub fn page_table_pages() -> usize {
+    allocator::ALLOCATED_TABLES.load(Ordering::Relaxed)
+}
 
 pub struct PageTable {
     root: Box<repr::PageTable, PageTableAllocator>,
-    subtables: Vec<NonNull<repr::PageTable>>,
+    /// Keyed by the physical address of the subtable
+    subtables: BTreeMap<PhysicalAddress, Subtable>,
+}
+
+struct Subtable {
+    table: NonNull<repr::PageTable>,
+    /// How many of the subtable's entries are valid, so it can be freed once
+    /// there are none left
+    valid_entries: usize,
 }
 
 impl PageTable {
     /// Creates a new [`PageTable`] without copying kernel regions
     pub fn new_raw() -> Self {
         let root = Self::new_table();
-        Self { root, subtables: Vec::new() }
+        Self { root, subtables: BTreeMap::new() }
     }
 
     /// Creates a new [`PageTable`], copying the kernel regions from the active
@@ -34,7 +47,7 @@ impl PageTable {
         // Safety: This is safe since page tables are made up of trivial types,
         // of which zero is a valid state (and the one we want for new ones)
         let root = Self::new_table();
-        let mut this = Self { root, subtables: Vec::new() };
+        let mut this = Self { root, subtables: BTreeMap::new() };
 
         this.copy_kernel_regions();
 
@@ -49,6 +62,7 @@ impl PageTable {
         size.assert_addr_aligned(to.as_usize());
 
         let mut table = &mut *self.root;
+        let mut table_phys = None;
         let mut current = PageSize::top_level();
 
         for vpn in to.vpns().into_iter().rev() {
@@ -63,6 +77,7 @@ impl PageTable {
 
                 entry.set_flags(flags);
                 entry.set_ppn(from);
+                self.entry_added(table_phys);
                 return;
             }
 
@@ -74,7 +89,10 @@ impl PageTable {
                         from, to, flags, size, current, entry, self.resolve(to)
                     )
                 }
-                EntryKind::Branch(paddr) => table = unsafe { &mut *(phys2virt(paddr).as_mut_ptr().cast()) },
+                EntryKind::Branch(paddr) => {
+                    table = unsafe { &mut *(phys2virt(paddr).as_mut_ptr().cast()) };
+                    table_phys = Some(paddr);
+                }
                 EntryKind::NotValid => {
                     let new_subtable = Box::leak(Self::new_table());
                     let subtable_phys = virt2phys(VirtualAddress::from_ptr(new_subtable));
@@ -82,9 +100,14 @@ impl PageTable {
                     entry.set_flags(flags::VALID);
                     entry.set_ppn(subtable_phys);
 
-                    self.subtables.push(unsafe { NonNull::new_unchecked(new_subtable) });
+                    self.entry_added(table_phys);
+                    self.subtables.insert(
+                        subtable_phys,
+                        Subtable { table: unsafe { NonNull::new_unchecked(new_subtable) }, valid_entries: 0 },
+                    );
 
                     table = new_subtable;
+                    table_phys = Some(subtable_phys);
                 }
             }
 
@@ -95,14 +118,185 @@ impl PageTable {
         }
     }
 
+    /// Map the `len` bytes of physically contiguous memory at `from` to `to`,
+    /// using the largest pages the alignment of the two addresses allows and
+    /// kilopages for whatever's left over at either end
     #[track_caller]
-    pub fn unmap(&mut self, address: VirtualAddress) {
+    pub fn map_range(&mut self, from: PhysicalAddress, to: VirtualAddress, len: usize, flags: Flags) {
+        PageSize::Kilopage.assert_addr_aligned(len);
+
+        let mut offset = 0;
+        while offset < len {
+            let (from, to) = (from.offset(offset), to.add(offset));
+            let size = PageSize::largest_for(from, to, len - offset);
+
+            self.map(from, to, flags, size);
+            offset += size.to_byte_size();
+        }
+    }
+
+    /// Unmap the page containing `address`, returning the size of the page
+    /// which was unmapped. Any tables left empty by unmapping it are freed.
+    #[track_caller]
+    pub fn unmap(&mut self, address: VirtualAddress) -> PageSize {
         log::debug!("Unmapping {:#p}", address);
 
-        let entry = self.with_entry_mut(address, |e, _| (e.is_valid(), *e = repr::PageTableEntry::new()));
-        if let None | Some((false, _)) = entry {
-            panic!("attempting to unmap and already unmapped page: {:#p}", address);
+        let vpns = address.vpns();
+        // The physical address of the table used at each level, or `None` for
+        // the root table
+        let mut tables = [None; MAX_LEVELS];
+        let mut table = &mut *self.root;
+        let mut current = PageSize::top_level();
+        let mut level = vpns.levels() - 1;
+
+        loop {
+            let entry = &mut table.entries[vpns[level]];
+
+            match entry.kind() {
+                EntryKind::Leaf => {
+                    *entry = repr::PageTableEntry::new();
+                    break;
+                }
+                EntryKind::Branch(paddr) if level > 0 => {
+                    table = unsafe { &mut *(phys2virt(paddr).as_mut_ptr().cast()) };
+                    tables[level - 1] = Some(paddr);
+                }
+                _ => panic!("attempting to unmap and already unmapped page: {:#p}", address),
+            }
+
+            level -= 1;
+            current = match current.next() {
+                Some(next) => next,
+                None => unreachable!("next level page size"),
+            };
+        }
+
+        // Walk back up towards the root, freeing each table which no longer
+        // has anything in it and removing the entry pointing to it
+        while let Some(table_phys) = tables[level] {
+            if !self.entry_removed(table_phys) {
+                break;
+            }
+
+            level += 1;
+            let parent = match tables[level] {
+                Some(parent) => unsafe { &mut *phys2virt(parent).as_mut_ptr().cast::<repr::PageTable>() },
+                None => &mut *self.root,
+            };
+            parent.entries[vpns[level]] = repr::PageTableEntry::new();
         }
+
+        current
+    }
+
+    /// Note that an entry in the subtable at `table` (or the root table if
+    /// `None`) was made valid
+    fn entry_added(&mut self, table: Option<PhysicalAddress>) {
+        if let Some(table) = table {
+            if let Some(subtable) = self.subtables.get_mut(&table) {
+                subtable.valid_entries += 1;
+            }
+        }
+    }
+
+    /// Note that an entry in the subtable at `table` was invalidated, freeing
+    /// the subtable if that was its last valid entry. Returns whether it was
+    /// freed.
+    fn entry_removed(&mut self, table: PhysicalAddress) -> bool {
+        let subtable = match self.subtables.get_mut(&table) {
+            Some(subtable) => subtable,
+            None => return false,
+        };
+
+        subtable.valid_entries -= 1;
+        if subtable.valid_entries > 0 {
+            return false;
+        }
+
+        let subtable = self.subtables.remove(&table).unwrap();
+        unsafe { drop(Box::from_raw_in(subtable.table.as_ptr(), allocator::PageTableAllocator)) };
+
+        true
+    }
+
+    /// The number of pages used by this page table, including the root table
+    pub fn table_pages(&self) -> usize {
+        self.subtables.len() + 1
+    }
+
+    /// Replace the kilopage mappings which make up the megapage at `at` with a
+    /// single megapage mapping of `from`, freeing the table which held them
+    #[track_caller]
+    pub fn promote(&mut self, from: PhysicalAddress, at: VirtualAddress, flags: Flags) {
+        log::debug!("Promoting {:#p} to a megapage of {:#p}", at, from);
+
+        PageSize::Megapage.assert_addr_aligned(from.as_usize());
+        PageSize::Megapage.assert_addr_aligned(at.as_usize());
+
+        let entry = match self.entry_for_size_mut(at, PageSize::Megapage) {
+            Some(entry) => entry,
+            None => panic!("attempted to promote an unmapped megapage: {:#p}", at),
+        };
+
+        let subtable = match entry.kind() {
+            EntryKind::Branch(subtable) => subtable,
+            _ => panic!("attempted to promote a megapage which isn't made of kilopages: {:#p}", at),
+        };
+
+        entry.set_flags(flags);
+        entry.set_ppn(from);
+
+        let subtable = self.subtables.remove(&subtable).unwrap();
+        unsafe { drop(Box::from_raw_in(subtable.table.as_ptr(), allocator::PageTableAllocator)) };
+    }
+
+    /// Split the megapage or gigapage mapping at `at` back up into mappings of
+    /// the same memory with the same flags, using the next page size down
+    #[track_caller]
+    pub fn demote(&mut self, at: VirtualAddress) {
+        let size = match self.page_size(at) {
+            Some(size @ (PageSize::Megapage | PageSize::Gigapage)) => size,
+            _ => panic!("attempted to demote something other than a megapage or gigapage: {:#p}", at),
+        };
+
+        log::debug!("Demoting {:?} {:#p}", size, at);
+
+        let new_subtable = Box::leak(Self::new_table());
+        let subtable_phys = virt2phys(VirtualAddress::from_ptr(new_subtable));
+
+        // Only a leaf can have been found above
+        let entry = self.entry_for_size_mut(at, size).unwrap();
+        let (base, flags, next) = (entry.ppn().unwrap(), entry.flags(), size.next().unwrap());
+        for (i, page) in new_subtable.entries.iter_mut().enumerate() {
+            page.set_flags(flags);
+            page.set_ppn(base.offset(i * next.to_byte_size()));
+        }
+
+        entry.set_flags(flags::VALID);
+        entry.set_ppn(subtable_phys);
+
+        self.subtables.insert(
+            subtable_phys,
+            Subtable { table: unsafe { NonNull::new_unchecked(new_subtable) }, valid_entries: 512 },
+        );
+    }
+
+    /// Point the kilopage mapping `at` at `to` instead, keeping its flags.
+    /// Returns `false` if `at` isn't mapped by a kilopage.
+    pub fn remap(&mut self, at: VirtualAddress, to: PhysicalAddress) -> bool {
+        self.with_entry_mut(at, |e, size| match size {
+            PageSize::Kilopage => {
+                e.set_ppn(to);
+                true
+            }
+            _ => false,
+        })
+        .unwrap_or_default()
+    }
+
+    /// The size of the page mapping `address`, if it's mapped
+    pub fn page_size(&self, address: VirtualAddress) -> Option<PageSize> {
+        self.with_entry(address, |_, size| size)
     }
 
     pub fn modify_page_flags(&mut self, address: VirtualAddress, f: impl FnOnce(Flags) -> Flags) -> bool {
@@ -141,15 +335,87 @@ impl PageTable {
         PageTableDebug(&self.root, PageSize::top_level(), VirtualAddress::new(0))
     }
 
+    /// The userspace half of the table, with runs of pages which map
+    /// contiguous physical memory the same way merged together
+    pub fn mappings(&self) -> Vec<Mapping> {
+        let mut mappings = Vec::new();
+        collect_mappings(&self.root, PageSize::top_level(), VirtualAddress::new(0), &mut mappings);
+
+        mappings
+    }
+
     #[doc(hidden)]
     #[track_caller]
     pub fn static_map(&mut self, from: PhysicalAddress, to: VirtualAddress, flags: Flags, size: PageSize) {
-        size.assert_addr_aligned(from.as_usize());
+        Self::static_map_in(&mut self.root, from, to, flags, size);
+    }
+
+    /// Make the tables needed to map pages of `size` at `to` without mapping
+    /// anything yet. Done at boot for the kernel's half of the address space,
+    /// every address space copies the entries pointing to these tables, so
+    /// whatever [`PageTable::map_kernel`] maps below them later shows up in all
+    /// of them.
+    #[doc(hidden)]
+    #[track_caller]
+    pub fn static_reserve(&mut self, to: VirtualAddress, size: PageSize) {
         size.assert_addr_aligned(to.as_usize());
 
         let mut table = &mut *self.root;
         let mut current = PageSize::top_level();
 
+        for vpn in to.vpns().into_iter().rev() {
+            if current == size {
+                return;
+            }
+
+            let entry = &mut table.entries[vpn];
+            match entry.kind() {
+                EntryKind::Leaf => panic!("attempted to reserve tables under a mapped page: {:#p}", to),
+                EntryKind::Branch(paddr) => table = unsafe { &mut *(phys2virt(paddr).as_mut_ptr().cast()) },
+                EntryKind::NotValid => {
+                    let new_subtable = Box::leak(Self::new_table());
+                    entry.set_flags(flags::VALID);
+                    entry.set_ppn(virt2phys(VirtualAddress::from_ptr(new_subtable)));
+
+                    table = new_subtable;
+                }
+            }
+
+            current = match current.next() {
+                Some(next) => next,
+                None => unreachable!("next level page size"),
+            };
+        }
+    }
+
+    /// Map a page into the kernel's half of the active address space, which
+    /// every other address space sees too
+    ///
+    /// # Safety
+    ///
+    /// `to` has to be in a part of the address space set up with
+    /// [`PageTable::static_reserve`] at boot, otherwise the mapping only shows
+    /// up in the active address space. Calls mapping under the same tables
+    /// have to be serialized.
+    #[track_caller]
+    pub unsafe fn map_kernel(from: PhysicalAddress, to: VirtualAddress, flags: Flags, size: PageSize) {
+        let root = phys2virt(crate::csr::satp::read().root_page_table).as_mut_ptr().cast::<repr::PageTable>();
+        Self::static_map_in(&mut *root, from, to, flags, size);
+    }
+
+    #[track_caller]
+    fn static_map_in(
+        mut table: &mut repr::PageTable,
+        from: PhysicalAddress,
+        to: VirtualAddress,
+        flags: Flags,
+        size: PageSize,
+    ) {
+        size.assert_addr_aligned(from.as_usize());
+        size.assert_addr_aligned(to.as_usize());
+
+        let mut current = PageSize::top_level();
+
         for vpn in to.vpns().into_iter().rev() {
             let entry = &mut table.entries[vpn];
             if current == size {
@@ -209,6 +475,33 @@ impl PageTable {
         None
     }
 
+    /// The entry for `address` in the table for pages of `size`, whatever kind
+    /// of entry it is
+    fn entry_for_size_mut(&mut self, address: VirtualAddress, size: PageSize) -> Option<&mut repr::PageTableEntry> {
+        let mut table = &mut *self.root;
+        let mut current = PageSize::top_level();
+
+        for vpn in address.vpns().into_iter().rev() {
+            let entry = &mut table.entries[vpn];
+
+            if current == size {
+                return Some(entry);
+            }
+
+            match entry.kind() {
+                EntryKind::Branch(paddr) => table = unsafe { &mut *(phys2virt(paddr).as_mut_ptr().cast()) },
+                EntryKind::Leaf | EntryKind::NotValid => return None,
+            }
+
+            current = match current.next() {
+                Some(next) => next,
+                None => unreachable!("next level page size"),
+            };
+        }
+
+        None
+    }
+
     fn with_entry<T>(
         &self,
         address: VirtualAddress,
@@ -239,7 +532,7 @@ impl PageTable {
         let current: *const repr::PageTable = { phys2virt(crate::csr::satp::read().root_page_table).as_ptr().cast() };
 
         // FIXME: this address should be available somewhere else and not hardcoded
-        let start_idx = *VirtualAddress::kernelspace_range().start.vpns().last().unwrap();
+        let start_idx = VirtualAddress::kernelspace_range().start.vpns().root();
         for i in start_idx..512 {
             self.root.entries[i] = unsafe { (*current).entries[i] };
         }
@@ -257,8 +550,8 @@ unsafe impl Sync for PageTable {}
 
 impl Drop for PageTable {
     fn drop(&mut self) {
-        for pt in self.subtables.drain(..) {
-            unsafe { Box::from_raw_in(pt.as_ptr(), allocator::PageTableAllocator) };
+        for (_, subtable) in core::mem::take(&mut self.subtables) {
+            unsafe { Box::from_raw_in(subtable.table.as_ptr(), allocator::PageTableAllocator) };
         }
     }
 }
@@ -269,6 +562,74 @@ impl core::fmt::Debug for PageTable {
     }
 }
 
+/// A run of pages mapping contiguous physical memory with the same page size
+/// and permissions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mapping {
+    pub virt: VirtualAddress,
+    pub phys: PhysicalAddress,
+    /// In bytes
+    pub len: usize,
+    pub page_size: PageSize,
+    /// Without the accessed and dirty bits, which would split up runs which
+    /// are otherwise the same
+    pub flags: Flags,
+}
+
+impl core::fmt::Display for Mapping {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "[{}] {:#p}-{:#p} -> {:#p}-{:#p} ({:?}) x{}",
+            size_to_letter(self.page_size),
+            self.virt,
+            self.virt.add(self.len),
+            self.phys,
+            self.phys.offset(self.len),
+            self.flags,
+            self.len / self.page_size.to_byte_size(),
+        )
+    }
+}
+
+fn collect_mappings(table: &repr::PageTable, size: PageSize, base: VirtualAddress, mappings: &mut Vec<Mapping>) {
+    for (i, entry) in table.entries.iter().enumerate() {
+        let virt = {
+            let mut vpns = base.vpns();
+            vpns[size as usize] = i;
+            VirtualAddress::from_vpns(vpns)
+        };
+
+        if virt.is_kernel_region() {
+            break;
+        }
+
+        match entry.kind() {
+            EntryKind::NotValid => {}
+            EntryKind::Leaf => {
+                let phys = entry.ppn().unwrap();
+                let flags = Flags::new(entry.flags().value() & !(flags::ACCESSED.value() | flags::DIRTY.value()));
+
+                match mappings.last_mut() {
+                    Some(last)
+                        if last.page_size == size
+                            && last.flags == flags
+                            && last.virt.as_usize() + last.len == virt.as_usize()
+                            && last.phys.as_usize() + last.len == phys.as_usize() =>
+                    {
+                        last.len += size.to_byte_size()
+                    }
+                    _ => mappings.push(Mapping { virt, phys, len: size.to_byte_size(), page_size: size, flags }),
+                }
+            }
+            EntryKind::Branch(next_level) => {
+                let next_level = unsafe { &*phys2virt(next_level).as_ptr().cast() };
+                collect_mappings(next_level, size.next().unwrap(), virt, mappings);
+            }
+        }
+    }
+}
+
 pub struct PageTableDebug<'a>(&'a repr::PageTable, PageSize, VirtualAddress);
 
 impl PageTableDebug<'_> {
@@ -290,7 +651,7 @@ impl PageTableDebug<'_> {
                     writeln!(
                         f,
                         "[{}] {:#p} -> {:#p} ({:?})",
-                        self.size_to_letter(),
+                        size_to_letter(self.1),
                         addr,
                         entry.ppn().unwrap(),
                         entry.flags(),
@@ -316,15 +677,14 @@ impl PageTableDebug<'_> {
 
         Ok(())
     }
+}
 
-    fn size_to_letter(&self) -> char {
-        match self.1 {
-            PageSize::Kilopage => 'K',
-            PageSize::Megapage => 'M',
-            PageSize::Gigapage => 'G',
-            #[cfg(any(feature = "paging.sv48", feature = "paging.sv57"))]
-            PageSize::Terapage => 'T',
-        }
+fn size_to_letter(size: PageSize) -> char {
+    match size {
+        PageSize::Kilopage => 'K',
+        PageSize::Megapage => 'M',
+        PageSize::Gigapage => 'G',
+        PageSize::Terapage => 'T',
     }
 }
 
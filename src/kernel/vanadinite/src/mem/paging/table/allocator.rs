@@ -7,15 +7,18 @@
 
 use {
     crate::mem::{
-        phys::{zalloc_page, PhysicalPage},
+        phys::{zalloc_local_page, PhysicalPage},
         phys2virt, virt2phys, PhysicalMemoryAllocator, VirtualAddress, PHYSICAL_MEMORY_ALLOCATOR,
     },
     core::{
         alloc::{AllocError, Layout},
         ptr::NonNull,
+        sync::atomic::{AtomicUsize, Ordering},
     },
 };
 
+pub static ALLOCATED_TABLES: AtomicUsize = AtomicUsize::new(0);
+
 pub struct PageTableAllocator;
 
 unsafe impl alloc::alloc::Allocator for PageTableAllocator {
@@ -23,7 +26,8 @@ unsafe impl alloc::alloc::Allocator for PageTableAllocator {
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
         assert_eq!(layout.align(), 4096, "attempted to allocate something other than a page table");
 
-        let page = phys2virt(zalloc_page().as_phys_address()).as_mut_ptr();
+        let page = phys2virt(zalloc_local_page().as_phys_address()).as_mut_ptr();
+        ALLOCATED_TABLES.fetch_add(1, Ordering::Relaxed);
 
         Ok(unsafe { NonNull::new_unchecked(core::ptr::slice_from_raw_parts_mut(page, 4096)) })
     }
@@ -36,5 +40,6 @@ unsafe impl alloc::alloc::Allocator for PageTableAllocator {
             PhysicalPage::from_ptr(virt2phys(VirtualAddress::from_ptr(ptr.as_ptr())).as_mut_ptr()),
             crate::mem::paging::PageSize::Kilopage,
         );
+        ALLOCATED_TABLES.fetch_sub(1, Ordering::Relaxed);
     }
 }
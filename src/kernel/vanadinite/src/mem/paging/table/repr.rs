@@ -5,19 +5,15 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
-use core::ops::Range;
+use core::ops::{Index, IndexMut, Range};
 
-use crate::{mem::paging::table::flags::*, utils::Units};
+use crate::{
+    mem::paging::{levels, table::flags::*},
+    utils::Units,
+};
 
-// Default to Sv39
-#[cfg(all(not(feature = "paging.sv48"), not(feature = "paging.sv57")))]
-const N_VPN: usize = 3;
-// Sv48
-#[cfg(all(feature = "paging.sv48", not(feature = "paging.sv57")))]
-const N_VPN: usize = 4;
-// Sv57
-#[cfg(feature = "paging.sv57")]
-const N_VPN: usize = 5;
+/// The most levels a page table can have, which is how many Sv48 uses
+pub const MAX_LEVELS: usize = 4;
 
 const VPN_BITMASK: usize = 0x1FF;
 const PPN_MASK: usize = 0x00FF_FFFF_FFFF_FFFF;
@@ -126,10 +122,10 @@ impl core::fmt::Pointer for PhysicalAddress {
 pub struct VirtualAddress(usize);
 
 impl VirtualAddress {
-    pub const fn new(mut addr: usize) -> Self {
-        let top_most_bit = 1 << (12 + N_VPN * 9 - 1);
+    pub fn new(mut addr: usize) -> Self {
+        let top_most_bit = 1 << (12 + levels() * 9 - 1);
         if addr & top_most_bit == top_most_bit {
-            addr |= usize::MAX << (12 + N_VPN * 9 - 1);
+            addr |= usize::MAX << (12 + levels() * 9 - 1);
         }
         VirtualAddress(addr)
     }
@@ -234,14 +230,11 @@ impl VirtualAddress {
         Self(self.align_down_to(size).0 + size.to_byte_size())
     }
 
-    pub fn vpns(self) -> [usize; N_VPN] {
-        #[cfg(feature = "paging.sv57")]
-        compile_error!("sv57 stuff");
-
-        let mut vpns = [0; N_VPN];
+    pub fn vpns(self) -> Vpns {
+        let mut vpns = Vpns { vpns: [0; MAX_LEVELS], levels: levels() };
         let mut shift = 12;
 
-        for vpn in vpns.iter_mut() {
+        for vpn in vpns.vpns.iter_mut().take(vpns.levels) {
             *vpn = (self.0 >> shift) & VPN_BITMASK;
             shift += 9;
         }
@@ -251,7 +244,6 @@ impl VirtualAddress {
 
     pub fn offset_into_page(self, page_size: PageSize) -> usize {
         match page_size {
-            #[cfg(any(feature = "paging.sv48", feature = "paging.sv57"))]
             PageSize::Terapage => self.0 & (512.gib() - 1),
             PageSize::Gigapage => self.0 & (1.gib() - 1),
             PageSize::Megapage => self.0 & (2.mib() - 1),
@@ -263,47 +255,82 @@ impl VirtualAddress {
         self.offset_into_page(page_size) == 0
     }
 
-    pub const fn from_vpns(vpns: [usize; N_VPN]) -> Self {
-        #[cfg(feature = "paging.sv57")]
-        compile_error!("sv57 stuff");
-
+    pub fn from_vpns(vpns: Vpns) -> Self {
         let mut addr = 0;
         let mut shift = 12;
 
-        // Replace this with a `for` once that's available in `const fn`s
-        let mut i = 0;
-        while i < N_VPN {
-            addr |= vpns[i] << shift;
+        for vpn in vpns {
+            addr |= vpn << shift;
             shift += 9;
-
-            i += 1;
         }
 
-        let top_most_bit = 1 << (12 + N_VPN * 9 - 1);
-        if addr & top_most_bit == top_most_bit {
-            addr |= usize::MAX << (12 + N_VPN * 9 - 1);
-        }
-
-        VirtualAddress(addr)
+        VirtualAddress::new(addr)
     }
 
     pub fn is_kernel_region(self) -> bool {
         (self.0 as isize).is_negative()
     }
 
-    pub const fn userspace_range() -> Range<VirtualAddress> {
-        VirtualAddress::new(0)..VirtualAddress::new(1 << (12 + N_VPN * 9 - 1))
+    pub fn userspace_range() -> Range<VirtualAddress> {
+        // Not built with `new`, which would sign extend the end into the
+        // kernel's half
+        VirtualAddress(0)..VirtualAddress(1 << (12 + levels() * 9 - 1))
     }
 
-    pub const fn kernelspace_range() -> Range<VirtualAddress> {
-        let mut vpns = [0; N_VPN];
-        vpns[N_VPN - 1] = 256;
+    pub fn kernelspace_range() -> Range<VirtualAddress> {
+        let mut vpns = Vpns { vpns: [0; MAX_LEVELS], levels: levels() };
+        vpns[levels() - 1] = 256;
 
         // This should probably be a `..=` range, but...
         VirtualAddress::from_vpns(vpns)..VirtualAddress::new(usize::MAX)
     }
 }
 
+/// The index into the page table at each level for a [`VirtualAddress`],
+/// starting from the lowest level, with as many levels as the current paging
+/// mode uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vpns {
+    vpns: [usize; MAX_LEVELS],
+    levels: usize,
+}
+
+impl Vpns {
+    pub fn levels(&self) -> usize {
+        self.levels
+    }
+
+    /// The index into the root table
+    pub fn root(&self) -> usize {
+        self.vpns[self.levels - 1]
+    }
+}
+
+impl Index<usize> for Vpns {
+    type Output = usize;
+
+    #[track_caller]
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.vpns[..self.levels][index]
+    }
+}
+
+impl IndexMut<usize> for Vpns {
+    #[track_caller]
+    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+        &mut self.vpns[..self.levels][index]
+    }
+}
+
+impl IntoIterator for Vpns {
+    type Item = usize;
+    type IntoIter = core::iter::Take<core::array::IntoIter<usize, MAX_LEVELS>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.vpns.into_iter().take(self.levels)
+    }
+}
+
 impl core::fmt::Debug for VirtualAddress {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "VirtualAddress({:#p})", self.0 as *const u8)
@@ -369,6 +396,10 @@ impl PhysicalAddress {
     pub fn null() -> Self {
         Self(0)
     }
+
+    pub fn is_null(self) -> bool {
+        self.0 == 0
+    }
 }
 
 impl core::fmt::Debug for PhysicalAddress {
@@ -383,7 +414,7 @@ pub enum PageSize {
     Kilopage = 0,
     Megapage = 1,
     Gigapage = 2,
-    #[cfg(any(feature = "paging.sv48", feature = "paging.sv57"))]
+    /// Only in Sv48
     Terapage = 3,
 }
 
@@ -400,7 +431,6 @@ impl PageSize {
             PageSize::Kilopage => 4.kib(),
             PageSize::Megapage => 2.mib(),
             PageSize::Gigapage => 1.gib(),
-            #[cfg(any(feature = "paging.sv48", feature = "paging.sv57"))]
             PageSize::Terapage => 512.gib(),
         }
     }
@@ -410,20 +440,26 @@ impl PageSize {
             PageSize::Kilopage => None,
             PageSize::Megapage => Some(PageSize::Kilopage),
             PageSize::Gigapage => Some(PageSize::Megapage),
-            #[cfg(any(feature = "paging.sv48", feature = "paging.sv57"))]
             PageSize::Terapage => Some(PageSize::Gigapage),
         }
     }
 
+    /// The largest page which can map `from` at `to` without going past `len`
+    /// bytes. Terapages are never used, since nothing is ever mapped at that
+    /// granularity.
+    pub fn largest_for(from: PhysicalAddress, to: VirtualAddress, len: usize) -> Self {
+        [PageSize::Gigapage, PageSize::Megapage]
+            .into_iter()
+            .find(|&size| from.offset_into_page(size) == 0 && to.is_aligned(size) && len >= size.to_byte_size())
+            .unwrap_or(PageSize::Kilopage)
+    }
+
+    /// The size of the pages mapped by the root table
     pub fn top_level() -> Self {
-        // Default to Sv39
-        #[cfg(all(not(feature = "paging.sv48"), not(feature = "paging.sv57")))]
-        return PageSize::Gigapage;
-        // Sv48
-        #[cfg(all(feature = "paging.sv48", not(feature = "paging.sv57")))]
-        return PageSize::Terapage;
-        // Sv57
-        #[cfg(feature = "paging.sv57")]
-        compile_error!("sv57 stuff");
+        match levels() {
+            3 => PageSize::Gigapage,
+            4 => PageSize::Terapage,
+            levels => unreachable!("{} level page tables", levels),
+        }
     }
 }
@@ -21,3 +21,63 @@ fn virtual_addr_checks() {
     assert!(VirtualAddress::userspace_range().end.checked_add(0xffffff8000000000).is_none());
     assert!(VirtualAddress::kernelspace_range().start.checked_offset(-1).is_none());
 }
+
+#[test]
+fn contiguous_mappings_are_merged() {
+    let mut table = PageTable::new_raw();
+    let rw = flags::VALID | flags::USER | flags::READ | flags::WRITE;
+    let base = VirtualAddress::new(0x4000_0000);
+
+    for i in 0..3 {
+        table.map(PhysicalAddress::new(0x8000_0000 + i * 4096), base.add(i * 4096), rw, PageSize::Kilopage);
+    }
+
+    // Physically discontiguous, then different permissions
+    table.map(PhysicalAddress::new(0x9000_0000), base.add(3 * 4096), rw, PageSize::Kilopage);
+    table.map(PhysicalAddress::new(0x9000_1000), base.add(4 * 4096), rw | flags::EXECUTE, PageSize::Kilopage);
+
+    let mappings = table.mappings();
+    assert_eq!(mappings.len(), 3);
+    assert_eq!(mappings[0].virt, base);
+    assert_eq!(mappings[0].phys, PhysicalAddress::new(0x8000_0000));
+    assert_eq!(mappings[0].len, 3 * 4096);
+    assert_eq!(mappings[1].len, 4096);
+    assert_eq!(mappings[2].flags, rw | flags::EXECUTE);
+}
+
+#[test]
+fn ranges_use_large_pages_where_aligned() {
+    let mut table = PageTable::new_raw();
+    let rw = flags::VALID | flags::USER | flags::READ | flags::WRITE;
+    let base = VirtualAddress::new(0x4000_0000);
+
+    // A kilopage either side of a megapage aligned run of two megapages
+    let from = PhysicalAddress::new(0x8020_0000 - 4096);
+    table.map_range(from, base.add(0x20_0000 - 4096), 2 * 4096 + 2 * 0x20_0000, rw);
+
+    assert_eq!(table.page_size(base.add(0x20_0000 - 4096)), Some(PageSize::Kilopage));
+    assert_eq!(table.page_size(base.add(0x20_0000)), Some(PageSize::Megapage));
+    assert_eq!(table.page_size(base.add(0x40_0000)), Some(PageSize::Megapage));
+    assert_eq!(table.page_size(base.add(0x60_0000)), Some(PageSize::Kilopage));
+    assert_eq!(table.resolve(base.add(0x40_0000)), Some(PhysicalAddress::new(0x8040_0000)));
+
+    // Misaligned with each other, so only kilopages can be used
+    let base = VirtualAddress::new(0x8000_0000);
+    table.map_range(PhysicalAddress::new(0x9000_1000), base, 0x20_0000, rw);
+    assert!(table.mappings().iter().filter(|m| m.virt >= base).all(|m| m.page_size == PageSize::Kilopage));
+}
+
+#[test]
+fn gigapages_demote_to_megapages() {
+    let mut table = PageTable::new_raw();
+    let rw = flags::VALID | flags::USER | flags::READ | flags::WRITE;
+    let base = VirtualAddress::new(0x4000_0000);
+
+    table.map_range(PhysicalAddress::new(0x8000_0000), base, 0x4000_0000, rw);
+    assert_eq!(table.page_size(base), Some(PageSize::Gigapage));
+
+    table.demote(base);
+    assert_eq!(table.page_size(base.add(0x20_0000)), Some(PageSize::Megapage));
+    assert_eq!(table.resolve(base.add(0x20_0000)), Some(PhysicalAddress::new(0x8020_0000)));
+    assert_eq!(table.page_flags(base.add(0x3FE0_0000)), Some(rw));
+}
@@ -5,10 +5,16 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
-use super::{PhysicalAddress, PhysicalMemoryAllocator, PhysicalPage};
-use crate::{mem::paging::PageSize, Units};
+use super::{MemoryStats, PhysicalAddress, PhysicalMemoryAllocator, PhysicalPage, Zone};
+use crate::{
+    mem::{paging::PageSize, region::KILOPAGES_PER_MEGAPAGE},
+    Units,
+};
+use core::ops::Range;
 
 const SINGLE_ENTRY_SIZE_BYTES: usize = 64 * 4096;
+/// The number of entries covering a megapage
+const ENTRIES_PER_BLOCK: usize = KILOPAGES_PER_MEGAPAGE / 64;
 
 pub struct BitmapAllocator {
     bitmap: *mut u64,
@@ -36,12 +42,110 @@ impl BitmapAllocator {
         }
     }
 
+    /// Whether `page` is part of the memory managed by this allocator
+    pub fn contains(&self, page: PhysicalPage) -> bool {
+        (self.mem_start..self.mem_end).contains(&page.as_phys_address().as_mut_ptr())
+    }
+
+    /// The bitmap entries covering `zone`. Entries which straddle the end of
+    /// the DMA32 zone belong to the normal zone.
+    fn zone_entries(&self, zone: Zone) -> Range<usize> {
+        let dma32_end = match (self.mem_start as usize).checked_sub(Zone::DMA32_END) {
+            Some(_) => 0,
+            None => ((Zone::DMA32_END - self.mem_start as usize) / SINGLE_ENTRY_SIZE_BYTES).min(self.size),
+        };
+
+        match zone {
+            Zone::Dma32 => 0..dma32_end,
+            Zone::Normal => dma32_end..self.size,
+        }
+    }
+
+    /// The megapage sized and aligned blocks of memory which are entirely
+    /// covered by `entries`, as ranges of entries
+    fn blocks(&self, entries: Range<usize>) -> impl Iterator<Item = Range<usize>> {
+        let first_aligned = self.mem_start.align_offset(PageSize::Megapage.to_byte_size()) / SINGLE_ENTRY_SIZE_BYTES;
+        let skip = entries.start.saturating_sub(first_aligned);
+        let first = first_aligned + (skip + ENTRIES_PER_BLOCK - 1) / ENTRIES_PER_BLOCK * ENTRIES_PER_BLOCK;
+        // The last entry has bits past the end of memory, so it's never part
+        // of a whole block
+        let end = entries.end.min(self.size.saturating_sub(1));
+
+        (first..end.saturating_sub(ENTRIES_PER_BLOCK - 1))
+            .step_by(ENTRIES_PER_BLOCK)
+            .map(|start| start..start + ENTRIES_PER_BLOCK)
+    }
+
+    /// The block `page` is part of, if it's part of a whole one
+    fn block_containing(&self, page: PhysicalPage) -> Option<Range<usize>> {
+        let index = (page.as_phys_address().as_usize() - self.mem_start as usize) / SINGLE_ENTRY_SIZE_BYTES;
+        let zone = Zone::ALL.into_iter().map(|zone| self.zone_entries(zone)).find(|zone| zone.contains(&index))?;
+
+        self.blocks(zone).find(|block| block.contains(&index))
+    }
+
+    fn block_usage_of(&mut self, block: Range<usize>) -> usize {
+        self.bitmap_slice()[block].iter().map(|entry| entry.count_ones() as usize).sum()
+    }
+
+    /// How many pages of the megapage sized block `page` is part of are in
+    /// use, or `None` if it isn't part of a whole block
+    pub fn block_usage(&mut self, page: PhysicalPage) -> Option<usize> {
+        let block = self.block_containing(page)?;
+        Some(self.block_usage_of(block))
+    }
+
+    /// Allocate a kilopage to move `page` into from the fullest block in the
+    /// same zone which has more pages in use than the block `page` is part of,
+    /// so that moving it leaves the free pages less spread out. Returns `None`
+    /// if there's no such block.
+    ///
+    /// # Safety
+    ///
+    /// See [`PhysicalMemoryAllocator::alloc`]
+    pub unsafe fn alloc_denser(&mut self, page: PhysicalPage) -> Option<PhysicalPage> {
+        let from = self.block_containing(page)?;
+        let zone = Zone::ALL.into_iter().map(|zone| self.zone_entries(zone)).find(|zone| zone.contains(&from.start))?;
+        let from_usage = self.block_usage_of(from.clone());
+
+        let (_, target) = self
+            .blocks(zone)
+            .filter(|block| *block != from)
+            .map(|block| (self.block_usage_of(block.clone()), block))
+            .filter(|(usage, _)| *usage > from_usage && *usage < KILOPAGES_PER_MEGAPAGE)
+            .max_by_key(|(usage, _)| *usage)?;
+
+        self.alloc_4k_page(target)
+    }
+
+    fn alloc_4k_page(&mut self, entries: Range<usize>) -> Option<PhysicalPage> {
+        log::trace!("attempting to allocate a single page");
+        let offset = entries.start;
+        if let Some((index, entry)) = self.bitmap_slice()[entries].iter_mut().enumerate().find(|(_, e)| **e != u64::MAX)
+        {
+            let bit_index = entry.trailing_ones() as usize;
+
+            let page_ptr = (self.mem_start as usize + (offset + index) * SINGLE_ENTRY_SIZE_BYTES) + (bit_index * 4096);
+            let page_ptr = page_ptr as *mut u8;
+
+            if page_ptr <= self.mem_end {
+                *entry |= 1 << bit_index;
+                log::trace!("Allocated page at: {:#p}", page_ptr);
+                return Some(PhysicalPage::from_ptr(page_ptr));
+            }
+        }
+
+        None
+    }
+
     // TODO: Check for small inter-regions as well
-    fn alloc_contig_4k_intra_pages(&mut self, n: usize) -> Option<PhysicalPage> {
+    fn alloc_contig_4k_intra_pages(&mut self, entries: Range<usize>, n: usize) -> Option<PhysicalPage> {
         let mask = u64::MAX << n;
+        let offset = entries.start;
 
         if n == 64 {
-            let (index, entry) = self.bitmap_slice().iter_mut().enumerate().find(|(_, e)| **e == 0)?;
+            let (index, entry) = self.bitmap_slice()[entries].iter_mut().enumerate().find(|(_, e)| **e == 0)?;
+            let index = offset + index;
             *entry = u64::MAX;
 
             let page_ptr = (self.mem_start as usize + index * SINGLE_ENTRY_SIZE_BYTES) as *mut u8;
@@ -53,7 +157,8 @@ impl BitmapAllocator {
         }
 
         let free_bit_filter = |(_, e): &(usize, &mut u64)| e.count_zeros() as usize >= n;
-        for (index, entry) in self.bitmap_slice().iter_mut().enumerate().filter(free_bit_filter) {
+        for (index, entry) in self.bitmap_slice()[entries].iter_mut().enumerate().filter(free_bit_filter) {
+            let index = offset + index;
             let bit_index = match (0..(64 - n)).map(|i| (i, *entry >> i)).find(|(_, e)| e | mask == mask) {
                 Some((idx, _)) => idx,
                 None => continue,
@@ -72,12 +177,12 @@ impl BitmapAllocator {
         None
     }
 
-    fn alloc_contig_4k_inter_pages(&mut self, n: usize) -> Option<PhysicalPage> {
+    fn alloc_contig_4k_inter_pages(&mut self, entries: Range<usize>, n: usize) -> Option<PhysicalPage> {
         let whole_entries_needed = n / 64;
         let last_bits_needed = (n % 64) as u32;
 
-        let mut start_index = 0;
-        let bitmap = self.bitmap_slice();
+        let mut start_index = entries.start;
+        let bitmap = &mut self.bitmap_slice()[..entries.end];
 
         loop {
             let range = start_index..(start_index + whole_entries_needed);
@@ -129,53 +234,54 @@ unsafe impl PhysicalMemoryAllocator for BitmapAllocator {
 
     #[track_caller]
     unsafe fn alloc(&mut self, align_to: PageSize) -> Option<PhysicalPage> {
-        match align_to {
-            PageSize::Megapage => self.alloc_contiguous(align_to, 1),
-            PageSize::Kilopage => {
-                log::trace!("attempting to allocate a single page");
-                if let Some((index, entry)) = self.bitmap_slice().iter_mut().enumerate().find(|(_, e)| **e != u64::MAX)
-                {
-                    let bit_index = entry.trailing_ones() as usize;
-
-                    let page_ptr = (self.mem_start as usize + index * SINGLE_ENTRY_SIZE_BYTES) + (bit_index * 4096);
-                    let page_ptr = page_ptr as *mut u8;
-
-                    if page_ptr <= self.mem_end {
-                        *entry |= 1 << bit_index;
-                        log::trace!("Allocated page at: {:#p}", page_ptr);
-                        return Some(PhysicalPage::from_ptr(page_ptr));
-                    }
-                }
+        Zone::FALLBACK_ORDER.into_iter().find_map(|zone| self.alloc_in(zone, align_to))
+    }
 
-                None
-            }
+    #[track_caller]
+    unsafe fn alloc_in(&mut self, zone: Zone, align_to: PageSize) -> Option<PhysicalPage> {
+        match align_to {
+            PageSize::Megapage => self.alloc_contiguous_in(zone, align_to, 1),
+            PageSize::Kilopage => self.alloc_4k_page(self.zone_entries(zone)),
             _ => todo!("[pmalloc.allocator] BitmapAllocator::alloc: >megapage alloc"),
         }
     }
 
     #[track_caller]
     unsafe fn alloc_contiguous(&mut self, align_to: PageSize, n: usize) -> Option<PhysicalPage> {
+        Zone::FALLBACK_ORDER.into_iter().find_map(|zone| self.alloc_contiguous_in(zone, align_to, n))
+    }
+
+    #[track_caller]
+    unsafe fn alloc_contiguous_in(&mut self, zone: Zone, align_to: PageSize, n: usize) -> Option<PhysicalPage> {
+        let entries = self.zone_entries(zone);
+        if entries.is_empty() {
+            return None;
+        }
+
         if let PageSize::Kilopage = align_to {
             match n {
-                0..=64 => return self.alloc_contig_4k_intra_pages(n),
-                _ => return self.alloc_contig_4k_inter_pages(n),
+                0..=64 => return self.alloc_contig_4k_intra_pages(entries, n),
+                _ => return self.alloc_contig_4k_inter_pages(entries, n),
             }
         }
 
         // Megapages and above can use the same code
         let n_entries = (((align_to.to_byte_size() / 4.kib()) * n) / 64).max(1);
+        let entries_per_page = align_to.to_byte_size() / SINGLE_ENTRY_SIZE_BYTES;
         let mut start_index = {
-            let offset = self.mem_start.align_offset(align_to.to_byte_size());
-            offset / 64.kib()
+            let first_aligned = self.mem_start.align_offset(align_to.to_byte_size()) / SINGLE_ENTRY_SIZE_BYTES;
+            let skip = entries.start.saturating_sub(first_aligned);
+            first_aligned + (skip + entries_per_page - 1) / entries_per_page * entries_per_page
         };
 
         let mut end_index = start_index + n_entries;
-        while self.bitmap_slice().get(start_index..end_index)?.iter().any(|n| n.count_ones() != 0) {
+        let bitmap = &mut self.bitmap_slice()[..entries.end];
+        while bitmap.get(start_index..end_index)?.iter().any(|n| n.count_ones() != 0) {
             start_index += n_entries;
             end_index = start_index + n_entries;
         }
 
-        for entry in &mut self.bitmap_slice()[start_index..end_index] {
+        for entry in &mut bitmap[start_index..end_index] {
             *entry = u64::MAX;
         }
 
@@ -287,6 +393,28 @@ unsafe impl PhysicalMemoryAllocator for BitmapAllocator {
 
         *entry &= !(1 << bit);
     }
+
+    fn free_pages(&mut self) -> usize {
+        let n_pages = (self.mem_end as usize - self.mem_start as usize) / 4.kib();
+        // The last entry has bits past the end of memory, which are never used
+        let past_end = self.size * 64 - n_pages;
+        let free: usize = self.bitmap_slice().iter().map(|entry| entry.count_zeros() as usize).sum();
+
+        free.saturating_sub(past_end)
+    }
+
+    fn zone_stats(&mut self, zone: Zone) -> MemoryStats {
+        let n_pages = (self.mem_end as usize - self.mem_start as usize) / 4.kib();
+        let entries = self.zone_entries(zone);
+        let (first_page, end_page) = (entries.start * 64, entries.end * 64);
+
+        let total_pages = end_page.min(n_pages) - first_page.min(n_pages);
+        // Same as for `free_pages`, bits past the end of memory aren't free
+        let past_end = end_page.max(n_pages) - first_page.max(n_pages);
+        let free: usize = self.bitmap_slice()[entries].iter().map(|entry| entry.count_zeros() as usize).sum();
+
+        MemoryStats { total_pages, free_pages: free.saturating_sub(past_end) }
+    }
 }
 
 unsafe impl Send for BitmapAllocator {}
@@ -0,0 +1,473 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A buddy allocator for physical memory
+//!
+//! Free memory is kept in blocks of 2^order pages, with a free list for each
+//! order in each zone. Blocks are aligned to their own size in physical memory,
+//! so an order 9 block is always a usable megapage. Allocations split the
+//! smallest block big enough for them in half until they're the size asked
+//! for, and freeing a block merges it with its buddy, the other half of the
+//! block one order up, for as long as the buddy is free too.
+//!
+//! What each page is doing is kept in a byte per page at the start of the
+//! region, and the free lists are threaded through the free blocks themselves.
+//! Allocations aren't tracked, so memory can be freed in different sized pieces
+//! than it was allocated in.
+
+use super::{MemoryStats, PhysicalAddress, PhysicalMemoryAllocator, PhysicalPage, Zone};
+use crate::{
+    mem::{paging::PageSize, phys2virt, region::KILOPAGES_PER_MEGAPAGE},
+    Units,
+};
+use core::ops::Range;
+
+/// The largest blocks are a gigapage
+pub const MAX_ORDER: usize = 18;
+const MEGAPAGE_ORDER: usize = 9;
+
+/// Set in the state of the first page of a free block, along with its order
+const FREE_HEAD: u8 = 0x80;
+const NONE: usize = usize::MAX;
+
+/// Kept in the first page of every free block
+#[derive(Clone, Copy)]
+struct FreeBlock {
+    next: usize,
+    prev: usize,
+}
+
+pub struct BuddyAllocator {
+    /// One byte per page
+    states: *mut u8,
+    mem_start: *mut u8,
+    mem_end: *mut u8,
+    n_pages: usize,
+    /// The first page of each zone's free blocks of each order, indexed by
+    /// [`zone_index`]
+    free_lists: [[usize; MAX_ORDER + 1]; 2],
+    free_pages: [usize; 2],
+}
+
+impl BuddyAllocator {
+    pub const fn new() -> Self {
+        Self {
+            states: core::ptr::null_mut(),
+            mem_start: core::ptr::null_mut(),
+            mem_end: core::ptr::null_mut(),
+            n_pages: 0,
+            free_lists: [[NONE; MAX_ORDER + 1]; 2],
+            free_pages: [0; 2],
+        }
+    }
+
+    /// Whether `page` is part of the memory managed by this allocator
+    pub fn contains(&self, page: PhysicalPage) -> bool {
+        (self.mem_start..self.mem_end).contains(&page.as_phys_address().as_mut_ptr())
+    }
+
+    fn states(&mut self) -> &'static mut [u8] {
+        unsafe {
+            core::slice::from_raw_parts_mut(
+                phys2virt(PhysicalAddress::from_ptr(self.states)).as_mut_ptr(),
+                self.n_pages,
+            )
+        }
+    }
+
+    fn block(&self, index: usize) -> &'static mut FreeBlock {
+        unsafe { &mut *phys2virt(self.address_of(index)).as_mut_ptr().cast() }
+    }
+
+    fn address_of(&self, index: usize) -> PhysicalAddress {
+        PhysicalAddress::from_ptr(self.mem_start).offset(index * 4.kib())
+    }
+
+    fn index_of(&self, page: PhysicalPage) -> usize {
+        (page.as_phys_address().as_usize() - self.mem_start as usize) / 4.kib()
+    }
+
+    /// Blocks are aligned by their physical address rather than where they are
+    /// in the region
+    fn pfn(&self, index: usize) -> usize {
+        self.mem_start as usize / 4.kib() + index
+    }
+
+    fn zone_of(&self, index: usize) -> Zone {
+        match self.address_of(index).as_usize() < Zone::DMA32_END {
+            true => Zone::Dma32,
+            false => Zone::Normal,
+        }
+    }
+
+    /// The pages of the region in `zone`
+    fn zone_pages(&self, zone: Zone) -> Range<usize> {
+        let dma32_end = (Zone::DMA32_END.saturating_sub(self.mem_start as usize) / 4.kib()).min(self.n_pages);
+
+        match zone {
+            Zone::Dma32 => 0..dma32_end,
+            Zone::Normal => dma32_end..self.n_pages,
+        }
+    }
+
+    /// The buddy of the block of `order` at `index`, if it's in the region
+    fn buddy_of(&self, index: usize, order: usize) -> Option<usize> {
+        let buddy = (self.pfn(index) ^ (1 << order)).checked_sub(self.pfn(0))?;
+        (buddy < self.n_pages).then_some(buddy)
+    }
+
+    fn push(&mut self, index: usize, order: usize) {
+        let zone = zone_index(self.zone_of(index));
+        let head = self.free_lists[zone][order];
+
+        *self.block(index) = FreeBlock { next: head, prev: NONE };
+        if head != NONE {
+            self.block(head).prev = index;
+        }
+
+        self.free_lists[zone][order] = index;
+        self.states()[index] = FREE_HEAD | order as u8;
+    }
+
+    fn remove(&mut self, index: usize, order: usize) {
+        let zone = zone_index(self.zone_of(index));
+        let FreeBlock { next, prev } = *self.block(index);
+
+        match prev {
+            NONE => self.free_lists[zone][order] = next,
+            prev => self.block(prev).next = next,
+        }
+
+        if next != NONE {
+            self.block(next).prev = prev;
+        }
+
+        self.states()[index] = 0;
+    }
+
+    /// The free block `index` is part of, and its order
+    fn free_block_containing(&mut self, index: usize) -> Option<(usize, usize)> {
+        let base = self.pfn(0);
+        (0..=MAX_ORDER).find_map(|order| {
+            let head = (self.pfn(index) & !((1 << order) - 1)).checked_sub(base)?;
+            (self.states()[head] == FREE_HEAD | order as u8).then_some((head, order))
+        })
+    }
+
+    /// Free the block of `order` at `index`, merging it with its buddies
+    fn free_block(&mut self, mut index: usize, mut order: usize) {
+        self.free_pages[zone_index(self.zone_of(index))] += 1 << order;
+
+        while order < MAX_ORDER {
+            match self.buddy_of(index, order) {
+                Some(buddy) if self.states()[buddy] == FREE_HEAD | order as u8 => {
+                    self.remove(buddy, order);
+                    index = index.min(buddy);
+                    order += 1;
+                }
+                _ => break,
+            }
+        }
+
+        self.push(index, order);
+    }
+
+    /// Free `n` pages starting at `index` as the biggest blocks they can be
+    /// split into
+    #[track_caller]
+    fn free_range(&mut self, mut index: usize, n: usize) {
+        if n == 0 {
+            return;
+        }
+
+        let end = index + n;
+        assert!(end <= self.n_pages, "[pmalloc.allocator] BuddyAllocator::dealloc: freeing past the end of memory");
+
+        let already_free = self.free_block_containing(index).is_some()
+            || self.states()[index..end].iter().any(|state| state & FREE_HEAD != 0);
+        if already_free {
+            panic!(
+                "[pmalloc.allocator] BuddyAllocator::dealloc: double free detected for address {:#p}",
+                self.address_of(index).as_ptr()
+            );
+        }
+
+        while index < end {
+            let order = (0..=MAX_ORDER)
+                .rev()
+                .find(|&order| self.pfn(index) % (1 << order) == 0 && index + (1 << order) <= end)
+                .unwrap();
+
+            self.free_block(index, order);
+            index += 1 << order;
+        }
+    }
+
+    /// Take the page at `index` out of the free block it's part of, if it's
+    /// free, putting the rest of the block back as smaller blocks. Returns
+    /// whether the page was free.
+    fn take_page(&mut self, index: usize) -> bool {
+        let (mut head, mut order) = match self.free_block_containing(index) {
+            Some(block) => block,
+            None => return false,
+        };
+
+        self.remove(head, order);
+        self.free_pages[zone_index(self.zone_of(index))] -= 1;
+
+        while order > 0 {
+            order -= 1;
+            let half = 1 << order;
+            match index >= head + half {
+                true => {
+                    self.push(head, order);
+                    head += half;
+                }
+                false => self.push(head + half, order),
+            }
+        }
+
+        true
+    }
+
+    /// Allocate a block of 2^`order` pages from `zone`, aligned to its size
+    pub fn alloc_order(&mut self, zone: Zone, order: usize) -> Option<PhysicalPage> {
+        let zone_lists = self.free_lists[zone_index(zone)];
+        let mut from = (order..=MAX_ORDER).find(|&from| zone_lists[from] != NONE)?;
+        let index = zone_lists[from];
+
+        self.remove(index, from);
+        self.free_pages[zone_index(zone)] -= 1 << order;
+
+        while from > order {
+            from -= 1;
+            self.push(index + (1 << from), from);
+        }
+
+        Some(PhysicalPage::from_ptr(self.address_of(index).as_mut_ptr()))
+    }
+
+    /// The megapage sized and aligned blocks which are entirely within `zone`
+    fn megapages(&self, zone: Zone) -> impl Iterator<Item = Range<usize>> {
+        let pages = self.zone_pages(zone);
+        let first = pages.start + (self.pfn(pages.start).wrapping_neg() % KILOPAGES_PER_MEGAPAGE);
+
+        (first..pages.end.saturating_sub(KILOPAGES_PER_MEGAPAGE - 1))
+            .step_by(KILOPAGES_PER_MEGAPAGE)
+            .map(|start| start..start + KILOPAGES_PER_MEGAPAGE)
+    }
+
+    fn megapage_containing(&self, index: usize) -> Option<Range<usize>> {
+        let zone = self.zone_of(index);
+        self.megapages(zone).find(|block| block.contains(&index))
+    }
+
+    fn megapage_usage(&mut self, block: Range<usize>) -> usize {
+        if matches!(self.free_block_containing(block.start), Some((_, order)) if order >= MEGAPAGE_ORDER) {
+            return 0;
+        }
+
+        let mut free = 0;
+        let mut index = block.start;
+        while index < block.end {
+            match self.states()[index] {
+                state if state & FREE_HEAD != 0 => {
+                    free += 1 << (state & !FREE_HEAD);
+                    index += 1 << (state & !FREE_HEAD);
+                }
+                _ => index += 1,
+            }
+        }
+
+        KILOPAGES_PER_MEGAPAGE - free
+    }
+
+    /// How many pages of the megapage sized block `page` is part of are in
+    /// use, or `None` if it isn't part of a whole block
+    pub fn block_usage(&mut self, page: PhysicalPage) -> Option<usize> {
+        let block = self.megapage_containing(self.index_of(page))?;
+        Some(self.megapage_usage(block))
+    }
+
+    /// Allocate a kilopage to move `page` into from the fullest block in the
+    /// same zone which has more pages in use than the block `page` is part of,
+    /// so that moving it leaves the free pages less spread out. Returns `None`
+    /// if there's no such block.
+    ///
+    /// # Safety
+    ///
+    /// See [`PhysicalMemoryAllocator::alloc`]
+    pub unsafe fn alloc_denser(&mut self, page: PhysicalPage) -> Option<PhysicalPage> {
+        let from = self.megapage_containing(self.index_of(page))?;
+        let from_usage = self.megapage_usage(from.clone());
+
+        let (_, target) = self
+            .megapages(self.zone_of(from.start))
+            .filter(|block| *block != from)
+            .map(|block| (self.megapage_usage(block.clone()), block))
+            .filter(|(usage, _)| *usage > from_usage && *usage < KILOPAGES_PER_MEGAPAGE)
+            .max_by_key(|(usage, _)| *usage)?;
+
+        let index = target.clone().find(|&index| self.states()[index] & FREE_HEAD != 0)?;
+        self.take_page(index);
+
+        Some(PhysicalPage::from_ptr(self.address_of(index).as_mut_ptr()))
+    }
+}
+
+unsafe impl PhysicalMemoryAllocator for BuddyAllocator {
+    unsafe fn init(&mut self, start: *mut u8, end: *mut u8) {
+        assert!(!start.is_null(), "null start pointer!");
+        assert_eq!(start as usize % 4096, 0, "unaligned memory start page");
+        self.mem_start = start;
+        self.mem_end = end;
+        self.n_pages = (end as usize - start as usize) / 4.kib();
+        self.states = start;
+
+        self.states().fill(0);
+
+        // The states of the pages are kept in the first few of them
+        let state_pages = (self.n_pages + 4.kib() - 1) / 4.kib();
+        if let Some(free) = self.n_pages.checked_sub(state_pages) {
+            self.free_range(state_pages, free);
+        }
+    }
+
+    #[track_caller]
+    unsafe fn alloc(&mut self, align_to: PageSize) -> Option<PhysicalPage> {
+        Zone::FALLBACK_ORDER.into_iter().find_map(|zone| self.alloc_in(zone, align_to))
+    }
+
+    #[track_caller]
+    unsafe fn alloc_in(&mut self, zone: Zone, align_to: PageSize) -> Option<PhysicalPage> {
+        self.alloc_contiguous_in(zone, align_to, 1)
+    }
+
+    #[track_caller]
+    unsafe fn alloc_contiguous(&mut self, align_to: PageSize, n: usize) -> Option<PhysicalPage> {
+        Zone::FALLBACK_ORDER.into_iter().find_map(|zone| self.alloc_contiguous_in(zone, align_to, n))
+    }
+
+    #[track_caller]
+    unsafe fn alloc_contiguous_in(&mut self, zone: Zone, align_to: PageSize, n: usize) -> Option<PhysicalPage> {
+        let pages = n.checked_mul(align_to.to_byte_size() / 4.kib())?.max(1);
+        let order = pages.checked_next_power_of_two()?.trailing_zeros() as usize;
+        let page = self.alloc_order(zone, order)?;
+
+        // Anything the power of two rounded up to goes straight back
+        let index = self.index_of(page);
+        self.free_range(index + pages, (1 << order) - pages);
+
+        Some(page)
+    }
+
+    #[track_caller]
+    unsafe fn dealloc(&mut self, page: PhysicalPage, size: PageSize) {
+        self.dealloc_contiguous(page, size, 1)
+    }
+
+    #[track_caller]
+    unsafe fn dealloc_contiguous(&mut self, page: PhysicalPage, size: PageSize, n: usize) {
+        let index = self.index_of(page);
+        self.free_range(index, n * (size.to_byte_size() / 4.kib()));
+    }
+
+    #[track_caller]
+    unsafe fn set_used(&mut self, page: PhysicalPage) {
+        let index = self.index_of(page);
+        self.take_page(index);
+    }
+
+    #[track_caller]
+    unsafe fn set_unused(&mut self, page: PhysicalPage) {
+        let index = self.index_of(page);
+        if self.free_block_containing(index).is_none() {
+            self.free_block(index, 0);
+        }
+    }
+
+    fn free_pages(&mut self) -> usize {
+        self.free_pages.iter().sum()
+    }
+
+    fn zone_stats(&mut self, zone: Zone) -> MemoryStats {
+        MemoryStats { total_pages: self.zone_pages(zone).len(), free_pages: self.free_pages[zone_index(zone)] }
+    }
+}
+
+unsafe impl Send for BuddyAllocator {}
+unsafe impl Sync for BuddyAllocator {}
+
+fn zone_index(zone: Zone) -> usize {
+    match zone {
+        Zone::Dma32 => 0,
+        Zone::Normal => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::phys::PHYSICAL_MEMORY_ALLOCATOR;
+    use alloc::vec::Vec;
+    use vanadinite_macros::test;
+
+    /// Run `f` with a buddy allocator managing 4 MiB of memory taken from the
+    /// kernel's allocator
+    fn with_allocator(f: impl FnOnce(&mut BuddyAllocator)) {
+        let region = unsafe { PHYSICAL_MEMORY_ALLOCATOR.lock().alloc_contiguous(PageSize::Megapage, 2) }.unwrap();
+        let start = region.as_phys_address().as_mut_ptr();
+
+        let mut allocator = BuddyAllocator::new();
+        unsafe { allocator.init(start, start.add(4.mib())) };
+        f(&mut allocator);
+
+        unsafe { PHYSICAL_MEMORY_ALLOCATOR.lock().dealloc_contiguous(region, PageSize::Megapage, 2) };
+    }
+
+    #[test]
+    fn buddies_coalesce() {
+        with_allocator(|allocator| {
+            // The first page holds the states of the rest
+            let free = allocator.free_pages();
+            assert_eq!(free, 1023);
+
+            let pages = core::iter::from_fn(|| unsafe { allocator.alloc(PageSize::Kilopage) }).collect::<Vec<_>>();
+            assert_eq!(pages.len(), free);
+            assert_eq!(allocator.free_pages(), 0);
+
+            for page in pages.into_iter().rev() {
+                unsafe { allocator.dealloc(page, PageSize::Kilopage) };
+            }
+
+            assert_eq!(allocator.free_pages(), free);
+            let megapage = unsafe { allocator.alloc(PageSize::Megapage) }.unwrap();
+            assert_eq!(megapage.as_phys_address().as_usize() % 2.mib(), 0);
+        });
+    }
+
+    #[test]
+    fn contiguous_allocations_are_exact() {
+        with_allocator(|allocator| {
+            let free = allocator.free_pages();
+
+            let three = unsafe { allocator.alloc_contiguous(PageSize::Kilopage, 3) }.unwrap();
+            assert_eq!(three.as_phys_address().as_usize() % 16.kib(), 0);
+            assert_eq!(allocator.free_pages(), free - 3);
+
+            // The fourth page of the block was given back
+            let fourth = unsafe { allocator.alloc(PageSize::Kilopage) }.unwrap();
+            assert_eq!(fourth.as_phys_address(), three.as_phys_address().offset(3 * 4.kib()));
+
+            unsafe { allocator.dealloc_contiguous(three, PageSize::Kilopage, 3) };
+            unsafe { allocator.dealloc(fourth, PageSize::Kilopage) };
+            assert_eq!(allocator.free_pages(), free);
+            // Only the page with the states in it is left
+            assert_eq!(allocator.block_usage(three), Some(1));
+        });
+    }
+}
@@ -6,15 +6,24 @@
 // obtain one at https://mozilla.org/MPL/2.0/.
 
 pub mod bitmap;
+pub mod buddy;
+pub mod numa;
+pub mod zeroed;
 
 use crate::mem::paging::PhysicalAddress;
-use bitmap::BitmapAllocator;
+use alloc::collections::BTreeMap;
+use numa::NumaAllocator;
 use sync::SpinMutex;
 
 use super::paging::PageSize;
 
-#[cfg(any(not(any(feature = "pmalloc.allocator.buddy")), feature = "pmalloc.allocator.bitmap"))]
-pub static PHYSICAL_MEMORY_ALLOCATOR: SpinMutex<BitmapAllocator> = SpinMutex::new(BitmapAllocator::new());
+pub static PHYSICAL_MEMORY_ALLOCATOR: SpinMutex<NumaAllocator> = SpinMutex::new(NumaAllocator::new());
+/// The number of references to each page beyond the first, for the pages
+/// shared copy-on-write which have more than one. This is kept apart from the
+/// allocator since growing the kernel heap needs the allocator's lock.
+static SHARED_PAGES: SpinMutex<BTreeMap<usize, usize>> = SpinMutex::new(BTreeMap::new());
+/// The number of times each pinned page has been pinned, see [`pin`]
+static PINNED_PAGES: SpinMutex<BTreeMap<usize, usize>> = SpinMutex::new(BTreeMap::new());
 
 pub unsafe trait PhysicalMemoryAllocator {
     /// # Safety
@@ -29,12 +38,24 @@ pub unsafe trait PhysicalMemoryAllocator {
     /// without it having been deallocated before being reused each time
     unsafe fn alloc(&mut self, align_to: PageSize) -> Option<PhysicalPage>;
 
+    /// # Safety
+    ///
+    /// The requirements for this method are the same as [`alloc`], but the
+    /// page returned must lie within `zone`
+    unsafe fn alloc_in(&mut self, zone: Zone, align_to: PageSize) -> Option<PhysicalPage>;
+
     /// # Safety
     ///
     /// The requirements for this method are the same as [`alloc`], but apply to
     /// the entire range returned
     unsafe fn alloc_contiguous(&mut self, align_to: PageSize, n: usize) -> Option<PhysicalPage>;
 
+    /// # Safety
+    ///
+    /// The requirements for this method are the same as [`alloc_contiguous`],
+    /// but the range returned must lie entirely within `zone`
+    unsafe fn alloc_contiguous_in(&mut self, zone: Zone, align_to: PageSize, n: usize) -> Option<PhysicalPage>;
+
     /// # Safety
     ///
     /// See the memory safety requirements of [`set_unused`]
@@ -61,6 +82,36 @@ pub unsafe trait PhysicalMemoryAllocator {
     /// requirement could result in undefined behavior if the freed page is then
     /// reallocated to another object in memory, resulting in memory corruption
     unsafe fn set_unused(&mut self, page: PhysicalPage);
+
+    /// The number of pages which are currently free
+    fn free_pages(&mut self) -> usize;
+
+    /// How much of `zone` there is and how much of it is free
+    fn zone_stats(&mut self, zone: Zone) -> MemoryStats;
+}
+
+/// Physical memory is split into zones by which devices are able to address
+/// it. Allocations which don't need a specific zone are made from the zones in
+/// [`Zone::FALLBACK_ORDER`], so that the memory which devices with limited
+/// addressing can use is kept for them for as long as possible.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    /// Memory below 4 GiB, for devices which can only address 32 bits
+    Dma32,
+    Normal,
+}
+
+impl Zone {
+    /// The physical address the DMA32 zone ends at
+    pub const DMA32_END: usize = 1 << 32;
+    pub const FALLBACK_ORDER: [Zone; 2] = [Zone::Normal, Zone::Dma32];
+    pub const ALL: [Zone; 2] = [Zone::Dma32, Zone::Normal];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryStats {
+    pub total_pages: usize,
+    pub free_pages: usize,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -78,19 +129,158 @@ impl PhysicalPage {
     }
 }
 
+#[track_caller]
 pub fn alloc_page() -> PhysicalPage {
-    unsafe { PHYSICAL_MEMORY_ALLOCATOR.lock().alloc(PageSize::Kilopage).expect("out of memory") }
+    try_alloc_page().expect("out of memory")
+}
+
+/// Allocate a page, returning `None` instead of panicking if there aren't any
+/// left anywhere
+pub fn try_alloc_page() -> Option<PhysicalPage> {
+    let page = unsafe { PHYSICAL_MEMORY_ALLOCATOR.lock().alloc(PageSize::Kilopage) };
+
+    // Pages lent to the host are the last resort before running out of memory
+    page.or_else(zeroed::take).or_else(super::balloon::reclaim)
+}
+
+/// Allocate 2^`order` physically contiguous pages, for drivers and DMA buffers
+/// which need more than a single page. With the buddy allocator, the pages are
+/// also aligned to their combined size.
+pub fn alloc_contiguous(order: usize) -> Option<PhysicalPage> {
+    unsafe { PHYSICAL_MEMORY_ALLOCATOR.lock().alloc_contiguous(PageSize::Kilopage, 1 << order) }
+}
+
+/// Free pages allocated with [`alloc_contiguous`]
+///
+/// # Safety
+///
+/// See [`PhysicalMemoryAllocator::dealloc_contiguous`]
+pub unsafe fn dealloc_contiguous(page: PhysicalPage, order: usize) {
+    PHYSICAL_MEMORY_ALLOCATOR.lock().dealloc_contiguous(page, PageSize::Kilopage, 1 << order)
+}
+
+/// Allocate a zeroed page from the current hart's NUMA node if it has any free
+/// memory, for memory that's mostly used by this hart
+pub fn zalloc_local_page() -> PhysicalPage {
+    match unsafe { PHYSICAL_MEMORY_ALLOCATOR.lock().alloc_on(numa::local_node(), PageSize::Kilopage) } {
+        Some(page) => {
+            zeroed::zero_page(page);
+            page
+        }
+        None => zalloc_page(),
+    }
 }
 
+/// Allocate a zeroed page, taking one which was zeroed ahead of time if there
+/// are any
+#[track_caller]
 pub fn zalloc_page() -> PhysicalPage {
-    let page = alloc_page();
-    let ptr = crate::mem::phys2virt(page.as_phys_address()).as_mut_ptr().cast::<u64>();
+    try_zalloc_page().expect("out of memory")
+}
+
+/// Same as [`zalloc_page`], returning `None` if there aren't any pages left
+pub fn try_zalloc_page() -> Option<PhysicalPage> {
+    if let Some(page) = zeroed::take() {
+        return Some(page);
+    }
+
+    let page = try_alloc_page()?;
+    zeroed::zero_page(page);
 
-    unsafe {
-        for i in 0..(4096 / 8) {
-            *ptr.add(i) = 0;
+    Some(page)
+}
+
+/// Take another reference to the allocated `page`, so it isn't freed until
+/// every reference to it has been released with [`release`]
+pub fn share(page: PhysicalPage) {
+    *SHARED_PAGES.lock().entry(page.as_phys_address().as_usize()).or_insert(0) += 1;
+}
+
+/// Whether there's more than one reference to `page`
+pub fn is_shared(page: PhysicalPage) -> bool {
+    SHARED_PAGES.lock().contains_key(&page.as_phys_address().as_usize())
+}
+
+/// Drop a reference to `page`, freeing it if it was the last one
+///
+/// # Safety
+///
+/// See [`PhysicalMemoryAllocator::dealloc`], which only applies to the last
+/// reference
+#[track_caller]
+pub unsafe fn release(page: PhysicalPage, size: PageSize) {
+    let key = page.as_phys_address().as_usize();
+    {
+        let mut shared = SHARED_PAGES.lock();
+        match shared.get_mut(&key) {
+            Some(1) => {
+                shared.remove(&key);
+                return;
+            }
+            Some(extra) => {
+                *extra -= 1;
+                return;
+            }
+            None => {}
         }
     }
 
-    page
+    PHYSICAL_MEMORY_ALLOCATOR.lock().dealloc(page, size)
+}
+
+/// Keep `page` allocated and where it is in physical memory until it's
+/// unpinned with [`unpin`], so it can be handed to a device. Pinned pages
+/// aren't moved by compaction or megapage promotion, swapped out, or shared
+/// copy-on-write, and pinning takes a reference to the page so it outlives the
+/// regions it's mapped by.
+pub fn pin(page: PhysicalPage) {
+    share(page);
+    *PINNED_PAGES.lock().entry(page.as_phys_address().as_usize()).or_insert(0) += 1;
+}
+
+/// Whether `page` has been pinned with [`pin`]
+pub fn is_pinned(page: PhysicalPage) -> bool {
+    PINNED_PAGES.lock().contains_key(&page.as_phys_address().as_usize())
+}
+
+/// Undo a [`pin`], releasing the reference it took to `page`
+#[track_caller]
+pub fn unpin(page: PhysicalPage, size: PageSize) {
+    let key = page.as_phys_address().as_usize();
+    {
+        let mut pinned = PINNED_PAGES.lock();
+        match pinned.get_mut(&key) {
+            Some(1) => {
+                pinned.remove(&key);
+            }
+            Some(pins) => *pins -= 1,
+            None => panic!("unpinning a page which isn't pinned: {:#p}", page.as_phys_address()),
+        }
+    }
+
+    // SAFETY: regions only free their reference to the page, so if this was
+    // the last one nothing has it mapped anymore
+    unsafe { release(page, size) }
+}
+
+/// Same as [`release`], for `n` contiguous pages
+///
+/// # Safety
+///
+/// See [`PhysicalMemoryAllocator::dealloc_contiguous`], which only applies to
+/// the last reference to each page
+#[track_caller]
+pub unsafe fn release_contiguous(page: PhysicalPage, size: PageSize, n: usize) {
+    let start = page.as_phys_address().as_usize();
+    let end = start + n * size.to_byte_size();
+
+    // Shared pages are rare enough that it's fine to go a page at a time when
+    // there are any
+    if SHARED_PAGES.lock().range(start..end).next().is_none() {
+        return PHYSICAL_MEMORY_ALLOCATOR.lock().dealloc_contiguous(page, size, n);
+    }
+
+    for at in (start..end).step_by(size.to_byte_size()) {
+        release(PhysicalPage::from_ptr(at as *mut u8), size);
+    }
 }
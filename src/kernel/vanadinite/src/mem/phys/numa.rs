@@ -0,0 +1,243 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Physical memory spread across NUMA nodes
+//!
+//! Every region of memory in the device tree gets its own allocator, tagged
+//! with the NUMA node the region belongs to. Allocations which don't care where
+//! their memory comes from are made from whichever region has room, while
+//! memory used heavily by a single hart, like its kernel stack and page tables,
+//! comes from that hart's own node when it can.
+
+use super::{MemoryStats, PhysicalMemoryAllocator, PhysicalPage, Zone};
+use crate::{mem::paging::PageSize, HART_ID};
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+use fdt::Fdt;
+use sync::SpinRwLock;
+
+pub const MAX_REGIONS: usize = 16;
+
+/// The bitmap allocator is only used when it's asked for, otherwise each region
+/// is managed by a buddy allocator
+#[cfg(feature = "pmalloc.allocator.bitmap")]
+type RegionAllocator = super::bitmap::BitmapAllocator;
+#[cfg(not(feature = "pmalloc.allocator.bitmap"))]
+type RegionAllocator = super::buddy::BuddyAllocator;
+
+static HART_NODES: SpinRwLock<BTreeMap<usize, usize>> = SpinRwLock::new(BTreeMap::new());
+/// `HART_ID` can't be read until thread locals are set up, which needs page
+/// tables and so physical memory, so don't look up the current hart until then
+static HART_NODES_KNOWN: AtomicBool = AtomicBool::new(false);
+
+const NO_REGION: Option<NodeRegion> = None;
+
+pub struct NumaAllocator {
+    regions: [Option<NodeRegion>; MAX_REGIONS],
+}
+
+struct NodeRegion {
+    node: usize,
+    allocator: RegionAllocator,
+}
+
+impl NumaAllocator {
+    pub const fn new() -> Self {
+        Self { regions: [NO_REGION; MAX_REGIONS] }
+    }
+
+    /// Add the memory from `start` to `end` to NUMA node `node`, returning
+    /// `false` if there's no room left for more regions
+    ///
+    /// # Safety
+    ///
+    /// The same as [`PhysicalMemoryAllocator::init`], and the region must not
+    /// overlap any other region
+    pub unsafe fn add_region(&mut self, node: usize, start: *mut u8, end: *mut u8) -> bool {
+        match self.regions.iter_mut().find(|region| region.is_none()) {
+            Some(slot) => {
+                let mut allocator = RegionAllocator::new();
+                allocator.init(start, end);
+                *slot = Some(NodeRegion { node, allocator });
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Allocate a page from `node` only, returning `None` if it's out of memory
+    ///
+    /// # Safety
+    ///
+    /// See [`PhysicalMemoryAllocator::alloc`]
+    pub unsafe fn alloc_on(&mut self, node: usize, align_to: PageSize) -> Option<PhysicalPage> {
+        Zone::FALLBACK_ORDER.into_iter().find_map(|zone| {
+            self.regions_on(node).find_map(|region| unsafe { region.allocator.alloc_in(zone, align_to) })
+        })
+    }
+
+    /// Allocate a contiguous range from `node` only, returning `None` if it
+    /// doesn't have enough contiguous free memory
+    ///
+    /// # Safety
+    ///
+    /// See [`PhysicalMemoryAllocator::alloc_contiguous`]
+    pub unsafe fn alloc_contiguous_on(&mut self, node: usize, align_to: PageSize, n: usize) -> Option<PhysicalPage> {
+        Zone::FALLBACK_ORDER.into_iter().find_map(|zone| {
+            self.regions_on(node).find_map(|region| unsafe { region.allocator.alloc_contiguous_in(zone, align_to, n) })
+        })
+    }
+
+    /// See [`BuddyAllocator::block_usage`](super::buddy::BuddyAllocator::block_usage)
+    pub fn block_usage(&mut self, page: PhysicalPage) -> Option<usize> {
+        self.owner(page).block_usage(page)
+    }
+
+    /// Allocate a page to move `page` into from the same region of memory,
+    /// see [`BuddyAllocator::alloc_denser`](super::buddy::BuddyAllocator::alloc_denser)
+    ///
+    /// # Safety
+    ///
+    /// See [`PhysicalMemoryAllocator::alloc`]
+    pub unsafe fn alloc_denser(&mut self, page: PhysicalPage) -> Option<PhysicalPage> {
+        self.owner(page).alloc_denser(page)
+    }
+
+    /// The nodes which have memory, in ascending order
+    pub fn nodes(&self) -> Vec<usize> {
+        let mut nodes = self.regions.iter().flatten().map(|region| region.node).collect::<Vec<_>>();
+        nodes.sort_unstable();
+        nodes.dedup();
+
+        nodes
+    }
+
+    pub fn node_stats(&mut self, node: usize) -> MemoryStats {
+        let mut stats = MemoryStats { total_pages: 0, free_pages: 0 };
+        for region in self.regions_on(node) {
+            for zone in Zone::ALL {
+                let zone_stats = region.allocator.zone_stats(zone);
+                stats.total_pages += zone_stats.total_pages;
+                stats.free_pages += zone_stats.free_pages;
+            }
+        }
+
+        stats
+    }
+
+    fn regions(&mut self) -> impl Iterator<Item = &mut NodeRegion> {
+        self.regions.iter_mut().flatten()
+    }
+
+    fn regions_on(&mut self, node: usize) -> impl Iterator<Item = &mut NodeRegion> {
+        self.regions().filter(move |region| region.node == node)
+    }
+
+    #[track_caller]
+    fn owner(&mut self, page: PhysicalPage) -> &mut RegionAllocator {
+        match self.regions().find(|region| region.allocator.contains(page)) {
+            Some(region) => &mut region.allocator,
+            None => panic!(
+                "[pmalloc.allocator] NumaAllocator: address {:#p} isn't in any memory region",
+                page.as_phys_address().as_ptr()
+            ),
+        }
+    }
+}
+
+unsafe impl PhysicalMemoryAllocator for NumaAllocator {
+    unsafe fn init(&mut self, start: *mut u8, end: *mut u8) {
+        assert!(self.add_region(0, start, end), "no room for another memory region");
+    }
+
+    unsafe fn alloc(&mut self, align_to: PageSize) -> Option<PhysicalPage> {
+        Zone::FALLBACK_ORDER.into_iter().find_map(|zone| self.alloc_in(zone, align_to))
+    }
+
+    unsafe fn alloc_in(&mut self, zone: Zone, align_to: PageSize) -> Option<PhysicalPage> {
+        self.regions().find_map(|region| unsafe { region.allocator.alloc_in(zone, align_to) })
+    }
+
+    unsafe fn alloc_contiguous(&mut self, align_to: PageSize, n: usize) -> Option<PhysicalPage> {
+        Zone::FALLBACK_ORDER.into_iter().find_map(|zone| self.alloc_contiguous_in(zone, align_to, n))
+    }
+
+    unsafe fn alloc_contiguous_in(&mut self, zone: Zone, align_to: PageSize, n: usize) -> Option<PhysicalPage> {
+        self.regions().find_map(|region| unsafe { region.allocator.alloc_contiguous_in(zone, align_to, n) })
+    }
+
+    #[track_caller]
+    unsafe fn dealloc(&mut self, page: PhysicalPage, size: PageSize) {
+        self.owner(page).dealloc(page, size)
+    }
+
+    #[track_caller]
+    unsafe fn dealloc_contiguous(&mut self, page: PhysicalPage, size: PageSize, n: usize) {
+        self.owner(page).dealloc_contiguous(page, size, n)
+    }
+
+    #[track_caller]
+    unsafe fn set_used(&mut self, page: PhysicalPage) {
+        self.owner(page).set_used(page)
+    }
+
+    #[track_caller]
+    unsafe fn set_unused(&mut self, page: PhysicalPage) {
+        self.owner(page).set_unused(page)
+    }
+
+    fn free_pages(&mut self) -> usize {
+        self.regions().map(|region| region.allocator.free_pages()).sum()
+    }
+
+    fn zone_stats(&mut self, zone: Zone) -> MemoryStats {
+        let mut stats = MemoryStats { total_pages: 0, free_pages: 0 };
+        for region in self.regions() {
+            let region_stats = region.allocator.zone_stats(zone);
+            stats.total_pages += region_stats.total_pages;
+            stats.free_pages += region_stats.free_pages;
+        }
+
+        stats
+    }
+}
+
+/// Record which NUMA node each hart belongs to from their `numa-node-id`
+/// properties. Harts without one are treated as being part of node 0.
+pub fn init_hart_nodes(fdt: &Fdt<'_>) {
+    let cpus = fdt.all_nodes().filter(|node| node.property("device_type").and_then(|p| p.as_str()) == Some("cpu"));
+
+    let mut hart_nodes = HART_NODES.write();
+    for cpu in cpus {
+        let hart = cpu.property("reg").and_then(|p| p.as_usize());
+        let node = cpu.property("numa-node-id").and_then(|p| p.as_usize());
+
+        if let (Some(hart), Some(node)) = (hart, node) {
+            hart_nodes.insert(hart, node);
+        }
+    }
+
+    HART_NODES_KNOWN.store(true, Ordering::Release);
+}
+
+pub fn hart_node(hart: usize) -> usize {
+    HART_NODES.read().get(&hart).copied().unwrap_or(0)
+}
+
+/// The NUMA node of the current hart
+pub fn local_node() -> usize {
+    match HART_NODES_KNOWN.load(Ordering::Acquire) {
+        true => hart_node(HART_ID.get()),
+        false => 0,
+    }
+}
+
+/// The NUMA node a memory node in the device tree belongs to
+pub fn memory_node_id(node: &fdt::node::FdtNode<'_, '_>) -> usize {
+    node.property("numa-node-id").and_then(|p| p.as_usize()).unwrap_or(0)
+}
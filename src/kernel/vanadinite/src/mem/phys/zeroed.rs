@@ -0,0 +1,107 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A pool of pages which have already been zeroed
+//!
+//! The pool is topped up by an idle job, so that zeroed pages for page tables
+//! and userspace memory can usually be handed out without zeroing them on the
+//! spot. When memory runs out, the pool is drained before anything else.
+//!
+//! There's also a single page of zeroes which is never written to, which
+//! lazily allocated memory maps read-only until it's first written to.
+
+use super::{PhysicalMemoryAllocator, PhysicalPage, PHYSICAL_MEMORY_ALLOCATOR};
+use crate::mem::{cache, paging::PageSize, phys2virt};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use sync::SpinMutex;
+
+/// Number of pages the pool is topped up to (1 MiB)
+const POOL_TARGET: usize = 256;
+/// The pool isn't topped up while fewer than this many pages are free, so that
+/// it doesn't compete with actual allocations for the last of the memory
+const MIN_FREE_PAGES: usize = 2048;
+/// Pages zeroed each time the idle job runs
+const REFILL_BATCH: usize = 8;
+
+static POOL: SpinMutex<Vec<PhysicalPage>> = SpinMutex::new(Vec::new());
+/// The physical address of the shared zero page, or 0 if it hasn't been needed
+/// yet
+static SHARED_ZERO_PAGE: AtomicUsize = AtomicUsize::new(0);
+
+/// Take an already zeroed page out of the pool, if there are any
+pub fn take() -> Option<PhysicalPage> {
+    POOL.lock().pop()
+}
+
+/// The number of zeroed pages waiting in the pool
+pub fn pooled_pages() -> usize {
+    POOL.lock().len()
+}
+
+/// The page of zeroes shared by every untouched page of lazily allocated
+/// memory which has been read from. It's never freed, and must never be mapped
+/// writable.
+pub fn shared_zero_page() -> PhysicalPage {
+    let page = SHARED_ZERO_PAGE.load(Ordering::Acquire);
+    if page != 0 {
+        return PhysicalPage::from_ptr(page as *mut u8);
+    }
+
+    let new = super::zalloc_page();
+    match SHARED_ZERO_PAGE.compare_exchange(0, new.as_phys_address().as_usize(), Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => new,
+        // Another hart needed it at the same time
+        Err(page) => {
+            unsafe { PHYSICAL_MEMORY_ALLOCATOR.lock().dealloc(new, PageSize::Kilopage) };
+            PhysicalPage::from_ptr(page as *mut u8)
+        }
+    }
+}
+
+pub fn zero_page(page: PhysicalPage) {
+    let ptr = phys2virt(page.as_phys_address()).as_mut_ptr();
+    unsafe { cache::zero(ptr, PageSize::Kilopage.to_byte_size()) };
+}
+
+/// Zero a few more pages for the pool if it isn't full. This is run as an idle
+/// job, and returns whether the pool still needs more pages.
+pub fn refill() -> bool {
+    let wanted = POOL_TARGET.saturating_sub(pooled_pages()).min(REFILL_BATCH);
+    if wanted == 0 {
+        return false;
+    }
+
+    for _ in 0..wanted {
+        let page = {
+            let mut allocator = PHYSICAL_MEMORY_ALLOCATOR.lock();
+            if allocator.free_pages() < MIN_FREE_PAGES {
+                return false;
+            }
+
+            match unsafe { allocator.alloc(PageSize::Kilopage) } {
+                Some(page) => page,
+                None => return false,
+            }
+        };
+
+        zero_page(page);
+
+        let mut pool = POOL.lock();
+        match pool.len() < POOL_TARGET {
+            true => pool.push(page),
+            // Another hart topped up the pool in the meantime
+            false => {
+                drop(pool);
+                unsafe { PHYSICAL_MEMORY_ALLOCATOR.lock().dealloc(page, PageSize::Kilopage) };
+                return false;
+            }
+        }
+    }
+
+    pooled_pages() < POOL_TARGET
+}
@@ -0,0 +1,114 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Telling userspace when memory is running low
+//!
+//! Tasks which subscribed with the memory pressure capability are sent the new
+//! level on their kernel channel whenever free memory crosses one of the
+//! thresholds below. Free memory is checked while harts are idle, and the
+//! level goes straight to critical whenever an allocation made for userspace
+//! fails, so subscribers get a chance to free what they can before the next
+//! one does too. A level is only left once free memory is a little past its
+//! threshold again, so memory hovering around one doesn't flood subscribers
+//! with notifications.
+
+use super::phys::{PhysicalMemoryAllocator, PHYSICAL_MEMORY_ALLOCATOR};
+use crate::{scheduler::TASKS, syscall::channel::UserspaceChannel, task::Task};
+use alloc::vec::Vec;
+use librust::{
+    syscalls::{channel::KernelMessage, pressure::MemoryPressure},
+    task::Tid,
+};
+use sync::SpinMutex;
+
+/// Memory is low once fewer than this many pages are free (16 MiB), which is
+/// before swap starts evicting anything
+const LOW_FREE_PAGES: usize = 4096;
+/// Memory is critical once fewer than this many pages are free (4 MiB)
+const CRITICAL_FREE_PAGES: usize = 1024;
+/// How far past a threshold free memory has to get to leave its level (1 MiB)
+const HYSTERESIS_PAGES: usize = 256;
+
+static PRESSURE: SpinMutex<Pressure> = SpinMutex::new(Pressure::new());
+
+struct Pressure {
+    level: MemoryPressure,
+    /// Subscribers and their kernel channels, which they're notified on
+    subscribers: Vec<(Tid, UserspaceChannel)>,
+}
+
+impl Pressure {
+    const fn new() -> Self {
+        Self { level: MemoryPressure::Normal, subscribers: Vec::new() }
+    }
+
+    fn set(&mut self, level: MemoryPressure) {
+        if level == self.level {
+            return;
+        }
+
+        match level {
+            MemoryPressure::Normal => log::info!("Memory pressure back to normal"),
+            level => log::warn!("Memory pressure is {:?}", level),
+        }
+
+        self.level = level;
+
+        // Tasks which have exited since subscribing can't do anything about it
+        self.subscribers.retain(|(tid, _)| TASKS.get(*tid).is_some());
+        for (_, channel) in &self.subscribers {
+            channel.send_kernel_message(KernelMessage::MemoryPressure(level));
+        }
+    }
+}
+
+/// Notify `task` whenever the memory pressure level changes, returning what it
+/// is now
+pub fn subscribe(task: &Task) -> MemoryPressure {
+    let mut pressure = PRESSURE.lock();
+    if !pressure.subscribers.iter().any(|(tid, _)| *tid == task.tid) {
+        pressure.subscribers.push((task.tid, task.kernel_channel.clone()));
+    }
+
+    pressure.level
+}
+
+/// The level for `free_pages` free pages, when the level was `current`
+fn level_for(free_pages: usize, current: MemoryPressure) -> MemoryPressure {
+    let threshold = |pages: usize, level: MemoryPressure| match current >= level {
+        true => pages + HYSTERESIS_PAGES,
+        false => pages,
+    };
+
+    if free_pages < threshold(CRITICAL_FREE_PAGES, MemoryPressure::Critical) {
+        MemoryPressure::Critical
+    } else if free_pages < threshold(LOW_FREE_PAGES, MemoryPressure::Low) {
+        MemoryPressure::Low
+    } else {
+        MemoryPressure::Normal
+    }
+}
+
+/// Update the memory pressure level from how much memory is free, notifying
+/// subscribers if it changed. This is run as an idle job, and never has more
+/// to do.
+pub fn check_in_background() -> bool {
+    let free_pages = PHYSICAL_MEMORY_ALLOCATOR.lock().free_pages();
+
+    let mut pressure = PRESSURE.lock();
+    let level = level_for(free_pages, pressure.level);
+    pressure.set(level);
+
+    false
+}
+
+/// Report that an allocation made for userspace failed, which makes the
+/// memory pressure critical whatever the number of free pages says, since
+/// they may be too fragmented to use
+pub fn out_of_memory() {
+    PRESSURE.lock().set(MemoryPressure::Critical);
+}
@@ -6,9 +6,19 @@
 // obtain one at https://mozilla.org/MPL/2.0/.
 
 use super::{paging::PageSize, PhysicalAddress};
-use crate::mem::{
-    phys::{PhysicalMemoryAllocator, PhysicalPage, PHYSICAL_MEMORY_ALLOCATOR},
-    phys2virt,
+use crate::{
+    mem::{
+        balloon, cache,
+        paging::VirtualAddress,
+        phys::{
+            self, alloc_page, try_zalloc_page, zalloc_page, PhysicalMemoryAllocator, PhysicalPage, Zone,
+            PHYSICAL_MEMORY_ALLOCATOR,
+        },
+        phys2virt,
+        rmap::{self, AddressSpaceId, Mapping, Rmap},
+        swap::{self, SwapSlot},
+    },
+    task::Task,
 };
 use alloc::{sync::Arc, vec::Vec};
 
@@ -47,21 +57,23 @@ impl PhysicalRegion {
     /// Returns the number of pages contained within the region
     pub fn page_count(&self) -> usize {
         match self {
-            PhysicalRegion::Shared(shared) => shared.n_pages,
+            PhysicalRegion::Shared(shared) => shared.n_pages(),
             PhysicalRegion::Unique(unique) => unique.n_pages,
         }
     }
 
     pub fn physical_addresses(&self) -> impl Iterator<Item = PhysicalAddress> + '_ {
-        match self {
-            PhysicalRegion::Shared(shared) => shared.physical_addresses(),
-            PhysicalRegion::Unique(unique) => unique.physical_addresses(),
-        }
+        let (region, first, n_pages) = match self {
+            PhysicalRegion::Shared(shared) => (&*shared.region, shared.first, shared.n_pages),
+            PhysicalRegion::Unique(unique) => (unique, 0, unique.n_pages),
+        };
+
+        region.physical_addresses().skip(first).take(n_pages)
     }
 
     pub fn page_size(&self) -> PageSize {
         match self {
-            PhysicalRegion::Shared(shared) => shared.page_size,
+            PhysicalRegion::Shared(shared) => shared.page_size(),
             PhysicalRegion::Unique(unique) => unique.page_size,
         }
     }
@@ -74,52 +86,322 @@ enum PhysicalRegionKind {
     Sparse(Vec<PhysicalPage>),
 }
 
+/// The number of kilopages which make up a megapage
+pub const KILOPAGES_PER_MEGAPAGE: usize = 512;
+
 #[derive(Debug, PartialEq)]
 pub struct UniquePhysicalRegion {
     kind: PhysicalRegionKind,
     page_size: PageSize,
     n_pages: usize,
+    /// The first page of each run of pages in a sparse region which has been
+    /// replaced by a single megapage
+    megapages: Vec<usize>,
+    /// The pages of a sparse region which have been swapped out, and where to.
+    /// Their place in the region is taken by a null page until they're back.
+    swapped: Vec<(usize, SwapSlot)>,
+    /// The pages of a sparse region which have been shared copy-on-write with
+    /// another region, and are mapped read-only until they're written to
+    cow: Vec<usize>,
 }
 
+// Pages of a lazily allocated region which haven't been touched yet are also
+// null pages, just without a swap slot.
+
 impl UniquePhysicalRegion {
     /// This function allows aliasing physical memory at arbitrary addresses,
     /// bypassing the physical frame allocator.
     #[track_caller]
     pub fn mmio(at: PhysicalAddress, page_size: PageSize, n_pages: usize) -> Self {
-        Self { kind: PhysicalRegionKind::Mmio(PhysicalPage::from_ptr(at.as_mut_ptr())), page_size, n_pages }
+        Self {
+            kind: PhysicalRegionKind::Mmio(PhysicalPage::from_ptr(at.as_mut_ptr())),
+            page_size,
+            n_pages,
+            megapages: Vec::new(),
+            swapped: Vec::new(),
+            cow: Vec::new(),
+        }
     }
 
     #[track_caller]
     pub fn alloc_contiguous(page_size: PageSize, n_pages: usize) -> Self {
-        // log::trace!("Allocating page for contiguous region");
-        let mut lock = PHYSICAL_MEMORY_ALLOCATOR.lock();
+        Self::try_alloc_contiguous(page_size, n_pages).expect("couldn't alloc contiguous region")
+    }
 
-        let kind = PhysicalRegionKind::Contiguous(unsafe {
-            lock.alloc_contiguous(page_size, n_pages).expect("couldn't alloc contiguous region")
-        });
+    /// Same as [`Self::alloc_contiguous`], returning `None` if there isn't
+    /// enough contiguous free memory left
+    pub fn try_alloc_contiguous(page_size: PageSize, n_pages: usize) -> Option<Self> {
+        // log::trace!("Allocating page for contiguous region");
+        let start = unsafe { PHYSICAL_MEMORY_ALLOCATOR.lock().alloc_contiguous(page_size, n_pages)? };
+
+        Some(Self {
+            kind: PhysicalRegionKind::Contiguous(start),
+            page_size,
+            n_pages,
+            megapages: Vec::new(),
+            swapped: Vec::new(),
+            cow: Vec::new(),
+        })
+    }
 
-        Self { kind, page_size, n_pages }
+    /// Allocate a contiguous region from within `zone`, returning `None` if
+    /// there isn't enough contiguous free memory left in it
+    pub fn alloc_contiguous_in(zone: Zone, page_size: PageSize, n_pages: usize) -> Option<Self> {
+        let start = unsafe { PHYSICAL_MEMORY_ALLOCATOR.lock().alloc_contiguous_in(zone, page_size, n_pages)? };
+
+        Some(Self {
+            kind: PhysicalRegionKind::Contiguous(start),
+            page_size,
+            n_pages,
+            megapages: Vec::new(),
+            swapped: Vec::new(),
+            cow: Vec::new(),
+        })
     }
 
     #[track_caller]
     pub fn alloc_sparse(page_size: PageSize, n_pages: usize) -> Self {
+        Self::try_alloc_sparse(page_size, n_pages).expect("couldn't alloc sparse region")
+    }
+
+    /// Same as [`Self::alloc_sparse`], returning `None` if there aren't enough
+    /// free pages left. Any pages which were allocated before running out are
+    /// freed again.
+    pub fn try_alloc_sparse(page_size: PageSize, n_pages: usize) -> Option<Self> {
         if n_pages == 1 {
-            return Self::alloc_contiguous(page_size, 1);
+            return Self::try_alloc_contiguous(page_size, 1);
         }
 
-        let kind = PhysicalRegionKind::Sparse(unsafe {
+        let mut pages = Vec::with_capacity(n_pages);
+        {
             let mut allocator = PHYSICAL_MEMORY_ALLOCATOR.lock();
-            let mut pages = Vec::with_capacity(n_pages);
 
             for _ in 0..n_pages {
                 // log::trace!("Allocating page for sparse region");
-                pages.push(allocator.alloc(page_size).expect("couldn't alloc sparse region"));
+                let page = match (unsafe { allocator.alloc(page_size) }, page_size) {
+                    (None, PageSize::Kilopage) => balloon::reclaim(),
+                    (page, _) => page,
+                };
+
+                match page {
+                    Some(page) => pages.push(page),
+                    None => break,
+                }
             }
+        }
 
-            pages
-        });
+        Self::sparse(page_size, n_pages, pages)
+    }
+
+    /// A sparse region of `pages`, if all `n_pages` of them could be
+    /// allocated. Otherwise they're freed along with the region, which can't
+    /// happen while the physical memory allocator is locked.
+    fn sparse(page_size: PageSize, n_pages: usize, pages: Vec<PhysicalPage>) -> Option<Self> {
+        let complete = pages.len() == n_pages;
+        let region = Self {
+            n_pages: pages.len(),
+            kind: PhysicalRegionKind::Sparse(pages),
+            page_size,
+            megapages: Vec::new(),
+            swapped: Vec::new(),
+            cow: Vec::new(),
+        };
+
+        match complete {
+            true => Some(region),
+            false => None,
+        }
+    }
+
+    /// Make a sparse region without allocating any of its pages, which are
+    /// allocated and zeroed with [`Self::populate`] as they're first touched.
+    /// Until then, the shared zero page can be mapped read-only in their
+    /// place, which doesn't change anything about the region.
+    pub fn lazy(page_size: PageSize, n_pages: usize) -> Self {
+        let pages = alloc::vec![PhysicalPage::from_ptr(core::ptr::null_mut()); n_pages];
+        Self {
+            kind: PhysicalRegionKind::Sparse(pages),
+            page_size,
+            n_pages,
+            megapages: Vec::new(),
+            swapped: Vec::new(),
+            cow: Vec::new(),
+        }
+    }
+
+    /// Whether the page at `index` of a lazily allocated region hasn't been
+    /// touched yet
+    pub fn is_unpopulated(&self, index: usize) -> bool {
+        match &self.kind {
+            PhysicalRegionKind::Sparse(pages) => {
+                matches!(pages.get(index), Some(page) if page.as_phys_address().is_null())
+                    && self.swapped_slot(index).is_none()
+            }
+            _ => false,
+        }
+    }
+
+    /// Allocate a zeroed page in place of the untouched page at `index`,
+    /// returning it so it can be mapped. Returns `None` if the page has
+    /// already been allocated.
+    pub fn populate(&mut self, index: usize) -> Option<PhysicalPage> {
+        if !self.is_unpopulated(index) {
+            return None;
+        }
+
+        let page = match self.page_size {
+            PageSize::Kilopage => zalloc_page(),
+            size => {
+                let page = unsafe { PHYSICAL_MEMORY_ALLOCATOR.lock().alloc(size) }.expect("out of memory");
+                unsafe { cache::zero(phys2virt(page.as_phys_address()).as_mut_ptr(), size.to_byte_size()) };
+                page
+            }
+        };
+
+        match &mut self.kind {
+            PhysicalRegionKind::Sparse(pages) => pages[index] = page,
+            _ => unreachable!(),
+        }
+
+        Some(page)
+    }
+
+    /// Share the `n_pages` pages starting at page `index` with a new private
+    /// region, without copying them. The pages are copied the first time
+    /// either region writes to them, see [`Self::unshare`], so both have to
+    /// map them read-only until then. Untouched pages aren't shared, since
+    /// each region can allocate its own. Returns `None` if the pages are
+    /// device memory, or any of them are swapped out, pinned or part of a
+    /// promoted megapage.
+    pub fn copy_on_write(&mut self, index: usize, n_pages: usize) -> Option<Self> {
+        let range = index..index.checked_add(n_pages).filter(|&end| end <= self.n_pages)?;
+        let promoted =
+            self.megapages.iter().any(|&start| start < range.end && range.start < start + KILOPAGES_PER_MEGAPAGE);
+        if promoted || self.swapped.iter().any(|(i, _)| range.contains(i)) || self.has_pinned(index, n_pages) {
+            return None;
+        }
+
+        // Only sparse regions can have single pages replaced when they're
+        // copied
+        if let PhysicalRegionKind::Contiguous(_) = self.kind {
+            let pages = self.physical_addresses().map(|addr| PhysicalPage::from_ptr(addr.as_mut_ptr())).collect();
+            self.kind = PhysicalRegionKind::Sparse(pages);
+        }
+
+        let pages = match &self.kind {
+            PhysicalRegionKind::Sparse(pages) => pages[range.clone()].to_vec(),
+            _ => return None,
+        };
+
+        let mut cow = Vec::new();
+        for (i, page) in pages.iter().enumerate().filter(|(_, page)| !page.as_phys_address().is_null()) {
+            phys::share(*page);
+            cow.push(i);
+
+            if !self.cow.contains(&(index + i)) {
+                self.cow.push(index + i);
+            }
+        }
+
+        Some(Self {
+            kind: PhysicalRegionKind::Sparse(pages),
+            page_size: self.page_size,
+            n_pages,
+            megapages: Vec::new(),
+            swapped: Vec::new(),
+            cow,
+        })
+    }
+
+    /// Copy every page of the region into newly allocated memory, for when it
+    /// can't be shared copy-on-write because some of it has been promoted to
+    /// megapages. Untouched pages stay untouched in the copy. Returns `None` if
+    /// any of its pages are swapped out, or there isn't enough free memory.
+    pub fn try_duplicate(&self) -> Option<Self> {
+        if !self.swapped.is_empty() {
+            return None;
+        }
+
+        let mut pages = Vec::with_capacity(self.n_pages);
+        for from in self.physical_addresses() {
+            if from.is_null() {
+                pages.push(PhysicalPage::from_ptr(core::ptr::null_mut()));
+                continue;
+            }
+
+            let page = match unsafe { PHYSICAL_MEMORY_ALLOCATOR.lock().alloc(self.page_size) } {
+                Some(page) => page,
+                None => break,
+            };
+
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    phys2virt(from).as_ptr(),
+                    phys2virt(page.as_phys_address()).as_mut_ptr(),
+                    self.page_size.to_byte_size(),
+                )
+            };
+
+            pages.push(page);
+        }
+
+        Self::sparse(self.page_size, self.n_pages, pages)
+    }
+
+    /// Whether the page at `index` was shared copy-on-write, and hasn't been
+    /// written to since
+    pub fn is_cow(&self, index: usize) -> bool {
+        self.cow.contains(&index)
+    }
+
+    /// Whether any of the `n_pages` pages starting at `index` are pinned, see
+    /// [`phys::pin`]
+    pub fn has_pinned(&self, index: usize, n_pages: usize) -> bool {
+        self.physical_addresses()
+            .skip(index)
+            .take(n_pages)
+            .filter(|addr| !addr.is_null())
+            .any(|addr| phys::is_pinned(PhysicalPage::from_ptr(addr.as_mut_ptr())))
+    }
+
+    /// Every page which [`Self::is_cow`]
+    pub fn cow_pages(&self) -> &[usize] {
+        &self.cow
+    }
 
-        Self { kind, page_size, n_pages }
+    /// Give this region its own copy of the page at `index` if it was shared
+    /// copy-on-write, returning the page it should be mapped writable to from
+    /// now on along with the page it replaced, or `None` if it wasn't shared.
+    /// The page is only copied if another region still has it, otherwise it's
+    /// kept as it is. Like with [`Self::migrate`], the replaced page is still
+    /// mapped, so it's up to the caller to release it once it isn't.
+    pub fn unshare(&mut self, index: usize) -> Option<(PhysicalPage, Option<PhysicalPage>)> {
+        let cow = self.cow.iter().position(|&i| i == index)?;
+        self.cow.swap_remove(cow);
+
+        let page = match &mut self.kind {
+            PhysicalRegionKind::Sparse(pages) => &mut pages[index],
+            _ => unreachable!(),
+        };
+
+        if !phys::is_shared(*page) {
+            return Some((*page, None));
+        }
+
+        let copy = match self.page_size {
+            PageSize::Kilopage => alloc_page(),
+            size => unsafe { PHYSICAL_MEMORY_ALLOCATOR.lock().alloc(size) }.expect("out of memory"),
+        };
+
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                phys2virt(page.as_phys_address()).as_ptr(),
+                phys2virt(copy.as_phys_address()).as_mut_ptr(),
+                self.page_size.to_byte_size(),
+            )
+        };
+
+        Some((copy, Some(core::mem::replace(page, copy))))
     }
 
     pub fn physical_addresses(&self) -> impl Iterator<Item = PhysicalAddress> + '_ {
@@ -138,15 +420,63 @@ impl UniquePhysicalRegion {
         contig.into_iter().flatten().chain(sparse.into_iter().flatten())
     }
 
+    /// The physical address of the page at `index`, which is null if it's
+    /// swapped out or untouched
+    pub fn physical_address(&self, index: usize) -> Option<PhysicalAddress> {
+        match &self.kind {
+            PhysicalRegionKind::Contiguous(start) | PhysicalRegionKind::Mmio(start) => {
+                (index < self.n_pages).then(|| start.as_phys_address().offset(index * self.page_size.to_byte_size()))
+            }
+            PhysicalRegionKind::Sparse(pages) => pages.get(index).map(|page| page.as_phys_address()),
+        }
+    }
+
+    /// Allocate a region which is entirely zeroed. Sparse regions of
+    /// [`PageSize::Kilopage`]s are made up of pages which were zeroed ahead of
+    /// time where possible.
+    #[track_caller]
+    pub fn alloc_zeroed(page_size: PageSize, n_pages: usize, contiguous: bool) -> Self {
+        Self::try_alloc_zeroed(page_size, n_pages, contiguous).expect("out of memory")
+    }
+
+    /// Same as [`Self::alloc_zeroed`], returning `None` if there isn't enough
+    /// free memory left
+    pub fn try_alloc_zeroed(page_size: PageSize, n_pages: usize, contiguous: bool) -> Option<Self> {
+        if contiguous || n_pages == 1 || page_size != PageSize::Kilopage {
+            let mut region = match contiguous {
+                true => Self::try_alloc_contiguous(page_size, n_pages)?,
+                false => Self::try_alloc_sparse(page_size, n_pages)?,
+            };
+            region.zero();
+
+            return Some(region);
+        }
+
+        let mut pages = Vec::with_capacity(n_pages);
+        for _ in 0..n_pages {
+            match try_zalloc_page() {
+                Some(page) => pages.push(page),
+                None => break,
+            }
+        }
+
+        Self::sparse(page_size, n_pages, pages)
+    }
+
+    /// Copy `data` to the start of the region, zeroing anything after it so
+    /// that none of what was in the memory before is left behind
     pub fn copy_data_into(&mut self, data: &[u8]) {
-        for (phys_addr, data) in self.physical_addresses().zip(data.chunks(self.page_size.to_byte_size())) {
+        let mut chunks = data.chunks(self.page_size.to_byte_size());
+        for phys_addr in self.physical_addresses() {
             let virt_addr = phys2virt(phys_addr).as_mut_ptr();
+            let data = chunks.next().unwrap_or(&[]);
 
             log::trace!("copy_data_into: phys_addr={:#p} virt_addr={:#p} len={}", phys_addr, virt_addr, data.len());
 
             let copy_to = unsafe { core::slice::from_raw_parts_mut(virt_addr, self.page_size.to_byte_size()) };
 
             copy_to[..data.len()].copy_from_slice(data);
+            copy_to[data.len()..].fill(0);
         }
     }
 
@@ -156,14 +486,205 @@ impl UniquePhysicalRegion {
 
             log::trace!("zero: phys_addr={:#p} virt_addr={:#p}", phys_addr, virt_addr);
 
-            let copy_to = unsafe { core::slice::from_raw_parts_mut(virt_addr, self.page_size.to_byte_size()) };
+            unsafe { cache::zero(virt_addr, self.page_size.to_byte_size()) };
+        }
+    }
+
+    /// Whether the run of pages starting at page `index` has been replaced by
+    /// a megapage
+    pub fn is_promoted(&self, index: usize) -> bool {
+        self.megapages.contains(&index)
+    }
+
+    /// Copy the run of kilopages starting at page `index` into a new megapage
+    /// which replaces them, returning the megapage and the pages it replaced.
+    /// The replaced pages are still mapped, so it's up to the caller to free
+    /// them once they aren't. Returns `None` if the region isn't made up of
+    /// sparse kilopages, any of them are shared copy-on-write, untouched or
+    /// pinned, or there are no megapages left.
+    pub fn promote(&mut self, index: usize) -> Option<(PhysicalAddress, Vec<PhysicalPage>)> {
+        let pages = match &mut self.kind {
+            PhysicalRegionKind::Sparse(pages)
+                if self.page_size == PageSize::Kilopage
+                    && index + KILOPAGES_PER_MEGAPAGE <= pages.len()
+                    && !self.megapages.contains(&index)
+                    && !self.cow.iter().any(|i| (index..index + KILOPAGES_PER_MEGAPAGE).contains(i))
+                    && !pages[index..][..KILOPAGES_PER_MEGAPAGE]
+                        .iter()
+                        .any(|&page| page.as_phys_address().is_null() || phys::is_pinned(page)) =>
+            {
+                &mut pages[index..][..KILOPAGES_PER_MEGAPAGE]
+            }
+            _ => return None,
+        };
+
+        let megapage = unsafe { PHYSICAL_MEMORY_ALLOCATOR.lock().alloc(PageSize::Megapage)? }.as_phys_address();
+        let mut replaced = Vec::with_capacity(KILOPAGES_PER_MEGAPAGE);
+
+        for (i, page) in pages.iter_mut().enumerate() {
+            let new = megapage.offset(i * PageSize::Kilopage.to_byte_size());
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    phys2virt(page.as_phys_address()).as_ptr(),
+                    phys2virt(new).as_mut_ptr(),
+                    PageSize::Kilopage.to_byte_size(),
+                )
+            };
+
+            replaced.push(core::mem::replace(page, PhysicalPage::from_ptr(new.as_mut_ptr())));
+        }
+
+        self.megapages.push(index);
+
+        Some((megapage, replaced))
+    }
+
+    /// The page at `index` if it can be moved elsewhere in physical memory,
+    /// which is only the case for kilopages of sparse regions which aren't part
+    /// of a promoted megapage, shared copy-on-write or pinned
+    pub fn movable_page(&self, index: usize) -> Option<PhysicalPage> {
+        match &self.kind {
+            PhysicalRegionKind::Sparse(pages) if self.page_size == PageSize::Kilopage => {
+                let promoted =
+                    self.megapages.iter().any(|&start| (start..start + KILOPAGES_PER_MEGAPAGE).contains(&index));
+                pages.get(index).copied().filter(|&page| {
+                    !promoted
+                        && !self.cow.contains(&index)
+                        && !page.as_phys_address().is_null()
+                        && !phys::is_pinned(page)
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Copy the page at `index` into `to`, which replaces it, returning the
+    /// page it replaced. Like with [`Self::promote`], the replaced page is
+    /// still mapped, so it's up to the caller to free it once it isn't.
+    /// Returns `None` if the page isn't movable.
+    pub fn migrate(&mut self, index: usize, to: PhysicalPage) -> Option<PhysicalPage> {
+        self.movable_page(index)?;
+
+        let page = match &mut self.kind {
+            PhysicalRegionKind::Sparse(pages) => &mut pages[index],
+            _ => unreachable!(),
+        };
 
-            copy_to.fill(0);
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                phys2virt(page.as_phys_address()).as_ptr(),
+                phys2virt(to.as_phys_address()).as_mut_ptr(),
+                PageSize::Kilopage.to_byte_size(),
+            )
+        };
+
+        Some(core::mem::replace(page, to))
+    }
+
+    /// Take the page at `index` out of the region, to be swapped out to
+    /// `slot`. Like with [`Self::migrate`], the page is still mapped, so it's
+    /// up to the caller to unmap it before it's used for anything else.
+    /// Returns `None` if the page isn't movable.
+    pub fn swap_out(&mut self, index: usize, slot: SwapSlot) -> Option<PhysicalPage> {
+        self.movable_page(index)?;
+
+        let page = match &mut self.kind {
+            PhysicalRegionKind::Sparse(pages) => &mut pages[index],
+            _ => unreachable!(),
+        };
+
+        self.swapped.push((index, slot));
+        Some(core::mem::replace(page, PhysicalPage::from_ptr(core::ptr::null_mut())))
+    }
+
+    /// Put `page` back in place of the page at `index` which was swapped out
+    #[track_caller]
+    pub fn swap_in(&mut self, index: usize, page: PhysicalPage) {
+        let swapped = self.swapped.iter().position(|&(i, _)| i == index).expect("page wasn't swapped out");
+        self.swapped.swap_remove(swapped);
+
+        match &mut self.kind {
+            PhysicalRegionKind::Sparse(pages) => pages[index] = page,
+            _ => unreachable!(),
         }
     }
 
+    /// The slot the page at `index` was swapped out to, if it has been
+    pub fn swapped_slot(&self, index: usize) -> Option<SwapSlot> {
+        self.swapped.iter().find(|&&(i, _)| i == index).map(|&(_, slot)| slot)
+    }
+
+    /// The first page which is swapped out, if there are any
+    pub fn first_swapped(&self) -> Option<usize> {
+        self.swapped.iter().map(|&(i, _)| i).min()
+    }
+
+    /// Add `n_pages` untouched pages to the end of the region, which are
+    /// allocated and zeroed by [`Self::populate`] as they're first touched
+    /// like the pages of a [`Self::lazy`] region. Returns `false` for device
+    /// memory, which can't be extended.
+    pub fn extend(&mut self, n_pages: usize) -> bool {
+        // Only sparse regions can have untouched pages
+        if let PhysicalRegionKind::Contiguous(_) = self.kind {
+            let pages = self.physical_addresses().map(|addr| PhysicalPage::from_ptr(addr.as_mut_ptr())).collect();
+            self.kind = PhysicalRegionKind::Sparse(pages);
+        }
+
+        match &mut self.kind {
+            PhysicalRegionKind::Sparse(pages) => {
+                pages.resize(pages.len() + n_pages, PhysicalPage::from_ptr(core::ptr::null_mut()))
+            }
+            _ => return false,
+        }
+
+        self.n_pages += n_pages;
+
+        true
+    }
+
+    /// Release every page from page `n_pages` onwards, leaving the region with
+    /// that many. Unlike with [`Self::migrate`], the pages are gone once this
+    /// returns, so it's up to the caller to unmap them first. A promoted
+    /// megapage which is cut short is released a kilopage at a time from then
+    /// on, so it has to be unmapped as kilopages too.
+    pub fn truncate(&mut self, n_pages: usize) {
+        if n_pages >= self.n_pages {
+            return;
+        }
+
+        self.swapped.retain(|&(i, slot)| {
+            if i >= n_pages {
+                swap::discard(slot);
+            }
+
+            i < n_pages
+        });
+        self.cow.retain(|&i| i < n_pages);
+
+        match &mut self.kind {
+            PhysicalRegionKind::Contiguous(start) => {
+                let first = start.as_phys_address().offset(n_pages * self.page_size.to_byte_size());
+                let first = PhysicalPage::from_ptr(first.as_mut_ptr());
+                unsafe { phys::release_contiguous(first, self.page_size, self.n_pages - n_pages) };
+            }
+            PhysicalRegionKind::Sparse(pages) => {
+                self.megapages.retain(|&start| start + KILOPAGES_PER_MEGAPAGE <= n_pages);
+                // Swapped out and untouched pages are null
+                for page in pages.drain(n_pages..).filter(|page| !page.as_phys_address().is_null()) {
+                    unsafe { phys::release(page, self.page_size) };
+                }
+            }
+            PhysicalRegionKind::Mmio(_) => {}
+        }
+
+        self.n_pages = n_pages;
+    }
+
     pub fn into_shared_region(self) -> SharedPhysicalRegion {
-        SharedPhysicalRegion { region: Arc::new(self) }
+        debug_assert!(self.swapped.is_empty(), "sharing a region with pages swapped out");
+        debug_assert!(self.cow.is_empty(), "sharing a region with copy-on-write pages");
+        let n_pages = self.n_pages;
+        SharedPhysicalRegion { region: Arc::new(self), rmap: Arc::new(Rmap::new()), first: 0, n_pages }
     }
 
     pub fn page_size(&self) -> PageSize {
@@ -177,15 +698,30 @@ impl UniquePhysicalRegion {
 
 impl Drop for UniquePhysicalRegion {
     fn drop(&mut self) {
+        for &(_, slot) in &self.swapped {
+            swap::discard(slot);
+        }
+
         match &mut self.kind {
+            // Any of the pages could have been shared copy-on-write, so they're
+            // only released rather than freed outright
             PhysicalRegionKind::Contiguous(start) => unsafe {
-                PHYSICAL_MEMORY_ALLOCATOR.lock().dealloc_contiguous(*start, self.page_size, self.n_pages)
+                phys::release_contiguous(*start, self.page_size, self.n_pages)
             },
             PhysicalRegionKind::Sparse(pages) => {
-                let mut allocator = PHYSICAL_MEMORY_ALLOCATOR.lock();
+                // Promoted megapages can hold pages which were shared
+                // copy-on-write after the region was made shared
+                for &index in &self.megapages {
+                    unsafe { phys::release_contiguous(pages[index], PageSize::Kilopage, KILOPAGES_PER_MEGAPAGE) };
+                }
 
-                for page in pages.drain(..) {
-                    unsafe { allocator.dealloc(page, self.page_size) };
+                let promoted =
+                    |i: usize| self.megapages.iter().any(|&start| (start..start + KILOPAGES_PER_MEGAPAGE).contains(&i));
+                // Swapped out and untouched pages are null
+                for (_, page) in
+                    pages.drain(..).enumerate().filter(|(i, page)| !promoted(*i) && !page.as_phys_address().is_null())
+                {
+                    unsafe { phys::release(page, self.page_size) };
                 }
             }
             // These are directly mapped, so we don't need to deallocate pages
@@ -194,15 +730,98 @@ impl Drop for UniquePhysicalRegion {
     }
 }
 
+/// A run of pages out of a [`UniquePhysicalRegion`] which is shared between
+/// anything holding a clone of it, and is freed once none of them are left.
+/// Every clone shares the same [`Rmap`], so where any part of the underlying
+/// region is mapped can be found from any of them.
 #[derive(Debug, Clone, PartialEq)]
 pub struct SharedPhysicalRegion {
     region: Arc<UniquePhysicalRegion>,
+    rmap: Arc<Rmap>,
+    first: usize,
+    n_pages: usize,
 }
 
-impl core::ops::Deref for SharedPhysicalRegion {
-    type Target = UniquePhysicalRegion;
+impl SharedPhysicalRegion {
+    pub fn page_size(&self) -> PageSize {
+        self.region.page_size
+    }
+
+    pub fn n_pages(&self) -> usize {
+        self.n_pages
+    }
+
+    pub fn physical_addresses(&self) -> impl Iterator<Item = PhysicalAddress> + '_ {
+        self.region.physical_addresses().skip(self.first).take(self.n_pages)
+    }
+
+    /// The physical address of the page at `index`, see
+    /// [`UniquePhysicalRegion::physical_address`]
+    pub fn physical_address(&self, index: usize) -> Option<PhysicalAddress> {
+        match index < self.n_pages {
+            true => self.region.physical_address(self.first + index),
+            false => None,
+        }
+    }
+
+    /// The `n_pages` pages starting at page `first` of this region, which keep
+    /// the whole of the underlying region alive. Returns `None` if they aren't
+    /// all part of this region.
+    pub fn slice(&self, first: usize, n_pages: usize) -> Option<Self> {
+        match first.checked_add(n_pages) {
+            Some(end) if end <= self.n_pages && n_pages > 0 => Some(Self {
+                region: Arc::clone(&self.region),
+                rmap: Arc::clone(&self.rmap),
+                first: self.first + first,
+                n_pages,
+            }),
+            _ => None,
+        }
+    }
+
+    /// A new private region made of the same pages, which are copied the first
+    /// time it writes to them. Nothing else can be writing to them, since the
+    /// copy would see it, so this is only for memory which has had writes
+    /// sealed.
+    pub fn copy_on_write(&self) -> UniquePhysicalRegion {
+        let pages = self
+            .physical_addresses()
+            .map(|addr| {
+                let page = PhysicalPage::from_ptr(addr.as_mut_ptr());
+                phys::share(page);
+                page
+            })
+            .collect();
+
+        UniquePhysicalRegion {
+            kind: PhysicalRegionKind::Sparse(pages),
+            page_size: self.page_size(),
+            n_pages: self.n_pages,
+            megapages: Vec::new(),
+            swapped: Vec::new(),
+            cow: (0..self.n_pages).collect(),
+        }
+    }
+
+    /// Record that this region has been mapped at `at` in `space`
+    pub fn record_mapping(&self, space: AddressSpaceId, at: VirtualAddress) {
+        self.rmap.add(Mapping { space, at, first: self.first, n_pages: self.n_pages });
+    }
+
+    /// Forget about the mapping of this region at `at` in `space`, once it's
+    /// been unmapped
+    pub fn forget_mapping(&self, space: AddressSpaceId, at: VirtualAddress) {
+        self.rmap.remove(space, at);
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.region
+    /// Run `f` for everywhere page `index` of this region is mapped, see
+    /// [`rmap::for_each_mapping`]
+    pub fn for_each_mapping(
+        &self,
+        index: usize,
+        current: &mut Task,
+        f: impl FnMut(&mut super::manager::MemoryManager, VirtualAddress),
+    ) {
+        rmap::for_each_mapping(&self.rmap, self.first + index, self.page_size().to_byte_size(), current, f)
     }
 }
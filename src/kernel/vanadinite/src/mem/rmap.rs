@@ -0,0 +1,138 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Reverse mappings for shared memory
+//!
+//! Private memory is only ever mapped by the address space which owns it, but
+//! shared memory can be mapped by any number of them. Every shared region keeps
+//! a list of where it's mapped, so anything which needs to change the page
+//! table entries for some shared memory, like revoking access to it, moving it
+//! somewhere else in physical memory or taking away permissions, can find all
+//! of them without going through every page table.
+//!
+//! Each [`MemoryManager`] has an [`AddressSpaceId`], and the task using it is
+//! recorded here while it's running, so the memory manager behind a mapping
+//! can be found again. Vmspaces which haven't been spawned yet don't belong to
+//! a task, and their mappings are skipped until they are.
+
+use super::{manager::MemoryManager, paging::VirtualAddress};
+use crate::scheduler::TASKS;
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use librust::task::Tid;
+use sync::{SpinMutex, SpinRwLock};
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+static OWNERS: SpinRwLock<BTreeMap<AddressSpaceId, Tid>> = SpinRwLock::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct AddressSpaceId(usize);
+
+impl AddressSpaceId {
+    pub fn new() -> Self {
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Record that the address space `space` is being used by `tid`
+pub fn set_owner(space: AddressSpaceId, tid: Tid) {
+    OWNERS.write().insert(space, tid);
+}
+
+/// The task using the address space `space`, if there is one
+pub fn owner(space: AddressSpaceId) -> Option<Tid> {
+    OWNERS.read().get(&space).copied()
+}
+
+/// Forget about `space`, once it's been torn down
+pub(super) fn remove_owner(space: AddressSpaceId) {
+    OWNERS.write().remove(&space);
+}
+
+/// Part of a shared region mapped into an address space
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mapping {
+    pub space: AddressSpaceId,
+    /// Where the first page of the mapping is
+    pub at: VirtualAddress,
+    /// The index of the first page mapped, in the underlying region
+    pub first: usize,
+    pub n_pages: usize,
+}
+
+/// Everywhere a shared region is mapped
+#[derive(Debug, Default)]
+pub struct Rmap {
+    mappings: SpinMutex<Vec<Mapping>>,
+}
+
+impl Rmap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&self, mapping: Mapping) {
+        self.mappings.lock().push(mapping);
+    }
+
+    /// Remove the mapping of the region at `at` in `space`
+    pub fn remove(&self, space: AddressSpaceId, at: VirtualAddress) {
+        self.mappings.lock().retain(|mapping| mapping.space != space || mapping.at != at);
+    }
+
+    /// Where page `index` of the underlying region is mapped, with the page
+    /// being `page_size` bytes
+    pub fn mappings_of(&self, index: usize, page_size: usize) -> Vec<(AddressSpaceId, VirtualAddress)> {
+        self.mappings
+            .lock()
+            .iter()
+            .filter(|mapping| (mapping.first..mapping.first + mapping.n_pages).contains(&index))
+            .map(|mapping| (mapping.space, mapping.at.add((index - mapping.first) * page_size)))
+            .collect()
+    }
+}
+
+/// Rmaps are only ever compared by identity, since two regions with the same
+/// mappings are still different regions
+impl PartialEq for Rmap {
+    fn eq(&self, other: &Self) -> bool {
+        core::ptr::eq(self, other)
+    }
+}
+
+/// Run `f` with the memory manager of every task mapping the page at `index`
+/// in `rmap` and where it's mapped there. `current` is the task calling this,
+/// which is already locked. Any task other than `current` could be running on
+/// another hart, so its TLB isn't flushed by changes made here.
+pub fn for_each_mapping(
+    rmap: &Rmap,
+    index: usize,
+    page_size: usize,
+    current: &mut crate::task::Task,
+    mut f: impl FnMut(&mut MemoryManager, VirtualAddress),
+) {
+    for (space, at) in rmap.mappings_of(index, page_size) {
+        if space == current.memory_manager.id() {
+            f(&mut current.memory_manager, at);
+            continue;
+        }
+
+        // An address space the current task exec'd out of can still be
+        // around until it's torn down
+        let task = match owner(space).filter(|&tid| tid != current.tid).and_then(|tid| TASKS.get(tid)) {
+            Some(task) => task,
+            None => continue,
+        };
+
+        let mut task = task.lock();
+        // The task may have exec'd into a different address space since the
+        // mappings were looked up
+        if task.memory_manager.id() == space {
+            f(&mut task.memory_manager, at);
+        }
+    }
+}
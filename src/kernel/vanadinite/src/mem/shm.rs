@@ -0,0 +1,132 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Shared memory objects
+//!
+//! A shared memory object owns physical memory independently of any address
+//! space, and is what public memory capabilities refer to. Every task holding a
+//! capability to the object can map it, and the memory is freed once the last
+//! capability and mapping are gone. Growing an object appends a new chunk of
+//! memory to it, so existing mappings stay valid but only cover the size the
+//! object had when they were made.
+//!
+//! Writes to an object can be sealed off, after which every mapping of it is
+//! read-only, including any which already existed, which are found through the
+//! [`super::rmap`]s of its chunks. Once writes are sealed, the object can also
+//! be mapped as a private copy, which shares its memory until it's written to.
+
+use super::{
+    paging::{
+        flags::{self, Flags},
+        PageSize,
+    },
+    region::{SharedPhysicalRegion, UniquePhysicalRegion},
+};
+use crate::{task::Task, utils};
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+use sync::SpinRwLock;
+
+#[derive(Debug, Clone)]
+pub struct SharedMemory {
+    chunks: Arc<SpinRwLock<Vec<SharedPhysicalRegion>>>,
+    page_size: PageSize,
+    write_sealed: Arc<AtomicBool>,
+}
+
+impl SharedMemory {
+    pub fn new(region: SharedPhysicalRegion) -> Self {
+        let page_size = region.page_size();
+        Self {
+            chunks: Arc::new(SpinRwLock::new(alloc::vec![region])),
+            page_size,
+            write_sealed: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Map the object's chunks with `map`, which is given the flags to map
+    /// them with when asked to map them with `flags`. Writes can't be sealed
+    /// while the chunks are being mapped, so any mapping made here is always
+    /// seen by [`Self::seal_writes`].
+    pub fn map_with<R>(&self, flags: Flags, map: impl FnOnce(&[SharedPhysicalRegion], Flags) -> R) -> R {
+        let chunks = self.chunks.read();
+        let flags = match self.write_sealed.load(Ordering::Acquire) {
+            true => without_write(flags),
+            false => flags,
+        };
+
+        map(&chunks, flags)
+    }
+
+    /// Stop anything from writing to the object again, taking write access
+    /// away from every existing mapping of it. `current` is the task sealing
+    /// it, which is already locked.
+    pub fn seal_writes(&self, current: &mut Task) {
+        let chunks = {
+            let chunks = self.chunks.write();
+            self.write_sealed.store(true, Ordering::Release);
+            chunks.clone()
+        };
+
+        for chunk in chunks {
+            for index in 0..chunk.n_pages() {
+                chunk.for_each_mapping(index, current, |memory_manager, at| {
+                    memory_manager.modify_page_flags(at, without_write);
+                });
+            }
+        }
+    }
+
+    /// Private copies of the object's chunks, which share its memory until
+    /// they're written to, or `None` if writes to it haven't been sealed, since
+    /// the copies would see anything written to it until they are
+    pub fn copy_on_write(&self) -> Option<Vec<UniquePhysicalRegion>> {
+        let chunks = self.chunks.read();
+        match self.write_sealed.load(Ordering::Acquire) {
+            true => Some(chunks.iter().map(SharedPhysicalRegion::copy_on_write).collect()),
+            false => None,
+        }
+    }
+
+    pub fn page_size(&self) -> PageSize {
+        self.page_size
+    }
+
+    /// The size of the object in bytes
+    pub fn size(&self) -> usize {
+        self.n_pages() * self.page_size.to_byte_size()
+    }
+
+    pub fn n_pages(&self) -> usize {
+        self.chunks.read().iter().map(|chunk| chunk.n_pages()).sum()
+    }
+
+    /// Grow the object to at least `new_size` bytes, returning the zeroed chunk
+    /// which was added to the end of it, or `None` if the object is already
+    /// that big
+    pub fn grow(&self, new_size: usize) -> Option<SharedPhysicalRegion> {
+        let mut chunks = self.chunks.write();
+        let page_bytes = self.page_size.to_byte_size();
+        let current_pages = chunks.iter().map(|chunk| chunk.n_pages()).sum::<usize>();
+        let new_pages = utils::round_up_to_next(new_size, page_bytes) / page_bytes;
+
+        if new_pages <= current_pages {
+            return None;
+        }
+
+        let chunk = UniquePhysicalRegion::alloc_zeroed(self.page_size, new_pages - current_pages, false);
+        let chunk = chunk.into_shared_region();
+        chunks.push(chunk.clone());
+
+        Some(chunk)
+    }
+}
+
+/// `flags` without write permission
+pub fn without_write(flags: Flags) -> Flags {
+    Flags::new(flags.value() & !flags::WRITE.value())
+}
@@ -0,0 +1,252 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! TLB shootdowns
+//!
+//! Changing an address space's mappings only flushes the TLB of the hart
+//! making the change. Harts which switch to the address space afterwards flush
+//! their own out of date entries for it (see [`asid`]), which leaves the harts
+//! running it at the time. Those are asked to flush the affected range
+//! themselves, and the hart making the change waits until they all have, so
+//! nothing is still using the old mappings once it carries on.
+//!
+//! When the firmware implements the SBI RFENCE extension it does the asking
+//! and waiting. Otherwise the harts are sent IPIs, and acknowledge each request
+//! once they've flushed what it asked for. Harts only take IPIs with interrupts
+//! enabled, so ones spinning on a lock in the kernel check for requests while
+//! they spin instead, since the lock could be held by the hart waiting on them.
+//!
+//! [`asid`]: crate::mem::manager::asid
+
+use super::{
+    paging::{PageSize, VirtualAddress},
+    sfence,
+};
+use crate::{
+    cpu_local::{self, PerHart},
+    hotplug::send_ipi,
+    utils::Units,
+    HART_ID,
+};
+use alloc::vec::Vec;
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use sync::{mutex::SpinMutexGuard, SpinMutex};
+
+const BASE_EXTENSION: usize = 0x10;
+const BASE_PROBE_EXTENSION: usize = 3;
+const RFENCE_EXTENSION: usize = 0x52464E43;
+const RFENCE_SFENCE_VMA: usize = 1;
+const RFENCE_SFENCE_VMA_ASID: usize = 2;
+
+/// Ranges longer than this many pages are flushed all at once, rather than a
+/// page at a time
+const MAX_PAGES_FENCED: usize = 64;
+
+static RFENCE: AtomicBool = AtomicBool::new(false);
+/// The address space each hart is running, or 0 if it isn't running one
+static ACTIVE: PerHart<AtomicUsize> = PerHart::new(|| AtomicUsize::new(0));
+static MAILBOXES: PerHart<Mailbox> = PerHart::new(Mailbox::new);
+
+struct Mailbox {
+    requests: SpinMutex<Requests>,
+    /// How many requests the hart has finished
+    acknowledged: AtomicUsize,
+}
+
+impl Mailbox {
+    fn new() -> Self {
+        Self {
+            requests: SpinMutex::new(Requests { pending: Vec::new(), posted: 0 }),
+            acknowledged: AtomicUsize::new(0),
+        }
+    }
+}
+
+struct Requests {
+    pending: Vec<Request>,
+    /// How many requests have ever been posted
+    posted: usize,
+}
+
+struct Request {
+    range: Option<Range<VirtualAddress>>,
+    asid: Option<u16>,
+}
+
+/// Check whether the firmware can do shootdowns for us, returning whether it
+/// can
+pub fn init() -> bool {
+    let (error, available) = sbi_call(BASE_EXTENSION, BASE_PROBE_EXTENSION, [RFENCE_EXTENSION, 0, 0, 0, 0]);
+    let rfence = error == 0 && available != 0;
+    RFENCE.store(rfence, Ordering::Relaxed);
+
+    rfence
+}
+
+/// Record that the current hart is now running the address space `space`, a
+/// nonzero ID unique to it. This has to happen before checking whether the
+/// hart's TLB entries for it are out of date.
+pub fn set_active(space: usize) {
+    ACTIVE.current().store(space, Ordering::SeqCst);
+}
+
+/// Record that the current hart has stopped running any address space
+pub fn set_inactive() {
+    ACTIVE.current().store(0, Ordering::SeqCst);
+}
+
+/// Flush the TLB entries for `range`, or everything, tagged with `asid`, or
+/// any ASID, on the current hart
+pub fn fence(range: Option<Range<VirtualAddress>>, asid: Option<u16>) {
+    let pages = match range {
+        Some(range) => range.start.align_down_to(PageSize::Kilopage).as_usize()..range.end.as_usize(),
+        None => return sfence(None, asid),
+    };
+
+    if pages.len() > MAX_PAGES_FENCED * 4.kib() {
+        return sfence(None, asid);
+    }
+
+    for page in pages.step_by(4.kib()) {
+        sfence(Some(VirtualAddress::new(page)), asid);
+    }
+}
+
+/// Have every other hart running the address space `space` flush its TLB
+/// entries for `range`, or everything, tagged with `asid`, or any ASID, and
+/// wait until they have. The generation of the address space has to have been
+/// bumped first, so any hart switching to it from now on flushes its own.
+pub fn shootdown(space: usize, range: Option<Range<VirtualAddress>>, asid: Option<u16>) {
+    let current = HART_ID.get();
+    let harts: Vec<usize> = ACTIVE
+        .iter()
+        .enumerate()
+        .filter(|(hart, active)| *hart != current && active.load(Ordering::SeqCst) == space)
+        .map(|(hart, _)| hart)
+        .collect();
+
+    if harts.is_empty() {
+        return;
+    }
+
+    // The firmware doesn't return until the harts are done
+    if RFENCE.load(Ordering::Relaxed) && remote_fence(&harts, range.clone(), asid) {
+        return;
+    }
+
+    let mut waiting = Vec::with_capacity(harts.len());
+    for hart in harts {
+        let mailbox = &MAILBOXES[hart];
+        let mut requests = mailbox.requests.lock();
+        requests.pending.push(Request { range: range.clone(), asid });
+        requests.posted += 1;
+        waiting.push((hart, requests.posted));
+        drop(requests);
+
+        send_ipi(hart);
+    }
+
+    // A hart which switches to another address space has nothing left that
+    // could use the old mappings, and will flush them if it switches back
+    while !waiting.is_empty() {
+        waiting.retain(|&(hart, request)| {
+            MAILBOXES[hart].acknowledged.load(Ordering::Acquire) < request
+                && ACTIVE[hart].load(Ordering::SeqCst) == space
+        });
+
+        // Another hart could be waiting on this one at the same time
+        handle_requests();
+        core::hint::spin_loop();
+    }
+}
+
+/// Flush whatever other harts have asked the current hart to. This is called
+/// on every supervisor software interrupt.
+pub fn handle_requests() {
+    let mailbox = MAILBOXES.current();
+    flush_requests(mailbox, mailbox.requests.lock());
+}
+
+/// Like [`handle_requests`], but skipped if another hart is posting a request
+/// at the same time. This is the spin hook for every lock in the kernel, so it
+/// can't spin on the mailbox itself.
+pub fn poll_requests() {
+    // Harts which are still booting don't have their thread locals yet, and
+    // have nothing to flush anyway
+    if RFENCE.load(Ordering::Relaxed) || cpu_local::tp().is_null() {
+        return;
+    }
+
+    let mailbox = MAILBOXES.current();
+    if let Some(requests) = mailbox.requests.try_lock() {
+        flush_requests(mailbox, requests);
+    }
+}
+
+fn flush_requests(mailbox: &Mailbox, mut requests: SpinMutexGuard<'_, Requests, sync::NoCheck>) {
+    let (pending, posted) = (core::mem::take(&mut requests.pending), requests.posted);
+    drop(requests);
+
+    for request in pending {
+        fence(request.range, request.asid);
+    }
+
+    mailbox.acknowledged.store(posted, Ordering::Release);
+}
+
+/// Ask the firmware to flush the TLBs of `harts`, returning whether it did.
+/// `harts` has to be in order.
+fn remote_fence(harts: &[usize], range: Option<Range<VirtualAddress>>, asid: Option<u16>) -> bool {
+    let (start, size) = match range {
+        Some(range) => (range.start.as_usize(), range.end.as_usize() - range.start.as_usize()),
+        None => (0, usize::MAX),
+    };
+
+    // Hart masks cover the 64 harts starting from their base
+    let mut remaining = harts;
+    while let Some(&base) = remaining.first() {
+        let n = remaining.iter().take_while(|&&hart| hart - base < 64).count();
+        let mask = remaining[..n].iter().fold(0, |mask, hart| mask | 1 << (hart - base));
+        remaining = &remaining[n..];
+
+        let (error, _) = match asid {
+            Some(asid) => sbi_call(RFENCE_EXTENSION, RFENCE_SFENCE_VMA_ASID, [mask, base, start, size, asid as usize]),
+            None => sbi_call(RFENCE_EXTENSION, RFENCE_SFENCE_VMA, [mask, base, start, size, 0]),
+        };
+
+        if error != 0 {
+            log::warn!("SBI remote fence failed with {}, falling back to IPIs", error as isize);
+            RFENCE.store(false, Ordering::Relaxed);
+            return false;
+        }
+    }
+
+    true
+}
+
+fn sbi_call(extension: usize, function: usize, args: [usize; 5]) -> (usize, usize) {
+    let error: usize;
+    let value: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") args[0] => error,
+            inlateout("a1") args[1] => value,
+            in("a2") args[2],
+            in("a3") args[3],
+            in("a4") args[4],
+            in("a6") function,
+            in("a7") extension,
+        );
+    }
+
+    (error, value)
+}
@@ -0,0 +1,434 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Swapping private memory out to a block device
+//!
+//! Block devices are driven from userspace, so the kernel only picks which
+//! pages to swap out and hands them to a swap server, which keeps them in a
+//! swap space on the device. Once the server has enabled swap, an idle job goes
+//! through the blocked tasks one at a time whenever free memory runs low. The
+//! private pages it finds which have been accessed since it last looked get
+//! their accessed bit cleared and another chance, and the rest are unmapped and
+//! queued for the server to write out, with their region keeping track of the
+//! slot of the swap space they went to.
+//!
+//! Touching a page which has been swapped out blocks the task until the server
+//! has read it back in, unless it hasn't been written out yet, in which case
+//! it's just taken back out of the queue. Syscalls which are passed memory that
+//! has been swapped out are restarted the same way once it's back in.
+//!
+//! The swap server itself is never swapped out, since nothing could bring it
+//! back in.
+
+use super::{
+    manager::MemoryManager,
+    paging::{flags, PageSize, VirtualAddress},
+    phys::{alloc_page, PhysicalMemoryAllocator, PhysicalPage, PHYSICAL_MEMORY_ALLOCATOR},
+    phys2virt,
+};
+use crate::{
+    scheduler::{Scheduler, WakeToken, SCHEDULER, TASKS},
+    syscall::channel::UserspaceChannel,
+    task::Task,
+};
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    vec::Vec,
+};
+use librust::{syscalls::channel::KernelMessage, task::Tid};
+use sync::SpinMutex;
+
+/// Pages are swapped out while fewer than this many are free (8 MiB)
+const LOW_FREE_PAGES: usize = 2048;
+/// The most pages swapped out of a task each time the job runs
+const EVICT_BATCH: usize = 64;
+
+static SWAP: SpinMutex<Swap> = SpinMutex::new(Swap::new());
+/// The last task looked at, or `None` to start over from the first task
+static CURSOR: SpinMutex<Option<Tid>> = SpinMutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwapError {
+    /// The task isn't the one managing swap, or swap hasn't been enabled yet
+    NotOwner,
+    AlreadyEnabled,
+    /// The slot isn't waiting to be read back in
+    InvalidSlot,
+}
+
+/// A page sized slot of the swap space
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SwapSlot(u32);
+
+impl SwapSlot {
+    pub fn new(slot: u32) -> Self {
+        Self(slot)
+    }
+
+    pub fn value(self) -> u32 {
+        self.0
+    }
+}
+
+/// Whether a page touched by a task is back in memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultIn {
+    Resident,
+    /// The page is being read back in, and the task has to block until it's
+    /// woken up
+    Wait,
+}
+
+#[derive(Debug)]
+enum SlotState {
+    /// Swapped out, but not handed to the server yet
+    Queued(PhysicalPage),
+    /// Handed to the server to be written out
+    Stored,
+    /// Waiting on the server to read it back in for a task
+    Reading(Tid),
+    /// Read back in, but not mapped again yet
+    Arrived(PhysicalPage),
+    /// Freed while it was being read back in, so it can't be used again until
+    /// the server is done with it
+    Abandoned,
+}
+
+pub struct Swap {
+    owner: Option<Tid>,
+    /// The owner's kernel channel, which it's told about new work on
+    notify: Option<UserspaceChannel>,
+    n_slots: u32,
+    /// Every slot from this one on has never been used
+    next_unused: u32,
+    free: Vec<SwapSlot>,
+    slots: BTreeMap<SwapSlot, SlotState>,
+    writes: VecDeque<SwapSlot>,
+    reads: VecDeque<SwapSlot>,
+}
+
+impl Swap {
+    pub const fn new() -> Self {
+        Self {
+            owner: None,
+            notify: None,
+            n_slots: 0,
+            next_unused: 0,
+            free: Vec::new(),
+            slots: BTreeMap::new(),
+            writes: VecDeque::new(),
+            reads: VecDeque::new(),
+        }
+    }
+
+    /// Only the task which enabled swap is allowed to manage it
+    fn check_owner(&self, tid: Tid) -> Result<(), SwapError> {
+        match self.owner {
+            Some(owner) if owner == tid => Ok(()),
+            _ => Err(SwapError::NotOwner),
+        }
+    }
+
+    fn alloc_slot(&mut self) -> Option<SwapSlot> {
+        if let Some(slot) = self.free.pop() {
+            return Some(slot);
+        }
+
+        let slot = SwapSlot(self.next_unused);
+        match slot.0 < self.n_slots {
+            true => {
+                self.next_unused += 1;
+                Some(slot)
+            }
+            false => None,
+        }
+    }
+
+    /// Queue `page` to be written out to `slot`
+    fn queue_write(&mut self, slot: SwapSlot, page: PhysicalPage) {
+        self.slots.insert(slot, SlotState::Queued(page));
+        self.writes.push_back(slot);
+
+        // The server keeps going until there's nothing left, so it only needs
+        // telling when there wasn't anything before
+        if self.writes.len() == 1 {
+            self.notify_owner();
+        }
+    }
+
+    fn queue_read(&mut self, slot: SwapSlot, tid: Tid) {
+        self.slots.insert(slot, SlotState::Reading(tid));
+        self.reads.push_back(slot);
+
+        if self.reads.len() == 1 {
+            self.notify_owner();
+        }
+    }
+
+    fn notify_owner(&self) {
+        if let Some(channel) = &self.notify {
+            channel.send_kernel_message(KernelMessage::SwapPending);
+        }
+    }
+}
+
+/// Start swapping out to `n_slots` slots, with `task` managing the swap space
+pub fn enable(task: &Task, n_slots: u32) -> Result<(), SwapError> {
+    let mut swap = SWAP.lock();
+    match swap.owner {
+        Some(owner) if owner != task.tid => return Err(SwapError::NotOwner),
+        Some(_) => return Err(SwapError::AlreadyEnabled),
+        None => {}
+    }
+
+    swap.owner = Some(task.tid);
+    swap.n_slots = n_slots;
+    swap.notify = Some(task.kernel_channel.clone());
+
+    log::info!("Task {} enabled swap with {} slots", task.name, n_slots);
+
+    Ok(())
+}
+
+/// Take up to `max` of the pages waiting to be written out along with their
+/// slots. The pages are no longer in use, so it's up to the caller to free them
+/// once it's copied them.
+pub fn take_writes(tid: Tid, max: usize) -> Result<Vec<(SwapSlot, PhysicalPage)>, SwapError> {
+    let mut swap = SWAP.lock();
+    swap.check_owner(tid)?;
+
+    let mut taken = Vec::with_capacity(max.min(swap.writes.len()));
+    while taken.len() < max {
+        let slot = match swap.writes.pop_front() {
+            Some(slot) => slot,
+            None => break,
+        };
+
+        match swap.slots.insert(slot, SlotState::Stored) {
+            Some(SlotState::Queued(page)) => taken.push((slot, page)),
+            state => unreachable!("slot {:?} queued for writing in state {:?}", slot, state),
+        }
+    }
+
+    Ok(taken)
+}
+
+/// Take up to `max` of the slots waiting to be read back in
+pub fn take_reads(tid: Tid, max: usize) -> Result<Vec<SwapSlot>, SwapError> {
+    let mut swap = SWAP.lock();
+    swap.check_owner(tid)?;
+
+    let n = max.min(swap.reads.len());
+    Ok(swap.reads.drain(..n).collect())
+}
+
+/// Hand over the contents of `slot` once they've been read back in, waking up
+/// the task waiting on them
+pub fn complete_read(tid: Tid, slot: SwapSlot, contents: &[u8]) -> Result<(), SwapError> {
+    let waiter = {
+        let mut swap = SWAP.lock();
+        swap.check_owner(tid)?;
+
+        match swap.slots.get(&slot) {
+            Some(SlotState::Reading(waiter)) => {
+                let waiter = *waiter;
+                let page = alloc_page();
+                let to = phys2virt(page.as_phys_address()).as_mut_ptr();
+                unsafe { core::ptr::copy_nonoverlapping(contents.as_ptr(), to, PageSize::Kilopage.to_byte_size()) };
+
+                swap.slots.insert(slot, SlotState::Arrived(page));
+                waiter
+            }
+            Some(SlotState::Abandoned) => {
+                swap.slots.remove(&slot);
+                swap.free.push(slot);
+                return Ok(());
+            }
+            _ => return Err(SwapError::InvalidSlot),
+        }
+    };
+
+    // The task might have exited while it was waiting
+    if TASKS.get(waiter).is_none() {
+        return Ok(());
+    }
+
+    // The waiter commits to blocking before the read is queued, but could
+    // still be on its way into the scheduler on another hart
+    while !SCHEDULER.is_blocked(waiter) {
+        core::hint::spin_loop();
+    }
+
+    // Waking a task steps it over the syscall it was blocked in, but it has to
+    // go back to whatever touched the page
+    SCHEDULER.unblock(WakeToken::new(waiter, |task| task.context.pc -= 4));
+
+    Ok(())
+}
+
+/// Bring the page at `at` back into `task`'s memory if it's been swapped out,
+/// returning `None` if it hasn't been. If it has to be read back in, the task
+/// has to block right away until it's woken up, and then go back to what it
+/// was doing when it touched the page.
+pub fn fault_in(task: &mut Task, at: VirtualAddress) -> Option<FaultIn> {
+    let slot = task.memory_manager.swapped_slot(at)?;
+
+    let page = {
+        let mut swap = SWAP.lock();
+        match swap.slots.remove(&slot) {
+            Some(SlotState::Queued(page)) => {
+                swap.writes.retain(|&queued| queued != slot);
+                swap.free.push(slot);
+                page
+            }
+            Some(SlotState::Arrived(page)) => {
+                swap.free.push(slot);
+                page
+            }
+            Some(SlotState::Stored) => {
+                swap.queue_read(slot, task.tid);
+                return Some(FaultIn::Wait);
+            }
+            // Woken up by something else before the read finished
+            Some(SlotState::Reading(_)) => {
+                swap.slots.insert(slot, SlotState::Reading(task.tid));
+                return Some(FaultIn::Wait);
+            }
+            state => unreachable!("page at {:#p} swapped out to slot {:?} in state {:?}", at, slot, state),
+        }
+    };
+
+    task.memory_manager.swap_in(at, page);
+    log::trace!("Swapped {:#p} back in for task {}", at, task.name);
+
+    Some(FaultIn::Resident)
+}
+
+/// Give up `slot` once the page swapped out to it is freed
+pub fn discard(slot: SwapSlot) {
+    let page = {
+        let mut swap = SWAP.lock();
+        let page = match swap.slots.remove(&slot) {
+            Some(SlotState::Queued(page)) => {
+                swap.writes.retain(|&queued| queued != slot);
+                Some(page)
+            }
+            Some(SlotState::Arrived(page)) => Some(page),
+            Some(SlotState::Stored) => None,
+            Some(SlotState::Reading(_)) => match swap.reads.iter().position(|&queued| queued == slot) {
+                Some(index) => {
+                    swap.reads.remove(index);
+                    None
+                }
+                // The server already has it, and could still be reading it
+                None => {
+                    swap.slots.insert(slot, SlotState::Abandoned);
+                    return;
+                }
+            },
+            state => unreachable!("slot {:?} discarded in state {:?}", slot, state),
+        };
+
+        swap.free.push(slot);
+        page
+    };
+
+    if let Some(page) = page {
+        unsafe { PHYSICAL_MEMORY_ALLOCATOR.lock().dealloc(page, PageSize::Kilopage) };
+    }
+}
+
+/// Swap out some of the pages of the next blocked task while free memory is
+/// running low. This is run as an idle job, and returns whether there are more
+/// tasks to look at.
+pub fn swap_out_in_background() -> bool {
+    let owner = match SWAP.lock().owner {
+        Some(owner) if TASKS.get(owner).is_some() => owner,
+        // Nothing can be swapped out if there's nowhere for it to go
+        _ => return false,
+    };
+
+    if PHYSICAL_MEMORY_ALLOCATOR.lock().free_pages() >= LOW_FREE_PAGES {
+        return false;
+    }
+
+    let mut cursor = match CURSOR.try_lock() {
+        Some(cursor) => cursor,
+        None => return false,
+    };
+
+    let (tid, task) = match TASKS.next_after(*cursor) {
+        Some(next) => next,
+        None => {
+            *cursor = None;
+            return false;
+        }
+    };
+
+    *cursor = Some(tid);
+    if tid == owner {
+        return true;
+    }
+
+    // Holding the lock keeps the task from being scheduled, but it could
+    // already be running if it isn't blocked
+    let mut task = match task.try_lock() {
+        Some(task) if SCHEDULER.is_blocked(tid) => task,
+        _ => return true,
+    };
+
+    let evicted = evict(&mut task.memory_manager);
+    if evicted > 0 {
+        log::debug!("Swapped out {} pages of task {}", evicted, task.name);
+    }
+
+    true
+}
+
+/// Age every private page in `memory_manager`, swapping out up to
+/// [`EVICT_BATCH`] of the ones which weren't accessed since the last time,
+/// returning how many were swapped out
+fn evict(memory_manager: &mut MemoryManager) -> usize {
+    let candidates = memory_manager.movable_pages().map(|(at, _)| at).collect::<Vec<_>>();
+    let mut evicted = 0;
+
+    for at in candidates {
+        let accessed = match memory_manager.page_flags(at) {
+            Some(flags) => flags & flags::ACCESSED,
+            None => continue,
+        };
+
+        if accessed {
+            memory_manager.modify_page_flags(at, |f| flags::Flags::new(f.value() & !flags::ACCESSED.value()));
+            continue;
+        }
+
+        // The rest still need aging
+        if evicted == EVICT_BATCH {
+            continue;
+        }
+
+        let mut swap = SWAP.lock();
+        let slot = match swap.alloc_slot() {
+            Some(slot) => slot,
+            None => {
+                log::debug!("Out of swap space");
+                break;
+            }
+        };
+
+        match memory_manager.swap_out(at, slot) {
+            Some(page) => {
+                swap.queue_write(slot, page);
+                evicted += 1;
+            }
+            None => swap.free.push(slot),
+        }
+    }
+
+    evicted
+}
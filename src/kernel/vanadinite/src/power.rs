@@ -0,0 +1,184 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Shutting down and rebooting the system
+//!
+//! A reset doesn't happen right away when it's requested. Every task which
+//! subscribed to shutdown notifications is sent one first, and the system is
+//! only reset once they've all acknowledged it or the time they have to do so
+//! runs out.
+
+use crate::{
+    csr,
+    scheduler::{Scheduler, SCHEDULER, TASKS},
+    syscall::channel::ChannelMessage,
+    task::Task,
+    utils::ticks_per_us,
+};
+use alloc::{collections::BTreeSet, vec::Vec};
+use core::sync::atomic::Ordering;
+use librust::{
+    syscalls::{channel::KernelMessage, power::ResetKind},
+    task::Tid,
+};
+use sync::SpinMutex;
+
+/// How long subscribers have to acknowledge a shutdown before the system is
+/// reset without them
+const ACKNOWLEDGE_TIMEOUT_US: u64 = 5_000_000;
+
+static SUBSCRIBERS: SpinMutex<BTreeSet<Tid>> = SpinMutex::new(BTreeSet::new());
+static PENDING: SpinMutex<Option<PendingReset>> = SpinMutex::new(None);
+
+struct PendingReset {
+    kind: ResetKind,
+    /// In `time` CSR ticks
+    deadline: u64,
+    waiting_on: BTreeSet<Tid>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerError {
+    /// A shutdown or reboot has already been requested
+    AlreadyPending,
+}
+
+pub fn subscribe(tid: Tid) {
+    SUBSCRIBERS.lock().insert(tid);
+}
+
+/// Notify every subscriber that the system is going to be reset, resetting it
+/// right away if there aren't any. `caller` is the task making the request,
+/// whose lock is already held.
+pub fn request(caller: &Task, kind: ResetKind) -> Result<(), PowerError> {
+    let mut pending = PENDING.lock();
+    if pending.is_some() {
+        return Err(PowerError::AlreadyPending);
+    }
+
+    log::info!("Task {} requested a {:?}", caller.name, kind);
+
+    // Tasks which have exited since subscribing can't acknowledge anything
+    let subscribers: BTreeSet<Tid> =
+        SUBSCRIBERS.lock().iter().copied().filter(|&tid| tid == caller.tid || TASKS.get(tid).is_some()).collect();
+
+    if subscribers.is_empty() {
+        reset(kind);
+    }
+
+    let ticks_per_us = ticks_per_us(1, crate::TIMER_FREQ.load(Ordering::Relaxed));
+    let deadline = csr::time::read().saturating_add(ACKNOWLEDGE_TIMEOUT_US * ticks_per_us);
+    *pending = Some(PendingReset { kind, deadline, waiting_on: subscribers.clone() });
+    drop(pending);
+
+    for tid in subscribers {
+        match tid == caller.tid {
+            true => notify(caller, kind),
+            false => {
+                let task = match TASKS.get(tid) {
+                    Some(task) => task,
+                    None => continue,
+                };
+
+                let task = task.lock();
+                notify(&task, kind);
+
+                let token = task.kernel_channel.sender.wake.lock().take();
+                if let Some(token) = token {
+                    drop(task);
+                    SCHEDULER.unblock(token);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn notify(task: &Task, kind: ResetKind) {
+    log::debug!("Notifying task {} of the {:?}", task.name, kind);
+    task.kernel_channel.sender.inner.write().push_urgent(ChannelMessage {
+        data: Into::into(KernelMessage::ShutdownRequested(kind)),
+        caps: Vec::new(),
+        charge: None,
+    });
+}
+
+/// Mark `tid` as being ready for the system to be reset, resetting it if that
+/// was the last subscriber being waited on
+pub fn acknowledge(tid: Tid) {
+    let mut pending = PENDING.lock();
+    if let Some(reset_pending) = &mut *pending {
+        reset_pending.waiting_on.remove(&tid);
+        if reset_pending.waiting_on.is_empty() {
+            reset(reset_pending.kind);
+        }
+    }
+}
+
+/// Reset the system if a reset is pending and its subscribers have run out of
+/// time to acknowledge it. This is called on every timer interrupt.
+pub fn poll() {
+    let pending = match PENDING.try_lock() {
+        Some(pending) => pending,
+        None => return,
+    };
+
+    if let Some(reset_pending) = &*pending {
+        if csr::time::read() >= reset_pending.deadline {
+            log::warn!("Timed out waiting on tasks {:?} to acknowledge the shutdown", reset_pending.waiting_on);
+            reset(reset_pending.kind);
+        }
+    }
+}
+
+/// Reset the system immediately, through SBI if the firmware supports it and
+/// otherwise through whatever the device tree describes
+pub fn reset(kind: ResetKind) -> ! {
+    use sbi::{
+        probe_extension,
+        system_reset::{system_reset, ResetReason, ResetType, EXTENSION_ID},
+        ExtensionAvailability,
+    };
+
+    log::info!("Resetting the system ({:?})", kind);
+
+    #[cfg(feature = "debug.replay")]
+    crate::replay::dump();
+
+    if let ExtensionAvailability::Available(_) = probe_extension(EXTENSION_ID) {
+        let reset_type = match kind {
+            ResetKind::Shutdown => ResetType::Shutdown,
+            ResetKind::Reboot => ResetType::ColdReboot,
+        };
+
+        if let Err(e) = system_reset(reset_type, ResetReason::NoReason) {
+            log::warn!("SBI system reset failed: {:?}", e);
+        }
+    }
+
+    #[cfg(feature = "driver.syscon")]
+    {
+        use crate::{drivers::generic::syscon::SysconReset, platform::FDT};
+
+        let compatible = match kind {
+            ResetKind::Shutdown => "syscon-poweroff",
+            ResetKind::Reboot => "syscon-reboot",
+        };
+
+        let fdt = unsafe { fdt::Fdt::from_ptr(FDT.load(Ordering::Acquire)) }.unwrap();
+        if let Some(syscon) = SysconReset::find(&fdt, compatible) {
+            unsafe { syscon.reset() };
+        }
+    }
+
+    log::error!("No way to reset the system, halting");
+    crate::csr::sstatus::disable_interrupts();
+    loop {
+        unsafe { core::arch::asm!("wfi") };
+    }
+}
@@ -0,0 +1,144 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Sampling profiler
+//!
+//! While the profiler is running each hart keeps the deadline of its next
+//! sample, which the timer is armed for along with the end of the time slice
+//! and the hart's timers (see [`timer::next_deadline`]). The timer interrupt
+//! handler takes a sample whenever one is due, and interrupts which were only
+//! taken for a sample go straight back to whatever was running instead of
+//! through the scheduler, so profiling doesn't cut time slices short.
+//!
+//! Samples go in a ring buffer per hart, which drops the oldest sample once
+//! it's full. The kernel only takes interrupts while it's idle, so time spent
+//! in syscalls is put down to the instruction after the `ecall` in the task
+//! which made it.
+//!
+//! [`timer::next_deadline`]: crate::scheduler::timer::next_deadline
+
+use crate::{cpu_local::PerHart, csr, symbols, task::Task, trap::GeneralRegisters, HART_ID};
+use alloc::collections::VecDeque;
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+use librust::syscalls::profiler::{ProfileSample, PROFILER_BUFFER_SAMPLES};
+use sync::SpinMutex;
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+/// Timer ticks between samples
+static INTERVAL: AtomicU64 = AtomicU64::new(0);
+/// The most return addresses to record in each sample
+static DEPTH: AtomicUsize = AtomicUsize::new(0);
+static BUFFERS: PerHart<SpinMutex<SampleBuffer>> = PerHart::new(|| SpinMutex::new(SampleBuffer::new()));
+
+#[thread_local]
+static NEXT_SAMPLE: Cell<u64> = Cell::new(0);
+
+struct SampleBuffer {
+    samples: VecDeque<ProfileSample>,
+    /// Samples thrown away for want of space since the buffer was last drained
+    dropped: usize,
+}
+
+impl SampleBuffer {
+    const fn new() -> Self {
+        Self { samples: VecDeque::new(), dropped: 0 }
+    }
+}
+
+/// Start taking a sample on every hart each `interval` timer ticks, with up to
+/// `depth` return addresses. Samples left over from before are thrown away.
+pub fn start(interval: u64, depth: usize) {
+    RUNNING.store(false, Ordering::Release);
+
+    // The space for every sample is set aside up front, so taking them never
+    // allocates
+    for buffer in BUFFERS.iter() {
+        let mut buffer = buffer.lock();
+        buffer.samples = VecDeque::with_capacity(PROFILER_BUFFER_SAMPLES);
+        buffer.dropped = 0;
+    }
+
+    INTERVAL.store(interval.max(1), Ordering::Relaxed);
+    DEPTH.store(depth, Ordering::Relaxed);
+    RUNNING.store(true, Ordering::Release);
+}
+
+/// Stop taking samples, keeping the ones already taken until they're drained
+pub fn stop() {
+    RUNNING.store(false, Ordering::Release);
+}
+
+pub fn running() -> bool {
+    RUNNING.load(Ordering::Acquire)
+}
+
+/// The deadline of this hart's next sample, or `u64::MAX` if the profiler isn't
+/// running. Other harts' deadlines are left over from the last time they were
+/// profiled, so they sample on their next timer interrupt after starting.
+pub fn next_sample() -> u64 {
+    match running() {
+        true => NEXT_SAMPLE.get(),
+        false => u64::MAX,
+    }
+}
+
+/// Take a sample if one is due, from a timer interrupt which interrupted `pc`
+/// with `regs`, with `task` running on the hart or `None` if it was idle
+pub fn sample(task: Option<&Task>, regs: &GeneralRegisters, pc: usize) {
+    let now = csr::time::read();
+    if now < next_sample() {
+        return;
+    }
+
+    NEXT_SAMPLE.set(now + INTERVAL.load(Ordering::Relaxed));
+
+    let hart = HART_ID.get();
+    let kernel = csr::sstatus::trapped_from_supervisor();
+    let mut sample =
+        ProfileSample { tid: task.map_or(0, |task| task.tid.value()), hart, kernel, ..ProfileSample::new() };
+    sample.frames[0] = pc;
+
+    let return_addresses = &mut sample.frames[1..][..DEPTH.load(Ordering::Relaxed)];
+    let n_return_addresses = match (kernel, task) {
+        (true, _) => return_addresses.iter_mut().zip(symbols::backtrace_from(regs.s0)).map(|(to, ra)| *to = ra).count(),
+        (false, Some(task)) => crate::trap::user_backtrace(task, regs.s0, return_addresses),
+        (false, None) => 0,
+    };
+    sample.depth = 1 + n_return_addresses;
+
+    let mut buffer = BUFFERS[hart].lock();
+    if buffer.samples.len() >= PROFILER_BUFFER_SAMPLES {
+        buffer.samples.pop_front();
+        buffer.dropped += 1;
+    }
+
+    buffer.samples.push_back(sample);
+}
+
+/// Move as many samples as fit into `out`, each hart's oldest first. Returns
+/// the number moved and the number dropped since the last drain.
+pub fn drain(out: &mut [ProfileSample]) -> (usize, usize) {
+    let mut drained = 0;
+    let mut dropped = 0;
+
+    for buffer in BUFFERS.iter() {
+        let mut buffer = buffer.lock();
+        let n = buffer.samples.len().min(out.len() - drained);
+
+        for (to, sample) in out[drained..].iter_mut().zip(buffer.samples.drain(..n)) {
+            *to = sample;
+        }
+
+        drained += n;
+        dropped += core::mem::take(&mut buffer.dropped);
+    }
+
+    (drained, dropped)
+}
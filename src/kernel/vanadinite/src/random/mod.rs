@@ -0,0 +1,231 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The kernel's random number generator
+//!
+//! Entropy from every available source is mixed into a BLAKE2s pool, which is
+//! hashed down into the key for a ChaCha20 generator once it has collected
+//! enough. Boards without a hardware RNG still have CPU timing jitter and
+//! interrupt arrival times to draw from, which are credited conservatively but
+//! are always available, so random numbers can always be produced.
+//!
+//! A random boot ID is drawn once the generator is first seeded, which tasks
+//! tag anything outliving the boot with, like crash dumps and traces, so it's
+//! never mistaken for something from another boot.
+
+mod blake2s;
+mod chacha20;
+
+use crate::{csr, TIMER_FREQ};
+use blake2s::Blake2s;
+use chacha20::KEY_SIZE;
+use core::sync::atomic::{AtomicBool, Ordering};
+use sync::SpinMutex;
+
+/// The amount of credited entropy needed before the generator is (re)seeded
+const SEED_BITS: usize = 256;
+/// Reseed after producing this many bytes
+const RESEED_BYTES: usize = 1024 * 1024;
+/// Reseed after this many seconds
+const RESEED_INTERVAL: u64 = 300;
+/// Interrupt arrival times are only credited a single bit for this many
+/// interrupts, since they're fairly predictable on an idle system
+const INTERRUPTS_PER_BIT: usize = 64;
+/// Timing jitter samples are only credited a single bit for this many samples
+/// which differ from the last
+const JITTER_SAMPLES_PER_BIT: usize = 16;
+/// Give up on crediting jitter after this many samples, for harts whose cycle
+/// counter barely varies (or doesn't count at all) and would otherwise never
+/// credit enough to reseed
+const MAX_JITTER_SAMPLES: usize = SEED_BITS * JITTER_SAMPLES_PER_BIT * 64;
+/// The most bytes generated while holding the generator's lock, so that tasks
+/// asking for large amounts don't keep interrupts and other harts waiting
+const FILL_CHUNK: usize = 1024;
+
+static RNG: SpinMutex<Rng> = SpinMutex::new(Rng::new());
+static BOOT_ID: SpinMutex<u128> = SpinMutex::new(0);
+/// Only warn about jitter falling short once, since it'll keep doing so on the
+/// same hardware every reseed
+static JITTER_WARNED: AtomicBool = AtomicBool::new(false);
+
+struct Rng {
+    pool: Blake2s,
+    credited_bits: usize,
+    interrupt_samples: usize,
+    /// `None` until the pool has collected enough entropy for the first time
+    key: Option<[u8; KEY_SIZE]>,
+    generated: usize,
+    last_reseed: u64,
+}
+
+impl Rng {
+    const fn new() -> Self {
+        Self { pool: Blake2s::new(), credited_bits: 0, interrupt_samples: 0, key: None, generated: 0, last_reseed: 0 }
+    }
+
+    fn mix(&mut self, data: &[u8], credit_bits: usize) {
+        self.pool.update(data);
+        self.credited_bits = self.credited_bits.saturating_add(credit_bits);
+    }
+
+    /// Time a short memory-bound loop over and over, the variation in which
+    /// comes from cache, TLB, and pipeline state that isn't predictable from
+    /// outside of the hart. Gives up after [`MAX_JITTER_SAMPLES`], reseeding
+    /// with whatever has been collected rather than hanging.
+    fn collect_jitter(&mut self) {
+        let mut scratch = [0u8; 256];
+        let mut last_delta = 0;
+        let mut varying = 0;
+
+        for _ in 0..MAX_JITTER_SAMPLES {
+            if self.credited_bits >= SEED_BITS {
+                return;
+            }
+
+            let start = csr::cycle::read();
+            for i in 0..scratch.len() {
+                let index = (start.wrapping_mul(31).wrapping_add(i * 7)) % scratch.len();
+                unsafe { core::ptr::write_volatile(&mut scratch[index], scratch[index].wrapping_add(i as u8)) };
+            }
+            let delta = csr::cycle::read().wrapping_sub(start);
+
+            self.pool.update(&delta.to_le_bytes());
+            if delta != last_delta {
+                varying += 1;
+                if varying % JITTER_SAMPLES_PER_BIT == 0 {
+                    self.credited_bits += 1;
+                }
+            }
+
+            last_delta = delta;
+        }
+
+        if self.credited_bits < SEED_BITS && !JITTER_WARNED.swap(true, Ordering::Relaxed) {
+            log::warn!(
+                "Cycle counter jitter only credited {} of {} bits, reseeding RNG without enough entropy",
+                self.credited_bits,
+                SEED_BITS
+            );
+        }
+    }
+
+    fn reseed_due(&self, now: u64) -> bool {
+        let interval = RESEED_INTERVAL * TIMER_FREQ.load(Ordering::Relaxed);
+        self.key.is_none() || self.generated >= RESEED_BYTES || now.wrapping_sub(self.last_reseed) >= interval
+    }
+
+    fn reseed(&mut self, now: u64) {
+        // Jitter is always available, so the pool is topped up with it
+        // whenever the other sources haven't provided enough in time
+        self.collect_jitter();
+
+        let mut hasher = core::mem::replace(&mut self.pool, Blake2s::new());
+        // Mixing the old key in means a reseed never makes the output any
+        // weaker than it already was
+        if let Some(key) = &self.key {
+            hasher.update(key);
+        }
+
+        self.key = Some(hasher.finalize());
+        self.credited_bits = 0;
+        self.generated = 0;
+        self.last_reseed = now;
+    }
+
+    fn fill(&mut self, buffer: &mut [u8]) {
+        let now = csr::time::read();
+        if self.reseed_due(now) {
+            self.reseed(now);
+        }
+
+        // The first half of the first block replaces the key before any output
+        // is handed out, so previous output can't be recovered from the
+        // generator's state later on
+        let key = self.key.as_mut().unwrap();
+        let first = chacha20::block(key, 0);
+        let (next_key, first) = first.split_at(KEY_SIZE);
+
+        let (head, rest) = buffer.split_at_mut(buffer.len().min(first.len()));
+        head.copy_from_slice(&first[..head.len()]);
+        for (counter, chunk) in rest.chunks_mut(chacha20::BLOCK_SIZE).enumerate() {
+            let block = chacha20::block(key, counter as u64 + 1);
+            chunk.copy_from_slice(&block[..chunk.len()]);
+        }
+
+        key.copy_from_slice(next_key);
+        self.generated = self.generated.saturating_add(buffer.len());
+    }
+}
+
+/// Seed the generator with whatever is available at boot, `bootloader_seed`
+/// being the contents of the `/chosen/rng-seed` property from the device tree
+pub fn init(bootloader_seed: Option<&[u8]>) {
+    let mut rng = RNG.lock();
+    rng.mix(&csr::time::read().to_le_bytes(), 0);
+
+    // The bootloader is trusted as much as the kernel image it loaded
+    if let Some(seed) = bootloader_seed {
+        log::info!("Seeding RNG with {} bytes from the bootloader", seed.len());
+        rng.mix(seed, seed.len() * 8);
+    }
+
+    let now = csr::time::read();
+    rng.reseed(now);
+
+    // Laid out as a version 4 UUID (RFC 4122 section 4.4)
+    let mut boot_id = [0; 16];
+    rng.fill(&mut boot_id);
+    boot_id[6] = (boot_id[6] & 0x0F) | 0x40;
+    boot_id[8] = (boot_id[8] & 0x3F) | 0x80;
+
+    let boot_id = u128::from_be_bytes(boot_id);
+    *BOOT_ID.lock() = boot_id;
+
+    log::info!(
+        "Boot ID: {:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        boot_id >> 96,
+        (boot_id >> 80) & 0xFFFF,
+        (boot_id >> 64) & 0xFFFF,
+        (boot_id >> 48) & 0xFFFF,
+        boot_id & 0xFFFF_FFFF_FFFF
+    );
+}
+
+/// The ID drawn for this boot by [`init`], as a big endian UUID
+pub fn boot_id() -> u128 {
+    *BOOT_ID.lock()
+}
+
+/// Mix in the time an interrupt arrived at. This is called from trap handlers,
+/// so the sample is dropped if the pool is busy instead of waiting on it.
+pub fn add_interrupt_entropy(source: usize) {
+    if let Some(mut rng) = RNG.try_lock() {
+        let mut sample = [0; 16];
+        sample[..8].copy_from_slice(&csr::cycle::read().to_le_bytes());
+        sample[8..].copy_from_slice(&source.to_le_bytes());
+        rng.pool.update(&sample);
+
+        rng.interrupt_samples += 1;
+        if rng.interrupt_samples % INTERRUPTS_PER_BIT == 0 {
+            rng.credited_bits += 1;
+        }
+    }
+}
+
+/// Mix in data from a source that can't be trusted to be unpredictable, which
+/// never hurts but isn't credited as entropy
+pub fn add_uncredited_entropy(data: &[u8]) {
+    RNG.lock().mix(data, 0);
+}
+
+/// Fill `buffer` with random bytes, [`FILL_CHUNK`] bytes at a time so the lock
+/// is released in between
+pub fn fill(buffer: &mut [u8]) {
+    for chunk in buffer.chunks_mut(FILL_CHUNK) {
+        RNG.lock().fill(chunk);
+    }
+}
@@ -0,0 +1,404 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Deterministic record and replay of task interleavings
+//!
+//! With the `debug.replay` feature, the kernel records which task each hart
+//! runs, where tasks get preempted, when device interrupts are handled and the
+//! order channel messages are sent in, and prints the trace when the system
+//! shuts down or panics. Building with that trace as `build/replay.trace` (see
+//! `cargo xtask run --replay`) makes the kernel re-drive the same scheduling
+//! decisions, preemption points and interrupt delivery, so a bug which only
+//! shows up with one particular interleaving happens again every run.
+//!
+//! Preemption can't be replayed at an exact instruction, so while recording,
+//! a timer interrupt only marks the running task to be preempted at its next
+//! syscall, and the preemption is recorded as the number of syscalls the task
+//! had made. Only tasks which run a whole time slice without a syscall are
+//! preempted right away, and those points are replayed approximately. Device
+//! interrupts arriving earlier than they did in the trace are held back until
+//! the trace gets to them, and ones arriving later are waited for. Channel
+//! messages and timer expiry aren't driven by the trace, but messages are
+//! checked against it so the replay notices when it has gone off course, at
+//! which point it logs where and carries on without the trace.
+//!
+//! Replaying is only exact with a single hart, since the order harts take
+//! events in relative to each other isn't recorded.
+
+use crate::{
+    interrupts::{isr::invoke_isr, PLIC},
+    scheduler, time, HART_ID, N_CPUS,
+};
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{
+    cell::Cell,
+    num::NonZeroUsize,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+use librust::{capabilities::CapabilityPtr, task::Tid};
+use sync::SpinMutex;
+
+/// Empty when recording
+static TRACE: &[u8] = include_bytes!("../../../../build/replay.trace");
+/// Keep recording from using up the whole kernel heap (6 MiB of events)
+const MAX_EVENTS: usize = 1 << 18;
+const EVENT_SIZE: usize = 24;
+/// How long to wait for a device interrupt the trace expects before giving up
+const INTERRUPT_TIMEOUT: Duration = Duration::from_secs(1);
+/// The time slice the scheduler normally gives tasks, used to re-arm the timer
+/// when a preemption is put off
+const TIME_SLICE_US: u64 = 10_000;
+
+static RECORDED: SpinMutex<Vec<Event>> = SpinMutex::new(Vec::new());
+static CURSOR: AtomicUsize = AtomicUsize::new(0);
+static DIVERGED: AtomicBool = AtomicBool::new(false);
+static SYSCALLS: SpinMutex<BTreeMap<Tid, u64>> = SpinMutex::new(BTreeMap::new());
+/// Interrupts which have been claimed but not handled yet, since the trace
+/// hasn't gotten to them
+static DEFERRED_INTERRUPTS: SpinMutex<Vec<usize>> = SpinMutex::new(Vec::new());
+
+#[thread_local]
+static PREEMPT_PENDING: Cell<bool> = Cell::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Event {
+    Schedule {
+        hart: usize,
+        tid: usize,
+    },
+    /// `exact` is `false` when the task was preempted in the middle of running
+    /// rather than at a syscall
+    Preempt {
+        hart: usize,
+        tid: usize,
+        syscalls: u64,
+        exact: bool,
+    },
+    Interrupt {
+        hart: usize,
+        id: usize,
+    },
+    /// `to` is zero if the receiving end doesn't belong to a task
+    Message {
+        hart: usize,
+        to: usize,
+        cptr: usize,
+    },
+}
+
+impl Event {
+    fn encode(self) -> [u64; 3] {
+        let header = |kind: u64, hart: usize| kind | (hart as u64) << 8;
+        match self {
+            Event::Schedule { hart, tid } => [header(0, hart), tid as u64, 0],
+            Event::Preempt { hart, tid, syscalls, exact: true } => [header(1, hart), tid as u64, syscalls],
+            Event::Preempt { hart, tid, syscalls, exact: false } => [header(2, hart), tid as u64, syscalls],
+            Event::Interrupt { hart, id } => [header(3, hart), id as u64, 0],
+            Event::Message { hart, to, cptr } => [header(4, hart), to as u64, cptr as u64],
+        }
+    }
+
+    fn decode([header, a, b]: [u64; 3]) -> Option<Self> {
+        let hart = (header >> 8) as usize;
+        match header & 0xFF {
+            0 => Some(Event::Schedule { hart, tid: a as usize }),
+            1 => Some(Event::Preempt { hart, tid: a as usize, syscalls: b, exact: true }),
+            2 => Some(Event::Preempt { hart, tid: a as usize, syscalls: b, exact: false }),
+            3 => Some(Event::Interrupt { hart, id: a as usize }),
+            4 => Some(Event::Message { hart, to: a as usize, cptr: b as usize }),
+            _ => None,
+        }
+    }
+}
+
+/// What the scheduler should run next according to the trace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// Not replaying, or the trace doesn't say
+    Free,
+    Run(Tid),
+}
+
+pub fn init() {
+    match TRACE.is_empty() {
+        true => log::info!("Recording a replay trace"),
+        false => {
+            log::info!("Replaying a trace of {} events", TRACE.len() / EVENT_SIZE);
+
+            if TRACE.len() % EVENT_SIZE != 0 {
+                log::warn!("Replay trace has {} trailing bytes, ignoring them", TRACE.len() % EVENT_SIZE);
+            }
+
+            if N_CPUS.load(Ordering::Acquire) > 1 {
+                log::warn!("Replaying with more than one hart, the replay won't be exact");
+            }
+        }
+    }
+}
+
+fn replaying() -> bool {
+    !TRACE.is_empty() && !DIVERGED.load(Ordering::Acquire)
+}
+
+fn record(event: Event) {
+    if !TRACE.is_empty() {
+        return;
+    }
+
+    let mut recorded = RECORDED.lock();
+    match recorded.len() {
+        MAX_EVENTS => {}
+        n => {
+            if n == MAX_EVENTS - 1 {
+                log::warn!("Replay trace is full, recording stopped");
+            }
+
+            recorded.push(event);
+        }
+    }
+}
+
+fn peek() -> Option<Event> {
+    let index = CURSOR.load(Ordering::Acquire);
+    let bytes = TRACE.get(index * EVENT_SIZE..)?.get(..EVENT_SIZE)?;
+    let word = |n: usize| u64::from_le_bytes(bytes[n * 8..][..8].try_into().unwrap());
+
+    Event::decode([word(0), word(1), word(2)])
+}
+
+fn consume() {
+    CURSOR.fetch_add(1, Ordering::AcqRel);
+}
+
+fn diverge(actual: Event) {
+    if !DIVERGED.swap(true, Ordering::AcqRel) {
+        log::error!(
+            "Replay diverged at event {}: the trace has {:?} but got {:?}, continuing without the trace",
+            CURSOR.load(Ordering::Acquire),
+            peek(),
+            actual
+        );
+    }
+}
+
+/// Record `event`, or when replaying, check that it's the next one in the trace
+fn observe(event: Event) {
+    match replaying() {
+        true if peek() == Some(event) => consume(),
+        true => diverge(event),
+        false => record(event),
+    }
+}
+
+fn syscalls_made(tid: Tid) -> u64 {
+    SYSCALLS.lock().get(&tid).copied().unwrap_or(0)
+}
+
+/// Which task the trace ran next on this hart
+pub fn next_scheduled() -> Decision {
+    if !replaying() {
+        return Decision::Free;
+    }
+
+    match peek() {
+        Some(Event::Schedule { hart, tid }) if hart == HART_ID.get() => match NonZeroUsize::new(tid) {
+            Some(tid) => Decision::Run(Tid::new(tid)),
+            None => Decision::Free,
+        },
+        _ => Decision::Free,
+    }
+}
+
+/// The scheduler is about to run `tid` on this hart
+pub fn scheduled(tid: Tid) {
+    PREEMPT_PENDING.set(false);
+    observe(Event::Schedule { hart: HART_ID.get(), tid: tid.value() });
+}
+
+/// A timer interrupt arrived while `tid` was running, returns whether to
+/// preempt it now. If not, the timer has already been re-armed.
+pub fn preempt_on_timer(tid: Tid) -> bool {
+    let hart = HART_ID.get();
+    let preempt = match replaying() {
+        true => {
+            let expected = Event::Preempt { hart, tid: tid.value(), syscalls: syscalls_made(tid), exact: false };
+            peek() == Some(expected)
+        }
+        // It already had a whole time slice to make a syscall in
+        false if PREEMPT_PENDING.get() => true,
+        false => {
+            PREEMPT_PENDING.set(true);
+            false
+        }
+    };
+
+    match preempt {
+        true => observe(Event::Preempt { hart, tid: tid.value(), syscalls: syscalls_made(tid), exact: false }),
+        false => {
+            let deadline = time::Instant::now() + Duration::from_micros(TIME_SLICE_US);
+            scheduler::end_time_slice_at(deadline.ticks());
+        }
+    }
+
+    preempt
+}
+
+/// `tid` finished a syscall, returns whether to preempt it before it goes back
+/// to running
+pub fn preempt_at_syscall(tid: Tid) -> bool {
+    let syscalls = {
+        let mut counts = SYSCALLS.lock();
+        let count = counts.entry(tid).or_default();
+        *count += 1;
+        *count
+    };
+
+    let event = Event::Preempt { hart: HART_ID.get(), tid: tid.value(), syscalls, exact: true };
+    let preempt = match replaying() {
+        true => peek() == Some(event),
+        false => PREEMPT_PENDING.get(),
+    };
+
+    if preempt {
+        PREEMPT_PENDING.set(false);
+        observe(event);
+    }
+
+    preempt
+}
+
+/// A device interrupt was claimed, returns whether to handle it now. If not,
+/// it's handled by [`deliver_interrupts`] once the trace gets to it, and must
+/// be left incomplete until then.
+pub fn interrupt_arrived(id: usize) -> bool {
+    let event = Event::Interrupt { hart: HART_ID.get(), id };
+    match replaying() {
+        true if peek() == Some(event) => {
+            consume();
+            true
+        }
+        true => {
+            log::debug!("Holding back interrupt {} until the replay gets to it", id);
+            DEFERRED_INTERRUPTS.lock().push(id);
+            false
+        }
+        false => {
+            record(event);
+            true
+        }
+    }
+}
+
+/// Handle any device interrupts which come next in the trace, waiting for
+/// them if they haven't arrived yet. This must not be called while holding a
+/// task lock.
+pub fn deliver_interrupts() {
+    if !replaying() {
+        release_deferred_interrupts();
+        return;
+    }
+
+    while replaying() {
+        let id = match peek() {
+            Some(Event::Interrupt { hart, id }) if hart == HART_ID.get() => id,
+            _ => return,
+        };
+
+        let plic = match *PLIC.lock() {
+            Some(plic) => plic,
+            None => return,
+        };
+        let context = crate::platform::current_plic_context();
+
+        let deferred = {
+            let mut deferred = DEFERRED_INTERRUPTS.lock();
+            let index = deferred.iter().position(|&deferred| deferred == id);
+            index.map(|index| deferred.remove(index))
+        };
+
+        let claim = match deferred {
+            Some(_) => Some(plic.reclaim(context, id)),
+            None => {
+                let mut claim = None;
+                time::wait_for(INTERRUPT_TIMEOUT, || match plic.claim(context) {
+                    Some(claimed) if claimed.interrupt_id() == id => {
+                        claim = Some(claimed);
+                        true
+                    }
+                    Some(claimed) => {
+                        DEFERRED_INTERRUPTS.lock().push(claimed.interrupt_id());
+                        false
+                    }
+                    None => false,
+                });
+
+                claim
+            }
+        };
+
+        match claim {
+            Some(claim) => {
+                consume();
+                if let Err(e) = invoke_isr(plic, claim, id) {
+                    log::error!("Error during ISR: {}", e);
+                }
+            }
+            None => {
+                log::error!("Interrupt {} never arrived, continuing without the trace", id);
+                DIVERGED.store(true, Ordering::Release);
+                release_deferred_interrupts();
+            }
+        }
+    }
+}
+
+/// Handle everything that was held back for a replay which has gone off course
+fn release_deferred_interrupts() {
+    let deferred = core::mem::take(&mut *DEFERRED_INTERRUPTS.lock());
+    if deferred.is_empty() {
+        return;
+    }
+
+    let plic = match *PLIC.lock() {
+        Some(plic) => plic,
+        None => return,
+    };
+
+    for id in deferred {
+        let claim = plic.reclaim(crate::platform::current_plic_context(), id);
+        if let Err(e) = invoke_isr(plic, claim, id) {
+            log::error!("Error during ISR: {}", e);
+        }
+    }
+}
+
+/// A channel message was queued for `cptr` in the task `to`
+pub fn message_sent(to: Option<Tid>, cptr: CapabilityPtr) {
+    observe(Event::Message { hart: HART_ID.get(), to: to.map_or(0, |tid| tid.value()), cptr: cptr.value() });
+}
+
+/// Print the recorded trace to the console, for `cargo xtask run --replay` to
+/// pick back up from the log
+pub fn dump() {
+    if !TRACE.is_empty() {
+        return;
+    }
+
+    // This is also called when panicking, possibly with the lock held
+    let recorded = match RECORDED.try_lock() {
+        Some(recorded) => recorded,
+        None => return,
+    };
+
+    crate::println!("replay-trace-start {}", recorded.len());
+    for event in recorded.iter() {
+        let [a, b, c] = event.encode();
+        crate::println!("replay-trace: {:016x}{:016x}{:016x}", a, b, c);
+    }
+    crate::println!("replay-trace-end");
+}
@@ -6,6 +6,7 @@
 // obtain one at https://mozilla.org/MPL/2.0/.
 
 pub mod round_robin;
+pub mod timer;
 
 use crate::{
     csr,
@@ -14,7 +15,9 @@ use crate::{
 };
 use alloc::{boxed::Box, collections::BTreeMap, sync::Arc};
 use core::{
+    cell::Cell,
     num::NonZeroUsize,
+    ops::Bound,
     sync::atomic::{AtomicUsize, Ordering},
 };
 use librust::task::Tid;
@@ -26,6 +29,13 @@ pub static TASKS: TaskList = TaskList::new();
 // Used for heuristics in schedulers if they so choose
 static N_TASKS: AtomicUsize = AtomicUsize::new(0);
 
+/// How long tasks run for before being preempted
+const TIME_SLICE_US: u64 = 10_000;
+
+/// When the time slice of whatever's running on this hart ends
+#[thread_local]
+static TIME_SLICE_END: Cell<u64> = Cell::new(0);
+
 //pub fn init_scheduler(scheduler: Box<dyn Scheduler>) {
 //    SCHEDULER.0.write().replace(scheduler).expect("reinitialized scheduler!");
 //}
@@ -61,6 +71,11 @@ impl LockedTask {
     pub fn try_lock(&self) -> Option<LockedTaskGuard<'_>> {
         self.0.try_lock().map(LockedTaskGuard)
     }
+
+    /// Whether both are the same task
+    pub fn ptr_eq(&self, other: &LockedTask) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
 }
 
 pub struct WakeToken {
@@ -93,6 +108,7 @@ impl TaskList {
     pub fn insert(&self, mut task: Task) -> (Tid, LockedTask) {
         let tid = Tid::new(NonZeroUsize::new(self.next_id.load(Ordering::Acquire)).unwrap());
         task.tid = tid;
+        crate::mem::rmap::set_owner(task.memory_manager.id(), tid);
         let task: LockedTask = LockedTask::new(task);
         // FIXME: reuse older pids at some point
         let _ = self.map.write().insert(tid, LockedTask::clone(&task));
@@ -123,6 +139,18 @@ impl TaskList {
     pub fn get(&self, tid: Tid) -> Option<LockedTask> {
         self.map.read().get(&tid).cloned()
     }
+
+    /// The task with the lowest [`Tid`] after `tid`, or the lowest overall if
+    /// `tid` is `None`, for going through every task a bit at a time
+    pub fn next_after(&self, tid: Option<Tid>) -> Option<(Tid, LockedTask)> {
+        let map = self.map.read();
+        let mut after = match tid {
+            Some(tid) => map.range((Bound::Excluded(tid), Bound::Unbounded)),
+            None => map.range(..),
+        };
+
+        after.next().map(|(tid, task)| (*tid, task.clone()))
+    }
 }
 
 pub trait Scheduler: Send {
@@ -132,12 +160,50 @@ pub trait Scheduler: Send {
     fn dequeue(&self, tid: Tid);
     fn block(&self, tid: Tid);
     fn unblock(&self, token: WakeToken);
+    /// Whether `tid` is blocked, and so isn't running on any hart
+    fn is_blocked(&self, tid: Tid) -> bool;
+    /// Whether `tid` is in a run queue without a syscall it was blocked in
+    /// left to finish, so it isn't blocked or being woken up
+    fn is_ready(&self, tid: Tid) -> bool;
     fn active_on_cpu(&self) -> Option<LockedTask>;
+    /// Whether `task` is the one running on any hart. Once a task which isn't
+    /// runnable is no longer active, it won't be until it's runnable again.
+    fn is_active(&self, task: &LockedTask) -> bool;
+    /// Whether tasks can be put on `hart_id`. Harts are online from when
+    /// they're first started.
+    fn set_hart_online(&self, hart_id: usize, online: bool);
+    /// Move every task on the current hart to the online harts, including the
+    /// one which was running, whose context must have been saved already
+    fn migrate_tasks(&self);
+}
+
+/// Start a new time slice on this hart, arming the timer for when it ends or
+/// for whatever's due before then
+fn start_time_slice() {
+    end_time_slice_at(csr::time::read() + ticks_per_us(TIME_SLICE_US, crate::TIMER_FREQ.load(Ordering::Relaxed)));
+}
+
+/// Have the current time slice on this hart end at `deadline`, arming the
+/// timer for then or for whatever's due before then
+pub fn end_time_slice_at(deadline: u64) {
+    TIME_SLICE_END.set(deadline);
+    sbi::timer::set_timer(timer::next_deadline(deadline)).unwrap();
+}
+
+/// Carry on with the current time slice after a timer interrupt which came
+/// before the end of it, arming the timer again
+pub fn resume_time_slice() {
+    end_time_slice_at(TIME_SLICE_END.get());
+}
+
+pub fn time_slice_over() -> bool {
+    csr::time::read() >= TIME_SLICE_END.get()
 }
 
 fn sleep() -> ! {
-    sbi::timer::set_timer(csr::time::read() + ticks_per_us(10_000, crate::TIMER_FREQ.load(Ordering::Relaxed))).unwrap();
+    start_time_slice();
     csr::sie::enable();
+    crate::worker::run_while_idle();
     csr::sstatus::enable_interrupts();
 
     #[rustfmt::skip]
@@ -9,12 +9,15 @@ use core::sync::atomic::Ordering;
 
 use super::{LockedTask, Scheduler, Task, Tid, WakeToken, TASKS};
 use crate::{
+    boot::early_paging::BOOTSTRAP_SATP,
+    cpu_local::PerHart,
     csr::{self, satp::Satp},
-    mem::{self, paging::SATP_MODE},
+    mem,
+    stats::{self, Event},
     task::TaskState,
-    utils::{ticks_per_us, SameHartDeadlockDetection},
+    utils::SameHartDeadlockDetection,
 };
-use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use alloc::{collections::VecDeque, sync::Arc};
 use sync::Lazy;
 
 type SpinMutex<T> = sync::SpinMutex<T, SameHartDeadlockDetection>;
@@ -30,39 +33,60 @@ struct QueuedTask {
 struct Queue {
     active: Option<LockedTask>,
     queue: VecDeque<QueuedTask>,
+    /// Whether tasks can be put on this hart, which stops being the case once
+    /// it's been asked to go offline
+    online: bool,
 }
 
 pub struct RoundRobinScheduler {
     blocked: Lazy<SpinMutex<VecDeque<QueuedTask>>>,
-    queues: Lazy<Vec<SpinMutex<Queue>>>,
+    queues: PerHart<SpinMutex<Queue>>,
 }
 
 impl RoundRobinScheduler {
     pub const fn new() -> Self {
         Self {
             blocked: Lazy::new(|| SpinMutex::new(VecDeque::new())),
-            queues: Lazy::new(|| {
-                let n_cpus = crate::N_CPUS.load(core::sync::atomic::Ordering::Acquire);
-                let mut v = Vec::with_capacity(n_cpus);
-
-                for _ in 0..n_cpus {
-                    v.push(SpinMutex::new(Queue { active: None, queue: VecDeque::with_capacity(16) }));
-                }
-
-                v
+            queues: PerHart::new(|| {
+                SpinMutex::new(Queue { active: None, queue: VecDeque::with_capacity(16), online: true })
             }),
         }
     }
 
     fn current_queue(&self) -> &SpinMutex<Queue> {
-        let current_hart = crate::HART_ID.get();
-        &self.queues[current_hart]
+        self.queues.current()
+    }
+
+    /// Put `task` on the online hart with the fewest tasks queued
+    fn push_to_least_loaded(&self, task: QueuedTask) {
+        loop {
+            let selected = self
+                .queues
+                .iter()
+                .min_by_key(|queue| {
+                    let queue = queue.lock();
+                    (!queue.online, queue.queue.len())
+                })
+                .unwrap_or(&self.queues[0]);
+
+            // The hart could have been taken offline since it was picked, but
+            // there's always at least one left online
+            let mut queue = selected.lock();
+            if queue.online {
+                queue.queue.push_back(task);
+                return;
+            }
+        }
     }
 }
 
 impl Scheduler for RoundRobinScheduler {
     fn schedule(&self) -> ! {
         log::debug!("Starting scheduling");
+
+        #[cfg(feature = "debug.replay")]
+        crate::replay::deliver_interrupts();
+
         let mut queue_lock = self.current_queue().lock();
         let Queue { ref mut active, ref mut queue } = &mut *queue_lock;
         let queue_len = queue.len();
@@ -71,6 +95,22 @@ impl Scheduler for RoundRobinScheduler {
             queue.rotate_left(1);
         }
 
+        // Run whatever the trace ran next, or wait for it to be runnable
+        #[cfg(feature = "debug.replay")]
+        let replayed_runnable = match crate::replay::next_scheduled() {
+            crate::replay::Decision::Run(tid) => {
+                let runnable = queue
+                    .iter()
+                    .position(|queued| queued.tid == tid && matches!(queued.task.lock().state, TaskState::Running));
+                if let Some(index) = runnable {
+                    queue.rotate_left(index);
+                }
+
+                runnable.is_some()
+            }
+            crate::replay::Decision::Free => true,
+        };
+
         let to_run = loop {
             let queued_task = match queue.front_mut() {
                 Some(queued_task) => queued_task,
@@ -80,15 +120,23 @@ impl Scheduler for RoundRobinScheduler {
             let state = queued_task.task.lock().state;
 
             match state {
-                TaskState::Blocked if queue_len > 1 => queue.rotate_left(1),
-                TaskState::Blocked => break None,
-                TaskState::Dead => drop(queue.pop_front()),
+                TaskState::Blocked | TaskState::Suspended | TaskState::Frozen if queue_len > 1 => queue.rotate_left(1),
+                TaskState::Blocked | TaskState::Suspended | TaskState::Frozen => break None,
+                // This hart could still be using the task's page table, so
+                // it can't be the one to free it until it's gone idle
+                TaskState::Dead => {
+                    let dead = queue.pop_front();
+                    crate::worker::defer_pinned(move || drop(dead));
+                }
                 TaskState::Running => {
                     break Some(queued_task);
                 }
             }
         };
 
+        #[cfg(feature = "debug.replay")]
+        let to_run = to_run.filter(|_| replayed_runnable);
+
         match to_run {
             Some(queued_task) => {
                 *active = Some(LockedTask::clone(&queued_task.task));
@@ -99,14 +147,13 @@ impl Scheduler for RoundRobinScheduler {
                 // Drop queue lock here in case the wake needs the scheduler for some reason?
                 drop(queue_lock);
 
-                let root_page_table = task.memory_manager.table_phys_address();
-                let tid = task.tid;
+                #[cfg(feature = "debug.replay")]
+                crate::replay::scheduled(task.tid);
 
                 // FIXME: We need to switch page tables before doing work on the
                 // wake token, but this feels kinda shitty, maybe find a way to
                 // do waking that doesn't need it?
-                csr::satp::write(Satp { mode: SATP_MODE, asid: tid.value() as u16, root_page_table });
-                mem::sfence(None, None);
+                task.memory_manager.activate();
 
                 if let Some(token) = token {
                     (token.work)(&mut task);
@@ -116,13 +163,11 @@ impl Scheduler for RoundRobinScheduler {
                 let context = task.context.clone();
 
                 log::debug!("Scheduling {:?}, pc: {:#p}", task.name, task.context.pc as *mut u8);
-                sbi::timer::set_timer(
-                    csr::time::read() + ticks_per_us(10_000, crate::TIMER_FREQ.load(Ordering::Relaxed)),
-                )
-                .unwrap();
+                super::start_time_slice();
 
                 // !! RELEASE LOCKS BEFORE CONTEXT SWITCHING !!
                 drop(task);
+                stats::count(Event::ContextSwitch);
 
                 unsafe { super::return_to_usermode(&context) }
             }
@@ -133,7 +178,12 @@ impl Scheduler for RoundRobinScheduler {
 
                 log::debug!("No work to do, sleeping :(");
 
+                // The last task to run on this hart could be torn down by
+                // background work while we're idle, so stop using its page
+                // table
+                csr::satp::write(Satp::from_usize(BOOTSTRAP_SATP.load(Ordering::Acquire)));
                 mem::sfence(None, None);
+                mem::shootdown::set_inactive();
 
                 super::sleep()
             }
@@ -144,8 +194,7 @@ impl Scheduler for RoundRobinScheduler {
         let (tid, task) = TASKS.insert(task);
 
         log::debug!("Trying to enqueue task");
-        let selected = self.queues.iter().min_by_key(|queue| queue.lock().queue.len()).unwrap_or(&self.queues[0]);
-        selected.lock().queue.push_back(QueuedTask { tid, task, token: None });
+        self.push_to_least_loaded(QueuedTask { tid, task, token: None });
         log::debug!("Enqueued task");
 
         tid
@@ -155,8 +204,7 @@ impl Scheduler for RoundRobinScheduler {
         let (tid, task) = TASKS.insert_with(f);
 
         log::debug!("Trying to enqueue task");
-        let selected = self.queues.iter().min_by_key(|queue| queue.lock().queue.len()).unwrap_or(&self.queues[0]);
-        selected.lock().queue.push_back(QueuedTask { tid, task, token: None });
+        self.push_to_least_loaded(QueuedTask { tid, task, token: None });
         log::debug!("Enqueued task");
 
         tid
@@ -185,13 +233,40 @@ impl Scheduler for RoundRobinScheduler {
         drop(blocked);
 
         task.token = Some(token);
+        self.push_to_least_loaded(task);
+    }
 
-        let selected = self.queues.iter().min_by_key(|queue| queue.lock().queue.len()).unwrap_or(&self.queues[0]);
-        selected.lock().queue.push_back(task);
+    fn is_blocked(&self, tid: Tid) -> bool {
+        self.blocked.lock().iter().any(|t| t.tid == tid)
+    }
+
+    fn is_ready(&self, tid: Tid) -> bool {
+        self.queues.iter().any(|queue| queue.lock().queue.iter().any(|t| t.tid == tid && t.token.is_none()))
     }
 
     #[track_caller]
     fn active_on_cpu(&self) -> Option<LockedTask> {
         self.current_queue().lock().active.clone()
     }
+
+    fn is_active(&self, task: &LockedTask) -> bool {
+        self.queues.iter().any(|queue| matches!(&queue.lock().active, Some(active) if active.ptr_eq(task)))
+    }
+
+    fn set_hart_online(&self, hart_id: usize, online: bool) {
+        self.queues[hart_id].lock().online = online;
+    }
+
+    fn migrate_tasks(&self) {
+        let tasks = {
+            let mut queue = self.current_queue().lock();
+            queue.active = None;
+            core::mem::take(&mut queue.queue)
+        };
+
+        log::debug!("Moving {} tasks off of hart {}", tasks.len(), crate::HART_ID.get());
+        for task in tasks {
+            self.push_to_least_loaded(task);
+        }
+    }
 }
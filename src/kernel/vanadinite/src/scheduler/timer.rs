@@ -0,0 +1,310 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Per-hart timer wheels
+//!
+//! Timers are always armed on behalf of the task running on the current hart,
+//! so each hart keeps its timers in a wheel only it ever touches, and arming
+//! one takes no locks. A timer can be cancelled from any hart through the
+//! [`TimerHandle`] returned when arming it: the owning hart removes it straight
+//! away, and other harts hand it to the owner through a lock-free queue the
+//! owner empties before firing anything. Each hart also publishes its earliest
+//! deadline, so the earliest deadline across the whole system is a handful of
+//! atomic loads away.
+//!
+//! A hart going offline hands its timers over to one which is staying online.
+//! Cancelling one of those can miss it, since the handle still names the hart
+//! it was armed on, which is no different from a cancellation queue filling up.
+
+use super::{Scheduler, SCHEDULER, TASKS};
+use crate::{cpu_local::PerHart, csr, syscall::channel::ChannelMessage, time, HART_ID};
+use alloc::vec::Vec;
+use core::{
+    cell::RefCell,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use crossbeam_queue::ArrayQueue;
+use librust::{syscalls::channel::KernelMessage, task::Tid};
+use sync::SpinMutex;
+
+const N_SLOTS: usize = 256;
+/// How much time each slot of a wheel covers
+const SLOT_WIDTH: Duration = Duration::from_millis(1);
+/// Cancellations from other harts which can be waiting on a hart at once,
+/// beyond which the timer is left to fire and be ignored
+const CANCEL_QUEUE_SIZE: usize = 256;
+
+#[thread_local]
+static WHEEL: RefCell<TimerWheel> = RefCell::new(TimerWheel::new());
+static HARTS: PerHart<HartTimers> = PerHart::new(HartTimers::new);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Timer {
+    /// A userspace timer, which notifies the task with its ID
+    User(usize),
+    /// The timeout of a futex wait
+    FutexTimeout,
+}
+
+/// Identifies an armed timer, for cancelling it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerHandle {
+    hart: usize,
+    id: u64,
+    deadline: u64,
+}
+
+impl TimerHandle {
+    /// When the timer fires, or fired if it already has
+    pub fn deadline(&self) -> u64 {
+        self.deadline
+    }
+}
+
+/// The parts of a hart's timer state other harts can see
+#[repr(align(64))]
+struct HartTimers {
+    earliest: AtomicU64,
+    cancelled: ArrayQueue<TimerHandle>,
+    /// Timers from harts which went offline, waiting to be put in the wheel
+    handed_off: SpinMutex<Vec<Entry>>,
+}
+
+impl HartTimers {
+    fn new() -> Self {
+        Self {
+            earliest: AtomicU64::new(u64::MAX),
+            cancelled: ArrayQueue::new(CANCEL_QUEUE_SIZE),
+            handed_off: SpinMutex::new(Vec::new()),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    handle: TimerHandle,
+    tid: Tid,
+    timer: Timer,
+}
+
+struct TimerWheel {
+    /// Timers are put in the slot their deadline falls in, modulo the number of
+    /// slots, so a slot can also hold timers for later turns of the wheel
+    slots: [Vec<Entry>; N_SLOTS],
+    /// The slot (counting from when `time` was zero) timers have been fired up
+    /// to
+    current: u64,
+    earliest: u64,
+}
+
+impl TimerWheel {
+    const fn new() -> Self {
+        Self { slots: [const { Vec::new() }; N_SLOTS], current: 0, earliest: u64::MAX }
+    }
+
+    fn insert(&mut self, entry: Entry) {
+        let deadline = entry.handle.deadline;
+        let slot = slot_of(deadline).max(self.current);
+
+        self.slots[slot as usize % N_SLOTS].push(entry);
+        self.earliest = self.earliest.min(deadline);
+    }
+
+    fn remove(&mut self, handle: TimerHandle) {
+        let slot = &mut self.slots[slot_of(handle.deadline).max(self.current) as usize % N_SLOTS];
+        match slot.iter().position(|entry| entry.handle == handle) {
+            Some(index) => drop(slot.swap_remove(index)),
+            // Timers armed for a slot which had already been passed are put in
+            // the current one instead
+            None => self.slots.iter_mut().for_each(|slot| slot.retain(|entry| entry.handle != handle)),
+        }
+    }
+
+    /// Take out every timer whose deadline is at or before `now`
+    fn expire(&mut self, now: u64) -> Vec<Entry> {
+        let end = slot_of(now).max(self.current);
+        // Going around the wheel once covers every slot
+        let n_slots = (end - self.current + 1).min(N_SLOTS as u64);
+        let mut expired = Vec::new();
+
+        for slot in end + 1 - n_slots..=end {
+            let slot = &mut self.slots[slot as usize % N_SLOTS];
+            let mut i = 0;
+            while i < slot.len() {
+                match slot[i].handle.deadline <= now {
+                    true => expired.push(slot.swap_remove(i)),
+                    false => i += 1,
+                }
+            }
+        }
+
+        self.current = end;
+        self.earliest = self.slots.iter().flatten().map(|entry| entry.handle.deadline).min().unwrap_or(u64::MAX);
+
+        expired
+    }
+}
+
+fn slot_of(deadline: u64) -> u64 {
+    deadline / time::ticks_from_duration(SLOT_WIDTH).max(1)
+}
+
+fn publish_earliest(earliest: u64) {
+    HARTS.current().earliest.store(earliest, Ordering::Release);
+}
+
+/// Arm a one-shot timer which notifies `tid` with `id` after `micros`
+/// microseconds have passed
+pub fn arm(tid: Tid, id: usize, micros: u64) -> TimerHandle {
+    arm_at(tid, deadline_after(micros), Timer::User(id))
+}
+
+/// Arm a one-shot timer which fires at `deadline`
+pub fn arm_at(tid: Tid, deadline: u64, timer: Timer) -> TimerHandle {
+    let handle = TimerHandle { hart: HART_ID.get(), id: NEXT_ID.fetch_add(1, Ordering::Relaxed), deadline };
+
+    let mut wheel = WHEEL.borrow_mut();
+    wheel.insert(Entry { handle, tid, timer });
+    publish_earliest(wheel.earliest);
+
+    handle
+}
+
+/// Disarm a timer if it hasn't fired yet. Timers armed on another hart can
+/// still fire if that hart has a lot of cancellations queued up already, so
+/// owners need to be able to tell a stale timer from the current one.
+pub fn cancel(handle: TimerHandle) {
+    match handle.hart == HART_ID.get() {
+        true => WHEEL.borrow_mut().remove(handle),
+        false => {
+            if HARTS[handle.hart].cancelled.push(handle).is_err() {
+                log::debug!("Cancellation queue for hart {} is full, letting timer {} fire", handle.hart, handle.id);
+            }
+        }
+    }
+}
+
+/// The deadline `micros` microseconds from now
+pub fn deadline_after(micros: u64) -> u64 {
+    // Userspace controls `micros`, so don't let it overflow the deadline
+    csr::time::read().saturating_add(time::ticks_from_duration(Duration::from_micros(micros)))
+}
+
+/// The point in time this hart should next be interrupted at, which is
+/// whichever comes first of `default`, the earliest deadline of its timers, or
+/// the next profiler sample
+pub fn next_deadline(default: u64) -> u64 {
+    WHEEL.borrow().earliest.min(default).min(crate::profiler::next_sample())
+}
+
+/// Whether any of this hart's timers are due to fire
+pub fn any_expired() -> bool {
+    WHEEL.borrow().earliest <= csr::time::read()
+}
+
+/// The earliest deadline of any timer on any hart, or `u64::MAX` if there are
+/// none, for deciding how long the system can stay idle for. Cancelled timers
+/// can still count towards it until their hart next fires its timers.
+pub fn earliest_deadline() -> u64 {
+    HARTS.iter().map(|hart| hart.earliest.load(Ordering::Acquire)).min().unwrap_or(u64::MAX)
+}
+
+/// Hand every timer on this hart over to `hart_id`, for when this hart is going
+/// offline. The other hart picks them up the next time it fires its timers or
+/// calls [`adopt_handed_off`].
+pub fn hand_off(hart_id: usize) {
+    let entries = {
+        let mut wheel = WHEEL.borrow_mut();
+        while let Some(handle) = HARTS.current().cancelled.pop() {
+            wheel.remove(handle);
+        }
+
+        let entries = wheel.slots.iter_mut().flat_map(core::mem::take).collect::<Vec<_>>();
+        wheel.earliest = u64::MAX;
+        publish_earliest(u64::MAX);
+
+        entries
+    };
+
+    let earliest = entries.iter().map(|entry| entry.handle.deadline).min().unwrap_or(u64::MAX);
+    HARTS[hart_id].handed_off.lock().extend(entries);
+    HARTS[hart_id].earliest.fetch_min(earliest, Ordering::AcqRel);
+}
+
+/// Put any timers handed over by harts which went offline in this hart's
+/// wheel, returning whether there were any
+pub fn adopt_handed_off() -> bool {
+    let mut wheel = WHEEL.borrow_mut();
+    let adopted = adopt(&mut wheel);
+    publish_earliest(wheel.earliest);
+
+    adopted
+}
+
+fn adopt(wheel: &mut TimerWheel) -> bool {
+    let handed_off = core::mem::take(&mut *HARTS.current().handed_off.lock());
+    let adopted = !handed_off.is_empty();
+    for entry in handed_off {
+        wheel.insert(entry);
+    }
+
+    adopted
+}
+
+/// Notify the owners of any of this hart's timers whose deadlines have passed.
+/// This must not be called while holding a task lock.
+pub fn fire_expired() {
+    let now = csr::time::read();
+
+    let expired = {
+        let mut wheel = WHEEL.borrow_mut();
+        adopt(&mut wheel);
+        while let Some(handle) = HARTS.current().cancelled.pop() {
+            wheel.remove(handle);
+        }
+
+        let expired = wheel.expire(now);
+        publish_earliest(wheel.earliest);
+
+        expired
+    };
+
+    for Entry { handle, tid, timer } in expired {
+        let id = match timer {
+            Timer::User(id) => id,
+            Timer::FutexTimeout => {
+                crate::futex::time_out(tid, handle);
+                continue;
+            }
+        };
+
+        // The task might have exited before its timer fired
+        let task = match TASKS.get(tid) {
+            Some(task) => task,
+            None => continue,
+        };
+
+        let task = task.lock();
+        log::debug!("Timer {} expired for task {}", id, task.name);
+
+        let mut send_lock = task.kernel_channel.sender.inner.write();
+        send_lock.push_back(ChannelMessage {
+            data: Into::into(KernelMessage::TimerExpired(id)),
+            caps: Vec::new(),
+            charge: None,
+        });
+
+        let token = task.kernel_channel.sender.wake.lock().take();
+        if let Some(token) = token {
+            drop(send_lock);
+            drop(task);
+            SCHEDULER.unblock(token);
+        }
+    }
+}
@@ -0,0 +1,140 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Event counters
+//!
+//! Each hart counts events in its own block of counters, which is padded out
+//! to a cache line so that counting is a relaxed increment on memory no other
+//! hart writes to. The counters are only summed up when they're read.
+
+use crate::{
+    cpu_local::PerHart,
+    mem::{
+        heap::HEAP_ALLOCATOR,
+        phys::{PhysicalMemoryAllocator, Zone, PHYSICAL_MEMORY_ALLOCATOR},
+    },
+    utils::Units,
+    HART_ID,
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+use librust::syscalls::stats::{KernelStats, MemoryStats, STATS_INTERRUPT_SOURCES, STATS_SYSCALLS};
+
+static HARTS: PerHart<HartCounters> = PerHart::new(HartCounters::new);
+
+#[derive(Debug, Clone, Copy)]
+pub enum Event {
+    ContextSwitch,
+    IpcMessage,
+    TimerInterrupt,
+    /// With the interrupt ID
+    ExternalInterrupt(usize),
+    LoadPageFault,
+    StorePageFault,
+    InstructionPageFault,
+    /// With the syscall number
+    Syscall(usize),
+}
+
+#[repr(align(64))]
+struct HartCounters {
+    context_switches: AtomicU64,
+    ipc_messages: AtomicU64,
+    timer_interrupts: AtomicU64,
+    external_interrupts: [AtomicU64; STATS_INTERRUPT_SOURCES],
+    load_page_faults: AtomicU64,
+    store_page_faults: AtomicU64,
+    instruction_page_faults: AtomicU64,
+    syscalls: [AtomicU64; STATS_SYSCALLS],
+}
+
+impl HartCounters {
+    fn new() -> Self {
+        Self {
+            context_switches: AtomicU64::new(0),
+            ipc_messages: AtomicU64::new(0),
+            timer_interrupts: AtomicU64::new(0),
+            external_interrupts: [(); STATS_INTERRUPT_SOURCES].map(|_| AtomicU64::new(0)),
+            load_page_faults: AtomicU64::new(0),
+            store_page_faults: AtomicU64::new(0),
+            instruction_page_faults: AtomicU64::new(0),
+            syscalls: [(); STATS_SYSCALLS].map(|_| AtomicU64::new(0)),
+        }
+    }
+
+    fn counter(&self, event: Event) -> Option<&AtomicU64> {
+        match event {
+            Event::ContextSwitch => Some(&self.context_switches),
+            Event::IpcMessage => Some(&self.ipc_messages),
+            Event::TimerInterrupt => Some(&self.timer_interrupts),
+            Event::ExternalInterrupt(id) => Some(&self.external_interrupts[id.min(STATS_INTERRUPT_SOURCES - 1)]),
+            Event::LoadPageFault => Some(&self.load_page_faults),
+            Event::StorePageFault => Some(&self.store_page_faults),
+            Event::InstructionPageFault => Some(&self.instruction_page_faults),
+            Event::Syscall(n) => self.syscalls.get(n),
+        }
+    }
+
+    fn add_to(&self, stats: &mut KernelStats) {
+        let read = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        stats.context_switches += read(&self.context_switches);
+        stats.ipc_messages += read(&self.ipc_messages);
+        stats.timer_interrupts += read(&self.timer_interrupts);
+        for (total, counter) in stats.external_interrupts.iter_mut().zip(&self.external_interrupts) {
+            *total += read(counter);
+        }
+        stats.load_page_faults += read(&self.load_page_faults);
+        stats.store_page_faults += read(&self.store_page_faults);
+        stats.instruction_page_faults += read(&self.instruction_page_faults);
+        for (total, counter) in stats.syscalls.iter_mut().zip(&self.syscalls) {
+            *total += read(counter);
+        }
+    }
+}
+
+/// Count an event on the current hart
+pub fn count(event: Event) {
+    if let Some(counter) = HARTS.get(HART_ID.get()).and_then(|hart| hart.counter(event)) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The counters of `hart`, or the totals of every hart if `None`, returning
+/// `None` if there's no such hart
+pub fn snapshot(hart: Option<usize>) -> Option<KernelStats> {
+    let mut stats = KernelStats::new();
+    match hart {
+        Some(hart) => HARTS.get(hart)?.add_to(&mut stats),
+        None => HARTS.iter().for_each(|hart| hart.add_to(&mut stats)),
+    }
+
+    Some(stats)
+}
+
+pub fn n_harts() -> usize {
+    HARTS.len()
+}
+
+/// How much physical memory and kernel heap there is and how much is in use,
+/// leaving the resident size for the caller to fill in
+pub fn memory() -> MemoryStats {
+    let mut stats = MemoryStats::default();
+    {
+        let mut allocator = PHYSICAL_MEMORY_ALLOCATOR.lock();
+        for zone in Zone::ALL {
+            let zone_stats = allocator.zone_stats(zone);
+            stats.total += (zone_stats.total_pages * 4.kib()) as u64;
+            stats.free += (zone_stats.free_pages * 4.kib()) as u64;
+        }
+    }
+
+    let heap = HEAP_ALLOCATOR.stats();
+    stats.heap_total = heap.total as u64;
+    stats.heap_allocated = heap.allocated as u64;
+
+    stats
+}
@@ -0,0 +1,229 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Suspending the system to RAM
+//!
+//! The hart a suspend is requested on does all of the work. Every other hart
+//! is sent an IPI and parks itself in an SBI HSM retentive suspend, with only
+//! software interrupts left enabled so nothing but the IPI waking it back up
+//! can get it out. Drivers which registered a [`SuspendResume`] then save the
+//! state of their devices, and the requesting hart suspends itself until an
+//! external interrupt or the wakeup timer arrives. Waking up happens in
+//! reverse.
+//!
+//! Retentive suspends keep all of the hart's state, so the call to suspend
+//! simply returns on wakeup and everything carries on where it left off. When
+//! the firmware doesn't support them, harts wait with `wfi` instead, which
+//! saves less power but wakes up the same way.
+
+use crate::{
+    csr,
+    drivers::SuspendResume,
+    hotplug::{self, send_ipi},
+    utils::ticks_per_us,
+    watchdog, HART_ID,
+};
+use alloc::{boxed::Box, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use sync::SpinMutex;
+
+/// How long the other harts have to park themselves before giving up on
+/// suspending
+const PARK_TIMEOUT_US: u64 = 100_000;
+
+const HSM_EXTENSION: usize = 0x48534D;
+const HSM_HART_SUSPEND: usize = 3;
+/// The default retentive suspend type, which keeps all of the hart's state
+const DEFAULT_RETENTIVE_SUSPEND: usize = 0;
+
+const SOFTWARE_INTERRUPT: usize = 1 << 1;
+const TIMER_INTERRUPT: usize = 1 << 5;
+const EXTERNAL_INTERRUPT: usize = 1 << 9;
+
+static DRIVERS: SpinMutex<Vec<RegisteredDriver>> = SpinMutex::new(Vec::new());
+/// Held for the whole suspend, so only one can happen at a time
+static SUSPENDING: SpinMutex<()> = SpinMutex::new(());
+/// Whether the other harts should stay parked
+static PARK: AtomicBool = AtomicBool::new(false);
+static PARKED: AtomicUsize = AtomicUsize::new(0);
+
+struct RegisteredDriver {
+    name: &'static str,
+    device: Box<dyn SuspendResume>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SuspendError {
+    /// Another hart is already suspending the system
+    AlreadySuspending,
+    /// Some other hart didn't park itself in time, likely because it's been
+    /// running with interrupts disabled
+    HartsBusy,
+    /// A driver couldn't suspend its device
+    DeviceFailed(&'static str),
+}
+
+/// Have `device` suspended and resumed along with the system. Devices are
+/// suspended in the order they're registered, and resumed in reverse.
+pub fn register(name: &'static str, device: Box<dyn SuspendResume>) {
+    DRIVERS.lock().push(RegisteredDriver { name, device });
+}
+
+/// Suspend the system until an external interrupt arrives, or until
+/// `wake_after_us` microseconds have passed if it's given. This must be called
+/// with interrupts disabled.
+pub fn suspend(wake_after_us: Option<u64>) -> Result<(), SuspendError> {
+    let _suspending = SUSPENDING.try_lock().ok_or(SuspendError::AlreadySuspending)?;
+    let ticks_per_us = ticks_per_us(1, crate::TIMER_FREQ.load(Ordering::Relaxed));
+
+    let current = HART_ID.get();
+    let others = hotplug::online_harts().into_iter().filter(|&hart| hart != current).collect::<Vec<_>>();
+
+    log::info!("Suspending the system");
+
+    PARKED.store(0, Ordering::Release);
+    PARK.store(true, Ordering::Release);
+    for &hart in &others {
+        send_ipi(hart);
+    }
+
+    let deadline = csr::time::read() + PARK_TIMEOUT_US * ticks_per_us;
+    while PARKED.load(Ordering::Acquire) < others.len() {
+        if csr::time::read() >= deadline {
+            log::warn!(
+                "Only {} of {} harts parked in time, staying awake",
+                PARKED.load(Ordering::Acquire),
+                others.len()
+            );
+            unpark(&others);
+            return Err(SuspendError::HartsBusy);
+        }
+
+        core::hint::spin_loop();
+    }
+
+    let mut drivers = DRIVERS.lock();
+    let mut suspended = 0;
+    let mut failed = None;
+    for driver in drivers.iter_mut() {
+        match driver.device.suspend() {
+            Ok(()) => suspended += 1,
+            Err(e) => {
+                failed = Some((driver.name, e));
+                break;
+            }
+        }
+    }
+
+    if let Some((name, e)) = failed {
+        log::warn!("Failed to suspend {}: {}, staying awake", name, e);
+        for driver in drivers[..suspended].iter_mut().rev() {
+            driver.device.resume();
+        }
+
+        unpark(&others);
+        return Err(SuspendError::DeviceFailed(name));
+    }
+
+    let wake_at = wake_after_us.map(|us| csr::time::read().saturating_add(us.saturating_mul(ticks_per_us)));
+    // The hardware watchdog can't be stopped, so the hart has to wake up
+    // every so often to keep it from resetting the system
+    let pet_interval = ticks_per_us * watchdog::HARDWARE_TIMEOUT_US / 2;
+
+    csr::sie::write(EXTERNAL_INTERRUPT | TIMER_INTERRUPT);
+    loop {
+        let timer = match watchdog::has_hardware() {
+            true => wake_at.unwrap_or(u64::MAX).min(csr::time::read().saturating_add(pet_interval)),
+            false => wake_at.unwrap_or(u64::MAX),
+        };
+        sbi::timer::set_timer(timer).unwrap();
+
+        retentive_suspend();
+
+        let now = csr::time::read();
+        if csr::sip::read() & EXTERNAL_INTERRUPT != 0 || wake_at.map_or(false, |wake_at| now >= wake_at) {
+            break;
+        }
+
+        watchdog::pet_hardware();
+    }
+
+    // Anything left pending is handled once interrupts are back on
+    csr::sie::enable();
+    for driver in drivers.iter_mut().rev() {
+        driver.device.resume();
+    }
+    drop(drivers);
+
+    // Time kept going while suspended, so nobody should be blamed for not
+    // petting the watchdog in the meantime
+    watchdog::pet_all();
+    unpark(&others);
+
+    // Fire right away so the scheduler sets up its next deadline
+    sbi::timer::set_timer(csr::time::read()).unwrap();
+
+    log::info!("Resumed from suspend");
+
+    Ok(())
+}
+
+/// Park this hart until the suspend which sent it an IPI is over. This is
+/// called on every supervisor software interrupt.
+pub fn park_if_requested() {
+    if !PARK.load(Ordering::Acquire) {
+        return;
+    }
+
+    let sie = csr::sie::read();
+    csr::sie::write(SOFTWARE_INTERRUPT);
+    sbi::timer::set_timer(u64::MAX).unwrap();
+    PARKED.fetch_add(1, Ordering::AcqRel);
+
+    while PARK.load(Ordering::Acquire) {
+        retentive_suspend();
+        csr::sip::clear_software_interrupt();
+        // The hart suspending the system can still change mappings
+        crate::mem::shootdown::handle_requests();
+    }
+
+    csr::sie::write(sie);
+    sbi::timer::set_timer(csr::time::read()).unwrap();
+}
+
+fn unpark(others: &[usize]) {
+    PARK.store(false, Ordering::Release);
+    for &hart in others {
+        send_ipi(hart);
+    }
+}
+
+/// Suspend the current hart until an interrupt enabled in `sie` is pending,
+/// even with interrupts disabled
+fn retentive_suspend() {
+    if sbi_call(HSM_EXTENSION, HSM_HART_SUSPEND, DEFAULT_RETENTIVE_SUSPEND, 0, 0) != 0 {
+        unsafe { core::arch::asm!("wfi") };
+    }
+}
+
+/// Make a raw SBI call, returning its error code
+pub fn sbi_call(extension: usize, function: usize, a0: usize, a1: usize, a2: usize) -> isize {
+    let error: isize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") a0 => error,
+            inlateout("a1") a1 => _,
+            in("a2") a2,
+            in("a6") function,
+            in("a7") extension,
+        );
+    }
+
+    error
+}
@@ -0,0 +1,170 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Kernel symbol lookup
+//!
+//! `xtask` generates a table of every function in the kernel after building it,
+//! then builds the kernel again with the table embedded in its own section at
+//! the end of the image (see `xtask/src/symbols.rs` for the format). Since it's
+//! placed after everything else, filling the table in doesn't move any of the
+//! addresses it describes.
+
+use crate::utils::LinkerSymbol;
+use core::fmt;
+
+const MAGIC: &[u8] = b"VSYM";
+const MAX_NAME_LEN: usize = 255;
+/// Frames past this are most likely garbage from a corrupted stack
+const MAX_FRAMES: usize = 32;
+/// The start of the upper half of the address space the kernel lives in
+const KERNEL_HALF: usize = 0xFFFF_FFC0_0000_0000;
+
+#[used]
+#[link_section = ".kernel_symbols"]
+static SYMBOLS: [u8; include_bytes!("../../../../build/kernel.symbols").len()] =
+    *include_bytes!("../../../../build/kernel.symbols");
+
+extern "C" {
+    static __kernel_symbols_start: LinkerSymbol;
+    static __kernel_symbols_end: LinkerSymbol;
+}
+
+/// The embedded table, read through the linker symbols rather than `SYMBOLS`
+/// so the contents of the table can't change how anything else is compiled
+fn table() -> &'static [u8] {
+    unsafe {
+        let start = __kernel_symbols_start.as_ptr();
+        core::slice::from_raw_parts(start, __kernel_symbols_end.as_usize() - start as usize)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Symbol {
+    name: [u8; MAX_NAME_LEN],
+    len: usize,
+    pub start: usize,
+    pub size: usize,
+}
+
+impl Symbol {
+    pub fn name(&self) -> &str {
+        // Names are cut off at a fixed length, which can land in the middle of
+        // a character
+        match core::str::from_utf8(&self.name[..self.len]) {
+            Ok(name) => name,
+            Err(e) => unsafe { core::str::from_utf8_unchecked(&self.name[..e.valid_up_to()]) },
+        }
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Symbol")
+            .field("name", &self.name())
+            .field("start", &format_args!("{:#x}", self.start))
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
+/// An address resolved to the function it's in
+#[derive(Debug, Clone, Copy)]
+pub struct Resolved {
+    pub symbol: Symbol,
+    pub offset: usize,
+}
+
+impl fmt::Display for Resolved {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}+{:#x}", self.symbol.name(), self.offset)
+    }
+}
+
+/// Find the function `addr` is in, if there's a symbol table and the address
+/// falls inside of one
+pub fn resolve(addr: usize) -> Option<Resolved> {
+    let mut entries = table().strip_prefix(MAGIC)?;
+    let mut symbol = Symbol { name: [0; MAX_NAME_LEN], len: 0, start: 0, size: 0 };
+    let mut found = None;
+
+    while !entries.is_empty() {
+        let delta = leb128(&mut entries)?;
+        let size = leb128(&mut entries)?;
+        let (&shared, rest) = entries.split_first()?;
+        let (&suffix_len, rest) = rest.split_first()?;
+        let suffix = rest.get(..usize::from(suffix_len))?;
+        entries = &rest[suffix.len()..];
+
+        let shared = usize::from(shared).min(symbol.len);
+        let len = (shared + suffix.len()).min(MAX_NAME_LEN);
+        symbol.name[shared..len].copy_from_slice(&suffix[..len - shared]);
+        symbol.len = len;
+        symbol.start += delta;
+        symbol.size = size;
+
+        // Entries are sorted by address, so nothing after this can match
+        if symbol.start > addr {
+            break;
+        }
+
+        if addr < symbol.start + symbol.size {
+            found = Some(Resolved { symbol, offset: addr - symbol.start });
+        }
+    }
+
+    found
+}
+
+fn leb128(bytes: &mut &[u8]) -> Option<usize> {
+    let mut n = 0usize;
+    let mut shift = 0;
+
+    loop {
+        let (&byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+
+        n |= usize::from(byte & 0x7F).checked_shl(shift)?;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return Some(n);
+        }
+    }
+}
+
+/// Walk the kernel stack from the caller's frame, returning the return address
+/// of each frame. This relies on the kernel being built with frame pointers,
+/// where the frame pointer sits just above the saved return address and
+/// previous frame pointer.
+pub fn backtrace() -> impl Iterator<Item = usize> {
+    backtrace_from(crate::asm::fp() as usize)
+}
+
+/// Walk the kernel stack like [`backtrace`], starting from the frame `fp`
+/// points to instead of the caller's
+pub fn backtrace_from(mut fp: usize) -> impl Iterator<Item = usize> {
+    core::iter::from_fn(move || {
+        if fp < KERNEL_HALF || fp % 8 != 0 {
+            return None;
+        }
+
+        let (prev_fp, ra) = unsafe { (*(fp as *const usize).sub(2), *(fp as *const usize).sub(1)) };
+
+        // The stack grows down, so going up through the callers has to keep
+        // going up the stack
+        fp = match prev_fp > fp {
+            true => prev_fp,
+            false => 0,
+        };
+
+        match ra {
+            0 => None,
+            ra => Some(ra),
+        }
+    })
+    .take(MAX_FRAMES)
+}
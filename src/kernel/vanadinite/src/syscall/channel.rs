@@ -5,6 +5,7 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
+use super::snoop::Snooper;
 use crate::{
     capabilities::{Capability, CapabilityResource},
     interrupts::PLIC,
@@ -13,18 +14,19 @@ use crate::{
         user::{self, RawUserSlice},
     },
     scheduler::{Scheduler, WakeToken, SCHEDULER, TASKS},
+    stats::{self, Event},
     task::Task,
     trap::GeneralRegisters,
     HART_ID,
 };
 use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use librust::{
     capabilities::{CapabilityPtr, CapabilityRights},
     error::SyscallError,
     syscalls::{
-        channel::{ChannelReadFlags, KernelMessage},
-        mem::MemoryPermissions,
+        channel::{ChannelReadFlags, ChannelSendFlags, KernelMessage, MAX_MESSAGE_CAPS},
+        mem::{MemoryPermissions, SealFlags},
     },
     task::Tid,
 };
@@ -38,8 +40,14 @@ pub struct UserspaceChannel {
 
 impl UserspaceChannel {
     pub fn new() -> (Self, Self) {
+        Self::with_depth(DEFAULT_QUEUE_DEPTH)
+    }
+
+    /// Create a channel whose queues hold up to `depth` messages of each
+    /// priority sent from userspace
+    pub fn with_depth(depth: usize) -> (Self, Self) {
         let (sender1, receiver1) = {
-            let message_queue = Arc::new(SpinRwLock::new(VecDeque::new()));
+            let message_queue = Arc::new(SpinRwLock::new(MessageQueue::new(depth)));
             let alive = Arc::new(AtomicBool::new(true));
             let wake = Arc::new(SpinMutex::new(None));
 
@@ -56,7 +64,7 @@ impl UserspaceChannel {
         };
 
         let (sender2, receiver2) = {
-            let message_queue = Arc::new(SpinRwLock::new(VecDeque::new()));
+            let message_queue = Arc::new(SpinRwLock::new(MessageQueue::new(depth)));
             let alive = Arc::new(AtomicBool::new(true));
             let wake = Arc::new(SpinMutex::new(None));
 
@@ -77,18 +85,199 @@ impl UserspaceChannel {
 
         (first, second)
     }
+
+    /// Queue `message` on a task's kernel channel, waking the task up if it's
+    /// waiting on it. Unlike going through the task itself, this doesn't need
+    /// the task to be locked.
+    pub fn send_kernel_message(&self, message: KernelMessage) {
+        self.sender.inner.write().push_back(ChannelMessage {
+            data: message.into_parts(),
+            caps: Vec::new(),
+            charge: None,
+        });
+
+        let token = self.sender.wake.lock().take();
+        if let Some(token) = token {
+            SCHEDULER.unblock(token);
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct ChannelMessage {
     pub data: [usize; 7],
     pub caps: Vec<Capability>,
+    /// What the message counts against its sender's [`IpcQuota`] for, if it
+    /// was sent from userspace
+    pub charge: Option<QuotaCharge>,
+}
+
+impl ChannelMessage {
+    /// Roughly how much kernel memory the message takes up while it's queued
+    fn size(caps: usize) -> usize {
+        core::mem::size_of::<Self>() + caps * core::mem::size_of::<Capability>()
+    }
+}
+
+/// How much kernel memory a task's sent messages can take up while they're
+/// waiting to be read, unless it's given a different quota
+pub const DEFAULT_IPC_QUOTA: usize = 1024 * 1024;
+
+/// Limits how much kernel memory the messages a task has sent but which haven't
+/// been read yet can take up, so one task flooding a channel that's being read
+/// slowly can't run the kernel out of heap. This counts across every channel
+/// the task sends on, unlike the depth of a channel's queue.
+#[derive(Debug)]
+pub struct IpcQuota {
+    used: AtomicUsize,
+    limit: usize,
+}
+
+impl IpcQuota {
+    pub fn new(limit: usize) -> Self {
+        Self { used: AtomicUsize::new(0), limit }
+    }
+
+    /// Count `bytes` against the quota, returning `None` if it would go over.
+    /// The bytes are given back once the returned charge is dropped.
+    pub fn charge(self: &Arc<Self>, bytes: usize) -> Option<QuotaCharge> {
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(bytes).filter(|&used| used <= self.limit)
+            })
+            .ok()?;
+
+        Some(QuotaCharge { quota: Arc::clone(self), bytes })
+    }
+}
+
+#[derive(Debug)]
+pub struct QuotaCharge {
+    quota: Arc<IpcQuota>,
+    bytes: usize,
+}
+
+impl Drop for QuotaCharge {
+    fn drop(&mut self) {
+        self.quota.used.fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
+
+/// Charge the message `caps` will be sent with to `quota`
+pub(super) fn charge_message(quota: &Arc<IpcQuota>, caps: &[Capability]) -> Result<QuotaCharge, SyscallError> {
+    quota.charge(ChannelMessage::size(caps.len())).ok_or(SyscallError::QuotaExceeded)
+}
+
+/// How many messages of each priority a channel queues up before sends from
+/// userspace start failing, unless the receiver picks a different depth
+pub const DEFAULT_QUEUE_DEPTH: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessagePriority {
+    Normal,
+    /// Read before any normal messages, for control messages like
+    /// cancellations which shouldn't wait behind bulk data
+    Urgent,
+}
+
+/// The messages waiting to be read on one side of a channel
+///
+/// Each priority holds up to `depth` messages sent from userspace. Messages
+/// pushed by the kernel itself aren't limited, since there's nobody to hand the
+/// failure back to.
+#[derive(Debug)]
+pub struct MessageQueue {
+    urgent: VecDeque<ChannelMessage>,
+    normal: VecDeque<ChannelMessage>,
+    depth: usize,
+    /// Who messages sent from userspace are reported to as they're queued
+    snoopers: Vec<Snooper>,
+}
+
+impl MessageQueue {
+    pub fn new(depth: usize) -> Self {
+        Self { urgent: VecDeque::new(), normal: VecDeque::new(), depth, snoopers: Vec::new() }
+    }
+
+    pub fn push_back(&mut self, message: ChannelMessage) {
+        self.normal.push_back(message);
+    }
+
+    pub fn push_urgent(&mut self, message: ChannelMessage) {
+        self.urgent.push_back(message);
+    }
+
+    /// Put `message` back so it's the next one read
+    pub fn push_front(&mut self, message: ChannelMessage) {
+        self.urgent.push_front(message);
+    }
+
+    pub fn pop_front(&mut self) -> Option<ChannelMessage> {
+        self.urgent.pop_front().or_else(|| self.normal.pop_front())
+    }
+
+    /// Change how many messages of each priority can be queued. Messages
+    /// already queued past the new depth are kept.
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+    }
+
+    /// Throw away the oldest unread message of the given priority
+    pub fn pop_oldest(&mut self, priority: MessagePriority) -> Option<ChannelMessage> {
+        match priority {
+            MessagePriority::Normal => self.normal.pop_front(),
+            MessagePriority::Urgent => self.urgent.pop_front(),
+        }
+    }
+
+    pub(super) fn try_push(
+        &mut self,
+        message: ChannelMessage,
+        priority: MessagePriority,
+    ) -> Result<(), ChannelMessage> {
+        let queue = match priority {
+            MessagePriority::Normal => &mut self.normal,
+            MessagePriority::Urgent => &mut self.urgent,
+        };
+
+        match queue.len() < self.depth {
+            true => Ok(queue.push_back(message)),
+            false => Err(message),
+        }
+    }
+
+    /// Whether `n` more messages of the given priority fit under the depth
+    pub(super) fn has_room_for(&self, n: usize, priority: MessagePriority) -> bool {
+        let queue = match priority {
+            MessagePriority::Normal => &self.normal,
+            MessagePriority::Urgent => &self.urgent,
+        };
+
+        queue.len() + n <= self.depth
+    }
+
+    pub(super) fn add_snooper(&mut self, snooper: Snooper) {
+        self.snoopers.push(snooper);
+    }
+
+    /// Report a message which was just queued to everyone snooping on the queue
+    fn snoop(&mut self, data: [usize; 7], caps: usize, priority: MessagePriority) {
+        self.snoopers.retain_mut(|snooper| snooper.report(data, caps, priority));
+    }
+}
+
+#[derive(Debug)]
+pub enum SendError {
+    /// The other side of the channel is gone
+    Dead(ChannelMessage),
+    /// The other side has as many messages of the priority queued as it allows
+    Full(ChannelMessage),
 }
 
 #[derive(Debug, Clone)]
 pub(super) struct Receiver {
     // FIXME: Replace these with something like a lockfree ring buffer
-    pub(super) inner: Arc<SpinRwLock<VecDeque<ChannelMessage>>>,
+    pub(super) inner: Arc<SpinRwLock<MessageQueue>>,
     pub(super) alive: Arc<AtomicBool>,
     pub(super) wake: Arc<SpinMutex<Option<WakeToken>>>,
 }
@@ -120,7 +309,7 @@ impl Drop for Receiver {
 #[derive(Debug, Clone)]
 pub struct Sender {
     // FIXME: Replace these with something like a lockfree ring buffer
-    pub(super) inner: Arc<SpinRwLock<VecDeque<ChannelMessage>>>,
+    pub(super) inner: Arc<SpinRwLock<MessageQueue>>,
     pub(super) alive: Arc<AtomicBool>,
     pub(super) wake: Arc<SpinMutex<Option<WakeToken>>>,
     pub(super) other_tid: Option<Tid>,
@@ -128,16 +317,25 @@ pub struct Sender {
 }
 
 impl Sender {
-    fn try_send(&self, message: ChannelMessage) -> Result<(), ChannelMessage> {
+    pub(super) fn try_send(&self, message: ChannelMessage, priority: MessagePriority) -> Result<(), SendError> {
         if !self.alive.load(Ordering::Acquire) {
             log::debug!("Channel to {:?}:{:?} is dead", self.other_tid, self.other_cptr);
-            return Err(message);
+            return Err(SendError::Dead(message));
         }
 
-        // FIXME: set a buffer limit at some point
+        let (data, caps) = (message.data, message.caps.len());
         let mut lock = self.inner.write();
 
-        lock.push_back(message);
+        if let Err(message) = lock.try_push(message, priority) {
+            log::debug!("Channel to {:?}:{:?} is full", self.other_tid, self.other_cptr);
+            return Err(SendError::Full(message));
+        }
+
+        lock.snoop(data, caps, priority);
+
+        #[cfg(feature = "debug.replay")]
+        crate::replay::message_sent(self.other_tid, self.other_cptr);
+
         if let Some(token) = self.wake.lock().take() {
             log::debug!("Waking other side of channel [{:?}:{:?}]", self.other_tid, self.other_cptr);
             SCHEDULER.unblock(token);
@@ -147,10 +345,17 @@ impl Sender {
             let task = task.lock();
             if task.subscribes_to_events {
                 log::debug!("Enqueuing kernel message for other cptr [{}:{:?}]", task.name, self.other_cptr);
-                task.kernel_channel.sender.try_send(ChannelMessage {
+                // The message itself has been queued already, so there's
+                // nothing to report back if the notification can't be
+                let notification = ChannelMessage {
                     data: KernelMessage::into_parts(KernelMessage::NewChannelMessage(self.other_cptr)),
                     caps: Vec::new(),
-                })?;
+                    charge: None,
+                };
+
+                if let Err(e) = task.kernel_channel.sender.try_send(notification, MessagePriority::Normal) {
+                    log::warn!("Couldn't notify task {} of a new channel message: {:?}", task.name, e);
+                }
             }
         }
 
@@ -169,6 +374,7 @@ pub fn send_message(task: &mut Task, frame: &mut GeneralRegisters) -> Result<(),
     let cptr = CapabilityPtr::new(frame.a1);
     let caps =
         RawUserSlice::<user::Read, librust::capabilities::Capability>::new(VirtualAddress::new(frame.a2), frame.a3);
+    let flags = ChannelSendFlags::new(frame.a4);
     let data = [frame.t0, frame.t1, frame.t2, frame.t3, frame.t4, frame.t5, frame.t6];
 
     let channel = match task.cspace.resolve(cptr) {
@@ -190,42 +396,109 @@ pub fn send_message(task: &mut Task, frame: &mut GeneralRegisters) -> Result<(),
                 Err(_) => return Err(SyscallError::InvalidArgument(3)),
             };
 
-            let cap_slice = cap_slice.guarded();
-
-            // NOTE: A capacity of 2 is used to prevent users from passing us a
-            // (potentially very) large slice of invalid cptrs and causing us to
-            // pre-allocate a large amount of memory that will only potentially
-            // cause heap allocator pressure. Messages are unlikely to contain
-            // more than 1 or 2 caps, so default to 2 as a reasonable
-            // preallocation amount.
-            let mut cloned_caps = Vec::with_capacity(2);
-            for librust::capabilities::Capability { cptr, rights } in cap_slice.iter().copied() {
-                match task.cspace.resolve(cptr) {
-                    Some(cap) if cap.rights.is_superset(rights) && cap.rights & CapabilityRights::GRANT => {
-                        // Can't allow sending invalid memory permissions
-                        if let CapabilityResource::Memory(..) = &cap.resource {
-                            if cap.rights & CapabilityRights::WRITE && !(cap.rights & CapabilityRights::READ) {
-                                return Err(SyscallError::InvalidArgument(2));
-                            }
-                        }
+            clone_caps_to_send(task, &cap_slice.guarded(), 2)?
+        }
+    };
 
-                        cloned_caps.push(cap.clone())
-                    }
-                    _ => return Err(SyscallError::InvalidArgument(2)),
-                }
-            }
+    let priority = match flags & ChannelSendFlags::URGENT {
+        true => MessagePriority::Urgent,
+        false => MessagePriority::Normal,
+    };
+
+    let charge = charge_message(&task.ipc_quota, &caps)?;
+
+    log::debug!("[{}:{}] Sending {:?} channel message", task.name, task.tid, priority);
+    match channel.sender.try_send(ChannelMessage { data, caps, charge: Some(charge) }, priority) {
+        Ok(()) => {}
+        Err(SendError::Dead(_)) => return Err(SyscallError::InvalidOperation(0)),
+        Err(SendError::Full(_)) => return Err(SyscallError::WouldBlock),
+    }
+    stats::count(Event::IpcMessage);
 
-            cloned_caps
+    Ok(())
+}
+
+/// Set how many messages of each priority can be waiting to be read on this
+/// side of the channel before sends to it fail with
+/// [`SyscallError::WouldBlock`]
+pub fn set_queue_depth(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let cptr = CapabilityPtr::new(regs.a1);
+    let depth = regs.a2;
+
+    let channel = match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::Channel(channel), rights })
+            if *rights & CapabilityRights::READ =>
+        {
+            channel
         }
+        _ => return Err(SyscallError::InvalidArgument(0)),
     };
 
-    log::debug!("[{}:{}] Sending channel message", task.name, task.tid);
-    // FIXME: this should notify the sender the channel is dead if it is
-    channel.sender.try_send(ChannelMessage { data, caps }).unwrap();
+    if depth == 0 {
+        return Err(SyscallError::InvalidArgument(1));
+    }
+
+    log::debug!("[{}:{}:{:?}] Setting channel queue depth to {}", task.name, task.tid, cptr, depth);
+    channel.receiver.inner.write().set_depth(depth);
 
     Ok(())
 }
 
+/// Clone the capabilities `caps` refers to so they can be sent in a message,
+/// checking that they're allowed to be sent with the rights asked for. Nothing
+/// is sent unless every one of them can be. `caps` is argument `arg` of the
+/// syscall.
+pub(super) fn clone_caps_to_send(
+    task: &Task,
+    caps: &[librust::capabilities::Capability],
+    arg: u32,
+) -> Result<Vec<Capability>, SyscallError> {
+    if caps.len() > MAX_MESSAGE_CAPS {
+        return Err(SyscallError::InvalidArgument(arg));
+    }
+
+    // Bounded by the check above, so a large slice of invalid cptrs can't make
+    // us preallocate much memory before it fails
+    let mut cloned_caps = Vec::with_capacity(caps.len());
+    for librust::capabilities::Capability { cptr, rights } in caps.iter().copied() {
+        match task.cspace.resolve(cptr) {
+            Some(cap) if cap.rights.is_superset(rights) && cap.rights & CapabilityRights::GRANT => {
+                // Can't allow sending invalid memory permissions
+                if let CapabilityResource::Memory(.., seals) = &cap.resource {
+                    if cap.rights & CapabilityRights::WRITE && !(cap.rights & CapabilityRights::READ) {
+                        return Err(SyscallError::InvalidArgument(arg));
+                    }
+
+                    if *seals & SealFlags::GRANT {
+                        return Err(SyscallError::InsufficientRights(arg));
+                    }
+
+                    // Memory is sent with only the rights asked for,
+                    // so read-only views of it can be handed out
+                    if !(rights & CapabilityRights::READ) {
+                        return Err(SyscallError::InvalidArgument(arg));
+                    }
+
+                    cloned_caps.push(Capability { resource: cap.resource.clone(), rights });
+                    continue;
+                }
+
+                // Likewise for topics, so tasks can be allowed to subscribe to
+                // one without being able to publish to it
+                if let CapabilityResource::Topic(_) = &cap.resource {
+                    cloned_caps.push(Capability { resource: cap.resource.clone(), rights });
+                    continue;
+                }
+
+                cloned_caps.push(cap.clone())
+            }
+            _ => return Err(SyscallError::InvalidArgument(arg)),
+        }
+    }
+
+    Ok(cloned_caps)
+}
+
 pub fn read_message(task: &mut Task, regs: &mut GeneralRegisters) -> Result<super::Outcome, SyscallError> {
     let cptr = CapabilityPtr::new(regs.a1);
     let cap_buffer = RawUserSlice::<user::ReadWrite, librust::capabilities::CapabilityWithDescription>::new(
@@ -271,7 +544,7 @@ pub fn read_message(task: &mut Task, regs: &mut GeneralRegisters) -> Result<supe
 
             Ok(super::Outcome::Blocked)
         }
-        Some(ChannelMessage { data, mut caps }) => {
+        Some(ChannelMessage { data, mut caps, charge }) => {
             let (caps_written, caps_remaining) = match cap_buffer.len() {
                 0 => (0, caps.len()),
                 len => {
@@ -317,7 +590,7 @@ pub fn read_message(task: &mut Task, regs: &mut GeneralRegisters) -> Result<supe
 
                                 (cptr, librust::capabilities::CapabilityDescription::Channel)
                             }
-                            CapabilityResource::Memory(region, _, kind) => {
+                            CapabilityResource::Memory(shm, _, kind, seals) => {
                                 let mut permissions = MemoryPermissions::new(0);
                                 let mut memflags = flags::VALID | flags::USER;
 
@@ -336,11 +609,10 @@ pub fn read_message(task: &mut Task, regs: &mut GeneralRegisters) -> Result<supe
                                     memflags |= flags::EXECUTE;
                                 }
 
-                                let addr =
-                                    task.memory_manager.apply_shared_region(None, memflags, region.clone(), kind);
+                                let addr = task.memory_manager.map_shared_memory(None, memflags, &shm, kind);
 
                                 let cptr = task.cspace.mint(Capability {
-                                    resource: CapabilityResource::Memory(region, addr.clone(), kind),
+                                    resource: CapabilityResource::Memory(shm, addr.clone(), kind, seals),
                                     rights,
                                 });
 
@@ -376,7 +648,11 @@ pub fn read_message(task: &mut Task, regs: &mut GeneralRegisters) -> Result<supe
                                     plic.set_interrupt_priority(interrupt, 7);
                                     crate::interrupts::isr::register_isr(interrupt, move |plic, _, id| {
                                         plic.disable_interrupt(crate::platform::current_plic_context(), id);
-                                        let task = TASKS.get(tid).unwrap();
+                                        // The owner has exited, so leave the interrupt disabled
+                                        let task = match TASKS.get(tid) {
+                                            Some(task) => task,
+                                            None => return Ok(()),
+                                        };
                                         let mut task = task.lock();
 
                                         log::debug!(
@@ -393,6 +669,7 @@ pub fn read_message(task: &mut Task, regs: &mut GeneralRegisters) -> Result<supe
                                         send_lock.push_back(ChannelMessage {
                                             data: Into::into(KernelMessage::InterruptOccurred(id)),
                                             caps: Vec::new(),
+                                            charge: None,
                                         });
 
                                         let token = task.kernel_channel.sender.wake.lock().take();
@@ -421,6 +698,54 @@ pub fn read_message(task: &mut Task, regs: &mut GeneralRegisters) -> Result<supe
                                     },
                                 )
                             }
+                            CapabilityResource::Power => {
+                                let cptr = task.cspace.mint(Capability { resource: CapabilityResource::Power, rights });
+                                (cptr, librust::capabilities::CapabilityDescription::Power)
+                            }
+                            CapabilityResource::Debug => {
+                                let cptr = task.cspace.mint(Capability { resource: CapabilityResource::Debug, rights });
+                                (cptr, librust::capabilities::CapabilityDescription::Debug)
+                            }
+                            CapabilityResource::Clock => {
+                                let cptr = task.cspace.mint(Capability { resource: CapabilityResource::Clock, rights });
+                                (cptr, librust::capabilities::CapabilityDescription::Clock)
+                            }
+                            CapabilityResource::MemoryPressure => {
+                                let cptr = task
+                                    .cspace
+                                    .mint(Capability { resource: CapabilityResource::MemoryPressure, rights });
+                                (cptr, librust::capabilities::CapabilityDescription::MemoryPressure)
+                            }
+                            CapabilityResource::Console => {
+                                let cptr =
+                                    task.cspace.mint(Capability { resource: CapabilityResource::Console, rights });
+                                (cptr, librust::capabilities::CapabilityDescription::Console)
+                            }
+                            CapabilityResource::Balloon => {
+                                let cptr =
+                                    task.cspace.mint(Capability { resource: CapabilityResource::Balloon, rights });
+                                (cptr, librust::capabilities::CapabilityDescription::Balloon)
+                            }
+                            CapabilityResource::Swap => {
+                                let cptr = task.cspace.mint(Capability { resource: CapabilityResource::Swap, rights });
+                                (cptr, librust::capabilities::CapabilityDescription::Swap)
+                            }
+                            CapabilityResource::TaskGroup(group) => {
+                                let cptr = task
+                                    .cspace
+                                    .mint(Capability { resource: CapabilityResource::TaskGroup(group), rights });
+                                (cptr, librust::capabilities::CapabilityDescription::TaskGroup)
+                            }
+                            CapabilityResource::Topic(topic) => {
+                                let cptr =
+                                    task.cspace.mint(Capability { resource: CapabilityResource::Topic(topic), rights });
+                                (cptr, librust::capabilities::CapabilityDescription::Topic)
+                            }
+                            CapabilityResource::Pipe(end) => {
+                                let cptr =
+                                    task.cspace.mint(Capability { resource: CapabilityResource::Pipe(end), rights });
+                                (cptr, librust::capabilities::CapabilityDescription::Pipe)
+                            }
                         };
 
                         *target = librust::capabilities::CapabilityWithDescription {
@@ -434,7 +759,9 @@ pub fn read_message(task: &mut Task, regs: &mut GeneralRegisters) -> Result<supe
             };
 
             if caps_remaining != 0 {
-                receiver.push_front(ChannelMessage { data: [0; 7], caps });
+                // The caps left over are still taking up memory until they're
+                // read, so they hang onto the whole charge
+                receiver.push_front(ChannelMessage { data: [0; 7], caps, charge });
             }
 
             regs.a1 = caps_written;
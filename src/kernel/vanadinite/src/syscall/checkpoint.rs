@@ -0,0 +1,313 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checkpointing tasks, see [`librust::syscalls::checkpoint`]
+//!
+//! A task other than the caller has to be frozen while it's checkpointed or
+//! restored, and can't be running on another hart, since its registers are
+//! only saved into its context when it's interrupted. Freezing it stops it
+//! from being scheduled again, but a hart already running it only notices at
+//! the end of its time slice, so until then the syscall fails with
+//! [`SyscallError::WouldBlock`] and the task stays frozen for the retry. The
+//! caller keeps track of the tasks it's left frozen like that, which are
+//! thawed if it exits without retrying.
+
+use crate::{
+    mem::{
+        manager::{MemoryManager, SnapshotError},
+        paging::{flags, VirtualAddress},
+        phys2virt,
+        user::{RawUserPtr, RawUserSlice, ReadWrite},
+    },
+    scheduler::{LockedTask, Scheduler, SCHEDULER, TASKS},
+    task::{Context, Task, TaskState},
+    trap::GeneralRegisters,
+};
+use alloc::collections::BTreeSet;
+use core::num::NonZeroUsize;
+use librust::{
+    capabilities::CapabilityPtr,
+    error::SyscallError,
+    syscalls::checkpoint::{CheckpointId, CheckpointRegisters},
+    task::Tid,
+};
+
+/// The registers and memory of a task at some point in time
+pub struct Checkpoint {
+    /// The task the checkpoint was taken of, for logging
+    pub name: alloc::boxed::Box<str>,
+    pub context: Context,
+    pub memory_manager: MemoryManager,
+}
+
+pub fn snapshot_task(task: &mut Task, regs: &mut GeneralRegisters, sepc: usize) -> Result<(), SyscallError> {
+    super::debug::check_debug_capability(task, CapabilityPtr::new(regs.a1))?;
+
+    let tid = NonZeroUsize::new(regs.a2).map(Tid::new).ok_or(SyscallError::InvalidArgument(1))?;
+    let id = CheckpointId::new(task.checkpoint_next_id);
+
+    let checkpoint = match tid == task.tid {
+        // The task picks up from right after this syscall when it's restored,
+        // and can tell it was restored from `a2`
+        true => {
+            let size = task.memory_manager.resident_size();
+            let memory_manager = task.memory_manager.snapshot().map_err(|e| snapshot_failed(&task.name, size, e))?;
+            let mut gp_regs = *regs;
+            gp_regs.a0 = 0;
+            gp_regs.a1 = id.value();
+            gp_regs.a2 = 1;
+
+            let fp_regs = crate::trap::current_fp_registers(task);
+            Checkpoint { name: task.name.clone(), context: Context { gp_regs, fp_regs, pc: sepc + 4 }, memory_manager }
+        }
+        false => {
+            let target = TASKS.get(tid).ok_or(SyscallError::InvalidArgument(1))?;
+            freeze(task, &target)?;
+
+            let mut target = target.lock();
+            let memory_manager = target.memory_manager.snapshot();
+            thaw(&mut target);
+
+            // Swapped out pages can only be brought back in by the task
+            // they belong to, since it has to wait for them
+            let memory_manager = match memory_manager {
+                Ok(memory_manager) => memory_manager,
+                Err(SnapshotError::Swapped) => {
+                    target.memory_manager.take_missing_access();
+                    return Err(SyscallError::InvalidOperation(1));
+                }
+                Err(e) => return Err(snapshot_failed(&target.name, target.memory_manager.resident_size(), e)),
+            };
+
+            Checkpoint { name: target.name.clone(), context: target.context.clone(), memory_manager }
+        }
+    };
+
+    log::debug!("[{}] Took checkpoint {} of {}", task.name, id.value(), checkpoint.name);
+
+    task.checkpoints.insert(id, checkpoint);
+    task.checkpoint_next_id += 1;
+
+    regs.a1 = id.value();
+    regs.a2 = 0;
+
+    Ok(())
+}
+
+/// Restore a task to a checkpoint. When the task restores itself, it has to be
+/// rescheduled to pick up from the checkpoint.
+pub fn restore_task(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    super::debug::check_debug_capability(task, CapabilityPtr::new(regs.a1))?;
+
+    let tid = NonZeroUsize::new(regs.a2).map(Tid::new).ok_or(SyscallError::InvalidArgument(1))?;
+    let id = CheckpointId::new(regs.a3);
+
+    if !task.checkpoints.contains_key(&id) {
+        return Err(SyscallError::InvalidArgument(2));
+    }
+
+    if tid == task.tid {
+        let checkpoint = task.checkpoints.get_mut(&id).unwrap();
+        let size = checkpoint.memory_manager.resident_size();
+        let memory_manager = checkpoint.memory_manager.snapshot().map_err(|e| snapshot_failed(&task.name, size, e))?;
+        let context = checkpoint.context.clone();
+
+        log::debug!("[{}] Restoring itself to checkpoint {}", task.name, id.value());
+        restore(task, memory_manager, context);
+
+        return Ok(());
+    }
+
+    let target = TASKS.get(tid).ok_or(SyscallError::InvalidArgument(1))?;
+    freeze(task, &target)?;
+
+    // A task blocked in a syscall would finish it on top of the restored
+    // registers once it's woken up. Frozen tasks can't start blocking, or be
+    // woken up without having been blocked, so this can't change.
+    if !SCHEDULER.is_ready(tid) {
+        thaw(&mut target.lock());
+        return Err(SyscallError::InvalidOperation(1));
+    }
+
+    let mut target = target.lock();
+    let checkpoint = task.checkpoints.get_mut(&id).unwrap();
+    let memory_manager = match checkpoint.memory_manager.snapshot() {
+        Ok(memory_manager) => memory_manager,
+        Err(e) => {
+            thaw(&mut target);
+            return Err(snapshot_failed(&target.name, checkpoint.memory_manager.resident_size(), e));
+        }
+    };
+
+    log::debug!("[{}] Restoring {} to checkpoint {}", task.name, target.name, id.value());
+    restore(&mut target, memory_manager, checkpoint.context.clone());
+    thaw(&mut target);
+
+    Ok(())
+}
+
+pub fn read_checkpoint_registers(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let checkpoint = task.checkpoints.get(&CheckpointId::new(regs.a1)).ok_or(SyscallError::InvalidArgument(0))?;
+    let out_ptr = VirtualAddress::new(regs.a2);
+    let out = RawUserPtr::<ReadWrite, CheckpointRegisters>::writable(out_ptr);
+    let mut out = match unsafe { out.validate(&task.memory_manager) } {
+        Ok(out) => out,
+        Err(e) => {
+            log::debug!("Bad checkpoint registers buffer @ {:#p}: {:?}", out_ptr, e);
+            return Err(SyscallError::InvalidArgument(1));
+        }
+    };
+
+    out.with(|out| {
+        out.pc = checkpoint.context.pc;
+        // SAFETY: `GeneralRegisters` is `repr(C)` and contains exactly `x1`
+        // through `x31` in order
+        out.registers = unsafe { core::mem::transmute::<GeneralRegisters, [usize; 31]>(checkpoint.context.gp_regs) };
+    });
+
+    Ok(())
+}
+
+pub fn read_checkpoint_memory(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let checkpoint = task.checkpoints.get(&CheckpointId::new(regs.a1)).ok_or(SyscallError::InvalidArgument(0))?;
+    let at = VirtualAddress::new(regs.a2);
+    let out_ptr = VirtualAddress::new(regs.a3);
+    let len = regs.a4;
+
+    let out = RawUserSlice::<ReadWrite, u8>::writable(out_ptr, len);
+    let mut out = match unsafe { out.validate(&task.memory_manager) } {
+        Ok(out) => out,
+        Err((_, e)) => {
+            log::debug!("Bad checkpoint memory buffer @ {:#p}: {:?}", out_ptr, e);
+            return Err(SyscallError::InvalidArgument(2));
+        }
+    };
+
+    match at.checked_add(len) {
+        Some(end) if !at.is_kernel_region() && !end.is_kernel_region() => {}
+        _ => return Err(SyscallError::InvalidArgument(1)),
+    }
+
+    let memory_manager = &checkpoint.memory_manager;
+    let page_size = |at: VirtualAddress| match memory_manager.region_for(at) {
+        Some(region) if region.permissions & flags::READ => region.region.as_ref().map(|region| region.page_size()),
+        _ => None,
+    };
+
+    // Everything has to be readable before any of it is copied
+    let mut checked = 0;
+    while checked < len {
+        let page_size = page_size(at.add(checked)).ok_or(SyscallError::InvalidArgument(1))?;
+        checked += page_size.to_byte_size() - at.add(checked).offset_into_page(page_size);
+    }
+
+    out.with(|out| {
+        let mut copied = 0;
+        while copied < out.len() {
+            let from = at.add(copied);
+            let page_size = page_size(from).unwrap();
+            let offset = from.offset_into_page(page_size);
+            let chunk = &mut out[copied..][..(page_size.to_byte_size() - offset).min(len - copied)];
+
+            // Pages which were never touched aren't mapped
+            match memory_manager.resolve(from) {
+                Some(page) => unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        phys2virt(page.offset(offset)).as_ptr(),
+                        chunk.as_mut_ptr(),
+                        chunk.len(),
+                    )
+                },
+                None => chunk.fill(0),
+            }
+
+            copied += chunk.len();
+        }
+    });
+
+    Ok(())
+}
+
+pub fn discard_checkpoint(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    match task.checkpoints.remove(&CheckpointId::new(regs.a1)) {
+        Some(_) => Ok(()),
+        None => Err(SyscallError::InvalidArgument(0)),
+    }
+}
+
+/// `size` is how much memory the address space being copied has mapped
+fn snapshot_failed(name: &str, size: usize, error: SnapshotError) -> SyscallError {
+    match error {
+        SnapshotError::DeviceMemory => SyscallError::InvalidOperation(1),
+        SnapshotError::OutOfMemory => super::mem::out_of_memory(name, size),
+        // The syscall is restarted once the page is back
+        SnapshotError::Swapped => SyscallError::WouldBlock,
+    }
+}
+
+/// Stop `target` from being scheduled until it's thawed, failing with
+/// [`SyscallError::WouldBlock`] if it's still running on another hart, in which
+/// case it's left frozen on behalf of `task`
+fn freeze(task: &mut Task, target: &LockedTask) -> Result<(), SyscallError> {
+    let tid = {
+        let mut target = target.lock();
+        match target.state {
+            TaskState::Dead => return Err(SyscallError::InvalidArgument(1)),
+            TaskState::Running => target.state = TaskState::Frozen,
+            _ => {}
+        }
+
+        target.tid
+    };
+
+    // The scheduler can't be asked while the task is locked, since it locks
+    // tasks while holding its own locks
+    match SCHEDULER.is_active(target) {
+        true => {
+            task.frozen.insert(tid);
+            Err(SyscallError::WouldBlock)
+        }
+        // The syscall thaws it again itself
+        false => {
+            task.frozen.remove(&tid);
+            Ok(())
+        }
+    }
+}
+
+/// Thaw the tasks which an exited task left frozen without coming back to
+pub fn thaw_abandoned(frozen: BTreeSet<Tid>) {
+    for tid in frozen {
+        if let Some(target) = TASKS.get(tid) {
+            thaw(&mut target.lock());
+        }
+    }
+}
+
+/// Let a task which was frozen be scheduled again. Tasks which were suspended
+/// by job control stay suspended.
+fn thaw(task: &mut Task) {
+    if let TaskState::Frozen = task.state {
+        task.state = TaskState::Running;
+    }
+}
+
+/// Replace the address space and registers of `task`, which isn't running
+/// anywhere but possibly this hart, with those of a checkpoint
+fn restore(task: &mut Task, memory_manager: MemoryManager, context: Context) {
+    task.unmap_dma_allocations();
+
+    // A hart could still be using the old page tables until it schedules
+    // something else
+    let old_memory_manager = core::mem::replace(&mut task.memory_manager, memory_manager);
+    crate::mem::rmap::set_owner(task.memory_manager.id(), task.tid);
+    crate::worker::defer_pinned(move || drop(old_memory_manager));
+
+    task.context = context;
+    // They can have memory mapped into the old address space
+    task.vmspace_objects = Default::default();
+}
@@ -0,0 +1,111 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{
+    capabilities::{Capability, CapabilityResource},
+    mem::{
+        memory_map::{self, MemoryMapEntry},
+        paging::{flags, Mapping, VirtualAddress},
+        user::{RawUserSlice, ReadWrite, ValidatedUserSlice},
+    },
+    scheduler::TASKS,
+    task::Task,
+    trap::GeneralRegisters,
+};
+use core::num::NonZeroUsize;
+use librust::{
+    capabilities::{CapabilityPtr, CapabilityRights},
+    error::SyscallError,
+    syscalls::debug::PageMapping,
+    task::Tid,
+};
+
+/// Check that `cptr` is a readable debug capability, which is always the first
+/// argument of the debug syscalls
+pub(super) fn check_debug_capability(task: &Task, cptr: CapabilityPtr) -> Result<(), SyscallError> {
+    match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::Debug, rights }) if *rights & CapabilityRights::READ => Ok(()),
+        Some(Capability { resource: CapabilityResource::Debug, .. }) => Err(SyscallError::InsufficientRights(0)),
+        _ => Err(SyscallError::InvalidArgument(0)),
+    }
+}
+
+pub fn dump_page_table(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    check_debug_capability(task, CapabilityPtr::new(regs.a1))?;
+
+    let tid = NonZeroUsize::new(regs.a2).map(Tid::new).ok_or(SyscallError::InvalidArgument(1))?;
+    let out_ptr = VirtualAddress::new(regs.a3);
+    let mut out: ValidatedUserSlice<ReadWrite, PageMapping> =
+        match unsafe { RawUserSlice::new(out_ptr, regs.a4).validate(&task.memory_manager) } {
+            Ok(out) => out,
+            Err((_, e)) => {
+                log::debug!("Bad page mapping buffer @ {:#p}: {:?}", out_ptr, e);
+                return Err(SyscallError::InvalidArgument(2));
+            }
+        };
+
+    // The current task is already locked
+    let mappings = match tid == task.tid {
+        true => task.memory_manager.mappings(),
+        false => TASKS.get(tid).ok_or(SyscallError::InvalidArgument(1))?.lock().memory_manager.mappings(),
+    };
+
+    out.with(|out| {
+        for (out, mapping) in out.iter_mut().zip(&mappings) {
+            *out = to_page_mapping(mapping);
+        }
+    });
+
+    // The total, so the caller can tell whether its buffer was big enough
+    regs.a1 = mappings.len();
+
+    Ok(())
+}
+
+pub fn read_memory_map(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    check_debug_capability(task, CapabilityPtr::new(regs.a1))?;
+
+    let out_ptr = VirtualAddress::new(regs.a2);
+    let mut out: ValidatedUserSlice<ReadWrite, MemoryMapEntry> =
+        match unsafe { RawUserSlice::new(out_ptr, regs.a3).validate(&task.memory_manager) } {
+            Ok(out) => out,
+            Err((_, e)) => {
+                log::debug!("Bad memory map buffer @ {:#p}: {:?}", out_ptr, e);
+                return Err(SyscallError::InvalidArgument(1));
+            }
+        };
+
+    regs.a1 = memory_map::with(|entries| {
+        out.with(|out| out.iter_mut().zip(entries).for_each(|(out, entry)| *out = *entry));
+        entries.len()
+    });
+
+    Ok(())
+}
+
+fn to_page_mapping(mapping: &Mapping) -> PageMapping {
+    let mut flags = 0;
+    for (flag, bit) in [
+        (flags::READ, PageMapping::READ),
+        (flags::WRITE, PageMapping::WRITE),
+        (flags::EXECUTE, PageMapping::EXECUTE),
+        (flags::USER, PageMapping::USER),
+        (flags::GLOBAL, PageMapping::GLOBAL),
+    ] {
+        if mapping.flags & flag {
+            flags |= bit;
+        }
+    }
+
+    PageMapping {
+        virt: mapping.virt.as_usize(),
+        phys: mapping.phys.as_usize(),
+        len: mapping.len,
+        page_size: mapping.page_size.to_byte_size(),
+        flags,
+    }
+}
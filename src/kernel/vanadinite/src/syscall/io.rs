@@ -5,6 +5,8 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
+#[cfg(feature = "driver.riscv_iommu")]
+use crate::mem::dma::IOMMU;
 use crate::{
     capabilities::{Capability, CapabilityResource},
     interrupts::PLIC,
@@ -64,6 +66,18 @@ pub fn claim_device(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(),
             // FIXME: what about multiple regions?
             match node.reg().into_iter().flatten().next() {
                 Some(fdt::standard_nodes::MemoryRegion { size: Some(len), starting_address }) => {
+                    // Devices behind the IOMMU can only access the DMA memory
+                    // of the task that claimed them
+                    #[cfg(feature = "driver.riscv_iommu")]
+                    if let Some(iommu) = *IOMMU.read() {
+                        for device_id in iommu_device_ids(&node, iommu.phandle()) {
+                            if let Err(e) = iommu.attach(task.tid, device_id) {
+                                log::error!("Failed to attach {} to the IOMMU: {:?}", node_path, e);
+                                return Err(SyscallError::InvalidOperation(0));
+                            }
+                        }
+                    }
+
                     claimed.upgrade().insert(node_path.into(), task.tid);
                     let map_to = unsafe {
                         task.memory_manager.map_mmio_device(PhysicalAddress::from_ptr(starting_address), None, len)
@@ -93,7 +107,11 @@ pub fn claim_device(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(),
                         plic.set_interrupt_priority(interrupt, 7);
                         crate::interrupts::isr::register_isr(interrupt, move |plic, _, id| {
                             plic.disable_interrupt(crate::platform::current_plic_context(), id);
-                            let task = TASKS.get(current_tid).unwrap();
+                            // The owner has exited, so leave the interrupt disabled
+                            let task = match TASKS.get(current_tid) {
+                                Some(task) => task,
+                                None => return Ok(()),
+                            };
                             let mut task = task.lock();
 
                             log::debug!(
@@ -109,6 +127,7 @@ pub fn claim_device(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(),
                             send_lock.push_back(ChannelMessage {
                                 data: Into::into(KernelMessage::InterruptOccurred(id)),
                                 caps: Vec::new(),
+                                charge: None,
                             });
 
                             let token = task.kernel_channel.sender.wake.lock().take();
@@ -132,6 +151,26 @@ pub fn claim_device(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(),
     }
 }
 
+/// The device IDs given to the node by the IOMMU with the given phandle,
+/// assuming the IOMMU uses a single cell for its device IDs
+#[cfg(feature = "driver.riscv_iommu")]
+fn iommu_device_ids(node: &fdt::node::FdtNode<'_, '_>, phandle: Option<u32>) -> Vec<u32> {
+    let iommus = match (node.property("iommus"), phandle) {
+        (Some(iommus), Some(_)) => iommus,
+        _ => return Vec::new(),
+    };
+
+    iommus
+        .value
+        .chunks_exact(8)
+        .map(|cells| {
+            (u32::from_be_bytes(cells[..4].try_into().unwrap()), u32::from_be_bytes(cells[4..].try_into().unwrap()))
+        })
+        .filter(|&(iommu, _)| Some(iommu) == phandle)
+        .map(|(_, device_id)| device_id)
+        .collect()
+}
+
 pub fn complete_interrupt(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
     let interrupt_id = regs.a1;
     match task.claimed_interrupts.remove(&interrupt_id) {
@@ -140,7 +179,14 @@ pub fn complete_interrupt(task: &mut Task, regs: &mut GeneralRegisters) -> Resul
             log::debug!("Task {} completing interrupt {}", task.name, interrupt_id);
             if let Some(plic) = &*PLIC.lock() {
                 plic.complete(crate::platform::plic_context_for(hart), interrupt_id);
-                plic.enable_interrupt(crate::platform::plic_context_for(hart), interrupt_id);
+
+                // The interrupts of a hart which has gone offline since were
+                // moved elsewhere, so this one should go along with them
+                let enable_on = match crate::hotplug::is_online(hart) {
+                    true => crate::platform::plic_context_for(hart),
+                    false => crate::platform::current_plic_context(),
+                };
+                plic.enable_interrupt(enable_on, interrupt_id);
             }
 
             Ok(())
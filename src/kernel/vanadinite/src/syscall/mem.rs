@@ -8,18 +8,35 @@
 use crate::{
     capabilities::{Capability, CapabilityResource},
     mem::{
-        manager::{AddressRegionKind, FillOption, RegionDescription},
-        paging::{flags, PageSize, VirtualAddress},
-        user::{RawUserSlice, ReadWrite, ValidatedUserSlice},
+        balloon,
+        dma::{self, DmaAllocation, DmaPin, PinnedSegment},
+        manager::{AddressRegion, AddressRegionKind, FillOption, RegionDescription},
+        paging::{
+            flags::{self, Flags},
+            PageSize, VirtualAddress,
+        },
+        phys::{PhysicalMemoryAllocator, PhysicalPage, Zone, PHYSICAL_MEMORY_ALLOCATOR},
+        phys2virt, pressure,
+        region::{MemoryRegion, PhysicalRegion, UniquePhysicalRegion},
+        shm::SharedMemory,
+        swap::{self, SwapError, SwapSlot},
+        user::{RawUserSlice, Read, ReadWrite, ValidatedUserSlice},
     },
     task::Task,
     trap::GeneralRegisters,
     utils,
 };
+use core::ops::Range;
 use librust::{
     capabilities::{CapabilityPtr, CapabilityRights},
     error::SyscallError,
-    syscalls::mem::{AllocationOptions, DmaAllocationOptions, MemoryPermissions},
+    syscalls::{
+        mem::{
+            AllocationOptions, DeflateOptions, DmaAllocationOptions, DmaPinId, DmaSegment, MemoryBacking,
+            MemoryPermissions, SealFlags,
+        },
+        swap::PAGE_SIZE,
+    },
 };
 
 pub fn alloc_virtual_memory(task: &mut Task, frame: &mut GeneralRegisters) -> Result<(), SyscallError> {
@@ -47,11 +64,26 @@ pub fn alloc_virtual_memory(task: &mut Task, frame: &mut GeneralRegisters) -> Re
 
     let page_size = if options & AllocationOptions::LARGE_PAGE { PageSize::Megapage } else { PageSize::Kilopage };
 
+    // Shared memory has to be backed up front for other tasks to map it
+    if options & AllocationOptions::LAZY && !(options & AllocationOptions::PRIVATE) {
+        return Err(SyscallError::InvalidArgument(1));
+    }
+
     match size {
         0 => Err(SyscallError::InvalidArgument(0)),
         _ => {
-            let (cptr, allocated_at) = if options & AllocationOptions::PRIVATE {
-                let allocated_at = task.memory_manager.alloc_region(
+            let (cptr, allocated_at) = if options & AllocationOptions::LAZY {
+                let allocated_at = task.memory_manager.alloc_lazy_region(
+                    None,
+                    page_size,
+                    utils::round_up_to_next(size, page_size.to_byte_size()) / page_size.to_byte_size(),
+                    flags,
+                    AddressRegionKind::UserAllocated,
+                );
+
+                (CapabilityPtr::new(usize::MAX), allocated_at)
+            } else if options & AllocationOptions::PRIVATE {
+                let allocated_at = task.memory_manager.try_alloc_region(
                     None,
                     RegionDescription {
                         size: page_size,
@@ -67,9 +99,9 @@ pub fn alloc_virtual_memory(task: &mut Task, frame: &mut GeneralRegisters) -> Re
                     },
                 );
 
-                (CapabilityPtr::new(usize::MAX), allocated_at)
+                (CapabilityPtr::new(usize::MAX), allocated_at.ok_or_else(|| out_of_memory(&task.name, size))?)
             } else {
-                let (allocated_at, region) = task.memory_manager.alloc_shared_region(
+                let allocated = task.memory_manager.try_alloc_shared_region(
                     None,
                     RegionDescription {
                         size: page_size,
@@ -84,6 +116,7 @@ pub fn alloc_virtual_memory(task: &mut Task, frame: &mut GeneralRegisters) -> Re
                         kind: AddressRegionKind::UserAllocated,
                     },
                 );
+                let (allocated_at, region) = allocated.ok_or_else(|| out_of_memory(&task.name, size))?;
 
                 let rights = match (
                     permissions & MemoryPermissions::READ,
@@ -92,15 +125,17 @@ pub fn alloc_virtual_memory(task: &mut Task, frame: &mut GeneralRegisters) -> Re
                 ) {
                     (true, true, true) => CapabilityRights::READ | CapabilityRights::WRITE | CapabilityRights::EXECUTE,
                     (true, true, false) => CapabilityRights::READ | CapabilityRights::WRITE,
+                    (true, false, true) => CapabilityRights::READ | CapabilityRights::EXECUTE,
                     (true, false, false) => CapabilityRights::READ,
                     (r, w, x) => unreachable!("read={r} write={w} execute={x}"),
                 };
 
                 let cptr = task.cspace.mint(Capability {
                     resource: CapabilityResource::Memory(
-                        region,
+                        SharedMemory::new(region),
                         allocated_at.clone(),
                         AddressRegionKind::UserAllocated,
+                        SealFlags::NONE,
                     ),
                     rights: rights | CapabilityRights::GRANT,
                 });
@@ -127,38 +162,211 @@ pub fn alloc_dma_memory(task: &mut Task, frame: &mut GeneralRegisters) -> Result
     match size {
         0 => Err(SyscallError::InvalidArgument(0)),
         _ => {
-            let allocated_at = task.memory_manager.alloc_region(
-                None,
-                RegionDescription {
-                    size: page_size,
-                    len: utils::round_up_to_next(size, page_size.to_byte_size()) / page_size.to_byte_size(),
-                    contiguous: true,
-                    flags: flags::VALID | flags::USER | flags::READ | flags::WRITE,
-                    fill: if options & DmaAllocationOptions::ZERO {
-                        FillOption::Zeroed
-                    } else {
-                        FillOption::Unitialized
-                    },
-                    kind: AddressRegionKind::Dma,
+            let len = utils::round_up_to_next(size, page_size.to_byte_size()) / page_size.to_byte_size();
+            let flags = flags::VALID | flags::USER | flags::READ | flags::WRITE;
+            let dma32 = options & DmaAllocationOptions::DMA32;
+
+            let allocated_at = match dma32 {
+                true => match UniquePhysicalRegion::alloc_contiguous_in(Zone::Dma32, page_size, len) {
+                    Some(mut backing) => {
+                        backing.zero();
+                        task.memory_manager.map_region(None, backing, flags, AddressRegionKind::Dma)
+                    }
+                    None => return Err(out_of_memory(&task.name, size)),
                 },
-            );
+                false => task.memory_manager.try_alloc_region(
+                    None,
+                    RegionDescription {
+                        size: page_size,
+                        len,
+                        contiguous: true,
+                        flags,
+                        fill: if options & DmaAllocationOptions::ZERO {
+                            FillOption::Zeroed
+                        } else {
+                            FillOption::Unitialized
+                        },
+                        kind: AddressRegionKind::Dma,
+                    },
+                ),
+            };
+            let allocated_at = allocated_at.ok_or_else(|| out_of_memory(&task.name, size))?;
 
             let phys = task.memory_manager.resolve(allocated_at.start).unwrap();
+            let len = allocated_at.end.as_usize() - allocated_at.start.as_usize();
+            let device_addr = match dma::map(task.tid, phys, len) {
+                // An IOMMU can hand out device addresses above 4 GiB even
+                // though the memory itself is below it
+                Ok(device_addr) if dma32 && device_addr.as_usize() + len > Zone::DMA32_END => {
+                    log::warn!("No DMA32 device addresses left for {}", task.name);
+                    dma::unmap(task.tid, device_addr, len);
+                    task.memory_manager.dealloc_region(allocated_at.start);
+                    return Err(SyscallError::InvalidOperation(0));
+                }
+                Ok(device_addr) => device_addr,
+                Err(e) => {
+                    log::warn!("Failed to map DMA memory for {}: {:?}", task.name, e);
+                    task.memory_manager.dealloc_region(allocated_at.start);
+                    return Err(SyscallError::InvalidOperation(0));
+                }
+            };
+
+            // Don't let lines left dirty by zeroing the memory get written back
+            // over what a device writes to it
+            dma::sync_for_device(phys, len, dma::DmaDirection::Bidirectional);
+            task.dma_allocations.insert(allocated_at.start, DmaAllocation { device_addr, len });
 
-            log::debug!("Allocated DMA memory at {:#p} for user process", allocated_at.start);
+            log::debug!(
+                "Allocated DMA memory at {:#p} (device address {:#x}) for user process",
+                allocated_at.start,
+                device_addr.as_usize()
+            );
 
-            frame.a1 = phys.as_usize();
+            frame.a1 = device_addr.as_usize();
             frame.a2 = allocated_at.start.as_usize();
             Ok(())
         }
     }
 }
 
+/// Free DMA memory, given where it's mapped, after removing it from the devices
+/// of the task
+pub fn dealloc_dma_memory(task: &mut Task, frame: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let at = VirtualAddress::new(frame.a1);
+    let allocation = task.dma_allocations.remove(&at).ok_or(SyscallError::InvalidArgument(0))?;
+
+    dma::unmap(task.tid, allocation.device_addr, allocation.len);
+    task.memory_manager.dealloc_region(at);
+
+    log::debug!("Freed DMA memory at {:#p} for {}", at, task.name);
+
+    Ok(())
+}
+
+/// Cache maintenance around handing DMA memory to a device and taking it back
+pub fn sync_dma_memory(task: &mut Task, frame: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let start = VirtualAddress::new(frame.a1);
+    let len = frame.a2;
+    let direction = dma::DmaDirection::from_usize(frame.a3).ok_or(SyscallError::InvalidArgument(2))?;
+    let end = start.as_usize().checked_add(len).ok_or(SyscallError::InvalidArgument(1))?;
+
+    let sync = match frame.a4 {
+        0 => dma::sync_for_device,
+        1 => dma::sync_for_cpu,
+        _ => return Err(SyscallError::InvalidArgument(3)),
+    };
+
+    match task.memory_manager.region_for(start) {
+        Some(region) if region.kind == AddressRegionKind::Dma => {
+            if end > region.span.end.as_usize() {
+                return Err(SyscallError::InvalidArgument(1));
+            }
+
+            // DMA memory is physically contiguous
+            let phys = task.memory_manager.resolve(start).ok_or(SyscallError::InvalidArgument(0))?;
+            sync(phys, len, direction);
+        }
+        // Anything else has to have been pinned
+        _ => {
+            let pin = task
+                .dma_pins
+                .values()
+                .find(|pin| pin.range.contains(&start))
+                .ok_or(SyscallError::InvalidArgument(0))?;
+            if end > pin.range.end.as_usize() {
+                return Err(SyscallError::InvalidArgument(1));
+            }
+
+            pin.for_each_in(start..VirtualAddress::new(end), |phys, len| sync(phys, len, direction));
+        }
+    }
+
+    Ok(())
+}
+
+/// Pin memory anywhere in the task's address space so it can be handed to its
+/// devices, writing the physically contiguous pieces it's made of to a buffer
+pub fn pin_dma_memory(task: &mut Task, frame: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let start = VirtualAddress::new(frame.a1);
+    let len = frame.a2;
+    let direction = dma::DmaDirection::from_usize(frame.a3).ok_or(SyscallError::InvalidArgument(2))?;
+    let out_ptr = VirtualAddress::new(frame.a4);
+    let out_len = frame.a5;
+
+    let end = match start.checked_add(len) {
+        Some(end) if len > 0 => end,
+        _ => return Err(SyscallError::InvalidArgument(1)),
+    };
+
+    let out = RawUserSlice::<ReadWrite, DmaSegment>::writable(out_ptr, out_len);
+    let mut out = match unsafe { out.validate(&task.memory_manager) } {
+        Ok(out) => out,
+        Err((_, e)) => {
+            log::debug!("Bad DMA segment buffer @ {:#p}: {:?}", out_ptr, e);
+            return Err(SyscallError::InvalidArgument(3));
+        }
+    };
+
+    // Devices write to the memory unless it's only going to them
+    let writable = direction != dma::DmaDirection::ToDevice;
+    let valid = |f: Flags| f & flags::READ && (!writable || f & flags::WRITE);
+    if let Err((at, e)) = task.memory_manager.is_user_region_valid(start..end, valid) {
+        log::debug!("Bad memory to pin @ {:#p}: {:?}", at, e);
+        return Err(SyscallError::InvalidArgument(0));
+    }
+
+    // The syscall is restarted once any pages which aren't there yet are
+    let pinned = task.memory_manager.pin(start..end).ok_or(SyscallError::InvalidArgument(0))?;
+    let mut pin = DmaPin { range: start..end, pages: pinned.pages, segments: alloc::vec::Vec::new() };
+
+    if pinned.segments.len() > out_len {
+        pin.release(task.tid);
+        return Err(SyscallError::InvalidArgument(3));
+    }
+
+    for (phys, len) in pinned.segments {
+        match PinnedSegment::map(task.tid, phys, len) {
+            Ok(segment) => pin.segments.push(segment),
+            Err(e) => {
+                log::warn!("Failed to map pinned memory for {}: {:?}", task.name, e);
+                pin.release(task.tid);
+                return Err(SyscallError::InvalidOperation(0));
+            }
+        }
+    }
+
+    out.with(|out| {
+        for (out, segment) in out.iter_mut().zip(&pin.segments) {
+            *out = DmaSegment { device_addr: segment.device_addr.as_usize(), len: segment.len };
+        }
+    });
+
+    let id = DmaPinId::new(task.dma_pin_next_id);
+    task.dma_pin_next_id += 1;
+
+    log::debug!("Pinned {} bytes at {:#p} in {} segments for {}", len, start, pin.segments.len(), task.name);
+
+    frame.a1 = id.value();
+    frame.a2 = pin.segments.len();
+    task.dma_pins.insert(id, pin);
+
+    Ok(())
+}
+
+/// Unpin memory pinned with [`pin_dma_memory`], after removing it from the
+/// devices of the task
+pub fn unpin_dma_memory(task: &mut Task, frame: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let pin = task.dma_pins.remove(&DmaPinId::new(frame.a1)).ok_or(SyscallError::InvalidArgument(0))?;
+    pin.release(task.tid);
+
+    Ok(())
+}
+
 pub fn query_mem_cap(task: &mut Task, frame: &mut GeneralRegisters) -> Result<(), SyscallError> {
     let cptr = CapabilityPtr::new(frame.a1);
 
     match task.cspace.resolve(cptr) {
-        Some(Capability { resource: CapabilityResource::Memory(_, vmem, _), rights }) => {
+        Some(Capability { resource: CapabilityResource::Memory(_, vmem, ..), rights }) => {
             let memory_perms = match (*rights & CapabilityRights::READ, *rights & CapabilityRights::WRITE) {
                 (true, true) => MemoryPermissions::READ | MemoryPermissions::WRITE,
                 (true, false) => MemoryPermissions::READ,
@@ -174,6 +382,333 @@ pub fn query_mem_cap(task: &mut Task, frame: &mut GeneralRegisters) -> Result<()
     }
 }
 
+/// Describe the region of the task's address map containing an address, which
+/// is the kernel's record of everything the task has mapped
+pub fn query_memory(task: &mut Task, frame: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let at = VirtualAddress::new(frame.a1);
+    if !VirtualAddress::userspace_range().contains(&at) {
+        return Err(SyscallError::InvalidArgument(0));
+    }
+
+    let region = task.memory_manager.region_for(at).ok_or(SyscallError::InvalidArgument(0))?;
+    let backing = match (&region.region, region.kind) {
+        (None, _) => MemoryBacking::Unmapped,
+        (Some(MemoryRegion::GuardPage), _) | (_, AddressRegionKind::Guard) => MemoryBacking::Guard,
+        (_, AddressRegionKind::Dma) => MemoryBacking::Dma,
+        (_, AddressRegionKind::Mmio) => MemoryBacking::Mmio,
+        (Some(MemoryRegion::Backed(PhysicalRegion::Shared(_))), _) => MemoryBacking::Shared,
+        _ => MemoryBacking::Anonymous,
+    };
+
+    let mut permissions = MemoryPermissions::READ;
+    if region.permissions & flags::WRITE {
+        permissions = permissions | MemoryPermissions::WRITE;
+    }
+
+    if region.permissions & flags::EXECUTE {
+        permissions = permissions | MemoryPermissions::EXECUTE;
+    }
+
+    frame.a1 = region.span.start.as_usize();
+    frame.a2 = region.span.end.as_usize() - region.span.start.as_usize();
+    frame.a3 = permissions.value();
+    frame.a4 = backing.to_usize();
+
+    Ok(())
+}
+
+pub fn shared_memory_size(task: &mut Task, frame: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    match task.cspace.resolve(CapabilityPtr::new(frame.a1)) {
+        Some(Capability { resource: CapabilityResource::Memory(shm, ..), .. }) => {
+            frame.a1 = shm.size();
+            Ok(())
+        }
+        _ => Err(SyscallError::InvalidArgument(0)),
+    }
+}
+
+/// Grow a shared memory object, extending the caller's mapping of it in place
+/// if there's room after it and mapping the whole object again otherwise
+pub fn grow_shared_memory(task: &mut Task, frame: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let cptr = CapabilityPtr::new(frame.a1);
+    let new_size = frame.a2;
+
+    let (shm, mapped_at, kind, rights, seals) = match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::Memory(shm, mapped_at, kind, seals), rights }) => {
+            (shm.clone(), mapped_at.clone(), *kind, *rights, *seals)
+        }
+        _ => return Err(SyscallError::InvalidArgument(0)),
+    };
+
+    if !(rights & CapabilityRights::WRITE) || seals & SealFlags::GROW {
+        return Err(SyscallError::InsufficientRights(0));
+    }
+
+    if new_size >= VirtualAddress::userspace_range().end.as_usize() {
+        return Err(SyscallError::InvalidArgument(1));
+    }
+
+    let chunk = shm.grow(new_size).ok_or(SyscallError::InvalidArgument(1))?;
+    let chunk_end = mapped_at.end.add(chunk.n_pages() * chunk.page_size().to_byte_size());
+    let flags = memory_flags(rights);
+
+    let mapped_at = match task.memory_manager.is_unoccupied(mapped_at.end..chunk_end) {
+        true => {
+            shm.map_with(flags, |_, flags| {
+                task.memory_manager.apply_shared_region(Some(mapped_at.end), flags, chunk, kind)
+            });
+            mapped_at.start..chunk_end
+        }
+        false => task.memory_manager.map_shared_memory(None, flags, &shm, kind),
+    };
+
+    log::debug!("[{}] Grew shared memory to {} bytes, mapped at {:#p}", task.name, shm.size(), mapped_at.start);
+    remap_capability(task, cptr, mapped_at, frame);
+
+    Ok(())
+}
+
+/// Resize the private allocation starting at the given address, in place if
+/// nothing is mapped after it and by moving it otherwise. What was in it is
+/// kept either way, and the memory it gains is zeroed.
+pub fn resize_memory(task: &mut Task, frame: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let at = frame.a1;
+    let new_size = frame.a2;
+
+    if !VirtualAddress::userspace_range().contains(&VirtualAddress::new(at)) {
+        return Err(SyscallError::InvalidArgument(0));
+    }
+
+    if new_size == 0 || new_size >= VirtualAddress::userspace_range().end.as_usize() {
+        return Err(SyscallError::InvalidArgument(1));
+    }
+
+    let at = VirtualAddress::new(at);
+    let page_size = match task.memory_manager.region_for(at) {
+        Some(AddressRegion {
+            span,
+            kind: AddressRegionKind::UserAllocated,
+            region: Some(MemoryRegion::Backed(PhysicalRegion::Unique(unique))),
+            ..
+        }) if span.start == at => unique.page_size(),
+        _ => return Err(SyscallError::InvalidArgument(0)),
+    };
+
+    let n_pages = utils::round_up_to_next(new_size, page_size.to_byte_size()) / page_size.to_byte_size();
+    let resized_at = task.memory_manager.resize_region(at, n_pages).ok_or(SyscallError::InvalidArgument(0))?;
+
+    log::debug!("[{}] Resized allocation at {:#p} to {:#p}-{:#p}", task.name, at, resized_at.start, resized_at.end);
+
+    frame.a1 = resized_at.start.as_usize();
+    frame.a2 = resized_at.end.as_usize() - resized_at.start.as_usize();
+
+    Ok(())
+}
+
+pub fn map_shared_memory(task: &mut Task, frame: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let cptr = CapabilityPtr::new(frame.a1);
+
+    let (shm, kind, rights) = match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::Memory(shm, _, kind, _), rights }) => {
+            (shm.clone(), *kind, *rights)
+        }
+        _ => return Err(SyscallError::InvalidArgument(0)),
+    };
+
+    let mapped_at = task.memory_manager.map_shared_memory(None, memory_flags(rights), &shm, kind);
+    remap_capability(task, cptr, mapped_at, frame);
+
+    Ok(())
+}
+
+/// Map a private copy of the shared memory object behind a readable memory
+/// capability, which is always writable. The object has to have had writes
+/// sealed. Unlike [`map_shared_memory`] the capability doesn't refer to the
+/// copy, since it isn't the object.
+pub fn map_shared_memory_copy(task: &mut Task, frame: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let cptr = CapabilityPtr::new(frame.a1);
+
+    let (shm, kind, rights) = match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::Memory(shm, _, kind, _), rights }) => {
+            (shm.clone(), *kind, *rights)
+        }
+        _ => return Err(SyscallError::InvalidArgument(0)),
+    };
+
+    if !(rights & CapabilityRights::READ) {
+        return Err(SyscallError::InsufficientRights(0));
+    }
+
+    let flags = memory_flags(rights) | flags::WRITE;
+    let mapped_at = match task.memory_manager.map_shared_memory_copy(None, flags, &shm, kind) {
+        Some(mapped_at) => mapped_at,
+        None => return Err(SyscallError::InvalidArgument(0)),
+    };
+
+    frame.a1 = mapped_at.start.as_usize();
+    frame.a2 = mapped_at.end.as_usize() - mapped_at.start.as_usize();
+
+    Ok(())
+}
+
+pub fn seal_memory(task: &mut Task, frame: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let cptr = CapabilityPtr::new(frame.a1);
+    let flags = SealFlags::new(frame.a2);
+
+    let all = SealFlags::GROW | SealFlags::UPGRADE | SealFlags::GRANT | SealFlags::WRITE;
+    if flags.value() & !all.value() != 0 {
+        return Err(SyscallError::InvalidArgument(1));
+    }
+
+    let shm = match task.cspace.resolve_mut(cptr) {
+        Some(Capability { resource: CapabilityResource::Memory(shm, _, _, seals), rights }) => {
+            if flags & SealFlags::WRITE && !(*rights & CapabilityRights::WRITE) {
+                return Err(SyscallError::InsufficientRights(0));
+            }
+
+            *seals = *seals | flags;
+            shm.clone()
+        }
+        _ => return Err(SyscallError::InvalidArgument(0)),
+    };
+
+    // Unlike the other seals, this one applies to the object itself rather
+    // than just the capability
+    if flags & SealFlags::WRITE {
+        shm.seal_writes(task);
+    }
+
+    Ok(())
+}
+
+/// Change the permissions of whole allocations, given the memory capability
+/// they were allocated with or the invalid capability pointer private
+/// allocations are returned with. Capabilities limit the permissions to their
+/// rights, and nothing can be made writable again once writes to it are sealed.
+pub fn modify_memory_permissions(task: &mut Task, frame: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let cptr = CapabilityPtr::new(frame.a1);
+    let (start, len) = (frame.a2, frame.a3);
+    let permissions = MemoryPermissions::new(frame.a4);
+
+    let all = MemoryPermissions::READ | MemoryPermissions::WRITE | MemoryPermissions::EXECUTE;
+    if permissions.value() & !all.value() != 0 {
+        return Err(SyscallError::InvalidArgument(3));
+    }
+
+    if len == 0 || start % PAGE_SIZE != 0 || len % PAGE_SIZE != 0 {
+        return Err(SyscallError::InvalidArgument(1));
+    }
+
+    let range = match start.checked_add(len) {
+        Some(end) if end <= VirtualAddress::userspace_range().end.as_usize() => {
+            VirtualAddress::new(start)..VirtualAddress::new(end)
+        }
+        _ => return Err(SyscallError::InvalidArgument(1)),
+    };
+
+    let mut flags = flags::VALID | flags::USER | flags::READ;
+    if permissions & MemoryPermissions::WRITE {
+        flags |= flags::WRITE;
+    }
+
+    if permissions & MemoryPermissions::EXECUTE {
+        flags |= flags::EXECUTE;
+    }
+
+    let (shm, mapped_at, rights, seals) = match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::Memory(shm, mapped_at, _, seals), rights }) => {
+            (shm.clone(), mapped_at.clone(), *rights, *seals)
+        }
+        // Private allocations don't have a capability to limit them, and are
+        // never shared with anything else
+        _ if cptr.value() == usize::MAX => {
+            let private = |region: &AddressRegion| {
+                region.kind == AddressRegionKind::UserAllocated
+                    && matches!(region.region, Some(MemoryRegion::Backed(PhysicalRegion::Unique(_))))
+            };
+
+            return match task.memory_manager.modify_permissions(range, flags, private) {
+                true => Ok(()),
+                false => Err(SyscallError::InvalidArgument(1)),
+            };
+        }
+        _ => return Err(SyscallError::InvalidArgument(0)),
+    };
+
+    if range.start < mapped_at.start || range.end > mapped_at.end {
+        return Err(SyscallError::InvalidArgument(1));
+    }
+
+    if flags.value() & !memory_flags(rights).value() != 0 {
+        return Err(SyscallError::InsufficientRights(0));
+    }
+
+    // Mappings through a capability sealed against upgrades can only ever lose
+    // permissions
+    if seals & SealFlags::UPGRADE {
+        let rwx = (flags::READ | flags::WRITE | flags::EXECUTE).value();
+        let mut at = range.start;
+        while at < range.end {
+            match task.memory_manager.region_for(at) {
+                Some(region) if flags.value() & !region.permissions.value() & rwx != 0 => {
+                    return Err(SyscallError::InsufficientRights(0));
+                }
+                Some(region) => at = region.span.end,
+                None => return Err(SyscallError::InvalidArgument(1)),
+            }
+        }
+    }
+
+    // Writes can't be sealed while the object is being mapped, so this can't
+    // race with sealing it
+    shm.map_with(flags, |_, allowed| {
+        if flags & flags::WRITE && !(allowed & flags::WRITE) {
+            return Err(SyscallError::InsufficientRights(0));
+        }
+
+        let shared =
+            |region: &AddressRegion| matches!(region.region, Some(MemoryRegion::Backed(PhysicalRegion::Shared(_))));
+        match task.memory_manager.modify_permissions(range, flags, shared) {
+            true => Ok(()),
+            false => Err(SyscallError::InvalidArgument(1)),
+        }
+    })
+}
+
+/// Point the memory capability `cptr` at its new mapping, and return the
+/// mapping to userspace
+fn remap_capability(
+    task: &mut Task,
+    cptr: CapabilityPtr,
+    mapped_at: Range<VirtualAddress>,
+    frame: &mut GeneralRegisters,
+) {
+    frame.a1 = mapped_at.start.as_usize();
+    frame.a2 = mapped_at.end.as_usize() - mapped_at.start.as_usize();
+
+    if let Some(Capability { resource: CapabilityResource::Memory(_, range, ..), .. }) = task.cspace.resolve_mut(cptr) {
+        *range = mapped_at;
+    }
+}
+
+fn memory_flags(rights: CapabilityRights) -> Flags {
+    let mut flags = flags::VALID | flags::USER;
+
+    if rights & CapabilityRights::READ {
+        flags |= flags::READ;
+    }
+
+    if rights & CapabilityRights::WRITE {
+        flags |= flags::WRITE;
+    }
+
+    if rights & CapabilityRights::EXECUTE {
+        flags |= flags::EXECUTE;
+    }
+
+    flags
+}
+
 pub fn query_mmio_cap(task: &mut Task, frame: &mut GeneralRegisters) -> Result<(), SyscallError> {
     let cptr = CapabilityPtr::new(frame.a1);
     let buffer_ptr = VirtualAddress::new(frame.a2);
@@ -204,3 +739,188 @@ pub fn query_mmio_cap(task: &mut Task, frame: &mut GeneralRegisters) -> Result<(
         _ => Err(SyscallError::InvalidArgument(0)),
     }
 }
+
+/// Make sure `cptr` is a balloon capability which can be used to move pages
+/// in and out of the balloon
+fn check_balloon_capability(task: &Task, cptr: usize) -> Result<(), SyscallError> {
+    match task.cspace.resolve(CapabilityPtr::new(cptr)) {
+        Some(Capability { resource: CapabilityResource::Balloon, rights }) if *rights & CapabilityRights::WRITE => {
+            Ok(())
+        }
+        Some(Capability { resource: CapabilityResource::Balloon, .. }) => Err(SyscallError::InsufficientRights(0)),
+        _ => Err(SyscallError::InvalidArgument(0)),
+    }
+}
+
+pub fn inflate_balloon(task: &mut Task, frame: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    check_balloon_capability(task, frame.a1)?;
+    let buffer = validate_pfn_buffer(task, frame)?;
+    let pages = balloon::inflate(buffer.len());
+
+    write_pfns(&buffer, &pages);
+    frame.a1 = pages.len();
+
+    Ok(())
+}
+
+pub fn deflate_balloon(task: &mut Task, frame: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    check_balloon_capability(task, frame.a1)?;
+    let options = DeflateOptions::new(frame.a4);
+    let buffer = validate_pfn_buffer(task, frame)?;
+    let pages = balloon::deflate(buffer.len(), options & DeflateOptions::RECLAIMED_ONLY);
+
+    write_pfns(&buffer, &pages);
+    frame.a1 = pages.len();
+
+    Ok(())
+}
+
+fn validate_pfn_buffer(
+    task: &Task,
+    frame: &GeneralRegisters,
+) -> Result<ValidatedUserSlice<ReadWrite, u32>, SyscallError> {
+    let buffer_ptr = VirtualAddress::new(frame.a2);
+    match unsafe { RawUserSlice::new(buffer_ptr, frame.a3).validate(&task.memory_manager) } {
+        Ok(slice) => Ok(slice),
+        Err((_, e)) => {
+            log::debug!("Bad PFN buffer @ {:#p}: {:?}", buffer_ptr, e);
+            Err(SyscallError::InvalidArgument(1))
+        }
+    }
+}
+
+fn write_pfns(buffer: &ValidatedUserSlice<ReadWrite, u32>, pages: &[PhysicalPage]) {
+    let mut buffer = buffer.guarded();
+    for (pfn, page) in buffer.iter_mut().zip(pages) {
+        *pfn = (page.as_phys_address().as_usize() >> 12) as u32;
+    }
+}
+
+/// Fail an allocation of `size` bytes for the task named `name` which there
+/// wasn't enough memory for, letting anything subscribed to memory pressure
+/// know
+pub fn out_of_memory(name: &str, size: usize) -> SyscallError {
+    log::warn!("Out of memory allocating {} bytes for {}", size, name);
+    pressure::out_of_memory();
+
+    SyscallError::OutOfMemory
+}
+
+pub fn subscribe_memory_pressure(task: &mut Task, frame: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let rights = match task.cspace.resolve(CapabilityPtr::new(frame.a1)) {
+        Some(Capability { resource: CapabilityResource::MemoryPressure, rights }) => *rights,
+        _ => return Err(SyscallError::InvalidArgument(0)),
+    };
+
+    if !(rights & CapabilityRights::READ) {
+        return Err(SyscallError::InsufficientRights(0));
+    }
+
+    log::debug!("Task {} subscribed to memory pressure notifications", task.name);
+    frame.a1 = pressure::subscribe(task).to_usize();
+
+    Ok(())
+}
+
+/// Make sure `cptr` is a swap capability which can be used to enable swap
+fn check_swap_capability(task: &Task, cptr: usize) -> Result<(), SyscallError> {
+    match task.cspace.resolve(CapabilityPtr::new(cptr)) {
+        Some(Capability { resource: CapabilityResource::Swap, rights }) if *rights & CapabilityRights::WRITE => Ok(()),
+        Some(Capability { resource: CapabilityResource::Swap, .. }) => Err(SyscallError::InsufficientRights(0)),
+        _ => Err(SyscallError::InvalidArgument(0)),
+    }
+}
+
+pub fn enable_swap(task: &mut Task, frame: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    check_swap_capability(task, frame.a1)?;
+    let n_slots = match u32::try_from(frame.a2) {
+        Ok(0) | Err(_) => return Err(SyscallError::InvalidArgument(1)),
+        Ok(n_slots) => n_slots,
+    };
+
+    swap::enable(task, n_slots).map_err(swap_error)
+}
+
+pub fn take_swap_writes(task: &mut Task, frame: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let slots = validate_slot_buffer(task, frame)?;
+    let pages_ptr = VirtualAddress::new(frame.a3);
+    let pages: ValidatedUserSlice<ReadWrite, [u8; PAGE_SIZE]> =
+        match unsafe { RawUserSlice::new(pages_ptr, frame.a4).validate(&task.memory_manager) } {
+            Ok(slice) => slice,
+            Err((_, e)) => {
+                log::debug!("Bad swap page buffer @ {:#p}: {:?}", pages_ptr, e);
+                return Err(SyscallError::InvalidArgument(2));
+            }
+        };
+
+    let taken = swap::take_writes(task.tid, slots.len().min(pages.len())).map_err(swap_error)?;
+
+    let (mut slots, mut pages) = (slots.guarded(), pages.guarded());
+    for (i, &(slot, page)) in taken.iter().enumerate() {
+        slots[i] = slot.value();
+        unsafe {
+            core::ptr::copy_nonoverlapping(phys2virt(page.as_phys_address()).as_ptr(), pages[i].as_mut_ptr(), PAGE_SIZE)
+        };
+    }
+
+    let mut allocator = PHYSICAL_MEMORY_ALLOCATOR.lock();
+    for &(_, page) in &taken {
+        unsafe { allocator.dealloc(page, PageSize::Kilopage) };
+    }
+
+    frame.a1 = taken.len();
+
+    Ok(())
+}
+
+pub fn take_swap_reads(task: &mut Task, frame: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let slots = validate_slot_buffer(task, frame)?;
+    let taken = swap::take_reads(task.tid, slots.len()).map_err(swap_error)?;
+
+    let mut slots = slots.guarded();
+    for (to, slot) in slots.iter_mut().zip(&taken) {
+        *to = slot.value();
+    }
+
+    frame.a1 = taken.len();
+
+    Ok(())
+}
+
+pub fn complete_swap_read(task: &mut Task, frame: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let slot = u32::try_from(frame.a1).map_err(|_| SyscallError::InvalidArgument(0))?;
+    let page_ptr = VirtualAddress::new(frame.a2);
+    let page: ValidatedUserSlice<Read, u8> =
+        match unsafe { RawUserSlice::new(page_ptr, PAGE_SIZE).validate(&task.memory_manager) } {
+            Ok(slice) => slice,
+            Err((_, e)) => {
+                log::debug!("Bad swap page @ {:#p}: {:?}", page_ptr, e);
+                return Err(SyscallError::InvalidArgument(1));
+            }
+        };
+
+    let contents = page.guarded();
+    swap::complete_read(task.tid, SwapSlot::new(slot), &contents).map_err(swap_error)
+}
+
+fn validate_slot_buffer(
+    task: &Task,
+    frame: &GeneralRegisters,
+) -> Result<ValidatedUserSlice<ReadWrite, u32>, SyscallError> {
+    let buffer_ptr = VirtualAddress::new(frame.a1);
+    match unsafe { RawUserSlice::new(buffer_ptr, frame.a2).validate(&task.memory_manager) } {
+        Ok(slice) => Ok(slice),
+        Err((_, e)) => {
+            log::debug!("Bad swap slot buffer @ {:#p}: {:?}", buffer_ptr, e);
+            Err(SyscallError::InvalidArgument(0))
+        }
+    }
+}
+
+fn swap_error(error: SwapError) -> SyscallError {
+    match error {
+        SwapError::NotOwner => SyscallError::InsufficientRights(0),
+        SwapError::AlreadyEnabled => SyscallError::InvalidOperation(0),
+        SwapError::InvalidSlot => SyscallError::InvalidArgument(0),
+    }
+}
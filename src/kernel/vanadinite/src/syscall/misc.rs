@@ -6,11 +6,34 @@
 // obtain one at https://mozilla.org/MPL/2.0/.
 
 use crate::{
-    io::ConsoleDevice,
-    mem::{paging::VirtualAddress, user::RawUserSlice},
-    task::Task,
+    capabilities::{Capability, CapabilityResource},
+    futex, hotplug, job,
+    mem::{
+        paging::VirtualAddress,
+        user::{RawUserPtr, RawUserSlice, Read, ReadWrite, ValidatedUserSlice},
+    },
+    power, profiler, random,
+    scheduler::{timer, TASKS},
+    stats, suspend, symbols,
+    task::{FaultHandler, Task},
+    time,
+    trap::GeneralRegisters,
+    watchdog,
+};
+use core::{num::NonZeroUsize, time::Duration};
+use librust::{
+    capabilities::{CapabilityPtr, CapabilityRights},
+    error::SyscallError,
+    syscalls::{
+        io::ConsoleSinks,
+        job::JobSignal,
+        power::ResetKind,
+        profiler::{ProfileSample, PROFILER_MAX_DEPTH, PROFILER_MAX_FREQUENCY},
+        stats::{KernelStats, MemoryStats, ALL_HARTS},
+        task::MAX_PENDING_TIMERS,
+    },
+    task::Tid,
 };
-use librust::error::SyscallError;
 
 pub fn print(task: &mut Task, start: VirtualAddress, len: usize) -> Result<(), SyscallError> {
     let user_slice = RawUserSlice::readable(start, len);
@@ -24,8 +47,512 @@ pub fn print(task: &mut Task, start: VirtualAddress, len: usize) -> Result<(), S
 
     log::trace!("Attempting to print memory at {:#p} (len={})", start, len);
 
-    let mut console = crate::io::CONSOLE.lock();
-    user_slice.with(|bytes| bytes.iter().copied().for_each(|b| console.write(b)));
+    user_slice.with(crate::io::write_bytes);
+
+    Ok(())
+}
+
+pub fn set_timer(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let id = regs.a1;
+    let micros = regs.a2 as u64;
+
+    // Timers whose deadlines have passed have either fired already or are
+    // about to, so they no longer count against the limit
+    let now = crate::csr::time::read();
+    task.timers.retain(|handle| handle.deadline() > now);
+    if task.timers.len() >= MAX_PENDING_TIMERS {
+        return Err(SyscallError::QuotaExceeded);
+    }
+
+    log::trace!("Task {} arming timer {} for {}us", task.name, id, micros);
+    task.timers.push(timer::arm(task.tid, id, micros));
+
+    Ok(())
+}
+
+pub fn read_wall_clock(_: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let (since_epoch, synchronized) = time::wall_clock();
+    regs.a1 = since_epoch.as_nanos() as usize;
+    regs.a2 = synchronized as usize;
+
+    Ok(())
+}
+
+/// Make sure `cptr` is a clock capability which can be used to set the wall
+/// clock. Reading it is open to everyone, but only the time service should be
+/// able to skew it.
+fn check_clock_capability(task: &Task, cptr: usize) -> Result<(), SyscallError> {
+    match task.cspace.resolve(CapabilityPtr::new(cptr)) {
+        Some(Capability { resource: CapabilityResource::Clock, rights }) if *rights & CapabilityRights::WRITE => Ok(()),
+        Some(Capability { resource: CapabilityResource::Clock, .. }) => Err(SyscallError::InsufficientRights(0)),
+        _ => Err(SyscallError::InvalidArgument(0)),
+    }
+}
+
+pub fn set_time(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    check_clock_capability(task, regs.a1)?;
+
+    match regs.a2 {
+        0 => {
+            let since_epoch = Duration::from_nanos(regs.a3 as u64);
+            log::info!("Task {} set the wall clock to {}s since the epoch", task.name, since_epoch.as_secs());
+            time::step_wall_clock(since_epoch);
+        }
+        1 => {
+            let offset = regs.a3 as i64;
+            log::debug!("Task {} slewing the wall clock by {}ns", task.name, offset);
+            time::slew_wall_clock(offset);
+        }
+        _ => return Err(SyscallError::InvalidArgument(1)),
+    }
+
+    Ok(())
+}
+
+/// Make sure `cptr` is a console capability with `rights`
+fn check_console_capability(task: &Task, cptr: usize, rights: CapabilityRights) -> Result<(), SyscallError> {
+    match task.cspace.resolve(CapabilityPtr::new(cptr)) {
+        Some(Capability { resource: CapabilityResource::Console, rights: held }) if *held & rights => Ok(()),
+        Some(Capability { resource: CapabilityResource::Console, .. }) => Err(SyscallError::InsufficientRights(0)),
+        _ => Err(SyscallError::InvalidArgument(0)),
+    }
+}
+
+pub fn set_console_sinks(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    check_console_capability(task, regs.a1, CapabilityRights::WRITE)?;
+
+    let sinks = ConsoleSinks::new(regs.a2);
+    if sinks.value() & !ConsoleSinks::ALL.value() != 0 {
+        return Err(SyscallError::InvalidArgument(1));
+    }
+
+    log::debug!("Task {} routing console output to {:?}", task.name, sinks);
+    regs.a1 = crate::io::set_console_sinks(sinks).value();
+
+    Ok(())
+}
+
+pub fn read_console_ring(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    check_console_capability(task, regs.a1, CapabilityRights::READ)?;
+
+    let buffer_ptr = VirtualAddress::new(regs.a2);
+    let mut buffer: ValidatedUserSlice<ReadWrite, u8> =
+        match unsafe { RawUserSlice::writable(buffer_ptr, regs.a3).validate(&task.memory_manager) } {
+            Ok(buffer) => buffer,
+            Err((_, e)) => {
+                log::debug!("Bad console ring buffer @ {:#p}: {:?}", buffer_ptr, e);
+                return Err(SyscallError::InvalidArgument(1));
+            }
+        };
+
+    let (read, next) = buffer.with(|buffer| crate::io::CONSOLE_RING.lock().read_from(regs.a4 as u64, buffer));
+    regs.a1 = read;
+    regs.a2 = next as usize;
+
+    Ok(())
+}
+
+pub fn get_random(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let buffer_ptr = VirtualAddress::new(regs.a1);
+    let buffer: ValidatedUserSlice<ReadWrite, u8> =
+        match unsafe { RawUserSlice::new(buffer_ptr, regs.a2).validate(&task.memory_manager) } {
+            Ok(slice) => slice,
+            Err((_, e)) => {
+                log::debug!("Bad random buffer @ {:#p}: {:?}", buffer_ptr, e);
+                return Err(SyscallError::InvalidArgument(0));
+            }
+        };
+
+    random::fill(&mut buffer.guarded());
+
+    Ok(())
+}
+
+pub fn read_boot_id(_: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let boot_id = random::boot_id();
+    regs.a1 = (boot_id >> 64) as usize;
+    regs.a2 = boot_id as usize;
+
+    Ok(())
+}
+
+pub fn add_entropy(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let data_ptr = VirtualAddress::new(regs.a1);
+    let data = match unsafe { RawUserSlice::readable(data_ptr, regs.a2).validate(&task.memory_manager) } {
+        Ok(slice) => slice,
+        Err((_, e)) => {
+            log::debug!("Bad entropy buffer @ {:#p}: {:?}", data_ptr, e);
+            return Err(SyscallError::InvalidArgument(0));
+        }
+    };
+
+    data.with(random::add_uncredited_entropy);
+
+    Ok(())
+}
+
+pub fn set_fault_handler(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let entry = VirtualAddress::new(regs.a1);
+    let stack_top = VirtualAddress::new(regs.a2);
+
+    if regs.a1 == 0 {
+        log::trace!("Task {} removed its fault handler", task.name);
+        task.fault_handler = None;
+        return Ok(());
+    }
+
+    if entry.is_kernel_region() {
+        return Err(SyscallError::InvalidArgument(0));
+    }
+
+    // The stack is only checked when the handler is entered, since it can be
+    // unmapped at any point before then
+    if stack_top.is_kernel_region() || stack_top.as_usize() % 16 != 0 {
+        return Err(SyscallError::InvalidArgument(1));
+    }
+
+    log::trace!("Task {} set its fault handler to {:#p} (stack: {:#p})", task.name, entry, stack_top);
+    task.fault_handler = Some(FaultHandler { entry, stack_top });
+
+    Ok(())
+}
+
+/// Make sure `cptr` is a power capability which can be used to reset the
+/// system, which is argument `arg` of the syscall
+fn check_power_capability(task: &Task, cptr: usize, arg: u32) -> Result<(), SyscallError> {
+    match task.cspace.resolve(CapabilityPtr::new(cptr)) {
+        Some(Capability { resource: CapabilityResource::Power, rights }) if *rights & CapabilityRights::WRITE => Ok(()),
+        Some(Capability { resource: CapabilityResource::Power, .. }) => Err(SyscallError::InsufficientRights(arg)),
+        _ => Err(SyscallError::InvalidArgument(arg)),
+    }
+}
+
+pub fn system_reset(task: &mut Task, regs: &mut GeneralRegisters, kind: ResetKind) -> Result<(), SyscallError> {
+    check_power_capability(task, regs.a1, 0)?;
+
+    match power::request(task, kind) {
+        Ok(()) => Ok(()),
+        Err(power::PowerError::AlreadyPending) => Err(SyscallError::InvalidOperation(0)),
+    }
+}
+
+pub fn system_suspend(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    check_power_capability(task, regs.a1, 0)?;
+
+    let wake_after_us = match regs.a2 {
+        0 => None,
+        micros => Some(micros as u64),
+    };
+
+    log::info!("Task {} requested a suspend", task.name);
+    match suspend::suspend(wake_after_us) {
+        Ok(()) => Ok(()),
+        Err(suspend::SuspendError::AlreadySuspending) => Err(SyscallError::InvalidOperation(0)),
+        Err(suspend::SuspendError::HartsBusy | suspend::SuspendError::DeviceFailed(_)) => Err(SyscallError::WouldBlock),
+    }
+}
+
+pub fn hart_offline(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    check_power_capability(task, regs.a1, 0)?;
+
+    log::info!("Task {} requested hart {} go offline", task.name, regs.a2);
+    hotplug::offline(regs.a2).map_err(hotplug_error)
+}
+
+pub fn hart_online(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    check_power_capability(task, regs.a1, 0)?;
+
+    log::info!("Task {} requested hart {} come online", task.name, regs.a2);
+    hotplug::online(regs.a2).map_err(hotplug_error)
+}
+
+fn hotplug_error(error: hotplug::HotplugError) -> SyscallError {
+    match error {
+        hotplug::HotplugError::NoSuchHart => SyscallError::InvalidArgument(1),
+        hotplug::HotplugError::AlreadyOnline
+        | hotplug::HotplugError::AlreadyOffline
+        | hotplug::HotplugError::LastHart => SyscallError::InvalidOperation(1),
+        hotplug::HotplugError::Busy | hotplug::HotplugError::StartFailed => SyscallError::WouldBlock,
+    }
+}
+
+pub fn subscribe_shutdown(task: &mut Task, _: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    log::debug!("Task {} subscribed to shutdown notifications", task.name);
+    power::subscribe(task.tid);
+
+    Ok(())
+}
+
+pub fn acknowledge_shutdown(task: &mut Task, _: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    log::debug!("Task {} acknowledged the shutdown", task.name);
+    // The task may well stop petting the watchdog once it's done cleaning up
+    watchdog::disarm(task.tid);
+    power::acknowledge(task.tid);
+
+    Ok(())
+}
+
+pub fn arm_watchdog(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    check_power_capability(task, regs.a1, 0)?;
+
+    let micros = regs.a2 as u64;
+    if micros == 0 {
+        return Err(SyscallError::InvalidArgument(1));
+    }
+
+    log::debug!("Task {} armed the watchdog with a {}us timeout", task.name, micros);
+    watchdog::arm(task.tid, micros);
+
+    Ok(())
+}
+
+pub fn pet_watchdog(task: &mut Task, _: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    watchdog::pet(task.tid);
+    Ok(())
+}
+
+pub fn disarm_watchdog(task: &mut Task, _: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    log::debug!("Task {} disarmed the watchdog", task.name);
+    watchdog::disarm(task.tid);
+
+    Ok(())
+}
+
+pub fn kernel_stats(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let hart = match regs.a1 {
+        ALL_HARTS => None,
+        hart => Some(hart),
+    };
+
+    let out_ptr = VirtualAddress::new(regs.a2);
+    let mut out =
+        match unsafe { RawUserPtr::<ReadWrite, KernelStats>::writable(out_ptr).validate(&task.memory_manager) } {
+            Ok(out) => out,
+            Err(e) => {
+                log::debug!("Bad stats pointer @ {:#p}: {:?}", out_ptr, e);
+                return Err(SyscallError::InvalidArgument(1));
+            }
+        };
+
+    let snapshot = stats::snapshot(hart).ok_or(SyscallError::InvalidArgument(0))?;
+    out.with(|out| *out = snapshot);
+    regs.a1 = stats::n_harts();
+
+    Ok(())
+}
+
+pub fn memory_stats(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let out_ptr = VirtualAddress::new(regs.a2);
+    let mut out =
+        match unsafe { RawUserPtr::<ReadWrite, MemoryStats>::writable(out_ptr).validate(&task.memory_manager) } {
+            Ok(out) => out,
+            Err(e) => {
+                log::debug!("Bad memory stats pointer @ {:#p}: {:?}", out_ptr, e);
+                return Err(SyscallError::InvalidArgument(1));
+            }
+        };
+
+    // The current task is already locked
+    let resident = match NonZeroUsize::new(regs.a1).map(Tid::new) {
+        None => task.memory_manager.resident_size(),
+        Some(tid) if tid == task.tid => task.memory_manager.resident_size(),
+        Some(tid) => TASKS.get(tid).ok_or(SyscallError::InvalidArgument(0))?.lock().memory_manager.resident_size(),
+    };
+
+    let mut stats = stats::memory();
+    stats.resident = resident as u64;
+    out.with(|out| *out = stats);
+
+    Ok(())
+}
+
+pub fn start_profiler(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    super::debug::check_debug_capability(task, CapabilityPtr::new(regs.a1))?;
+
+    let frequency = match regs.a2 {
+        1..=PROFILER_MAX_FREQUENCY => regs.a2 as u64,
+        _ => return Err(SyscallError::InvalidArgument(1)),
+    };
+
+    let depth = match regs.a3 {
+        0..=PROFILER_MAX_DEPTH => regs.a3,
+        _ => return Err(SyscallError::InvalidArgument(2)),
+    };
+
+    log::info!("Task {} started the profiler at {} Hz", task.name, frequency);
+    profiler::start(crate::TIMER_FREQ.load(core::sync::atomic::Ordering::Relaxed) / frequency, depth);
+
+    Ok(())
+}
+
+pub fn stop_profiler(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    super::debug::check_debug_capability(task, CapabilityPtr::new(regs.a1))?;
+
+    log::info!("Task {} stopped the profiler", task.name);
+    profiler::stop();
+
+    Ok(())
+}
+
+pub fn drain_profile_samples(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    super::debug::check_debug_capability(task, CapabilityPtr::new(regs.a1))?;
+
+    let out_ptr = VirtualAddress::new(regs.a2);
+    let mut out: ValidatedUserSlice<ReadWrite, ProfileSample> =
+        match unsafe { RawUserSlice::new(out_ptr, regs.a3).validate(&task.memory_manager) } {
+            Ok(out) => out,
+            Err((_, e)) => {
+                log::debug!("Bad profile sample buffer @ {:#p}: {:?}", out_ptr, e);
+                return Err(SyscallError::InvalidArgument(1));
+            }
+        };
+
+    let (drained, dropped) = out.with(profiler::drain);
+    regs.a1 = drained;
+    regs.a2 = dropped;
+
+    Ok(())
+}
+
+pub fn resolve_kernel_symbol(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    super::debug::check_debug_capability(task, CapabilityPtr::new(regs.a1))?;
+
+    let name_ptr = VirtualAddress::new(regs.a3);
+    let mut name =
+        match unsafe { RawUserSlice::<ReadWrite, u8>::writable(name_ptr, regs.a4).validate(&task.memory_manager) } {
+            Ok(name) => name,
+            Err((_, e)) => {
+                log::debug!("Bad symbol name buffer @ {:#p}: {:?}", name_ptr, e);
+                return Err(SyscallError::InvalidArgument(2));
+            }
+        };
+
+    match symbols::resolve(regs.a2) {
+        Some(resolved) => {
+            let symbol = resolved.symbol.name().as_bytes();
+            let len = symbol.len().min(name.len());
+            name.with(|name| name[..len].copy_from_slice(&symbol[..len]));
+
+            regs.a1 = len;
+            regs.a2 = resolved.offset;
+        }
+        None => regs.a1 = usize::MAX,
+    }
+
+    Ok(())
+}
+
+pub fn futex_wait(task: &mut Task, regs: &mut GeneralRegisters) -> Result<super::Outcome, SyscallError> {
+    let addr = VirtualAddress::new(regs.a1);
+    let expected = regs.a2 as u32;
+    let timeout_us = match regs.a3 {
+        usize::MAX => None,
+        micros => Some(micros as u64),
+    };
+
+    let word = match unsafe { RawUserPtr::<Read, u32>::readable(addr).validate(&task.memory_manager) } {
+        Ok(word) => word,
+        Err(e) => {
+            log::debug!("Bad futex address @ {:#p}: {:?}", addr, e);
+            return Err(SyscallError::InvalidArgument(0));
+        }
+    };
+
+    let key = task.memory_manager.resolve(addr).ok_or(SyscallError::InvalidArgument(0))?;
+    match futex::wait(task.tid, key, || word.read() == expected, timeout_us) {
+        true => Ok(super::Outcome::Blocked),
+        false => Err(SyscallError::WouldBlock),
+    }
+}
+
+pub fn futex_wake(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let addr = VirtualAddress::new(regs.a1);
+    if addr.as_usize() % 4 != 0 {
+        return Err(SyscallError::InvalidArgument(0));
+    }
+
+    let key = task.memory_manager.resolve(addr).ok_or(SyscallError::InvalidArgument(0))?;
+    regs.a1 = futex::wake(key, regs.a2);
+
+    Ok(())
+}
+
+pub fn futex_requeue(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let from = VirtualAddress::new(regs.a1);
+    let expected = regs.a2 as u32;
+    let to = VirtualAddress::new(regs.a3);
+
+    let word = match unsafe { RawUserPtr::<Read, u32>::readable(from).validate(&task.memory_manager) } {
+        Ok(word) => word,
+        Err(e) => {
+            log::debug!("Bad futex address @ {:#p}: {:?}", from, e);
+            return Err(SyscallError::InvalidArgument(0));
+        }
+    };
+
+    if to.as_usize() % 4 != 0 {
+        return Err(SyscallError::InvalidArgument(2));
+    }
+
+    let from_key = task.memory_manager.resolve(from).ok_or(SyscallError::InvalidArgument(0))?;
+    let to_key = task.memory_manager.resolve(to).ok_or(SyscallError::InvalidArgument(2))?;
+
+    match futex::requeue(from_key, to_key, || word.read() == expected, regs.a4, regs.a5) {
+        Some((woken, requeued)) => {
+            regs.a1 = woken;
+            regs.a2 = requeued;
+            Ok(())
+        }
+        None => Err(SyscallError::WouldBlock),
+    }
+}
+
+pub fn create_task_group(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let group = job::new_group();
+    let cptr = task.cspace.mint(Capability {
+        resource: CapabilityResource::TaskGroup(group),
+        rights: CapabilityRights::READ | CapabilityRights::WRITE | CapabilityRights::GRANT,
+    });
+
+    log::debug!("Task {} created task group {}", task.name, group);
+    regs.a1 = cptr.value();
+
+    Ok(())
+}
+
+/// Look up the task group `cptr` refers to, making sure the capability has
+/// `right`. `cptr` is argument `arg` of the syscall.
+fn task_group_capability(task: &Task, cptr: usize, right: CapabilityRights, arg: u32) -> Result<usize, SyscallError> {
+    match task.cspace.resolve(CapabilityPtr::new(cptr)) {
+        Some(Capability { resource: CapabilityResource::TaskGroup(group), rights }) if *rights & right => Ok(*group),
+        Some(Capability { resource: CapabilityResource::TaskGroup(_), .. }) => {
+            Err(SyscallError::InsufficientRights(arg))
+        }
+        _ => Err(SyscallError::InvalidArgument(arg)),
+    }
+}
+
+pub fn join_task_group(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let group = task_group_capability(task, regs.a1, CapabilityRights::READ, 0)?;
+
+    log::debug!("Task {} joined task group {}", task.name, group);
+    task.group = group;
+
+    Ok(())
+}
+
+pub fn signal_task_group(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let group = task_group_capability(task, regs.a1, CapabilityRights::WRITE, 0)?;
+    let signal = JobSignal::from_usize(regs.a2).ok_or(SyscallError::InvalidArgument(1))?;
+
+    job::signal(task, group, signal);
+
+    Ok(())
+}
+
+pub fn handle_job_control(task: &mut Task, _: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    log::debug!("Task {} is handling its own job control", task.name);
+    task.handles_job_control = true;
 
     Ok(())
 }
@@ -6,23 +6,37 @@
 // obtain one at https://mozilla.org/MPL/2.0/.
 
 pub mod channel;
+pub mod checkpoint;
+pub mod debug;
 pub mod io;
 pub mod mem;
 pub mod misc;
+pub mod pipe;
+pub mod snoop;
+pub mod topic;
 pub mod vmspace;
 
 use crate::{
-    mem::paging::VirtualAddress,
+    mem::{
+        paging::VirtualAddress,
+        swap::{self, FaultIn},
+    },
     scheduler::{Scheduler, SCHEDULER},
+    stats::{self, Event},
     task::TaskState,
     trap::TrapFrame,
 };
-use librust::{error::SyscallError, syscalls::Syscall};
+use librust::{
+    error::SyscallError,
+    syscalls::{power::ResetKind, Syscall},
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Outcome {
     Blocked,
     Completed,
+    /// The syscall needs to be made again from the start
+    Restarted,
 }
 
 pub fn handle(frame: &mut TrapFrame, sepc: usize) -> Outcome {
@@ -30,6 +44,12 @@ pub fn handle(frame: &mut TrapFrame, sepc: usize) -> Outcome {
     let mut task_lock = task_lock.lock();
     let task = &mut *task_lock;
 
+    // Kept around in case the syscall touches memory which has been swapped
+    // out, not allocated yet, or shared copy-on-write, and has to be restarted
+    // once it's usable
+    let original = frame.registers;
+    task.memory_manager.take_missing_access();
+
     let mut regs = &mut frame.registers;
 
     let syscall = match Syscall::from_usize(regs.a0) {
@@ -40,10 +60,13 @@ pub fn handle(frame: &mut TrapFrame, sepc: usize) -> Outcome {
         }
     };
 
+    stats::count(Event::Syscall(syscall as usize));
+
     let res = match syscall {
         Syscall::Exit => {
             log::trace!("Task {} ({:?}) exited", task.tid, task.name);
             task.state = TaskState::Dead;
+            crate::task::reap_later(task.tid);
             drop(task_lock);
             SCHEDULER.schedule();
         }
@@ -51,38 +74,167 @@ pub fn handle(frame: &mut TrapFrame, sepc: usize) -> Outcome {
             regs.a1 = task.tid.value();
             Ok(())
         }
+        Syscall::KernelConfig => {
+            regs.a1 = crate::config::kernel_config().value();
+            Ok(())
+        }
         Syscall::DebugPrint => misc::print(task, VirtualAddress::new(regs.a1), regs.a2),
         Syscall::AllocDmaMemory => mem::alloc_dma_memory(task, regs),
+        Syscall::DeallocDmaMemory => mem::dealloc_dma_memory(task, regs),
+        Syscall::SyncDmaMemory => mem::sync_dma_memory(task, regs),
         Syscall::AllocVirtualMemory => mem::alloc_virtual_memory(task, regs),
         Syscall::ClaimDevice => io::claim_device(task, regs),
         Syscall::CompleteInterrupt => io::complete_interrupt(task, regs),
         Syscall::CreateVmspace => vmspace::create_vmspace(task, regs),
         Syscall::AllocVmspaceObject => vmspace::alloc_vmspace_object(task, regs),
+        Syscall::ShareVmspaceObject => vmspace::share_vmspace_object(task, regs),
         Syscall::SpawnVmspace => vmspace::spawn_vmspace(task, regs),
-        Syscall::QueryMemoryCapability => mem::query_mem_cap(task, regs),
-        Syscall::QueryMmioCapability => mem::query_mmio_cap(task, regs),
-        Syscall::ReadChannel => match channel::read_message(task, regs) {
-            Ok(Outcome::Blocked) => {
-                let tid = task.tid;
-                task.context.gp_regs = frame.registers;
-                task.context.pc = sepc;
+        Syscall::ExecVmspace => match vmspace::exec_vmspace(task, regs) {
+            // The task starts over in its new address space the next time
+            // it's scheduled
+            Ok(()) => {
                 drop(task_lock);
-                SCHEDULER.block(tid);
-                return Outcome::Blocked;
+                SCHEDULER.schedule();
             }
-            Ok(Outcome::Completed) => Ok(()),
             Err(e) => Err(e),
         },
+        Syscall::QueryMemoryCapability => mem::query_mem_cap(task, regs),
+        Syscall::QueryMemory => mem::query_memory(task, regs),
+        Syscall::QueryMmioCapability => mem::query_mmio_cap(task, regs),
+        Syscall::ReadChannel | Syscall::FutexWait | Syscall::ReadPipe | Syscall::WritePipe => {
+            let outcome = match syscall {
+                Syscall::FutexWait => misc::futex_wait(task, regs),
+                Syscall::ReadPipe => pipe::read_pipe(task, regs),
+                Syscall::WritePipe => pipe::write_pipe(task, regs),
+                _ => channel::read_message(task, regs),
+            };
+
+            match outcome {
+                Ok(Outcome::Blocked) => {
+                    let tid = task.tid;
+                    task.context.gp_regs = frame.registers;
+                    task.context.pc = sepc;
+                    drop(task_lock);
+                    SCHEDULER.block(tid);
+                    return Outcome::Blocked;
+                }
+                Ok(Outcome::Completed | Outcome::Restarted) => Ok(()),
+                Err(e) => Err(e),
+            }
+        }
         Syscall::WriteChannel => channel::send_message(task, regs),
+        Syscall::SetChannelQueueDepth => channel::set_queue_depth(task, regs),
+        Syscall::CreateTopic => topic::create_topic(task, regs),
+        Syscall::SubscribeTopic => topic::subscribe_topic(task, regs),
+        Syscall::PublishTopic => topic::publish_topic(task, regs),
+        Syscall::CreatePipe => pipe::create_pipe(task, regs),
+        Syscall::ClosePipe => pipe::close_pipe(task, regs),
         Syscall::MintCapability => todo!(),
         Syscall::RevokeCapability => todo!(),
         Syscall::EnableNotifications => Ok(task.subscribes_to_events = true),
+        Syscall::SetTimer => misc::set_timer(task, regs),
+        Syscall::ReadWallClock => misc::read_wall_clock(task, regs),
+        Syscall::SetTime => misc::set_time(task, regs),
+        Syscall::SetConsoleSinks => misc::set_console_sinks(task, regs),
+        Syscall::ReadConsoleRing => misc::read_console_ring(task, regs),
+        Syscall::InflateBalloon => mem::inflate_balloon(task, regs),
+        Syscall::DeflateBalloon => mem::deflate_balloon(task, regs),
+        Syscall::GetRandom => misc::get_random(task, regs),
+        Syscall::ReadBootId => misc::read_boot_id(task, regs),
+        Syscall::AddEntropy => misc::add_entropy(task, regs),
+        Syscall::SetFaultHandler => misc::set_fault_handler(task, regs),
+        Syscall::SystemShutdown => misc::system_reset(task, regs, ResetKind::Shutdown),
+        Syscall::SystemReboot => misc::system_reset(task, regs, ResetKind::Reboot),
+        Syscall::SystemSuspend => misc::system_suspend(task, regs),
+        Syscall::HartOffline => misc::hart_offline(task, regs),
+        Syscall::HartOnline => misc::hart_online(task, regs),
+        Syscall::StartProfiler => misc::start_profiler(task, regs),
+        Syscall::StopProfiler => misc::stop_profiler(task, regs),
+        Syscall::DrainProfileSamples => misc::drain_profile_samples(task, regs),
+        Syscall::ResolveKernelSymbol => misc::resolve_kernel_symbol(task, regs),
+        Syscall::SnoopChannels => snoop::snoop_channels(task, regs),
+        Syscall::DumpPageTable => debug::dump_page_table(task, regs),
+        Syscall::ReadMemoryMap => debug::read_memory_map(task, regs),
+        Syscall::SubscribeShutdown => misc::subscribe_shutdown(task, regs),
+        Syscall::AcknowledgeShutdown => misc::acknowledge_shutdown(task, regs),
+        Syscall::ArmWatchdog => misc::arm_watchdog(task, regs),
+        Syscall::PetWatchdog => misc::pet_watchdog(task, regs),
+        Syscall::DisarmWatchdog => misc::disarm_watchdog(task, regs),
+        Syscall::SharedMemorySize => mem::shared_memory_size(task, regs),
+        Syscall::GrowSharedMemory => mem::grow_shared_memory(task, regs),
+        Syscall::ResizeMemory => mem::resize_memory(task, regs),
+        Syscall::MapSharedMemory => mem::map_shared_memory(task, regs),
+        Syscall::MapSharedMemoryCopy => mem::map_shared_memory_copy(task, regs),
+        Syscall::SealMemory => mem::seal_memory(task, regs),
+        Syscall::ModifyMemoryPermissions => mem::modify_memory_permissions(task, regs),
+        Syscall::KernelStats => misc::kernel_stats(task, regs),
+        Syscall::MemoryStats => misc::memory_stats(task, regs),
+        Syscall::FutexWake => misc::futex_wake(task, regs),
+        Syscall::FutexRequeue => misc::futex_requeue(task, regs),
+        Syscall::CreateTaskGroup => misc::create_task_group(task, regs),
+        Syscall::JoinTaskGroup => misc::join_task_group(task, regs),
+        Syscall::SignalTaskGroup => misc::signal_task_group(task, regs),
+        Syscall::HandleJobControl => misc::handle_job_control(task, regs),
+        Syscall::EnableSwap => mem::enable_swap(task, regs),
+        Syscall::SubscribeMemoryPressure => mem::subscribe_memory_pressure(task, regs),
+        Syscall::SnapshotTask => checkpoint::snapshot_task(task, regs, sepc),
+        Syscall::RestoreTask => {
+            let restores_itself = regs.a2 == task.tid.value();
+            match checkpoint::restore_task(task, regs) {
+                // Like with exec, the task picks up from the checkpoint the
+                // next time it's scheduled
+                Ok(()) if restores_itself => {
+                    drop(task_lock);
+                    SCHEDULER.schedule();
+                }
+                res => res,
+            }
+        }
+        Syscall::ReadCheckpointRegisters => checkpoint::read_checkpoint_registers(task, regs),
+        Syscall::ReadCheckpointMemory => checkpoint::read_checkpoint_memory(task, regs),
+        Syscall::DiscardCheckpoint => checkpoint::discard_checkpoint(task, regs),
+        Syscall::TakeSwapWrites => mem::take_swap_writes(task, regs),
+        Syscall::TakeSwapReads => mem::take_swap_reads(task, regs),
+        Syscall::CompleteSwapRead => mem::complete_swap_read(task, regs),
+        Syscall::PinDmaMemory => mem::pin_dma_memory(task, regs),
+        Syscall::UnpinDmaMemory => mem::unpin_dma_memory(task, regs),
     };
 
+    if let (Err(_), Some(at)) = (res, task.memory_manager.take_missing_access()) {
+        if task.memory_manager.populate(at) || task.memory_manager.break_cow(at) {
+            *regs = original;
+            return Outcome::Restarted;
+        }
+
+        match swap::fault_in(task, at) {
+            Some(FaultIn::Resident) => {
+                *regs = original;
+                return Outcome::Restarted;
+            }
+            Some(FaultIn::Wait) => {
+                let tid = task.tid;
+                task.context.gp_regs = original;
+                task.context.pc = sepc;
+                drop(task_lock);
+                SCHEDULER.block(tid);
+                return Outcome::Blocked;
+            }
+            None => {}
+        }
+    }
+
     match res {
         Ok(()) => regs.a0 = 0,
         Err(e) => regs.a0 = usize::from(e),
     }
 
+    #[cfg(feature = "debug.replay")]
+    if crate::replay::preempt_at_syscall(task.tid) {
+        task.context.gp_regs = frame.registers;
+        task.context.pc = sepc + 4;
+        drop(task_lock);
+        SCHEDULER.schedule();
+    }
+
     Outcome::Completed
 }
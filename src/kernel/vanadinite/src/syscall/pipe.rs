@@ -0,0 +1,294 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Pipes
+//!
+//! A pipe is a bounded byte buffer with a read end and a write end. Each end is
+//! a capability, and every copy of one sent to another task counts as another
+//! open end, so the pipe knows when the last of either end is gone: reading
+//! from an empty pipe with no write ends left returns end of file, and writing
+//! to a pipe with no read ends left fails.
+//!
+//! Tasks which have to wait to read or write are woken up to make the syscall
+//! again from the start, since another task may have got to the pipe first.
+
+use crate::{
+    capabilities::{Capability, CapabilityResource},
+    mem::{
+        paging::VirtualAddress,
+        user::{self, RawUserSlice},
+    },
+    scheduler::{Scheduler, WakeToken, SCHEDULER, TASKS},
+    task::Task,
+    trap::GeneralRegisters,
+};
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use librust::{
+    capabilities::{CapabilityPtr, CapabilityRights},
+    error::SyscallError,
+    syscalls::pipe::{PipeFlags, DEFAULT_PIPE_CAPACITY, MAX_PIPE_CAPACITY},
+    task::Tid,
+};
+use sync::SpinMutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipeEndKind {
+    Read,
+    Write,
+}
+
+#[derive(Debug)]
+struct Pipe {
+    buffer: VecDeque<u8>,
+    capacity: usize,
+    readers: usize,
+    writers: usize,
+    /// Tasks waiting for something to read, or for the last write end to go
+    waiting_readers: Vec<Tid>,
+    /// Tasks waiting for room to write, or for the last read end to go
+    waiting_writers: Vec<Tid>,
+}
+
+/// One end of a pipe, which keeps count of how many of each end there are
+#[derive(Debug)]
+pub struct PipeEnd {
+    pipe: Arc<SpinMutex<Pipe>>,
+    kind: PipeEndKind,
+}
+
+impl PipeEnd {
+    /// Create a pipe holding up to `capacity` bytes, returning its read end and
+    /// write end
+    fn new_pair(capacity: usize) -> (Self, Self) {
+        let pipe = Arc::new(SpinMutex::new(Pipe {
+            buffer: VecDeque::with_capacity(capacity),
+            capacity,
+            readers: 1,
+            writers: 1,
+            waiting_readers: Vec::new(),
+            waiting_writers: Vec::new(),
+        }));
+
+        (Self { pipe: Arc::clone(&pipe), kind: PipeEndKind::Read }, Self { pipe, kind: PipeEndKind::Write })
+    }
+
+    pub fn kind(&self) -> PipeEndKind {
+        self.kind
+    }
+}
+
+impl Clone for PipeEnd {
+    fn clone(&self) -> Self {
+        let mut pipe = self.pipe.lock();
+        match self.kind {
+            PipeEndKind::Read => pipe.readers += 1,
+            PipeEndKind::Write => pipe.writers += 1,
+        }
+
+        Self { pipe: Arc::clone(&self.pipe), kind: self.kind }
+    }
+}
+
+impl Drop for PipeEnd {
+    fn drop(&mut self) {
+        let mut pipe = self.pipe.lock();
+        // Whoever's waiting on the other end needs to find out it's gone
+        let waiting = match self.kind {
+            PipeEndKind::Read => {
+                pipe.readers -= 1;
+                match pipe.readers {
+                    0 => core::mem::take(&mut pipe.waiting_writers),
+                    _ => Vec::new(),
+                }
+            }
+            PipeEndKind::Write => {
+                pipe.writers -= 1;
+                match pipe.writers {
+                    0 => core::mem::take(&mut pipe.waiting_readers),
+                    _ => Vec::new(),
+                }
+            }
+        };
+
+        drop(pipe);
+        wake(waiting);
+    }
+}
+
+/// Wake up tasks which were waiting on a pipe so they make their syscall again
+fn wake(waiting: Vec<Tid>) {
+    for tid in waiting {
+        // The task might have exited while it was waiting
+        if TASKS.get(tid).is_none() {
+            continue;
+        }
+
+        // The waiter commits to blocking while it holds the pipe lock, but
+        // could still be on its way into the scheduler on another hart
+        while !SCHEDULER.is_blocked(tid) {
+            core::hint::spin_loop();
+        }
+
+        // Waking a task steps it over the syscall it was blocked in, but it
+        // has to make it again
+        SCHEDULER.unblock(WakeToken::new(tid, |task| task.context.pc -= 4));
+    }
+}
+
+fn resolve_end(task: &Task, cptr: CapabilityPtr, kind: PipeEndKind) -> Result<&PipeEnd, SyscallError> {
+    let right = match kind {
+        PipeEndKind::Read => CapabilityRights::READ,
+        PipeEndKind::Write => CapabilityRights::WRITE,
+    };
+
+    match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::Pipe(end), rights }) if end.kind == kind => {
+            match *rights & right {
+                true => Ok(end),
+                false => Err(SyscallError::InsufficientRights(0)),
+            }
+        }
+        _ => Err(SyscallError::InvalidArgument(0)),
+    }
+}
+
+pub fn create_pipe(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let capacity = match regs.a1 {
+        0 => DEFAULT_PIPE_CAPACITY,
+        capacity if capacity <= MAX_PIPE_CAPACITY => capacity,
+        _ => return Err(SyscallError::InvalidArgument(0)),
+    };
+
+    let (read_end, write_end) = PipeEnd::new_pair(capacity);
+    regs.a1 = task
+        .cspace
+        .mint(Capability {
+            resource: CapabilityResource::Pipe(read_end),
+            rights: CapabilityRights::READ | CapabilityRights::GRANT,
+        })
+        .value();
+    regs.a2 = task
+        .cspace
+        .mint(Capability {
+            resource: CapabilityResource::Pipe(write_end),
+            rights: CapabilityRights::WRITE | CapabilityRights::GRANT,
+        })
+        .value();
+
+    log::debug!("[{}:{}] Created pipe holding {} bytes", task.name, task.tid, capacity);
+
+    Ok(())
+}
+
+pub fn read_pipe(task: &mut Task, regs: &mut GeneralRegisters) -> Result<super::Outcome, SyscallError> {
+    let end = resolve_end(task, CapabilityPtr::new(regs.a1), PipeEndKind::Read)?;
+    let flags = PipeFlags::new(regs.a4);
+
+    let mut out = match unsafe {
+        RawUserSlice::<user::ReadWrite, u8>::new(VirtualAddress::new(regs.a2), regs.a3).validate(&task.memory_manager)
+    } {
+        Ok(out) => out,
+        Err((addr, e)) => {
+            log::debug!("Bad pipe read buffer @ {:#p}: {:?}", addr, e);
+            return Err(SyscallError::InvalidArgument(1));
+        }
+    };
+
+    let mut pipe = end.pipe.lock();
+    if pipe.buffer.is_empty() && pipe.writers > 0 && out.len() > 0 {
+        if flags & PipeFlags::NONBLOCKING {
+            return Err(SyscallError::WouldBlock);
+        }
+
+        pipe.waiting_readers.push(task.tid);
+        return Ok(super::Outcome::Blocked);
+    }
+
+    let read = out.with(|out| {
+        let read = out.len().min(pipe.buffer.len());
+        for (byte, read) in out.iter_mut().zip(pipe.buffer.drain(..read)) {
+            *byte = read;
+        }
+
+        read
+    });
+
+    let waiting = match read {
+        0 => Vec::new(),
+        _ => core::mem::take(&mut pipe.waiting_writers),
+    };
+
+    drop(pipe);
+    wake(waiting);
+
+    regs.a1 = read;
+
+    Ok(super::Outcome::Completed)
+}
+
+pub fn write_pipe(task: &mut Task, regs: &mut GeneralRegisters) -> Result<super::Outcome, SyscallError> {
+    let end = resolve_end(task, CapabilityPtr::new(regs.a1), PipeEndKind::Write)?;
+    let flags = PipeFlags::new(regs.a4);
+
+    let data = match unsafe {
+        RawUserSlice::<user::Read, u8>::new(VirtualAddress::new(regs.a2), regs.a3).validate(&task.memory_manager)
+    } {
+        Ok(data) => data,
+        Err((addr, e)) => {
+            log::debug!("Bad pipe write buffer @ {:#p}: {:?}", addr, e);
+            return Err(SyscallError::InvalidArgument(1));
+        }
+    };
+
+    let mut pipe = end.pipe.lock();
+    if pipe.readers == 0 {
+        return Err(SyscallError::InvalidOperation(0));
+    }
+
+    let room = pipe.capacity - pipe.buffer.len();
+    if room == 0 && data.len() > 0 {
+        if flags & PipeFlags::NONBLOCKING {
+            return Err(SyscallError::WouldBlock);
+        }
+
+        pipe.waiting_writers.push(task.tid);
+        return Ok(super::Outcome::Blocked);
+    }
+
+    let written = data.with(|data| {
+        let written = data.len().min(room);
+        pipe.buffer.extend(&data[..written]);
+
+        written
+    });
+
+    let waiting = match written {
+        0 => Vec::new(),
+        _ => core::mem::take(&mut pipe.waiting_readers),
+    };
+
+    drop(pipe);
+    wake(waiting);
+
+    regs.a1 = written;
+
+    Ok(super::Outcome::Completed)
+}
+
+pub fn close_pipe(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let cptr = CapabilityPtr::new(regs.a1);
+
+    match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::Pipe(_), .. }) => {
+            // Dropping the end wakes up anyone waiting on the other end if it
+            // was the last one
+            task.cspace.remove(cptr);
+            Ok(())
+        }
+        _ => Err(SyscallError::InvalidArgument(0)),
+    }
+}
@@ -0,0 +1,156 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Snooping on channels
+//!
+//! A task holding the debug capability can have every message queued on
+//! another task's channels reported to it, for working out what's going wrong
+//! in protocols spread over several servers. Snoopers hang off the message
+//! queues themselves, so they see messages going both ways regardless of which
+//! task sends them, and nothing is taken away from the task the messages were
+//! meant for. Reports are pushed straight onto the snooping channel's queue
+//! rather than sent through it, so snooping on a snooping channel can't report
+//! on itself.
+
+use super::channel::{ChannelMessage, MessagePriority, Sender, UserspaceChannel};
+use crate::{
+    capabilities::{Capability, CapabilityResource, CapabilitySpace},
+    scheduler::{Scheduler, SCHEDULER, TASKS},
+    task::Task,
+    trap::GeneralRegisters,
+};
+use alloc::vec::Vec;
+use core::num::NonZeroUsize;
+use librust::{
+    capabilities::{CapabilityPtr, CapabilityRights},
+    error::SyscallError,
+    syscalls::debug::{SnoopDirection, SnoopMode, SnoopRecord},
+    task::Tid,
+};
+
+/// Where the messages queued on one direction of a channel are reported to
+#[derive(Debug)]
+pub struct Snooper {
+    /// The snooping task's side of the channel reports are pushed to
+    sender: Sender,
+    mode: SnoopMode,
+    direction: SnoopDirection,
+    peer: Option<Tid>,
+    /// What the snooped task calls the channel
+    cptr: CapabilityPtr,
+    /// How many messages couldn't be reported since the last one which was
+    missed: usize,
+}
+
+impl Snooper {
+    /// Report a message which was just queued, returning whether the snooper
+    /// is still around to report to
+    pub(super) fn report(&mut self, data: [usize; 7], caps: usize, priority: MessagePriority) -> bool {
+        let snooping_tid = self.sender.other_tid.expect("[BUG] snooper without a snooping task");
+
+        // Like topic subscriptions, snoopers aren't cleaned up when the
+        // snooping task exits, so drop them the next time they'd be used
+        if TASKS.get(snooping_tid).is_none() {
+            return false;
+        }
+
+        let has_data = self.mode == SnoopMode::Copy;
+        let record = SnoopRecord {
+            direction: self.direction,
+            peer: self.peer,
+            cptr: self.cptr,
+            urgent: priority == MessagePriority::Urgent,
+            caps,
+            missed: self.missed,
+            has_data,
+        };
+
+        let mut queue = self.sender.inner.write();
+
+        // The record and the copy of the data are queued together or not at
+        // all, so the snooping task never has to guess what a message is
+        if !queue.has_room_for(1 + has_data as usize, MessagePriority::Normal) {
+            self.missed += 1;
+            return true;
+        }
+
+        queue.push_back(ChannelMessage { data: record.into_parts(), caps: Vec::new(), charge: None });
+        if has_data {
+            queue.push_back(ChannelMessage { data, caps: Vec::new(), charge: None });
+        }
+
+        drop(queue);
+        self.missed = 0;
+
+        if let Some(token) = self.sender.wake.lock().take() {
+            SCHEDULER.unblock(token);
+        }
+
+        true
+    }
+}
+
+pub fn snoop_channels(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    super::debug::check_debug_capability(task, CapabilityPtr::new(regs.a1))?;
+
+    let tid = NonZeroUsize::new(regs.a2).map(Tid::new).ok_or(SyscallError::InvalidArgument(1))?;
+    let peer = NonZeroUsize::new(regs.a3).map(Tid::new);
+    let mode = SnoopMode::from_usize(regs.a4).ok_or(SyscallError::InvalidArgument(3))?;
+
+    // The current task is already locked
+    let channels = match tid == task.tid {
+        true => channels_to(&task.cspace, peer),
+        false => channels_to(&TASKS.get(tid).ok_or(SyscallError::InvalidArgument(1))?.lock().cspace, peer),
+    };
+
+    if channels.is_empty() {
+        return Err(SyscallError::InvalidArgument(2));
+    }
+
+    let (mut snooper_side, snooping_side) = UserspaceChannel::new();
+    snooper_side.sender.other_tid = Some(task.tid);
+
+    let cptr = task.cspace.mint_with(|cptr| {
+        snooper_side.sender.other_cptr = cptr;
+        Capability { resource: CapabilityResource::Channel(snooping_side), rights: CapabilityRights::READ }
+    });
+
+    for (channel_cptr, channel) in &channels {
+        let queues =
+            [(SnoopDirection::Sent, &channel.sender.inner), (SnoopDirection::Received, &channel.receiver.inner)];
+        for (direction, queue) in queues {
+            queue.write().add_snooper(Snooper {
+                sender: snooper_side.sender.clone(),
+                mode,
+                direction,
+                peer: channel.sender.other_tid,
+                cptr: *channel_cptr,
+                missed: 0,
+            });
+        }
+    }
+
+    log::info!("Task {} is snooping on {} channels of task {} ({:?})", task.name, channels.len(), tid, mode);
+    regs.a1 = cptr.value();
+    regs.a2 = channels.len();
+
+    Ok(())
+}
+
+/// The channels in `cspace` whose other side is `peer`, or every channel if
+/// `peer` is `None`
+fn channels_to(cspace: &CapabilitySpace, peer: Option<Tid>) -> Vec<(CapabilityPtr, UserspaceChannel)> {
+    cspace
+        .all()
+        .filter_map(|(cptr, cap)| match &cap.resource {
+            CapabilityResource::Channel(channel) if peer.is_none() || channel.sender.other_tid == peer => {
+                Some((*cptr, channel.clone()))
+            }
+            _ => None,
+        })
+        .collect()
+}
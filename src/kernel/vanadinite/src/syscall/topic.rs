@@ -0,0 +1,230 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Publish-subscribe topics
+//!
+//! A topic hands a copy of every message published to it to each of its
+//! subscribers, for events lots of tasks care about at once such as devices
+//! coming and going, the system shutting down or network links changing state.
+//! Every subscription is a read-only channel of its own, so subscribers read
+//! published messages like any other channel message, and a subscriber that
+//! falls behind only loses from its own copy of the stream, as decided by the
+//! [`LagPolicy`] it subscribed with.
+
+use super::channel::{ChannelMessage, IpcQuota, MessagePriority, SendError, Sender, UserspaceChannel};
+use crate::{
+    capabilities::{Capability, CapabilityResource},
+    mem::{
+        paging::VirtualAddress,
+        user::{self, RawUserSlice},
+    },
+    scheduler::TASKS,
+    stats::{self, Event},
+    task::Task,
+    trap::GeneralRegisters,
+};
+use alloc::{sync::Arc, vec::Vec};
+use librust::{
+    capabilities::{CapabilityPtr, CapabilityRights},
+    error::SyscallError,
+    syscalls::channel::ChannelSendFlags,
+    task::Tid,
+};
+use sync::SpinMutex;
+
+pub use librust::syscalls::topic::LagPolicy;
+
+#[derive(Debug)]
+pub struct Topic {
+    subscribers: SpinMutex<Vec<Subscriber>>,
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    sender: Sender,
+    policy: LagPolicy,
+    /// How many published messages this subscriber has missed from having a
+    /// full queue
+    lagged: usize,
+}
+
+impl Topic {
+    pub fn new() -> Self {
+        Self { subscribers: SpinMutex::new(Vec::new()) }
+    }
+
+    /// Subscribe `task` to the topic, returning the capability to the channel
+    /// published messages will arrive on
+    fn subscribe(&self, task: &mut Task, policy: LagPolicy) -> CapabilityPtr {
+        let (mut publisher, subscriber) = UserspaceChannel::new();
+        publisher.sender.other_tid = Some(task.tid);
+
+        let cptr = task.cspace.mint_with(|cptr| {
+            publisher.sender.other_cptr = cptr;
+            Capability { resource: CapabilityResource::Channel(subscriber), rights: CapabilityRights::READ }
+        });
+
+        self.subscribers.lock().push(Subscriber { sender: publisher.sender, policy, lagged: 0 });
+
+        cptr
+    }
+
+    /// Send a copy of the message to every subscriber, returning how many of
+    /// them it was delivered to. `publisher` is the task publishing it, which
+    /// is already locked, and every copy is charged to its `quota`. Only fails
+    /// if the quota kept the message from being delivered to anyone.
+    fn publish(
+        &self,
+        publisher: Tid,
+        quota: &Arc<IpcQuota>,
+        data: [usize; 7],
+        caps: Vec<Capability>,
+        priority: MessagePriority,
+    ) -> Result<usize, SyscallError> {
+        let mut delivered = 0;
+        let mut over_quota = false;
+
+        self.subscribers.lock().retain_mut(|subscriber| {
+            let tid = subscriber.sender.other_tid.expect("[BUG] subscription without a subscriber");
+
+            // Subscriptions aren't cleaned up when their task exits, so drop
+            // them the next time something is published instead
+            if TASKS.get(tid).is_none() {
+                return false;
+            }
+
+            // Going over the quota is the publisher's fault rather than the
+            // subscriber's, so it doesn't count towards the subscriber lagging
+            let charge = match super::channel::charge_message(quota, &caps) {
+                Ok(charge) => charge,
+                Err(_) => {
+                    over_quota = true;
+                    return true;
+                }
+            };
+
+            let message = ChannelMessage { data, caps: caps.clone(), charge: Some(charge) };
+            let message = match send(subscriber, publisher, message, priority) {
+                Ok(()) => {
+                    delivered += 1;
+                    return true;
+                }
+                Err(SendError::Dead(_)) => return false,
+                Err(SendError::Full(message)) => message,
+            };
+
+            subscriber.lagged += 1;
+            match subscriber.policy {
+                LagPolicy::DropNewest => true,
+                LagPolicy::DropOldest => {
+                    drop(subscriber.sender.inner.write().pop_oldest(priority));
+                    if send(subscriber, publisher, message, priority).is_ok() {
+                        delivered += 1;
+                    }
+
+                    true
+                }
+                LagPolicy::Disconnect => {
+                    log::debug!(
+                        "Disconnecting subscriber {:?}:{:?} after missing {} messages",
+                        tid,
+                        subscriber.sender.other_cptr,
+                        subscriber.lagged
+                    );
+                    false
+                }
+            }
+        });
+
+        match (delivered, over_quota) {
+            (0, true) => Err(SyscallError::QuotaExceeded),
+            _ => Ok(delivered),
+        }
+    }
+}
+
+/// Queue `message` for `subscriber`. The publisher is already locked, so it
+/// can't be sent the usual new message notification if it's subscribed to the
+/// topic itself, but it isn't blocked waiting on the message either.
+fn send(
+    subscriber: &Subscriber,
+    publisher: Tid,
+    message: ChannelMessage,
+    priority: MessagePriority,
+) -> Result<(), SendError> {
+    match subscriber.sender.other_tid == Some(publisher) {
+        true => subscriber.sender.inner.write().try_push(message, priority).map_err(SendError::Full),
+        false => subscriber.sender.try_send(message, priority),
+    }
+}
+
+/// Look up the topic `cptr` refers to, making sure the capability has `right`.
+/// `cptr` is argument `arg` of the syscall.
+fn topic_capability(task: &Task, cptr: usize, right: CapabilityRights, arg: u32) -> Result<Arc<Topic>, SyscallError> {
+    match task.cspace.resolve(CapabilityPtr::new(cptr)) {
+        Some(Capability { resource: CapabilityResource::Topic(topic), rights }) if *rights & right => {
+            Ok(Arc::clone(topic))
+        }
+        Some(Capability { resource: CapabilityResource::Topic(_), .. }) => Err(SyscallError::InsufficientRights(arg)),
+        _ => Err(SyscallError::InvalidArgument(arg)),
+    }
+}
+
+pub fn create_topic(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let cptr = task.cspace.mint(Capability {
+        resource: CapabilityResource::Topic(Arc::new(Topic::new())),
+        rights: CapabilityRights::READ | CapabilityRights::WRITE | CapabilityRights::GRANT,
+    });
+
+    log::debug!("Task {} created topic {:?}", task.name, cptr);
+    regs.a1 = cptr.value();
+
+    Ok(())
+}
+
+pub fn subscribe_topic(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let topic = topic_capability(task, regs.a1, CapabilityRights::READ, 0)?;
+    let policy = LagPolicy::from_usize(regs.a2).ok_or(SyscallError::InvalidArgument(1))?;
+
+    let cptr = topic.subscribe(task, policy);
+    log::debug!("Task {} subscribed to topic {:#x} on {:?} ({:?})", task.name, regs.a1, cptr, policy);
+    regs.a1 = cptr.value();
+
+    Ok(())
+}
+
+pub fn publish_topic(task: &mut Task, regs: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let topic = topic_capability(task, regs.a1, CapabilityRights::WRITE, 0)?;
+    let caps =
+        RawUserSlice::<user::Read, librust::capabilities::Capability>::new(VirtualAddress::new(regs.a2), regs.a3);
+    let flags = ChannelSendFlags::new(regs.a4);
+    let data = [regs.t0, regs.t1, regs.t2, regs.t3, regs.t4, regs.t5, regs.t6];
+
+    let caps = match caps.len() {
+        0 => Vec::new(),
+        _ => {
+            let cap_slice = match unsafe { caps.validate(&task.memory_manager) } {
+                Ok(cap_slice) => cap_slice,
+                Err(_) => return Err(SyscallError::InvalidArgument(1)),
+            };
+
+            super::channel::clone_caps_to_send(task, &cap_slice.guarded(), 1)?
+        }
+    };
+
+    let priority = match flags & ChannelSendFlags::URGENT {
+        true => MessagePriority::Urgent,
+        false => MessagePriority::Normal,
+    };
+
+    let delivered = topic.publish(task.tid, &task.ipc_quota, data, caps, priority)?;
+    log::debug!("[{}:{}] Published {:?} message to {} subscribers", task.name, task.tid, priority, delivered);
+    stats::count(Event::IpcMessage);
+    regs.a1 = delivered;
+
+    Ok(())
+}
@@ -12,15 +12,15 @@ use crate::{
     mem::{
         manager::{AddressRegionKind, FillOption, MemoryManager, RegionDescription},
         paging::{flags, PageSize, VirtualAddress},
-        user::RawUserSlice,
+        user::{RawUserPtr, RawUserSlice, Read},
     },
     scheduler::{Scheduler, SCHEDULER},
-    syscall::channel::UserspaceChannel,
+    syscall::channel::{self, ChannelMessage, IpcQuota, UserspaceChannel},
     task::{Context, Task},
     trap::GeneralRegisters,
     utils::{self, Units},
 };
-use alloc::{collections::BTreeMap, vec::Vec};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
 use librust::{
     capabilities::CapabilityRights,
     error::SyscallError,
@@ -100,7 +100,7 @@ pub fn alloc_vmspace_object(task: &mut Task, frame: &mut GeneralRegisters) -> Re
         false => Some(address),
     };
 
-    let (at, region) = object.memory_manager.alloc_shared_region(
+    let allocated = object.memory_manager.try_alloc_shared_region(
         at,
         RegionDescription {
             size: PageSize::Kilopage,
@@ -111,6 +111,7 @@ pub fn alloc_vmspace_object(task: &mut Task, frame: &mut GeneralRegisters) -> Re
             kind,
         },
     );
+    let (at, region) = allocated.ok_or_else(|| super::mem::out_of_memory(&task.name, size))?;
 
     // log::info!("Mapping region at {:#p} for task {}", at.start, task.name);
     let range = task.memory_manager.apply_shared_region(
@@ -128,11 +129,123 @@ pub fn alloc_vmspace_object(task: &mut Task, frame: &mut GeneralRegisters) -> Re
     Ok(())
 }
 
+/// Map memory the task already has into a vmspace object without copying it,
+/// for things like the read-only segments of a binary which are already in
+/// memory. The pages are shared between the task and the vmspace, so they can't
+/// be mapped writable into the vmspace.
+pub fn share_vmspace_object(task: &mut Task, frame: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let id = VmspaceObjectId::new(frame.a1);
+    let ours = VirtualAddress::new(frame.a2);
+    let size = utils::round_up_to_next(frame.a3, 4.kib());
+    let theirs = VirtualAddress::new(frame.a4);
+    let permissions = MemoryPermissions::new(frame.a5);
+
+    let object = match task.vmspace_objects.get_mut(&id) {
+        Some(object) => object,
+        None => return Err(SyscallError::InvalidArgument(0)),
+    };
+
+    if !ours.is_aligned(PageSize::Kilopage) || ours.is_kernel_region() {
+        return Err(SyscallError::InvalidArgument(1));
+    }
+
+    let ours_end = match ours.checked_add(size) {
+        Some(end) if size != 0 && !end.is_kernel_region() => end,
+        _ => return Err(SyscallError::InvalidArgument(2)),
+    };
+
+    let theirs = match theirs.is_null() {
+        true => None,
+        false => match theirs.checked_add(size) {
+            Some(end)
+                if theirs.is_aligned(PageSize::Kilopage)
+                    && !end.is_kernel_region()
+                    && object.memory_manager.is_unoccupied(theirs..end) =>
+            {
+                Some(theirs)
+            }
+            _ => return Err(SyscallError::InvalidArgument(3)),
+        },
+    };
+
+    let (flags, kind) = match (
+        permissions & MemoryPermissions::READ,
+        permissions & MemoryPermissions::WRITE,
+        permissions & MemoryPermissions::EXECUTE,
+    ) {
+        (true, false, false) => (flags::READ, AddressRegionKind::ReadOnly),
+        (_, false, true) => (flags::READ | flags::EXECUTE, AddressRegionKind::Text),
+        (_, true, false) => (flags::READ | flags::WRITE, AddressRegionKind::Data),
+        _ => return Err(SyscallError::InvalidArgument(4)),
+    };
+
+    // Writable mappings get their own copy of each page the first time either
+    // side writes to it
+    if flags & flags::WRITE {
+        // Device memory can only be handed out through the capabilities for it
+        match task.memory_manager.region_for(ours) {
+            Some(region)
+                if region.permissions & flags::READ
+                    && !matches!(region.kind, AddressRegionKind::Mmio | AddressRegionKind::Dma) => {}
+            _ => return Err(SyscallError::InvalidArgument(1)),
+        }
+
+        let (region, our_kind, our_flags) = match task.memory_manager.copy_on_write(ours..ours_end) {
+            Some(copy) => copy,
+            None => return Err(SyscallError::InvalidArgument(1)),
+        };
+
+        let range = object.memory_manager.map_region(theirs, region, flags::USER | flags::VALID | flags, kind);
+        log::debug!(
+            "[{}] Shared {:#p} ({:?}, {:?}) copy-on-write into vmspace {} at {:#p}",
+            task.name,
+            ours,
+            our_kind,
+            our_flags,
+            id.value(),
+            range.start
+        );
+
+        frame.a1 = range.start.as_usize();
+        return Ok(());
+    }
+
+    // Device memory can only be handed out through the capabilities for it
+    let (region, our_kind, our_flags) = match task.memory_manager.share_range(ours..ours_end) {
+        Some((_, AddressRegionKind::Mmio | AddressRegionKind::Dma, _)) | None => {
+            return Err(SyscallError::InvalidArgument(1))
+        }
+        Some((_, _, our_flags)) if !(our_flags & flags::READ) => return Err(SyscallError::InvalidArgument(1)),
+        Some(shared) => shared,
+    };
+
+    let range = object.memory_manager.apply_shared_region(theirs, flags::USER | flags::VALID | flags, region, kind);
+    log::debug!(
+        "[{}] Shared {:#p} ({:?}, {:?}) into vmspace {} at {:#p}",
+        task.name,
+        ours,
+        our_kind,
+        our_flags,
+        id.value(),
+        range.start
+    );
+
+    frame.a1 = range.start.as_usize();
+    Ok(())
+}
+
+/// Start a new task running in a vmspace object. The bootstrap message and the
+/// capabilities sent with it are queued on the new task's parent channel before
+/// it's scheduled, so it never sees its parent channel without them, and if any
+/// of the capabilities can't be sent the task isn't spawned at all.
 #[allow(clippy::too_many_arguments)]
 pub fn spawn_vmspace(task: &mut Task, frame: &mut GeneralRegisters) -> Result<(), SyscallError> {
     let id: VmspaceObjectId = VmspaceObjectId::new(frame.a1);
     let name: VirtualAddress = VirtualAddress::new(frame.a2);
     let len: usize = frame.a3;
+    let caps =
+        RawUserSlice::<Read, librust::capabilities::Capability>::readable(VirtualAddress::new(frame.a4), frame.a5);
+    let message = RawUserPtr::<Read, [usize; 7]>::readable(VirtualAddress::new(frame.a6));
     let pc: usize = frame.t0;
     let a0: usize = frame.t1;
     let a1: usize = frame.t2;
@@ -140,10 +253,11 @@ pub fn spawn_vmspace(task: &mut Task, frame: &mut GeneralRegisters) -> Result<()
     let sp: usize = frame.t4;
     let tp: usize = frame.t5;
 
-    let object = match task.vmspace_objects.remove(&id) {
-        Some(map) => map,
-        None => return Err(SyscallError::InvalidArgument(0)),
-    };
+    // Likewise for exec, everything is checked before the vmspace object is
+    // used up so a failed spawn can be retried
+    if !task.vmspace_objects.contains_key(&id) {
+        return Err(SyscallError::InvalidArgument(0));
+    }
 
     let user_slice = RawUserSlice::readable(name, len);
     let user_slice = match unsafe { user_slice.validate(&task.memory_manager) } {
@@ -163,6 +277,28 @@ pub fn spawn_vmspace(task: &mut Task, frame: &mut GeneralRegisters) -> Result<()
         }
     };
 
+    let caps = match caps.len() {
+        0 => Vec::new(),
+        _ => match unsafe { caps.validate(&task.memory_manager) } {
+            Ok(cap_slice) => channel::clone_caps_to_send(task, &cap_slice.guarded(), 3)?,
+            Err(_) => return Err(SyscallError::InvalidArgument(3)),
+        },
+    };
+
+    let data = match unsafe { message.validate(&task.memory_manager) } {
+        Ok(message) => message.read(),
+        Err(e) => {
+            log::error!("Bad memory from process: {:?}", e);
+            return Err(SyscallError::InvalidArgument(5));
+        }
+    };
+
+    let charge = channel::charge_message(&task.ipc_quota, &caps)?;
+    let mut object = task.vmspace_objects.remove(&id).unwrap();
+    if !object.memory_manager.guard_stack(VirtualAddress::new(sp)) {
+        log::debug!("No room for a guard page below the stack of {} at {:#p}", task_name, sp as *const u8);
+    }
+
     log::debug!(
         "Spawning new task: pc={:#p} sp={:#p} tp={:#p} a0={:x} a1={:x} a2={:x}",
         pc as *const u8,
@@ -174,7 +310,8 @@ pub fn spawn_vmspace(task: &mut Task, frame: &mut GeneralRegisters) -> Result<()
     );
     log::debug!("Memory map:\n{:#?}", object.memory_manager.address_map_debug(None));
 
-    let (kernel_channel, user_read) = UserspaceChannel::new();
+    // Kernel messages can't be retried, so the kernel channel doesn't limit them
+    let (kernel_channel, user_read) = UserspaceChannel::with_depth(usize::MAX);
     let mut new_task = Task {
         tid: Tid::new(NonZeroUsize::new(usize::MAX).unwrap()),
         name: alloc::string::String::from(task_name).into_boxed_str(),
@@ -191,9 +328,21 @@ pub fn spawn_vmspace(task: &mut Task, frame: &mut GeneralRegisters) -> Result<()
         kernel_channel,
         claimed_interrupts: BTreeMap::new(),
         subscribes_to_events: false,
+        fault_handler: None,
+        group: task.group,
+        handles_job_control: false,
+        ipc_quota: Arc::new(IpcQuota::new(channel::DEFAULT_IPC_QUOTA)),
+        dma_allocations: BTreeMap::new(),
+        dma_pins: BTreeMap::new(),
+        dma_pin_next_id: 0,
+        checkpoints: BTreeMap::new(),
+        checkpoint_next_id: 0,
+        frozen: Default::default(),
+        timers: Vec::new(),
     };
 
     let (mut channel1, mut channel2) = UserspaceChannel::new();
+    channel2.receiver.inner.write().push_back(ChannelMessage { data, caps, charge: Some(charge) });
 
     new_task
         .cspace
@@ -239,3 +388,105 @@ pub fn spawn_vmspace(task: &mut Task, frame: &mut GeneralRegisters) -> Result<()
 
     Ok(())
 }
+
+/// Replace the task's address space with a vmspace object, keeping its TID,
+/// kernel channel and parent channel. Everything else in its capability space
+/// is dropped, other than the capabilities it sends itself in the bootstrap
+/// message, which is queued at the front of its parent channel for the new
+/// image to read like it would a message from its parent.
+pub fn exec_vmspace(task: &mut Task, frame: &mut GeneralRegisters) -> Result<(), SyscallError> {
+    let id: VmspaceObjectId = VmspaceObjectId::new(frame.a1);
+    let name: VirtualAddress = VirtualAddress::new(frame.a2);
+    let len: usize = frame.a3;
+    let caps =
+        RawUserSlice::<Read, librust::capabilities::Capability>::readable(VirtualAddress::new(frame.a4), frame.a5);
+    let message = RawUserPtr::<Read, [usize; 7]>::readable(VirtualAddress::new(frame.a6));
+    let pc: usize = frame.t0;
+    let a0: usize = frame.t1;
+    let a1: usize = frame.t2;
+    let a2: usize = frame.t3;
+    let sp: usize = frame.t4;
+    let tp: usize = frame.t5;
+
+    // Everything is checked before the task is touched, so a failed exec
+    // leaves it running as it was
+    if !task.vmspace_objects.contains_key(&id) {
+        return Err(SyscallError::InvalidArgument(0));
+    }
+
+    let user_slice = RawUserSlice::readable(name, len);
+    let user_slice = match unsafe { user_slice.validate(&task.memory_manager) } {
+        Ok(slice) => slice,
+        Err((_, e)) => {
+            log::error!("Bad memory from process: {:?}", e);
+            return Err(SyscallError::InvalidArgument(1));
+        }
+    };
+
+    let task_name = match core::str::from_utf8(&user_slice.guarded()) {
+        Ok(s) => alloc::string::String::from(s).into_boxed_str(),
+        Err(_) => {
+            log::error!("Invalid UTF-8 in task name from process");
+            return Err(SyscallError::InvalidArgument(1));
+        }
+    };
+
+    let caps = match caps.len() {
+        0 => Vec::new(),
+        _ => match unsafe { caps.validate(&task.memory_manager) } {
+            Ok(cap_slice) => channel::clone_caps_to_send(task, &cap_slice.guarded(), 3)?,
+            Err(_) => return Err(SyscallError::InvalidArgument(3)),
+        },
+    };
+
+    let data = match unsafe { message.validate(&task.memory_manager) } {
+        Ok(message) => message.read(),
+        Err(e) => {
+            log::error!("Bad memory from process: {:?}", e);
+            return Err(SyscallError::InvalidArgument(5));
+        }
+    };
+
+    let parent = match task.cspace.resolve(PARENT_CHANNEL) {
+        Some(Capability { resource: CapabilityResource::Channel(channel), .. }) => channel.clone(),
+        _ => return Err(SyscallError::InvalidOperation(0)),
+    };
+
+    let mut object = task.vmspace_objects.remove(&id).unwrap();
+    if !object.memory_manager.guard_stack(VirtualAddress::new(sp)) {
+        log::debug!("No room for a guard page below the stack of {} at {:#p}", task_name, sp as *const u8);
+    }
+
+    log::debug!("Task {} exec'ing into {}: pc={:#p} sp={:#p}", task.name, task_name, pc as *const u8, sp as *const u8);
+
+    let mut cspace = CapabilitySpace::new();
+    for cptr in [KERNEL_CHANNEL, PARENT_CHANNEL] {
+        let cap = task.cspace.remove(cptr).expect("[BUG] checked above");
+        cspace.mint_with_id(cptr, cap).expect("[BUG] capability space isn't empty?");
+    }
+
+    parent.receiver.inner.write().push_front(ChannelMessage { data, caps, charge: None });
+
+    task.unmap_dma_allocations();
+
+    // This hart is still running on the old page tables until the task is
+    // scheduled again
+    let old_memory_manager = core::mem::replace(&mut task.memory_manager, object.memory_manager);
+    crate::mem::rmap::set_owner(task.memory_manager.id(), task.tid);
+    crate::worker::defer_pinned(move || drop(old_memory_manager));
+
+    task.name = task_name;
+    task.context = Context {
+        pc,
+        gp_regs: GeneralRegisters { a0, a1, a2, sp, tp, ..Default::default() },
+        fp_regs: Default::default(),
+    };
+    task.cspace = cspace;
+    task.vmspace_objects = Default::default();
+    task.checkpoints = Default::default();
+    task.subscribes_to_events = false;
+    task.fault_handler = None;
+    task.handles_job_control = false;
+
+    Ok(())
+}
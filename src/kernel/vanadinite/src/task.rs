@@ -9,24 +9,38 @@ use core::num::NonZeroUsize;
 
 use crate::{
     capabilities::{Capability, CapabilityResource, CapabilitySpace},
+    exec::{self, LoadError, LoadedImage},
     mem::{
+        dma::{self, DmaAllocation, DmaPin},
         manager::{AddressRegionKind, FillOption, MemoryManager, RegionDescription},
         paging::{
-            flags::{EXECUTE, READ, USER, VALID, WRITE},
+            flags::{READ, USER, VALID, WRITE},
             PageSize, VirtualAddress,
         },
     },
     platform::FDT,
-    syscall::{channel::UserspaceChannel, vmspace::VmspaceObject},
+    scheduler::{
+        timer::{self, TimerHandle},
+        TASKS,
+    },
+    syscall::{
+        channel::{IpcQuota, UserspaceChannel, DEFAULT_IPC_QUOTA},
+        checkpoint::Checkpoint,
+        vmspace::VmspaceObject,
+    },
     trap::{FloatingPointRegisters, GeneralRegisters},
     utils::{round_up_to_next, Units},
 };
-use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
-use elf64::{Elf, ProgramSegmentType, Relocation};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    vec::Vec,
+};
 use fdt::Fdt;
 use librust::{
     capabilities::CapabilityRights,
-    syscalls::{channel::KERNEL_CHANNEL, vmspace::VmspaceObjectId},
+    syscalls::{channel::KERNEL_CHANNEL, checkpoint::CheckpointId, mem::DmaPinId, vmspace::VmspaceObjectId},
     task::Tid,
 };
 
@@ -88,171 +102,38 @@ pub struct Task {
     pub kernel_channel: UserspaceChannel,
     pub claimed_interrupts: BTreeMap<usize, usize>,
     pub subscribes_to_events: bool,
+    pub fault_handler: Option<FaultHandler>,
+    /// The task group used for job control, see [`crate::job`]
+    pub group: usize,
+    pub handles_job_control: bool,
+    /// Shared by every message the task has sent that hasn't been read yet
+    pub ipc_quota: Arc<IpcQuota>,
+    /// The DMA memory the task has allocated, by where it's mapped
+    pub dma_allocations: BTreeMap<VirtualAddress, DmaAllocation>,
+    /// Memory the task has pinned for DMA
+    pub dma_pins: BTreeMap<DmaPinId, DmaPin>,
+    pub dma_pin_next_id: usize,
+    /// Checkpoints the task has taken, of itself or other tasks
+    pub checkpoints: BTreeMap<CheckpointId, Checkpoint>,
+    pub checkpoint_next_id: usize,
+    /// Tasks the task froze to checkpoint or restore them, which stay frozen
+    /// until it retries since they were still running
+    pub frozen: BTreeSet<Tid>,
+    /// The userspace timers the task has armed which may not have fired yet
+    pub timers: Vec<TimerHandle>,
 }
 
 impl Task {
-    pub fn load<'a, I>(name: &str, elf: &Elf, args: I) -> Self
+    /// Load a task from `image`, which can be in any of the registered
+    /// [`crate::exec::ExecutableFormat`]s
+    pub fn load<'a, I>(name: &str, image: &[u8], args: I) -> Result<Self, LoadError>
     where
         I: Iterator<Item = &'a str> + Clone,
     {
         let mut memory_manager = MemoryManager::new();
         let mut cspace = CapabilitySpace::new();
 
-        let relocations = elf
-            .relocations()
-            .map(|reloc| match reloc {
-                Relocation::Rel(rel) => (VirtualAddress::new(rel.offset as usize), reloc),
-                Relocation::Rela(rela) => (VirtualAddress::new(rela.offset as usize), reloc),
-            })
-            .collect::<BTreeMap<VirtualAddress, Relocation>>();
-
-        // Try to estimate the size of the buffer we'll need
-        let (total_size, max_file_size) = elf.load_segments().fold((0, 0), |(sum, max), header| {
-            (
-                round_up_to_next(sum, header.align as usize)
-                    + round_up_to_next(header.memory_size as usize, header.align as usize),
-                max.max(header.file_size as usize),
-            )
-        });
-
-        // See if we have a RELRO section to fix up
-        let relro = elf
-            .program_headers()
-            .find(|header| header.r#type == ProgramSegmentType::GnuRelro)
-            .map(|header| header.vaddr as usize);
-
-        assert_eq!(total_size % 4.kib(), 0, "load segments not totally whole pages");
-
-        // FIXME: first segment load might be `2.mib()`, so prob need a `match` here
-        let task_load_base = memory_manager.find_free_region(PageSize::Kilopage, total_size / 4.kib());
-        let mut segment_offset = task_load_base;
-        let mut segment_data = alloc::vec![0; max_file_size];
-        let mut pc = VirtualAddress::new(0);
-        let elf_entry = VirtualAddress::new(elf.header.entry as usize);
-
-        for header in elf.load_segments() {
-            let align = header.align as usize;
-            let mem_size = header.memory_size as usize;
-            let vaddr = header.vaddr as usize;
-            let file_size = header.file_size as usize;
-            let is_relro = Some(vaddr) == relro;
-
-            assert!(align.is_power_of_two(), "ELF segment alignment isn't a power of two!");
-            assert!(mem_size >= file_size, "ELF segment has less data in memory than in the file?");
-
-            // Need to align-up the segment offset we were given here
-            let segment_load_base = VirtualAddress::new(round_up_to_next(segment_offset.as_usize(), align));
-            // Grab the bottom bits that we need to start writing data at
-            let segment_load_offset = vaddr & (align - 1);
-            // The total segment length we need is the size in the file + the
-            // above offset since we start at the aligned address
-            let segment_len = file_size + segment_load_offset;
-            // The total size in memory rounded up to the next alignment for the
-            // segment
-            let region_size = round_up_to_next(mem_size + segment_load_offset, align);
-
-            if segment_data.len() < segment_len {
-                segment_data.resize(segment_len, 0);
-            }
-
-            // Copy the segment data starting at the offset
-            segment_data[segment_load_offset..][..file_size].copy_from_slice(elf.program_segment_data(&header));
-
-            // We use these values to key off of some information (e.g.
-            // relocation calculations and calculating the PC)
-            let raw_segment_start = VirtualAddress::new(header.vaddr as usize);
-            let raw_segment_end = raw_segment_start.add(header.memory_size as usize);
-            let raw_segment_range = raw_segment_start..raw_segment_end;
-
-            // The real PC needs calculated from the offset, so we check to see
-            // if this is the segment that contains the entry point
-            if raw_segment_range.contains(&elf_entry) {
-                let offset = elf_entry.as_usize() - raw_segment_start.as_usize() + segment_load_offset;
-                pc = segment_load_base.add(offset);
-            }
-
-            // Find any relocations and fix them up before we write the memory
-            // so we don't need to deal with the `UniquePhysicalRegion` which
-            // doesn't play nice with arbitrary indexing since the physical
-            // pages aren't guaranteed to be contiguous here so we can reuse
-            // memory
-            for (_, relocation) in relocations.range(raw_segment_start..raw_segment_end) {
-                match relocation {
-                    Relocation::Rel(_) => todo!("rel relocations"),
-                    Relocation::Rela(rela) => {
-                        let offset_into = rela.offset as usize - raw_segment_start.as_usize() + segment_load_offset;
-
-                        match rela.r#type {
-                            // RELATIVE
-                            3 => {
-                                // FIXME: Should prob check for negative addends?
-                                let fixup = task_load_base.as_usize() + rela.addend as usize;
-                                segment_data[offset_into..][..8].copy_from_slice(&fixup.to_le_bytes());
-                            }
-                            n => todo!("relocation type: {}", n),
-                        }
-                    }
-                }
-            }
-
-            // RELRO will override any other permission flags here, so check to
-            // see if the region we just processed is the RELRO segment
-            let (kind, flags) = match (is_relro, header.flags) {
-                (true, _) => (AddressRegionKind::ReadOnly, USER | READ | VALID),
-                (false, 0b101) => (AddressRegionKind::Text, USER | READ | EXECUTE | VALID),
-                (false, 0b110) => (AddressRegionKind::Data, USER | READ | WRITE | VALID),
-                (false, 0b100) => (AddressRegionKind::ReadOnly, USER | READ | VALID),
-                (false, flags) => unreachable!("flags: {:#b}", flags),
-            };
-
-            memory_manager.alloc_region(
-                Some(segment_load_base),
-                RegionDescription {
-                    size: PageSize::Kilopage,
-                    len: region_size / 4.kib(),
-                    contiguous: false,
-                    flags,
-                    fill: FillOption::Data(&segment_data[..segment_len]),
-                    kind,
-                },
-            );
-
-            segment_offset = segment_load_base.add(region_size);
-        }
-
-        let tls = elf.program_headers().find(|header| header.r#type == elf64::ProgramSegmentType::Tls).map(|header| {
-            let n_pages_needed = round_up_to_next(header.memory_size as usize + 8 + 16, 4.kib()) / 4.kib();
-            let tls_base = memory_manager.find_free_region(PageSize::Kilopage, n_pages_needed);
-
-            let segment_len = header.file_size as usize + 8 + 16;
-
-            if segment_data.len() < segment_len {
-                segment_data.resize(segment_len, 0);
-            }
-
-            let segment_file_size = header.file_size as usize;
-            let tls_base_addr = tls_base.as_usize();
-            segment_data[0..][..8].copy_from_slice(&(tls_base_addr + 8).to_le_bytes()[..]); // ->|
-            segment_data[8..][..8].copy_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0]); // <-|
-            segment_data[16..][..8].copy_from_slice(&(tls_base_addr + 24).to_le_bytes()[..]);
-
-            segment_data[24..][..segment_file_size].copy_from_slice(elf.program_segment_data(&header));
-            segment_data[segment_file_size..segment_len].fill(0);
-
-            memory_manager.alloc_region(
-                Some(tls_base),
-                RegionDescription {
-                    size: PageSize::Kilopage,
-                    len: n_pages_needed,
-                    contiguous: false,
-                    flags: USER | READ | WRITE | VALID,
-                    fill: FillOption::Data(&segment_data[..segment_len]),
-                    kind: AddressRegionKind::Tls,
-                },
-            );
-
-            tls_base_addr + 24
-        });
+        let LoadedImage { entry, thread_pointer } = exec::load(image, &mut memory_manager)?;
 
         // We guard the stack on both ends, though a stack underflow is
         // unlikely, but better to be safe than sorry!
@@ -318,10 +199,10 @@ impl Task {
         };
 
         let context = Context {
-            pc: pc.as_usize(),
+            pc: entry.as_usize(),
             gp_regs: GeneralRegisters {
                 sp: sp.as_usize(),
-                tp: tls.unwrap_or(0),
+                tp: thread_pointer.map_or(0, |tp| tp.as_usize()),
                 a0,
                 a1,
                 a2: fdt_loc.start.as_usize(),
@@ -330,7 +211,8 @@ impl Task {
             fp_regs: FloatingPointRegisters::default(),
         };
 
-        let (kernel_channel, user_read) = UserspaceChannel::new();
+        // Kernel messages can't be retried, so the kernel channel doesn't limit them
+        let (kernel_channel, user_read) = UserspaceChannel::with_depth(usize::MAX);
         cspace
             .mint_with_id(
                 KERNEL_CHANNEL,
@@ -338,7 +220,7 @@ impl Task {
             )
             .expect("[BUG] kernel channel cap already created?");
 
-        Self {
+        Ok(Self {
             tid: Tid::new(NonZeroUsize::new(usize::MAX).unwrap()),
             name: Box::from(name),
             context,
@@ -350,15 +232,62 @@ impl Task {
             kernel_channel,
             claimed_interrupts: BTreeMap::new(),
             subscribes_to_events: false,
+            fault_handler: None,
+            group: 0,
+            handles_job_control: false,
+            ipc_quota: Arc::new(IpcQuota::new(DEFAULT_IPC_QUOTA)),
+            dma_allocations: BTreeMap::new(),
+            dma_pins: BTreeMap::new(),
+            dma_pin_next_id: 0,
+            checkpoints: BTreeMap::new(),
+            checkpoint_next_id: 0,
+            frozen: BTreeSet::new(),
+            timers: Vec::new(),
+        })
+    }
+
+    /// Remove every DMA allocation the task still has from its devices, which
+    /// has to happen before the address space holding the memory is dropped so
+    /// the devices can't keep writing to the memory once it's reused. Pinned
+    /// memory is unpinned along with it.
+    pub fn unmap_dma_allocations(&mut self) {
+        for (_, allocation) in core::mem::take(&mut self.dma_allocations) {
+            dma::unmap(self.tid, allocation.device_addr, allocation.len);
+        }
+
+        for (_, pin) in core::mem::take(&mut self.dma_pins) {
+            pin.release(self.tid);
+        }
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        self.unmap_dma_allocations();
+
+        for handle in self.timers.drain(..) {
+            timer::cancel(handle);
         }
     }
 }
 
+/// Where a task is sent instead of being killed when it hits a fatal fault
+#[derive(Debug, Clone, Copy)]
+pub struct FaultHandler {
+    pub entry: VirtualAddress,
+    pub stack_top: VirtualAddress,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum TaskState {
     Blocked,
     Dead,
     Running,
+    /// Stopped by job control until it's continued
+    Suspended,
+    /// Stopped while a checkpoint is taken of it, see
+    /// [`crate::syscall::checkpoint`]
+    Frozen,
 }
 
 impl TaskState {
@@ -366,3 +295,22 @@ impl TaskState {
         matches!(self, TaskState::Dead)
     }
 }
+
+/// Free everything belonging to the exited task `tid` once a hart is idle. The
+/// task lives on until everything else holding onto it, like the run queue it
+/// was on, lets go of it too.
+pub fn reap_later(tid: Tid) {
+    crate::worker::defer(move || {
+        // Tasks it left frozen would never be scheduled again otherwise, and
+        // they can't be locked while it is
+        let frozen = match TASKS.get(tid) {
+            Some(task) => core::mem::take(&mut task.lock().frozen),
+            None => BTreeSet::new(),
+        };
+        crate::syscall::checkpoint::thaw_abandoned(frozen);
+
+        if TASKS.remove(tid).is_some() {
+            log::debug!("Reaped task {:?}", tid);
+        }
+    });
+}
@@ -48,7 +48,7 @@ pub extern "C" fn ktest(hart_id: usize, fdt: *const u8) -> ! {
     if let Some((_, reg, compatible)) = stdout.and_then(|n| Some((n, n.reg()?.next()?, n.compatible()?))) {
         let stdout_addr = reg.starting_address as *mut u8;
 
-        if let Some(device) = crate::io::ConsoleDevices::from_compatible(compatible) {
+        if let Some(device) = crate::io::ConsoleDriver::from_compatible(compatible) {
             let stdout_phys = PhysicalAddress::from_ptr(stdout_addr);
             let ptr = phys2virt(stdout_phys);
 
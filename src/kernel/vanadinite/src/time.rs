@@ -0,0 +1,295 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Monotonic time, the wall clock, and busy-wait delays for kernel code
+//!
+//! Everything here is based on the `time` CSR, which counts up at the timebase
+//! frequency given by the CPU nodes in the device tree and never goes
+//! backwards. Delays spin on it rather than counting loop iterations, so they
+//! take the same time no matter how fast the hart runs, and pet the hardware
+//! watchdog while they wait since they may run with interrupts disabled.
+//!
+//! The wall clock is an offset from the `time` CSR, seeded from the RTC at boot
+//! if there is one and kept in line by whatever synchronizes it over the
+//! network afterwards. Small corrections are slewed in by running the clock
+//! slightly fast or slow until they're made up, so it never jumps or goes
+//! backwards, and only large ones step it.
+
+use crate::{
+    csr,
+    mem::{
+        paging::{PhysicalAddress, VirtualAddress},
+        phys2virt,
+    },
+    watchdog, TIMER_FREQ,
+};
+use core::{
+    ops::{Add, Sub},
+    sync::atomic::Ordering,
+    time::Duration,
+};
+use fdt::Fdt;
+use sync::SpinMutex;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+/// How often the hardware watchdog is pet while spinning
+const WATCHDOG_PET_INTERVAL: Duration = Duration::from_millis(100);
+/// How long to measure the timebase against the RTC for at boot
+const CALIBRATION_PERIOD: Duration = Duration::from_millis(20);
+/// How far off the timebase can be from the RTC before warning about it, in
+/// parts per thousand
+const CALIBRATION_TOLERANCE: u64 = 10;
+/// How much faster or slower the wall clock runs while slewing, in parts per
+/// million, which is the same limit `adjtime` has elsewhere
+const MAX_SLEW_PPM: u128 = 500;
+
+static WALL_CLOCK: SpinMutex<WallClock> =
+    SpinMutex::new(WallClock { base: Instant(0), base_nanos: 0, slew: 0, synchronized: false });
+
+/// A point in time as measured by the `time` CSR
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Self(csr::time::read())
+    }
+
+    pub fn from_ticks(ticks: u64) -> Self {
+        Self(ticks)
+    }
+
+    pub fn ticks(self) -> u64 {
+        self.0
+    }
+
+    /// The time since `earlier`, or zero if `earlier` is later than `self`
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        duration_from_ticks(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(self) -> Duration {
+        Instant::now().duration_since(self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Self::Output {
+        Instant(self.0.saturating_add(ticks_from_duration(rhs)))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Self::Output {
+        self.duration_since(rhs)
+    }
+}
+
+/// The wall clock, as the Unix time at some [`Instant`] and how much is left to
+/// slew in from then on
+struct WallClock {
+    base: Instant,
+    /// Nanoseconds since the Unix epoch at `base`
+    base_nanos: u64,
+    /// Nanoseconds still to be added to the clock, or taken off it if negative
+    slew: i64,
+    synchronized: bool,
+}
+
+impl WallClock {
+    /// How much of the remaining slew has been made up by `now`
+    fn slewed(&self, now: Instant) -> i64 {
+        let max = (now.duration_since(self.base).as_nanos() * MAX_SLEW_PPM / 1_000_000).min(i64::MAX as u128) as i64;
+        self.slew.clamp(-max, max)
+    }
+
+    fn nanos(&self, now: Instant) -> u64 {
+        let elapsed = now.duration_since(self.base).as_nanos().min(u64::MAX as u128) as u64;
+        self.base_nanos.saturating_add(elapsed).saturating_add_signed(self.slewed(now))
+    }
+
+    /// Move `base` up to `now`, so changes to the slew only apply from now on
+    fn rebase(&mut self, now: Instant) {
+        self.base_nanos = self.nanos(now);
+        self.slew -= self.slewed(now);
+        self.base = now;
+    }
+}
+
+/// Read the timebase frequency from the device tree, warning if the harts
+/// don't agree on it, and return it
+pub fn init(fdt: &Fdt<'_>, hart_id: usize) -> u64 {
+    let current_cpu = fdt.cpus().find(|cpu| cpu.ids().first() == hart_id).unwrap();
+    let frequency = current_cpu.timebase_frequency() as u64;
+
+    for cpu in fdt.cpus() {
+        if cpu.timebase_frequency() as u64 != frequency {
+            log::warn!(
+                "Hart {} has a timebase of {}Hz, but hart {} has {}Hz, delays may be off",
+                cpu.ids().first(),
+                cpu.timebase_frequency(),
+                hart_id,
+                frequency
+            );
+        }
+    }
+
+    TIMER_FREQ.store(frequency, Ordering::Relaxed);
+    frequency
+}
+
+/// The frequency of the `time` CSR in Hz
+pub fn frequency() -> u64 {
+    TIMER_FREQ.load(Ordering::Relaxed)
+}
+
+pub fn ticks_from_duration(duration: Duration) -> u64 {
+    let ticks = duration.as_nanos() * frequency() as u128 / NANOS_PER_SEC;
+    ticks.min(u64::MAX as u128) as u64
+}
+
+pub fn duration_from_ticks(ticks: u64) -> Duration {
+    match frequency() {
+        0 => Duration::ZERO,
+        frequency => Duration::from_nanos((ticks as u128 * NANOS_PER_SEC / frequency as u128) as u64),
+    }
+}
+
+/// Spin until `duration` has passed
+pub fn delay(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    spin_until(deadline, || false);
+}
+
+pub fn ndelay(nanos: u64) {
+    delay(Duration::from_nanos(nanos));
+}
+
+pub fn udelay(micros: u64) {
+    delay(Duration::from_micros(micros));
+}
+
+pub fn mdelay(millis: u64) {
+    delay(Duration::from_millis(millis));
+}
+
+/// Spin until `done` returns `true` or `timeout` passes, returning whether
+/// `done` returned `true`. This is what drivers waiting on a device should use
+/// instead of spinning forever.
+pub fn wait_for(timeout: Duration, done: impl FnMut() -> bool) -> bool {
+    spin_until(Instant::now() + timeout, done)
+}
+
+fn spin_until(deadline: Instant, mut done: impl FnMut() -> bool) -> bool {
+    let mut last_pet = Instant::now();
+
+    loop {
+        if done() {
+            return true;
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return false;
+        }
+
+        if now - last_pet >= WATCHDOG_PET_INTERVAL {
+            watchdog::pet_hardware();
+            last_pet = now;
+        }
+
+        core::hint::spin_loop();
+    }
+}
+
+/// The time since the Unix epoch according to the wall clock, and whether it's
+/// been synchronized with anything since boot. Without an RTC or anything
+/// synchronizing it, the wall clock starts from the epoch at boot.
+pub fn wall_clock() -> (Duration, bool) {
+    let now = Instant::now();
+    let clock = WALL_CLOCK.lock();
+
+    (Duration::from_nanos(clock.nanos(now)), clock.synchronized)
+}
+
+/// Set the wall clock to `since_epoch` immediately, throwing away whatever was
+/// still being slewed in
+pub fn step_wall_clock(since_epoch: Duration) {
+    let mut clock = WALL_CLOCK.lock();
+    clock.base = Instant::now();
+    clock.base_nanos = since_epoch.as_nanos().min(u64::MAX as u128) as u64;
+    clock.slew = 0;
+    clock.synchronized = true;
+}
+
+/// Gradually move the wall clock by `offset_nanos`, replacing whatever was
+/// still being slewed in. At [`MAX_SLEW_PPM`], every millisecond of offset
+/// takes two seconds to make up.
+pub fn slew_wall_clock(offset_nanos: i64) {
+    let now = Instant::now();
+    let mut clock = WALL_CLOCK.lock();
+    clock.rebase(now);
+    clock.slew = offset_nanos;
+    clock.synchronized = true;
+}
+
+/// Start the wall clock from the RTC, if there is one
+pub fn init_wall_clock(fdt: &Fdt<'_>) {
+    let rtc = match find_rtc(fdt) {
+        Some(rtc) => rtc,
+        None => return,
+    };
+
+    let mut clock = WALL_CLOCK.lock();
+    clock.base = Instant::now();
+    clock.base_nanos = read_rtc(rtc);
+}
+
+/// Compare the timebase against the RTC, if there is one, to catch device
+/// trees with the wrong `timebase-frequency`
+pub fn validate_against_rtc(fdt: &Fdt<'_>) {
+    let rtc = match find_rtc(fdt) {
+        Some(rtc) => rtc,
+        None => return,
+    };
+
+    let rtc_start = read_rtc(rtc);
+    delay(CALIBRATION_PERIOD);
+    let rtc_nanos = read_rtc(rtc).saturating_sub(rtc_start);
+
+    let expected = CALIBRATION_PERIOD.as_nanos() as u64;
+    let error = expected.abs_diff(rtc_nanos) * 1000 / expected;
+    match error > CALIBRATION_TOLERANCE {
+        true => log::warn!(
+            "Timebase doesn't match the RTC: {}ms of timebase took {}us on the RTC, the timebase frequency is likely \
+             closer to {}Hz",
+            CALIBRATION_PERIOD.as_millis(),
+            rtc_nanos / 1000,
+            (frequency() as u128 * expected as u128 / rtc_nanos.max(1) as u128) as u64,
+        ),
+        false => log::debug!("Timebase is within {}.{}% of the RTC", error / 10, error % 10),
+    }
+}
+
+fn find_rtc(fdt: &Fdt<'_>) -> Option<VirtualAddress> {
+    let reg = fdt.find_compatible(&["google,goldfish-rtc"])?.reg()?.next()?;
+    Some(phys2virt(PhysicalAddress::from_ptr(reg.starting_address)))
+}
+
+/// Nanoseconds since the Unix epoch according to the RTC
+fn read_rtc(rtc: VirtualAddress) -> u64 {
+    // Reading the low half latches the high half
+    unsafe {
+        let low = rtc.as_ptr().cast::<u32>().read_volatile() as u64;
+        let high = rtc.as_ptr().cast::<u32>().add(1).read_volatile() as u64;
+        (high << 32) | low
+    }
+}
@@ -9,14 +9,19 @@ use crate::{
     csr::sstatus,
     interrupts::{isr::invoke_isr, PLIC},
     mem::{
-        manager::AddressRegion,
+        kernel_stacks,
+        manager::{AddressRegion, AddressRegionKind},
         paging::{flags, VirtualAddress},
         region::MemoryRegion,
+        swap::{self, FaultIn},
+        user::{RawUserPtr, RawUserSlice, Read, ReadWrite},
     },
     scheduler::{Scheduler, SCHEDULER},
+    stats::{self, Event},
     syscall,
-    task::TaskState,
+    task::{FaultHandler, Task, TaskState},
 };
+use librust::task::{FaultInfo, FAULT_BACKTRACE_LEN, FAULT_NAME_LEN, FAULT_STACK_SNAPSHOT};
 
 #[derive(Clone, Copy, Default)]
 #[repr(C)]
@@ -261,22 +266,84 @@ pub extern "C" fn trap_handler(regs: &mut TrapFrame, sepc: usize, scause: usize,
     let trap_kind = Trap::from_cause(scause);
     match trap_kind {
         Trap::SupervisorTimerInterrupt => {
-            if let Some(lock) = SCHEDULER.active_on_cpu() {
-                let mut lock = lock.lock();
+            stats::count(Event::TimerInterrupt);
+            crate::random::add_interrupt_entropy(scause);
+
+            #[cfg(feature = "debug.replay")]
+            let mut preempt = true;
+
+            let active = SCHEDULER.active_on_cpu();
+            let mut task = active.as_ref().map(|task| task.lock());
+            crate::profiler::sample(task.as_deref(), regs, sepc);
+
+            // Interrupts taken only for a sample go straight back to what was
+            // running, so profiling doesn't cut time slices short
+            if crate::profiler::running()
+                && !crate::scheduler::time_slice_over()
+                && !crate::scheduler::timer::any_expired()
+            {
+                crate::scheduler::resume_time_slice();
+                return sepc;
+            }
 
-                lock.context.pc = sepc;
-                lock.context.gp_regs = regs.registers;
+            if let Some(task) = &mut task {
+                save_context(task, regs, sepc);
 
-                if let sstatus::FloatingPointStatus::Dirty = sstatus::fs() {
-                    save_fp_registers(&mut lock.context.fp_regs);
+                #[cfg(feature = "debug.replay")]
+                {
+                    preempt = crate::replay::preempt_on_timer(task.tid);
                 }
             }
+
+            drop(task);
+
+            crate::scheduler::timer::fire_expired();
+            crate::power::poll();
+            crate::watchdog::poll();
+
+            #[cfg(feature = "debug.replay")]
+            if !preempt {
+                return sepc;
+            }
+
             SCHEDULER.schedule()
         }
-        Trap::UserModeEnvironmentCall => match syscall::handle(regs, sepc) {
-            syscall::Outcome::Completed => sepc + 4,
-            syscall::Outcome::Blocked => SCHEDULER.schedule(),
-        },
+        // Sent to park harts while the system suspends, to take harts offline,
+        // to have harts pick up the timers of harts going offline, and for TLB
+        // shootdowns
+        Trap::SupervisorSoftwareInterrupt => {
+            crate::csr::sip::clear_software_interrupt();
+            crate::mem::shootdown::handle_requests();
+            crate::suspend::park_if_requested();
+
+            if crate::hotplug::stop_requested() {
+                if let Some(lock) = SCHEDULER.active_on_cpu() {
+                    save_context(&mut lock.lock(), regs, sepc);
+                }
+
+                crate::hotplug::stop();
+            }
+
+            // Fire right away in case any of them are already due, the
+            // scheduler sets up the next deadline after
+            if crate::scheduler::timer::adopt_handed_off() {
+                sbi::timer::set_timer(crate::csr::time::read()).unwrap();
+            }
+
+            sepc
+        }
+        Trap::UserModeEnvironmentCall => {
+            let outcome = syscall::handle(regs, sepc);
+
+            #[cfg(feature = "debug.replay")]
+            crate::replay::deliver_interrupts();
+
+            match outcome {
+                syscall::Outcome::Completed => sepc + 4,
+                syscall::Outcome::Blocked => SCHEDULER.schedule(),
+                syscall::Outcome::Restarted => sepc,
+            }
+        }
         Trap::SupervisorExternalInterrupt => {
             // FIXME: there has to be a better way
             if let Some(plic) = &*PLIC.lock() {
@@ -284,6 +351,15 @@ pub extern "C" fn trap_handler(regs: &mut TrapFrame, sepc: usize, scause: usize,
                     log::debug!("External interrupt for: {:?}", claimed);
 
                     let interrupt_id = claimed.interrupt_id();
+                    stats::count(Event::ExternalInterrupt(interrupt_id));
+                    crate::random::add_interrupt_entropy(interrupt_id);
+
+                    // Left incomplete until the replay gets to it
+                    #[cfg(feature = "debug.replay")]
+                    if !crate::replay::interrupt_arrived(interrupt_id) {
+                        return sepc;
+                    }
+
                     match invoke_isr(plic, claimed, interrupt_id) {
                         Ok(_) => log::trace!("ISR (interrupt ID: {}) completed successfully", interrupt_id),
                         Err(e) => log::error!("Error during ISR: {}", e),
@@ -294,11 +370,27 @@ pub extern "C" fn trap_handler(regs: &mut TrapFrame, sepc: usize, scause: usize,
             sepc
         }
         Trap::LoadPageFault | Trap::StorePageFault | Trap::InstructionPageFault => {
+            stats::count(match trap_kind {
+                Trap::LoadPageFault => Event::LoadPageFault,
+                Trap::StorePageFault => Event::StorePageFault,
+                _ => Event::InstructionPageFault,
+            });
+
             let sepc = VirtualAddress::new(sepc);
             let stval = VirtualAddress::new(stval);
             match sepc.is_kernel_region() {
                 // We should always have marked memory regions up front from the initial mapping
                 true => {
+                    if kernel_stacks::is_guard_page(stval) {
+                        let active = SCHEDULER.active_on_cpu();
+                        let name = match active.as_ref().and_then(|active| active.try_lock()) {
+                            Some(task) => task.name.clone(),
+                            None => "<unknown>".into(),
+                        };
+
+                        panic!("Stack overflow in task {} while in the kernel @ {:#p} (pc={:#p})", name, stval, sepc);
+                    }
+
                     let active = SCHEDULER.active_on_cpu().unwrap();
 
                     match active.try_lock() {
@@ -313,14 +405,58 @@ pub extern "C" fn trap_handler(regs: &mut TrapFrame, sepc: usize, scause: usize,
                 false => {
                     let active_task_lock = SCHEDULER.active_on_cpu().unwrap();
                     let mut active_task = active_task_lock.lock();
+
+                    // First read of a lazily allocated page, which is backed by
+                    // the shared zero page until it's written to
+                    if trap_kind == Trap::LoadPageFault && active_task.memory_manager.map_zero_page(stval) {
+                        return sepc.as_usize();
+                    }
+
+                    // First write to, or execution of, a lazily allocated page
+                    if active_task.memory_manager.populate(stval) {
+                        return sepc.as_usize();
+                    }
+
+                    // First write to a page shared copy-on-write
+                    if trap_kind == Trap::StorePageFault && active_task.memory_manager.break_cow(stval) {
+                        return sepc.as_usize();
+                    }
+
+                    match swap::fault_in(&mut active_task, stval) {
+                        Some(FaultIn::Resident) => return sepc.as_usize(),
+                        // Picks up where it left off once the page is back in
+                        Some(FaultIn::Wait) => {
+                            let tid = active_task.tid;
+                            active_task.context.pc = sepc.as_usize();
+                            active_task.context.gp_regs = regs.registers;
+
+                            if let sstatus::FloatingPointStatus::Dirty = sstatus::fs() {
+                                save_fp_registers(&mut active_task.context.fp_regs);
+                            }
+
+                            drop(active_task);
+                            drop(active_task_lock);
+                            SCHEDULER.block(tid);
+
+                            return SCHEDULER.schedule();
+                        }
+                        None => {}
+                    }
+
                     let memory_manager = &mut active_task.memory_manager;
 
                     //log::info!("{:#?}", memory_manager.region_for(stval));
 
                     let valid = match memory_manager.region_for(stval) {
                         None | Some(AddressRegion { region: None, .. }) => false,
-                        Some(AddressRegion { region: Some(MemoryRegion::GuardPage), .. }) => {
-                            log::error!("Process hit a guard page, stack overflow?");
+                        Some(AddressRegion { region: Some(MemoryRegion::GuardPage), span, .. }) => {
+                            match memory_manager.region_for(span.end) {
+                                Some(AddressRegion { kind: AddressRegionKind::Stack, .. }) => {
+                                    log::error!("Stack overflow in task {} @ {:#p}", active_task.name, stval)
+                                }
+                                _ => log::error!("Process hit a guard page, stack overflow?"),
+                            }
+
                             false
                         }
                         _ => match trap_kind {
@@ -351,40 +487,176 @@ pub extern "C" fn trap_handler(regs: &mut TrapFrame, sepc: usize, scause: usize,
                             sepc.as_usize()
                         }
                         false => {
-                            log::error!(
-                                "Process {} died to a {:?} @ {:#p} (PC: {:#p})",
-                                active_task.name,
-                                trap_kind,
-                                stval,
-                                sepc,
-                            );
-                            log::error!("Register dump:\n{:?}", regs);
-                            // log::error!("Stack dump (last 32 values):\n");
-                            // let mut sp = regs.registers.sp as *const u64;
-                            // for _ in 0..32 {
-                            //     log::error!("{:#p}: {:#x}", sp, unsafe { *sp });
-                            //     sp = unsafe { sp.offset(1) };
-                            // }
-                            log::error!(
-                                "Memory map:\n{:#?}",
-                                active_task.memory_manager.address_map_debug(Some(stval))
-                            );
-                            log::error!("Phys addr (if any): {:?}", active_task.memory_manager.resolve(stval));
-                            active_task.state = TaskState::Dead;
-
                             drop(active_task);
                             drop(active_task_lock);
 
-                            SCHEDULER.schedule()
+                            fatal_user_fault(regs, trap_kind, scause, sepc, stval)
                         }
                     }
                 }
             }
         }
+        Trap::InstructionAddressMisaligned
+        | Trap::InstructionAccessFault
+        | Trap::IllegalInstruction
+        | Trap::Breakpoint
+        | Trap::LoadAddressMisaligned
+        | Trap::LoadAccessFault
+        | Trap::StoreAddressMisaligned
+        | Trap::StoreAccessFault
+            if !VirtualAddress::new(sepc).is_kernel_region() =>
+        {
+            fatal_user_fault(regs, trap_kind, scause, VirtualAddress::new(sepc), VirtualAddress::new(stval))
+        }
         trap => panic!("Ignoring trap: {:?}, sepc: {:#x}, stval: {:#x}", trap, sepc, stval),
     }
 }
 
+/// Send the active task to its fault handler if it has one, otherwise kill it,
+/// returning the `sepc` to return to
+fn fatal_user_fault(
+    regs: &mut TrapFrame,
+    trap_kind: Trap,
+    scause: usize,
+    sepc: VirtualAddress,
+    stval: VirtualAddress,
+) -> usize {
+    let active_task_lock = SCHEDULER.active_on_cpu().unwrap();
+    let mut active_task = active_task_lock.lock();
+
+    if let Some(handler) = active_task.fault_handler.take() {
+        match write_fault_info(&active_task, handler, regs, scause, sepc, stval) {
+            Some(info) => {
+                log::debug!(
+                    "Process {} hit a {:?} @ {:#p} (PC: {:#p}), entering its fault handler",
+                    active_task.name,
+                    trap_kind,
+                    stval,
+                    sepc,
+                );
+
+                regs.ra = 0;
+                regs.sp = info.as_usize();
+                regs.a0 = info.as_usize();
+
+                return handler.entry.as_usize();
+            }
+            None => log::error!("Process {} has an unusable fault handler stack", active_task.name),
+        }
+    }
+
+    log::error!("Process {} died to a {:?} @ {:#p} (PC: {:#p})", active_task.name, trap_kind, stval, sepc,);
+    log::error!("Register dump:\n{:?}", regs);
+    let mut backtrace = [0; FAULT_BACKTRACE_LEN];
+    let backtrace_len = user_backtrace(&active_task, regs.s0, &mut backtrace);
+    log::error!("Backtrace:");
+    for (i, ra) in backtrace[..backtrace_len].iter().enumerate() {
+        log::error!("  {:>2}: {:#x}", i, ra);
+    }
+    log::error!("Memory map:\n{:#?}", active_task.memory_manager.address_map_debug(Some(stval)));
+    log::error!("Phys addr (if any): {:?}", active_task.memory_manager.resolve(stval));
+    active_task.state = TaskState::Dead;
+    crate::task::reap_later(active_task.tid);
+
+    drop(active_task);
+    drop(active_task_lock);
+
+    SCHEDULER.schedule()
+}
+
+/// Write out a [`FaultInfo`] to the top of the fault handler's stack,
+/// returning where it was written or `None` if the stack isn't writable
+fn write_fault_info(
+    task: &Task,
+    handler: FaultHandler,
+    regs: &TrapFrame,
+    scause: usize,
+    sepc: VirtualAddress,
+    stval: VirtualAddress,
+) -> Option<VirtualAddress> {
+    let top = handler.stack_top.as_usize();
+    let info_addr = VirtualAddress::new(top.checked_sub(core::mem::size_of::<FaultInfo>())? & !15);
+    let mut info =
+        unsafe { RawUserPtr::<ReadWrite, FaultInfo>::writable(info_addr).validate(&task.memory_manager) }.ok()?;
+
+    // The whole snapshot is taken if possible, otherwise only what's left of
+    // the page the stack pointer is in, which is the most likely to be mapped
+    // after a stack overflow
+    let sp = regs.sp;
+    let rest_of_page = (4096 - sp % 4096).min(FAULT_STACK_SNAPSHOT);
+    let stack = [FAULT_STACK_SNAPSHOT, rest_of_page].into_iter().find_map(|len| unsafe {
+        RawUserSlice::<Read, u8>::readable(VirtualAddress::new(sp), len).validate(&task.memory_manager).ok()
+    });
+
+    let name = task.name.as_bytes();
+    let name_len = name.len().min(FAULT_NAME_LEN);
+    let mut backtrace = [0; FAULT_BACKTRACE_LEN];
+    let backtrace_len = user_backtrace(task, regs.s0, &mut backtrace);
+
+    info.with(|info| {
+        info.cause = scause;
+        info.pc = sepc.as_usize();
+        info.address = stval.as_usize();
+        // SAFETY: `GeneralRegisters` is `repr(C)` and contains exactly `x1`
+        // through `x31` in order
+        info.registers = unsafe { core::mem::transmute::<GeneralRegisters, [usize; 31]>(regs.registers) };
+        info.name_len = name_len;
+        info.name[..name_len].copy_from_slice(&name[..name_len]);
+        info.stack_len = 0;
+
+        if let Some(stack) = &stack {
+            stack.with(|stack| {
+                info.stack[..stack.len()].copy_from_slice(stack);
+                info.stack_len = stack.len();
+            });
+        }
+
+        info.backtrace = backtrace;
+        info.backtrace_len = backtrace_len;
+    });
+
+    Some(info_addr)
+}
+
+/// Walk the task's frame pointers starting at `fp`, filling `backtrace` with
+/// the return addresses found along the way and returning how many there were.
+/// Userspace can put anything in `s0`, so each frame record is validated before
+/// it's read, and the walk stops at the first one which isn't readable or
+/// doesn't move up the stack.
+pub fn user_backtrace(task: &Task, mut fp: usize, backtrace: &mut [usize]) -> usize {
+    let mut len = 0;
+
+    while len < backtrace.len() {
+        // The frame record is the caller's `fp` followed by the return
+        // address, just below where `fp` points
+        let record = match fp.checked_sub(16) {
+            Some(record) => VirtualAddress::new(record),
+            None => break,
+        };
+
+        let record = match unsafe { RawUserSlice::<Read, usize>::readable(record, 2).validate(&task.memory_manager) } {
+            Ok(record) => record,
+            Err(_) => break,
+        };
+
+        let (next_fp, ra) = record.with(|record| (record[0], record[1]));
+        if ra == 0 {
+            break;
+        }
+
+        backtrace[len] = ra;
+        len += 1;
+
+        if next_fp <= fp {
+            break;
+        }
+
+        fp = next_fp;
+    }
+
+    len
+}
+
 /// # Safety
 /// nice try
 #[naked]
@@ -470,6 +742,12 @@ pub unsafe extern "C" fn stvec_trap_shim() -> ! {
 
         call trap_handler
 
+        # Every return address pushed to the shadow call stack should have
+        # been popped again by now, anything else means it's been tampered with
+        csrr t0, sscratch
+        ld t0, 16(t0)
+        bne gp, t0, 1f
+
         csrw sepc, a0
 
         ld x1, 0(sp)
@@ -510,9 +788,40 @@ pub unsafe extern "C" fn stvec_trap_shim() -> ! {
 
         # gtfo
         sret
+
+    1:
+        mv a0, gp
+        mv a1, t0
+        call shadow_call_stack_corrupted
     ", options(noreturn));
 }
 
+#[no_mangle]
+extern "C" fn shadow_call_stack_corrupted(gp: usize, expected: usize) -> ! {
+    panic!("Shadow call stack corrupted: gp is {:#x} on trap exit, expected {:#x}", gp, expected);
+}
+
+/// Save where `task` was interrupted, so it can be picked back up later
+fn save_context(task: &mut Task, regs: &TrapFrame, sepc: usize) {
+    task.context.pc = sepc;
+    task.context.gp_regs = regs.registers;
+
+    if let sstatus::FloatingPointStatus::Dirty = sstatus::fs() {
+        save_fp_registers(&mut task.context.fp_regs);
+    }
+}
+
+/// The floating point registers of `task`, which has to be the task running on
+/// this hart, since they're only saved into its context when it's interrupted
+pub fn current_fp_registers(task: &Task) -> FloatingPointRegisters {
+    let mut fp_regs = task.context.fp_regs;
+    if let sstatus::FloatingPointStatus::Dirty = sstatus::fs() {
+        save_fp_registers(&mut fp_regs);
+    }
+
+    fp_regs
+}
+
 #[rustfmt::skip]
 extern "C" fn save_fp_registers(fp_regs: &mut FloatingPointRegisters) {
     unsafe {
@@ -35,26 +35,6 @@ impl LinkerSymbol {
 unsafe impl Sync for LinkerSymbol {}
 unsafe impl Send for LinkerSymbol {}
 
-#[repr(transparent)]
-pub struct StaticMut<T>(core::cell::UnsafeCell<T>);
-
-impl<T> StaticMut<T> {
-    pub const fn new(t: T) -> Self {
-        Self(core::cell::UnsafeCell::new(t))
-    }
-
-    /// # Safety
-    ///
-    /// You must ensure that no data races nor aliasing occurs when using the
-    /// resulting pointer
-    pub const fn get(&self) -> *mut T {
-        self.0.get()
-    }
-}
-
-unsafe impl<T> Sync for StaticMut<T> {}
-unsafe impl<T> Send for StaticMut<T> {}
-
 pub fn micros(ticks: u64, hz: u64) -> u64 {
     // ticks / hz -> second
     // ticks / (hz / 1000) -> millisecond
@@ -0,0 +1,141 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Watchdog for critical services
+//!
+//! Tasks which arm the watchdog have to pet it before their timeout runs out,
+//! and the system is rebooted if any of them doesn't. Expired timeouts are
+//! checked for on every timer interrupt, which is also when the hardware
+//! watchdog is pet if the device tree describes one, so that the system is
+//! still rebooted if the kernel itself stops handling interrupts.
+
+use crate::{csr, scheduler::TASKS, time};
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::time::Duration;
+use librust::{syscalls::power::ResetKind, task::Tid};
+use sync::{SpinMutex, SpinRwLock};
+
+/// How long the hardware watchdog waits to be pet before resetting the system
+pub const HARDWARE_TIMEOUT_US: u64 = 10_000_000;
+
+static WATCHES: SpinMutex<BTreeMap<Tid, Watch>> = SpinMutex::new(BTreeMap::new());
+static HARDWARE: SpinRwLock<Option<&'static dyn HardwareWatchdog>> = SpinRwLock::new(None);
+
+/// A watchdog timer which resets the system on its own if it isn't pet in time
+pub trait HardwareWatchdog: Send + Sync {
+    /// Restart the watchdog's countdown
+    fn pet(&self);
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Watch {
+    /// In `time` CSR ticks
+    timeout: u64,
+    deadline: u64,
+    last_pet: u64,
+}
+
+/// Pet `watchdog` from now on, which must already be started
+pub fn register_hardware(watchdog: &'static dyn HardwareWatchdog) {
+    watchdog.pet();
+    *HARDWARE.write() = Some(watchdog);
+}
+
+/// Pet the hardware watchdog, if there is one, for code which keeps the hart
+/// busy with interrupts disabled for a while
+pub fn pet_hardware() {
+    if let Some(hardware) = *HARDWARE.read() {
+        hardware.pet();
+    }
+}
+
+/// Whether there's a hardware watchdog which needs petting
+pub fn has_hardware() -> bool {
+    HARDWARE.read().is_some()
+}
+
+/// Start watching `tid`, which has to pet the watchdog at least once every
+/// `micros` microseconds
+pub fn arm(tid: Tid, micros: u64) {
+    let timeout = time::ticks_from_duration(Duration::from_micros(micros));
+    let now = csr::time::read();
+
+    WATCHES.lock().insert(tid, Watch { timeout, deadline: now.saturating_add(timeout), last_pet: now });
+}
+
+pub fn pet(tid: Tid) {
+    if let Some(watch) = WATCHES.lock().get_mut(&tid) {
+        watch.last_pet = csr::time::read();
+        watch.deadline = watch.last_pet.saturating_add(watch.timeout);
+    }
+}
+
+/// Restart the countdown of every watched task, for when they couldn't have
+/// run for a while through no fault of their own
+pub fn pet_all() {
+    let now = csr::time::read();
+    for watch in WATCHES.lock().values_mut() {
+        watch.last_pet = now;
+        watch.deadline = now.saturating_add(watch.timeout);
+    }
+}
+
+pub fn disarm(tid: Tid) {
+    WATCHES.lock().remove(&tid);
+}
+
+/// Reboot the system if any watched task has missed its deadline, otherwise
+/// pet the hardware watchdog. This is called on every timer interrupt.
+pub fn poll() {
+    let watches = match WATCHES.try_lock() {
+        Some(watches) => watches,
+        None => return,
+    };
+
+    let now = csr::time::read();
+    let expired = watches.iter().filter(|(_, watch)| watch.deadline <= now).collect::<Vec<_>>();
+
+    if expired.is_empty() {
+        drop(watches);
+        pet_hardware();
+
+        return;
+    }
+
+    log::error!("Watchdog expired for {} task(s), rebooting", expired.len());
+    for (&tid, watch) in expired {
+        let since_pet_ms = time::duration_from_ticks(now - watch.last_pet).as_millis();
+        let timeout_ms = time::duration_from_ticks(watch.timeout).as_millis();
+
+        match TASKS.get(tid) {
+            // Another hart could be holding the lock forever if that's what
+            // the task is stuck on, so don't wait for it
+            Some(task) => match task.try_lock() {
+                Some(task) => log::error!(
+                    "  Task {} ({:?}) last pet the watchdog {}ms ago (timeout: {}ms), state: {:?}, pc: {:#x}",
+                    task.name,
+                    tid,
+                    since_pet_ms,
+                    timeout_ms,
+                    task.state,
+                    task.context.pc
+                ),
+                None => log::error!(
+                    "  Task {:?} last pet the watchdog {}ms ago (timeout: {}ms), its lock is held",
+                    tid,
+                    since_pet_ms,
+                    timeout_ms
+                ),
+            },
+            None => {
+                log::error!("  Task {:?} exited without disarming the watchdog, last pet {}ms ago", tid, since_pet_ms)
+            }
+        }
+    }
+
+    crate::power::reset(ResetKind::Reboot);
+}
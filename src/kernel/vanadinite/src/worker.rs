@@ -0,0 +1,127 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Background work done by harts which would otherwise be idle
+//!
+//! There are two kinds of background work. Deferred work is a one-off closure
+//! queued on the hart which deferred it, so that e.g. tearing down an exited
+//! task doesn't hold up the next one being scheduled. Harts which run out of
+//! their own deferred work steal it from the other harts' queues, unless it was
+//! pinned to the hart which queued it. Idle jobs are registered once and run
+//! over and over for as long as they have something to do, for upkeep like
+//! reclaiming memory before it runs out.
+//!
+//! What runs here:
+//!
+//! * Freed address spaces and exited tasks are dropped through
+//!   [`defer_pinned`], which serves as the kernel's deferred-free queue: a hart
+//!   only runs its pinned work once it's idle, by which point it can't still be
+//!   using what's being freed.
+//! * Freed pages are scrubbed into the pool of zeroed pages by
+//!   [`crate::mem::phys::zeroed::refill`], so zeroed allocations rarely have to
+//!   wait on it.
+//! * Balloon reclaim, megapage promotion, compaction, swap-out, and memory
+//!   pressure checks are idle jobs registered at boot.
+//!
+//! Buddy blocks aren't merged here, since the buddy allocator already merges a
+//! block with its buddy as soon as it's freed. Dirty block-cache writeback isn't
+//! done either: block devices and any caching of them live in userspace
+//! servers, and the kernel has no block cache to write back.
+//!
+//! All of it runs in the scheduler's idle path with interrupts disabled, so
+//! each piece of work should be short. The hart goes back to sleep as soon as
+//! an interrupt is pending.
+
+use crate::{cpu_local::PerHart, csr, HART_ID};
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
+use sync::{SpinMutex, SpinRwLock};
+
+type DeferredWork = Box<dyn FnOnce() + Send>;
+
+static QUEUES: PerHart<SpinMutex<HartQueue>> =
+    PerHart::new(|| SpinMutex::new(HartQueue { pinned: VecDeque::new(), stealable: VecDeque::new() }));
+
+/// Each job returns whether it still has more to do
+static IDLE_JOBS: SpinRwLock<Vec<fn() -> bool>> = SpinRwLock::new(Vec::new());
+
+struct HartQueue {
+    /// Work which can only be run by this hart
+    pinned: VecDeque<DeferredWork>,
+    stealable: VecDeque<DeferredWork>,
+}
+
+/// Run `work` the next time a hart is idle
+pub fn defer(work: impl FnOnce() + Send + 'static) {
+    QUEUES.current().lock().stealable.push_back(Box::new(work));
+}
+
+/// Run `work` the next time the current hart is idle, for work which isn't
+/// safe to do on other harts until this one has gone idle
+pub fn defer_pinned(work: impl FnOnce() + Send + 'static) {
+    QUEUES.current().lock().pinned.push_back(Box::new(work));
+}
+
+/// Let other harts take the current hart's pinned work, for when it's going
+/// offline and has stopped using anything the work could tear down
+pub fn release_pinned() {
+    let mut queue = QUEUES.current().lock();
+    let pinned = core::mem::take(&mut queue.pinned);
+    queue.stealable.extend(pinned);
+}
+
+/// Run `job` whenever a hart is idle, until it returns `false` for that idle
+/// period
+pub fn register_idle_job(job: fn() -> bool) {
+    IDLE_JOBS.write().push(job);
+}
+
+/// Run background work until there's none left or an interrupt needs to be
+/// handled
+pub fn run_while_idle() {
+    while !interrupt_pending() {
+        if let Some(work) = take_work() {
+            work();
+            continue;
+        }
+
+        let mut more = false;
+        for job in IDLE_JOBS.read().iter() {
+            if interrupt_pending() {
+                return;
+            }
+
+            more |= job();
+        }
+
+        if !more {
+            break;
+        }
+    }
+}
+
+/// Take the oldest work queued on this hart, or steal the newest work from
+/// another hart if there isn't any
+fn take_work() -> Option<DeferredWork> {
+    let current = HART_ID.get();
+    {
+        let mut queue = QUEUES[current].lock();
+        if let Some(work) = queue.pinned.pop_front().or_else(|| queue.stealable.pop_front()) {
+            return Some(work);
+        }
+    }
+
+    // Don't wait on another hart which is busy with its own queue
+    QUEUES
+        .iter()
+        .enumerate()
+        .filter(|(hart, _)| *hart != current)
+        .find_map(|(_, queue)| queue.try_lock().and_then(|mut queue| queue.stealable.pop_back()))
+}
+
+fn interrupt_pending() -> bool {
+    csr::sip::read() & csr::sie::read() != 0
+}
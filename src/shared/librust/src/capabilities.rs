@@ -102,6 +102,18 @@ pub enum CapabilityDescription {
     Channel = 0,
     Memory { ptr: *mut u8, len: usize, permissions: MemoryPermissions } = 1,
     MappedMmio { ptr: *mut u8, len: usize, n_interrupts: usize } = 2,
+    Power = 3,
+    TaskGroup = 4,
+    Topic = 5,
+    Debug = 6,
+    /// One end of a pipe, the read end if the capability has `READ` and the
+    /// write end if it has `WRITE`
+    Pipe = 7,
+    Clock = 8,
+    MemoryPressure = 9,
+    Console = 10,
+    Balloon = 11,
+    Swap = 12,
 }
 
 impl Default for CapabilityDescription {
@@ -12,6 +12,8 @@ pub const INVALID_OPERATION: usize = 2;
 pub const INVALID_ARGUMENT: usize = 3;
 pub const WOULD_BLOCK: usize = 4;
 pub const UNKNOWN_SYSCALL: usize = 5;
+pub const QUOTA_EXCEEDED: usize = 6;
+pub const OUT_OF_MEMORY: usize = 7;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum SyscallError {
@@ -20,6 +22,11 @@ pub enum SyscallError {
     InvalidArgument(u32),
     UnknownSyscall,
     WouldBlock,
+    /// Sending would put the task over the limit on how much kernel memory its
+    /// unread messages can take up
+    QuotaExceeded,
+    /// There isn't enough free memory left to allocate what was asked for
+    OutOfMemory,
 }
 
 impl SyscallError {
@@ -38,6 +45,8 @@ impl SyscallError {
                 RawSyscallError::new(NonZeroUsize::new(UNKNOWN_SYSCALL).unwrap())
             }
             Self::WouldBlock => RawSyscallError::new(NonZeroUsize::new(WOULD_BLOCK).unwrap()),
+            Self::QuotaExceeded => RawSyscallError::new(NonZeroUsize::new(QUOTA_EXCEEDED).unwrap()),
+            Self::OutOfMemory => RawSyscallError::new(NonZeroUsize::new(OUT_OF_MEMORY).unwrap()),
         }
     }
 }
@@ -83,6 +92,8 @@ impl RawSyscallError {
             INVALID_ARGUMENT => SyscallError::InvalidArgument(self.context() as u32),
             UNKNOWN_SYSCALL => SyscallError::UnknownSyscall,
             WOULD_BLOCK => SyscallError::WouldBlock,
+            QUOTA_EXCEEDED => SyscallError::QuotaExceeded,
+            OUT_OF_MEMORY => SyscallError::OutOfMemory,
             _ => panic!("invalid syscall error kind"),
         }
     }
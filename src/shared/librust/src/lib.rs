@@ -29,7 +29,10 @@ extern crate alloc;
 pub mod capabilities;
 pub mod error;
 pub mod mem;
+#[cfg(feature = "rt")]
+pub mod rt;
 pub mod syscalls;
 pub mod task;
 pub mod taskgroup;
 pub mod units;
+pub mod uuid;
@@ -8,7 +8,10 @@
 use crate::{
     capabilities::CapabilityPtr,
     error::SyscallError,
-    syscalls::mem::{alloc_dma_memory, AllocationOptions, DmaAllocationOptions, MemoryPermissions},
+    syscalls::mem::{
+        alloc_dma_memory, dealloc_dma_memory, sync_dma_for_cpu, sync_dma_for_device, AllocationOptions,
+        DmaAllocationOptions, DmaDirection, MemoryPermissions, SealFlags,
+    },
     units::Bytes,
 };
 use core::{
@@ -44,6 +47,73 @@ impl MemoryAllocation {
         })
     }
 
+    /// The size of the underlying shared memory object, which can be bigger
+    /// than [`Self::ptr`] if another task has grown it
+    pub fn shared_size(&self) -> Result<usize, SyscallError> {
+        crate::syscalls::mem::shared_memory_size(self.cptr)
+    }
+
+    /// Grow the allocation to at least `new_size` bytes, which can move it.
+    /// For shared allocations this grows the underlying shared memory object,
+    /// and private allocations keep what's in them without being copied.
+    pub fn grow(&mut self, new_size: Bytes) -> Result<(), SyscallError> {
+        let ptr = match self.is_private() {
+            true if new_size.0 <= self.ptr.len() => return Ok(()),
+            // SAFETY: The allocation is only accessed through `self.ptr`,
+            // which is updated to where it moved to
+            true => unsafe { crate::syscalls::mem::resize_memory(self.ptr.as_ptr().cast(), new_size.0)? },
+            false => crate::syscalls::mem::grow_shared_memory(self.cptr, new_size.0)?,
+        };
+
+        // SAFETY: The kernel will never return us a null pointer if the
+        // syscall succeeds
+        self.ptr = unsafe { NonNull::new_unchecked(ptr) };
+
+        Ok(())
+    }
+
+    /// Shrink a private allocation to `new_size` bytes rounded up to its page
+    /// size, freeing the rest. Shared memory objects can't shrink, since other
+    /// tasks could still be using the memory.
+    pub fn shrink(&mut self, new_size: Bytes) -> Result<(), SyscallError> {
+        if !self.is_private() {
+            return Err(SyscallError::InvalidArgument(0));
+        }
+
+        if new_size.0 >= self.ptr.len() {
+            return Ok(());
+        }
+
+        // SAFETY: The allocation is only accessed through `self.ptr`, which is
+        // updated to cover what's left of it
+        let ptr = unsafe { crate::syscalls::mem::resize_memory(self.ptr.as_ptr().cast(), new_size.0)? };
+        // SAFETY: The kernel will never return us a null pointer if the
+        // syscall succeeds
+        self.ptr = unsafe { NonNull::new_unchecked(ptr) };
+
+        Ok(())
+    }
+
+    fn is_private(&self) -> bool {
+        self.cptr.value() == usize::MAX
+    }
+
+    /// Map the underlying shared memory object again, to pick up memory added
+    /// by another task growing it
+    pub fn remap(&mut self) -> Result<(), SyscallError> {
+        let ptr = crate::syscalls::mem::map_shared_memory(self.cptr)?;
+        // SAFETY: The kernel will never return us a null pointer if the
+        // syscall succeeds
+        self.ptr = unsafe { NonNull::new_unchecked(ptr) };
+
+        Ok(())
+    }
+
+    /// Permanently seal the allocation's capability, see [`SealFlags`]
+    pub fn seal(&self, flags: SealFlags) -> Result<(), SyscallError> {
+        crate::syscalls::mem::seal(self.cptr, flags)
+    }
+
     pub unsafe fn as_mut(&mut self) -> &mut [u8] {
         self.ptr.as_mut()
     }
@@ -155,6 +225,8 @@ impl<T: ?Sized> DmaRegion<T> {
             .map(|(phys, virt)| Self { phys, virt: core::ptr::from_raw_parts_mut(virt.cast(), metadata) })
     }
 
+    /// The address devices should use to access the region, which might not
+    /// be its physical address if there's an IOMMU
     pub fn physical_address(&self) -> PhysicalAddress {
         self.phys
     }
@@ -162,6 +234,18 @@ impl<T: ?Sized> DmaRegion<T> {
     pub fn get_mut(&mut self) -> &mut T {
         unsafe { &mut *self.virt }
     }
+
+    /// Make everything written to the region visible to the device before
+    /// handing it over
+    pub fn sync_for_device(&self, direction: DmaDirection) -> Result<(), SyscallError> {
+        sync_dma_for_device(self.virt.cast(), core::mem::size_of_val(&**self), direction)
+    }
+
+    /// Make everything the device wrote to the region visible after taking it
+    /// back
+    pub fn sync_for_cpu(&self, direction: DmaDirection) -> Result<(), SyscallError> {
+        sync_dma_for_cpu(self.virt.cast(), core::mem::size_of_val(&**self), direction)
+    }
 }
 
 impl<T> DmaRegion<MaybeUninit<T>> {
@@ -206,8 +290,11 @@ impl<T: ?Sized> core::ops::DerefMut for DmaRegion<T> {
 }
 
 impl<T: ?Sized> core::ops::Drop for DmaRegion<T> {
-    // TODO: dealloc memory
-    fn drop(&mut self) {}
+    fn drop(&mut self) {
+        // Nothing can be done about it failing, and it only fails if the
+        // memory isn't DMA memory anymore
+        let _ = unsafe { dealloc_dma_memory(self.virt.cast()) };
+    }
 }
 
 pub struct DmaElement<'a, T> {
@@ -230,3 +317,82 @@ impl<'a, T> DmaElement<'a, T> {
         unsafe { &mut *self.virt }
     }
 }
+
+/// Memory anywhere in the address space pinned for DMA, for handing a device
+/// a list of the physically contiguous pieces it's made of instead of copying
+/// it into a [`DmaRegion`]. The memory stays resident and where it is in
+/// physical memory until the list is dropped.
+#[cfg(feature = "alloc")]
+pub struct SgList {
+    id: crate::syscalls::mem::DmaPinId,
+    virt: *const u8,
+    len: usize,
+    direction: DmaDirection,
+    segments: alloc::vec::Vec<crate::syscalls::mem::DmaSegment>,
+}
+
+#[cfg(feature = "alloc")]
+impl SgList {
+    /// Pin the `len` bytes at `virt` for a device to access in `direction`,
+    /// which needs them to be writable unless it's
+    /// [`DmaDirection::ToDevice`]
+    pub fn new(virt: *const u8, len: usize, direction: DmaDirection) -> Result<Self, SyscallError> {
+        use crate::syscalls::{mem::DmaSegment, swap::PAGE_SIZE};
+
+        // Enough for every page the memory touches to be a segment of its own
+        let end = (virt as usize).checked_add(len).ok_or(SyscallError::InvalidArgument(1))?;
+        let n_pages = match len {
+            0 => 0,
+            _ => (end - 1) / PAGE_SIZE - virt as usize / PAGE_SIZE + 1,
+        };
+        let mut segments = alloc::vec![DmaSegment::default(); n_pages];
+
+        let (id, n_segments) = crate::syscalls::mem::pin_dma_memory(virt, len, direction, &mut segments)?;
+        segments.truncate(n_segments);
+
+        Ok(Self { id, virt, len, direction, segments })
+    }
+
+    /// Pin `buffer` for a device to access in `direction`. The list doesn't
+    /// borrow `buffer`, so it's up to the caller not to reuse it while a device
+    /// could still be accessing it.
+    pub fn from_slice(buffer: &mut [u8], direction: DmaDirection) -> Result<Self, SyscallError> {
+        Self::new(buffer.as_ptr(), buffer.len(), direction)
+    }
+
+    /// The pieces of physically contiguous memory the list is made of, in
+    /// order, as devices see them
+    pub fn segments(&self) -> &[crate::syscalls::mem::DmaSegment] {
+        &self.segments
+    }
+
+    /// The total length of the list
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Make everything written to the memory visible to the device before
+    /// handing it over
+    pub fn sync_for_device(&self) -> Result<(), SyscallError> {
+        sync_dma_for_device(self.virt, self.len, self.direction)
+    }
+
+    /// Make everything the device wrote to the memory visible after taking it
+    /// back
+    pub fn sync_for_cpu(&self) -> Result<(), SyscallError> {
+        sync_dma_for_cpu(self.virt, self.len, self.direction)
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl core::ops::Drop for SgList {
+    fn drop(&mut self) {
+        // Nothing can be done about it failing, and it only fails if the
+        // memory isn't pinned anymore
+        let _ = unsafe { crate::syscalls::mem::unpin_dma_memory(self.id) };
+    }
+}
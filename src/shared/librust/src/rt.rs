@@ -0,0 +1,130 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A minimal runtime for tasks which don't use `std`
+//!
+//! Tiny driver tasks and test payloads don't need a heap, the bootstrap
+//! capabilities, or buffered stdout, so they can skip `std` entirely and be
+//! built with only `librust`:
+//!
+//! ```ignore
+//! #![no_std]
+//! #![no_main]
+//!
+//! librust::entry!(main);
+//!
+//! fn main() {
+//!     librust::println!("hello world");
+//! }
+//! ```
+//!
+//! [`entry!`] defines `_start` and the panic handler, since those can only be
+//! defined once per program and `std` programs already have their own. The
+//! kernel sets up the stack before the task starts, so all that's left to do is
+//! point `gp` at the small data section and zero `.bss`. Panicking prints the
+//! message with the debug print syscall and exits. Everything else is done
+//! directly through [`crate::syscalls`].
+
+use crate::syscalls::{io::debug_print, task::exit};
+use core::fmt::Write;
+
+static mut ARGS: [usize; 2] = [0; 2];
+static mut A2: usize = 0;
+
+/// Define the program's entry point to run `main`, which takes no arguments and
+/// returns `()`, with the `librust` runtime
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        #[no_mangle]
+        unsafe extern "C" fn _start(argc: usize, argv: usize, a2: usize) -> ! {
+            $crate::rt::start(argc, argv, a2, $main)
+        }
+
+        #[panic_handler]
+        fn panic(info: &core::panic::PanicInfo) -> ! {
+            $crate::rt::panic(info)
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {{
+        let _ = core::fmt::Write::write_fmt(&mut $crate::rt::DebugWriter, format_args!($($arg)*));
+    }};
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+/// Set up the runtime, run `main`, then exit
+///
+/// # Safety
+///
+/// Must only be called once, from `_start`, with the arguments the kernel
+/// started the task with
+#[doc(hidden)]
+pub unsafe fn start(argc: usize, argv: usize, a2: usize, main: fn()) -> ! {
+    #[rustfmt::skip]
+    core::arch::asm!("
+            .option push
+            .option norelax
+            lla gp, __global_pointer$
+            .option pop
+
+            lla {bss_start}, __bss_start
+            lla {bss_end}, end
+            1:
+                sb zero, 0({bss_start})
+                addi {bss_start}, {bss_start}, 1
+                blt {bss_start}, {bss_end}, 1b
+        ",
+        bss_start = out(reg) _,
+        bss_end = out(reg) _,
+    );
+
+    // `.bss` has to be zeroed before these are written, since they live in it
+    ARGS = [argc, argv];
+    A2 = a2;
+
+    main();
+    exit()
+}
+
+#[doc(hidden)]
+pub fn panic(info: &core::panic::PanicInfo) -> ! {
+    let _ = writeln!(DebugWriter, "PANIC: {}", info);
+    exit()
+}
+
+/// The arguments the task was started with
+pub fn args() -> &'static [&'static str] {
+    match unsafe { ARGS } {
+        [0, _] | [_, 0] => &[],
+        [argc, argv] => unsafe { core::slice::from_raw_parts(argv as *const &str, argc) },
+    }
+}
+
+/// The value the task was started with in `a2`, which is a pointer to the device
+/// tree for tasks which are given one
+pub fn a2() -> usize {
+    unsafe { A2 }
+}
+
+/// Writes straight to the kernel console with [`debug_print`], without any
+/// buffering
+pub struct DebugWriter;
+
+impl Write for DebugWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        debug_print(s.as_bytes()).map_err(|_| core::fmt::Error)
+    }
+}
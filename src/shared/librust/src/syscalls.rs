@@ -6,10 +6,24 @@
 // obtain one at https://mozilla.org/MPL/2.0/.
 
 pub mod channel;
+pub mod checkpoint;
+pub mod config;
+pub mod debug;
+pub mod futex;
 pub mod io;
+pub mod job;
 pub mod mem;
+pub mod pipe;
+pub mod power;
+pub mod pressure;
+pub mod profiler;
+pub mod stats;
+pub mod swap;
 pub mod task;
+pub mod time;
+pub mod topic;
 pub mod vmspace;
+pub mod watchdog;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(usize)]
@@ -31,6 +45,76 @@ pub enum Syscall {
     MintCapability = 23,
     RevokeCapability = 24,
     EnableNotifications = 25,
+    SetTimer = 26,
+    SetConsoleSinks = 27,
+    InflateBalloon = 28,
+    DeflateBalloon = 29,
+    GetRandom = 30,
+    AddEntropy = 31,
+    SetFaultHandler = 32,
+    SystemShutdown = 33,
+    SystemReboot = 34,
+    SubscribeShutdown = 35,
+    AcknowledgeShutdown = 36,
+    ArmWatchdog = 37,
+    PetWatchdog = 38,
+    DisarmWatchdog = 39,
+    SharedMemorySize = 40,
+    GrowSharedMemory = 41,
+    MapSharedMemory = 42,
+    SealMemory = 43,
+    KernelStats = 44,
+    FutexWait = 45,
+    FutexWake = 46,
+    FutexRequeue = 47,
+    CreateTaskGroup = 48,
+    JoinTaskGroup = 49,
+    SignalTaskGroup = 50,
+    HandleJobControl = 51,
+    ExecVmspace = 52,
+    SyncDmaMemory = 53,
+    SetChannelQueueDepth = 54,
+    CreateTopic = 55,
+    SubscribeTopic = 56,
+    PublishTopic = 57,
+    ShareVmspaceObject = 58,
+    EnableSwap = 59,
+    TakeSwapWrites = 60,
+    TakeSwapReads = 61,
+    CompleteSwapRead = 62,
+    KernelConfig = 63,
+    SystemSuspend = 64,
+    HartOffline = 65,
+    HartOnline = 66,
+    StartProfiler = 67,
+    StopProfiler = 68,
+    DrainProfileSamples = 69,
+    ResolveKernelSymbol = 70,
+    SnoopChannels = 71,
+    DumpPageTable = 72,
+    CreatePipe = 73,
+    ReadPipe = 74,
+    WritePipe = 75,
+    ClosePipe = 76,
+    MapSharedMemoryCopy = 77,
+    ReadWallClock = 78,
+    SetTime = 79,
+    ReadBootId = 80,
+    ModifyMemoryPermissions = 81,
+    ReadMemoryMap = 82,
+    QueryMemory = 83,
+    DeallocDmaMemory = 84,
+    MemoryStats = 85,
+    ResizeMemory = 86,
+    SubscribeMemoryPressure = 87,
+    SnapshotTask = 88,
+    RestoreTask = 89,
+    ReadCheckpointRegisters = 90,
+    ReadCheckpointMemory = 91,
+    DiscardCheckpoint = 92,
+    PinDmaMemory = 93,
+    UnpinDmaMemory = 94,
+    ReadConsoleRing = 95,
 }
 
 impl Syscall {
@@ -53,6 +137,76 @@ impl Syscall {
             23 => Some(Self::MintCapability),
             24 => Some(Self::RevokeCapability),
             25 => Some(Self::EnableNotifications),
+            26 => Some(Self::SetTimer),
+            27 => Some(Self::SetConsoleSinks),
+            28 => Some(Self::InflateBalloon),
+            29 => Some(Self::DeflateBalloon),
+            30 => Some(Self::GetRandom),
+            31 => Some(Self::AddEntropy),
+            32 => Some(Self::SetFaultHandler),
+            33 => Some(Self::SystemShutdown),
+            34 => Some(Self::SystemReboot),
+            35 => Some(Self::SubscribeShutdown),
+            36 => Some(Self::AcknowledgeShutdown),
+            37 => Some(Self::ArmWatchdog),
+            38 => Some(Self::PetWatchdog),
+            39 => Some(Self::DisarmWatchdog),
+            40 => Some(Self::SharedMemorySize),
+            41 => Some(Self::GrowSharedMemory),
+            42 => Some(Self::MapSharedMemory),
+            43 => Some(Self::SealMemory),
+            44 => Some(Self::KernelStats),
+            45 => Some(Self::FutexWait),
+            46 => Some(Self::FutexWake),
+            47 => Some(Self::FutexRequeue),
+            48 => Some(Self::CreateTaskGroup),
+            49 => Some(Self::JoinTaskGroup),
+            50 => Some(Self::SignalTaskGroup),
+            51 => Some(Self::HandleJobControl),
+            52 => Some(Self::ExecVmspace),
+            53 => Some(Self::SyncDmaMemory),
+            54 => Some(Self::SetChannelQueueDepth),
+            55 => Some(Self::CreateTopic),
+            56 => Some(Self::SubscribeTopic),
+            57 => Some(Self::PublishTopic),
+            58 => Some(Self::ShareVmspaceObject),
+            59 => Some(Self::EnableSwap),
+            60 => Some(Self::TakeSwapWrites),
+            61 => Some(Self::TakeSwapReads),
+            62 => Some(Self::CompleteSwapRead),
+            63 => Some(Self::KernelConfig),
+            64 => Some(Self::SystemSuspend),
+            65 => Some(Self::HartOffline),
+            66 => Some(Self::HartOnline),
+            67 => Some(Self::StartProfiler),
+            68 => Some(Self::StopProfiler),
+            69 => Some(Self::DrainProfileSamples),
+            70 => Some(Self::ResolveKernelSymbol),
+            71 => Some(Self::SnoopChannels),
+            72 => Some(Self::DumpPageTable),
+            73 => Some(Self::CreatePipe),
+            74 => Some(Self::ReadPipe),
+            75 => Some(Self::WritePipe),
+            76 => Some(Self::ClosePipe),
+            77 => Some(Self::MapSharedMemoryCopy),
+            78 => Some(Self::ReadWallClock),
+            79 => Some(Self::SetTime),
+            80 => Some(Self::ReadBootId),
+            81 => Some(Self::ModifyMemoryPermissions),
+            82 => Some(Self::ReadMemoryMap),
+            83 => Some(Self::QueryMemory),
+            84 => Some(Self::DeallocDmaMemory),
+            85 => Some(Self::MemoryStats),
+            86 => Some(Self::ResizeMemory),
+            87 => Some(Self::SubscribeMemoryPressure),
+            88 => Some(Self::SnapshotTask),
+            89 => Some(Self::RestoreTask),
+            90 => Some(Self::ReadCheckpointRegisters),
+            91 => Some(Self::ReadCheckpointMemory),
+            92 => Some(Self::DiscardCheckpoint),
+            93 => Some(Self::PinDmaMemory),
+            94 => Some(Self::UnpinDmaMemory),
+            95 => Some(Self::ReadConsoleRing),
             _ => None,
         }
     }
@@ -8,7 +8,7 @@
 use crate::{
     capabilities::{Capability, CapabilityPtr, CapabilityWithDescription},
     error::{RawSyscallError, SyscallError},
-    syscalls::Syscall,
+    syscalls::{job::JobSignal, power::ResetKind, pressure::MemoryPressure, Syscall},
 };
 
 #[derive(Debug, Default, Clone, Copy)]
@@ -48,7 +48,57 @@ impl core::ops::BitAnd for ChannelReadFlags {
     }
 }
 
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(transparent)]
+pub struct ChannelSendFlags(usize);
+
+impl ChannelSendFlags {
+    pub const NONE: Self = Self(0);
+    /// Queue the message ahead of any normal messages the receiver hasn't read
+    /// yet, for control messages like cancellations
+    pub const URGENT: Self = Self(1);
+
+    pub const fn new(flags: usize) -> Self {
+        Self(flags)
+    }
+
+    pub const fn value(self) -> usize {
+        self.0
+    }
+}
+
+impl core::ops::BitOr for ChannelSendFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl core::ops::BitAnd for ChannelSendFlags {
+    type Output = bool;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        self.0 & rhs.0 == rhs.0
+    }
+}
+
+/// Sends never block: if the receiver already has as many messages queued as
+/// it allows, this fails with [`SyscallError::WouldBlock`]. The kernel memory
+/// taken up by messages the task has sent but which haven't been read yet is
+/// also limited, and going over that fails with
+/// [`SyscallError::QuotaExceeded`]. Up to [`MAX_MESSAGE_CAPS`] capabilities
+/// can be sent with the message.
 pub fn send_message(cptr: CapabilityPtr, message: ChannelMessage, caps: &[Capability]) -> Result<(), SyscallError> {
+    send_message_with_flags(cptr, message, caps, ChannelSendFlags::NONE)
+}
+
+pub fn send_message_with_flags(
+    cptr: CapabilityPtr,
+    message: ChannelMessage,
+    caps: &[Capability],
+    flags: ChannelSendFlags,
+) -> Result<(), SyscallError> {
     let error: usize;
 
     unsafe {
@@ -58,6 +108,7 @@ pub fn send_message(cptr: CapabilityPtr, message: ChannelMessage, caps: &[Capabi
             in("a1") cptr.value(),
             in("a2") caps.as_ptr(),
             in("a3") caps.len(),
+            in("a4") flags.0,
             in("t0") message.0[0],
             in("t1") message.0[1],
             in("t2") message.0[2],
@@ -114,16 +165,55 @@ pub fn read_message(
     }
 }
 
+/// Let up to `depth` messages of each priority wait to be read on this side of
+/// the channel before sends to it fail
+pub fn set_queue_depth(cptr: CapabilityPtr, depth: usize) -> Result<(), SyscallError> {
+    let error: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::SetChannelQueueDepth as usize => error,
+            in("a1") cptr.value(),
+            in("a2") depth,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(()),
+    }
+}
+
 pub const KERNEL_CHANNEL: CapabilityPtr = CapabilityPtr::new(0);
 pub const PARENT_CHANNEL: CapabilityPtr = CapabilityPtr::new(1);
 
+/// The most capabilities a single message can carry. They're transferred all
+/// at once, so the receiver either gets every one of them or the send fails
+/// without any of them being sent.
+pub const MAX_MESSAGE_CAPS: usize = 32;
+
 pub const KMSG_INTERRUPT_OCCURRED: usize = 0;
 pub const KMSG_NEW_CHANNEL_MESSAGE: usize = 1;
+pub const KMSG_TIMER_EXPIRED: usize = 2;
+pub const KMSG_SHUTDOWN_REQUESTED: usize = 3;
+pub const KMSG_JOB_CONTROL: usize = 4;
+pub const KMSG_SWAP_PENDING: usize = 5;
+pub const KMSG_MEMORY_PRESSURE: usize = 6;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum KernelMessage {
     InterruptOccurred(usize),
     NewChannelMessage(CapabilityPtr),
+    TimerExpired(usize),
+    ShutdownRequested(ResetKind),
+    JobControl(JobSignal),
+    /// There are pages for the swap server to write out or read back in, see
+    /// [`crate::syscalls::swap`]
+    SwapPending,
+    /// Free memory crossed into another level, see
+    /// [`crate::syscalls::pressure`]
+    MemoryPressure(MemoryPressure),
 }
 
 impl KernelMessage {
@@ -131,6 +221,11 @@ impl KernelMessage {
         match self {
             Self::InterruptOccurred(n) => [KMSG_INTERRUPT_OCCURRED, n, 0, 0, 0, 0, 0],
             Self::NewChannelMessage(cptr) => [KMSG_NEW_CHANNEL_MESSAGE, cptr.value(), 0, 0, 0, 0, 0],
+            Self::TimerExpired(id) => [KMSG_TIMER_EXPIRED, id, 0, 0, 0, 0, 0],
+            Self::ShutdownRequested(kind) => [KMSG_SHUTDOWN_REQUESTED, kind.to_usize(), 0, 0, 0, 0, 0],
+            Self::JobControl(signal) => [KMSG_JOB_CONTROL, signal.to_usize(), 0, 0, 0, 0, 0],
+            Self::SwapPending => [KMSG_SWAP_PENDING, 0, 0, 0, 0, 0, 0],
+            Self::MemoryPressure(level) => [KMSG_MEMORY_PRESSURE, level.to_usize(), 0, 0, 0, 0, 0],
         }
     }
 
@@ -138,6 +233,20 @@ impl KernelMessage {
         match parts[0] {
             KMSG_INTERRUPT_OCCURRED => Self::InterruptOccurred(parts[1]),
             KMSG_NEW_CHANNEL_MESSAGE => Self::NewChannelMessage(CapabilityPtr::new(parts[1])),
+            KMSG_TIMER_EXPIRED => Self::TimerExpired(parts[1]),
+            KMSG_SHUTDOWN_REQUESTED => match ResetKind::from_usize(parts[1]) {
+                Some(kind) => Self::ShutdownRequested(kind),
+                None => unreachable!(),
+            },
+            KMSG_JOB_CONTROL => match JobSignal::from_usize(parts[1]) {
+                Some(signal) => Self::JobControl(signal),
+                None => unreachable!(),
+            },
+            KMSG_SWAP_PENDING => Self::SwapPending,
+            KMSG_MEMORY_PRESSURE => match MemoryPressure::from_usize(parts[1]) {
+                Some(level) => Self::MemoryPressure(level),
+                None => unreachable!(),
+            },
             _ => unreachable!(),
         }
     }
@@ -0,0 +1,167 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checkpointing tasks, which needs the debug capability
+//!
+//! [`snapshot_task`] freezes a task and saves its registers and memory into a
+//! checkpoint, with the memory shared copy-on-write so it's cheap to take.
+//! [`restore_task`] puts a task back the way it was at a checkpoint, as many
+//! times as needed, like a fuzzing harness resetting its target between
+//! inputs. Checkpoints belong to the task which took them, and can be looked
+//! at with [`read_checkpoint_registers`] and [`read_checkpoint_memory`] until
+//! they're discarded.
+//!
+//! Only registers and memory are part of a checkpoint. Capabilities and
+//! everything else the kernel keeps for the task are left as they are when
+//! it's restored, and shared memory is shared with the checkpoint rather than
+//! copied, so writes to it aren't undone.
+
+use crate::{
+    capabilities::CapabilityPtr,
+    error::{RawSyscallError, SyscallError},
+    syscalls::Syscall,
+    task::Tid,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct CheckpointId(usize);
+
+impl CheckpointId {
+    pub const fn new(id: usize) -> Self {
+        Self(id)
+    }
+
+    pub const fn value(self) -> usize {
+        self.0
+    }
+}
+
+/// What [`snapshot_task`] returns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Snapshot {
+    Taken(CheckpointId),
+    /// A task which took a checkpoint of itself returns from
+    /// [`snapshot_task`] again each time it's restored to it, like `setjmp`
+    Restored(CheckpointId),
+}
+
+/// The registers of a task when a checkpoint was taken of it
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct CheckpointRegisters {
+    pub pc: usize,
+    /// `x1` through `x31`
+    pub registers: [usize; 31],
+}
+
+/// Take a checkpoint of `tid`, which can be the current task. Returns
+/// [`SyscallError::WouldBlock`] if the task is still running on another hart,
+/// in which case it's been frozen and the snapshot should be retried shortly.
+/// Tasks with device memory mapped can't be checkpointed.
+pub fn snapshot_task(debug: CapabilityPtr, tid: Tid) -> Result<Snapshot, SyscallError> {
+    let error: usize;
+    let id: usize;
+    let restored: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::SnapshotTask as usize => error,
+            inlateout("a1") debug.value() => id,
+            inlateout("a2") tid.value() => restored,
+        );
+    }
+
+    match (RawSyscallError::optional(error), restored) {
+        (Some(error), _) => Err(error.cook()),
+        (None, 0) => Ok(Snapshot::Taken(CheckpointId::new(id))),
+        (None, _) => Ok(Snapshot::Restored(CheckpointId::new(id))),
+    }
+}
+
+/// Put `tid` back the way it was at the checkpoint `id`, which doesn't have to
+/// have been taken of the same task. Restoring the current task doesn't return
+/// unless it fails. Other tasks can't be restored while they're blocked in a
+/// syscall, and can be made to restore themselves instead.
+pub fn restore_task(debug: CapabilityPtr, tid: Tid, id: CheckpointId) -> Result<(), SyscallError> {
+    let error: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::RestoreTask as usize => error,
+            in("a1") debug.value(),
+            in("a2") tid.value(),
+            in("a3") id.value(),
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(()),
+    }
+}
+
+pub fn read_checkpoint_registers(id: CheckpointId) -> Result<CheckpointRegisters, SyscallError> {
+    let error: usize;
+    let mut registers = CheckpointRegisters::default();
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::ReadCheckpointRegisters as usize => error,
+            in("a1") id.value(),
+            in("a2") &mut registers as *mut CheckpointRegisters,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(registers),
+    }
+}
+
+/// Copy the memory at `at` in the checkpoint `id` into `buffer`. Memory which
+/// was never touched reads as zeroes, and everything has to be mapped readable.
+pub fn read_checkpoint_memory(id: CheckpointId, at: *const u8, buffer: &mut [u8]) -> Result<(), SyscallError> {
+    let error: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::ReadCheckpointMemory as usize => error,
+            in("a1") id.value(),
+            in("a2") at,
+            in("a3") buffer.as_mut_ptr(),
+            in("a4") buffer.len(),
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(()),
+    }
+}
+
+/// Free the checkpoint `id`, along with any memory only it was still using
+pub fn discard_checkpoint(id: CheckpointId) -> Result<(), SyscallError> {
+    let error: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::DiscardCheckpoint as usize => error,
+            in("a1") id.value(),
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(()),
+    }
+}
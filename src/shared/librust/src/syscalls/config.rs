@@ -0,0 +1,103 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Which optional parts of the kernel it was built with
+//!
+//! Each of these corresponds to one of the kernel's Cargo features, so tests
+//! which depend on something that can be compiled out can check for it first.
+
+use crate::syscalls::Syscall;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct KernelConfig(usize);
+
+impl KernelConfig {
+    pub const NONE: Self = Self(0);
+    pub const PLATFORM_VIRT: Self = Self(1 << 0);
+    pub const PLATFORM_SIFIVE_U: Self = Self(1 << 1);
+    pub const DRIVER_DW_WDT: Self = Self(1 << 2);
+    pub const DRIVER_RISCV_IOMMU: Self = Self(1 << 3);
+    pub const DRIVER_SIFIVE_UART: Self = Self(1 << 4);
+    pub const DRIVER_SYSCON: Self = Self(1 << 5);
+    pub const DRIVER_UART16550: Self = Self(1 << 6);
+    /// Picked at boot rather than built in, when every hart supports Sv48
+    pub const PAGING_SV48: Self = Self(1 << 7);
+    pub const PMALLOC_BITMAP: Self = Self(1 << 8);
+    pub const PMALLOC_BUDDY: Self = Self(1 << 9);
+    pub const VMALLOC_FREELIST: Self = Self(1 << 10);
+    pub const HARDENING_SHADOW_CALL_STACK: Self = Self(1 << 11);
+    pub const DEBUG_REPLAY: Self = Self(1 << 12);
+
+    /// Every subsystem along with the name of the feature which enables it
+    pub const SUBSYSTEMS: &'static [(Self, &'static str)] = &[
+        (Self::PLATFORM_VIRT, "platform.virt"),
+        (Self::PLATFORM_SIFIVE_U, "platform.sifive_u"),
+        (Self::DRIVER_DW_WDT, "driver.dw_wdt"),
+        (Self::DRIVER_RISCV_IOMMU, "driver.riscv_iommu"),
+        (Self::DRIVER_SIFIVE_UART, "driver.sifive_uart"),
+        (Self::DRIVER_SYSCON, "driver.syscon"),
+        (Self::DRIVER_UART16550, "driver.uart16550"),
+        (Self::PAGING_SV48, "paging.sv48"),
+        (Self::PMALLOC_BITMAP, "pmalloc.allocator.bitmap"),
+        (Self::PMALLOC_BUDDY, "pmalloc.allocator.buddy"),
+        (Self::VMALLOC_FREELIST, "vmalloc.allocator.freelist"),
+        (Self::HARDENING_SHADOW_CALL_STACK, "hardening.shadow_call_stack"),
+        (Self::DEBUG_REPLAY, "debug.replay"),
+    ];
+
+    pub fn new(flags: usize) -> Self {
+        Self(flags)
+    }
+
+    pub fn value(self) -> usize {
+        self.0
+    }
+
+    /// The names of the features which are enabled
+    pub fn enabled(self) -> impl Iterator<Item = &'static str> {
+        Self::SUBSYSTEMS.iter().filter(move |&&(subsystem, _)| self & subsystem).map(|&(_, name)| name)
+    }
+}
+
+impl core::ops::BitOr for KernelConfig {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl core::ops::BitOrAssign for KernelConfig {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl core::ops::BitAnd for KernelConfig {
+    type Output = bool;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        self.0 & rhs.0 == rhs.0
+    }
+}
+
+/// The subsystems the running kernel was built with
+#[inline]
+pub fn kernel_config() -> KernelConfig {
+    let config: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::KernelConfig as usize => _,
+            lateout("a1") config,
+        );
+    }
+
+    KernelConfig(config)
+}
@@ -0,0 +1,260 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Looking in on other tasks, which needs the debug capability
+//!
+//! [`snoop_channels`] has the kernel report every message sent or received on
+//! a task's channels to the snooping task, without the messages being taken
+//! away from whoever they were meant for. Each message is reported as a
+//! [`SnoopRecord`], which in [`SnoopMode::Copy`] is followed by a copy of the
+//! message's data. Capabilities sent with the messages are only counted, never
+//! copied.
+//!
+//! [`dump_page_table`] lists what's mapped into a task's address space.
+
+use crate::{
+    capabilities::CapabilityPtr,
+    error::{RawSyscallError, SyscallError},
+    syscalls::Syscall,
+    task::Tid,
+};
+use core::num::NonZeroUsize;
+
+/// The capability init is started with which allows snooping on other tasks'
+/// channels. Init can hand it out to other tasks like any other capability.
+pub const DEBUG_CAPABILITY: CapabilityPtr = CapabilityPtr::new(3);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SnoopMode {
+    /// Report each message along with a copy of its data
+    Copy,
+    /// Only report that each message was sent, and how
+    Metadata,
+}
+
+impl SnoopMode {
+    pub const fn to_usize(self) -> usize {
+        match self {
+            SnoopMode::Copy => 0,
+            SnoopMode::Metadata => 1,
+        }
+    }
+
+    pub const fn from_usize(n: usize) -> Option<Self> {
+        match n {
+            0 => Some(SnoopMode::Copy),
+            1 => Some(SnoopMode::Metadata),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SnoopDirection {
+    /// The snooped task sent the message
+    Sent,
+    /// The snooped task was sent the message
+    Received,
+}
+
+/// What the kernel reports about each snooped message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnoopRecord {
+    pub direction: SnoopDirection,
+    /// The task on the other end of the channel, if the kernel knows it
+    pub peer: Option<Tid>,
+    /// What the snooped task calls the channel
+    pub cptr: CapabilityPtr,
+    pub urgent: bool,
+    /// How many capabilities were sent with the message
+    pub caps: usize,
+    /// How many messages weren't reported before this one because the snooping
+    /// channel was full
+    pub missed: usize,
+    /// Whether the next message on the snooping channel is a copy of this
+    /// one's data
+    pub has_data: bool,
+}
+
+impl SnoopRecord {
+    pub fn into_parts(self) -> [usize; 7] {
+        [
+            match self.direction {
+                SnoopDirection::Sent => 0,
+                SnoopDirection::Received => 1,
+            },
+            self.peer.map_or(0, Tid::value),
+            self.cptr.value(),
+            self.urgent as usize,
+            self.caps,
+            self.missed,
+            self.has_data as usize,
+        ]
+    }
+
+    pub fn construct(parts: [usize; 7]) -> Self {
+        Self {
+            direction: match parts[0] {
+                0 => SnoopDirection::Sent,
+                _ => SnoopDirection::Received,
+            },
+            peer: NonZeroUsize::new(parts[1]).map(Tid::new),
+            cptr: CapabilityPtr::new(parts[2]),
+            urgent: parts[3] != 0,
+            caps: parts[4],
+            missed: parts[5],
+            has_data: parts[6] != 0,
+        }
+    }
+}
+
+/// Snoop on the channels `tid` shares with `peer`, or on all of its channels
+/// if `peer` is `None`, returning the channel the [`SnoopRecord`]s arrive on
+/// and how many channels are being snooped. Only messages sent after this
+/// are reported, and fails with [`SyscallError::InvalidArgument`] if `tid`
+/// doesn't have any channels to `peer`.
+pub fn snoop_channels(
+    debug: CapabilityPtr,
+    tid: Tid,
+    peer: Option<Tid>,
+    mode: SnoopMode,
+) -> Result<(CapabilityPtr, usize), SyscallError> {
+    let error: usize;
+    let cptr: usize;
+    let snooped: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::SnoopChannels as usize => error,
+            inlateout("a1") debug.value() => cptr,
+            inlateout("a2") tid.value() => snooped,
+            in("a3") peer.map_or(0, Tid::value),
+            in("a4") mode.to_usize(),
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok((CapabilityPtr::new(cptr), snooped)),
+    }
+}
+
+/// A run of pages in a task's address space which map contiguous physical
+/// memory with the same page size and permissions
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PageMapping {
+    pub virt: usize,
+    pub phys: usize,
+    /// In bytes
+    pub len: usize,
+    /// In bytes
+    pub page_size: usize,
+    pub flags: usize,
+}
+
+impl PageMapping {
+    pub const READ: usize = 1 << 1;
+    pub const WRITE: usize = 1 << 2;
+    pub const EXECUTE: usize = 1 << 3;
+    pub const USER: usize = 1 << 4;
+    pub const GLOBAL: usize = 1 << 5;
+}
+
+/// Fill `mappings` with what's mapped into `tid`'s address space, in address
+/// order, returning how many mappings there are in total. If that's more than
+/// `mappings.len()`, the rest were left out.
+pub fn dump_page_table(debug: CapabilityPtr, tid: Tid, mappings: &mut [PageMapping]) -> Result<usize, SyscallError> {
+    let error: usize;
+    let total: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::DumpPageTable as usize => error,
+            inlateout("a1") debug.value() => total,
+            in("a2") tid.value(),
+            in("a3") mappings.as_mut_ptr(),
+            in("a4") mappings.len(),
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(total),
+    }
+}
+
+/// A range of physical memory and what it's used for, as the kernel found it
+/// at boot. Everything but [`MemoryMapEntry::RAM`] and
+/// [`MemoryMapEntry::LINEAR_MAP`] entries is carved out of RAM, so entries can
+/// overlap.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryMapEntry {
+    pub kind: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl MemoryMapEntry {
+    /// Memory the device tree or the EFI memory map says is usable RAM
+    pub const RAM: usize = 0;
+    /// Kept out of the allocator by a `/memreserve/` in the device tree
+    pub const RESERVED: usize = 1;
+    /// Kept for the firmware by a `/reserved-memory` node, or below the kernel
+    /// where the SBI implementation usually lives
+    pub const FIRMWARE: usize = 2;
+    pub const KERNEL_IMAGE: usize = 3;
+    pub const DEVICE_TREE: usize = 4;
+    /// The kernel heap, which is allocated before anything else
+    pub const KERNEL_HEAP: usize = 5;
+    /// The gigapages mapped into the kernel's linear map, which cover devices
+    /// as well as RAM
+    pub const LINEAR_MAP: usize = 6;
+    /// An initrd the bootloader loaded, which is kept out of the allocator
+    pub const INITRD: usize = 7;
+
+    /// A name for the kind of entry, for printing
+    pub fn kind_name(&self) -> &'static str {
+        match self.kind {
+            Self::RAM => "RAM",
+            Self::RESERVED => "reserved",
+            Self::FIRMWARE => "firmware",
+            Self::KERNEL_IMAGE => "kernel image",
+            Self::DEVICE_TREE => "device tree",
+            Self::KERNEL_HEAP => "kernel heap",
+            Self::LINEAR_MAP => "linear map",
+            Self::INITRD => "initrd",
+            _ => "unknown",
+        }
+    }
+}
+
+/// Fill `entries` with the physical memory map the kernel found at boot,
+/// ordered by address, returning how many entries there are in total. If
+/// that's more than `entries.len()`, the rest were left out.
+pub fn memory_map(debug: CapabilityPtr, entries: &mut [MemoryMapEntry]) -> Result<usize, SyscallError> {
+    let error: usize;
+    let total: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::ReadMemoryMap as usize => error,
+            inlateout("a1") debug.value() => total,
+            in("a2") entries.as_mut_ptr(),
+            in("a3") entries.len(),
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(total),
+    }
+}
@@ -0,0 +1,118 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Blocking on a word of memory
+//!
+//! A task can [`wait`] until the value of a futex word changes, and some other
+//! task which changed it can [`wake`] it up, or [`requeue`] it onto another
+//! futex. Futexes are identified by the memory backing them rather than their
+//! address, so they work across tasks which share memory too.
+
+use crate::{
+    error::{RawSyscallError, SyscallError},
+    syscalls::Syscall,
+};
+use core::{sync::atomic::AtomicU32, time::Duration};
+
+/// Block until the futex is woken, as long as it still holds `expected`.
+/// Returns right away if it doesn't, which should be treated the same as a
+/// wake.
+#[inline]
+pub fn wait(futex: &AtomicU32, expected: u32) {
+    let _ = raw_wait(futex, expected, usize::MAX);
+}
+
+/// Block until the futex is woken or `timeout` passes, as long as it still
+/// holds `expected`. Returns the time that was left until the timeout, or
+/// `None` if it passed first.
+#[inline]
+pub fn wait_timeout(futex: &AtomicU32, expected: u32, timeout: Duration) -> Option<Duration> {
+    // `usize::MAX` means no timeout at all
+    let micros = usize::try_from(timeout.as_micros()).unwrap_or(usize::MAX - 1).min(usize::MAX - 1);
+
+    match raw_wait(futex, expected, micros) {
+        Ok((true, remaining)) => Some(Duration::from_micros(remaining as u64)),
+        Ok((false, _)) => None,
+        Err(_) => Some(timeout),
+    }
+}
+
+/// Wake up to `n` of the tasks waiting on the futex, returning how many were
+/// woken
+#[inline]
+pub fn wake(futex: &AtomicU32, n: usize) -> usize {
+    let error: usize;
+    let woken: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::FutexWake as usize => error,
+            inlateout("a1") futex as *const AtomicU32 => woken,
+            in("a2") n,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(_) => 0,
+        None => woken,
+    }
+}
+
+/// Wake up to `n_wake` of the tasks waiting on `futex`, and move up to
+/// `n_requeue` of the rest over to waiting on `to`, as long as `futex` still
+/// holds `expected`. Returns how many tasks were woken and requeued.
+#[inline]
+pub fn requeue(
+    futex: &AtomicU32,
+    expected: u32,
+    to: &AtomicU32,
+    n_wake: usize,
+    n_requeue: usize,
+) -> Result<(usize, usize), SyscallError> {
+    let error: usize;
+    let woken: usize;
+    let requeued: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::FutexRequeue as usize => error,
+            inlateout("a1") futex as *const AtomicU32 => woken,
+            inlateout("a2") expected as usize => requeued,
+            in("a3") to as *const AtomicU32,
+            in("a4") n_wake,
+            in("a5") n_requeue,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok((woken, requeued)),
+    }
+}
+
+fn raw_wait(futex: &AtomicU32, expected: u32, timeout_us: usize) -> Result<(bool, usize), SyscallError> {
+    let error: usize;
+    let woken: usize;
+    let remaining_us: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::FutexWait as usize => error,
+            inlateout("a1") futex as *const AtomicU32 => woken,
+            inlateout("a2") expected as usize => remaining_us,
+            in("a3") timeout_us,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok((woken != 0, remaining_us)),
+    }
+}
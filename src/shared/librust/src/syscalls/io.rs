@@ -9,6 +9,7 @@ use super::{mem::MemoryPermissions, Syscall};
 use crate::{
     capabilities::CapabilityPtr,
     error::{RawSyscallError, SyscallError},
+    uuid::Uuid,
 };
 
 #[inline]
@@ -118,3 +119,163 @@ pub fn debug_print(value: &[u8]) -> Result<(), SyscallError> {
         None => Ok(()),
     }
 }
+
+/// The capability init is started with which allows changing where the
+/// kernel sends console output with `WRITE`, and reading the console ring with
+/// `READ`. Init can hand it out to other tasks like any other capability.
+pub const CONSOLE_CAPABILITY: CapabilityPtr = CapabilityPtr::new(6);
+
+/// Where the kernel sends console output. Consoles the kernel doesn't drive
+/// itself, like a virtio console or a framebuffer, are fed by a task which
+/// reads the ring with [`read_console_ring`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConsoleSinks(usize);
+
+impl ConsoleSinks {
+    pub const NONE: Self = Self(0);
+    /// The console device chosen at boot, a UART or the SBI console
+    pub const DEVICE: Self = Self(1 << 0);
+    /// An in-memory ring buffer holding the most recent output
+    pub const RING: Self = Self(1 << 1);
+    pub const ALL: Self = Self(Self::DEVICE.0 | Self::RING.0);
+
+    pub const fn new(flags: usize) -> Self {
+        Self(flags)
+    }
+
+    pub const fn value(self) -> usize {
+        self.0
+    }
+}
+
+impl core::ops::BitOr for ConsoleSinks {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl core::ops::BitAnd for ConsoleSinks {
+    type Output = bool;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        self.0 & rhs.0 == rhs.0
+    }
+}
+
+/// Change where the kernel sends console output, returning the previous sinks.
+/// This takes the [`CONSOLE_CAPABILITY`] with `WRITE`.
+#[inline]
+pub fn set_console_sinks(console: CapabilityPtr, sinks: ConsoleSinks) -> Result<ConsoleSinks, SyscallError> {
+    let error: usize;
+    let previous: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::SetConsoleSinks as usize => error,
+            inlateout("a1") console.value() => previous,
+            in("a2") sinks.0,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(ConsoleSinks(previous)),
+    }
+}
+
+/// Copy the kernel's console output out of the ring, starting at `position`
+/// bytes into the output since boot, returning the number of bytes copied and
+/// the position to continue from. Output which has already been overwritten by
+/// the time it's read is skipped over. This takes the [`CONSOLE_CAPABILITY`]
+/// with `READ`.
+#[inline]
+pub fn read_console_ring(
+    console: CapabilityPtr,
+    buffer: &mut [u8],
+    position: u64,
+) -> Result<(usize, u64), SyscallError> {
+    let error: usize;
+    let read: usize;
+    let next: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::ReadConsoleRing as usize => error,
+            inlateout("a1") console.value() => read,
+            inlateout("a2") buffer.as_mut_ptr() => next,
+            in("a3") buffer.len(),
+            in("a4") position,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok((read, next as u64)),
+    }
+}
+
+/// Fill `buffer` with random bytes from the kernel's random number generator,
+/// which is always seeded and never blocks
+#[inline]
+pub fn get_random(buffer: &mut [u8]) -> Result<(), SyscallError> {
+    let error: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::GetRandom as usize => error,
+            in("a1") buffer.as_mut_ptr(),
+            in("a2") buffer.len(),
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(()),
+    }
+}
+
+/// The ID the kernel drew at random for this boot, which tells apart anything
+/// saved across boots, like crash dumps
+#[inline]
+pub fn boot_id() -> Uuid {
+    let high: u64;
+    let low: u64;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::ReadBootId as usize => _,
+            lateout("a1") high,
+            lateout("a2") low,
+        );
+    }
+
+    Uuid::from_u128(((high as u128) << 64) | low as u128)
+}
+
+/// Mix `data` into the kernel's entropy pool, e.g. the output of a hardware
+/// RNG. It isn't credited as entropy since the kernel can't know whether it's
+/// actually unpredictable, but can never make the pool any weaker.
+#[inline]
+pub fn add_entropy(data: &[u8]) -> Result<(), SyscallError> {
+    let error: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::AddEntropy as usize => error,
+            in("a1") data.as_ptr(),
+            in("a2") data.len(),
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(()),
+    }
+}
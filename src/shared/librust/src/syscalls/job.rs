@@ -0,0 +1,117 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Task groups and job control
+//!
+//! Every task is in a task group, starting out in the group of the task which
+//! spawned it. A shell puts each job in its own group with [`create_group`]
+//! and [`join_group`], and hands the group's capability to the console server
+//! so that Ctrl-C and Ctrl-Z apply to the whole foreground job with
+//! [`signal_group`].
+//!
+//! By default, tasks are killed by [`JobSignal::Terminate`], stop running on
+//! [`JobSignal::Suspend`] and run again on [`JobSignal::Continue`]. Tasks
+//! which [`handle_job_control`] are sent a
+//! [`KernelMessage::JobControl`](crate::syscalls::channel::KernelMessage::JobControl)
+//! instead, and are left to deal with it themselves.
+
+use crate::{
+    capabilities::CapabilityPtr,
+    error::{RawSyscallError, SyscallError},
+    syscalls::Syscall,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum JobSignal {
+    Terminate,
+    Suspend,
+    Continue,
+}
+
+impl JobSignal {
+    pub const fn to_usize(self) -> usize {
+        match self {
+            JobSignal::Terminate => 0,
+            JobSignal::Suspend => 1,
+            JobSignal::Continue => 2,
+        }
+    }
+
+    pub const fn from_usize(n: usize) -> Option<Self> {
+        match n {
+            0 => Some(JobSignal::Terminate),
+            1 => Some(JobSignal::Suspend),
+            2 => Some(JobSignal::Continue),
+            _ => None,
+        }
+    }
+}
+
+/// Create a new, empty task group, returning a capability to it. The
+/// capability needs `READ` to join the group and `WRITE` to signal it.
+#[inline]
+pub fn create_group() -> Result<CapabilityPtr, SyscallError> {
+    let error: usize;
+    let cptr: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::CreateTaskGroup as usize => error,
+            lateout("a1") cptr,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(CapabilityPtr::new(cptr)),
+    }
+}
+
+/// Move the current task into `group`. Tasks it spawns from then on start out
+/// in `group` too.
+#[inline]
+pub fn join_group(group: CapabilityPtr) -> Result<(), SyscallError> {
+    group_syscall(Syscall::JoinTaskGroup, group, 0)
+}
+
+/// Send `signal` to every task in `group`
+#[inline]
+pub fn signal_group(group: CapabilityPtr, signal: JobSignal) -> Result<(), SyscallError> {
+    group_syscall(Syscall::SignalTaskGroup, group, signal.to_usize())
+}
+
+/// Be sent job control signals on the kernel channel instead of having the
+/// kernel act on them
+#[inline]
+pub fn handle_job_control() {
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::HandleJobControl as usize => _,
+        );
+    }
+}
+
+#[inline(always)]
+fn group_syscall(syscall: Syscall, group: CapabilityPtr, arg: usize) -> Result<(), SyscallError> {
+    let error: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") syscall as usize => error,
+            in("a1") group.value(),
+            in("a2") arg,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(()),
+    }
+}
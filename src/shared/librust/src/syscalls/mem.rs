@@ -35,6 +35,206 @@ pub fn query_memory_capability(cptr: CapabilityPtr) -> Result<(*mut u8, usize, M
     }
 }
 
+/// What the memory in a [`MemoryArea`] is backed by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryBacking {
+    /// Nothing is mapped there
+    Unmapped,
+    /// Memory private to the task, which might not have been faulted in yet
+    Anonymous,
+    /// A shared memory object
+    Shared,
+    Dma,
+    Mmio,
+    /// A guard page, which faults on any access
+    Guard,
+}
+
+impl MemoryBacking {
+    pub const fn to_usize(self) -> usize {
+        match self {
+            MemoryBacking::Unmapped => 0,
+            MemoryBacking::Anonymous => 1,
+            MemoryBacking::Shared => 2,
+            MemoryBacking::Dma => 3,
+            MemoryBacking::Mmio => 4,
+            MemoryBacking::Guard => 5,
+        }
+    }
+
+    pub const fn from_usize(n: usize) -> Option<Self> {
+        match n {
+            0 => Some(MemoryBacking::Unmapped),
+            1 => Some(MemoryBacking::Anonymous),
+            2 => Some(MemoryBacking::Shared),
+            3 => Some(MemoryBacking::Dma),
+            4 => Some(MemoryBacking::Mmio),
+            5 => Some(MemoryBacking::Guard),
+            _ => None,
+        }
+    }
+}
+
+/// A range of the address space which was mapped all at once, and so has the
+/// same permissions and backing throughout
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryArea {
+    pub range: *mut [u8],
+    /// Meaningless for [`MemoryBacking::Unmapped`] and [`MemoryBacking::Guard`]
+    /// areas
+    pub permissions: MemoryPermissions,
+    pub backing: MemoryBacking,
+}
+
+/// The area of this task's address space which contains `at`, including the
+/// unmapped ones between what has been mapped
+pub fn query_memory(at: *const u8) -> Result<MemoryArea, SyscallError> {
+    let error: usize;
+    let start: *mut u8;
+    let len: usize;
+    let perms: usize;
+    let backing: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::QueryMemory as usize => error,
+            inlateout("a1") at => start,
+            lateout("a2") len,
+            lateout("a3") perms,
+            lateout("a4") backing,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(MemoryArea {
+            range: core::ptr::slice_from_raw_parts_mut(start, len),
+            permissions: MemoryPermissions::new(perms),
+            backing: MemoryBacking::from_usize(backing).unwrap_or(MemoryBacking::Unmapped),
+        }),
+    }
+}
+
+/// The current size of the shared memory object behind a memory capability,
+/// which can be bigger than this task's mapping of it if it has been grown
+pub fn shared_memory_size(cptr: CapabilityPtr) -> Result<usize, SyscallError> {
+    let error: usize;
+    let size: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::SharedMemorySize as usize => error,
+            inlateout("a1") cptr.value() => size,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(size),
+    }
+}
+
+/// Grow the shared memory object behind a writable memory capability to at
+/// least `new_size` bytes, returning where the whole object is now mapped in
+/// this task. Other tasks holding the object have to map it again with
+/// [`map_shared_memory`] to see the memory which was added.
+pub fn grow_shared_memory(cptr: CapabilityPtr, new_size: usize) -> Result<*mut [u8], SyscallError> {
+    let error: usize;
+    let virt: *mut u8;
+    let len: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::GrowSharedMemory as usize => error,
+            inlateout("a1") cptr.value() => virt,
+            inlateout("a2") new_size => len,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(core::ptr::slice_from_raw_parts_mut(virt, len)),
+    }
+}
+
+/// Resize the private allocation starting at `at` to at least `new_size`
+/// bytes, returning where it is now. It's resized in place if nothing is
+/// mapped after it, and moved otherwise, keeping what was in it either way.
+/// Memory added to it is zeroed.
+///
+/// # Safety
+///
+/// Nothing may access memory which is cut off by shrinking the allocation, or
+/// the old allocation if it moves
+pub unsafe fn resize_memory(at: *mut u8, new_size: usize) -> Result<*mut [u8], SyscallError> {
+    let error: usize;
+    let virt: *mut u8;
+    let len: usize;
+
+    core::arch::asm!(
+        "ecall",
+        inlateout("a0") Syscall::ResizeMemory as usize => error,
+        inlateout("a1") at => virt,
+        inlateout("a2") new_size => len,
+    );
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(core::ptr::slice_from_raw_parts_mut(virt, len)),
+    }
+}
+
+/// Map the whole of the shared memory object behind a memory capability into
+/// this task again, returning where it was mapped. The capability refers to
+/// the new mapping afterwards, and the old one stays mapped.
+pub fn map_shared_memory(cptr: CapabilityPtr) -> Result<*mut [u8], SyscallError> {
+    let error: usize;
+    let virt: *mut u8;
+    let len: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::MapSharedMemory as usize => error,
+            inlateout("a1") cptr.value() => virt,
+            lateout("a2") len,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(core::ptr::slice_from_raw_parts_mut(virt, len)),
+    }
+}
+
+/// Map a private copy of the shared memory object behind a readable memory
+/// capability, returning where it was mapped. The copy is always writable, and
+/// shares the object's memory until each page of it is first written to. Writes
+/// to the object have to have been sealed with [`SealFlags::WRITE`] first. The
+/// capability keeps referring to the mapping it had before.
+pub fn map_shared_memory_copy(cptr: CapabilityPtr) -> Result<*mut [u8], SyscallError> {
+    let error: usize;
+    let virt: *mut u8;
+    let len: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::MapSharedMemoryCopy as usize => error,
+            inlateout("a1") cptr.value() => virt,
+            lateout("a2") len,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(core::ptr::slice_from_raw_parts_mut(virt, len)),
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(transparent)]
 pub struct MemoryPermissions(usize);
@@ -84,6 +284,10 @@ impl AllocationOptions {
     pub const LARGE_PAGE: Self = Self(1 << 0);
     pub const ZERO: Self = Self(1 << 1);
     pub const ZERO_ON_DROP: Self = Self(1 << 2);
+    /// Don't allocate any memory until each page is first written to, when
+    /// it's zeroed. Reading a page before then reads zeroes without using any
+    /// memory. Only allowed along with [`Self::PRIVATE`], since shared memory
+    /// has to exist for other tasks to map it.
     pub const LAZY: Self = Self(1 << 3);
     pub const JOB_GROUP_AVAILABLE: Self = Self(1 << 4);
     pub const PRIVATE: Self = Self(1 << 5);
@@ -140,11 +344,110 @@ pub fn alloc_virtual_memory(
     }
 }
 
+/// Restrictions placed on a memory capability by [`seal`], which can never be
+/// lifted again. Capabilities sent to other tasks keep the seals of the
+/// capability they were sent from.
+#[derive(Debug, Clone, Copy)]
+#[repr(transparent)]
+pub struct SealFlags(usize);
+
+impl SealFlags {
+    pub const NONE: Self = Self(0);
+    /// The shared memory object can't be grown through the capability
+    pub const GROW: Self = Self(1 << 0);
+    /// Mappings made through the capability can't gain any permissions they
+    /// don't already have, e.g. a read-only mapping can never be made writable
+    /// again
+    pub const UPGRADE: Self = Self(1 << 1);
+    /// The capability can't be sent to other tasks
+    pub const GRANT: Self = Self(1 << 2);
+    /// Nothing can write to the shared memory object again, and every mapping
+    /// of it becomes read-only, including ones which already exist. Unlike
+    /// the other seals this applies to the object rather than the capability,
+    /// and needs a writable capability.
+    pub const WRITE: Self = Self(1 << 3);
+
+    pub fn new(flags: usize) -> Self {
+        Self(flags)
+    }
+
+    pub fn value(self) -> usize {
+        self.0
+    }
+}
+
+impl core::ops::BitOr for SealFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl core::ops::BitAnd for SealFlags {
+    type Output = bool;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        self.0 & rhs.0 == rhs.0
+    }
+}
+
+/// Permanently seal a memory capability against the operations in `flags`, on
+/// top of any seals it already has
+pub fn seal(cptr: CapabilityPtr, flags: SealFlags) -> Result<(), SyscallError> {
+    let error: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::SealMemory as usize => error,
+            in("a1") cptr.value(),
+            in("a2") flags.0,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(()),
+    }
+}
+
+/// Change the permissions of the memory in `range`, which has to cover whole
+/// allocations. `cptr` is the capability pointer `alloc_virtual_memory`
+/// returned for them, which is invalid for private memory. Pages which are
+/// still shared copy-on-write or haven't been touched yet stay read-only until
+/// they're faulted in, even when made writable.
+pub fn modify_memory_permissions(
+    cptr: CapabilityPtr,
+    range: *mut [u8],
+    permissions: MemoryPermissions,
+) -> Result<(), SyscallError> {
+    let error: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::ModifyMemoryPermissions as usize => error,
+            in("a1") cptr.value(),
+            in("a2") range.cast::<u8>() as usize,
+            in("a3") range.len(),
+            in("a4") permissions.value(),
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(()),
+    }
+}
+
 pub struct DmaAllocationOptions(usize);
 
 impl DmaAllocationOptions {
     pub const NONE: Self = Self(0);
     pub const ZERO: Self = Self(1 << 1);
+    /// Allocate memory below 4 GiB, for devices which can only address 32 bits
+    pub const DMA32: Self = Self(1 << 2);
 
     pub fn new(flags: usize) -> Self {
         Self(flags)
@@ -171,6 +474,10 @@ impl core::ops::BitAnd for DmaAllocationOptions {
     }
 }
 
+/// Allocate physically contiguous memory for devices to access, returning the
+/// address devices should use for it and where it's mapped in this address
+/// space. The device address is only the physical address of the memory when
+/// the system has no IOMMU.
 pub fn alloc_dma_memory(
     size_in_bytes: usize,
     options: DmaAllocationOptions,
@@ -193,3 +500,275 @@ pub fn alloc_dma_memory(
         None => Ok((PhysicalAddress::new(phys), virt)),
     }
 }
+
+/// Free DMA memory allocated with [`alloc_dma_memory`], given where it's mapped
+/// in this address space, unmapping it from this task's devices first
+///
+/// # Safety
+///
+/// Neither the CPU nor any device may access the memory after it's freed
+pub unsafe fn dealloc_dma_memory(virt: *mut u8) -> Result<(), SyscallError> {
+    let error: usize;
+
+    core::arch::asm!(
+        "ecall",
+        inlateout("a0") Syscall::DeallocDmaMemory as usize => error,
+        in("a1") virt,
+    );
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(()),
+    }
+}
+
+/// Which way data moves through DMA memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DmaDirection {
+    /// The CPU writes the memory and the device reads it
+    ToDevice,
+    /// The device writes the memory and the CPU reads it
+    FromDevice,
+    Bidirectional,
+}
+
+impl DmaDirection {
+    pub const fn to_usize(self) -> usize {
+        match self {
+            DmaDirection::ToDevice => 0,
+            DmaDirection::FromDevice => 1,
+            DmaDirection::Bidirectional => 2,
+        }
+    }
+
+    pub const fn from_usize(n: usize) -> Option<Self> {
+        match n {
+            0 => Some(DmaDirection::ToDevice),
+            1 => Some(DmaDirection::FromDevice),
+            2 => Some(DmaDirection::Bidirectional),
+            _ => None,
+        }
+    }
+}
+
+/// Hand `len` bytes of DMA memory, or memory pinned with [`pin_dma_memory`],
+/// at `virt` over to a device, making sure it sees everything written to them
+/// so far. Only needed on platforms where devices don't snoop the CPU caches,
+/// and a no-op everywhere else.
+#[inline]
+pub fn sync_dma_for_device(virt: *const u8, len: usize, direction: DmaDirection) -> Result<(), SyscallError> {
+    sync_dma_memory(virt, len, direction, 0)
+}
+
+/// Take `len` bytes of DMA memory, or memory pinned with [`pin_dma_memory`],
+/// at `virt` back from a device, making sure everything it wrote to them is
+/// visible. Only needed on platforms where devices don't snoop the CPU caches,
+/// and a no-op everywhere else.
+#[inline]
+pub fn sync_dma_for_cpu(virt: *const u8, len: usize, direction: DmaDirection) -> Result<(), SyscallError> {
+    sync_dma_memory(virt, len, direction, 1)
+}
+
+fn sync_dma_memory(virt: *const u8, len: usize, direction: DmaDirection, for_cpu: usize) -> Result<(), SyscallError> {
+    let error: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::SyncDmaMemory as usize => error,
+            in("a1") virt,
+            in("a2") len,
+            in("a3") direction.to_usize(),
+            in("a4") for_cpu,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(()),
+    }
+}
+
+/// Memory pinned for DMA with [`pin_dma_memory`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct DmaPinId(usize);
+
+impl DmaPinId {
+    pub const fn new(id: usize) -> Self {
+        Self(id)
+    }
+
+    pub const fn value(self) -> usize {
+        self.0
+    }
+}
+
+/// A physically contiguous piece of memory pinned with [`pin_dma_memory`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct DmaSegment {
+    /// The address devices should use for the segment, which is only its
+    /// physical address when the system has no IOMMU
+    pub device_addr: usize,
+    pub len: usize,
+}
+
+impl DmaSegment {
+    /// The address devices should use for the segment, see
+    /// [`DmaSegment::device_addr`]
+    pub fn physical_address(&self) -> PhysicalAddress {
+        PhysicalAddress::new(self.device_addr)
+    }
+}
+
+/// Pin the `len` bytes at `virt`, which can be any memory but device memory,
+/// so they stay resident and where they are in physical memory until they're
+/// unpinned with [`unpin_dma_memory`], and make them accessible to this task's
+/// devices. The pieces of physically contiguous memory they're made of are
+/// written to `segments` in order, and the number of them is returned along
+/// with the pin. Fails with [`SyscallError::InvalidArgument`] for `segments`
+/// if it's too short, which can't happen with a segment for every page the
+/// memory touches. Memory the device writes to has to be writable.
+pub fn pin_dma_memory(
+    virt: *const u8,
+    len: usize,
+    direction: DmaDirection,
+    segments: &mut [DmaSegment],
+) -> Result<(DmaPinId, usize), SyscallError> {
+    let error: usize;
+    let id: usize;
+    let n_segments: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::PinDmaMemory as usize => error,
+            inlateout("a1") virt => id,
+            inlateout("a2") len => n_segments,
+            in("a3") direction.to_usize(),
+            in("a4") segments.as_mut_ptr(),
+            in("a5") segments.len(),
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok((DmaPinId::new(id), n_segments)),
+    }
+}
+
+/// Unpin memory pinned with [`pin_dma_memory`], removing it from this task's
+/// devices first
+///
+/// # Safety
+///
+/// No device may access the memory after it's unpinned
+pub unsafe fn unpin_dma_memory(id: DmaPinId) -> Result<(), SyscallError> {
+    let error: usize;
+
+    core::arch::asm!(
+        "ecall",
+        inlateout("a0") Syscall::UnpinDmaMemory as usize => error,
+        in("a1") id.value(),
+    );
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(()),
+    }
+}
+
+/// The capability init is started with which allows inflating and deflating
+/// the memory balloon, which should only be handed to the balloon driver
+pub const BALLOON_CAPABILITY: CapabilityPtr = CapabilityPtr::new(7);
+
+/// Take free pages from the kernel to lend to the host through a memory
+/// balloon device, writing the page frame number of each page taken to `pfns`
+/// and returning the number of pages taken. Fewer pages than requested are
+/// taken when the system is low on memory. This takes the
+/// [`BALLOON_CAPABILITY`] with `WRITE`.
+pub fn inflate_balloon(balloon: CapabilityPtr, pfns: &mut [u32]) -> Result<usize, SyscallError> {
+    let error: usize;
+    let taken: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::InflateBalloon as usize => error,
+            inlateout("a1") balloon.value() => taken,
+            in("a2") pfns.as_mut_ptr(),
+            in("a3") pfns.len(),
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(taken),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(transparent)]
+pub struct DeflateOptions(usize);
+
+impl DeflateOptions {
+    pub const NONE: Self = Self(0);
+    /// Only return the pages the kernel has taken back from the balloon on its
+    /// own since the last deflate
+    pub const RECLAIMED_ONLY: Self = Self(1 << 0);
+
+    pub fn new(flags: usize) -> Self {
+        Self(flags)
+    }
+
+    pub fn value(self) -> usize {
+        self.0
+    }
+}
+
+impl core::ops::BitOr for DeflateOptions {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl core::ops::BitAnd for DeflateOptions {
+    type Output = bool;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        self.0 & rhs.0 == rhs.0
+    }
+}
+
+/// Return pages lent to the host back to the kernel, writing the page frame
+/// number of each page returned to `pfns` and returning the number of pages
+/// returned. The kernel takes pages back from the balloon on its own when it
+/// runs out of memory, and those pages are always returned first so the host
+/// can be told about them. This takes the [`BALLOON_CAPABILITY`] with `WRITE`.
+pub fn deflate_balloon(
+    balloon: CapabilityPtr,
+    pfns: &mut [u32],
+    options: DeflateOptions,
+) -> Result<usize, SyscallError> {
+    let error: usize;
+    let returned: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::DeflateBalloon as usize => error,
+            inlateout("a1") balloon.value() => returned,
+            in("a2") pfns.as_mut_ptr(),
+            in("a3") pfns.len(),
+            in("a4") options.0,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(returned),
+    }
+}
@@ -0,0 +1,151 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Pipes
+//!
+//! A pipe is a bounded stream of bytes with a read end and a write end, each of
+//! which is a capability that can be sent to other tasks like any other.
+//! Reading from an empty pipe waits until something is written to it, or
+//! returns 0 bytes once every write end has been closed. Writing to a full pipe
+//! waits until there's room, and fails with [`SyscallError::InvalidOperation`]
+//! once every read end has been closed. Tasks close their ends with
+//! [`close_pipe`], or by exiting.
+
+use crate::{
+    capabilities::CapabilityPtr,
+    error::{RawSyscallError, SyscallError},
+    syscalls::Syscall,
+};
+
+/// How many bytes a pipe holds if it's created with a capacity of 0
+pub const DEFAULT_PIPE_CAPACITY: usize = 4096;
+/// The most bytes a pipe can hold
+pub const MAX_PIPE_CAPACITY: usize = 64 * 1024;
+
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(transparent)]
+pub struct PipeFlags(usize);
+
+impl PipeFlags {
+    pub const NONE: Self = Self(0);
+    /// Fail with [`SyscallError::WouldBlock`] instead of waiting
+    pub const NONBLOCKING: Self = Self(1);
+
+    pub const fn new(flags: usize) -> Self {
+        Self(flags)
+    }
+
+    pub const fn value(self) -> usize {
+        self.0
+    }
+}
+
+impl core::ops::BitOr for PipeFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl core::ops::BitAnd for PipeFlags {
+    type Output = bool;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        self.0 & rhs.0 == rhs.0
+    }
+}
+
+/// Create a pipe which holds up to `capacity` bytes, returning its read end
+/// and write end
+#[inline]
+pub fn create_pipe(capacity: usize) -> Result<(CapabilityPtr, CapabilityPtr), SyscallError> {
+    let error: usize;
+    let read_end: usize;
+    let write_end: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::CreatePipe as usize => error,
+            inlateout("a1") capacity => read_end,
+            lateout("a2") write_end,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok((CapabilityPtr::new(read_end), CapabilityPtr::new(write_end))),
+    }
+}
+
+/// Read up to `buffer.len()` bytes from the read end `pipe`, returning how many
+/// were read. Returns 0 once the pipe is empty and every write end is closed.
+#[inline]
+pub fn read_pipe(pipe: CapabilityPtr, buffer: &mut [u8], flags: PipeFlags) -> Result<usize, SyscallError> {
+    let error: usize;
+    let read: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::ReadPipe as usize => error,
+            inlateout("a1") pipe.value() => read,
+            in("a2") buffer.as_mut_ptr(),
+            in("a3") buffer.len(),
+            in("a4") flags.value(),
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(read),
+    }
+}
+
+/// Write as much of `data` to the write end `pipe` as there's room for,
+/// waiting for at least some room, returning how many bytes were written
+#[inline]
+pub fn write_pipe(pipe: CapabilityPtr, data: &[u8], flags: PipeFlags) -> Result<usize, SyscallError> {
+    let error: usize;
+    let written: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::WritePipe as usize => error,
+            inlateout("a1") pipe.value() => written,
+            in("a2") data.as_ptr(),
+            in("a3") data.len(),
+            in("a4") flags.value(),
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(written),
+    }
+}
+
+/// Close one end of a pipe, removing the capability to it
+#[inline]
+pub fn close_pipe(pipe: CapabilityPtr) -> Result<(), SyscallError> {
+    let error: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::ClosePipe as usize => error,
+            in("a1") pipe.value(),
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,178 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Shutting down, rebooting and suspending the system, and taking harts offline
+//!
+//! Tasks which [`subscribe_shutdown`] are sent a
+//! [`KernelMessage::ShutdownRequested`](crate::syscalls::channel::KernelMessage::ShutdownRequested)
+//! when a shutdown or reboot is requested, and have a few seconds to flush
+//! whatever they need to and [`acknowledge_shutdown`] before the system is
+//! reset without them.
+//!
+//! [`system_suspend`] doesn't notify anyone, the kernel saves the state of the
+//! devices it drives itself and the system picks up where it left off once it
+//! wakes up.
+//!
+//! [`hart_offline`] and [`hart_online`] stop and restart single harts while the
+//! rest of the system keeps running. Tasks on a hart going offline carry on on
+//! the others.
+
+use crate::{
+    capabilities::CapabilityPtr,
+    error::{RawSyscallError, SyscallError},
+    syscalls::Syscall,
+};
+use core::time::Duration;
+
+/// The capability init is started with which allows shutting down, rebooting,
+/// suspending the system and taking harts offline. Init can hand it out to other tasks like any other
+/// capability.
+pub const POWER_CAPABILITY: CapabilityPtr = CapabilityPtr::new(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ResetKind {
+    Shutdown,
+    Reboot,
+}
+
+impl ResetKind {
+    pub const fn to_usize(self) -> usize {
+        match self {
+            ResetKind::Shutdown => 0,
+            ResetKind::Reboot => 1,
+        }
+    }
+
+    pub const fn from_usize(n: usize) -> Option<Self> {
+        match n {
+            0 => Some(ResetKind::Shutdown),
+            1 => Some(ResetKind::Reboot),
+            _ => None,
+        }
+    }
+}
+
+/// Shut down the system once every subscriber has acknowledged it or the
+/// timeout for doing so runs out. This returns immediately, and the caller is
+/// notified like any other task if it subscribed.
+#[inline]
+pub fn system_shutdown(power: CapabilityPtr) -> Result<(), SyscallError> {
+    reset(Syscall::SystemShutdown, power)
+}
+
+/// Reboot the system once every subscriber has acknowledged it or the timeout
+/// for doing so runs out. This returns immediately, and the caller is notified
+/// like any other task if it subscribed.
+#[inline]
+pub fn system_reboot(power: CapabilityPtr) -> Result<(), SyscallError> {
+    reset(Syscall::SystemReboot, power)
+}
+
+#[inline(always)]
+fn reset(syscall: Syscall, power: CapabilityPtr) -> Result<(), SyscallError> {
+    let error: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") syscall as usize => error,
+            in("a1") power.value(),
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(()),
+    }
+}
+
+/// Suspend the system to RAM, returning once it's woken back up by an
+/// interrupt, or after `wake_after` if it's given. Fails with
+/// [`SyscallError::WouldBlock`] if the system couldn't be suspended right now,
+/// like when a device refuses to suspend.
+#[inline]
+pub fn system_suspend(power: CapabilityPtr, wake_after: Option<Duration>) -> Result<(), SyscallError> {
+    let error: usize;
+    // Zero means there's no wakeup timer, so round anything shorter up
+    let wake_after =
+        wake_after.map_or(0, |wake_after| u64::try_from(wake_after.as_micros()).unwrap_or(u64::MAX).max(1));
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::SystemSuspend as usize => error,
+            in("a1") power.value(),
+            in("a2") wake_after,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(()),
+    }
+}
+
+/// Take `hart_id` offline, moving whatever is running on it to the other harts
+/// and stopping it. This returns as soon as the hart has been told to stop, which
+/// can be the calling task's own hart. Fails with
+/// [`SyscallError::InvalidOperation`] if the hart is already offline or is the
+/// last one online.
+#[inline]
+pub fn hart_offline(power: CapabilityPtr, hart_id: usize) -> Result<(), SyscallError> {
+    hotplug(Syscall::HartOffline, power, hart_id)
+}
+
+/// Bring `hart_id` back online after [`hart_offline`]. Fails with
+/// [`SyscallError::WouldBlock`] if the hart is still on its way offline or the
+/// firmware couldn't start it.
+#[inline]
+pub fn hart_online(power: CapabilityPtr, hart_id: usize) -> Result<(), SyscallError> {
+    hotplug(Syscall::HartOnline, power, hart_id)
+}
+
+#[inline(always)]
+fn hotplug(syscall: Syscall, power: CapabilityPtr, hart_id: usize) -> Result<(), SyscallError> {
+    let error: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") syscall as usize => error,
+            in("a1") power.value(),
+            in("a2") hart_id,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(()),
+    }
+}
+
+/// Be notified on the kernel channel before the system is shut down or
+/// rebooted
+#[inline]
+pub fn subscribe_shutdown() {
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::SubscribeShutdown as usize => _,
+        );
+    }
+}
+
+/// Tell the kernel everything that needed to be done before shutting down is
+/// done. The task should expect to stop running at any point afterwards.
+#[inline]
+pub fn acknowledge_shutdown() {
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::AcknowledgeShutdown as usize => _,
+        );
+    }
+}
@@ -0,0 +1,75 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Finding out when the system is running low on memory
+//!
+//! Tasks holding the [`MEMORY_PRESSURE_CAPABILITY`] can [`subscribe`] to be sent
+//! a [`KernelMessage::MemoryPressure`](crate::syscalls::channel::KernelMessage::MemoryPressure)
+//! whenever free memory crosses into another [`MemoryPressure`] level, so they
+//! can drop caches and anything else they can rebuild before allocations start
+//! failing with [`SyscallError::OutOfMemory`].
+
+use crate::{
+    capabilities::CapabilityPtr,
+    error::{RawSyscallError, SyscallError},
+    syscalls::Syscall,
+};
+
+/// The capability init is started with which allows subscribing to memory
+/// pressure notifications. Init can hand it out to other tasks like any other
+/// capability.
+pub const MEMORY_PRESSURE_CAPABILITY: CapabilityPtr = CapabilityPtr::new(5);
+
+/// How low free memory is running
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MemoryPressure {
+    Normal,
+    /// Free memory is getting low, and anything which is cheap to give back
+    /// should be
+    Low,
+    /// Allocations are about to fail, or already have
+    Critical,
+}
+
+impl MemoryPressure {
+    pub const fn to_usize(self) -> usize {
+        match self {
+            MemoryPressure::Normal => 0,
+            MemoryPressure::Low => 1,
+            MemoryPressure::Critical => 2,
+        }
+    }
+
+    pub const fn from_usize(n: usize) -> Option<Self> {
+        match n {
+            0 => Some(MemoryPressure::Normal),
+            1 => Some(MemoryPressure::Low),
+            2 => Some(MemoryPressure::Critical),
+            _ => None,
+        }
+    }
+}
+
+/// Be notified whenever the memory pressure level changes, returning what it
+/// is now
+pub fn subscribe(pressure: CapabilityPtr) -> Result<MemoryPressure, SyscallError> {
+    let error: usize;
+    let level: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::SubscribeMemoryPressure as usize => error,
+            inlateout("a1") pressure.value() => level,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(MemoryPressure::from_usize(level).unwrap_or(MemoryPressure::Normal)),
+    }
+}
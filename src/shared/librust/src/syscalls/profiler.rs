@@ -0,0 +1,166 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Sampling profiler
+//!
+//! While the profiler is running, every hart is interrupted at the requested
+//! frequency and records what it was doing: which task was running, if any,
+//! whether it was in the kernel or in the task, and the program counter along
+//! with the return addresses of the frames above it, found by following frame
+//! pointers. Samples are kept in a buffer per hart until they're drained, and
+//! once a hart's buffer is full its oldest samples are dropped and counted.
+//!
+//! Kernel addresses can be turned into function names with
+//! [`resolve_kernel_symbol`], userspace ones are left to whoever has the
+//! task's binary.
+//!
+//! Samples and kernel symbols give away what every task is doing, so all of
+//! these take the [`DEBUG_CAPABILITY`](crate::syscalls::debug::DEBUG_CAPABILITY)
+//! with `READ`.
+
+use crate::{
+    capabilities::CapabilityPtr,
+    error::{RawSyscallError, SyscallError},
+    syscalls::Syscall,
+};
+
+/// The most return addresses recorded in a sample, on top of the program
+/// counter
+pub const PROFILER_MAX_DEPTH: usize = 15;
+/// The highest sampling frequency, in Hz
+pub const PROFILER_MAX_FREQUENCY: usize = 10_000;
+/// The number of samples each hart can hold before it starts dropping them
+pub const PROFILER_BUFFER_SAMPLES: usize = 4096;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ProfileSample {
+    /// The task running on the hart, or zero if it was idle
+    pub tid: usize,
+    pub hart: usize,
+    /// Whether the hart was running kernel code rather than the task's
+    pub kernel: bool,
+    /// The number of entries of `frames` which are filled in
+    pub depth: usize,
+    /// The program counter, followed by the return address of each frame
+    /// going up the stack
+    pub frames: [usize; PROFILER_MAX_DEPTH + 1],
+}
+
+impl ProfileSample {
+    pub const fn new() -> Self {
+        Self { tid: 0, hart: 0, kernel: false, depth: 0, frames: [0; PROFILER_MAX_DEPTH + 1] }
+    }
+
+    /// The filled in frames, innermost first
+    pub fn frames(&self) -> &[usize] {
+        &self.frames[..self.depth.min(self.frames.len())]
+    }
+}
+
+impl Default for ProfileSample {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Start sampling every hart `frequency` times a second, recording up to
+/// `depth` return addresses in each sample. Starting the profiler again
+/// changes the frequency and depth and throws away any samples which haven't
+/// been drained.
+pub fn start(debug: CapabilityPtr, frequency: usize, depth: usize) -> Result<(), SyscallError> {
+    let error: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::StartProfiler as usize => error,
+            in("a1") debug.value(),
+            in("a2") frequency,
+            in("a3") depth,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(()),
+    }
+}
+
+/// Stop sampling. Samples which were already taken are kept until they're
+/// drained.
+pub fn stop(debug: CapabilityPtr) -> Result<(), SyscallError> {
+    let error: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::StopProfiler as usize => error,
+            in("a1") debug.value(),
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(()),
+    }
+}
+
+/// Move samples out of the kernel and into `samples`, each hart's oldest
+/// first. Returns the number of samples written, and the number which were
+/// dropped since the last drain for want of space.
+pub fn drain(debug: CapabilityPtr, samples: &mut [ProfileSample]) -> Result<(usize, usize), SyscallError> {
+    let error: usize;
+    let drained: usize;
+    let dropped: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::DrainProfileSamples as usize => error,
+            inlateout("a1") debug.value() => drained,
+            inlateout("a2") samples.as_mut_ptr() => dropped,
+            in("a3") samples.len(),
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok((drained, dropped)),
+    }
+}
+
+/// Find the kernel function `address` is in, writing as much of its name as
+/// fits to `name`. Returns the length of the name written and how far into the
+/// function `address` is, or `None` if it isn't in a function the kernel knows
+/// about.
+pub fn resolve_kernel_symbol(
+    debug: CapabilityPtr,
+    address: usize,
+    name: &mut [u8],
+) -> Result<Option<(usize, usize)>, SyscallError> {
+    let error: usize;
+    let len: usize;
+    let offset: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::ResolveKernelSymbol as usize => error,
+            inlateout("a1") debug.value() => len,
+            inlateout("a2") address => offset,
+            in("a3") name.as_mut_ptr(),
+            in("a4") name.len(),
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None if len == usize::MAX => Ok(None),
+        None => Ok(Some((len, offset))),
+    }
+}
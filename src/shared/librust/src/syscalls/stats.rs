@@ -0,0 +1,136 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Kernel event counters
+//!
+//! The kernel counts scheduling, IPC, interrupt, page fault and syscall events
+//! on each hart since boot. The counters only ever go up, so rates are found by
+//! taking two snapshots and subtracting one from the other.
+
+use crate::{
+    error::{RawSyscallError, SyscallError},
+    syscalls::Syscall,
+    task::Tid,
+};
+
+/// Number of syscall numbers which are counted individually
+pub const STATS_SYSCALLS: usize = 64;
+/// Number of external interrupt IDs which are counted individually. Interrupts
+/// with a higher ID are counted in the last entry.
+pub const STATS_INTERRUPT_SOURCES: usize = 128;
+
+/// Pass instead of a hart ID to get the totals for every hart
+pub const ALL_HARTS: usize = usize::MAX;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct KernelStats {
+    /// Number of times a task was switched to
+    pub context_switches: u64,
+    /// Number of channel messages sent
+    pub ipc_messages: u64,
+    pub timer_interrupts: u64,
+    /// External interrupts, indexed by interrupt ID
+    pub external_interrupts: [u64; STATS_INTERRUPT_SOURCES],
+    pub load_page_faults: u64,
+    pub store_page_faults: u64,
+    pub instruction_page_faults: u64,
+    /// Syscalls, indexed by syscall number
+    pub syscalls: [u64; STATS_SYSCALLS],
+}
+
+impl KernelStats {
+    pub const fn new() -> Self {
+        Self {
+            context_switches: 0,
+            ipc_messages: 0,
+            timer_interrupts: 0,
+            external_interrupts: [0; STATS_INTERRUPT_SOURCES],
+            load_page_faults: 0,
+            store_page_faults: 0,
+            instruction_page_faults: 0,
+            syscalls: [0; STATS_SYSCALLS],
+        }
+    }
+
+    pub fn page_faults(&self) -> u64 {
+        self.load_page_faults + self.store_page_faults + self.instruction_page_faults
+    }
+
+    pub fn interrupts(&self) -> u64 {
+        self.timer_interrupts + self.external_interrupts.iter().sum::<u64>()
+    }
+
+    pub fn syscall_count(&self, syscall: Syscall) -> u64 {
+        self.syscalls.get(syscall as usize).copied().unwrap_or(0)
+    }
+}
+
+impl Default for KernelStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Read the counters of hart `hart`, or the totals of every hart if it's
+/// [`ALL_HARTS`], along with the number of harts in the system
+#[inline]
+pub fn kernel_stats(hart: usize) -> Result<(KernelStats, usize), SyscallError> {
+    let mut stats = KernelStats::new();
+    let error: usize;
+    let n_harts: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::KernelStats as usize => error,
+            inlateout("a1") hart => n_harts,
+            in("a2") &mut stats as *mut KernelStats,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok((stats, n_harts)),
+    }
+}
+
+/// How much memory there is and what it's being used for, in bytes
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct MemoryStats {
+    /// All of the physical memory the kernel hands out
+    pub total: u64,
+    pub free: u64,
+    /// Physical memory set aside for the kernel heap
+    pub heap_total: u64,
+    pub heap_allocated: u64,
+    /// Memory mapped into the address space of the task asked about
+    pub resident: u64,
+}
+
+/// Read how much memory is in use, with the resident size of `tid`, or of
+/// this task if it's `None`
+#[inline]
+pub fn memory_stats(tid: Option<Tid>) -> Result<MemoryStats, SyscallError> {
+    let mut stats = MemoryStats::default();
+    let error: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::MemoryStats as usize => error,
+            in("a1") tid.map_or(0, Tid::value),
+            in("a2") &mut stats as *mut MemoryStats,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(stats),
+    }
+}
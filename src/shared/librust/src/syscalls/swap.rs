@@ -0,0 +1,120 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Swapping memory out to a block device
+//!
+//! The kernel picks which pages are swapped out and when, but storing them is
+//! left to a swap server which owns the block device. The task which holds the
+//! [`SWAP_CAPABILITY`] and [`enable`]s swap becomes the only one allowed to
+//! manage it, and is sent a
+//! [`KernelMessage::SwapPending`](crate::syscalls::channel::KernelMessage::SwapPending)
+//! whenever there are pages for it to write out or read back in.
+//!
+//! The swap space is made up of page sized slots, each of which holds the page
+//! last handed out for it by [`take_writes`]. The server has to be done with
+//! everything it was handed by one call to [`take_writes`] or [`take_reads`]
+//! before making the next one, since a page can be asked for again as soon as
+//! it's been handed out to be written.
+
+use crate::{
+    capabilities::CapabilityPtr,
+    error::{RawSyscallError, SyscallError},
+    syscalls::Syscall,
+};
+
+/// The capability init is started with which allows enabling swap, which
+/// should only be handed to the swap server
+pub const SWAP_CAPABILITY: CapabilityPtr = CapabilityPtr::new(8);
+
+/// The size of each page moved in and out of the swap space
+pub const PAGE_SIZE: usize = 4096;
+
+/// Start swapping pages out to a swap space of `n_slots` pages. Swap can only
+/// be enabled once, and this takes the [`SWAP_CAPABILITY`] with `WRITE`.
+pub fn enable(cptr: CapabilityPtr, n_slots: usize) -> Result<(), SyscallError> {
+    let error: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::EnableSwap as usize => error,
+            in("a1") cptr.value(),
+            in("a2") n_slots,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(()),
+    }
+}
+
+/// Take pages which have been swapped out and need writing to the swap space,
+/// writing the slot each one belongs in to `slots` and its contents to `pages`.
+/// Returns the number of pages taken.
+pub fn take_writes(slots: &mut [u32], pages: &mut [[u8; PAGE_SIZE]]) -> Result<usize, SyscallError> {
+    let error: usize;
+    let taken: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::TakeSwapWrites as usize => error,
+            inlateout("a1") slots.as_mut_ptr() => taken,
+            in("a2") slots.len(),
+            in("a3") pages.as_mut_ptr(),
+            in("a4") pages.len(),
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(taken),
+    }
+}
+
+/// Take the slots of pages which need to be read back in from the swap space,
+/// writing them to `slots` and returning the number taken. Each one is handed
+/// back with [`complete_read`].
+pub fn take_reads(slots: &mut [u32]) -> Result<usize, SyscallError> {
+    let error: usize;
+    let taken: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::TakeSwapReads as usize => error,
+            inlateout("a1") slots.as_mut_ptr() => taken,
+            in("a2") slots.len(),
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(taken),
+    }
+}
+
+/// Hand back the contents of `slot` after reading it in from the swap space,
+/// which wakes up the task waiting on it
+pub fn complete_read(slot: u32, page: &[u8; PAGE_SIZE]) -> Result<(), SyscallError> {
+    let error: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::CompleteSwapRead as usize => error,
+            in("a1") slot,
+            in("a2") page.as_ptr(),
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(()),
+    }
+}
@@ -5,8 +5,12 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
-use crate::{error::RawSyscallError, syscalls::Syscall, task::Tid};
-use core::num::NonZeroUsize;
+use crate::{
+    error::{RawSyscallError, SyscallError},
+    syscalls::Syscall,
+    task::{FaultInfo, Tid},
+};
+use core::{num::NonZeroUsize, time::Duration};
 
 #[inline(always)]
 pub fn exit() -> ! {
@@ -44,3 +48,71 @@ pub fn enable_notifications() {
         );
     }
 }
+
+/// How many timers a task can have waiting to fire at once, beyond which
+/// [`set_timer`] fails with [`SyscallError::QuotaExceeded`]
+pub const MAX_PENDING_TIMERS: usize = 256;
+
+/// Arm a one-shot timer which delivers a
+/// [`KernelMessage::TimerExpired(id)`](crate::syscalls::channel::KernelMessage::TimerExpired)
+/// on the kernel channel once `duration` has elapsed. The timer may fire
+/// slightly late, but never early. There's no way to cancel a timer, so it
+/// counts towards [`MAX_PENDING_TIMERS`] until it fires.
+#[inline]
+pub fn set_timer(id: usize, duration: Duration) -> Result<(), SyscallError> {
+    let error: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::SetTimer as usize => error,
+            in("a1") id,
+            in("a2") u64::try_from(duration.as_micros()).unwrap_or(u64::MAX),
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(()),
+    }
+}
+
+/// Register a handler for faults which would otherwise kill the task. The
+/// handler is entered with a pointer to a [`FaultInfo`] in `a0`, running on the
+/// stack ending at `stack_top`, which the [`FaultInfo`] is written to the top
+/// of. The handler is removed once it's entered, so a fault inside of the
+/// handler kills the task.
+#[inline]
+pub fn set_fault_handler(
+    handler: extern "C" fn(*const FaultInfo) -> !,
+    stack_top: *mut u8,
+) -> Result<(), SyscallError> {
+    let error: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::SetFaultHandler as usize => error,
+            in("a1") handler as usize,
+            in("a2") stack_top,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(()),
+    }
+}
+
+/// Remove the task's fault handler, if it has one
+#[inline]
+pub fn clear_fault_handler() {
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::SetFaultHandler as usize => _,
+            in("a1") 0,
+            in("a2") 0,
+        );
+    }
+}
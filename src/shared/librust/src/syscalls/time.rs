@@ -0,0 +1,87 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The system wall clock
+//!
+//! The kernel starts the wall clock from the RTC at boot if there is one, or
+//! from the Unix epoch otherwise, and relies on a time service to keep it
+//! correct afterwards. Small corrections should be slewed in with
+//! [`TimeAdjustment::Slew`] so the clock never jumps or runs backwards under
+//! whatever is reading it, which the kernel does by running it up to 500 parts
+//! per million fast or slow until the offset is made up.
+//!
+//! Anyone can read the wall clock, but setting it takes the
+//! [`CLOCK_CAPABILITY`], which should only be handed to the time service.
+
+use crate::{
+    capabilities::CapabilityPtr,
+    error::{RawSyscallError, SyscallError},
+    syscalls::Syscall,
+};
+use core::time::Duration;
+
+/// The capability init is started with which allows setting the wall clock.
+/// Init can hand it out to other tasks like any other capability.
+pub const CLOCK_CAPABILITY: CapabilityPtr = CapabilityPtr::new(4);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct WallClock {
+    pub since_epoch: Duration,
+    /// Whether anything has set the clock since boot, otherwise it's only as
+    /// good as the RTC, if there is one
+    pub synchronized: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TimeAdjustment {
+    /// Set the clock to the given time since the Unix epoch immediately
+    Step(Duration),
+    /// Gradually move the clock forward by the given number of nanoseconds,
+    /// or back if negative, replacing whatever was still being slewed in
+    Slew(i64),
+}
+
+#[inline]
+pub fn wall_clock() -> WallClock {
+    let nanos: u64;
+    let synchronized: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::ReadWallClock as usize => _,
+            lateout("a1") nanos,
+            lateout("a2") synchronized,
+        );
+    }
+
+    WallClock { since_epoch: Duration::from_nanos(nanos), synchronized: synchronized != 0 }
+}
+
+#[inline]
+pub fn set_time(clock: CapabilityPtr, adjustment: TimeAdjustment) -> Result<(), SyscallError> {
+    let error: usize;
+    let (kind, value) = match adjustment {
+        TimeAdjustment::Step(since_epoch) => (0, u64::try_from(since_epoch.as_nanos()).unwrap_or(u64::MAX) as usize),
+        TimeAdjustment::Slew(offset) => (1, offset as usize),
+    };
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::SetTime as usize => error,
+            in("a1") clock.value(),
+            in("a2") kind,
+            in("a3") value,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,134 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Publish-subscribe topics
+//!
+//! Every message [`publish`]ed to a topic is copied to each of its
+//! subscribers, along with any capabilities sent with it. Subscribing with
+//! [`subscribe`] returns a read-only channel the copies arrive on, which is
+//! read like any other channel. Each subscription has its own queue, and its
+//! [`LagPolicy`] decides what happens once a subscriber falls so far behind
+//! that the queue is full.
+
+use crate::{
+    capabilities::{Capability, CapabilityPtr},
+    error::{RawSyscallError, SyscallError},
+    syscalls::{
+        channel::{ChannelMessage, ChannelSendFlags},
+        Syscall,
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LagPolicy {
+    /// Skip messages published while the subscriber's queue is full
+    DropNewest,
+    /// Throw away the oldest unread message to make room for the new one
+    DropOldest,
+    /// Stop sending the subscriber messages at all
+    Disconnect,
+}
+
+impl LagPolicy {
+    pub const fn to_usize(self) -> usize {
+        match self {
+            LagPolicy::DropNewest => 0,
+            LagPolicy::DropOldest => 1,
+            LagPolicy::Disconnect => 2,
+        }
+    }
+
+    pub const fn from_usize(n: usize) -> Option<Self> {
+        match n {
+            0 => Some(LagPolicy::DropNewest),
+            1 => Some(LagPolicy::DropOldest),
+            2 => Some(LagPolicy::Disconnect),
+            _ => None,
+        }
+    }
+}
+
+/// Create a new topic with no subscribers, returning a capability to it. The
+/// capability needs `READ` to subscribe to the topic and `WRITE` to publish to
+/// it.
+#[inline]
+pub fn create_topic() -> Result<CapabilityPtr, SyscallError> {
+    let error: usize;
+    let cptr: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::CreateTopic as usize => error,
+            lateout("a1") cptr,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(CapabilityPtr::new(cptr)),
+    }
+}
+
+/// Subscribe to `topic`, returning the channel messages published from now on
+/// will arrive on
+#[inline]
+pub fn subscribe(topic: CapabilityPtr, policy: LagPolicy) -> Result<CapabilityPtr, SyscallError> {
+    let error: usize;
+    let cptr: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::SubscribeTopic as usize => error,
+            inlateout("a1") topic.value() => cptr,
+            in("a2") policy.to_usize(),
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(CapabilityPtr::new(cptr)),
+    }
+}
+
+/// Send a copy of `message` and `caps` to every subscriber of `topic`,
+/// returning how many of them it was delivered to. Each copy counts towards
+/// the publisher's IPC quota, and this only fails with
+/// [`SyscallError::QuotaExceeded`] if none of them could be sent.
+pub fn publish(
+    topic: CapabilityPtr,
+    message: ChannelMessage,
+    caps: &[Capability],
+    flags: ChannelSendFlags,
+) -> Result<usize, SyscallError> {
+    let error: usize;
+    let delivered: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::PublishTopic as usize => error,
+            inlateout("a1") topic.value() => delivered,
+            in("a2") caps.as_ptr(),
+            in("a3") caps.len(),
+            in("a4") flags.value(),
+            in("t0") message.0[0],
+            in("t1") message.0[1],
+            in("t2") message.0[2],
+            in("t3") message.0[3],
+            in("t4") message.0[4],
+            in("t5") message.0[5],
+            in("t6") message.0[6],
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(delivered),
+    }
+}
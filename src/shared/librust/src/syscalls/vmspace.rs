@@ -5,9 +5,9 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
-use super::{mem::MemoryPermissions, Syscall};
+use super::{channel::ChannelMessage, mem::MemoryPermissions, Syscall};
 use crate::{
-    capabilities::CapabilityPtr,
+    capabilities::{Capability, CapabilityPtr},
     error::{RawSyscallError, SyscallError},
 };
 
@@ -75,6 +75,40 @@ pub fn alloc_vmspace_object(
     }
 }
 
+/// Map the memory at `ours` into the vmspace `id` without copying it, sharing
+/// the pages between the current task and the vmspace, and returning where it
+/// was mapped. `ours` has to be page aligned and part of a single allocation.
+///
+/// Writable mappings are copy-on-write: each page is copied the first time
+/// either side writes to it, so neither sees the other's writes. Only memory
+/// which isn't already shared can be mapped this way, and it can't be
+/// executable.
+pub fn share_vmspace_object(
+    id: VmspaceObjectId,
+    ours: *const u8,
+    mapping: VmspaceObjectMapping,
+) -> Result<*mut u8, SyscallError> {
+    let error: usize;
+    let theirs: *mut u8;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::ShareVmspaceObject as usize => error,
+            inlateout("a1") id.value() => theirs,
+            in("a2") ours,
+            in("a3") mapping.size,
+            in("a4") mapping.address,
+            in("a5") mapping.permissions.value(),
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(theirs),
+    }
+}
+
 pub struct VmspaceSpawnEnv {
     pub pc: usize,
     pub a0: usize,
@@ -84,7 +118,17 @@ pub struct VmspaceSpawnEnv {
     pub tp: usize,
 }
 
-pub fn spawn_vmspace(id: VmspaceObjectId, name: &str, env: VmspaceSpawnEnv) -> Result<CapabilityPtr, SyscallError> {
+/// Start a new task running in the vmspace `id` under the name `name`,
+/// returning the capability to the channel to it. `message` is queued on the
+/// new task's parent channel along with `caps` before it starts running, and
+/// either all of the capabilities are sent or the task isn't spawned.
+pub fn spawn_vmspace(
+    id: VmspaceObjectId,
+    name: &str,
+    message: ChannelMessage,
+    caps: &[Capability],
+    env: VmspaceSpawnEnv,
+) -> Result<CapabilityPtr, SyscallError> {
     let error: usize;
     let cptr: usize;
 
@@ -95,6 +139,9 @@ pub fn spawn_vmspace(id: VmspaceObjectId, name: &str, env: VmspaceSpawnEnv) -> R
             inlateout("a1") id.value() => cptr,
             in("a2") name.as_ptr(),
             in("a3") name.len(),
+            in("a4") caps.as_ptr(),
+            in("a5") caps.len(),
+            in("a6") &message as *const ChannelMessage,
             in("t0") env.pc,
             in("t1") env.a0,
             in("t2") env.a1,
@@ -109,3 +156,42 @@ pub fn spawn_vmspace(id: VmspaceObjectId, name: &str, env: VmspaceSpawnEnv) -> R
         None => Ok(CapabilityPtr::new(cptr)),
     }
 }
+
+/// Replace the current task's address space with the vmspace `id`, starting it
+/// over at `env.pc` under the name `name`. The task keeps its TID, kernel
+/// channel and parent channel, and `message` is queued at the front of its
+/// parent channel along with `caps`, which are the only other capabilities it
+/// keeps. Only returns if the task couldn't be replaced.
+pub fn exec_vmspace(
+    id: VmspaceObjectId,
+    name: &str,
+    message: ChannelMessage,
+    caps: &[Capability],
+    env: VmspaceSpawnEnv,
+) -> SyscallError {
+    let error: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::ExecVmspace as usize => error,
+            in("a1") id.value(),
+            in("a2") name.as_ptr(),
+            in("a3") name.len(),
+            in("a4") caps.as_ptr(),
+            in("a5") caps.len(),
+            in("a6") &message as *const ChannelMessage,
+            in("t0") env.pc,
+            in("t1") env.a0,
+            in("t2") env.a1,
+            in("t3") env.a2,
+            in("t4") env.sp,
+            in("t5") env.tp,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => error.cook(),
+        None => unreachable!("exec returned without an error"),
+    }
+}
@@ -0,0 +1,64 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Heartbeats for critical services
+//!
+//! A task which [`arm`]s the watchdog has to [`pet`] it at least once every
+//! `timeout`, otherwise the kernel assumes the task has hung and reboots the
+//! system. Exiting without [`disarm`]ing it counts as hanging, so arming the
+//! watchdog requires the same [power
+//! capability](crate::syscalls::power::POWER_CAPABILITY) as rebooting does.
+
+use crate::{
+    capabilities::CapabilityPtr,
+    error::{RawSyscallError, SyscallError},
+    syscalls::Syscall,
+};
+use core::time::Duration;
+
+/// Start watching the current task, which then has to [`pet`] the watchdog at
+/// least once every `timeout`. Arming it again replaces the previous timeout.
+#[inline]
+pub fn arm(power: CapabilityPtr, timeout: Duration) -> Result<(), SyscallError> {
+    let error: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::ArmWatchdog as usize => error,
+            in("a1") power.value(),
+            in("a2") u64::try_from(timeout.as_micros()).unwrap_or(u64::MAX),
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(()),
+    }
+}
+
+/// Let the kernel know the current task is still alive, restarting its timeout
+#[inline]
+pub fn pet() {
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::PetWatchdog as usize => _,
+        );
+    }
+}
+
+/// Stop watching the current task, e.g. before it exits on purpose
+#[inline]
+pub fn disarm() {
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") Syscall::DisarmWatchdog as usize => _,
+        );
+    }
+}
@@ -24,4 +24,57 @@ impl core::fmt::Display for Tid {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{}", self.0)
     }
-}
\ No newline at end of file
+}
+/// Number of bytes from the top of the faulting stack included in a
+/// [`FaultInfo`]
+pub const FAULT_STACK_SNAPSHOT: usize = 1024;
+/// Number of bytes of the task name included in a [`FaultInfo`]
+pub const FAULT_NAME_LEN: usize = 32;
+/// Maximum number of return addresses in the backtrace of a [`FaultInfo`]
+pub const FAULT_BACKTRACE_LEN: usize = 32;
+
+/// Everything the kernel knows about a fault which would have killed the task,
+/// which is written to the top of the task's fault handler stack before the
+/// handler is entered
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct FaultInfo {
+    /// The `scause` value of the fault
+    pub cause: usize,
+    pub pc: usize,
+    /// The `stval` value of the fault, which is the faulting address for
+    /// memory faults
+    pub address: usize,
+    /// `x1` through `x31` at the time of the fault
+    pub registers: [usize; 31],
+    pub name_len: usize,
+    pub name: [u8; FAULT_NAME_LEN],
+    /// Number of bytes starting at the faulting `sp` which were readable and
+    /// copied into `stack`
+    pub stack_len: usize,
+    pub stack: [u8; FAULT_STACK_SNAPSHOT],
+    /// Number of return addresses found by walking the frame pointers from
+    /// the faulting `s0`, which were copied into `backtrace`
+    pub backtrace_len: usize,
+    /// Return addresses of the callers of the faulting function, innermost
+    /// first
+    pub backtrace: [usize; FAULT_BACKTRACE_LEN],
+}
+
+impl FaultInfo {
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len.min(FAULT_NAME_LEN)]).unwrap_or("<invalid>")
+    }
+
+    pub fn stack(&self) -> &[u8] {
+        &self.stack[..self.stack_len.min(FAULT_STACK_SNAPSHOT)]
+    }
+
+    pub fn backtrace(&self) -> &[usize] {
+        &self.backtrace[..self.backtrace_len.min(FAULT_BACKTRACE_LEN)]
+    }
+
+    pub fn stack_pointer(&self) -> usize {
+        self.registers[1]
+    }
+}
@@ -0,0 +1,363 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! `memcpy`, `memmove`, `memset`, `memcmp` and `bcmp` for the kernel and
+//! userspace
+//!
+//! These replace the weak versions `compiler_builtins` provides, and work a
+//! word at a time wherever possible, eight words per iteration in the main
+//! loops. Misaligned accesses trap into the SBI to be emulated on most RV64
+//! hardware, which is far slower than doing them a byte at a time, so the
+//! destination is always brought up to alignment first, and a source with a
+//! different alignment is read in aligned words and shifted into place rather
+//! than being read misaligned.
+//!
+//! Anything linking this needs an `extern crate memops;` for the symbols to be
+//! linked in, since nothing refers to the crate directly. On anything other
+//! than bare metal they aren't exported, so they can be tested on the host.
+
+#![no_std]
+#![no_builtins]
+
+const WORD: usize = core::mem::size_of::<usize>();
+/// Copies shorter than this aren't worth aligning for
+const SMALL: usize = 2 * WORD;
+const UNROLL: usize = 8;
+
+/// # Safety
+///
+/// `src` must be valid for `n` bytes of reads, `dest` valid for `n` bytes of
+/// writes, and the two must not overlap
+#[cfg_attr(target_os = "none", no_mangle)]
+pub unsafe extern "C" fn memcpy(dest: *mut u8, src: *const u8, n: usize) -> *mut u8 {
+    copy_forward(dest, src, n);
+    dest
+}
+
+/// # Safety
+///
+/// `src` must be valid for `n` bytes of reads and `dest` valid for `n` bytes of
+/// writes
+#[cfg_attr(target_os = "none", no_mangle)]
+pub unsafe extern "C" fn memmove(dest: *mut u8, src: *const u8, n: usize) -> *mut u8 {
+    // Copying forwards only goes wrong when the start of `dest` is inside of
+    // `src`
+    match (dest as usize).wrapping_sub(src as usize) >= n {
+        true => copy_forward(dest, src, n),
+        false => copy_backward(dest, src, n),
+    }
+
+    dest
+}
+
+/// # Safety
+///
+/// `dest` must be valid for `n` bytes of writes
+#[cfg_attr(target_os = "none", no_mangle)]
+pub unsafe extern "C" fn memset(dest: *mut u8, c: i32, n: usize) -> *mut u8 {
+    let byte = c as u8;
+    let mut d = dest;
+    let mut n = n;
+
+    if n >= SMALL {
+        let head = d.align_offset(WORD);
+        set_bytes(d, byte, head);
+        d = d.add(head);
+        n -= head;
+
+        let word = usize::from_ne_bytes([byte; WORD]);
+        let mut dw = d as *mut usize;
+        let words = n / WORD;
+
+        for _ in 0..words / UNROLL {
+            for i in 0..UNROLL {
+                *dw.add(i) = word;
+            }
+
+            dw = dw.add(UNROLL);
+        }
+
+        for _ in 0..words % UNROLL {
+            *dw = word;
+            dw = dw.add(1);
+        }
+
+        d = dw as *mut u8;
+        n %= WORD;
+    }
+
+    set_bytes(d, byte, n);
+    dest
+}
+
+/// # Safety
+///
+/// `a` and `b` must both be valid for `n` bytes of reads
+#[cfg_attr(target_os = "none", no_mangle)]
+pub unsafe extern "C" fn memcmp(a: *const u8, b: *const u8, n: usize) -> i32 {
+    let mut a = a;
+    let mut b = b;
+    let mut n = n;
+
+    // Comparing a word at a time only works if both sides can be aligned at
+    // once, a differing word is then narrowed down to the differing byte below
+    if n >= SMALL && a.align_offset(WORD) == b.align_offset(WORD) {
+        let head = a.align_offset(WORD);
+        if let Some(ordering) = compare_bytes(a, b, head) {
+            return ordering;
+        }
+
+        a = a.add(head);
+        b = b.add(head);
+        n -= head;
+
+        while n >= WORD && *(a as *const usize) == *(b as *const usize) {
+            a = a.add(WORD);
+            b = b.add(WORD);
+            n -= WORD;
+        }
+    }
+
+    compare_bytes(a, b, n).unwrap_or(0)
+}
+
+/// # Safety
+///
+/// `a` and `b` must both be valid for `n` bytes of reads
+#[cfg_attr(target_os = "none", no_mangle)]
+pub unsafe extern "C" fn bcmp(a: *const u8, b: *const u8, n: usize) -> i32 {
+    memcmp(a, b, n)
+}
+
+unsafe fn copy_forward(dest: *mut u8, src: *const u8, n: usize) {
+    let mut d = dest;
+    let mut s = src;
+    let mut n = n;
+
+    if n >= SMALL {
+        let head = d.align_offset(WORD);
+        copy_bytes_forward(d, s, head);
+        d = d.add(head);
+        s = s.add(head);
+        n -= head;
+
+        let words = n / WORD;
+        match s as usize % WORD {
+            0 => copy_aligned_words_forward(d as *mut usize, s as *const usize, words),
+            _ => copy_misaligned_words_forward(d as *mut usize, s, words),
+        }
+
+        d = d.add(words * WORD);
+        s = s.add(words * WORD);
+        n %= WORD;
+    }
+
+    copy_bytes_forward(d, s, n);
+}
+
+unsafe fn copy_backward(dest: *mut u8, src: *const u8, n: usize) {
+    let mut d = dest.add(n);
+    let mut s = src.add(n);
+    let mut n = n;
+
+    // Going backwards is only needed for overlapping moves, which are rare
+    // enough that a differently aligned source is just copied byte by byte
+    if n >= SMALL && d.align_offset(WORD) == s.align_offset(WORD) {
+        let tail = d as usize % WORD;
+        d = d.sub(tail);
+        s = s.sub(tail);
+        copy_bytes_backward(d, s, tail);
+        n -= tail;
+
+        let mut dw = d as *mut usize;
+        let mut sw = s as *const usize;
+        for _ in 0..n / WORD {
+            dw = dw.sub(1);
+            sw = sw.sub(1);
+            *dw = *sw;
+        }
+
+        d = dw as *mut u8;
+        s = sw as *const u8;
+        n %= WORD;
+    }
+
+    copy_bytes_backward(d.sub(n), s.sub(n), n);
+}
+
+unsafe fn copy_aligned_words_forward(mut dest: *mut usize, mut src: *const usize, words: usize) {
+    for _ in 0..words / UNROLL {
+        let mut chunk = [0; UNROLL];
+        for (i, word) in chunk.iter_mut().enumerate() {
+            *word = *src.add(i);
+        }
+
+        for (i, word) in chunk.into_iter().enumerate() {
+            *dest.add(i) = word;
+        }
+
+        dest = dest.add(UNROLL);
+        src = src.add(UNROLL);
+    }
+
+    for _ in 0..words % UNROLL {
+        *dest = *src;
+        dest = dest.add(1);
+        src = src.add(1);
+    }
+}
+
+/// Copy `words` words from a `src` that isn't word aligned by reading the
+/// aligned words it spans and shifting them into place. Only the bytes of the
+/// first and last words which are part of `src` are read.
+unsafe fn copy_misaligned_words_forward(dest: *mut usize, src: *const u8, words: usize) {
+    if words == 0 {
+        return;
+    }
+
+    let offset = src as usize % WORD;
+    let shift = offset * 8;
+    let src_aligned = src.sub(offset) as *const usize;
+
+    // The part of the current destination word which comes from the aligned
+    // source word before it
+    let mut low = load_partial(src, WORD - offset);
+
+    for i in 0..words {
+        let next = match i + 1 < words {
+            true => *src_aligned.add(i + 1),
+            false => load_partial(src_aligned.add(i + 1) as *const u8, offset),
+        };
+
+        *dest.add(i) = low | next << (usize::BITS as usize - shift);
+        low = next >> shift;
+    }
+}
+
+/// Read `len` bytes from `src` into the low bytes of a word
+unsafe fn load_partial(src: *const u8, len: usize) -> usize {
+    let mut bytes = [0; WORD];
+    for (i, byte) in bytes.iter_mut().enumerate().take(len) {
+        *byte = *src.add(i);
+    }
+
+    usize::from_le_bytes(bytes)
+}
+
+unsafe fn copy_bytes_forward(dest: *mut u8, src: *const u8, n: usize) {
+    for i in 0..n {
+        *dest.add(i) = *src.add(i);
+    }
+}
+
+unsafe fn copy_bytes_backward(dest: *mut u8, src: *const u8, n: usize) {
+    for i in (0..n).rev() {
+        *dest.add(i) = *src.add(i);
+    }
+}
+
+unsafe fn set_bytes(dest: *mut u8, byte: u8, n: usize) {
+    for i in 0..n {
+        *dest.add(i) = byte;
+    }
+}
+
+unsafe fn compare_bytes(a: *const u8, b: *const u8, n: usize) -> Option<i32> {
+    for i in 0..n {
+        let (a, b) = (*a.add(i), *b.add(i));
+        if a != b {
+            return Some(i32::from(a) - i32::from(b));
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEN: usize = 200;
+
+    fn pattern() -> [u8; LEN] {
+        let mut bytes = [0; LEN];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = (i * 7 + 3) as u8;
+        }
+
+        bytes
+    }
+
+    #[test]
+    fn copies_at_every_alignment() {
+        let src = pattern();
+
+        for src_offset in 0..WORD {
+            for dest_offset in 0..WORD {
+                for n in 0..LEN - WORD {
+                    let mut dest = [0xFF; LEN];
+                    unsafe { memcpy(dest.as_mut_ptr().add(dest_offset), src.as_ptr().add(src_offset), n) };
+
+                    assert!(dest[..dest_offset].iter().all(|&b| b == 0xFF));
+                    assert_eq!(dest[dest_offset..][..n], src[src_offset..][..n]);
+                    assert!(dest[dest_offset + n..].iter().all(|&b| b == 0xFF));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn moves_overlapping() {
+        for from in 0..2 * WORD {
+            for to in 0..2 * WORD {
+                for n in 0..LEN - 2 * WORD {
+                    let mut bytes = pattern();
+                    let mut expected = pattern();
+                    expected.copy_within(from..from + n, to);
+
+                    unsafe { memmove(bytes.as_mut_ptr().add(to), bytes.as_ptr().add(from), n) };
+                    assert_eq!(bytes, expected, "from {} to {} ({} bytes)", from, to, n);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sets_at_every_alignment() {
+        for offset in 0..WORD {
+            for n in 0..LEN - WORD {
+                let mut bytes = [0; LEN];
+                unsafe { memset(bytes.as_mut_ptr().add(offset), 0x1A5, n) };
+
+                assert!(bytes[..offset].iter().all(|&b| b == 0));
+                assert!(bytes[offset..][..n].iter().all(|&b| b == 0xA5));
+                assert!(bytes[offset + n..].iter().all(|&b| b == 0));
+            }
+        }
+    }
+
+    #[test]
+    fn compares_like_slices() {
+        let a = pattern();
+
+        for offset in 0..WORD {
+            for n in 1..LEN - WORD {
+                for differs_at in [0, n / 2, n - 1] {
+                    for delta in [1u8, 255] {
+                        let mut b = a;
+                        b[offset + differs_at] = b[offset + differs_at].wrapping_add(delta);
+
+                        let ordering = unsafe { memcmp(a.as_ptr().add(offset), b.as_ptr().add(offset), n) };
+                        assert_eq!(ordering.signum(), a[offset..][..n].cmp(&b[offset..][..n]) as i32);
+                    }
+                }
+
+                assert_eq!(unsafe { memcmp(a.as_ptr().add(offset), a.as_ptr().add(offset), n) }, 0);
+            }
+        }
+    }
+}
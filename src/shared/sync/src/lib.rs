@@ -9,6 +9,7 @@
 
 pub mod lazy;
 pub mod mutex;
+pub mod once;
 pub mod rwlock;
 
 use core::{
@@ -17,8 +18,27 @@ use core::{
 };
 pub use lazy::Lazy;
 pub use mutex::SpinMutex;
+pub use once::OnceCell;
 pub use rwlock::SpinRwLock;
 
+static SPIN_HOOK: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Call `hook` on every turn of a loop spinning on a lock, for work which
+/// can't wait until the lock is free. It may itself spin on locks, so it has
+/// to cope with being called again from inside itself.
+pub fn set_spin_hook(hook: fn()) {
+    SPIN_HOOK.store(hook as *mut (), Ordering::Release);
+}
+
+fn spin() {
+    let hook = SPIN_HOOK.load(Ordering::Acquire);
+    if !hook.is_null() {
+        unsafe { core::mem::transmute::<*mut (), fn()>(hook)() };
+    }
+
+    core::hint::spin_loop();
+}
+
 #[repr(transparent)]
 pub struct AtomicConstPtr<T>(AtomicPtr<T>, PhantomData<T>);
 
@@ -63,6 +63,7 @@ impl<T: Send, D: DeadlockDetection> SpinMutex<T, D> {
             }
 
             spin_check_count -= 1;
+            crate::spin();
         }
 
         self.deadlock_metadata.store(D::gather_metadata(), Ordering::Release);
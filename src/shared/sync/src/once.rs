@@ -0,0 +1,99 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use core::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+const UNINIT: usize = 0;
+const INITIALIZING: usize = 1;
+const INIT: usize = 2;
+
+/// A value which is written once and only read after that, for statics which
+/// can't be built until the kernel or task is running. Anyone who tries to read
+/// it while it's being written waits until it's done.
+pub struct OnceCell<T> {
+    state: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+impl<T> OnceCell<T> {
+    pub const fn new() -> Self {
+        Self { state: AtomicUsize::new(UNINIT), value: UnsafeCell::new(MaybeUninit::uninit()) }
+    }
+
+    /// The value, if it's been written yet
+    pub fn get(&self) -> Option<&T> {
+        match self.state.load(Ordering::Acquire) {
+            INIT => Some(unsafe { self.get_unchecked() }),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        match *self.state.get_mut() {
+            INIT => Some(unsafe { self.value.get_mut().assume_init_mut() }),
+            _ => None,
+        }
+    }
+
+    /// Write the value, handing it back if the cell has already been written
+    /// to or is being written to
+    pub fn set(&self, value: T) -> Result<(), T> {
+        match self.state.compare_exchange(UNINIT, INITIALIZING, Ordering::Acquire, Ordering::Relaxed) {
+            Ok(_) => {
+                unsafe { self.value.get().write(MaybeUninit::new(value)) };
+                self.state.store(INIT, Ordering::Release);
+                Ok(())
+            }
+            Err(_) => Err(value),
+        }
+    }
+
+    /// The value, writing the result of `f` to the cell first if nothing has
+    /// been yet. If another hart is writing to it, this waits for it to finish.
+    pub fn get_or_init(&self, f: impl FnOnce() -> T) -> &T {
+        match self.state.compare_exchange(UNINIT, INITIALIZING, Ordering::Acquire, Ordering::Acquire) {
+            Ok(_) => {
+                unsafe { self.value.get().write(MaybeUninit::new(f())) };
+                self.state.store(INIT, Ordering::Release);
+            }
+            Err(INITIALIZING) => {
+                while self.state.load(Ordering::Acquire) != INIT {
+                    crate::spin();
+                }
+            }
+            Err(_) => {}
+        }
+
+        unsafe { self.get_unchecked() }
+    }
+
+    unsafe fn get_unchecked(&self) -> &T {
+        (*self.value.get()).assume_init_ref()
+    }
+}
+
+impl<T> Default for OnceCell<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for OnceCell<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == INIT {
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for OnceCell<T> {}
+// Whichever hart writes the value can be a different one than those reading it
+unsafe impl<T: Send + Sync> Sync for OnceCell<T> {}
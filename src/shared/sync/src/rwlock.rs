@@ -33,8 +33,7 @@ impl<T: Send> SpinRwLock<T> {
 
     fn lock_shared(&self) {
         while !self.try_lock_shared() {
-            // TODO: maybe add ability to specify instruction for stalling?
-            // crate::asm::pause();
+            crate::spin();
         }
     }
 
@@ -66,8 +65,7 @@ impl<T: Send> SpinRwLock<T> {
 
     fn lock_exclusive(&self) {
         while !self.try_lock_exclusive() {
-            // TODO: maybe add ability to specify instruction for stalling?
-            // crate::asm::pause();
+            crate::spin();
         }
     }
 
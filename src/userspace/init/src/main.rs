@@ -7,14 +7,26 @@
 
 use librust::{
     self,
-    capabilities::{CapabilityPtr, CapabilityRights},
+    capabilities::{Capability, CapabilityDescription, CapabilityPtr, CapabilityRights, CapabilityWithDescription},
     syscalls::mem::MemoryPermissions,
 };
+use std::collections::BTreeMap;
 
-static SERVERS: &[u8] = include_bytes!("../../../../build/initfs.tar");
+// Page aligned so servers' read-only segments can be shared with them rather
+// than copied, see `loadelf`
+static SERVERS: &[u8] = &PAGE_ALIGNED_SERVERS.0;
+static PAGE_ALIGNED_SERVERS: PageAligned<[u8; include_bytes!("../../../../build/initfs.tar").len()]> =
+    PageAligned(*include_bytes!("../../../../build/initfs.tar"));
+
+#[repr(align(4096))]
+struct PageAligned<T>(T);
 
 static INIT_ORDER: &str = r#"{
     "servers": [
+        {
+            "name": "config",
+            "caps": ["fdt"],
+        },
         {
             "name": "devicemgr",
             "caps": ["fdt"],
@@ -23,25 +35,91 @@ static INIT_ORDER: &str = r#"{
             "name": "stdio",
             "caps": ["devicemgr"],
         },
+        {
+            "name": "logger",
+            "caps": ["stdio"],
+        },
+        {
+            "name": "crashcollector",
+            "caps": ["stdio"],
+        },
         {
             "name": "virtiomgr",
-            "caps": ["devicemgr", "stdio"],
+            "caps": ["devicemgr", "stdio", "logger", "crashcollector"],
         },
         {
             "name": "filesystem",
-            "caps": ["virtiomgr", "stdio"],
+            "caps": ["virtiomgr", "stdio", "logger", "crashcollector"],
         },
         {
             "name": "network",
-            "caps": ["virtiomgr", "stdio"],
+            "caps": ["virtiomgr", "stdio", "logger", "crashcollector", "clock"],
+        },
+        {
+            "name": "console",
+            "caps": ["virtiomgr", "stdio", "logger", "crashcollector", "kconsole"],
+        },
+        {
+            "name": "vsock",
+            "caps": ["virtiomgr", "stdio", "logger", "crashcollector"],
+        },
+        {
+            "name": "balloon",
+            "caps": ["devicemgr", "virtiomgr", "stdio", "logger", "crashcollector", "kballoon"],
+        },
+        {
+            "name": "rng",
+            "caps": ["virtiomgr", "stdio", "logger", "crashcollector"],
+        },
+        {
+            "name": "swap",
+            "caps": ["virtiomgr", "stdio", "logger", "crashcollector", "kswap"],
+        },
+        {
+            "name": "thermal",
+            "caps": ["fdt", "devicemgr", "stdio", "logger", "crashcollector"],
         },
         {
             "name": "servicemgr",
-            "caps": ["devicemgr", "stdio", "network"],
+            "caps": ["devicemgr", "stdio", "logger", "network", "crashcollector"],
+        },
+        {
+            "name": "login",
+            "caps": ["fdt", "stdio", "logger", "vsock", "console", "crashcollector"],
         },
         {
             "name": "echonet",
-            "caps": ["stdio", "network"],
+            "caps": ["stdio", "logger", "network", "crashcollector"],
+        },
+        {
+            "name": "httpd",
+            "caps": ["stdio", "logger", "vsock", "vsock as vsock.1", "vsock as vsock.2", "vsock as vsock.3", "filesystem", "crashcollector"],
+        },
+        {
+            "name": "ipc-trace",
+            "caps": ["stdio", "debug"],
+        },
+    ],
+    "config": [
+        {
+            "key": "log.level",
+            "value": "info",
+        },
+        {
+            "key": "echonet.port",
+            "value": "1337",
+        },
+        {
+            "key": "login.port",
+            "value": "2323",
+        },
+        {
+            "key": "httpd.port",
+            "value": "8080",
+        },
+        {
+            "key": "sntp.interval",
+            "value": "1024",
         },
     ]
 }"#;
@@ -50,6 +128,7 @@ json::derive! {
     Deserialize,
     struct InitOrder {
         servers: Vec<Server>,
+        config: Vec<ConfigEntry>,
     }
 }
 
@@ -61,36 +140,94 @@ json::derive! {
     }
 }
 
+json::derive! {
+    Deserialize,
+    struct ConfigEntry {
+        key: String,
+        value: String,
+    }
+}
+
 fn main() {
     let fdt_ptr = std::env::a2() as *const u8;
-    let fdt = unsafe { fdt::Fdt::from_ptr(fdt_ptr).unwrap() };
-    let fdt_size = fdt.total_size();
+    let fdt_size = unsafe { fdt::Fdt::from_ptr(fdt_ptr).unwrap() }.total_size();
+    let fdt = unsafe { core::slice::from_raw_parts(fdt_ptr, fdt_size) };
     let tar = tar::Archive::new(SERVERS).unwrap();
 
-    let mut caps = std::collections::BTreeMap::<String, CapabilityPtr>::new();
+    let mut caps = BTreeMap::<String, CapabilityPtr>::new();
+    // The kernel hands init the only power, debug, clock, memory pressure,
+    // kernel console, balloon and swap capabilities, which servers can be
+    // granted by listing `power`, `debug`, `clock`, `memorypressure`,
+    // `kconsole`, `kballoon` or `kswap` in their caps
+    caps.insert(String::from("power"), librust::syscalls::power::POWER_CAPABILITY);
+    caps.insert(String::from("debug"), librust::syscalls::debug::DEBUG_CAPABILITY);
+    caps.insert(String::from("clock"), librust::syscalls::time::CLOCK_CAPABILITY);
+    caps.insert(String::from("memorypressure"), librust::syscalls::pressure::MEMORY_PRESSURE_CAPABILITY);
+    caps.insert(String::from("kconsole"), librust::syscalls::io::CONSOLE_CAPABILITY);
+    caps.insert(String::from("kballoon"), librust::syscalls::mem::BALLOON_CAPABILITY);
+    caps.insert(String::from("kswap"), librust::syscalls::swap::SWAP_CAPABILITY);
     let init_order: InitOrder = json::deserialize(INIT_ORDER.as_bytes()).unwrap();
 
     for server in init_order.servers {
-        let file = tar.file(&server.name).unwrap();
-        let (mut space, mut env) = loadelf::load_elf(&server.name, &loadelf::Elf::new(file.contents).unwrap()).unwrap();
-
-        for cap in server.caps {
-            if cap == "fdt" {
-                let mut fdt_obj = space.create_object(core::ptr::null(), fdt_size, MemoryPermissions::READ).unwrap();
-                fdt_obj.as_slice()[..fdt_size]
-                    .copy_from_slice(unsafe { core::slice::from_raw_parts(fdt_ptr, fdt_size) });
-                env.a2 = fdt_obj.vmspace_address() as usize;
-                continue;
+        let cap = spawn(&tar, &server, &caps, fdt);
+
+        if server.name == "config" {
+            std::env::register_capability(
+                "config",
+                CapabilityWithDescription {
+                    capability: Capability { cptr: cap, rights: CapabilityRights::READ | CapabilityRights::WRITE },
+                    description: CapabilityDescription::Channel,
+                },
+            );
+
+            // Options from the kernel command line take precedence over the
+            // defaults here
+            for entry in &init_order.config {
+                if std::env::config(&entry.key).unwrap().is_none() {
+                    std::env::set_config(&entry.key, Some(&entry.value)).unwrap();
+                }
             }
+        }
+
+        caps.insert(server.name, cap);
+    }
+
+    // Test suites named on the kernel command line, which is how `xtask test`
+    // runs them, are started once everything else is up
+    let tests = std::env::config("init.tests").unwrap().unwrap_or_default();
+    for name in tests.split(',').filter(|name| !name.is_empty()) {
+        let server = Server { name: String::from(name), caps: vec![String::from("stdio"), String::from("devicemgr")] };
+        spawn(&tar, &server, &caps, fdt);
+    }
+}
+
+fn spawn(tar: &tar::Archive<'_>, server: &Server, caps: &BTreeMap<String, CapabilityPtr>, fdt: &[u8]) -> CapabilityPtr {
+    let file = tar.file(&server.name).unwrap();
+    let (mut space, mut env) = loadelf::load_elf(&server.name, &loadelf::Elf::new(file.contents).unwrap()).unwrap();
 
-            let cptr = *caps.get(&cap).unwrap();
-            space.grant(&cap, cptr, CapabilityRights::READ | CapabilityRights::WRITE);
+    for cap in &server.caps {
+        // `"<cap> as <name>"` grants `cap` under another name, which gives the
+        // server another channel to it
+        let (cap, name) = cap.split_once(" as ").unwrap_or((cap.as_str(), cap.as_str()));
+
+        if cap == "fdt" {
+            let mut fdt_obj = space.create_object(core::ptr::null(), fdt.len(), MemoryPermissions::READ).unwrap();
+            fdt_obj.as_slice()[..fdt.len()].copy_from_slice(fdt);
+            env.a2 = fdt_obj.vmspace_address() as usize;
+            continue;
         }
 
-        env.a0 = 0;
-        env.a1 = 0;
+        let cptr = *caps.get(cap).unwrap();
+        space.grant(name, cptr, CapabilityRights::READ | CapabilityRights::WRITE);
+    }
 
-        let cap = space.spawn(env).unwrap();
-        caps.insert(server.name, cap);
+    // Every server after the config server gets to use it
+    if let Some(&cptr) = caps.get("config") {
+        space.grant("config", cptr, CapabilityRights::READ | CapabilityRights::WRITE);
     }
+
+    env.a0 = 0;
+    env.a1 = 0;
+
+    space.spawn(env).unwrap()
 }
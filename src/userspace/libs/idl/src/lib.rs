@@ -0,0 +1,128 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Interface definitions for IPC protocols
+//!
+//! [`interface!`] is defined in `std` so the protocols `std` speaks itself can
+//! use it, see [`std::ipc::interface`] for what it generates.
+//!
+//! ```ignore
+//! idl::interface! {
+//!     pub mod devicemgr {
+//!         fn request_devices(compatible: Vec<String>) -> Vec<Device> [caps];
+//!     }
+//! }
+//! ```
+
+pub use std::interface;
+
+#[cfg(test)]
+mod tests {
+    use librust::capabilities::{Capability, CapabilityPtr, CapabilityRights, CapabilityWithDescription};
+
+    crate::interface! {
+        /// Exercises every shape of method the macro accepts
+        pub mod example {
+            fn no_args() -> u32;
+            fn no_return(value: u64);
+            fn many_args(a: u8, b: String, c: Vec<u16>,) -> Option<String>;
+            fn with_caps(name: String) -> usize [caps];
+        }
+    }
+
+    #[derive(Default)]
+    struct Example {
+        callers: Vec<CapabilityPtr>,
+        stored: u64,
+    }
+
+    impl example::Server for Example {
+        fn caller(&mut self, cptr: CapabilityPtr) {
+            self.callers.push(cptr);
+        }
+
+        fn no_args(&mut self) -> u32 {
+            42
+        }
+
+        fn no_return(&mut self, value: u64) {
+            self.stored = value;
+        }
+
+        fn many_args(&mut self, a: u8, b: String, c: Vec<u16>) -> Option<String> {
+            Some(format!("{}:{}:{}", a, b, c.len()))
+        }
+
+        fn with_caps(&mut self, name: String, caps: Vec<CapabilityWithDescription>) -> (usize, Vec<Capability>) {
+            let reply = Capability::new(CapabilityPtr::new(7), CapabilityRights::READ);
+            (name.len() + caps.len(), vec![reply])
+        }
+    }
+
+    fn round_trip<T: wire::Serialize + wire::DeserializeOwned>(t: &T) -> T {
+        wire::from_bytes(&wire::to_bytes(t)).unwrap()
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        let request = round_trip(&example::Request::many_args { a: 3, b: String::from("abc"), c: vec![1, 2] });
+        match request {
+            example::Request::many_args { a, b, c } => {
+                assert_eq!(a, 3);
+                assert_eq!(b, "abc");
+                assert_eq!(c, [1, 2]);
+            }
+            _ => panic!("decoded as the wrong method"),
+        }
+
+        assert!(matches!(round_trip(&example::Request::no_args {}), example::Request::no_args {}));
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        assert!(matches!(round_trip(&example::Response::no_args(5)), example::Response::no_args(5)));
+        assert!(matches!(round_trip(&example::Response::no_return(())), example::Response::no_return(())));
+        assert!(matches!(round_trip(&example::Response::many_args(None)), example::Response::many_args(None)));
+
+        let error = round_trip(&example::Response::Error(wire::DecodeError::VersionMismatch { expected: 1, found: 2 }));
+        assert!(matches!(
+            error,
+            example::Response::Error(wire::DecodeError::VersionMismatch { expected: 1, found: 2 })
+        ));
+    }
+
+    #[test]
+    fn handle_calls_the_matching_method() {
+        let mut server = Example::default();
+
+        let (response, caps) = example::handle(&mut server, example::Request::no_args {}, Vec::new());
+        assert!(matches!(response, example::Response::no_args(42)));
+        assert!(caps.is_empty());
+
+        let (response, _) = example::handle(&mut server, example::Request::no_return { value: 9 }, Vec::new());
+        assert!(matches!(response, example::Response::no_return(())));
+        assert_eq!(server.stored, 9);
+
+        let request = example::Request::many_args { a: 1, b: String::from("x"), c: vec![0; 4] };
+        match example::handle(&mut server, request, Vec::new()).0 {
+            example::Response::many_args(Some(s)) => assert_eq!(s, "1:x:4"),
+            _ => panic!("wrong response"),
+        }
+    }
+
+    #[test]
+    fn handle_passes_capabilities_both_ways() {
+        let mut server = Example::default();
+        let sent = vec![CapabilityWithDescription::default(); 2];
+
+        let (response, caps) =
+            example::handle(&mut server, example::Request::with_caps { name: String::from("abcd") }, sent);
+        assert!(matches!(response, example::Response::with_caps(6)));
+        assert_eq!(caps.len(), 1);
+        assert_eq!(caps[0].cptr, CapabilityPtr::new(7));
+    }
+}
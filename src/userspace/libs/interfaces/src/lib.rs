@@ -0,0 +1,303 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+wire::derive! {
+    #[derive(Debug, Clone)]
+    pub struct Device {
+        pub name: String,
+        pub compatible: Vec<String>,
+        pub interrupts: Vec<usize>,
+    }
+}
+
+wire::derive! {
+    #[derive(Debug, Clone)]
+    pub enum HotplugEvent {
+        /// A device showed up after boot, e.g. a virtio device in a slot which
+        /// used to be empty, a card being inserted or a PCIe slot powering on
+        Added(Device),
+        /// A device went away, and whatever was driving it should stop using
+        /// it
+        Removed(Device),
+    }
+}
+
+wire::derive! {
+    /// The outcome of the network server's latest attempt to synchronize the
+    /// wall clock over SNTP, published on the topic it replies to
+    /// [`network::Request::time_status`] with
+    #[derive(Debug, Clone)]
+    pub enum TimeSyncStatus {
+        Synchronized {
+            /// The address of the NTP server
+            server: String,
+            /// How far the wall clock was from the server's time, in
+            /// nanoseconds, negative if it was ahead
+            offset_nanos: i64,
+            round_trip_nanos: u64,
+            /// How many servers away from a reference clock the server is,
+            /// where 1 is directly attached to one
+            stratum: u8,
+            /// Whether the offset was too large to slew in, so the clock was
+            /// set to the server's time immediately
+            stepped: bool,
+        },
+        /// The server didn't answer, or its answer wasn't usable, and the wall
+        /// clock was left alone
+        Unreachable { server: String },
+    }
+}
+
+idl::interface! {
+    /// Hands out the devices described by the FDT
+    pub mod devicemgr {
+        /// Claim every device compatible with one of the strings in
+        /// `compatible`, replying with an MMIO capability for each device in
+        /// the same order as the returned devices
+        fn request_devices(compatible: Vec<String>) -> Vec<Device> [caps];
+        /// Reply with a capability to the topic [`HotplugEvent`]s are
+        /// published on, which drivers subscribe to so they can probe devices
+        /// added after boot and tear down ones which are removed
+        fn hotplug_events() [caps];
+        /// Publish `event` to everyone subscribed to hotplug events, for bus
+        /// drivers which notice devices coming and going
+        fn report_hotplug(event: HotplugEvent);
+    }
+}
+
+wire::derive! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum PortType {
+        Udp,
+        Raw,
+    }
+}
+
+wire::derive! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum BindError {
+        PortInUse,
+    }
+}
+
+wire::derive! {
+    /// A datagram sent from or received on a bound port
+    #[derive(Debug, Clone)]
+    pub struct Datagram {
+        /// The IPv4 address the datagram is sent to or was received from
+        pub ip: [u8; 4],
+        pub port: u16,
+        pub data: Vec<u8>,
+    }
+}
+
+idl::interface! {
+    /// Binds ports on the network server. Each channel binds a single port,
+    /// after which it carries [`Datagram`]s in both directions instead of
+    /// requests.
+    pub mod network {
+        /// Bind `port`, replying with the port which was bound
+        fn bind(port: u16, port_type: PortType) -> Result<u16, BindError>;
+        /// Reply with a capability to the topic the outcome of each attempt
+        /// to synchronize the wall clock is published on as a
+        /// [`TimeSyncStatus`], which only the network server can publish to
+        fn time_status() [caps];
+    }
+}
+
+wire::derive! {
+    #[derive(Debug, Clone)]
+    pub struct TemperatureReading {
+        /// The device tree node of the sensor
+        pub sensor: String,
+        /// Which of the sensor's channels this is, e.g. `local` and `remote`
+        /// for a sensor with an external diode
+        pub channel: String,
+        /// `None` if the sensor couldn't be read, or has no reading yet
+        pub millicelsius: Option<i32>,
+    }
+}
+
+wire::derive! {
+    #[derive(Debug, Clone)]
+    pub struct ClockReading {
+        /// The device tree node of the clock controller
+        pub controller: String,
+        pub clock: String,
+        pub hz: u64,
+    }
+}
+
+wire::derive! {
+    #[derive(Debug, Clone)]
+    pub struct Telemetry {
+        pub temperatures: Vec<TemperatureReading>,
+        pub clocks: Vec<ClockReading>,
+    }
+}
+
+idl::interface! {
+    /// Reads the temperature sensors and clock controllers the thermal server
+    /// found in the FDT
+    pub mod thermal {
+        /// Reply with a fresh reading of every sensor and the current rate of
+        /// every clock
+        fn telemetry() -> Telemetry;
+    }
+}
+
+/// The protocol spoken over a channel to the console server. Each channel is
+/// bound to a single port with [`console::Request::Open`], after which the data
+/// written to and received from the port flows over that channel.
+///
+/// Besides the virtio-console ports, the console server hands out
+/// pseudo-terminals for things like terminal emulators and remote shells to
+/// host interactive programs on. The channel which creates one with
+/// [`console::Request::CreatePty`] is its master side, and whatever opens
+/// [`console::PortSelector::Pty`] is its slave side, which behaves like any
+/// other port: what's written by one side is received by the other.
+pub mod console {
+    wire::derive! {
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub enum PortSelector {
+            /// The port the host marked as the console port
+            Console,
+            /// The port with the given name, e.g. `org.qemu.guest_agent.0`
+            Name(String),
+            Id(u32),
+            /// The slave side of the pseudo-terminal with the given ID
+            Pty(u32),
+        }
+    }
+
+    wire::derive! {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+        pub struct WindowSize {
+            pub rows: u16,
+            pub columns: u16,
+        }
+    }
+
+    wire::derive! {
+        #[derive(Debug, Clone)]
+        pub enum Request {
+            Open(PortSelector),
+            Write(Vec<u8>),
+            /// Make the task group whose capability is sent along with the
+            /// request the port's foreground job, which Ctrl-C and Ctrl-Z
+            /// received on the port are turned into job control signals for
+            /// instead of being passed on as data. Sending no capability
+            /// clears the foreground job.
+            SetForeground,
+            Close,
+            /// Bind the channel to the master side of a new pseudo-terminal
+            /// with the given window size, replying with
+            /// [`Response::PtyCreated`]
+            CreatePty(WindowSize),
+            /// Change the window size of the pseudo-terminal the channel is
+            /// the master side of, which is passed on to the slave side as
+            /// [`Response::Resized`]
+            Resize(WindowSize),
+            /// Reply with the window size of the pseudo-terminal the channel
+            /// is either side of
+            WindowSize,
+        }
+    }
+
+    wire::derive! {
+        #[derive(Debug, Clone)]
+        pub enum Response {
+            Opened { id: u32, name: Option<String> },
+            NotFound,
+            InUse,
+            /// The channel must be bound to a port before writing to it
+            NotOpen,
+            Data(Vec<u8>),
+            /// Whether something on the host side is connected to the port
+            HostConnected(bool),
+            /// The port was removed from the device and the channel is no
+            /// longer bound to it
+            Removed,
+            /// The channel is the master side of the pseudo-terminal with the
+            /// given ID. [`Response::HostConnected`] is sent to it whenever
+            /// the slave side is opened or closed.
+            PtyCreated { id: u32 },
+            /// The window size of the pseudo-terminal, sent whenever the
+            /// master side changes it or in reply to [`Request::WindowSize`]
+            Resized(WindowSize),
+            /// The channel isn't the right side of a pseudo-terminal for the
+            /// request
+            NotAPty,
+        }
+    }
+}
+
+/// The protocol spoken over a channel to the vsock server. Like the network
+/// server, each channel holds a single socket, which is either connected to a
+/// peer with [`vsock::Request::Connect`] or waits for a peer to connect to it
+/// with [`vsock::Request::Accept`].
+pub mod vsock {
+    wire::derive! {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub struct VsockAddress {
+            /// The context ID, where the host is always `2`
+            pub cid: u64,
+            pub port: u32,
+        }
+    }
+
+    wire::derive! {
+        #[derive(Debug, Clone)]
+        pub enum Request {
+            Connect(VsockAddress),
+            /// Listen on the given port until a single peer connects to it
+            Accept { port: u32 },
+            Send(Vec<u8>),
+            Shutdown,
+        }
+    }
+
+    wire::derive! {
+        #[derive(Debug, Clone)]
+        pub enum Response {
+            Connected { local: VsockAddress, peer: VsockAddress },
+            Refused,
+            PortInUse,
+            /// The channel already holds a socket, or the socket isn't
+            /// connected yet
+            InvalidState,
+            Data(Vec<u8>),
+            /// The peer closed the connection or it was reset
+            Closed,
+        }
+    }
+}
+
+/// The protocol spoken over a channel to the login server, which accepts
+/// remote connections and gives each one that authenticates a pseudo-terminal
+/// from the console server. Whatever runs the shell asks for the next session
+/// with [`login::Request::NextSession`], and opens the slave side of the
+/// pseudo-terminal it's given. The connection is closed once the slave side is.
+pub mod login {
+    wire::derive! {
+        #[derive(Debug, Clone)]
+        pub enum Request {
+            /// Wait until someone logs in, replying with
+            /// [`Response::Session`]
+            NextSession,
+        }
+    }
+
+    wire::derive! {
+        #[derive(Debug, Clone)]
+        pub enum Response {
+            /// The ID of the pseudo-terminal the session is bridged to, to be
+            /// opened with [`super::console::PortSelector::Pty`]
+            Session { pty: u32 },
+        }
+    }
+}
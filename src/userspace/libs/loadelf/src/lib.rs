@@ -58,25 +58,60 @@ pub fn load_elf(name: &str, elf: &Elf) -> Result<(Vmspace, VmspaceSpawnEnv), ()>
         // segment
         let region_size = round_up_to_next(mem_size + segment_load_offset, align);
 
-        let mut object = vmspace.create_object(segment_offset as *const _, region_size, permissions).unwrap();
-
-        if task_load_base == 0 {
-            segment_load_base = object.vmspace_address() as usize;
-            task_load_base = object.vmspace_address() as usize;
-        }
-
         assert!(align.is_power_of_two(), "ELF segment alignment isn't a power of two!");
         assert!(mem_size >= file_size, "ELF segment has less data in memory than in the file?");
 
-        // Copy the segment data starting at the offset
-        object.as_slice()[segment_load_offset..][..file_size].copy_from_slice(elf.program_segment_data(&header));
-
         // We use these values to key off of some information (e.g.
         // relocation calculations and calculating the PC)
         let raw_segment_start = header.vaddr as usize;
         let raw_segment_end = raw_segment_start + header.memory_size as usize;
         let raw_segment_range = raw_segment_start..raw_segment_end;
 
+        let data = elf.program_segment_data(&header);
+        let page_offset = vaddr % PAGE_SIZE;
+
+        // Segments which are never written to, not even by relocations, can be
+        // mapped straight from the pages the ELF is already in, as long as the
+        // data sits in them the same way it would once loaded
+        let shareable = !(permissions & MemoryPermissions::WRITE)
+            && file_size != 0
+            && mem_size == file_size
+            && data.as_ptr() as usize % PAGE_SIZE == page_offset
+            && relocations.range(raw_segment_range.clone()).next().is_none();
+
+        if shareable {
+            let pages_start = segment_load_offset - page_offset;
+            let at = match task_load_base {
+                0 => core::ptr::null(),
+                _ => (segment_load_base + pages_start) as *const u8,
+            };
+
+            let memory = data.as_ptr().wrapping_sub(page_offset);
+            let theirs = vmspace.share_object(at, memory, file_size + page_offset, permissions).unwrap();
+
+            if task_load_base == 0 {
+                segment_load_base = theirs as usize - pages_start;
+                task_load_base = segment_load_base;
+            }
+
+            if raw_segment_range.contains(&elf_entry) {
+                pc = segment_load_base + elf_entry - raw_segment_start + segment_load_offset;
+            }
+
+            segment_offset = segment_load_base + region_size;
+            continue;
+        }
+
+        let mut object = vmspace.create_object(segment_offset as *const _, region_size, permissions).unwrap();
+
+        if task_load_base == 0 {
+            segment_load_base = object.vmspace_address() as usize;
+            task_load_base = object.vmspace_address() as usize;
+        }
+
+        // Copy the segment data starting at the offset
+        object.as_slice()[segment_load_offset..][..file_size].copy_from_slice(data);
+
         // The real PC needs calculated from the offset, so we check to see
         // if this is the segment that contains the entry point
         if raw_segment_range.contains(&elf_entry) {
@@ -9,6 +9,7 @@ use librust::{units::Bytes, syscalls::{mem::{AllocationOptions, MemoryPermission
 use crate::reactor::{BlockType, EVENT_REGISTRY, NEW_IPC_CHANNELS};
 use core::{future::Future, pin::Pin};
 use std::{
+    ipc::IpcError,
     task::{Context, Poll},
 };
 
@@ -108,6 +109,28 @@ impl IpcChannel {
 
         Ok((t, msg, caps))
     }
+
+    pub fn send_serialized<T: wire::Serialize + ?Sized>(&self, t: &T, caps: &[Capability]) -> Result<(), SyscallError> {
+        let (message, payload_cap) = std::ipc::encode_message(t)?;
+
+        match payload_cap {
+            Some(payload_cap) => {
+                let mut all_caps = vec![payload_cap];
+                all_caps.extend_from_slice(caps);
+                channel::send_message(self.0, message, &all_caps)
+            }
+            None => channel::send_message(self.0, message, caps),
+        }
+    }
+
+    pub async fn read_serialized<T: wire::DeserializeOwned>(
+        &self,
+    ) -> Result<(T, Vec<CapabilityWithDescription>), IpcError> {
+        let (message, mut caps) = self.read_with_all_caps().await?;
+        let t = std::ipc::decode_message(&message, &mut caps)?;
+
+        Ok((t, caps))
+    }
 }
 
 impl Drop for IpcChannel {
@@ -10,8 +10,11 @@
 pub mod interrupt;
 pub mod ipc;
 pub mod join;
+pub mod net;
 pub mod reactor;
 pub mod sync;
+pub mod time;
+pub mod vsock;
 pub mod waker;
 
 extern crate sync as sync_prims;
@@ -63,6 +66,7 @@ impl Present {
     }
 
     fn run_with<F: Future>(&self, f: F) -> F::Output {
+        librust::syscalls::task::enable_notifications();
         let mut value = None;
 
         // SAFETY: We're single threaded and block the current thread until the
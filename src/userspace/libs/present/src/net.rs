@@ -0,0 +1,72 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::ipc::IpcChannel;
+use interfaces::{
+    network::{Request, Response},
+    BindError, Datagram, PortType,
+};
+use librust::{capabilities::CapabilityPtr, error::SyscallError};
+use netstack::ipv4::{IpV4Address, IpV4Socket};
+use std::ipc::IpcError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    Ipc(IpcError),
+    /// The network server refused to bind the port
+    BindFailed(BindError),
+}
+
+impl From<IpcError> for NetError {
+    fn from(e: IpcError) -> Self {
+        Self::Ipc(e)
+    }
+}
+
+impl From<SyscallError> for NetError {
+    fn from(e: SyscallError) -> Self {
+        Self::Ipc(IpcError::Syscall(e))
+    }
+}
+
+/// A UDP port bound through the network server
+pub struct UdpSocket {
+    channel: IpcChannel,
+    port: u16,
+}
+
+impl UdpSocket {
+    /// Bind `port` using `network`, a channel to the network server. The
+    /// network server treats each channel as a single client, so the channel
+    /// can't be shared with another socket.
+    pub async fn bind(network: CapabilityPtr, port: u16) -> Result<Self, NetError> {
+        let channel = IpcChannel::new(network);
+        channel.send_serialized(&Request::bind { port, port_type: PortType::Udp }, &[])?;
+
+        match channel.read_serialized::<Response>().await?.0 {
+            Response::bind(Ok(port)) => Ok(Self { channel, port }),
+            Response::bind(Err(e)) => Err(NetError::BindFailed(e)),
+            _ => Err(NetError::Ipc(IpcError::UnexpectedMessage)),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn send_to(&self, data: &[u8], to: IpV4Socket) -> Result<(), NetError> {
+        let datagram = Datagram { ip: to.ip.to_bytes(), port: to.port, data: data.to_vec() };
+        self.channel.send_serialized(&datagram, &[])?;
+
+        Ok(())
+    }
+
+    pub async fn recv_from(&self) -> Result<(Vec<u8>, IpV4Socket), NetError> {
+        let (datagram, _) = self.channel.read_serialized::<Datagram>().await?;
+        Ok((datagram.data, IpV4Socket::new(IpV4Address::from(datagram.ip), datagram.port)))
+    }
+}
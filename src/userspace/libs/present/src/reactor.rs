@@ -33,7 +33,7 @@ impl EventRegistry {
     }
 
     pub(crate) fn unregister_interest(&self, block_type: BlockType) {
-        assert!(self.interest.borrow_mut().remove(&block_type).is_none());
+        assert!(self.interest.borrow_mut().remove(&block_type).is_some());
     }
 
     pub(crate) fn is_interest(&self, block_type: BlockType) -> bool {
@@ -79,6 +79,7 @@ pub(crate) enum BlockType {
     IpcChannelMessage(CapabilityPtr),
     Interrupt(usize),
     AsyncChannel(u64),
+    Timer(usize),
 }
 
 pub struct Reactor;
@@ -92,6 +93,17 @@ impl Reactor {
                     waker.wake();
                 }
             }
+            KernelMessage::TimerExpired(id) => {
+                EVENT_REGISTRY.add_interested_event(BlockType::Timer(id));
+                if let Some(waker) = EVENT_REGISTRY.unregister(BlockType::Timer(id)) {
+                    waker.wake();
+                }
+            }
+            // Only sent to tasks which subscribed to them themselves
+            KernelMessage::ShutdownRequested(_)
+            | KernelMessage::JobControl(_)
+            | KernelMessage::SwapPending
+            | KernelMessage::MemoryPressure(_) => {}
             KernelMessage::NewChannelMessage(cptr) => {
                 let saw = SEEN_IPC_CHANNELS.borrow().get(&cptr).is_some();
                 match saw {
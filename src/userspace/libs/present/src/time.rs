@@ -0,0 +1,113 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::reactor::{BlockType, EVENT_REGISTRY};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use std::task::{Context, Poll};
+
+static NEXT_TIMER_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Completes once `duration` has elapsed
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep { duration, state: SleepState::Unarmed }
+}
+
+/// Runs `future` to completion, or gives up on it once `duration` has elapsed
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout { future, sleep: sleep(duration) }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SleepState {
+    Unarmed,
+    Armed(usize),
+    Fired,
+}
+
+#[derive(Debug)]
+pub struct Sleep {
+    duration: Duration,
+    state: SleepState,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        match this.state {
+            SleepState::Fired => Poll::Ready(()),
+            SleepState::Unarmed if this.duration.is_zero() => {
+                this.state = SleepState::Fired;
+                Poll::Ready(())
+            }
+            SleepState::Unarmed => {
+                let id = NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed);
+                EVENT_REGISTRY.register_interest(BlockType::Timer(id));
+                librust::syscalls::task::set_timer(id, this.duration).expect("failed to arm timer");
+
+                this.state = SleepState::Armed(id);
+                EVENT_REGISTRY.register(BlockType::Timer(id), cx.waker().clone());
+                Poll::Pending
+            }
+            SleepState::Armed(id) => match EVENT_REGISTRY.consume_interest_event(BlockType::Timer(id)) {
+                true => {
+                    EVENT_REGISTRY.unregister_interest(BlockType::Timer(id));
+                    this.state = SleepState::Fired;
+                    Poll::Ready(())
+                }
+                false => {
+                    EVENT_REGISTRY.register(BlockType::Timer(id), cx.waker().clone());
+                    Poll::Pending
+                }
+            },
+        }
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        // The kernel will still notify us when the timer expires, but without
+        // any interest registered the reactor will just ignore it
+        if let SleepState::Armed(id) = self.state {
+            EVENT_REGISTRY.unregister(BlockType::Timer(id));
+            EVENT_REGISTRY.unregister_interest(BlockType::Timer(id));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+pub struct Timeout<F: Future> {
+    future: F,
+    sleep: Sleep,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: `future` is never moved out of `self`, and `Sleep` is `Unpin`
+        let this = unsafe { self.get_unchecked_mut() };
+
+        if let Poll::Ready(t) = unsafe { Pin::new_unchecked(&mut this.future) }.poll(cx) {
+            return Poll::Ready(Ok(t));
+        }
+
+        match Pin::new(&mut this.sleep).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,109 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::ipc::IpcChannel;
+use interfaces::vsock::{Request, Response};
+use librust::{capabilities::CapabilityPtr, error::SyscallError};
+use std::ipc::IpcError;
+
+pub use interfaces::vsock::VsockAddress;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VsockError {
+    Ipc(IpcError),
+    /// The peer refused the connection
+    Refused,
+    /// Something is already listening on the requested port
+    PortInUse,
+    /// The channel already holds a socket, or the stream isn't connected
+    InvalidState,
+    /// The peer closed the connection or it was reset
+    Closed,
+    /// The vsock server sent a response which doesn't fit the request
+    UnexpectedResponse,
+}
+
+impl From<IpcError> for VsockError {
+    fn from(e: IpcError) -> Self {
+        Self::Ipc(e)
+    }
+}
+
+impl From<SyscallError> for VsockError {
+    fn from(e: SyscallError) -> Self {
+        Self::Ipc(IpcError::Syscall(e))
+    }
+}
+
+/// A stream connection made through the vsock server
+pub struct VsockStream {
+    channel: IpcChannel,
+    local: VsockAddress,
+    peer: VsockAddress,
+}
+
+impl VsockStream {
+    /// Connect to `peer` using `vsock`, a channel to the vsock server. The
+    /// vsock server treats each channel as a single socket, so the channel
+    /// can't be shared with another stream.
+    pub async fn connect(vsock: CapabilityPtr, peer: VsockAddress) -> Result<Self, VsockError> {
+        Self::establish(vsock, Request::Connect(peer)).await
+    }
+
+    /// Wait for a peer to connect to `port`, using `vsock` the same way as
+    /// [`VsockStream::connect`]. Only a single connection is accepted, after
+    /// which the port is free to be listened on again.
+    pub async fn accept(vsock: CapabilityPtr, port: u32) -> Result<Self, VsockError> {
+        Self::establish(vsock, Request::Accept { port }).await
+    }
+
+    async fn establish(vsock: CapabilityPtr, request: Request) -> Result<Self, VsockError> {
+        let channel = IpcChannel::new(vsock);
+        channel.send_serialized(&request, &[])?;
+
+        match channel.read_serialized().await?.0 {
+            Response::Connected { local, peer } => Ok(Self { channel, local, peer }),
+            Response::Refused => Err(VsockError::Refused),
+            Response::PortInUse => Err(VsockError::PortInUse),
+            Response::InvalidState => Err(VsockError::InvalidState),
+            Response::Closed => Err(VsockError::Closed),
+            Response::Data(_) => Err(VsockError::UnexpectedResponse),
+        }
+    }
+
+    pub fn local_address(&self) -> VsockAddress {
+        self.local
+    }
+
+    pub fn peer_address(&self) -> VsockAddress {
+        self.peer
+    }
+
+    pub fn send(&self, data: &[u8]) -> Result<(), VsockError> {
+        self.channel.send_serialized(&Request::Send(data.to_vec()), &[])?;
+
+        Ok(())
+    }
+
+    /// Receive the next chunk of data sent by the peer
+    pub async fn recv(&self) -> Result<Vec<u8>, VsockError> {
+        match self.channel.read_serialized().await?.0 {
+            Response::Data(data) => Ok(data),
+            Response::Closed => Err(VsockError::Closed),
+            Response::InvalidState => Err(VsockError::InvalidState),
+            _ => Err(VsockError::UnexpectedResponse),
+        }
+    }
+
+    /// Close the connection, the peer will see the stream end once it's read
+    /// everything sent before this
+    pub fn shutdown(self) -> Result<(), VsockError> {
+        self.channel.send_serialized(&Request::Shutdown, &[])?;
+
+        Ok(())
+    }
+}
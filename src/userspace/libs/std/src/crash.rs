@@ -0,0 +1,160 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Reporting fatal faults to a crash collector
+//!
+//! Tasks which are given a `crashcollector` capability have a fault handler
+//! installed before `main` runs. When the task hits a fault which would
+//! otherwise kill it, the handler packs up the registers, the top of the
+//! faulting stack, a backtrace, and where the executable was loaded into a
+//! [`Minidump`], sends it to the collector, and then exits. Each minidump is
+//! tagged with an ID of its own and the boot it came from, so ones kept around
+//! across boots can't be mixed up.
+
+use crate::ipc::IpcChannel;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use librust::{
+    capabilities::CapabilityPtr,
+    error::SyscallError,
+    syscalls::mem::{self, AllocationOptions, MemoryPermissions},
+    task::FaultInfo,
+    units::Bytes,
+    uuid::{self, Uuid},
+};
+
+/// The handler has to serialize the minidump, which needs a fair bit of stack
+const HANDLER_STACK_SIZE: usize = 64 * 1024;
+const NO_COLLECTOR: usize = usize::MAX;
+
+/// The collector's channel is looked up ahead of time, since the capability
+/// map could be in any state when the fault happens
+static COLLECTOR: AtomicUsize = AtomicUsize::new(NO_COLLECTOR);
+
+extern "C" {
+    static __ehdr_start: u8;
+    static end: u8;
+}
+
+wire::derive! {
+    #[derive(Debug, Clone)]
+    pub struct Minidump {
+        /// Unique to this crash, see [`Minidump::id`]
+        pub id: [u8; 16],
+        /// The boot the crash happened in, see [`Minidump::boot_id`]
+        pub boot_id: [u8; 16],
+        pub task_name: String,
+        pub tid: u64,
+        /// The `scause` value of the fault
+        pub cause: u64,
+        pub pc: u64,
+        /// The faulting address for memory faults
+        pub address: u64,
+        /// `x1` through `x31`
+        pub registers: Vec<u64>,
+        /// The top of the stack, starting at `sp`
+        pub stack: Vec<u8>,
+        /// Return addresses found by walking the frame pointers, innermost
+        /// first
+        pub backtrace: Vec<u64>,
+        /// Where the executable starts in memory, which is subtracted from
+        /// code addresses to look them up in the executable
+        pub image_base: u64,
+        pub image_end: u64,
+    }
+}
+
+impl Minidump {
+    pub fn id(&self) -> Uuid {
+        Uuid::from_bytes(self.id)
+    }
+
+    pub fn boot_id(&self) -> Uuid {
+        Uuid::from_bytes(self.boot_id)
+    }
+
+    pub fn cause_name(&self) -> &'static str {
+        match self.cause {
+            0 => "instruction address misaligned",
+            1 => "instruction access fault",
+            2 => "illegal instruction",
+            3 => "breakpoint",
+            4 => "load address misaligned",
+            5 => "load access fault",
+            6 => "store address misaligned",
+            7 => "store access fault",
+            12 => "instruction page fault",
+            13 => "load page fault",
+            15 => "store page fault",
+            _ => "unknown fault",
+        }
+    }
+
+    pub fn stack_pointer(&self) -> u64 {
+        self.registers.get(1).copied().unwrap_or(0)
+    }
+}
+
+/// Install the fault handler if the task was given a crash collector
+pub(crate) fn init() {
+    if let Some(collector) = crate::env::lookup_capability("crashcollector") {
+        if let Err(e) = install(collector.capability.cptr) {
+            crate::println!("Failed to install fault handler: {:?}", e);
+        }
+    }
+}
+
+/// Send minidumps for faults to `collector` from now on
+pub fn install(collector: CapabilityPtr) -> Result<(), SyscallError> {
+    let (_, stack) = mem::alloc_virtual_memory(
+        Bytes(HANDLER_STACK_SIZE),
+        AllocationOptions::PRIVATE,
+        MemoryPermissions::READ | MemoryPermissions::WRITE,
+    )?;
+
+    COLLECTOR.store(collector.value(), Ordering::Relaxed);
+
+    let stack_top = unsafe { stack.cast::<u8>().add(HANDLER_STACK_SIZE) };
+    librust::syscalls::task::set_fault_handler(fault_handler, stack_top)
+}
+
+extern "C" fn fault_handler(info: *const FaultInfo) -> ! {
+    // SAFETY: the kernel always enters the handler with a valid `FaultInfo`
+    let info = unsafe { &*info };
+
+    let minidump = Minidump {
+        id: *uuid::generate_uuid().unwrap_or(Uuid::NIL).as_bytes(),
+        boot_id: *librust::syscalls::io::boot_id().as_bytes(),
+        task_name: info.name().into(),
+        tid: librust::syscalls::task::current_tid().value() as u64,
+        cause: info.cause as u64,
+        pc: info.pc as u64,
+        address: info.address as u64,
+        registers: info.registers.iter().map(|&r| r as u64).collect(),
+        stack: info.stack().to_vec(),
+        backtrace: info.backtrace().iter().map(|&ra| ra as u64).collect(),
+        image_base: unsafe { core::ptr::addr_of!(__ehdr_start) } as u64,
+        image_end: unsafe { core::ptr::addr_of!(end) } as u64,
+    };
+
+    crate::println!(
+        "Task {} crashed: {} @ {:#x} (PC: {:#x})",
+        minidump.task_name,
+        minidump.cause_name(),
+        minidump.address,
+        minidump.pc
+    );
+    crate::io::Stdout.flush();
+
+    match COLLECTOR.load(Ordering::Relaxed) {
+        NO_COLLECTOR => {}
+        collector => {
+            let _ = IpcChannel::new(CapabilityPtr::new(collector)).send_serialized(&minidump, &[]);
+        }
+    }
+
+    librust::syscalls::task::exit()
+}
@@ -5,10 +5,22 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
-use alloc::{collections::BTreeMap, string::String};
+pub mod config;
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    string::String,
+    vec::Vec,
+};
+use config::{Request, Response};
 use librust::capabilities::{CapabilityPtr, CapabilityWithDescription};
 
-use crate::sync::SyncRefCell;
+use crate::{
+    ipc::{ChannelReadFlags, IpcChannel, IpcError},
+    sync::SyncRefCell,
+};
+
+pub use config::ConfigChange;
 
 #[no_mangle]
 static mut ARGS: [usize; 2] = [0; 2];
@@ -30,6 +42,26 @@ pub fn a2() -> usize {
     unsafe { A2 }
 }
 
+/// Bumped whenever the layout of [`Bootstrap`] changes incompatibly
+pub(crate) const BOOTSTRAP_VERSION: u32 = 1;
+
+wire::derive! {
+    /// The first message a task receives from its parent, naming the
+    /// capabilities sent along with it
+    pub(crate) struct Bootstrap {
+        pub(crate) version: u32,
+        pub(crate) slots: Vec<CapabilitySlot>,
+    }
+}
+
+wire::derive! {
+    pub(crate) struct CapabilitySlot {
+        pub(crate) name: String,
+        /// Index of the capability in the bootstrap message
+        pub(crate) index: u32,
+    }
+}
+
 pub(crate) static CAP_MAP: SyncRefCell<BTreeMap<String, CapabilityWithDescription>> = SyncRefCell::new(BTreeMap::new());
 
 pub fn lookup_capability(service: &str) -> Option<CapabilityWithDescription> {
@@ -39,3 +71,84 @@ pub fn lookup_capability(service: &str) -> Option<CapabilityWithDescription> {
 pub fn register_capability(service: &str, cptr: CapabilityWithDescription) {
     CAP_MAP.borrow_mut().insert(service.into(), cptr);
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The task wasn't given a `config` capability
+    Unavailable,
+    Ipc(IpcError),
+    /// The config server sent a response which doesn't fit the request
+    UnexpectedResponse,
+}
+
+impl From<IpcError> for ConfigError {
+    fn from(e: IpcError) -> Self {
+        Self::Ipc(e)
+    }
+}
+
+impl From<librust::error::SyscallError> for ConfigError {
+    fn from(e: librust::error::SyscallError) -> Self {
+        Self::Ipc(IpcError::Syscall(e))
+    }
+}
+
+/// Changes to watched keys which arrived while waiting on a response
+static PENDING_CHANGES: SyncRefCell<VecDeque<ConfigChange>> = SyncRefCell::new(VecDeque::new());
+
+fn config_channel() -> Result<IpcChannel, ConfigError> {
+    match lookup_capability("config") {
+        Some(config) => Ok(IpcChannel::new(config.capability.cptr)),
+        None => Err(ConfigError::Unavailable),
+    }
+}
+
+fn config_call(request: &Request) -> Result<Response, ConfigError> {
+    let channel = config_channel()?;
+    channel.send_serialized(request, &[])?;
+
+    loop {
+        match channel.read_serialized(ChannelReadFlags::NONE)?.0 {
+            Response::Changed(change) => PENDING_CHANGES.borrow_mut().push_back(change),
+            response => return Ok(response),
+        }
+    }
+}
+
+/// The value of the configuration key `key`, like `log.level`
+pub fn config(key: &str) -> Result<Option<String>, ConfigError> {
+    match config_call(&Request::Get { key: key.into() })? {
+        Response::Value(value) => Ok(value),
+        _ => Err(ConfigError::UnexpectedResponse),
+    }
+}
+
+/// Set the configuration key `key` to `value`, or unset it if `value` is
+/// `None`
+pub fn set_config(key: &str, value: Option<&str>) -> Result<(), ConfigError> {
+    match config_call(&Request::Set { key: key.into(), value: value.map(Into::into) })? {
+        Response::Done => Ok(()),
+        _ => Err(ConfigError::UnexpectedResponse),
+    }
+}
+
+/// Start watching every configuration key starting with `prefix` for changes,
+/// which are returned by [`next_config_change`]
+pub fn watch_config(prefix: &str) -> Result<(), ConfigError> {
+    match config_call(&Request::Watch { prefix: prefix.into() })? {
+        Response::Done => Ok(()),
+        _ => Err(ConfigError::UnexpectedResponse),
+    }
+}
+
+/// Wait for the next change to a watched configuration key
+pub fn next_config_change() -> Result<ConfigChange, ConfigError> {
+    if let Some(change) = PENDING_CHANGES.borrow_mut().pop_front() {
+        return Ok(change);
+    }
+
+    match config_channel()?.read_serialized(ChannelReadFlags::NONE)?.0 {
+        Response::Changed(change) => Ok(change),
+        _ => Err(ConfigError::UnexpectedResponse),
+    }
+}
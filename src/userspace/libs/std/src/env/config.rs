@@ -0,0 +1,42 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The protocol spoken over a channel to the config server
+//!
+//! Keys are dotted names like `log.level`, and both keys and values are plain
+//! strings. Every request gets exactly one [`Response`], but a channel which
+//! watches keys can also be sent a [`Response::Changed`] at any time.
+
+wire::derive! {
+    #[derive(Debug, Clone)]
+    pub enum Request {
+        Get { key: String },
+        /// Set `key` to `value`, or unset it if `value` is `None`
+        Set { key: String, value: Option<String> },
+        /// Be sent a [`Response::Changed`] whenever a key starting with
+        /// `prefix` is set or unset, where an empty prefix watches every key
+        Watch { prefix: String },
+    }
+}
+
+wire::derive! {
+    #[derive(Debug, Clone)]
+    pub enum Response {
+        Value(Option<String>),
+        Done,
+        Changed(ConfigChange),
+    }
+}
+
+wire::derive! {
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct ConfigChange {
+        pub key: String,
+        /// `None` if the key was unset
+        pub value: Option<String>,
+    }
+}
@@ -0,0 +1,294 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Files and directories provided by the filesystem server, which is reached
+//! through the `filesystem` capability handed to the task at startup
+
+pub mod protocol;
+
+use crate::{
+    ipc::IpcError,
+    path::{Path, PathBuf},
+};
+use protocol::{filesystem, FsError, OpenFlags};
+
+pub use protocol::{FileKind, Metadata, SeekFrom};
+
+/// The most data moved in a single request, larger reads and writes are split
+/// up into multiple requests
+const MAX_TRANSFER: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The task wasn't given a `filesystem` capability
+    Unavailable,
+    Ipc(IpcError),
+    Filesystem(FsError),
+    /// The file's contents weren't valid UTF-8
+    InvalidUtf8,
+    /// The filesystem server sent a response which doesn't fit the request
+    UnexpectedResponse,
+}
+
+impl From<IpcError> for Error {
+    fn from(e: IpcError) -> Self {
+        Self::Ipc(e)
+    }
+}
+
+impl From<librust::error::SyscallError> for Error {
+    fn from(e: librust::error::SyscallError) -> Self {
+        Self::Ipc(IpcError::Syscall(e))
+    }
+}
+
+impl From<FsError> for Error {
+    fn from(e: FsError) -> Self {
+        Self::Filesystem(e)
+    }
+}
+
+fn client() -> Result<filesystem::Client, Error> {
+    match crate::env::lookup_capability("filesystem") {
+        Some(cap) => Ok(filesystem::Client::new(cap.capability.cptr)),
+        None => Err(Error::Unavailable),
+    }
+}
+
+/// An open file, which is closed when dropped
+#[derive(Debug)]
+pub struct File {
+    handle: u64,
+}
+
+impl File {
+    /// Open an existing file for reading
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        OpenOptions::new().read(true).open(path)
+    }
+
+    /// Open a file for writing, creating it if it doesn't exist and truncating
+    /// it if it does
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        OpenOptions::new().write(true).create(true).truncate(true).open(path)
+    }
+
+    pub fn options() -> OpenOptions {
+        OpenOptions::new()
+    }
+
+    /// Read into `buffer` from the current position, returning the number of
+    /// bytes read, which is only zero at the end of the file
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+        let len = buffer.len().min(MAX_TRANSFER) as u64;
+        match client()?.read(self.handle, len)?? {
+            data if data.len() <= buffer.len() => {
+                buffer[..data.len()].copy_from_slice(&data);
+                Ok(data.len())
+            }
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Read everything from the current position to the end of the file,
+    /// returning the number of bytes read
+    pub fn read_to_end(&mut self, buffer: &mut Vec<u8>) -> Result<usize, Error> {
+        let client = client()?;
+        let start = buffer.len();
+        loop {
+            match client.read(self.handle, MAX_TRANSFER as u64)?? {
+                data if data.is_empty() => return Ok(buffer.len() - start),
+                data => buffer.extend_from_slice(&data),
+            }
+        }
+    }
+
+    pub fn read_to_string(&mut self, buffer: &mut String) -> Result<usize, Error> {
+        let mut bytes = Vec::new();
+        self.read_to_end(&mut bytes)?;
+
+        let s = core::str::from_utf8(&bytes).map_err(|_| Error::InvalidUtf8)?;
+        buffer.push_str(s);
+
+        Ok(s.len())
+    }
+
+    /// Write `data` at the current position, returning the number of bytes
+    /// written
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, Error> {
+        let data = &data[..data.len().min(MAX_TRANSFER)];
+        Ok(client()?.write(self.handle, data.to_vec())?? as usize)
+    }
+
+    pub fn write_all(&mut self, mut data: &[u8]) -> Result<(), Error> {
+        while !data.is_empty() {
+            match self.write(data)? {
+                0 => return Err(Error::UnexpectedResponse),
+                n => data = &data[n..],
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Move the position reads and writes happen at, returning the new
+    /// position from the start of the file
+    pub fn seek(&mut self, from: SeekFrom) -> Result<u64, Error> {
+        Ok(client()?.seek(self.handle, from)??)
+    }
+
+    pub fn metadata(&self) -> Result<Metadata, Error> {
+        Ok(client()?.handle_metadata(self.handle)??)
+    }
+}
+
+impl core::fmt::Write for File {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.write_all(s.as_bytes()).map_err(|_| core::fmt::Error)
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        if let Ok(client) = client() {
+            let _ = client.close(self.handle);
+        }
+    }
+}
+
+/// Options for how a file is opened, which match those of the same name in
+/// Rust's standard library
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OpenOptions(OpenFlags);
+
+impl OpenOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read(&mut self, read: bool) -> &mut Self {
+        self.0.read = read;
+        self
+    }
+
+    pub fn write(&mut self, write: bool) -> &mut Self {
+        self.0.write = write;
+        self
+    }
+
+    /// Implies `write`
+    pub fn append(&mut self, append: bool) -> &mut Self {
+        self.0.append = append;
+        self
+    }
+
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.0.truncate = truncate;
+        self
+    }
+
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.0.create = create;
+        self
+    }
+
+    /// Create the file, failing if it already exists
+    pub fn create_new(&mut self, create_new: bool) -> &mut Self {
+        self.0.create_new = create_new;
+        self
+    }
+
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<File, Error> {
+        let mut flags = self.0;
+        flags.write |= flags.append;
+
+        let handle = client()?.open(path.as_ref().as_str().into(), flags)??;
+        Ok(File { handle })
+    }
+}
+
+/// An entry in a directory listed by [`read_dir`]
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    path: PathBuf,
+    metadata: Metadata,
+}
+
+impl DirEntry {
+    /// The full path to the entry, which is the directory's path joined with
+    /// the entry's name
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn file_name(&self) -> &str {
+        self.path.file_name().unwrap_or_default()
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        self.metadata
+    }
+}
+
+/// The entries in a directory at the time it was read, in no particular order
+#[derive(Debug)]
+pub struct ReadDir {
+    entries: alloc::vec::IntoIter<DirEntry>,
+}
+
+impl Iterator for ReadDir {
+    type Item = DirEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.entries.next()
+    }
+}
+
+pub fn read_dir<P: AsRef<Path>>(path: P) -> Result<ReadDir, Error> {
+    let entries = client()?.read_dir(path.as_ref().as_str().into())??;
+
+    let entries = entries
+        .into_iter()
+        .map(|entry| DirEntry { path: path.as_ref().join(&entry.name), metadata: entry.metadata })
+        .collect::<Vec<_>>();
+
+    Ok(ReadDir { entries: entries.into_iter() })
+}
+
+pub fn metadata<P: AsRef<Path>>(path: P) -> Result<Metadata, Error> {
+    Ok(client()?.metadata(path.as_ref().as_str().into())??)
+}
+
+pub fn create_dir<P: AsRef<Path>>(path: P) -> Result<(), Error> {
+    Ok(client()?.create_dir(path.as_ref().as_str().into())??)
+}
+
+pub fn remove_file<P: AsRef<Path>>(path: P) -> Result<(), Error> {
+    Ok(client()?.remove_file(path.as_ref().as_str().into())??)
+}
+
+/// Read the entire contents of a file
+pub fn read<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, Error> {
+    let mut contents = Vec::new();
+    File::open(path)?.read_to_end(&mut contents)?;
+
+    Ok(contents)
+}
+
+/// Read the entire contents of a file into a string
+pub fn read_to_string<P: AsRef<Path>>(path: P) -> Result<String, Error> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+
+    Ok(contents)
+}
+
+/// Replace the contents of a file with `contents`, creating it if it doesn't
+/// exist
+pub fn write<P: AsRef<Path>>(path: P, contents: &[u8]) -> Result<(), Error> {
+    File::create(path)?.write_all(contents)
+}
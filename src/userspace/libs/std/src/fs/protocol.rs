@@ -0,0 +1,122 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The protocol spoken over a channel to the filesystem server
+//!
+//! The requests themselves are defined by [`filesystem`]. Open files are
+//! referred to by handles which are only valid on the channel they were opened
+//! on.
+
+wire::derive! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct OpenFlags {
+        pub read: bool,
+        pub write: bool,
+        /// Every write goes to the end of the file, regardless of the position
+        pub append: bool,
+        pub truncate: bool,
+        pub create: bool,
+        /// Fail if the file already exists
+        pub create_new: bool,
+    }
+}
+
+wire::derive! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum SeekFrom {
+        Start(u64),
+        End(i64),
+        Current(i64),
+    }
+}
+
+wire::derive! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum FileKind {
+        File,
+        Directory,
+    }
+}
+
+wire::derive! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Metadata {
+        pub kind: FileKind,
+        /// The size of the file in bytes, or the number of entries in a
+        /// directory
+        pub len: u64,
+    }
+}
+
+impl Metadata {
+    pub fn is_file(&self) -> bool {
+        self.kind == FileKind::File
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.kind == FileKind::Directory
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+wire::derive! {
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct DirEntry {
+        pub name: String,
+        pub metadata: Metadata,
+    }
+}
+
+wire::derive! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum FsError {
+        NotFound,
+        AlreadyExists,
+        IsADirectory,
+        NotADirectory,
+        DirectoryNotEmpty,
+        /// The path is malformed or escapes the filesystem's root
+        InvalidPath,
+        /// The handle isn't open on this channel
+        InvalidHandle,
+        /// The file wasn't opened for reading or writing
+        PermissionDenied,
+        /// Seeking to before the start of the file
+        InvalidSeek,
+        /// Seeking or writing past the largest size a file can have
+        FileTooLarge,
+    }
+}
+
+crate::interface! {
+    /// Files and directories, which each client refers to by path or by the
+    /// handles it opened
+    pub mod filesystem {
+        fn open(path: String, flags: OpenFlags) -> Result<u64, FsError>;
+        /// Read up to `len` bytes from the current position, where fewer bytes
+        /// are only returned at the end of the file
+        fn read(handle: u64, len: u64) -> Result<Vec<u8>, FsError>;
+        /// Write `data` at the current position, replying with the number of
+        /// bytes written
+        fn write(handle: u64, data: Vec<u8>) -> Result<u64, FsError>;
+        /// Reply with the new position from the start of the file
+        fn seek(handle: u64, from: SeekFrom) -> Result<u64, FsError>;
+        fn close(handle: u64) -> Result<(), FsError>;
+        fn handle_metadata(handle: u64) -> Result<Metadata, FsError>;
+        fn metadata(path: String) -> Result<Metadata, FsError>;
+        fn read_dir(path: String) -> Result<Vec<DirEntry>, FsError>;
+        fn create_dir(path: String) -> Result<(), FsError>;
+        fn remove_file(path: String) -> Result<(), FsError>;
+    }
+}
@@ -0,0 +1,247 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Small integers standing in for capabilities, like file descriptors
+//!
+//! Each task has a table of handles, numbered from 0 with the lowest free
+//! number used first, which starts out with [`STDIN`], [`STDOUT`], and
+//! [`STDERR`] open. A parent passes handles on to a child with
+//! [`Vmspace::grant_handle`](crate::vmspace::Vmspace::grant_handle), so it can
+//! decide what the child's stdio is connected to. Handles which weren't passed
+//! on by the parent are connected to the `stdio` capability, if there is one.
+//!
+//! Handles made with [`pipe`] can be [`read`] from and [`write`]n to, so a
+//! shell can connect one task's [`STDOUT`] to another's [`STDIN`]. Handles
+//! open to a channel, like the `stdio` capability, can be written to as well,
+//! with each write sent as [`Framed`] frames.
+//!
+//! The kernel can't duplicate capabilities yet, so handles made with [`dup`]
+//! refer to the same capability as the original. [`close`] only removes the
+//! handle from the table, except for the last handle to one end of a pipe,
+//! which closes that end too.
+
+use crate::{
+    ipc::{ChannelReadFlags, FrameError, Framed, IpcChannel},
+    sync::SyncRefCell,
+};
+use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
+use librust::{
+    capabilities::{Capability, CapabilityDescription, CapabilityPtr, CapabilityRights, CapabilityWithDescription},
+    error::SyscallError,
+    syscalls::pipe::{self, PipeFlags},
+};
+
+pub const STDIN: Handle = Handle(0);
+pub const STDOUT: Handle = Handle(1);
+pub const STDERR: Handle = Handle(2);
+
+/// The name of the bootstrap capability slot `handle` is passed on in
+pub(crate) fn slot_name(handle: Handle) -> String {
+    format!("handle.{}", handle.0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct Handle(usize);
+
+impl Handle {
+    pub const fn new(n: usize) -> Self {
+        Self(n)
+    }
+
+    pub const fn value(self) -> usize {
+        self.0
+    }
+}
+
+impl core::fmt::Display for Handle {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandleError {
+    /// Nothing is open under the handle
+    NotOpen,
+    /// The handle is open, but not to a channel
+    NotAChannel,
+    /// The handle is open, but not to a pipe
+    NotAPipe,
+    Syscall(SyscallError),
+    Frame(FrameError),
+}
+
+impl From<SyscallError> for HandleError {
+    fn from(error: SyscallError) -> Self {
+        Self::Syscall(error)
+    }
+}
+
+impl From<FrameError> for HandleError {
+    fn from(error: FrameError) -> Self {
+        match error {
+            FrameError::Syscall(error) => Self::Syscall(error),
+            error => Self::Frame(error),
+        }
+    }
+}
+
+static HANDLES: SyncRefCell<BTreeMap<Handle, CapabilityWithDescription>> = SyncRefCell::new(BTreeMap::new());
+/// The framing of every channel which has been written to, kept between writes
+/// so the credits the other side hands back are counted
+static FRAMED: SyncRefCell<BTreeMap<CapabilityPtr, Framed>> = SyncRefCell::new(BTreeMap::new());
+
+/// Open a new handle to `capability`, returning the lowest free one
+pub fn open(capability: CapabilityWithDescription) -> Handle {
+    let mut handles = HANDLES.borrow_mut();
+    let handle = lowest_free(&handles);
+    handles.insert(handle, capability);
+
+    handle
+}
+
+/// The capability open under `handle`
+pub fn get(handle: Handle) -> Result<CapabilityWithDescription, HandleError> {
+    HANDLES.borrow().get(&handle).copied().ok_or(HandleError::NotOpen)
+}
+
+/// The channel open under `handle`
+pub fn channel(handle: Handle) -> Result<IpcChannel, HandleError> {
+    let capability = get(handle)?;
+    match capability.description {
+        CapabilityDescription::Channel => Ok(IpcChannel::new(capability.capability.cptr)),
+        _ => Err(HandleError::NotAChannel),
+    }
+}
+
+/// Open another handle to the capability open under `handle`, returning the
+/// lowest free one
+pub fn dup(handle: Handle) -> Result<Handle, HandleError> {
+    Ok(open(get(handle)?))
+}
+
+/// Create a pipe holding up to `capacity` bytes (or the default, if it's 0),
+/// returning handles to its read end and write end
+pub fn pipe(capacity: usize) -> Result<(Handle, Handle), HandleError> {
+    let (read_end, write_end) = pipe::create_pipe(capacity)?;
+    let read_end = open(CapabilityWithDescription {
+        capability: Capability { cptr: read_end, rights: CapabilityRights::READ | CapabilityRights::GRANT },
+        description: CapabilityDescription::Pipe,
+    });
+    let write_end = open(CapabilityWithDescription {
+        capability: Capability { cptr: write_end, rights: CapabilityRights::WRITE | CapabilityRights::GRANT },
+        description: CapabilityDescription::Pipe,
+    });
+
+    Ok((read_end, write_end))
+}
+
+/// Read up to `buffer.len()` bytes from the pipe open under `handle`, waiting
+/// until there's something to read. Returns 0 at end of file.
+pub fn read(handle: Handle, buffer: &mut [u8]) -> Result<usize, HandleError> {
+    Ok(pipe::read_pipe(pipe_end(handle)?, buffer, PipeFlags::NONE)?)
+}
+
+/// Write all of `data` to the pipe or channel open under `handle`, waiting for
+/// room as needed
+pub fn write(handle: Handle, mut data: &[u8]) -> Result<(), HandleError> {
+    let capability = get(handle)?;
+    let cptr = capability.capability.cptr;
+    match capability.description {
+        CapabilityDescription::Pipe => {
+            while !data.is_empty() {
+                let written = pipe::write_pipe(cptr, data, PipeFlags::NONE)?;
+                data = &data[written..];
+            }
+        }
+        CapabilityDescription::Channel => {
+            let mut framed = FRAMED.borrow_mut();
+            let framed = framed.entry(cptr).or_insert_with(|| Framed::new(IpcChannel::new(cptr)));
+            for frame in data.chunks(framed.max_frame_len()) {
+                framed.send(frame, &[], ChannelReadFlags::NONE)?;
+            }
+        }
+        _ => return Err(HandleError::NotAPipe),
+    }
+
+    Ok(())
+}
+
+fn pipe_end(handle: Handle) -> Result<CapabilityPtr, HandleError> {
+    let capability = get(handle)?;
+    match capability.description {
+        CapabilityDescription::Pipe => Ok(capability.capability.cptr),
+        _ => Err(HandleError::NotAPipe),
+    }
+}
+
+/// Open `to` to the capability open under `handle`, closing whatever `to` was
+/// open to first. Does nothing if they're the same handle.
+pub fn dup2(handle: Handle, to: Handle) -> Result<(), HandleError> {
+    let capability = get(handle)?;
+    HANDLES.borrow_mut().insert(to, capability);
+
+    Ok(())
+}
+
+/// Close `handle`, returning the capability it was open to so it can be
+/// reopened. If it was the last handle to one end of a pipe, that end is
+/// closed too, and the capability returned can't be reopened.
+pub fn close(handle: Handle) -> Result<CapabilityWithDescription, HandleError> {
+    let mut handles = HANDLES.borrow_mut();
+    let capability = handles.remove(&handle).ok_or(HandleError::NotOpen)?;
+
+    let cptr = capability.capability.cptr;
+    let last = !handles.values().any(|open| open.capability.cptr == cptr);
+    if matches!(capability.description, CapabilityDescription::Pipe) && last {
+        pipe::close_pipe(cptr)?;
+    }
+
+    if last {
+        FRAMED.borrow_mut().remove(&cptr);
+    }
+
+    Ok(capability)
+}
+
+/// Every open handle, in order
+pub fn handles() -> Vec<(Handle, CapabilityWithDescription)> {
+    HANDLES.borrow().iter().map(|(&handle, &capability)| (handle, capability)).collect()
+}
+
+fn lowest_free(handles: &BTreeMap<Handle, CapabilityWithDescription>) -> Handle {
+    // Handles are in order, so the first gap is the lowest free one
+    let mut next = 0;
+    for handle in handles.keys() {
+        if handle.0 != next {
+            break;
+        }
+
+        next += 1;
+    }
+
+    Handle(next)
+}
+
+/// Open the handles the parent passed on, and connect the stdio handles it
+/// didn't to the `stdio` capability
+pub(crate) fn init(capabilities: &BTreeMap<String, CapabilityWithDescription>) {
+    let mut handles = HANDLES.borrow_mut();
+
+    for (name, &capability) in capabilities {
+        if let Some(n) = name.strip_prefix("handle.").and_then(|n| n.parse().ok()) {
+            handles.insert(Handle(n), capability);
+        }
+    }
+
+    if let Some(&stdio) = capabilities.get("stdio") {
+        for handle in [STDIN, STDOUT, STDERR] {
+            handles.entry(handle).or_insert(stdio);
+        }
+    }
+}
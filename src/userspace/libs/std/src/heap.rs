@@ -5,6 +5,8 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
+pub mod profiler;
+
 use crate::sync::SyncRefCell;
 use core::{
     alloc::{AllocError, Allocator, GlobalAlloc, Layout},
@@ -128,13 +130,18 @@ struct GlobalTaskLocalAllocator;
 unsafe impl GlobalAlloc for GlobalTaskLocalAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         match TASK_LOCAL_ALLOCATOR.borrow().allocate(layout) {
-            Ok(ptr) => ptr.as_ptr() as *mut u8,
+            Ok(ptr) => {
+                let ptr = ptr.as_ptr() as *mut u8;
+                profiler::allocated(ptr, layout.size());
+                ptr
+            }
             Err(_) => core::ptr::null_mut(),
         }
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         if !ptr.is_null() {
+            profiler::deallocated(ptr);
             TASK_LOCAL_ALLOCATOR.borrow().deallocate(NonNull::new_unchecked(ptr), layout)
         }
     }
@@ -0,0 +1,296 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A sampling heap profiler
+//!
+//! Once [`enable`]d, one in every `sample_rate` allocations has its call stack
+//! recorded by walking the frame pointer chain, and is aggregated with other
+//! allocations made from the same call stack. Sampled allocations are tracked
+//! until they're freed, so [`report`] shows both how much each site has
+//! allocated overall and how much of it is still live.
+//!
+//! Everything the profiler keeps lives in fixed size tables, since it runs
+//! inside of the global allocator and can't allocate itself. Samples which
+//! don't fit are counted as dropped instead.
+
+use crate::sync::SyncRefCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Call stack depth recorded for each sample
+const MAX_FRAMES: usize = 12;
+/// Number of distinct call stacks which can be told apart
+const MAX_SITES: usize = 256;
+/// Number of sampled allocations which can be live at once, one slot of which
+/// is always left empty
+const MAX_TRACKED: usize = 1024;
+/// Frame pointers further apart than this are assumed to be garbage, which
+/// ends the walk rather than reading from wherever they point
+const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// Set while the profiler is enabled or still tracking allocations, so the
+/// allocator can skip it entirely otherwise
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static PROFILER: SyncRefCell<Profiler> = SyncRefCell::new(Profiler::new());
+
+extern "C" {
+    static __ehdr_start: u8;
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Site {
+    frames: [usize; MAX_FRAMES],
+    allocations: usize,
+    bytes: usize,
+    live_allocations: usize,
+    live_bytes: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Tracked {
+    ptr: usize,
+    size: usize,
+    site: usize,
+}
+
+struct Profiler {
+    enabled: bool,
+    sample_rate: usize,
+    countdown: usize,
+    sites: [Option<Site>; MAX_SITES],
+    tracked: [Option<Tracked>; MAX_TRACKED],
+    n_tracked: usize,
+    dropped: usize,
+}
+
+impl Profiler {
+    const fn new() -> Self {
+        Self {
+            enabled: false,
+            sample_rate: 1,
+            countdown: 1,
+            sites: [None; MAX_SITES],
+            tracked: [None; MAX_TRACKED],
+            n_tracked: 0,
+            dropped: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.sites.iter_mut().for_each(|site| *site = None);
+        self.tracked.iter_mut().for_each(|tracked| *tracked = None);
+        self.n_tracked = 0;
+        self.dropped = 0;
+    }
+
+    fn slot(ptr: usize) -> usize {
+        // Allocations are at least 8 byte aligned, so the low bits are useless
+        (ptr >> 3).wrapping_mul(0x9E37_79B9_7F4A_7C15) % MAX_TRACKED
+    }
+
+    fn allocated(&mut self, ptr: usize, size: usize) {
+        self.countdown -= 1;
+        if self.countdown > 0 {
+            return;
+        }
+
+        self.countdown = self.sample_rate;
+
+        if self.n_tracked == MAX_TRACKED - 1 {
+            self.dropped += 1;
+            return;
+        }
+
+        let mut frames = [0; MAX_FRAMES];
+        backtrace(&mut frames);
+
+        let site = match self.sites.iter().position(|site| matches!(site, Some(site) if site.frames == frames)) {
+            Some(site) => site,
+            None => match self.sites.iter().position(Option::is_none) {
+                Some(empty) => {
+                    self.sites[empty] =
+                        Some(Site { frames, allocations: 0, bytes: 0, live_allocations: 0, live_bytes: 0 });
+                    empty
+                }
+                None => {
+                    self.dropped += 1;
+                    return;
+                }
+            },
+        };
+
+        let entry = self.sites[site].as_mut().unwrap();
+        entry.allocations += 1;
+        entry.bytes += size;
+        entry.live_allocations += 1;
+        entry.live_bytes += size;
+
+        let mut slot = Self::slot(ptr);
+        while self.tracked[slot].is_some() {
+            slot = (slot + 1) % MAX_TRACKED;
+        }
+
+        self.tracked[slot] = Some(Tracked { ptr, size, site });
+        self.n_tracked += 1;
+    }
+
+    fn deallocated(&mut self, ptr: usize) {
+        let mut slot = Self::slot(ptr);
+        loop {
+            match self.tracked[slot] {
+                Some(tracked) if tracked.ptr == ptr => break,
+                Some(_) => slot = (slot + 1) % MAX_TRACKED,
+                None => return,
+            }
+        }
+
+        let tracked = self.tracked[slot].take().unwrap();
+        self.n_tracked -= 1;
+
+        if let Some(site) = &mut self.sites[tracked.site] {
+            site.live_allocations -= 1;
+            site.live_bytes -= tracked.size;
+        }
+
+        // Shift back any entries after the removed one which would no longer
+        // be reachable from their home slot, so lookups can keep stopping at
+        // the first empty slot
+        let mut next = slot;
+        loop {
+            next = (next + 1) % MAX_TRACKED;
+            let home = match self.tracked[next] {
+                Some(tracked) => Self::slot(tracked.ptr),
+                None => break,
+            };
+
+            let reachable = match slot <= next {
+                true => slot < home && home <= next,
+                false => slot < home || home <= next,
+            };
+
+            if !reachable {
+                self.tracked[slot] = self.tracked[next].take();
+                slot = next;
+            }
+        }
+    }
+}
+
+/// Walk the frame pointer chain, filling `frames` with return addresses
+/// relative to the start of the executable so they can be looked up in the
+/// ELF file directly
+#[inline(never)]
+fn backtrace(frames: &mut [usize; MAX_FRAMES]) {
+    let base = unsafe { core::ptr::addr_of!(__ehdr_start) } as usize;
+    let mut fp: usize;
+    unsafe { core::arch::asm!("mv {}, s0", out(reg) fp) };
+
+    for frame in frames.iter_mut() {
+        if fp == 0 || fp % 8 != 0 {
+            break;
+        }
+
+        // The return address and the caller's frame pointer are stored just
+        // below where the frame pointer points
+        let (ra, next) = unsafe { (*(fp as *const usize).sub(1), *(fp as *const usize).sub(2)) };
+        if ra == 0 {
+            break;
+        }
+
+        *frame = ra.wrapping_sub(base);
+
+        // The stack grows down, so callers always have higher frame pointers
+        if next <= fp || next - fp > MAX_FRAME_SIZE {
+            break;
+        }
+
+        fp = next;
+    }
+}
+
+/// Start sampling one in every `sample_rate` allocations, clearing anything
+/// recorded previously
+pub fn enable(sample_rate: usize) {
+    let mut profiler = PROFILER.borrow_mut();
+    profiler.clear();
+    profiler.enabled = true;
+    profiler.sample_rate = sample_rate.max(1);
+    profiler.countdown = profiler.sample_rate;
+
+    ACTIVE.store(true, Ordering::Relaxed);
+}
+
+/// Stop sampling new allocations. Allocations which were already sampled are
+/// still tracked until they're freed.
+pub fn disable() {
+    let mut profiler = PROFILER.borrow_mut();
+    profiler.enabled = false;
+
+    ACTIVE.store(profiler.n_tracked > 0, Ordering::Relaxed);
+}
+
+pub(crate) fn allocated(ptr: *mut u8, size: usize) {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return;
+    }
+
+    // The profiler is already borrowed if it's allocating, e.g. while copying
+    // out a report, which isn't sampled
+    if let Ok(mut profiler) = PROFILER.try_borrow_mut() {
+        if profiler.enabled {
+            profiler.allocated(ptr as usize, size);
+        }
+    }
+}
+
+pub(crate) fn deallocated(ptr: *mut u8) {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return;
+    }
+
+    if let Ok(mut profiler) = PROFILER.try_borrow_mut() {
+        profiler.deallocated(ptr as usize);
+        if !profiler.enabled && profiler.n_tracked == 0 {
+            ACTIVE.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Print every allocation site seen so far, largest live size first. Sizes are
+/// estimates, scaled up from the sampled allocations by the sample rate.
+pub fn report() {
+    let (sample_rate, dropped, mut sites) = {
+        let profiler = PROFILER.borrow();
+        let sites = profiler.sites.iter().flatten().copied().collect::<Vec<_>>();
+        (profiler.sample_rate, profiler.dropped, sites)
+    };
+
+    sites.sort_by(|a, b| b.live_bytes.cmp(&a.live_bytes).then(b.bytes.cmp(&a.bytes)));
+
+    crate::println!(
+        "heap profile: {} sites, sampling 1 in {} allocations, {} samples dropped",
+        sites.len(),
+        sample_rate,
+        dropped
+    );
+
+    for (i, site) in sites.iter().enumerate() {
+        crate::println!(
+            "site {}: {} live bytes in {} allocations, {} bytes in {} allocations total",
+            i,
+            site.live_bytes * sample_rate,
+            site.live_allocations * sample_rate,
+            site.bytes * sample_rate,
+            site.allocations * sample_rate,
+        );
+
+        for (depth, frame) in site.frames.iter().take_while(|frame| **frame != 0).enumerate() {
+            crate::println!("    #{} {:#x}", depth, frame);
+        }
+    }
+
+    crate::io::Stdout.flush();
+}
@@ -6,53 +6,66 @@
 // obtain one at https://mozilla.org/MPL/2.0/.
 
 use crate::sync::SyncRefCell;
-use librust::{
-    capabilities::{Capability, CapabilityRights},
-    mem::MemoryAllocation,
-    syscalls::channel::ChannelMessage,
-    units::Bytes,
-};
+use librust::syscalls::io::debug_print;
 
-pub(crate) struct StdoutInner(SyncRefCell<Option<(usize, MemoryAllocation)>>);
+/// Buffered output is written out once it reaches this many bytes, even if it
+/// doesn't contain a newline
+const STDOUT_BUFFER_SIZE: usize = 1024;
+
+pub(crate) struct StdoutInner(SyncRefCell<Vec<u8>>);
 
 impl StdoutInner {
     pub const fn new() -> Self {
-        Self(SyncRefCell::new(None))
+        Self(SyncRefCell::new(Vec::new()))
     }
 }
 
 static STDOUT: StdoutInner = StdoutInner::new();
 
+/// Standard output, which is line buffered so each complete line is written
+/// out with a single syscall
 pub struct Stdout;
+
+impl Stdout {
+    /// Write out anything still sitting in the buffer
+    pub fn flush(&mut self) {
+        // If the buffer is already borrowed this is a panic from inside
+        // `write_str`, whatever was buffered is lost at that point
+        if let Ok(mut buffer) = STDOUT.0.try_borrow_mut() {
+            if !buffer.is_empty() {
+                let _ = debug_print(&buffer);
+                buffer.clear();
+            }
+        }
+    }
+}
+
 impl core::fmt::Write for Stdout {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        // let stdio = match crate::env::lookup_capability("stdio") {
-        //     Some(stdio) => crate::ipc::IpcChannel::new(stdio.capability.cptr),
-        //     None => return Ok(()),
-        // };
-
-        // let mut inner = STDOUT.0.borrow_mut();
-
-        // if inner.is_none() {
-        //     *inner = Some((0, MemoryAllocation::public_rw(Bytes(4096)).expect("failed to allocate memory for stdout")));
-        // }
-
-        // let (position, mem) = inner.as_mut().unwrap();
-        // let cptr = mem.cptr;
-        // // SAFETY: we don't ever copy the pointer out
-        // let buffer = unsafe { mem.as_mut() };
-        // for byte in s.bytes() {
-        //     buffer[*position] = byte;
-
-        //     if byte == b'\n' || *position == buffer.len() - 1 {
-        //         let msg = ChannelMessage([1, *position, 0, 0, 0, 0, 0]);
-        //         let _ = stdio.send(msg, &[Capability { cptr, rights: CapabilityRights::READ }]);
-        //     } else {
-        //         *position += 1;
-        //     }
-        // }
-
-        let _ = librust::syscalls::io::debug_print(s.as_bytes());
+        let mut buffer = match STDOUT.0.try_borrow_mut() {
+            Ok(buffer) => buffer,
+            Err(_) => {
+                let _ = debug_print(s.as_bytes());
+                return Ok(());
+            }
+        };
+
+        let start = buffer.len();
+        buffer.extend_from_slice(s.as_bytes());
+
+        let flush_to = match s.rfind('\n') {
+            _ if buffer.len() >= STDOUT_BUFFER_SIZE => buffer.len(),
+            Some(newline) => start + newline + 1,
+            None => return Ok(()),
+        };
+
+        let _ = debug_print(&buffer[..flush_to]);
+        buffer.drain(..flush_to);
+
         Ok(())
     }
 }
+
+pub fn stdout() -> Stdout {
+    Stdout
+}
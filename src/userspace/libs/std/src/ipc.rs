@@ -17,7 +17,41 @@ use librust::{
 pub use librust::capabilities::{
     Capability, CapabilityDescription, CapabilityPtr, CapabilityRights, CapabilityWithDescription,
 };
-pub use librust::syscalls::channel::{ChannelMessage, ChannelReadFlags};
+pub use librust::syscalls::channel::{ChannelMessage, ChannelReadFlags, ChannelSendFlags};
+
+mod framed;
+pub mod interface;
+mod topic;
+
+pub use framed::{BufferedChannel, Frame, FrameError, Framed};
+pub use topic::{LagPolicy, Topic};
+
+/// Serialized messages which fit in the message words following the header
+/// word are sent inline, anything larger is sent in a memory capability
+const INLINE_PAYLOAD_LEN: usize = 6 * core::mem::size_of::<usize>();
+const INLINE_PAYLOAD_FLAG: usize = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpcError {
+    Syscall(SyscallError),
+    Decode(wire::DecodeError),
+    MissingPayload,
+    UnexpectedMessage,
+    /// The server couldn't decode the request
+    Rejected(wire::DecodeError),
+}
+
+impl From<SyscallError> for IpcError {
+    fn from(e: SyscallError) -> Self {
+        Self::Syscall(e)
+    }
+}
+
+impl From<wire::DecodeError> for IpcError {
+    fn from(e: wire::DecodeError) -> Self {
+        Self::Decode(e)
+    }
+}
 
 #[derive(Debug)]
 pub struct IpcChannel {
@@ -29,6 +63,10 @@ impl IpcChannel {
         Self { cptr }
     }
 
+    pub fn cptr(&self) -> CapabilityPtr {
+        self.cptr
+    }
+
     pub fn read(
         &self,
         cap_buffer: &mut [CapabilityWithDescription],
@@ -56,6 +94,19 @@ impl IpcChannel {
         channel::send_message(self.cptr, msg, caps)
     }
 
+    /// Send a message which the other side reads before any normal messages
+    /// it has waiting, e.g. to cancel a request it hasn't gotten to yet
+    pub fn send_urgent(&self, msg: ChannelMessage, caps: &[Capability]) -> Result<(), SyscallError> {
+        channel::send_message_with_flags(self.cptr, msg, caps, ChannelSendFlags::URGENT)
+    }
+
+    /// Limit how many messages of each priority can be waiting to be read on
+    /// this side of the channel. Once that many are queued, sends from the
+    /// other side fail with [`SyscallError::WouldBlock`] until some are read.
+    pub fn set_queue_depth(&self, depth: usize) -> Result<(), SyscallError> {
+        channel::set_queue_depth(self.cptr, depth)
+    }
+
     pub fn temp_send_json<T: json::deser::Serialize<Vec<u8>>>(
         &self,
         message: ChannelMessage,
@@ -94,4 +145,97 @@ impl IpcChannel {
 
         Ok((t, msg, caps))
     }
+
+    /// Serialize `t` and send it along with `caps`
+    pub fn send_serialized<T: wire::Serialize + ?Sized>(&self, t: &T, caps: &[Capability]) -> Result<(), SyscallError> {
+        let (message, payload_cap) = encode_message(t)?;
+
+        match payload_cap {
+            Some(payload_cap) => {
+                let mut all_caps = vec![payload_cap];
+                all_caps.extend_from_slice(caps);
+                channel::send_message(self.cptr, message, &all_caps)
+            }
+            None => channel::send_message(self.cptr, message, caps),
+        }
+    }
+
+    /// Read a message sent with [`IpcChannel::send_serialized`], returning the
+    /// deserialized value and any capabilities sent along with it
+    pub fn read_serialized<T: wire::DeserializeOwned>(
+        &self,
+        flags: ChannelReadFlags,
+    ) -> Result<(T, Vec<CapabilityWithDescription>), IpcError> {
+        let (message, mut caps) = self.read_with_all_caps(flags)?;
+        let t = decode_message(&message, &mut caps)?;
+
+        Ok((t, caps))
+    }
+}
+
+/// Serializes `t` into a channel message, returning the memory capability
+/// holding the payload if it was too large to send inline. The capability
+/// must be sent as the first capability of the message.
+pub fn encode_message<T: wire::Serialize + ?Sized>(
+    t: &T,
+) -> Result<(ChannelMessage, Option<Capability>), SyscallError> {
+    let serialized = wire::to_bytes(t);
+    let mut message = ChannelMessage::default();
+
+    if serialized.len() <= INLINE_PAYLOAD_LEN {
+        message.0[0] = (serialized.len() << 1) | INLINE_PAYLOAD_FLAG;
+
+        for (word, chunk) in message.0[1..].iter_mut().zip(serialized.chunks(core::mem::size_of::<usize>())) {
+            let mut bytes = [0; core::mem::size_of::<usize>()];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            *word = usize::from_ne_bytes(bytes);
+        }
+
+        return Ok((message, None));
+    }
+
+    let (cptr, ptr) = librust::syscalls::mem::alloc_virtual_memory(
+        Bytes(serialized.len()),
+        AllocationOptions::NONE,
+        MemoryPermissions::READ | MemoryPermissions::WRITE,
+    )?;
+    unsafe { (*ptr)[..serialized.len()].copy_from_slice(&serialized) };
+    message.0[0] = serialized.len() << 1;
+
+    Ok((message, Some(Capability { cptr, rights: CapabilityRights::READ })))
+}
+
+/// Deserializes a message produced by [`encode_message`], removing the
+/// payload capability from `caps` if there is one
+pub fn decode_message<T: wire::DeserializeOwned>(
+    message: &ChannelMessage,
+    caps: &mut Vec<CapabilityWithDescription>,
+) -> Result<T, IpcError> {
+    let len = message.0[0] >> 1;
+
+    if message.0[0] & INLINE_PAYLOAD_FLAG == INLINE_PAYLOAD_FLAG {
+        if len > INLINE_PAYLOAD_LEN {
+            return Err(IpcError::MissingPayload);
+        }
+
+        let mut bytes = [0; INLINE_PAYLOAD_LEN];
+        for (chunk, word) in bytes.chunks_mut(core::mem::size_of::<usize>()).zip(&message.0[1..]) {
+            chunk.copy_from_slice(&word.to_ne_bytes());
+        }
+
+        return Ok(wire::from_bytes(&bytes[..len])?);
+    }
+
+    match caps.first() {
+        Some(CapabilityWithDescription {
+            description: CapabilityDescription::Memory { ptr, len: region_len, .. },
+            ..
+        }) if *region_len >= len => {
+            let t = wire::from_bytes(unsafe { core::slice::from_raw_parts(*ptr, len) })?;
+            caps.remove(0);
+
+            Ok(t)
+        }
+        _ => Err(IpcError::MissingPayload),
+    }
 }
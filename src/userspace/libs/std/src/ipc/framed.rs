@@ -0,0 +1,534 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Byte-stream style framing on top of channel messages
+//!
+//! Every frame begins with a header word holding the frame kind in the low two
+//! bits and the frame length above them. Small frames are split into
+//! [`CHUNK_LEN`] byte chunks, carried in the remaining words of the header
+//! message and as many continuation messages as needed, while larger frames are
+//! sent in a memory capability attached to the header message.
+//!
+//! Flow control is credit based: each side may have at most `window` frames
+//! in flight, and the receiver hands credits back to the sender as it consumes
+//! frames. Both sides of the channel must agree on the window size.
+
+use super::{
+    Capability, CapabilityDescription, CapabilityRights, CapabilityWithDescription, ChannelMessage, ChannelReadFlags,
+    IpcChannel,
+};
+use crate::collections::VecDeque;
+use librust::{
+    error::SyscallError,
+    syscalls::mem::{AllocationOptions, MemoryPermissions},
+    units::Bytes,
+};
+
+/// Bytes of frame data carried by each message
+pub const CHUNK_LEN: usize = 6 * core::mem::size_of::<usize>();
+/// Frames longer than this are sent in a memory capability instead of being
+/// split across messages
+pub const MAX_CHUNKED_FRAME_LEN: usize = 8 * CHUNK_LEN;
+pub const DEFAULT_MAX_FRAME_LEN: usize = 1024 * 1024;
+pub const DEFAULT_WINDOW: usize = 16;
+
+const KIND_MASK: usize = 0b11;
+const KIND_CHUNKED: usize = 0;
+const KIND_MEMORY: usize = 1;
+const KIND_CONTINUATION: usize = 2;
+const KIND_CREDIT: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    Syscall(SyscallError),
+    /// The frame was longer than the maximum frame length, and was discarded
+    TooLarge {
+        len: usize,
+        max: usize,
+    },
+    /// A frame header said the frame was in a memory capability, but it wasn't
+    MissingPayload,
+    /// A message arrived which doesn't fit the state of the frame being read
+    Malformed,
+}
+
+impl From<SyscallError> for FrameError {
+    fn from(e: SyscallError) -> Self {
+        Self::Syscall(e)
+    }
+}
+
+/// An [`IpcChannel`] which can hold on to messages that have been read but not
+/// yet consumed, so that readers can look ahead for a specific message
+#[derive(Debug)]
+pub struct BufferedChannel {
+    channel: IpcChannel,
+    buffered: VecDeque<(ChannelMessage, Vec<CapabilityWithDescription>)>,
+}
+
+impl BufferedChannel {
+    pub fn new(channel: IpcChannel) -> Self {
+        Self { channel, buffered: VecDeque::new() }
+    }
+
+    pub fn channel(&self) -> &IpcChannel {
+        &self.channel
+    }
+
+    pub fn into_inner(self) -> (IpcChannel, VecDeque<(ChannelMessage, Vec<CapabilityWithDescription>)>) {
+        (self.channel, self.buffered)
+    }
+
+    /// Number of messages which have been read from the channel but not
+    /// consumed
+    pub fn buffered(&self) -> usize {
+        self.buffered.len()
+    }
+
+    pub fn read(
+        &mut self,
+        flags: ChannelReadFlags,
+    ) -> Result<(ChannelMessage, Vec<CapabilityWithDescription>), SyscallError> {
+        match self.buffered.pop_front() {
+            Some(message) => Ok(message),
+            None => self.channel.read_with_all_caps(flags),
+        }
+    }
+
+    /// Read the first message that `f` returns `true` for, buffering any other
+    /// messages that were read in the process
+    pub fn read_matching(
+        &mut self,
+        flags: ChannelReadFlags,
+        mut f: impl FnMut(&ChannelMessage) -> bool,
+    ) -> Result<(ChannelMessage, Vec<CapabilityWithDescription>), SyscallError> {
+        if let Some(index) = self.buffered.iter().position(|(message, _)| f(message)) {
+            return Ok(self.buffered.remove(index).unwrap());
+        }
+
+        loop {
+            let (message, caps) = self.channel.read_with_all_caps(flags)?;
+            match f(&message) {
+                true => break Ok((message, caps)),
+                false => self.buffered.push_back((message, caps)),
+            }
+        }
+    }
+
+    /// Put a message back at the front of the buffer, so it will be the next
+    /// one returned by [`BufferedChannel::read`]
+    pub fn unread(&mut self, message: ChannelMessage, caps: Vec<CapabilityWithDescription>) {
+        self.buffered.push_front((message, caps));
+    }
+
+    pub fn send(&self, message: ChannelMessage, caps: &[Capability]) -> Result<(), SyscallError> {
+        self.channel.send(message, caps)
+    }
+}
+
+/// A received frame, along with any capabilities sent with it
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub data: Vec<u8>,
+    pub caps: Vec<CapabilityWithDescription>,
+}
+
+#[derive(Debug)]
+struct PartialFrame {
+    len: usize,
+    remaining: usize,
+    // `None` when the frame is too large and its chunks are being discarded
+    data: Option<Vec<u8>>,
+    caps: Vec<CapabilityWithDescription>,
+}
+
+/// Sends and receives length-delimited frames over a channel
+#[derive(Debug)]
+pub struct Framed {
+    channel: BufferedChannel,
+    max_frame_len: usize,
+    window: usize,
+    send_credits: usize,
+    unacknowledged: usize,
+    partial: Option<PartialFrame>,
+}
+
+impl Framed {
+    pub fn new(channel: IpcChannel) -> Self {
+        Self::with_limits(channel, DEFAULT_MAX_FRAME_LEN, DEFAULT_WINDOW)
+    }
+
+    pub fn with_limits(channel: IpcChannel, max_frame_len: usize, window: usize) -> Self {
+        assert!(window > 0, "window must allow at least one frame in flight");
+
+        Self {
+            channel: BufferedChannel::new(channel),
+            max_frame_len,
+            window,
+            send_credits: window,
+            unacknowledged: 0,
+            partial: None,
+        }
+    }
+
+    pub fn channel(&self) -> &IpcChannel {
+        self.channel.channel()
+    }
+
+    pub fn max_frame_len(&self) -> usize {
+        self.max_frame_len
+    }
+
+    /// Send `data` as a single frame. If the other side hasn't consumed enough
+    /// of the frames already sent, this waits for it to catch up, unless
+    /// `flags` is nonblocking, in which case it returns
+    /// [`SyscallError::WouldBlock`].
+    pub fn send(&mut self, data: &[u8], caps: &[Capability], flags: ChannelReadFlags) -> Result<(), FrameError> {
+        if data.len() > self.max_frame_len {
+            return Err(FrameError::TooLarge { len: data.len(), max: self.max_frame_len });
+        }
+
+        while self.send_credits == 0 {
+            let (message, _) = self.channel.read_matching(flags, |message| kind(message) == KIND_CREDIT)?;
+            self.send_credits += message.0[0] >> 2;
+        }
+
+        if data.len() > MAX_CHUNKED_FRAME_LEN {
+            let (cptr, ptr) = librust::syscalls::mem::alloc_virtual_memory(
+                Bytes(data.len()),
+                AllocationOptions::NONE,
+                MemoryPermissions::READ | MemoryPermissions::WRITE,
+            )?;
+            unsafe { (*ptr)[..data.len()].copy_from_slice(data) };
+
+            let mut all_caps = vec![Capability { cptr, rights: CapabilityRights::READ }];
+            all_caps.extend_from_slice(caps);
+
+            let mut message = ChannelMessage::default();
+            message.0[0] = (data.len() << 2) | KIND_MEMORY;
+            self.channel.send(message, &all_caps)?;
+        } else {
+            for (i, message) in chunk_frame(data).into_iter().enumerate() {
+                self.channel.send(message, if i == 0 { caps } else { &[] })?;
+            }
+        }
+
+        self.send_credits -= 1;
+
+        Ok(())
+    }
+
+    /// Receive the next frame. A nonblocking read which finds only part of a
+    /// frame keeps what it has read, so the next call resumes where it left
+    /// off.
+    pub fn recv(&mut self, flags: ChannelReadFlags) -> Result<Frame, FrameError> {
+        loop {
+            let (message, caps) = self.channel.read(flags)?;
+
+            let frame = match step(&mut self.partial, self.max_frame_len, &message, caps) {
+                Step::Credits(credits) => {
+                    self.send_credits += credits;
+                    continue;
+                }
+                Step::Incomplete => continue,
+                Step::Complete(frame) => frame,
+                Step::MissingPayload => {
+                    self.frame_consumed()?;
+                    return Err(FrameError::MissingPayload);
+                }
+                Step::Malformed => return Err(FrameError::Malformed),
+            };
+
+            self.frame_consumed()?;
+
+            return match frame.data {
+                Some(data) => Ok(Frame { data, caps: frame.caps }),
+                None => Err(FrameError::TooLarge { len: frame.len, max: self.max_frame_len }),
+            };
+        }
+    }
+
+    fn frame_consumed(&mut self) -> Result<(), SyscallError> {
+        self.unacknowledged += 1;
+
+        // Hand back credits in batches so we aren't sending an extra message
+        // for every frame received
+        if self.unacknowledged >= (self.window / 2).max(1) {
+            let mut message = ChannelMessage::default();
+            message.0[0] = (self.unacknowledged << 2) | KIND_CREDIT;
+            self.channel.send(message, &[])?;
+            self.unacknowledged = 0;
+        }
+
+        Ok(())
+    }
+}
+
+/// What reading a single message did to the frame being received
+#[derive(Debug)]
+enum Step {
+    /// The other side handed back credits for frames it has consumed
+    Credits(usize),
+    /// The message was part of a frame which has more messages to come
+    Incomplete,
+    /// The message finished a frame, whose data is `None` if it was too large
+    Complete(PartialFrame),
+    /// The message was the header of a frame sent in a memory capability, but
+    /// the capability was missing or too small. The frame is still consumed.
+    MissingPayload,
+    /// The message doesn't fit the frame being received, which is discarded
+    Malformed,
+}
+
+/// Fold `message` into the frame being received in `partial`, returning what
+/// became of it
+fn step(
+    partial: &mut Option<PartialFrame>,
+    max_frame_len: usize,
+    message: &ChannelMessage,
+    mut caps: Vec<CapabilityWithDescription>,
+) -> Step {
+    let header = message.0[0];
+
+    let frame = match (header & KIND_MASK, partial.take()) {
+        (KIND_CREDIT, in_progress) => {
+            *partial = in_progress;
+            return Step::Credits(header >> 2);
+        }
+        (KIND_CONTINUATION, Some(mut frame)) => {
+            let len = frame.remaining.min(CHUNK_LEN);
+            if let Some(data) = &mut frame.data {
+                data.extend_from_slice(&decode_chunk(message)[..len]);
+            }
+
+            frame.remaining -= len;
+            frame
+        }
+        (KIND_CHUNKED, None) => {
+            let len = header >> 2;
+            // Anything larger is sent in memory, so this can only come from a
+            // peer trying to keep us reassembling continuations forever
+            if len > MAX_CHUNKED_FRAME_LEN {
+                return Step::Malformed;
+            }
+
+            let in_header = len.min(CHUNK_LEN);
+            let data = match len > max_frame_len {
+                true => None,
+                false => {
+                    let mut data = Vec::with_capacity(len);
+                    data.extend_from_slice(&decode_chunk(message)[..in_header]);
+                    Some(data)
+                }
+            };
+
+            PartialFrame { len, remaining: len - in_header, data, caps }
+        }
+        (KIND_MEMORY, None) => {
+            let len = header >> 2;
+            let (ptr, region_len) = match caps.first().map(|cap| cap.description) {
+                Some(CapabilityDescription::Memory { ptr, len, .. }) => (ptr, len),
+                _ => return Step::MissingPayload,
+            };
+
+            caps.remove(0);
+
+            if region_len < len {
+                return Step::MissingPayload;
+            }
+
+            let data = match len > max_frame_len {
+                true => None,
+                false => Some(unsafe { core::slice::from_raw_parts(ptr, len) }.to_vec()),
+            };
+
+            PartialFrame { len, remaining: 0, data, caps }
+        }
+        _ => return Step::Malformed,
+    };
+
+    match frame.remaining {
+        0 => Step::Complete(frame),
+        _ => {
+            *partial = Some(frame);
+            Step::Incomplete
+        }
+    }
+}
+
+/// Split a frame of at most [`MAX_CHUNKED_FRAME_LEN`] bytes into its header
+/// message and as many continuation messages as it needs
+fn chunk_frame(data: &[u8]) -> Vec<ChannelMessage> {
+    let mut chunks = data.chunks(CHUNK_LEN);
+    let mut header = encode_chunk(chunks.next().unwrap_or(&[]));
+    header.0[0] = (data.len() << 2) | KIND_CHUNKED;
+
+    let mut messages = vec![header];
+    for chunk in chunks {
+        let mut message = encode_chunk(chunk);
+        message.0[0] = KIND_CONTINUATION;
+        messages.push(message);
+    }
+
+    messages
+}
+
+fn kind(message: &ChannelMessage) -> usize {
+    message.0[0] & KIND_MASK
+}
+
+fn encode_chunk(chunk: &[u8]) -> ChannelMessage {
+    let mut message = ChannelMessage::default();
+
+    for (word, bytes) in message.0[1..].iter_mut().zip(chunk.chunks(core::mem::size_of::<usize>())) {
+        let mut word_bytes = [0; core::mem::size_of::<usize>()];
+        word_bytes[..bytes.len()].copy_from_slice(bytes);
+        *word = usize::from_ne_bytes(word_bytes);
+    }
+
+    message
+}
+
+fn decode_chunk(message: &ChannelMessage) -> [u8; CHUNK_LEN] {
+    let mut bytes = [0; CHUNK_LEN];
+    for (chunk, word) in bytes.chunks_mut(core::mem::size_of::<usize>()).zip(&message.0[1..]) {
+        chunk.copy_from_slice(&word.to_ne_bytes());
+    }
+
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+
+    /// Feed `messages` through [`step`] one at a time, returning the frame
+    /// they complete
+    fn reassemble(messages: &[ChannelMessage], max_frame_len: usize) -> Option<Vec<u8>> {
+        let mut partial = None;
+        for (i, message) in messages.iter().enumerate() {
+            match step(&mut partial, max_frame_len, message, Vec::new()) {
+                Step::Incomplete => assert!(i + 1 < messages.len(), "frame ended early"),
+                Step::Complete(frame) => {
+                    assert_eq!(i + 1, messages.len(), "frame completed before its last message");
+                    return frame.data;
+                }
+                step => panic!("unexpected {:?}", step),
+            }
+        }
+
+        panic!("frame never completed")
+    }
+
+    fn bytes(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn chunked_frames_round_trip_at_chunk_boundaries() {
+        for len in [0, 1, CHUNK_LEN - 1, CHUNK_LEN, CHUNK_LEN + 1, 2 * CHUNK_LEN, MAX_CHUNKED_FRAME_LEN] {
+            let data = bytes(len);
+            let messages = chunk_frame(&data);
+
+            assert_eq!(messages.len(), len.saturating_sub(1) / CHUNK_LEN + 1, "message count for {} bytes", len);
+            assert_eq!(reassemble(&messages, DEFAULT_MAX_FRAME_LEN).as_deref(), Some(&data[..]), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn headers_hold_the_length_and_kind() {
+        let messages = chunk_frame(&bytes(CHUNK_LEN + 1));
+
+        assert_eq!(kind(&messages[0]), KIND_CHUNKED);
+        assert_eq!(messages[0].0[0] >> 2, CHUNK_LEN + 1);
+        assert_eq!(kind(&messages[1]), KIND_CONTINUATION);
+    }
+
+    #[test]
+    fn frames_over_the_limit_are_discarded_whole() {
+        let len = 3 * CHUNK_LEN;
+        let messages = chunk_frame(&bytes(len));
+
+        // Every chunk is still consumed, so the next frame starts cleanly
+        assert_eq!(reassemble(&messages, len - 1), None);
+        assert_eq!(reassemble(&messages, len).map(|data| data.len()), Some(len));
+    }
+
+    #[test]
+    fn credits_between_chunks_keep_the_partial_frame() {
+        let data = bytes(2 * CHUNK_LEN);
+        let messages = chunk_frame(&data);
+        let mut credit = ChannelMessage::default();
+        credit.0[0] = (3 << 2) | KIND_CREDIT;
+
+        let mut partial = None;
+        assert!(matches!(step(&mut partial, DEFAULT_MAX_FRAME_LEN, &messages[0], Vec::new()), Step::Incomplete));
+        assert!(matches!(step(&mut partial, DEFAULT_MAX_FRAME_LEN, &credit, Vec::new()), Step::Credits(3)));
+        match step(&mut partial, DEFAULT_MAX_FRAME_LEN, &messages[1], Vec::new()) {
+            Step::Complete(frame) => assert_eq!(frame.data, Some(data)),
+            step => panic!("unexpected {:?}", step),
+        }
+    }
+
+    #[test]
+    fn continuations_without_a_header_are_malformed() {
+        let messages = chunk_frame(&bytes(CHUNK_LEN + 1));
+
+        let mut partial = None;
+        assert!(matches!(step(&mut partial, DEFAULT_MAX_FRAME_LEN, &messages[1], Vec::new()), Step::Malformed));
+    }
+
+    #[test]
+    fn a_new_header_mid_frame_is_malformed() {
+        let messages = chunk_frame(&bytes(CHUNK_LEN + 1));
+
+        let mut partial = None;
+        assert!(matches!(step(&mut partial, DEFAULT_MAX_FRAME_LEN, &messages[0], Vec::new()), Step::Incomplete));
+        assert!(matches!(step(&mut partial, DEFAULT_MAX_FRAME_LEN, &messages[0], Vec::new()), Step::Malformed));
+        assert!(partial.is_none());
+    }
+
+    #[test]
+    fn chunked_headers_over_the_chunked_limit_are_malformed() {
+        let mut header = chunk_frame(&bytes(CHUNK_LEN)).remove(0);
+        header.0[0] = ((MAX_CHUNKED_FRAME_LEN + 1) << 2) | KIND_CHUNKED;
+
+        let mut partial = None;
+        assert!(matches!(step(&mut partial, usize::MAX, &header, Vec::new()), Step::Malformed));
+        assert!(partial.is_none());
+    }
+
+    #[test]
+    fn memory_frames_need_a_large_enough_payload() {
+        let data = bytes(MAX_CHUNKED_FRAME_LEN + 1);
+        let mut header = ChannelMessage::default();
+        header.0[0] = (data.len() << 2) | KIND_MEMORY;
+        let payload = |len| CapabilityWithDescription {
+            capability: Capability::default(),
+            description: CapabilityDescription::Memory {
+                ptr: data.as_ptr() as *mut u8,
+                len,
+                permissions: MemoryPermissions::READ,
+            },
+        };
+
+        let mut partial = None;
+        assert!(matches!(step(&mut partial, DEFAULT_MAX_FRAME_LEN, &header, Vec::new()), Step::MissingPayload));
+        assert!(matches!(
+            step(&mut partial, DEFAULT_MAX_FRAME_LEN, &header, vec![payload(data.len() - 1)]),
+            Step::MissingPayload
+        ));
+        match step(&mut partial, DEFAULT_MAX_FRAME_LEN, &header, vec![payload(data.len())]) {
+            Step::Complete(frame) => {
+                assert_eq!(frame.data, Some(data.clone()));
+                assert!(frame.caps.is_empty());
+            }
+            step => panic!("unexpected {:?}", step),
+        }
+    }
+}
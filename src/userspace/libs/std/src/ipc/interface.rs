@@ -0,0 +1,259 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Interface definitions for IPC protocols
+//!
+//! [`interface!`](crate::interface) takes a list of methods and generates a
+//! module containing:
+//!
+//! * `Request` and `Response` enums, serialized with `wire`
+//! * `Client`, a proxy with a blocking method for each call
+//! * `Server`, a trait for the server side to implement
+//! * `handle`, which passes a request to the matching method of a `Server`
+//! * `dispatch`, which reads a single request from a channel and replies to it
+//! * `serve`, which dispatches requests on any channel that receives a message
+//!
+//! A request the server can't decode is answered with `Response::Error`, which
+//! the client returns as [`IpcError::Rejected`](crate::ipc::IpcError::Rejected)
+//! rather than waiting on a reply that will never come.
+//!
+//! Methods marked with `[caps]` transfer capabilities in both directions: the
+//! client passes a slice of capabilities along with the arguments and receives
+//! the capabilities the server replied with, and vice versa.
+//!
+//! Servers which keep state for each client implement `Server::caller`, which
+//! is told the channel each request arrived on before it's handled, and
+//! `Server::disconnected`, which is told when a reply couldn't be delivered
+//! because the client's end of the channel is gone.
+//!
+//! The macro lives here rather than in `idl` so that the protocols `std`
+//! itself speaks, like the filesystem's, can be defined with it.
+//!
+//! ```ignore
+//! std::interface! {
+//!     pub mod devicemgr {
+//!         fn request_devices(compatible: Vec<String>) -> Vec<Device> [caps];
+//!     }
+//! }
+//! ```
+
+#[doc(hidden)]
+pub mod __private {
+    pub use crate::ipc::{ChannelReadFlags, IpcChannel, IpcError};
+    pub use alloc::vec::Vec;
+    pub use librust::{
+        capabilities::{Capability, CapabilityPtr, CapabilityWithDescription},
+        error::SyscallError,
+        syscalls::{
+            channel::{read_kernel_message, KernelMessage},
+            task::enable_notifications,
+        },
+    };
+    pub use wire;
+}
+
+#[macro_export]
+macro_rules! interface {
+    (
+        $(#[$attr:meta])*
+        $vis:vis mod $name:ident {
+            $(
+                $(#[$mattr:meta])*
+                fn $method:ident($($arg:ident: $t:ty),* $(,)?) $(-> $ret:ty)? $([$caps:ident])?;
+            )+
+        }
+    ) => {
+        $(#[$attr])*
+        $vis mod $name {
+            #![allow(non_camel_case_types)]
+
+            #[allow(unused_imports)]
+            use super::*;
+            use $crate::ipc::interface::__private::{
+                wire::DecodeError, Capability, CapabilityPtr, CapabilityWithDescription, ChannelReadFlags,
+                IpcChannel, IpcError, SyscallError, Vec,
+            };
+
+            $crate::ipc::interface::__private::wire::derive! {
+                pub enum Request {
+                    $($method { $($arg: $t),* }),+
+                }
+            }
+
+            $crate::ipc::interface::__private::wire::derive! {
+                pub enum Response {
+                    $($method($crate::interface!(@ret $($ret)?)),)+
+                    Error(DecodeError),
+                }
+            }
+
+            pub struct Client {
+                channel: IpcChannel,
+            }
+
+            impl Client {
+                pub fn new(cptr: CapabilityPtr) -> Self {
+                    Self { channel: IpcChannel::new(cptr) }
+                }
+
+                pub fn channel(&self) -> &IpcChannel {
+                    &self.channel
+                }
+
+                $(
+                    $crate::interface!(@client [$($caps)?] $(#[$mattr])* fn $method($($arg: $t),*) -> ($($ret)?));
+                )+
+            }
+
+            pub trait Server {
+                /// Called with the channel each request arrived on before the
+                /// request is handled
+                fn caller(&mut self, _cptr: CapabilityPtr) {}
+
+                /// Called when the reply to a request couldn't be sent since
+                /// the client's end of the channel is gone, so anything kept
+                /// for it can be dropped
+                fn disconnected(&mut self, _cptr: CapabilityPtr) {}
+
+                $(
+                    $crate::interface!(@server [$($caps)?] $(#[$mattr])* fn $method($($arg: $t),*) -> ($($ret)?));
+                )+
+            }
+
+            /// Pass `request` to the matching method of `server`, returning
+            /// the response and the capabilities to send along with it
+            pub fn handle<S: Server>(
+                server: &mut S,
+                request: Request,
+                caps: Vec<CapabilityWithDescription>,
+            ) -> (Response, Vec<Capability>) {
+                match request {
+                    $(
+                        Request::$method { $($arg),* } => {
+                            $crate::interface!(@dispatch [$($caps)?] server, caps, $method($($arg),*))
+                        }
+                    )+
+                }
+            }
+
+            /// Read a single request from `channel` and send the server's
+            /// response back over it
+            pub fn dispatch<S: Server>(
+                server: &mut S,
+                channel: &IpcChannel,
+                flags: ChannelReadFlags,
+            ) -> Result<(), IpcError> {
+                let error = match channel.read_serialized::<Request>(flags) {
+                    Ok((request, caps)) => {
+                        server.caller(channel.cptr());
+                        let (response, caps) = handle(server, request, caps);
+                        return reply(server, channel, &response, &caps);
+                    }
+                    Err(IpcError::Decode(e)) => e,
+                    Err(IpcError::MissingPayload) => DecodeError::UnexpectedEnd,
+                    Err(e) => return Err(e),
+                };
+
+                // The client is waiting on a reply either way
+                reply(server, channel, &Response::Error(error), &[])?;
+                Err(IpcError::Decode(error))
+            }
+
+            fn reply<S: Server>(
+                server: &mut S,
+                channel: &IpcChannel,
+                response: &Response,
+                caps: &[Capability],
+            ) -> Result<(), IpcError> {
+                match channel.send_serialized(response, caps) {
+                    Ok(()) => Ok(()),
+                    Err(e @ SyscallError::InvalidOperation(0)) => {
+                        server.disconnected(channel.cptr());
+                        Err(e.into())
+                    }
+                    Err(e) => Err(e.into()),
+                }
+            }
+
+            /// Handle requests as they arrive on any of the task's channels
+            pub fn serve<S: Server>(server: &mut S) -> ! {
+                $crate::ipc::interface::__private::enable_notifications();
+
+                loop {
+                    let cptr = match $crate::ipc::interface::__private::read_kernel_message() {
+                        $crate::ipc::interface::__private::KernelMessage::NewChannelMessage(cptr) => cptr,
+                        _ => continue,
+                    };
+
+                    // A malformed request has already been answered with an
+                    // error, so there's nothing to do other than move on to
+                    // the next one
+                    let _ = dispatch(server, &IpcChannel::new(cptr), ChannelReadFlags::NONBLOCKING);
+                }
+            }
+        }
+    };
+
+    (@ret) => { () };
+    (@ret $ret:ty) => { $ret };
+
+    (@client [] $(#[$mattr:meta])* fn $method:ident($($arg:ident: $t:ty),*) -> ($($ret:ty)?)) => {
+        $(#[$mattr])*
+        pub fn $method(&self, $($arg: $t),*) -> Result<$crate::interface!(@ret $($ret)?), IpcError> {
+            self.channel.send_serialized(&Request::$method { $($arg),* }, &[])?;
+
+            match self.channel.read_serialized::<Response>(ChannelReadFlags::NONE)? {
+                (Response::$method(ret), _) => Ok(ret),
+                (Response::Error(e), _) => Err(IpcError::Rejected(e)),
+                #[allow(unreachable_patterns)]
+                _ => Err(IpcError::UnexpectedMessage),
+            }
+        }
+    };
+
+    (@client [caps] $(#[$mattr:meta])* fn $method:ident($($arg:ident: $t:ty),*) -> ($($ret:ty)?)) => {
+        $(#[$mattr])*
+        pub fn $method(
+            &self,
+            $($arg: $t,)*
+            caps: &[Capability],
+        ) -> Result<($crate::interface!(@ret $($ret)?), Vec<CapabilityWithDescription>), IpcError> {
+            self.channel.send_serialized(&Request::$method { $($arg),* }, caps)?;
+
+            match self.channel.read_serialized::<Response>(ChannelReadFlags::NONE)? {
+                (Response::$method(ret), caps) => Ok((ret, caps)),
+                (Response::Error(e), _) => Err(IpcError::Rejected(e)),
+                #[allow(unreachable_patterns)]
+                _ => Err(IpcError::UnexpectedMessage),
+            }
+        }
+    };
+
+    (@server [] $(#[$mattr:meta])* fn $method:ident($($arg:ident: $t:ty),*) -> ($($ret:ty)?)) => {
+        $(#[$mattr])*
+        fn $method(&mut self, $($arg: $t),*) -> $crate::interface!(@ret $($ret)?);
+    };
+
+    (@server [caps] $(#[$mattr:meta])* fn $method:ident($($arg:ident: $t:ty),*) -> ($($ret:ty)?)) => {
+        $(#[$mattr])*
+        fn $method(
+            &mut self,
+            $($arg: $t,)*
+            caps: Vec<CapabilityWithDescription>,
+        ) -> ($crate::interface!(@ret $($ret)?), Vec<Capability>);
+    };
+
+    (@dispatch [] $server:ident, $caps:ident, $method:ident($($arg:ident),*)) => {{
+        drop($caps);
+        (Response::$method($server.$method($($arg),*)), Vec::new())
+    }};
+
+    (@dispatch [caps] $server:ident, $caps:ident, $method:ident($($arg:ident),*)) => {{
+        let (ret, caps) = $server.$method($($arg,)* $caps);
+        (Response::$method(ret), caps)
+    }};
+}
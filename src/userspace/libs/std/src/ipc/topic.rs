@@ -0,0 +1,71 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Publish-subscribe topics, for broadcasting events like device hotplug,
+//! shutdown or network link changes to however many tasks are interested
+
+use super::{encode_message, Capability, CapabilityPtr, ChannelMessage, ChannelSendFlags, IpcChannel};
+use librust::{error::SyscallError, syscalls::topic};
+
+pub use librust::syscalls::topic::LagPolicy;
+
+#[derive(Debug)]
+pub struct Topic {
+    cptr: CapabilityPtr,
+}
+
+impl Topic {
+    /// Create a new topic with no subscribers
+    pub fn new() -> Result<Self, SyscallError> {
+        Ok(Self { cptr: topic::create_topic()? })
+    }
+
+    pub fn from_cptr(cptr: CapabilityPtr) -> Self {
+        Self { cptr }
+    }
+
+    pub fn cptr(&self) -> CapabilityPtr {
+        self.cptr
+    }
+
+    /// Subscribe to the topic, returning the channel messages published to it
+    /// will arrive on from now on
+    pub fn subscribe(&self, policy: LagPolicy) -> Result<IpcChannel, SyscallError> {
+        Ok(IpcChannel::new(topic::subscribe(self.cptr, policy)?))
+    }
+
+    /// Send a copy of `message` to every subscriber, returning how many of them
+    /// it was delivered to
+    pub fn publish(&self, message: ChannelMessage, caps: &[Capability]) -> Result<usize, SyscallError> {
+        topic::publish(self.cptr, message, caps, ChannelSendFlags::NONE)
+    }
+
+    /// Like [`Topic::publish`], but subscribers read the message before any
+    /// normal ones they have waiting
+    pub fn publish_urgent(&self, message: ChannelMessage, caps: &[Capability]) -> Result<usize, SyscallError> {
+        topic::publish(self.cptr, message, caps, ChannelSendFlags::URGENT)
+    }
+
+    /// Serialize `t` and publish it along with `caps`. Subscribers can read it
+    /// with [`IpcChannel::read_serialized`].
+    pub fn publish_serialized<T: wire::Serialize + ?Sized>(
+        &self,
+        t: &T,
+        caps: &[Capability],
+    ) -> Result<usize, SyscallError> {
+        let (message, payload_cap) = encode_message(t)?;
+
+        match payload_cap {
+            Some(payload_cap) => {
+                let mut all_caps = vec![payload_cap];
+                all_caps.extend_from_slice(caps);
+                self.publish(message, &all_caps)
+            }
+            None => self.publish(message, caps),
+        }
+    }
+}
@@ -19,11 +19,18 @@
 #![allow(incomplete_features)]
 
 extern crate alloc;
+// Only needed for the `mem*` functions it exports
+extern crate memops;
 
+pub mod crash;
 pub mod env;
+pub mod fs;
+pub mod handle;
 pub mod heap;
 pub mod io;
 pub mod ipc;
+pub mod logging;
+pub mod path;
 pub mod prelude;
 pub mod rc;
 pub mod rt;
@@ -60,15 +67,15 @@ macro_rules! dbg {
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
     use core::fmt::Write;
-    // FIXME: hack rn to make output less wacky
-    // let out = args.to_string();
-    // let _ = io::Stdout.write_str(&out);
     let _ = io::Stdout.write_fmt(args);
 }
 
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
+    // Anything printed before the panic should show up before the message
+    io::Stdout.flush();
     println!("PANIC: {}", info);
+    io::Stdout.flush();
     librust::syscalls::task::exit()
 }
 
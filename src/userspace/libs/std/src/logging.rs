@@ -0,0 +1,313 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A [`log`] backend which sends records to the log service
+//!
+//! Tasks which are given a `logger` capability share a ring buffer with the
+//! log service, sent to it in the first message on the channel. Each record is
+//! written into the ring as a compact binary frame, and then an empty message
+//! is sent as a doorbell so the service knows to read it. Logging never waits
+//! on the service: a record which doesn't fit in the ring is dropped and
+//! counted in the ring's header instead, which the service reports the next
+//! time it reads the ring. Frames already in the ring are still read after the
+//! task exits.
+//!
+//! Tasks without a `logger` capability, like the servers started before the
+//! log service, log to stdout instead. Either way the maximum level is taken
+//! from the `log.level` configuration key, defaulting to `info`.
+//!
+//! Frames are laid out as:
+//!
+//! | Bytes | Contents                                   |
+//! |-------|--------------------------------------------|
+//! | 0..2  | Length of the whole frame, little endian   |
+//! | 2     | Level, from 1 for `error` to 5 for `trace` |
+//! | 3     | Length of the target                       |
+//! | 4..   | The target, followed by the message        |
+
+use crate::{ipc::IpcChannel, sync::Mutex};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use librust::{
+    capabilities::{Capability, CapabilityRights},
+    error::SyscallError,
+    syscalls::{
+        channel::ChannelMessage,
+        mem::{self, AllocationOptions, MemoryPermissions},
+    },
+    units::Bytes,
+};
+
+/// Size of the memory shared with the log service, including the header
+pub const RING_SIZE: usize = 16 * 1024;
+/// Longer messages are cut short to fit
+pub const MAX_FRAME_LEN: usize = 1024;
+const FRAME_HEADER_LEN: usize = 4;
+const MAX_TARGET_LEN: usize = u8::MAX as usize;
+
+static PRODUCER: Mutex<Option<Producer>> = Mutex::new(None);
+static LOGGER: Logger = Logger;
+
+/// The start of the memory shared with the log service
+#[repr(C)]
+pub struct RingHeader {
+    /// Bytes written into the ring so far, only moved by the task logging
+    pub head: AtomicUsize,
+    /// Bytes read out of the ring so far, only moved by the log service
+    pub tail: AtomicUsize,
+    /// Records dropped because the ring was full
+    pub dropped: AtomicUsize,
+}
+
+/// A record read out of a ring by the log service
+#[derive(Debug, Clone)]
+pub struct LogFrame {
+    pub level: log::Level,
+    pub target: String,
+    pub message: String,
+}
+
+/// The ring held something which isn't a frame, and everything in it was
+/// thrown away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MalformedFrame;
+
+/// One side of a ring buffer of log frames
+pub struct Ring {
+    header: *const RingHeader,
+    data: *mut u8,
+    capacity: usize,
+}
+
+unsafe impl Send for Ring {}
+
+impl Ring {
+    /// # Safety
+    ///
+    /// The `len` bytes at `ptr` must stay mapped readable and writable for as
+    /// long as the ring is used, and be shared with nothing but the ring on the
+    /// other side
+    pub unsafe fn new(ptr: *mut u8, len: usize) -> Option<Self> {
+        let header_len = core::mem::size_of::<RingHeader>();
+
+        match ptr as usize % core::mem::align_of::<RingHeader>() == 0 && len > header_len {
+            true => Some(Self { header: ptr.cast(), data: ptr.add(header_len), capacity: len - header_len }),
+            false => None,
+        }
+    }
+
+    pub fn header(&self) -> &RingHeader {
+        unsafe { &*self.header }
+    }
+
+    /// Write a frame into the ring, returning `false` if there isn't room for
+    /// it. `target` and `message` must already be short enough to fit in a
+    /// frame.
+    fn push(&self, level: log::Level, target: &str, message: &[u8]) -> bool {
+        let len = FRAME_HEADER_LEN + target.len() + message.len();
+        let head = self.header().head.load(Ordering::Relaxed);
+        let tail = self.header().tail.load(Ordering::Acquire);
+
+        if self.capacity - head.wrapping_sub(tail) < len {
+            return false;
+        }
+
+        let [len_lo, len_hi] = (len as u16).to_le_bytes();
+        self.copy_in(head, &[len_lo, len_hi, level as u8, target.len() as u8]);
+        self.copy_in(head.wrapping_add(FRAME_HEADER_LEN), target.as_bytes());
+        self.copy_in(head.wrapping_add(FRAME_HEADER_LEN + target.len()), message);
+        self.header().head.store(head.wrapping_add(len), Ordering::Release);
+
+        true
+    }
+
+    /// Read the next frame out of the ring, if there is one. The task on the
+    /// other side isn't trusted to have written valid frames.
+    pub fn read(&self) -> Result<Option<LogFrame>, MalformedFrame> {
+        let head = self.header().head.load(Ordering::Acquire);
+        let tail = self.header().tail.load(Ordering::Relaxed);
+
+        match head.wrapping_sub(tail) {
+            0 => Ok(None),
+            available => match self.frame_at(tail, available) {
+                Some((len, frame)) => {
+                    self.header().tail.store(tail.wrapping_add(len), Ordering::Release);
+                    Ok(Some(frame))
+                }
+                None => {
+                    self.header().tail.store(head, Ordering::Release);
+                    Err(MalformedFrame)
+                }
+            },
+        }
+    }
+
+    /// The frame at `tail` and its length, if it fits in the `available`
+    /// bytes and makes sense
+    fn frame_at(&self, tail: usize, available: usize) -> Option<(usize, LogFrame)> {
+        if available < FRAME_HEADER_LEN || available > self.capacity {
+            return None;
+        }
+
+        let mut header = [0; FRAME_HEADER_LEN];
+        self.copy_out(tail, &mut header);
+
+        let [len_lo, len_hi, level, target_len] = header;
+        let len = u16::from_le_bytes([len_lo, len_hi]) as usize;
+        let level = level_from_u8(level)?;
+
+        if len > available || len < FRAME_HEADER_LEN + target_len as usize {
+            return None;
+        }
+
+        let mut target = vec![0; len - FRAME_HEADER_LEN];
+        self.copy_out(tail.wrapping_add(FRAME_HEADER_LEN), &mut target);
+        let message = target.split_off(target_len as usize);
+
+        let frame = LogFrame {
+            level,
+            target: String::from_utf8_lossy(&target).into_owned(),
+            message: String::from_utf8_lossy(&message).into_owned(),
+        };
+
+        Some((len, frame))
+    }
+
+    fn copy_in(&self, at: usize, bytes: &[u8]) {
+        let start = at % self.capacity;
+        let (first, second) = bytes.split_at(bytes.len().min(self.capacity - start));
+
+        unsafe {
+            core::ptr::copy_nonoverlapping(first.as_ptr(), self.data.add(start), first.len());
+            core::ptr::copy_nonoverlapping(second.as_ptr(), self.data, second.len());
+        }
+    }
+
+    fn copy_out(&self, at: usize, bytes: &mut [u8]) {
+        let start = at % self.capacity;
+        let (first, second) = bytes.split_at_mut(bytes.len().min(self.capacity - start));
+
+        unsafe {
+            core::ptr::copy_nonoverlapping(self.data.add(start), first.as_mut_ptr(), first.len());
+            core::ptr::copy_nonoverlapping(self.data, second.as_mut_ptr(), second.len());
+        }
+    }
+}
+
+struct Producer {
+    ring: Ring,
+    doorbell: IpcChannel,
+    /// Reused for formatting each message
+    scratch: String,
+}
+
+impl Producer {
+    fn log(&mut self, record: &log::Record) {
+        use core::fmt::Write;
+
+        let target = truncate(record.target(), MAX_TARGET_LEN);
+        let max_message_len = MAX_FRAME_LEN - FRAME_HEADER_LEN - target.len();
+
+        self.scratch.clear();
+        let _ = write!(self.scratch, "{}", record.args());
+        let message = truncate(&self.scratch, max_message_len);
+
+        match self.ring.push(record.level(), target, message.as_bytes()) {
+            // A doorbell which doesn't fit in the channel means one is already
+            // waiting to be read, so the service will get to this frame too
+            true => {
+                let _ = self.doorbell.send(ChannelMessage::default(), &[]);
+            }
+            false => {
+                self.ring.header().dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+struct Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        match &mut *PRODUCER.lock() {
+            Some(producer) => producer.log(record),
+            None => crate::println!("[{}] {}: {}", record.target(), record.level(), record.args()),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Set up logging, to the log service if the task was given one
+pub(crate) fn init() {
+    if log::set_logger(&LOGGER).is_err() {
+        return;
+    }
+
+    let level = crate::env::config("log.level").ok().flatten().and_then(|level| level.parse().ok());
+    log::set_max_level(level.unwrap_or(log::LevelFilter::Info));
+
+    if let Some(logger) = crate::env::lookup_capability("logger") {
+        match connect(IpcChannel::new(logger.capability.cptr)) {
+            Ok(producer) => *PRODUCER.lock() = Some(producer),
+            Err(e) => crate::println!("Failed to connect to the log service: {:?}", e),
+        }
+    }
+}
+
+fn connect(doorbell: IpcChannel) -> Result<Producer, SyscallError> {
+    let (cptr, memory) = mem::alloc_virtual_memory(
+        Bytes(RING_SIZE),
+        AllocationOptions::ZERO,
+        MemoryPermissions::READ | MemoryPermissions::WRITE,
+    )?;
+
+    // SAFETY: the memory was just allocated, and only the log service gets
+    // the other side of it
+    let ring = unsafe { Ring::new(memory.cast(), RING_SIZE) }.ok_or(SyscallError::InvalidArgument(0))?;
+    doorbell.send(
+        ChannelMessage::default(),
+        &[Capability { cptr, rights: CapabilityRights::READ | CapabilityRights::WRITE }],
+    )?;
+
+    Ok(Producer { ring, doorbell, scratch: String::new() })
+}
+
+/// How many records this task has dropped because the log service wasn't
+/// keeping up
+pub fn dropped() -> usize {
+    match &*PRODUCER.lock() {
+        Some(producer) => producer.ring.header().dropped.load(Ordering::Relaxed),
+        None => 0,
+    }
+}
+
+/// `s` cut down to at most `len` bytes, without splitting a character
+fn truncate(s: &str, len: usize) -> &str {
+    match s.len() <= len {
+        true => s,
+        false => &s[..(0..=len).rev().find(|&i| s.is_char_boundary(i)).unwrap_or(0)],
+    }
+}
+
+fn level_from_u8(level: u8) -> Option<log::Level> {
+    match level {
+        1 => Some(log::Level::Error),
+        2 => Some(log::Level::Warn),
+        3 => Some(log::Level::Info),
+        4 => Some(log::Level::Debug),
+        5 => Some(log::Level::Trace),
+        _ => None,
+    }
+}
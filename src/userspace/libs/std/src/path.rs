@@ -0,0 +1,422 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! `/` separated paths, which are always UTF-8
+//!
+//! All manipulation is purely lexical, nothing here asks the filesystem server
+//! whether a path exists or follows links.
+
+use alloc::borrow::{Borrow, ToOwned};
+use core::ops::Deref;
+
+pub const SEPARATOR: char = '/';
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Component<'a> {
+    /// The leading `/` of an absolute path
+    RootDir,
+    /// A leading `.` of a relative path, any others are skipped
+    CurDir,
+    ParentDir,
+    Normal(&'a str),
+}
+
+impl<'a> Component<'a> {
+    pub fn as_str(self) -> &'a str {
+        match self {
+            Component::RootDir => "/",
+            Component::CurDir => ".",
+            Component::ParentDir => "..",
+            Component::Normal(s) => s,
+        }
+    }
+}
+
+/// The components of a [`Path`], skipping empty components and any `.` other
+/// than a leading one
+#[derive(Debug, Clone)]
+pub struct Components<'a> {
+    root: bool,
+    rest: core::str::Split<'a, char>,
+    first: bool,
+}
+
+impl<'a> Iterator for Components<'a> {
+    type Item = Component<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.root {
+            self.root = false;
+            self.first = false;
+            return Some(Component::RootDir);
+        }
+
+        for component in self.rest.by_ref() {
+            let first = core::mem::replace(&mut self.first, false);
+            match component {
+                "" => {}
+                "." if first => return Some(Component::CurDir),
+                "." => {}
+                ".." => return Some(Component::ParentDir),
+                normal => return Some(Component::Normal(normal)),
+            }
+        }
+
+        None
+    }
+}
+
+/// A borrowed path, the same way `str` is a borrowed `String`
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct Path {
+    inner: str,
+}
+
+impl Path {
+    pub fn new<S: AsRef<str> + ?Sized>(s: &S) -> &Self {
+        // SAFETY: `Path` is a `repr(transparent)` wrapper around `str`
+        unsafe { &*(s.as_ref() as *const str as *const Self) }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.inner
+    }
+
+    pub fn to_path_buf(&self) -> PathBuf {
+        PathBuf { inner: self.inner.into() }
+    }
+
+    pub fn is_absolute(&self) -> bool {
+        self.inner.starts_with(SEPARATOR)
+    }
+
+    pub fn is_relative(&self) -> bool {
+        !self.is_absolute()
+    }
+
+    pub fn components(&self) -> Components<'_> {
+        let (root, rest) = match self.inner.strip_prefix(SEPARATOR) {
+            Some(rest) => (true, rest),
+            None => (false, &self.inner),
+        };
+
+        Components { root, rest: rest.split(SEPARATOR), first: true }
+    }
+
+    /// The path without its last component, which is `None` for the root and
+    /// for empty paths
+    pub fn parent(&self) -> Option<&Path> {
+        let trimmed = self.inner.trim_end_matches(SEPARATOR);
+        if trimmed.is_empty() {
+            return None;
+        }
+
+        match trimmed.rfind(SEPARATOR) {
+            Some(i) => match trimmed[..i].trim_end_matches(SEPARATOR) {
+                "" => Some(Path::new("/")),
+                parent => Some(Path::new(parent)),
+            },
+            None => Some(Path::new("")),
+        }
+    }
+
+    /// The last component of the path, if it names a file or directory rather
+    /// than being the root, `.`, or `..`
+    pub fn file_name(&self) -> Option<&str> {
+        match self.components().last()? {
+            Component::Normal(name) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Append `path` to this path, replacing it entirely if `path` is absolute
+    pub fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        let mut joined = self.to_path_buf();
+        joined.push(path);
+        joined
+    }
+
+    /// Remove `.` components and resolve `..` components against the one
+    /// before them. `..` at the start of a relative path is kept, and `..` of
+    /// the root is the root.
+    pub fn normalize(&self) -> PathBuf {
+        let mut components = Vec::new();
+        for component in self.components() {
+            match component {
+                Component::CurDir => {}
+                Component::ParentDir => match components.last() {
+                    Some(Component::Normal(_)) => {
+                        components.pop();
+                    }
+                    Some(Component::RootDir) => {}
+                    _ => components.push(component),
+                },
+                component => components.push(component),
+            }
+        }
+
+        PathBuf::from_components(&components)
+    }
+
+    /// Resolve the path relative to a root it must stay inside of, returning
+    /// the normalized path relative to that root or `None` if a `..` would
+    /// escape it. A leading `/` refers to the root itself.
+    pub fn confine(&self) -> Option<PathBuf> {
+        let mut components = Vec::new();
+        for component in self.components() {
+            match component {
+                Component::RootDir | Component::CurDir => {}
+                Component::ParentDir => {
+                    components.pop()?;
+                }
+                component => components.push(component),
+            }
+        }
+
+        Some(PathBuf::from_components(&components))
+    }
+}
+
+impl core::fmt::Debug for Path {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(&self.inner, f)
+    }
+}
+
+impl core::fmt::Display for Path {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Display::fmt(&self.inner, f)
+    }
+}
+
+impl AsRef<Path> for Path {
+    fn as_ref(&self) -> &Path {
+        self
+    }
+}
+
+impl AsRef<Path> for str {
+    fn as_ref(&self) -> &Path {
+        Path::new(self)
+    }
+}
+
+impl AsRef<Path> for String {
+    fn as_ref(&self) -> &Path {
+        Path::new(self)
+    }
+}
+
+impl AsRef<str> for Path {
+    fn as_ref(&self) -> &str {
+        &self.inner
+    }
+}
+
+impl ToOwned for Path {
+    type Owned = PathBuf;
+
+    fn to_owned(&self) -> Self::Owned {
+        self.to_path_buf()
+    }
+}
+
+/// An owned path, the same way `String` is an owned `str`
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PathBuf {
+    inner: String,
+}
+
+impl PathBuf {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn from_components(components: &[Component<'_>]) -> Self {
+        let mut path = Self::new();
+        for component in components {
+            path.push(component.as_str());
+        }
+
+        path
+    }
+
+    pub fn as_path(&self) -> &Path {
+        Path::new(&self.inner)
+    }
+
+    pub fn into_string(self) -> String {
+        self.inner
+    }
+
+    /// Append `path`, replacing the whole path if `path` is absolute
+    pub fn push<P: AsRef<Path>>(&mut self, path: P) {
+        let path = path.as_ref().as_str();
+        if path.starts_with(SEPARATOR) {
+            self.inner.clear();
+        } else if !self.inner.is_empty() && !self.inner.ends_with(SEPARATOR) {
+            self.inner.push(SEPARATOR);
+        }
+
+        self.inner.push_str(path);
+    }
+
+    /// Truncate to the parent path, returning `false` if there's no parent
+    pub fn pop(&mut self) -> bool {
+        match self.parent().map(|parent| parent.inner.len()) {
+            Some(len) => {
+                self.inner.truncate(len);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Deref for PathBuf {
+    type Target = Path;
+
+    fn deref(&self) -> &Self::Target {
+        self.as_path()
+    }
+}
+
+impl Borrow<Path> for PathBuf {
+    fn borrow(&self) -> &Path {
+        self.as_path()
+    }
+}
+
+impl AsRef<Path> for PathBuf {
+    fn as_ref(&self) -> &Path {
+        self.as_path()
+    }
+}
+
+impl From<String> for PathBuf {
+    fn from(inner: String) -> Self {
+        Self { inner }
+    }
+}
+
+impl From<&str> for PathBuf {
+    fn from(s: &str) -> Self {
+        Self { inner: s.into() }
+    }
+}
+
+impl core::fmt::Debug for PathBuf {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(self.as_path(), f)
+    }
+}
+
+impl core::fmt::Display for PathBuf {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Display::fmt(self.as_path(), f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+
+    fn confine(path: &str) -> Option<PathBuf> {
+        Path::new(path).confine()
+    }
+
+    fn normalize(path: &str) -> PathBuf {
+        Path::new(path).normalize()
+    }
+
+    #[test]
+    fn components_skip_empty_and_inner_current_dirs() {
+        let components: Vec<_> = Path::new("/a//b/./c/").components().collect();
+        assert_eq!(
+            components,
+            [Component::RootDir, Component::Normal("a"), Component::Normal("b"), Component::Normal("c")]
+        );
+
+        let components: Vec<_> = Path::new("./a/./..").components().collect();
+        assert_eq!(components, [Component::CurDir, Component::Normal("a"), Component::ParentDir]);
+    }
+
+    #[test]
+    fn normalize_resolves_parent_dirs() {
+        assert_eq!(normalize("a/b/../c"), PathBuf::from("a/c"));
+        assert_eq!(normalize("a/.."), PathBuf::from(""));
+        assert_eq!(normalize("/a/../b"), PathBuf::from("/b"));
+    }
+
+    #[test]
+    fn normalize_keeps_leading_parent_dirs_of_relative_paths() {
+        assert_eq!(normalize(".."), PathBuf::from(".."));
+        assert_eq!(normalize("a/../.."), PathBuf::from(".."));
+        assert_eq!(normalize("../../a"), PathBuf::from("../../a"));
+    }
+
+    #[test]
+    fn normalize_stops_at_the_root() {
+        assert_eq!(normalize("/.."), PathBuf::from("/"));
+        assert_eq!(normalize("/../a/../.."), PathBuf::from("/"));
+    }
+
+    #[test]
+    fn normalize_drops_repeated_slashes_and_current_dirs() {
+        assert_eq!(normalize("/a//b/./c/"), PathBuf::from("/a/b/c"));
+        assert_eq!(normalize("./a/."), PathBuf::from("a"));
+        assert_eq!(normalize("//"), PathBuf::from("/"));
+    }
+
+    #[test]
+    fn confine_rejects_escaping_the_root() {
+        assert_eq!(confine(".."), None);
+        assert_eq!(confine("/.."), None);
+        assert_eq!(confine("a/../.."), None);
+        assert_eq!(confine("/a/../../b"), None);
+        assert_eq!(confine("./.."), None);
+    }
+
+    #[test]
+    fn confine_is_relative_to_the_root() {
+        assert_eq!(confine("/"), Some(PathBuf::from("")));
+        assert_eq!(confine("/a/b"), Some(PathBuf::from("a/b")));
+        assert_eq!(confine("a/b"), Some(PathBuf::from("a/b")));
+        assert_eq!(confine("a/.."), Some(PathBuf::from("")));
+        assert_eq!(confine("a/b/../c"), Some(PathBuf::from("a/c")));
+    }
+
+    #[test]
+    fn confine_drops_repeated_slashes_and_current_dirs() {
+        assert_eq!(confine("//a///b//"), Some(PathBuf::from("a/b")));
+        assert_eq!(confine("./a/./b/.."), Some(PathBuf::from("a")));
+        assert_eq!(confine("."), Some(PathBuf::from("")));
+    }
+
+    #[test]
+    fn parent_of_root_and_empty_is_none() {
+        assert_eq!(Path::new("/").parent(), None);
+        assert_eq!(Path::new("//").parent(), None);
+        assert_eq!(Path::new("").parent(), None);
+    }
+
+    #[test]
+    fn parent_trims_separators() {
+        assert_eq!(Path::new("/a").parent(), Some(Path::new("/")));
+        assert_eq!(Path::new("a").parent(), Some(Path::new("")));
+        assert_eq!(Path::new("a/b").parent(), Some(Path::new("a")));
+        assert_eq!(Path::new("/a//b//").parent(), Some(Path::new("/a")));
+    }
+
+    #[test]
+    fn parent_is_lexical() {
+        assert_eq!(Path::new("a/..").parent(), Some(Path::new("a")));
+        assert_eq!(Path::new("a/.").parent(), Some(Path::new("a")));
+    }
+}
@@ -5,6 +5,7 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
+use crate::env::{Bootstrap, CapabilitySlot, BOOTSTRAP_VERSION};
 use librust::{
     capabilities::{Capability, CapabilityDescription, CapabilityPtr, CapabilityRights, CapabilityWithDescription},
     syscalls::{
@@ -54,10 +55,20 @@ fn lang_start<T>(main: fn() -> T, argc: isize, argv: *const *const u8) -> isize
 
     let mut map = crate::env::CAP_MAP.borrow_mut();
     let channel = crate::ipc::IpcChannel::new(PARENT_CHANNEL);
-    // FIXME: Wowie is this some awful code!
-    if let Ok((names, _, caps)) = channel.temp_read_json::<Vec<String>>(ChannelReadFlags::NONE) {
-        for (name, cap) in names.into_iter().zip(caps) {
-            map.insert(name, cap);
+    if let Ok((bootstrap, caps)) = channel.read_serialized::<Bootstrap>(ChannelReadFlags::NONE) {
+        match bootstrap.version {
+            BOOTSTRAP_VERSION => {
+                for CapabilitySlot { name, index } in bootstrap.slots {
+                    if let Some(&cap) = caps.get(index as usize) {
+                        map.insert(name, cap);
+                    }
+                }
+            }
+            version => crate::println!(
+                "Ignoring capabilities from parent, unsupported bootstrap version {} (expected {})",
+                version,
+                BOOTSTRAP_VERSION
+            ),
         }
     }
 
@@ -68,8 +79,14 @@ fn lang_start<T>(main: fn() -> T, argc: isize, argv: *const *const u8) -> isize
             description: CapabilityDescription::Channel,
         },
     );
+    crate::handle::init(&map);
     drop(map);
 
+    crate::crash::init();
+    crate::logging::init();
+
     main();
+    crate::io::Stdout.flush();
+
     0
 }
@@ -8,6 +8,13 @@
 pub use alloc::sync::*;
 pub use core::sync::*;
 
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicPtr, AtomicU32, Ordering},
+    time::Duration,
+};
+use librust::{error::SyscallError, syscalls::futex};
+
 /// A [`core::cell::RefCell`] that implements `Send` and `Sync` to be suitable
 /// for use in `static`s.
 #[derive(Debug)]
@@ -68,3 +75,259 @@ impl<T> Clone for SyncRc<T> {
         Self(self.0.clone())
     }
 }
+
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+/// Locked, and there may be tasks waiting on the futex to take it next
+const CONTENDED: u32 = 2;
+/// Rounds of spinning to do before waiting on the futex, each one twice as
+/// long as the last
+const SPIN_ROUNDS: u32 = 6;
+
+/// A mutual exclusion lock which can be shared with other tasks through shared
+/// memory
+///
+/// Locking a held mutex spins for a short while with exponential backoff, since
+/// most critical sections are short enough that the lock is released before the
+/// cost of a syscall would be paid off. After that the task waits on the
+/// futex, so long critical sections don't waste time which could be spent
+/// running the task holding the lock.
+pub struct Mutex<T: ?Sized> {
+    state: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self { state: AtomicU32::new(UNLOCKED), data: UnsafeCell::new(value) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        if !self.try_acquire() {
+            self.acquire_contended(None);
+        }
+
+        MutexGuard { lock: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.try_acquire().then(|| MutexGuard { lock: self })
+    }
+
+    /// Try to lock the mutex, giving up if it's still held after `timeout`
+    pub fn try_lock_for(&self, timeout: Duration) -> Option<MutexGuard<'_, T>> {
+        (self.try_acquire() || self.acquire_contended(Some(timeout))).then(|| MutexGuard { lock: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    fn try_acquire(&self) -> bool {
+        self.state.compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    fn acquire_contended(&self, timeout: Option<Duration>) -> bool {
+        for round in 0..SPIN_ROUNDS {
+            for _ in 0..1 << round {
+                core::hint::spin_loop();
+            }
+
+            if self.state.load(Ordering::Relaxed) == UNLOCKED && self.try_acquire() {
+                return true;
+            }
+        }
+
+        self.park(timeout)
+    }
+
+    /// Wait on the futex until the lock can be taken. The lock is always taken
+    /// as contended, since there's no telling whether this was the last waiter.
+    fn park(&self, mut timeout: Option<Duration>) -> bool {
+        loop {
+            if self.state.swap(CONTENDED, Ordering::Acquire) == UNLOCKED {
+                return true;
+            }
+
+            match timeout {
+                None => futex::wait(&self.state, CONTENDED),
+                Some(left) => match futex::wait_timeout(&self.state, CONTENDED, left) {
+                    Some(remaining) => timeout = Some(remaining),
+                    None => return self.state.swap(CONTENDED, Ordering::Acquire) == UNLOCKED,
+                },
+            }
+        }
+    }
+
+    fn release(&self) {
+        if self.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            futex::wake(&self.state, 1);
+        }
+    }
+}
+
+impl<T: ?Sized + core::fmt::Debug> core::fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("Mutex").field("data", &&*guard).finish(),
+            None => f.debug_struct("Mutex").finish_non_exhaustive(),
+        }
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    lock: &'a Mutex<T>,
+}
+
+impl<T: ?Sized> core::ops::Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> core::ops::DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.release()
+    }
+}
+
+/// A condition variable to wait on while holding a [`Mutex`]
+///
+/// Notifying every waiter only wakes one of them, and moves the rest straight
+/// over to waiting on the mutex, since only one of them could take the mutex at
+/// a time anyway. A condition variable must only ever be used with one mutex.
+pub struct Condvar {
+    /// Bumped on every notification, so that a waiter can tell whether it
+    /// missed one between unlocking the mutex and waiting
+    seq: AtomicU32,
+    /// The state of the mutex waiters are using, for requeueing them onto it
+    mutex: AtomicPtr<AtomicU32>,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self { seq: AtomicU32::new(0), mutex: AtomicPtr::new(core::ptr::null_mut()) }
+    }
+
+    /// Unlock the mutex and wait to be notified, locking it again before
+    /// returning. Wakeups can be spurious, so the condition being waited on
+    /// should be checked in a loop.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.lock;
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.mutex.store(&mutex.state as *const AtomicU32 as *mut AtomicU32, Ordering::Relaxed);
+
+        drop(guard);
+        futex::wait(&self.seq, seq);
+
+        // This task might have been requeued onto the mutex, in which case
+        // there could be others still waiting on it
+        mutex.park(None);
+        MutexGuard { lock: mutex }
+    }
+
+    pub fn notify_one(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        futex::wake(&self.seq, 1);
+    }
+
+    pub fn notify_all(&self) {
+        let mutex = self.mutex.load(Ordering::Relaxed);
+        if mutex.is_null() {
+            return;
+        }
+
+        // SAFETY: the mutex outlives any of the waiters which are using it
+        let mutex = unsafe { &*mutex };
+        loop {
+            let seq = self.seq.fetch_add(1, Ordering::Release).wrapping_add(1);
+            // The sequence number only changes if someone else notified at
+            // the same time, in which case try again so no waiter is missed
+            if !matches!(futex::requeue(&self.seq, seq, mutex, 1, usize::MAX), Err(SyscallError::WouldBlock)) {
+                break;
+            }
+        }
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl core::fmt::Debug for Condvar {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Condvar").finish_non_exhaustive()
+    }
+}
+
+// Only the paths which don't wait on or wake a futex can be tested off-target,
+// the rest are checked by the syscall conformance suite
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+
+    #[test]
+    fn guard_unlocks_on_drop() {
+        let mutex = Mutex::new(1);
+        {
+            let mut guard = mutex.lock();
+            *guard += 1;
+            assert!(mutex.try_lock().is_none());
+        }
+
+        assert_eq!(mutex.state.load(Ordering::Relaxed), UNLOCKED);
+        assert_eq!(*mutex.try_lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn try_lock_for_takes_an_unlocked_mutex() {
+        let mutex = Mutex::new(());
+        let guard = mutex.try_lock_for(Duration::ZERO);
+
+        assert!(guard.is_some());
+        assert_eq!(mutex.state.load(Ordering::Relaxed), LOCKED);
+    }
+
+    #[test]
+    fn debug_doesnt_wait_on_a_held_mutex() {
+        let mutex = Mutex::new(5);
+        assert_eq!(alloc::format!("{:?}", mutex), "Mutex { data: 5 }");
+
+        let _guard = mutex.lock();
+        assert_eq!(alloc::format!("{:?}", mutex), "Mutex { .. }");
+    }
+
+    #[test]
+    fn get_mut_and_into_inner_bypass_the_lock() {
+        let mut mutex = Mutex::new(1);
+        *mutex.get_mut() = 3;
+
+        assert_eq!(mutex.into_inner(), 3);
+    }
+}
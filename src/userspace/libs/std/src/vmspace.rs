@@ -7,6 +7,10 @@
 
 use core::marker::PhantomData;
 
+use crate::{
+    env::{Bootstrap, CapabilitySlot, BOOTSTRAP_VERSION},
+    handle::{Handle, HandleError},
+};
 use librust::{
     capabilities::{Capability, CapabilityPtr, CapabilityRights},
     error::SyscallError,
@@ -50,19 +54,81 @@ impl Vmspace {
         }
     }
 
+    /// Map the `size` bytes of memory at `memory` into the vmspace at
+    /// `address` (or anywhere, if it's null) without copying them, returning
+    /// where they were mapped. `memory` has to start on a page boundary, and
+    /// is shared with the vmspace for as long as either of them has it mapped.
+    /// Writable mappings are copy-on-write, so each side gets its own copy of
+    /// a page once either of them writes to it.
+    pub fn share_object(
+        &self,
+        address: *const u8,
+        memory: *const u8,
+        size: usize,
+        permissions: MemoryPermissions,
+    ) -> Result<*mut u8, SyscallError> {
+        vmspace::share_vmspace_object(self.id, memory, VmspaceObjectMapping { address, size, permissions })
+    }
+
+    /// Start a task running in the vmspace, returning the channel to it. The
+    /// bootstrap message and every capability granted to it are sent as the
+    /// task is created, so it either starts with all of them or not at all.
     pub fn spawn(self, env: VmspaceSpawnEnv) -> Result<CapabilityPtr, SyscallError> {
-        let cptr = vmspace::spawn_vmspace(self.id, &self.name, env)?;
+        let (message, caps) = bootstrap_message(self.names, &self.caps_to_send)?;
+        vmspace::spawn_vmspace(self.id, &self.name, message, &caps, env)
+    }
 
-        let channel = crate::ipc::IpcChannel::new(cptr);
-        channel.temp_send_json(ChannelMessage::default(), &self.names, &self.caps_to_send[..])?;
+    /// Replace the current task with the vmspace, keeping only the
+    /// capabilities granted to it. Only returns if that failed.
+    pub fn exec(self, env: VmspaceSpawnEnv) -> SyscallError {
+        let (message, caps) = match bootstrap_message(self.names, &self.caps_to_send) {
+            Ok(encoded) => encoded,
+            Err(e) => return e,
+        };
 
-        Ok(cptr)
+        vmspace::exec_vmspace(self.id, &self.name, message, &caps, env)
     }
 
     pub fn grant(&mut self, name: &str, cptr: CapabilityPtr, rights: CapabilityRights) {
         self.names.push(name.into());
         self.caps_to_send.push(Capability { cptr, rights });
     }
+
+    /// Pass the capability open under `handle` on to the task, where it'll be
+    /// open under `as_handle`. Useful for redirecting its stdio, e.g. giving
+    /// it one end of a pipe as [`STDOUT`](crate::handle::STDOUT).
+    pub fn grant_handle(&mut self, as_handle: Handle, handle: Handle) -> Result<(), HandleError> {
+        let capability = crate::handle::get(handle)?.capability;
+        self.grant(&crate::handle::slot_name(as_handle), capability.cptr, capability.rights);
+
+        Ok(())
+    }
+}
+
+/// Encode the bootstrap message, returning it with the capabilities to send
+/// along with it
+fn bootstrap_message(
+    names: Vec<String>,
+    granted: &[Capability],
+) -> Result<(ChannelMessage, Vec<Capability>), SyscallError> {
+    let (message, payload_cap) = crate::ipc::encode_message(&bootstrap(names))?;
+
+    let mut caps = Vec::from_iter(payload_cap);
+    caps.extend_from_slice(granted);
+
+    Ok((message, caps))
+}
+
+/// The bootstrap message naming the capabilities granted to a new task
+fn bootstrap(names: Vec<String>) -> Bootstrap {
+    Bootstrap {
+        version: BOOTSTRAP_VERSION,
+        slots: names
+            .into_iter()
+            .enumerate()
+            .map(|(index, name)| CapabilitySlot { name, index: index as u32 })
+            .collect(),
+    }
 }
 
 #[derive(Debug)]
@@ -30,7 +30,7 @@ impl<'a> Archive<'a> {
 
             let content_start = archive_index + 512;
             let content_end = content_start + header.file_size;
-            let content_padding = 512 - (header.file_size % 512);
+            let content_padding = (512 - header.file_size % 512) % 512;
 
             if header.file_name == filename {
                 return Some(File { metadata: header, contents: self.data.get(content_start..content_end)? });
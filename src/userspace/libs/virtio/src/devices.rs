@@ -5,5 +5,9 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
+pub mod balloon;
 pub mod block;
+pub mod console;
 pub mod net;
+pub mod rng;
+pub mod vsock;
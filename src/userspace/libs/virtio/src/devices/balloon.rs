@@ -0,0 +1,77 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::VirtIoHeader;
+use volatile::{Read, Volatile};
+
+pub const INFLATE_QUEUE: u32 = 0;
+pub const DEFLATE_QUEUE: u32 = 1;
+pub const STATS_QUEUE: u32 = 2;
+
+/// Pages are always described to the device as 4 KiB page frame numbers,
+/// regardless of the page size the guest uses
+pub const PAGE_SHIFT: usize = 12;
+
+#[repr(C)]
+pub struct VirtIoBalloonDevice {
+    pub header: VirtIoHeader,
+    num_pages: Volatile<u32, Read>,
+    actual: Volatile<u32>,
+    free_page_hint_cmd_id: Volatile<u32, Read>,
+    poison_val: Volatile<u32>,
+}
+
+impl VirtIoBalloonDevice {
+    /// The number of pages the host would like the balloon to hold
+    pub fn num_pages(&self) -> u32 {
+        self.num_pages.read()
+    }
+
+    /// The number of pages the driver has told the host the balloon holds
+    pub fn actual(&self) -> u32 {
+        self.actual.read()
+    }
+
+    pub fn set_actual(&self, pages: u32) {
+        self.actual.write(pages);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BalloonDeviceFeatures(u64);
+
+impl BalloonDeviceFeatures {
+    /// The host must be told before any page is taken back out of the balloon
+    pub const MUST_TELL_HOST: Self = Self(1 << 0);
+    pub const STATS_QUEUE: Self = Self(1 << 1);
+    /// The guest may take pages back out of the balloon when it runs out of
+    /// memory
+    pub const DEFLATE_ON_OOM: Self = Self(1 << 2);
+    pub const FREE_PAGE_HINT: Self = Self(1 << 3);
+    pub const PAGE_POISON: Self = Self(1 << 4);
+    pub const PAGE_REPORTING: Self = Self(1 << 5);
+
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+}
+
+impl core::ops::BitOr for BalloonDeviceFeatures {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        BalloonDeviceFeatures(self.0 | rhs.0)
+    }
+}
+
+impl core::ops::BitAnd for BalloonDeviceFeatures {
+    type Output = bool;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        (self.0 & rhs.0) == rhs.0
+    }
+}
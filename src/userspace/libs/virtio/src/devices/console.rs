@@ -0,0 +1,157 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::VirtIoHeader;
+use volatile::{Read, ReadWrite, Volatile};
+
+/// Queue used by the device to send control messages to the driver
+pub const CONTROL_RECEIVE_QUEUE: u32 = 2;
+/// Queue used by the driver to send control messages to the device
+pub const CONTROL_TRANSMIT_QUEUE: u32 = 3;
+
+#[repr(C)]
+pub struct VirtIoConsoleDevice {
+    pub header: VirtIoHeader,
+    columns: Volatile<u16, Read>,
+    rows: Volatile<u16, Read>,
+    max_ports: Volatile<u32, Read>,
+    emergency_write: Volatile<u32, ReadWrite>,
+}
+
+impl VirtIoConsoleDevice {
+    /// The size of the console as `(columns, rows)`
+    ///
+    /// # Safety
+    /// This method is only safe to call if the [`ConsoleDeviceFeatures::SIZE`]
+    /// feature has been negotiated
+    pub unsafe fn size(&self) -> (u16, u16) {
+        (self.columns.read(), self.rows.read())
+    }
+
+    /// # Safety
+    /// This method is only safe to call if the
+    /// [`ConsoleDeviceFeatures::MULTIPORT`] feature has been negotiated
+    pub unsafe fn max_ports(&self) -> u32 {
+        self.max_ports.read()
+    }
+
+    /// Write a character to the console without needing any queues to be set
+    /// up
+    ///
+    /// # Safety
+    /// This method is only safe to call if the
+    /// [`ConsoleDeviceFeatures::EMERGENCY_WRITE`] feature has been negotiated
+    pub unsafe fn emergency_write(&self, c: u8) {
+        self.emergency_write.write(c as u32);
+    }
+}
+
+/// The queue the device uses to send data from `port` to the driver
+pub fn receive_queue(port: u32) -> u32 {
+    match port {
+        0 => 0,
+        port => port * 2 + 2,
+    }
+}
+
+/// The queue the driver uses to send data to `port`
+pub fn transmit_queue(port: u32) -> u32 {
+    receive_queue(port) + 1
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConsoleDeviceFeatures(u64);
+
+impl ConsoleDeviceFeatures {
+    /// Device reports the console size
+    pub const SIZE: Self = Self(1 << 0);
+    /// Device supports multiple ports, which are managed through the control
+    /// queues
+    pub const MULTIPORT: Self = Self(1 << 1);
+    /// Device supports emergency writes
+    pub const EMERGENCY_WRITE: Self = Self(1 << 2);
+
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+}
+
+impl core::ops::BitOr for ConsoleDeviceFeatures {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        ConsoleDeviceFeatures(self.0 | rhs.0)
+    }
+}
+
+impl core::ops::BitAnd for ConsoleDeviceFeatures {
+    type Output = bool;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        (self.0 & rhs.0) == rhs.0
+    }
+}
+
+/// A message sent over the control queues. Some events are followed by extra
+/// data in the same buffer, e.g. the name of the port for
+/// [`ControlEvent::PORT_NAME`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct ControlMessage {
+    pub port: u32,
+    pub event: ControlEvent,
+    pub value: u16,
+}
+
+impl ControlMessage {
+    pub const SIZE: usize = core::mem::size_of::<Self>();
+
+    /// Parse the message at the start of `bytes`, which may be followed by
+    /// extra data
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; Self::SIZE] = bytes.get(..Self::SIZE)?.try_into().ok()?;
+
+        Some(Self {
+            port: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            event: ControlEvent(u16::from_le_bytes([bytes[4], bytes[5]])),
+            value: u16::from_le_bytes([bytes[6], bytes[7]]),
+        })
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[..4].copy_from_slice(&self.port.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.event.0.to_le_bytes());
+        bytes[6..].copy_from_slice(&self.value.to_le_bytes());
+
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct ControlEvent(u16);
+
+impl ControlEvent {
+    /// Sent by the driver once it's ready to receive control messages
+    pub const DEVICE_READY: Self = Self(0);
+    /// Sent by the device when a port has been added
+    pub const DEVICE_ADD: Self = Self(1);
+    /// Sent by the device when a port has been removed
+    pub const DEVICE_REMOVE: Self = Self(2);
+    /// Sent by the driver when a port is ready (value 1) or failed to be set
+    /// up (value 0)
+    pub const PORT_READY: Self = Self(3);
+    /// Sent by the device to mark a port as a console port
+    pub const CONSOLE_PORT: Self = Self(4);
+    /// Sent by the device when the console size changes
+    pub const RESIZE: Self = Self(5);
+    /// Sent by either side when it opens (value 1) or closes (value 0) a port
+    pub const PORT_OPEN: Self = Self(6);
+    /// Sent by the device with the name of a port following the message
+    pub const PORT_NAME: Self = Self(7);
+}
@@ -225,6 +225,10 @@ impl NetDeviceFeatures {
         Self(raw & Self::ALL.0)
     }
 
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
     pub fn split(self) -> NetDeviceFeaturesSplit {
         NetDeviceFeaturesSplit { low: self.0 as u32, high: (self.0 >> 32) as u32 }
     }
@@ -0,0 +1,17 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::VirtIoHeader;
+
+/// Buffers placed on this queue are filled with random bytes by the device
+pub const REQUEST_QUEUE: u32 = 0;
+
+/// The entropy device has no configuration space of its own
+#[repr(C)]
+pub struct VirtIoRngDevice {
+    pub header: VirtIoHeader,
+}
@@ -0,0 +1,194 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::VirtIoHeader;
+use volatile::{Read, Volatile};
+
+pub const RECEIVE_QUEUE: u32 = 0;
+pub const TRANSMIT_QUEUE: u32 = 1;
+pub const EVENT_QUEUE: u32 = 2;
+
+/// The context ID of the host
+pub const HOST_CID: u64 = 2;
+
+#[repr(C)]
+pub struct VirtIoVsockDevice {
+    pub header: VirtIoHeader,
+    guest_cid: Volatile<u64, Read>,
+}
+
+impl VirtIoVsockDevice {
+    /// The context ID the host assigned to us, which can change after a
+    /// [`EventId::TRANSPORT_RESET`] event
+    pub fn guest_cid(&self) -> u64 {
+        self.guest_cid.read()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VsockDeviceFeatures(u64);
+
+impl VsockDeviceFeatures {
+    pub const STREAM: Self = Self(1 << 0);
+    pub const SEQPACKET: Self = Self(1 << 1);
+
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+}
+
+impl core::ops::BitOr for VsockDeviceFeatures {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        VsockDeviceFeatures(self.0 | rhs.0)
+    }
+}
+
+impl core::ops::BitAnd for VsockDeviceFeatures {
+    type Output = bool;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        (self.0 & rhs.0) == rhs.0
+    }
+}
+
+/// The header at the start of every packet, which is followed by `len` bytes of
+/// data for [`VsockOp::RW`] packets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VsockHeader {
+    pub src_cid: u64,
+    pub dst_cid: u64,
+    pub src_port: u32,
+    pub dst_port: u32,
+    pub len: u32,
+    pub ty: VsockType,
+    pub op: VsockOp,
+    pub flags: u32,
+    /// The size of the sender's receive buffer
+    pub buf_alloc: u32,
+    /// The number of bytes the sender has consumed from its receive buffer
+    pub fwd_cnt: u32,
+}
+
+impl VsockHeader {
+    /// The size of the header on the wire, which is packed
+    pub const SIZE: usize = 44;
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; Self::SIZE] = bytes.get(..Self::SIZE)?.try_into().ok()?;
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        let u64_at = |i: usize| u64::from(u32_at(i)) | (u64::from(u32_at(i + 4)) << 32);
+
+        Some(Self {
+            src_cid: u64_at(0),
+            dst_cid: u64_at(8),
+            src_port: u32_at(16),
+            dst_port: u32_at(20),
+            len: u32_at(24),
+            ty: VsockType(u16_at(28)),
+            op: VsockOp(u16_at(30)),
+            flags: u32_at(32),
+            buf_alloc: u32_at(36),
+            fwd_cnt: u32_at(40),
+        })
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0..8].copy_from_slice(&self.src_cid.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.dst_cid.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.src_port.to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.dst_port.to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.len.to_le_bytes());
+        bytes[28..30].copy_from_slice(&self.ty.0.to_le_bytes());
+        bytes[30..32].copy_from_slice(&self.op.0.to_le_bytes());
+        bytes[32..36].copy_from_slice(&self.flags.to_le_bytes());
+        bytes[36..40].copy_from_slice(&self.buf_alloc.to_le_bytes());
+        bytes[40..44].copy_from_slice(&self.fwd_cnt.to_le_bytes());
+
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct VsockType(u16);
+
+impl VsockType {
+    pub const STREAM: Self = Self(1);
+    pub const SEQPACKET: Self = Self(2);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct VsockOp(u16);
+
+impl VsockOp {
+    pub const INVALID: Self = Self(0);
+    /// Connection request
+    pub const REQUEST: Self = Self(1);
+    /// Connection accepted
+    pub const RESPONSE: Self = Self(2);
+    /// Connection refused or reset
+    pub const RST: Self = Self(3);
+    /// The sender won't send or receive any more data, depending on the
+    /// [`ShutdownFlags`] in the header
+    pub const SHUTDOWN: Self = Self(4);
+    /// Data
+    pub const RW: Self = Self(5);
+    /// The sender's `buf_alloc` or `fwd_cnt` changed
+    pub const CREDIT_UPDATE: Self = Self(6);
+    /// The sender wants a [`VsockOp::CREDIT_UPDATE`]
+    pub const CREDIT_REQUEST: Self = Self(7);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownFlags(u32);
+
+impl ShutdownFlags {
+    pub const RECEIVE: Self = Self(1 << 0);
+    pub const SEND: Self = Self(1 << 1);
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+}
+
+impl core::ops::BitOr for ShutdownFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        ShutdownFlags(self.0 | rhs.0)
+    }
+}
+
+/// An event sent on the event queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VsockEvent {
+    pub id: EventId,
+}
+
+impl VsockEvent {
+    pub const SIZE: usize = 4;
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes: [u8; Self::SIZE] = bytes.get(..Self::SIZE)?.try_into().ok()?;
+        Some(Self { id: EventId(u32::from_le_bytes(bytes)) })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct EventId(u32);
+
+impl EventId {
+    /// Every connection has been reset and the guest CID may have changed,
+    /// which happens e.g. after live migration
+    pub const TRANSPORT_RESET: Self = Self(0);
+}
@@ -7,8 +7,10 @@
 
 pub mod devices;
 pub mod splitqueue;
+pub mod transport;
 
 pub use registers::StatusFlag;
+pub use transport::{MmioTransport, PciTransport, Transport};
 use volatile::{Read, ReadWrite, Volatile, Write};
 
 #[repr(C)]
@@ -59,7 +61,7 @@ impl VirtIoHeader {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
 pub enum DeviceType {
     Reserved = 0,
@@ -130,6 +132,10 @@ mod registers {
         pub fn device_type_feature_bits(&self) -> u32 {
             self.0.read() & 0xFFFFFF
         }
+
+        pub fn raw(&self) -> u32 {
+            self.0.read()
+        }
     }
 
     #[derive(Debug)]
@@ -268,4 +274,6 @@ mod registers {
 pub enum VirtIoDeviceError {
     FeaturesNotRecognized,
     DeviceError,
+    /// The queue doesn't exist or can't hold as many descriptors as requested
+    QueueUnavailable(u32),
 }
@@ -0,0 +1,180 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+pub mod pci;
+
+pub use pci::PciTransport;
+
+use crate::{splitqueue::SplitVirtqueue, DeviceType, StatusFlag, VirtIoDeviceError, VirtIoHeader};
+
+/// The interrupt causes reported by a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptCause {
+    pub buffer_used: bool,
+    pub config_changed: bool,
+}
+
+/// The way a virtio device is attached to the system. Drivers should only talk
+/// to the device through this, so they work no matter how the device is
+/// exposed.
+pub trait Transport {
+    fn device_type(&self) -> Option<DeviceType>;
+    fn reset(&self);
+    fn set_status(&self, flag: StatusFlag);
+    fn status_is_set(&self, flag: StatusFlag) -> bool;
+    fn device_features(&self) -> u64;
+    fn set_driver_features(&self, features: u64);
+    fn max_queue_size(&self, queue: u32) -> u32;
+    /// Hand the memory backing `virtqueue` to the device and enable the queue
+    fn configure_queue(&self, queue: u32, virtqueue: &SplitVirtqueue) -> Result<(), VirtIoDeviceError>;
+    /// Notify the device that there are new buffers in the available ring of
+    /// `queue`
+    fn notify(&self, queue: u32);
+    /// Acknowledge any pending interrupts, returning what caused them
+    fn acknowledge_interrupt(&self) -> InterruptCause;
+
+    /// Reset the device and tell it that a driver has been found for it, which
+    /// must be done before negotiating features
+    fn begin_init(&self) {
+        self.reset();
+        self.set_status(StatusFlag::Acknowledge);
+        self.set_status(StatusFlag::Driver);
+    }
+
+    /// Accept every feature in `required`, and any feature in `optional` the
+    /// device offers, returning the accepted set. Fails if the device doesn't
+    /// offer all of the required features or rejects the selection.
+    fn negotiate_features(&self, required: u64, optional: u64) -> Result<u64, VirtIoDeviceError> {
+        let offered = self.device_features();
+
+        if offered & required != required {
+            self.set_status(StatusFlag::Failed);
+            return Err(VirtIoDeviceError::FeaturesNotRecognized);
+        }
+
+        let selected = required | (offered & optional);
+        self.set_driver_features(selected);
+        self.set_status(StatusFlag::FeaturesOk);
+
+        match self.status_is_set(StatusFlag::FeaturesOk) {
+            true => Ok(selected),
+            false => Err(VirtIoDeviceError::FeaturesNotRecognized),
+        }
+    }
+
+    /// Mark the driver as ready to drive the device, which must be done after
+    /// all queues have been configured
+    fn finish_init(&self) -> Result<(), VirtIoDeviceError> {
+        self.set_status(StatusFlag::DriverOk);
+        librust::mem::fence(librust::mem::FenceMode::Write);
+
+        match self.status_is_set(StatusFlag::Failed) {
+            true => Err(VirtIoDeviceError::DeviceError),
+            false => Ok(()),
+        }
+    }
+}
+
+/// A device using the virtio-mmio register layout
+#[derive(Clone, Copy)]
+pub struct MmioTransport {
+    header: &'static VirtIoHeader,
+}
+
+impl MmioTransport {
+    pub fn new(header: &'static VirtIoHeader) -> Result<Self, VirtIoDeviceError> {
+        match header.valid_magic() {
+            true => Ok(Self { header }),
+            false => Err(VirtIoDeviceError::DeviceError),
+        }
+    }
+
+    pub fn header(&self) -> &'static VirtIoHeader {
+        self.header
+    }
+}
+
+impl Transport for MmioTransport {
+    fn device_type(&self) -> Option<DeviceType> {
+        self.header.device_type()
+    }
+
+    fn reset(&self) {
+        self.header.status.reset();
+    }
+
+    fn set_status(&self, flag: StatusFlag) {
+        self.header.status.set_flag(flag);
+    }
+
+    fn status_is_set(&self, flag: StatusFlag) -> bool {
+        self.header.status.is_set(flag)
+    }
+
+    fn device_features(&self) -> u64 {
+        self.header.device_features_select.write(0);
+        let low = self.header.device_features.raw() as u64;
+        self.header.device_features_select.write(1);
+        let high = self.header.device_features.raw() as u64;
+
+        (high << 32) | low
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        self.header.driver_features_select.write(0);
+        self.header.driver_features.write(features as u32);
+        self.header.driver_features_select.write(1);
+        self.header.driver_features.write((features >> 32) as u32);
+    }
+
+    fn max_queue_size(&self, queue: u32) -> u32 {
+        self.header.queue_select.write(queue);
+        librust::mem::fence(librust::mem::FenceMode::Write);
+        self.header.queue_size_max.read()
+    }
+
+    fn configure_queue(&self, queue: u32, virtqueue: &SplitVirtqueue) -> Result<(), VirtIoDeviceError> {
+        // Also selects the queue for the writes below
+        let max_size = self.max_queue_size(queue);
+        if max_size == 0 || virtqueue.queue_size() > max_size {
+            return Err(VirtIoDeviceError::QueueUnavailable(queue));
+        }
+
+        self.header.queue_size.write(virtqueue.queue_size());
+        self.header.queue_descriptor.set(virtqueue.descriptors.physical_address());
+        self.header.queue_available.set(virtqueue.available.physical_address());
+        self.header.queue_used.set(virtqueue.used.physical_address());
+        self.header.queue_ready.ready();
+        librust::mem::fence(librust::mem::FenceMode::Write);
+
+        Ok(())
+    }
+
+    fn notify(&self, queue: u32) {
+        // MMIO register writes aren't ordered with respect to RAM writes, so
+        // make sure the device sees the updated rings first
+        librust::mem::fence(librust::mem::FenceMode::Write);
+        self.header.queue_notify.notify(queue);
+    }
+
+    fn acknowledge_interrupt(&self) -> InterruptCause {
+        let cause = InterruptCause {
+            buffer_used: self.header.interrupt_status.buffer_was_used(),
+            config_changed: self.header.interrupt_status.config_was_changed(),
+        };
+
+        if cause.buffer_used {
+            self.header.interrupt_ack.acknowledge_buffer_used();
+        }
+
+        if cause.config_changed {
+            self.header.interrupt_ack.acknowledge_config_change();
+        }
+
+        cause
+    }
+}
@@ -0,0 +1,518 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The virtio-over-PCI transport for modern (virtio 1.0+) devices
+//!
+//! A PCI device describes where its virtio registers live with vendor-specific
+//! capabilities in its configuration space, each of which points at a range of
+//! one of its BARs. Finding and mapping the device is up to the caller, which
+//! hands over a copy of the configuration space and the addresses the BARs are
+//! mapped at.
+
+use super::{InterruptCause, Transport};
+use crate::{splitqueue::SplitVirtqueue, DeviceType, StatusFlag, VirtIoDeviceError};
+use volatile::{Read, ReadWrite, Volatile};
+
+/// The PCI vendor ID all virtio devices use
+pub const VENDOR_ID: u16 = 0x1AF4;
+
+const STATUS_OFFSET: usize = 0x06;
+const STATUS_CAPABILITY_LIST: u16 = 1 << 4;
+const CAPABILITIES_POINTER_OFFSET: usize = 0x34;
+const CAPABILITY_VENDOR_SPECIFIC: u8 = 0x09;
+/// Every standard capability lives in the first 256 bytes of the
+/// configuration space and is at least 4 bytes long, so a list longer than
+/// this must loop back on itself
+const MAX_CAPABILITIES: usize = 64;
+
+const CFG_TYPE_COMMON: u8 = 1;
+const CFG_TYPE_NOTIFY: u8 = 2;
+const CFG_TYPE_ISR: u8 = 3;
+const CFG_TYPE_DEVICE: u8 = 4;
+
+const ISR_QUEUE: u8 = 1 << 0;
+const ISR_CONFIG: u8 = 1 << 1;
+
+/// The virtio device type of a PCI device, accepting both the modern device
+/// IDs and the transitional ones legacy-capable devices use
+pub fn device_type(vendor_id: u16, device_id: u16) -> Option<DeviceType> {
+    if vendor_id != VENDOR_ID {
+        return None;
+    }
+
+    match device_id {
+        0x1000 => Some(DeviceType::NetworkCard),
+        0x1001 => Some(DeviceType::BlockDevice),
+        0x1002 => Some(DeviceType::MemoryBallooningTraditional),
+        0x1003 => Some(DeviceType::Console),
+        0x1004 => Some(DeviceType::ScsiHost),
+        0x1005 => Some(DeviceType::EntropySource),
+        0x1009 => Some(DeviceType::Transport9P),
+        0x1040..=0x107F => DeviceType::from_u32(u32::from(device_id - 0x1040)),
+        _ => None,
+    }
+}
+
+/// A range of one of the device's BARs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarRegion {
+    pub bar: u8,
+    pub offset: u32,
+    pub length: u32,
+}
+
+/// The locations of the virtio register blocks of a PCI device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub common: BarRegion,
+    pub notify: BarRegion,
+    /// The queue notification addresses are `notify_off_multiplier` bytes
+    /// apart, times each queue's `queue_notify_off`
+    pub notify_off_multiplier: u32,
+    pub isr: BarRegion,
+    /// Not all devices have a device-specific configuration
+    pub device: Option<BarRegion>,
+}
+
+/// Walk the capability list in `config`, the device's PCI configuration space,
+/// for the virtio register blocks. When a device offers more than one
+/// capability of a type, the first is used, as the specification recommends.
+pub fn find_capabilities(config: &[u8]) -> Result<Capabilities, VirtIoDeviceError> {
+    let status = read_u16(config, STATUS_OFFSET).ok_or(VirtIoDeviceError::DeviceError)?;
+    if status & STATUS_CAPABILITY_LIST == 0 {
+        return Err(VirtIoDeviceError::DeviceError);
+    }
+
+    let (mut common, mut notify, mut isr, mut device) = (None, None, None, None);
+    let mut pointer = usize::from(*config.get(CAPABILITIES_POINTER_OFFSET).ok_or(VirtIoDeviceError::DeviceError)?);
+
+    for _ in 0..MAX_CAPABILITIES {
+        // The bottom two bits are reserved
+        pointer &= !0b11;
+        if pointer == 0 {
+            break;
+        }
+
+        let (Some(&id), Some(&next)) = (config.get(pointer), config.get(pointer + 1)) else { break };
+
+        if id == CAPABILITY_VENDOR_SPECIFIC {
+            if let Some((cfg_type, region)) = read_region(config, pointer) {
+                match cfg_type {
+                    CFG_TYPE_COMMON if common.is_none() => common = Some(region),
+                    CFG_TYPE_NOTIFY if notify.is_none() => {
+                        if let Some(multiplier) = read_u32(config, pointer + 16) {
+                            notify = Some((region, multiplier));
+                        }
+                    }
+                    CFG_TYPE_ISR if isr.is_none() => isr = Some(region),
+                    CFG_TYPE_DEVICE if device.is_none() => device = Some(region),
+                    _ => {}
+                }
+            }
+        }
+
+        pointer = usize::from(next);
+    }
+
+    match (common, notify, isr) {
+        (Some(common), Some((notify, notify_off_multiplier)), Some(isr)) => {
+            Ok(Capabilities { common, notify, notify_off_multiplier, isr, device })
+        }
+        _ => Err(VirtIoDeviceError::DeviceError),
+    }
+}
+
+fn read_region(config: &[u8], pointer: usize) -> Option<(u8, BarRegion)> {
+    let cfg_type = *config.get(pointer + 3)?;
+    let bar = *config.get(pointer + 4)?;
+    let offset = read_u32(config, pointer + 8)?;
+    let length = read_u32(config, pointer + 12)?;
+
+    // BARs 0 through 5 are the only ones that exist, the rest are reserved
+    match bar {
+        0..=5 => Some((cfg_type, BarRegion { bar, offset, length })),
+        _ => None,
+    }
+}
+
+fn read_u16(config: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(config.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn read_u32(config: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(config.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+/// The common configuration structure, see section 4.1.4.3 of the virtio
+/// specification
+#[repr(C)]
+struct CommonConfig {
+    device_feature_select: Volatile<u32, ReadWrite>,
+    device_feature: Volatile<u32, Read>,
+    driver_feature_select: Volatile<u32, ReadWrite>,
+    driver_feature: Volatile<u32, ReadWrite>,
+    _msix_config: Volatile<u16, ReadWrite>,
+    num_queues: Volatile<u16, Read>,
+    device_status: Volatile<u8, ReadWrite>,
+    _config_generation: Volatile<u8, Read>,
+    queue_select: Volatile<u16, ReadWrite>,
+    queue_size: Volatile<u16, ReadWrite>,
+    _queue_msix_vector: Volatile<u16, ReadWrite>,
+    queue_enable: Volatile<u16, ReadWrite>,
+    queue_notify_off: Volatile<u16, Read>,
+    // The 64-bit fields are written as two halves, since devices aren't
+    // required to accept 64-bit accesses
+    queue_desc: [Volatile<u32, ReadWrite>; 2],
+    queue_driver: [Volatile<u32, ReadWrite>; 2],
+    queue_device: [Volatile<u32, ReadWrite>; 2],
+}
+
+fn write_u64(register: &[Volatile<u32, ReadWrite>; 2], value: u64) {
+    register[0].write(value as u32);
+    register[1].write((value >> 32) as u32);
+}
+
+/// A device using the virtio-over-PCI register layout
+#[derive(Clone, Copy)]
+pub struct PciTransport {
+    device_type: Option<DeviceType>,
+    common: &'static CommonConfig,
+    isr: &'static Volatile<u8, Read>,
+    notify: *mut u8,
+    notify_off_multiplier: u32,
+    device_config: Option<*mut u8>,
+}
+
+impl PciTransport {
+    /// Create a transport for the device with the configuration space
+    /// `config`, whose memory BARs are mapped at the addresses in `bars`
+    ///
+    /// # Safety
+    ///
+    /// Each mapped BAR in `bars` must point to the device's BAR of the same
+    /// index, mapped as device memory for the rest of the program
+    pub unsafe fn new(config: &[u8], bars: [Option<*mut u8>; 6]) -> Result<Self, VirtIoDeviceError> {
+        let vendor_id = read_u16(config, 0x00).ok_or(VirtIoDeviceError::DeviceError)?;
+        let device_id = read_u16(config, 0x02).ok_or(VirtIoDeviceError::DeviceError)?;
+        let device_type = device_type(vendor_id, device_id);
+        if device_type.is_none() {
+            return Err(VirtIoDeviceError::DeviceError);
+        }
+
+        let capabilities = find_capabilities(config)?;
+        let locate = |region: BarRegion| match bars[usize::from(region.bar)] {
+            Some(base) => Ok(base.add(region.offset as usize)),
+            None => Err(VirtIoDeviceError::DeviceError),
+        };
+
+        if (capabilities.common.length as usize) < core::mem::size_of::<CommonConfig>() || capabilities.isr.length == 0
+        {
+            return Err(VirtIoDeviceError::DeviceError);
+        }
+
+        Ok(Self {
+            device_type,
+            common: &*locate(capabilities.common)?.cast(),
+            isr: &*locate(capabilities.isr)?.cast(),
+            notify: locate(capabilities.notify)?,
+            notify_off_multiplier: capabilities.notify_off_multiplier,
+            device_config: capabilities.device.map(locate).transpose()?,
+        })
+    }
+
+    /// The device-specific configuration, if the device has one
+    pub fn device_config(&self) -> Option<*mut u8> {
+        self.device_config
+    }
+
+    /// The number of virtqueues the device supports
+    pub fn num_queues(&self) -> u32 {
+        u32::from(self.common.num_queues.read())
+    }
+
+    fn select_queue(&self, queue: u32) -> Result<(), VirtIoDeviceError> {
+        if queue >= self.num_queues() {
+            return Err(VirtIoDeviceError::QueueUnavailable(queue));
+        }
+
+        self.common.queue_select.write(queue as u16);
+        librust::mem::fence(librust::mem::FenceMode::Write);
+
+        Ok(())
+    }
+}
+
+impl Transport for PciTransport {
+    fn device_type(&self) -> Option<DeviceType> {
+        self.device_type
+    }
+
+    fn reset(&self) {
+        self.common.device_status.write(0);
+
+        // The reset isn't complete until the device reads back zero
+        while self.common.device_status.read() != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn set_status(&self, flag: StatusFlag) {
+        self.common.device_status.write(self.common.device_status.read() | flag as u8);
+        librust::mem::fence(librust::mem::FenceMode::Write);
+    }
+
+    fn status_is_set(&self, flag: StatusFlag) -> bool {
+        self.common.device_status.read() & flag as u8 == flag as u8
+    }
+
+    fn device_features(&self) -> u64 {
+        self.common.device_feature_select.write(0);
+        let low = u64::from(self.common.device_feature.read());
+        self.common.device_feature_select.write(1);
+        let high = u64::from(self.common.device_feature.read());
+
+        (high << 32) | low
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        self.common.driver_feature_select.write(0);
+        self.common.driver_feature.write(features as u32);
+        self.common.driver_feature_select.write(1);
+        self.common.driver_feature.write((features >> 32) as u32);
+    }
+
+    fn max_queue_size(&self, queue: u32) -> u32 {
+        match self.select_queue(queue) {
+            // Before the driver writes a size, `queue_size` holds the maximum
+            Ok(()) => u32::from(self.common.queue_size.read()),
+            Err(_) => 0,
+        }
+    }
+
+    fn configure_queue(&self, queue: u32, virtqueue: &SplitVirtqueue) -> Result<(), VirtIoDeviceError> {
+        // Also selects the queue for the writes below
+        let max_size = self.max_queue_size(queue);
+        if max_size == 0 || virtqueue.queue_size() > max_size {
+            return Err(VirtIoDeviceError::QueueUnavailable(queue));
+        }
+
+        self.common.queue_size.write(virtqueue.queue_size() as u16);
+        write_u64(&self.common.queue_desc, virtqueue.descriptors.physical_address().as_usize() as u64);
+        write_u64(&self.common.queue_driver, virtqueue.available.physical_address().as_usize() as u64);
+        write_u64(&self.common.queue_device, virtqueue.used.physical_address().as_usize() as u64);
+        librust::mem::fence(librust::mem::FenceMode::Write);
+        self.common.queue_enable.write(1);
+        librust::mem::fence(librust::mem::FenceMode::Write);
+
+        Ok(())
+    }
+
+    fn notify(&self, queue: u32) {
+        if self.select_queue(queue).is_err() {
+            return;
+        }
+
+        let offset = usize::from(self.common.queue_notify_off.read()) * self.notify_off_multiplier as usize;
+
+        // Make sure the device sees the updated rings before the notification
+        librust::mem::fence(librust::mem::FenceMode::Write);
+        // SAFETY: the notify region was checked to be in a mapped BAR when the
+        // transport was created, and the device picks offsets inside of it
+        unsafe { core::ptr::write_volatile(self.notify.add(offset).cast::<u16>(), queue as u16) };
+    }
+
+    fn acknowledge_interrupt(&self) -> InterruptCause {
+        // Reading the ISR status acknowledges the interrupt
+        let isr = self.isr.read();
+
+        InterruptCause { buffer_used: isr & ISR_QUEUE != 0, config_changed: isr & ISR_CONFIG != 0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMMON_OFFSET: u32 = 0x0000;
+    const ISR_OFFSET: u32 = 0x1000;
+    const DEVICE_OFFSET: u32 = 0x2000;
+    const NOTIFY_OFFSET: u32 = 0x3000;
+
+    /// A configuration space laid out the way QEMU lays out virtio-net's
+    fn config_space() -> Vec<u8> {
+        let mut config = vec![0; 256];
+        config[0x00..0x02].copy_from_slice(&VENDOR_ID.to_le_bytes());
+        config[0x02..0x04].copy_from_slice(&0x1041u16.to_le_bytes());
+        config[0x06..0x08].copy_from_slice(&STATUS_CAPABILITY_LIST.to_le_bytes());
+        config[CAPABILITIES_POINTER_OFFSET] = 0x84;
+
+        // MSI-X, which should be skipped over
+        config[0x84] = 0x11;
+        config[0x85] = 0x70;
+        vendor_capability(&mut config, 0x70, 0x60, CFG_TYPE_COMMON, COMMON_OFFSET);
+        vendor_capability(&mut config, 0x60, 0x50, CFG_TYPE_ISR, ISR_OFFSET);
+        vendor_capability(&mut config, 0x50, 0x40, CFG_TYPE_DEVICE, DEVICE_OFFSET);
+        vendor_capability(&mut config, 0x40, 0x00, CFG_TYPE_NOTIFY, NOTIFY_OFFSET);
+        config[0x40 + 16..0x40 + 20].copy_from_slice(&4u32.to_le_bytes());
+
+        config
+    }
+
+    fn vendor_capability(config: &mut [u8], at: usize, next: u8, cfg_type: u8, offset: u32) {
+        config[at] = CAPABILITY_VENDOR_SPECIFIC;
+        config[at + 1] = next;
+        config[at + 2] = if cfg_type == CFG_TYPE_NOTIFY { 20 } else { 16 };
+        config[at + 3] = cfg_type;
+        config[at + 4] = 4;
+        config[at + 8..at + 12].copy_from_slice(&offset.to_le_bytes());
+        config[at + 12..at + 16].copy_from_slice(&0x1000u32.to_le_bytes());
+    }
+
+    fn region(offset: u32) -> BarRegion {
+        BarRegion { bar: 4, offset, length: 0x1000 }
+    }
+
+    #[test]
+    fn finds_every_capability() {
+        let capabilities = find_capabilities(&config_space()).unwrap();
+
+        assert_eq!(capabilities.common, region(COMMON_OFFSET));
+        assert_eq!(capabilities.isr, region(ISR_OFFSET));
+        assert_eq!(capabilities.device, Some(region(DEVICE_OFFSET)));
+        assert_eq!(capabilities.notify, region(NOTIFY_OFFSET));
+        assert_eq!(capabilities.notify_off_multiplier, 4);
+    }
+
+    #[test]
+    fn first_capability_of_a_type_wins() {
+        let mut config = config_space();
+        // Chain a second common configuration on after the rest
+        config[0x41] = 0x90;
+        vendor_capability(&mut config, 0x90, 0x00, CFG_TYPE_COMMON, 0x8000);
+
+        assert_eq!(find_capabilities(&config).unwrap().common, region(COMMON_OFFSET));
+    }
+
+    #[test]
+    fn device_config_is_optional() {
+        let mut config = config_space();
+        config[0x50 + 3] = 0xFF;
+
+        assert_eq!(find_capabilities(&config).unwrap().device, None);
+    }
+
+    #[test]
+    fn missing_capabilities_are_rejected() {
+        let mut config = config_space();
+        config[0x06] = 0;
+        assert!(find_capabilities(&config).is_err());
+
+        for (at, next) in [(0x70, 0x60), (0x60, 0x50), (0x40, 0x00)] {
+            let mut config = config_space();
+            // Hide the capability by making it look like some other kind
+            config[at] = 0x05;
+            config[at + 1] = next;
+            assert!(find_capabilities(&config).is_err(), "capability at {:#x} wasn't required", at);
+        }
+    }
+
+    #[test]
+    fn reserved_bars_are_ignored() {
+        let mut config = config_space();
+        config[0x70 + 4] = 6;
+
+        assert!(find_capabilities(&config).is_err());
+    }
+
+    #[test]
+    fn looping_capability_list_terminates() {
+        let mut config = config_space();
+        config[0x41] = 0x70;
+
+        assert!(find_capabilities(&config).is_ok());
+
+        config[0x71] = 0x70;
+        assert!(find_capabilities(&config).is_err());
+    }
+
+    #[test]
+    fn truncated_config_space_is_rejected() {
+        let config = config_space();
+
+        assert!(find_capabilities(&config[..0x20]).is_err());
+        assert!(find_capabilities(&config[..0x48]).is_err());
+    }
+
+    #[test]
+    fn device_ids() {
+        assert_eq!(device_type(VENDOR_ID, 0x1041), Some(DeviceType::NetworkCard));
+        assert_eq!(device_type(VENDOR_ID, 0x1042), Some(DeviceType::BlockDevice));
+        assert_eq!(device_type(VENDOR_ID, 0x1044), Some(DeviceType::EntropySource));
+        assert_eq!(device_type(VENDOR_ID, 0x1000), Some(DeviceType::NetworkCard));
+        assert_eq!(device_type(VENDOR_ID, 0x1003), Some(DeviceType::Console));
+        assert_eq!(device_type(VENDOR_ID, 0x1005), Some(DeviceType::EntropySource));
+        assert_eq!(device_type(VENDOR_ID, 0x1080), None);
+        assert_eq!(device_type(0x8086, 0x1041), None);
+    }
+
+    #[test]
+    fn common_config_layout() {
+        assert_eq!(core::mem::size_of::<CommonConfig>(), 0x38);
+    }
+
+    #[test]
+    fn registers_are_at_the_right_offsets() {
+        // `u64`s so the fake BAR is aligned like real device memory
+        let mut bar = vec![0u64; 0x4000 / 8];
+        let base = bar.as_mut_ptr().cast::<u8>();
+        let mut bars = [None; 6];
+        bars[4] = Some(base);
+
+        let at = |offset: u32| unsafe { base.add(offset as usize) };
+        unsafe {
+            // num_queues, queue_size, and queue_notify_off
+            at(COMMON_OFFSET + 0x12).cast::<u16>().write(2);
+            at(COMMON_OFFSET + 0x18).cast::<u16>().write(256);
+            at(COMMON_OFFSET + 0x1E).cast::<u16>().write(3);
+            // device_feature
+            at(COMMON_OFFSET + 0x04).cast::<u32>().write(0x8000_0001);
+            at(ISR_OFFSET).write(ISR_QUEUE | ISR_CONFIG);
+        }
+
+        let transport = unsafe { PciTransport::new(&config_space(), bars) }.unwrap();
+        assert_eq!(transport.device_type(), Some(DeviceType::NetworkCard));
+        assert_eq!(transport.device_config(), Some(at(DEVICE_OFFSET)));
+        assert_eq!(transport.device_features(), 0x8000_0001_8000_0001);
+        assert_eq!(transport.max_queue_size(1), 256);
+        assert_eq!(transport.max_queue_size(2), 0);
+
+        transport.set_status(StatusFlag::Acknowledge);
+        transport.set_status(StatusFlag::Driver);
+        assert!(transport.status_is_set(StatusFlag::Driver));
+        assert_eq!(
+            unsafe { at(COMMON_OFFSET + 0x14).read() },
+            StatusFlag::Acknowledge as u8 | StatusFlag::Driver as u8
+        );
+
+        transport.set_driver_features(0x1_0000_0020);
+        assert_eq!(unsafe { at(COMMON_OFFSET + 0x08).cast::<u32>().read() }, 1);
+        assert_eq!(unsafe { at(COMMON_OFFSET + 0x0C).cast::<u32>().read() }, 1);
+
+        // queue_notify_off of 3 times the multiplier of 4
+        transport.notify(1);
+        assert_eq!(unsafe { at(NOTIFY_OFFSET + 12).cast::<u16>().read() }, 1);
+
+        assert_eq!(transport.acknowledge_interrupt(), InterruptCause { buffer_used: true, config_changed: true });
+
+        drop(bar);
+    }
+
+    #[test]
+    fn unmapped_bars_are_rejected() {
+        assert!(unsafe { PciTransport::new(&config_space(), [None; 6]) }.is_err());
+    }
+}
@@ -0,0 +1,291 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+
+crate::derive! {
+    /// Serializable so a server can tell a client its request was malformed
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum DecodeError {
+        UnexpectedEnd,
+        InvalidBool(u8),
+        InvalidChar(u32),
+        InvalidUtf8,
+        InvalidVariant(u32),
+        IntegerOverflow,
+        TrailingBytes(usize),
+        VersionMismatch { expected: u8, found: u8 },
+    }
+}
+
+impl core::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            DecodeError::UnexpectedEnd => write!(f, "unexpected end of input"),
+            DecodeError::InvalidBool(n) => write!(f, "invalid boolean value: {}", n),
+            DecodeError::InvalidChar(n) => write!(f, "invalid character value: {:#x}", n),
+            DecodeError::InvalidUtf8 => write!(f, "string contained invalid UTF-8"),
+            DecodeError::InvalidVariant(n) => write!(f, "unknown variant discriminant: {}", n),
+            DecodeError::IntegerOverflow => write!(f, "integer value too large for its type"),
+            DecodeError::TrailingBytes(n) => write!(f, "{} unused bytes after value", n),
+            DecodeError::VersionMismatch { expected, found } => {
+                write!(f, "format version mismatch: expected {}, found {}", expected, found)
+            }
+        }
+    }
+}
+
+/// Reads values out of a byte slice, borrowing from it where possible
+#[derive(Debug, Clone)]
+pub struct Decoder<'de> {
+    bytes: &'de [u8],
+}
+
+impl<'de> Decoder<'de> {
+    pub fn new(bytes: &'de [u8]) -> Self {
+        Self { bytes }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, DecodeError> {
+        let (first, rest) = self.bytes.split_first().ok_or(DecodeError::UnexpectedEnd)?;
+        self.bytes = rest;
+        Ok(*first)
+    }
+
+    pub fn read_bytes(&mut self, n: usize) -> Result<&'de [u8], DecodeError> {
+        if n > self.bytes.len() {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+
+        let (bytes, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+
+    /// Read an unsigned LEB128 encoded integer
+    pub fn read_varint(&mut self) -> Result<u64, DecodeError> {
+        let mut n = 0u64;
+        let mut shift = 0;
+
+        loop {
+            let byte = self.read_u8()?;
+            let bits = (byte & 0x7F) as u64;
+
+            if (shift == 63 && bits > 1) || shift > 63 {
+                return Err(DecodeError::IntegerOverflow);
+            }
+
+            n |= bits << shift;
+            shift += 7;
+
+            if byte & 0x80 == 0 {
+                break Ok(n);
+            }
+        }
+    }
+
+    /// Read a zigzag + LEB128 encoded integer
+    pub fn read_signed_varint(&mut self) -> Result<i64, DecodeError> {
+        let n = self.read_varint()?;
+        Ok(((n >> 1) as i64) ^ -((n & 1) as i64))
+    }
+
+    pub fn read_len(&mut self) -> Result<usize, DecodeError> {
+        usize::try_from(self.read_varint()?).map_err(|_| DecodeError::IntegerOverflow)
+    }
+
+    /// Read a length-prefixed byte slice
+    pub fn read_slice(&mut self) -> Result<&'de [u8], DecodeError> {
+        let len = self.read_len()?;
+        self.read_bytes(len)
+    }
+
+    /// Reads a value written with [`crate::ser::Encoder::write_delimited`],
+    /// ignoring any data past what `f` consumes
+    pub fn read_delimited<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T, DecodeError>) -> Result<T, DecodeError> {
+        let mut inner = Decoder::new(self.read_slice()?);
+        f(&mut inner)
+    }
+
+    pub fn finish(self) -> Result<(), DecodeError> {
+        match self.bytes.len() {
+            0 => Ok(()),
+            n => Err(DecodeError::TrailingBytes(n)),
+        }
+    }
+}
+
+pub trait Deserialize<'de>: Sized {
+    fn deserialize(decoder: &mut Decoder<'de>) -> Result<Self, DecodeError>;
+}
+
+/// A type which can be deserialized without borrowing from the input
+pub trait DeserializeOwned: for<'de> Deserialize<'de> {}
+impl<T> DeserializeOwned for T where T: for<'de> Deserialize<'de> {}
+
+impl<'de> Deserialize<'de> for () {
+    fn deserialize(_: &mut Decoder<'de>) -> Result<Self, DecodeError> {
+        Ok(())
+    }
+}
+
+impl<'de> Deserialize<'de> for bool {
+    fn deserialize(decoder: &mut Decoder<'de>) -> Result<Self, DecodeError> {
+        match decoder.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            n => Err(DecodeError::InvalidBool(n)),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for u8 {
+    fn deserialize(decoder: &mut Decoder<'de>) -> Result<Self, DecodeError> {
+        decoder.read_u8()
+    }
+}
+
+impl<'de> Deserialize<'de> for i8 {
+    fn deserialize(decoder: &mut Decoder<'de>) -> Result<Self, DecodeError> {
+        Ok(decoder.read_u8()? as i8)
+    }
+}
+
+macro_rules! impl_varint {
+    ($($t:ty),+) => {
+        $(
+            impl<'de> Deserialize<'de> for $t {
+                fn deserialize(decoder: &mut Decoder<'de>) -> Result<Self, DecodeError> {
+                    <$t>::try_from(decoder.read_varint()?).map_err(|_| DecodeError::IntegerOverflow)
+                }
+            }
+        )+
+    };
+}
+
+macro_rules! impl_signed_varint {
+    ($($t:ty),+) => {
+        $(
+            impl<'de> Deserialize<'de> for $t {
+                fn deserialize(decoder: &mut Decoder<'de>) -> Result<Self, DecodeError> {
+                    <$t>::try_from(decoder.read_signed_varint()?).map_err(|_| DecodeError::IntegerOverflow)
+                }
+            }
+        )+
+    };
+}
+
+impl_varint!(u16, u32, u64, usize);
+impl_signed_varint!(i16, i32, i64, isize);
+
+impl<'de> Deserialize<'de> for char {
+    fn deserialize(decoder: &mut Decoder<'de>) -> Result<Self, DecodeError> {
+        let n = u32::deserialize(decoder)?;
+        char::from_u32(n).ok_or(DecodeError::InvalidChar(n))
+    }
+}
+
+impl<'de> Deserialize<'de> for &'de str {
+    fn deserialize(decoder: &mut Decoder<'de>) -> Result<Self, DecodeError> {
+        core::str::from_utf8(decoder.read_slice()?).map_err(|_| DecodeError::InvalidUtf8)
+    }
+}
+
+impl<'de> Deserialize<'de> for &'de [u8] {
+    fn deserialize(decoder: &mut Decoder<'de>) -> Result<Self, DecodeError> {
+        decoder.read_slice()
+    }
+}
+
+impl<'de> Deserialize<'de> for String {
+    fn deserialize(decoder: &mut Decoder<'de>) -> Result<Self, DecodeError> {
+        <&str>::deserialize(decoder).map(String::from)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Vec<T> {
+    fn deserialize(decoder: &mut Decoder<'de>) -> Result<Self, DecodeError> {
+        // Every element takes at least a byte, so a longer length is bogus and
+        // would have us preallocate a huge buffer. Elements which take none,
+        // like `()`, are held to the same limit rather than spinning through
+        // however many a bogus length asks for.
+        let len = decoder.read_len()?;
+        if len > decoder.remaining() {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+
+        let mut v = Vec::with_capacity(len);
+
+        for _ in 0..len {
+            v.push(T::deserialize(decoder)?);
+        }
+
+        Ok(v)
+    }
+}
+
+impl<'de, T: Deserialize<'de>, const N: usize> Deserialize<'de> for [T; N] {
+    fn deserialize(decoder: &mut Decoder<'de>) -> Result<Self, DecodeError> {
+        let mut v = Vec::with_capacity(N);
+
+        for _ in 0..N {
+            v.push(T::deserialize(decoder)?);
+        }
+
+        match v.try_into() {
+            Ok(array) => Ok(array),
+            Err(_) => unreachable!(),
+        }
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Box<T> {
+    fn deserialize(decoder: &mut Decoder<'de>) -> Result<Self, DecodeError> {
+        T::deserialize(decoder).map(Box::new)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Option<T> {
+    fn deserialize(decoder: &mut Decoder<'de>) -> Result<Self, DecodeError> {
+        match decoder.read_u8()? {
+            0 => Ok(None),
+            1 => T::deserialize(decoder).map(Some),
+            n => Err(DecodeError::InvalidVariant(n as u32)),
+        }
+    }
+}
+
+impl<'de, T: Deserialize<'de>, E: Deserialize<'de>> Deserialize<'de> for Result<T, E> {
+    fn deserialize(decoder: &mut Decoder<'de>) -> Result<Self, DecodeError> {
+        match decoder.read_u8()? {
+            0 => T::deserialize(decoder).map(Ok),
+            1 => E::deserialize(decoder).map(Err),
+            n => Err(DecodeError::InvalidVariant(n as u32)),
+        }
+    }
+}
+
+macro_rules! impl_tuple {
+    ($($g:ident),+) => {
+        impl<'de, $($g: Deserialize<'de>),+> Deserialize<'de> for ($($g,)+) {
+            fn deserialize(decoder: &mut Decoder<'de>) -> Result<Self, DecodeError> {
+                Ok(($($g::deserialize(decoder)?,)+))
+            }
+        }
+    };
+}
+
+impl_tuple!(A);
+impl_tuple!(A, B);
+impl_tuple!(A, B, C);
+impl_tuple!(A, B, C, D);
+impl_tuple!(A, B, C, D, E);
+impl_tuple!(A, B, C, D, E, F);
@@ -0,0 +1,306 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A compact binary serialization format for IPC messages
+//!
+//! Integers are LEB128 encoded (zigzag for signed integers), strings and
+//! slices are length-prefixed, and structs are wrapped in a length prefix so
+//! that fields can be appended to a message without breaking older readers.
+//! Borrowed types like `&str` and `&[u8]` are deserialized without copying.
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod de;
+pub mod ser;
+
+pub use de::{DecodeError, Decoder, Deserialize, DeserializeOwned};
+pub use ser::{Encoder, Serialize};
+
+use alloc::vec::Vec;
+
+/// Bumped whenever the encoding of a built-in type changes
+pub const FORMAT_VERSION: u8 = 1;
+
+#[macro_export]
+macro_rules! derive {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident<$lt:lifetime> {
+            $($(#[$fattr:meta])* $fvis:vis $field:ident: $t:ty),* $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        $vis struct $name<$lt> {
+            $($(#[$fattr])* $fvis $field: $t),*
+        }
+
+        impl<$lt> $crate::Serialize for $name<$lt> {
+            $crate::derive!(@ser struct { $($field),* });
+        }
+
+        impl<$lt> $crate::Deserialize<$lt> for $name<$lt> {
+            $crate::derive!(@de struct $lt { $($field),* });
+        }
+    };
+
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident$(<$($g:ident),+ $(,)?>)? {
+            $($(#[$fattr:meta])* $fvis:vis $field:ident: $t:ty),* $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        $vis struct $name$(<$($g),+>)? {
+            $($(#[$fattr])* $fvis $field: $t),*
+        }
+
+        impl$(<$($g: $crate::Serialize),+>)? $crate::Serialize for $name$(<$($g),+>)? {
+            $crate::derive!(@ser struct { $($field),* });
+        }
+
+        impl<'de, $($($g: $crate::Deserialize<'de>),+)?> $crate::Deserialize<'de> for $name$(<$($g),+>)? {
+            $crate::derive!(@de struct 'de { $($field),* });
+        }
+    };
+
+    (
+        $(#[$attr:meta])*
+        $vis:vis enum $name:ident$(<$($g:ident),+ $(,)?>)? {
+            $(
+                $(#[$vattr:meta])*
+                $variant:ident $(($vt:ty))? $({ $($vfield:ident: $vft:ty),* $(,)? })?
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        $vis enum $name$(<$($g),+>)? {
+            $(
+                $(#[$vattr])*
+                $variant $(($vt))? $({ $($vfield: $vft),* })?
+            ),*
+        }
+
+        impl$(<$($g: $crate::Serialize),+>)? $crate::Serialize for $name$(<$($g),+>)? {
+            #[allow(irrefutable_let_patterns)]
+            fn serialize(&self, encoder: &mut $crate::Encoder) {
+                #[allow(dead_code, non_camel_case_types)]
+                #[repr(u32)]
+                enum Discriminant { $($variant),* }
+
+                $(
+                    $crate::derive!(
+                        @ser variant self, encoder, Discriminant::$variant as u64,
+                        $variant $(($vt))? $({ $($vfield),* })?
+                    );
+                )*
+
+                unreachable!()
+            }
+        }
+
+        impl<'de, $($($g: $crate::Deserialize<'de>),+)?> $crate::Deserialize<'de> for $name$(<$($g),+>)? {
+            fn deserialize(decoder: &mut $crate::Decoder<'de>) -> Result<Self, $crate::DecodeError> {
+                #[allow(dead_code, non_camel_case_types)]
+                #[repr(u32)]
+                enum Discriminant { $($variant),* }
+
+                let discriminant = <u32 as $crate::Deserialize>::deserialize(decoder)?;
+
+                $(
+                    if discriminant == Discriminant::$variant as u32 {
+                        return Ok($crate::derive!(@de variant decoder, $variant $(($vt))? $({ $($vfield),* })?));
+                    }
+                )*
+
+                Err($crate::DecodeError::InvalidVariant(discriminant))
+            }
+        }
+    };
+
+    (@ser struct { $($field:ident),* }) => {
+        fn serialize(&self, encoder: &mut $crate::Encoder) {
+            encoder.write_delimited(|_encoder| {
+                $($crate::Serialize::serialize(&self.$field, _encoder);)*
+            });
+        }
+    };
+
+    (@de struct $lt:lifetime { $($field:ident),* }) => {
+        fn deserialize(decoder: &mut $crate::Decoder<$lt>) -> Result<Self, $crate::DecodeError> {
+            decoder.read_delimited(|_decoder| {
+                Ok(Self {
+                    $($field: $crate::Deserialize::deserialize(_decoder)?),*
+                })
+            })
+        }
+    };
+
+    (@ser variant $self:ident, $encoder:ident, $discriminant:expr, $variant:ident) => {
+        if let Self::$variant = $self {
+            $encoder.write_varint($discriminant);
+            return;
+        }
+    };
+
+    (@ser variant $self:ident, $encoder:ident, $discriminant:expr, $variant:ident($vt:ty)) => {
+        if let Self::$variant(value) = $self {
+            $encoder.write_varint($discriminant);
+            $crate::Serialize::serialize(value, $encoder);
+            return;
+        }
+    };
+
+    (@ser variant $self:ident, $encoder:ident, $discriminant:expr, $variant:ident { $($vfield:ident),* }) => {
+        if let Self::$variant { $($vfield),* } = $self {
+            $encoder.write_varint($discriminant);
+            $($crate::Serialize::serialize($vfield, $encoder);)*
+            return;
+        }
+    };
+
+    (@de variant $decoder:ident, $variant:ident) => {
+        Self::$variant
+    };
+
+    (@de variant $decoder:ident, $variant:ident($vt:ty)) => {
+        Self::$variant($crate::Deserialize::deserialize($decoder)?)
+    };
+
+    (@de variant $decoder:ident, $variant:ident { $($vfield:ident),* }) => {
+        Self::$variant { $($vfield: $crate::Deserialize::deserialize($decoder)?),* }
+    };
+}
+
+/// Serialize a value, prefixed with the format version
+pub fn to_bytes<T: Serialize + ?Sized>(t: &T) -> Vec<u8> {
+    let mut encoder = Encoder::new();
+    encoder.write_u8(FORMAT_VERSION);
+    t.serialize(&mut encoder);
+    encoder.into_bytes()
+}
+
+/// Deserialize a value produced by [`to_bytes`], erroring if there's any
+/// unused data after the value
+pub fn from_bytes<'de, T: Deserialize<'de>>(bytes: &'de [u8]) -> Result<T, DecodeError> {
+    let mut decoder = Decoder::new(bytes);
+
+    match decoder.read_u8()? {
+        FORMAT_VERSION => {}
+        found => return Err(DecodeError::VersionMismatch { expected: FORMAT_VERSION, found }),
+    }
+
+    let t = T::deserialize(&mut decoder)?;
+    decoder.finish()?;
+
+    Ok(t)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use alloc::{string::String, vec};
+
+    derive! {
+        #[derive(Debug, PartialEq)]
+        struct Request {
+            id: u64,
+            path: String,
+            offset: i32,
+            flags: Option<u8>,
+        }
+    }
+
+    derive! {
+        #[derive(Debug, PartialEq)]
+        struct RequestV2 {
+            id: u64,
+            path: String,
+            offset: i32,
+            flags: Option<u8>,
+            extra: Vec<u16>,
+        }
+    }
+
+    derive! {
+        #[derive(Debug, PartialEq)]
+        enum Response {
+            Ok,
+            Data(Vec<u8>),
+            Error { code: u32, message: String },
+        }
+    }
+
+    derive! {
+        #[derive(Debug, PartialEq)]
+        struct Borrowed<'a> {
+            name: &'a str,
+            data: &'a [u8],
+        }
+    }
+
+    #[test]
+    fn integers_round_trip() {
+        for n in [0u64, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            assert_eq!(from_bytes::<u64>(&to_bytes(&n)), Ok(n));
+        }
+
+        for n in [0i64, -1, 1, i64::MIN, i64::MAX] {
+            assert_eq!(from_bytes::<i64>(&to_bytes(&n)), Ok(n));
+        }
+
+        assert_eq!(from_bytes::<u8>(&to_bytes(&300u16)), Err(DecodeError::TrailingBytes(1)));
+        assert_eq!(from_bytes::<u16>(&to_bytes(&70000u32)), Err(DecodeError::IntegerOverflow));
+    }
+
+    #[test]
+    fn structs_and_enums_round_trip() {
+        let request = Request { id: 5, path: String::from("/etc/motd"), offset: -12, flags: Some(3) };
+        assert_eq!(from_bytes::<Request>(&to_bytes(&request)), Ok(request));
+
+        for response in [
+            Response::Ok,
+            Response::Data(vec![1, 2, 3]),
+            Response::Error { code: 2, message: String::from("not found") },
+        ] {
+            assert_eq!(from_bytes::<Response>(&to_bytes(&response)), Ok(response));
+        }
+
+        assert_eq!(from_bytes::<Response>(&[FORMAT_VERSION, 7]), Err(DecodeError::InvalidVariant(7)));
+    }
+
+    #[test]
+    fn newer_struct_versions_are_readable() {
+        let request = RequestV2 { id: 1, path: String::from("a"), offset: 0, flags: None, extra: vec![1, 2] };
+        let old = from_bytes::<Request>(&to_bytes(&request)).unwrap();
+        assert_eq!(old, Request { id: 1, path: String::from("a"), offset: 0, flags: None });
+    }
+
+    #[test]
+    fn borrowed_data_is_zero_copy() {
+        let bytes = to_bytes(&Borrowed { name: "hello", data: &[4, 5, 6] });
+        let borrowed = from_bytes::<Borrowed<'_>>(&bytes).unwrap();
+
+        assert_eq!(borrowed, Borrowed { name: "hello", data: &[4, 5, 6] });
+        assert!(bytes.as_ptr_range().contains(&borrowed.name.as_ptr()));
+    }
+
+    #[test]
+    fn bad_input_is_rejected() {
+        assert_eq!(from_bytes::<u32>(&[]), Err(DecodeError::UnexpectedEnd));
+        assert_eq!(from_bytes::<u32>(&[0xFF, 1]), Err(DecodeError::VersionMismatch { expected: 1, found: 0xFF }));
+        assert_eq!(from_bytes::<String>(&[FORMAT_VERSION, 10, b'a']), Err(DecodeError::UnexpectedEnd));
+        assert_eq!(from_bytes::<bool>(&[FORMAT_VERSION, 2]), Err(DecodeError::InvalidBool(2)));
+
+        let huge_len = [FORMAT_VERSION, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01];
+        assert_eq!(from_bytes::<Vec<u8>>(&huge_len), Err(DecodeError::UnexpectedEnd));
+        assert_eq!(from_bytes::<Vec<()>>(&huge_len), Err(DecodeError::UnexpectedEnd));
+        assert_eq!(from_bytes::<Vec<u32>>(&[FORMAT_VERSION, 3, 1, 2]), Err(DecodeError::UnexpectedEnd));
+    }
+}
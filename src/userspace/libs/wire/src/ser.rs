@@ -0,0 +1,232 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+
+/// Accumulates the encoded form of a value
+#[derive(Debug, Default, Clone)]
+pub struct Encoder {
+    buffer: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self { buffer: Vec::new() }
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self { buffer: Vec::with_capacity(capacity) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buffer
+    }
+
+    pub fn write_u8(&mut self, n: u8) {
+        self.buffer.push(n);
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Write an unsigned LEB128 encoded integer
+    pub fn write_varint(&mut self, mut n: u64) {
+        loop {
+            let byte = (n & 0x7F) as u8;
+            n >>= 7;
+
+            match n {
+                0 => break self.write_u8(byte),
+                _ => self.write_u8(byte | 0x80),
+            }
+        }
+    }
+
+    /// Write a zigzag + LEB128 encoded integer
+    pub fn write_signed_varint(&mut self, n: i64) {
+        self.write_varint(((n << 1) ^ (n >> 63)) as u64);
+    }
+
+    /// Write a length-prefixed byte slice
+    pub fn write_slice(&mut self, bytes: &[u8]) {
+        self.write_varint(bytes.len() as u64);
+        self.write_bytes(bytes);
+    }
+
+    /// Encodes the output of `f` with a length prefix so that readers can skip
+    /// any trailing data they don't know about
+    pub fn write_delimited(&mut self, f: impl FnOnce(&mut Self)) {
+        let mut inner = Encoder::new();
+        f(&mut inner);
+        self.write_slice(&inner.buffer);
+    }
+}
+
+pub trait Serialize {
+    fn serialize(&self, encoder: &mut Encoder);
+}
+
+impl<T: Serialize + ?Sized> Serialize for &'_ T {
+    fn serialize(&self, encoder: &mut Encoder) {
+        (**self).serialize(encoder);
+    }
+}
+
+impl Serialize for () {
+    fn serialize(&self, _: &mut Encoder) {}
+}
+
+impl Serialize for bool {
+    fn serialize(&self, encoder: &mut Encoder) {
+        encoder.write_u8(*self as u8);
+    }
+}
+
+impl Serialize for u8 {
+    fn serialize(&self, encoder: &mut Encoder) {
+        encoder.write_u8(*self);
+    }
+}
+
+impl Serialize for i8 {
+    fn serialize(&self, encoder: &mut Encoder) {
+        encoder.write_u8(*self as u8);
+    }
+}
+
+macro_rules! impl_varint {
+    ($($t:ty),+) => {
+        $(
+            impl Serialize for $t {
+                fn serialize(&self, encoder: &mut Encoder) {
+                    encoder.write_varint(*self as u64);
+                }
+            }
+        )+
+    };
+}
+
+macro_rules! impl_signed_varint {
+    ($($t:ty),+) => {
+        $(
+            impl Serialize for $t {
+                fn serialize(&self, encoder: &mut Encoder) {
+                    encoder.write_signed_varint(*self as i64);
+                }
+            }
+        )+
+    };
+}
+
+impl_varint!(u16, u32, u64, usize);
+impl_signed_varint!(i16, i32, i64, isize);
+
+impl Serialize for char {
+    fn serialize(&self, encoder: &mut Encoder) {
+        encoder.write_varint(*self as u64);
+    }
+}
+
+impl Serialize for str {
+    fn serialize(&self, encoder: &mut Encoder) {
+        encoder.write_slice(self.as_bytes());
+    }
+}
+
+impl Serialize for String {
+    fn serialize(&self, encoder: &mut Encoder) {
+        encoder.write_slice(self.as_bytes());
+    }
+}
+
+impl<T: Serialize> Serialize for [T] {
+    fn serialize(&self, encoder: &mut Encoder) {
+        encoder.write_varint(self.len() as u64);
+        for t in self {
+            t.serialize(encoder);
+        }
+    }
+}
+
+impl<T: Serialize, const N: usize> Serialize for [T; N] {
+    fn serialize(&self, encoder: &mut Encoder) {
+        for t in self {
+            t.serialize(encoder);
+        }
+    }
+}
+
+impl<T: Serialize> Serialize for Vec<T> {
+    fn serialize(&self, encoder: &mut Encoder) {
+        self[..].serialize(encoder);
+    }
+}
+
+impl<T: Serialize + ?Sized> Serialize for Box<T> {
+    fn serialize(&self, encoder: &mut Encoder) {
+        (**self).serialize(encoder);
+    }
+}
+
+impl<T: Serialize> Serialize for Option<T> {
+    fn serialize(&self, encoder: &mut Encoder) {
+        match self {
+            Some(t) => {
+                encoder.write_u8(1);
+                t.serialize(encoder);
+            }
+            None => encoder.write_u8(0),
+        }
+    }
+}
+
+impl<T: Serialize, E: Serialize> Serialize for Result<T, E> {
+    fn serialize(&self, encoder: &mut Encoder) {
+        match self {
+            Ok(t) => {
+                encoder.write_u8(0);
+                t.serialize(encoder);
+            }
+            Err(e) => {
+                encoder.write_u8(1);
+                e.serialize(encoder);
+            }
+        }
+    }
+}
+
+macro_rules! impl_tuple {
+    ($($g:ident),+) => {
+        impl<$($g: Serialize),+> Serialize for ($($g,)+) {
+            #[allow(non_snake_case)]
+            fn serialize(&self, encoder: &mut Encoder) {
+                let ($($g,)+) = self;
+                $($g.serialize(encoder);)+
+            }
+        }
+    };
+}
+
+impl_tuple!(A);
+impl_tuple!(A, B);
+impl_tuple!(A, B, C);
+impl_tuple!(A, B, C, D);
+impl_tuple!(A, B, C, D, E);
+impl_tuple!(A, B, C, D, E, F);
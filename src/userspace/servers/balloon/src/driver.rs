@@ -0,0 +1,167 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use librust::{
+    capabilities::CapabilityPtr,
+    mem::DmaRegion,
+    syscalls::mem::{self, DeflateOptions},
+};
+use virtio::{
+    devices::balloon::{BalloonDeviceFeatures, VirtIoBalloonDevice, DEFLATE_QUEUE, INFLATE_QUEUE},
+    splitqueue::{DescriptorFlags, SplitVirtqueue, SplitqueueIndex, VirtqueueDescriptor},
+    MmioTransport, Transport, VirtIoDeviceError,
+};
+
+const QUEUE_SIZE: usize = 8;
+/// The most pages moved in or out of the balloon in a single request
+const PFNS_PER_REQUEST: usize = 256;
+
+pub struct VirtIoBalloon {
+    device: &'static VirtIoBalloonDevice,
+    /// The capability allowing pages to be moved in and out of the balloon
+    pages: CapabilityPtr,
+    transport: MmioTransport,
+    inflate: PfnQueue,
+    deflate: PfnQueue,
+    /// The number of pages the kernel has handed to us, some of which the
+    /// device may not have been told about yet
+    held: u32,
+}
+
+impl VirtIoBalloon {
+    pub fn new(device: &'static VirtIoBalloonDevice, pages: CapabilityPtr) -> Result<Self, VirtIoDeviceError> {
+        let transport = MmioTransport::new(&device.header)?;
+        transport.begin_init();
+
+        // `MUST_TELL_HOST` is never accepted, since the kernel takes pages back
+        // out of the balloon on its own when it runs out of memory and can't
+        // wait on the device before using them
+        transport.negotiate_features(0, BalloonDeviceFeatures::DEFLATE_ON_OOM.bits())?;
+
+        let inflate = PfnQueue::new(&transport, INFLATE_QUEUE)?;
+        let deflate = PfnQueue::new(&transport, DEFLATE_QUEUE)?;
+        transport.finish_init()?;
+
+        Ok(Self { device, pages, transport, inflate, deflate, held: device.actual() })
+    }
+
+    pub fn process_interrupt(&mut self) {
+        self.transport.acknowledge_interrupt();
+        self.adjust();
+    }
+
+    /// Move the balloon a step closer to the size the host asked for, and tell
+    /// the host about any pages the kernel took back since the last step. Only
+    /// one request is sent to each queue at a time, so this is called again
+    /// whenever the device finishes with one.
+    pub fn adjust(&mut self) {
+        let mut actual = self.device.actual();
+        actual += self.inflate.complete();
+        actual -= self.deflate.complete();
+        self.device.set_actual(actual);
+
+        let target = self.device.num_pages();
+
+        if self.inflate.idle() && self.held < target {
+            let want = PFNS_PER_REQUEST.min((target - self.held) as usize);
+            match mem::inflate_balloon(self.pages, &mut self.inflate.pfns[..want]) {
+                Ok(0) => {}
+                Ok(n) => {
+                    self.held += n as u32;
+                    self.inflate.submit(&self.transport, n);
+                }
+                Err(e) => log::warn!("Failed to inflate balloon: {:?}", e),
+            }
+        }
+
+        if self.deflate.idle() {
+            // Reclaimed pages are always handed back first, so there's no need
+            // to ask for them separately when deflating
+            let (want, options) = match self.held > target {
+                true => (PFNS_PER_REQUEST.min((self.held - target) as usize), DeflateOptions::NONE),
+                false => (PFNS_PER_REQUEST, DeflateOptions::RECLAIMED_ONLY),
+            };
+
+            match mem::deflate_balloon(self.pages, &mut self.deflate.pfns[..want], options) {
+                Ok(0) => {}
+                Ok(n) => {
+                    self.held -= n as u32;
+                    self.deflate.submit(&self.transport, n);
+                }
+                Err(e) => log::warn!("Failed to deflate balloon: {:?}", e),
+            }
+        }
+    }
+
+    /// Hand every page in the balloon back to the kernel, for when the device
+    /// has gone away and can't be told about it anymore
+    pub fn release(&mut self) {
+        while self.held > 0 {
+            let want = PFNS_PER_REQUEST.min(self.held as usize);
+            match mem::deflate_balloon(self.pages, &mut self.deflate.pfns[..want], DeflateOptions::NONE) {
+                Ok(0) => break,
+                Ok(n) => self.held -= n as u32,
+                Err(e) => {
+                    log::warn!("Failed to release balloon: {:?}", e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// A queue of page frame number arrays, with at most one in flight at a time
+struct PfnQueue {
+    queue_number: u32,
+    queue: SplitVirtqueue,
+    pfns: DmaRegion<[u32; PFNS_PER_REQUEST]>,
+    /// The descriptor the device currently has and how many pages it describes
+    in_flight: Option<(SplitqueueIndex<VirtqueueDescriptor>, u32)>,
+}
+
+impl PfnQueue {
+    fn new(transport: &MmioTransport, queue_number: u32) -> Result<Self, VirtIoDeviceError> {
+        let queue = SplitVirtqueue::new(QUEUE_SIZE).unwrap();
+        transport.configure_queue(queue_number, &queue)?;
+
+        Ok(Self { queue_number, queue, pfns: unsafe { DmaRegion::zeroed().unwrap().assume_init() }, in_flight: None })
+    }
+
+    fn idle(&self) -> bool {
+        self.in_flight.is_none()
+    }
+
+    /// Hand the first `n` page frame numbers to the device
+    fn submit(&mut self, transport: &MmioTransport, n: usize) {
+        let descriptor = self.queue.alloc_descriptor().unwrap();
+        self.queue.descriptors.write(
+            descriptor,
+            VirtqueueDescriptor {
+                address: self.pfns.physical_address(),
+                length: (n * core::mem::size_of::<u32>()) as u32,
+                flags: DescriptorFlags::NONE,
+                next: SplitqueueIndex::new(0),
+            },
+        );
+        self.queue.available.push(descriptor);
+        self.in_flight = Some((descriptor, n as u32));
+
+        transport.notify(self.queue_number);
+    }
+
+    /// Returns the number of pages the device finished processing, if any
+    fn complete(&mut self) -> u32 {
+        match (self.queue.used.pop(), self.in_flight) {
+            (Some(_), Some((descriptor, n))) => {
+                self.queue.free_descriptor(descriptor);
+                self.in_flight = None;
+                n
+            }
+            _ => 0,
+        }
+    }
+}
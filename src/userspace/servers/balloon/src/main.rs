@@ -0,0 +1,130 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+mod driver;
+
+use driver::VirtIoBalloon;
+use interfaces::{devicemgr, HotplugEvent};
+use librust::{
+    capabilities::{Capability, CapabilityPtr, CapabilityWithDescription},
+    syscalls::channel::{ChannelMessage, KernelMessage},
+};
+use std::ipc::{ChannelReadFlags, IpcChannel, LagPolicy, Topic};
+
+/// How often the balloon is checked against the size the host asked for, which
+/// is also how quickly the host finds out about pages the kernel reclaimed
+const ADJUST_INTERVAL: core::time::Duration = core::time::Duration::from_secs(1);
+const ADJUST_TIMER: usize = 0;
+
+json::derive! {
+    #[derive(Debug, Clone)]
+    struct Device {
+        name: String,
+        compatible: Vec<String>,
+        interrupts: Vec<usize>,
+    }
+}
+
+json::derive! {
+    Serialize,
+    struct VirtIoDeviceRequest {
+        ty: u32,
+    }
+}
+
+json::derive! {
+    Deserialize,
+    #[derive(Debug)]
+    struct VirtIoDeviceResponse {
+        devices: Vec<Device>,
+    }
+}
+
+/// A balloon device and the driver for it
+struct Balloon {
+    name: String,
+    interrupt_id: usize,
+    driver: VirtIoBalloon,
+}
+
+/// Ask virtiomgr for a balloon device, which there might not be one of yet
+fn probe(virtiomgr: &IpcChannel, pages: CapabilityPtr) -> Option<Balloon> {
+    // The modern balloon device kept the legacy device ID
+    virtiomgr
+        .temp_send_json(
+            ChannelMessage::default(),
+            &VirtIoDeviceRequest { ty: virtio::DeviceType::MemoryBallooningTraditional as u32 },
+            &[],
+        )
+        .unwrap();
+
+    let (response, _, capabilities): (VirtIoDeviceResponse, _, _) =
+        virtiomgr.temp_read_json(ChannelReadFlags::NONE).unwrap();
+
+    if response.devices.is_empty() {
+        return None;
+    }
+
+    let (CapabilityWithDescription { capability: Capability { cptr: mmio_cap, .. }, .. }, device) =
+        (capabilities[0], &response.devices[0]);
+    let (info, _) = librust::syscalls::io::query_mmio_cap(mmio_cap, &mut []).unwrap();
+
+    let interrupt_id = device.interrupts[0];
+    let device_registers = unsafe { &*(info.address() as *const virtio::devices::balloon::VirtIoBalloonDevice) };
+    let mut driver = VirtIoBalloon::new(device_registers, pages).unwrap();
+
+    driver.adjust();
+    librust::syscalls::task::set_timer(ADJUST_TIMER, ADJUST_INTERVAL).unwrap();
+
+    Some(Balloon { name: device.name.clone(), interrupt_id, driver })
+}
+
+fn main() {
+    let virtiomgr = IpcChannel::new(std::env::lookup_capability("virtiomgr").unwrap().capability.cptr);
+    let devicemgr = devicemgr::Client::new(std::env::lookup_capability("devicemgr").unwrap().capability.cptr);
+    let pages = std::env::lookup_capability("kballoon").unwrap().capability.cptr;
+
+    // Balloon devices can be added and removed while the system is running, so
+    // keep listening for them even if there isn't one to start with
+    let (_, caps) = devicemgr.hotplug_events(&[]).unwrap();
+    let hotplug = Topic::from_cptr(caps[0].capability.cptr).subscribe(LagPolicy::DropOldest).unwrap();
+
+    librust::syscalls::task::enable_notifications();
+    let mut balloon = probe(&virtiomgr, pages);
+
+    loop {
+        match librust::syscalls::channel::read_kernel_message() {
+            KernelMessage::InterruptOccurred(id) => {
+                if let Some(balloon) = balloon.as_mut().filter(|balloon| balloon.interrupt_id == id) {
+                    balloon.driver.process_interrupt();
+                    librust::syscalls::io::complete_interrupt(id).unwrap();
+                }
+            }
+            KernelMessage::TimerExpired(ADJUST_TIMER) => {
+                if let Some(balloon) = &mut balloon {
+                    balloon.driver.adjust();
+                    librust::syscalls::task::set_timer(ADJUST_TIMER, ADJUST_INTERVAL).unwrap();
+                }
+            }
+            KernelMessage::NewChannelMessage(cptr) if cptr == hotplug.cptr() => {
+                while let Ok((event, _)) = hotplug.read_serialized::<HotplugEvent>(ChannelReadFlags::NONBLOCKING) {
+                    match event {
+                        HotplugEvent::Added(_) if balloon.is_none() => balloon = probe(&virtiomgr, pages),
+                        HotplugEvent::Removed(device) if balloon.as_ref().map(|b| &b.name) == Some(&device.name) => {
+                            log::info!("Balloon device {} removed", device.name);
+                            if let Some(mut balloon) = balloon.take() {
+                                balloon.driver.release();
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,96 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The config server holds the key-value configuration every task can reach
+//! through `std::env`. It starts out with the `config.<key>=<value>` options
+//! from the kernel command line, and init then sets the keys from its manifest
+//! which weren't given on the command line.
+
+use librust::{capabilities::CapabilityPtr, syscalls::channel::KernelMessage};
+use std::{
+    collections::BTreeMap,
+    env::config::{ConfigChange, Request, Response},
+    ipc::{ChannelReadFlags, IpcChannel},
+};
+
+const BOOTARGS_PREFIX: &str = "config.";
+
+#[derive(Debug, Default)]
+struct Config {
+    values: BTreeMap<String, String>,
+    /// The key prefixes each channel is watching
+    watchers: BTreeMap<CapabilityPtr, Vec<String>>,
+}
+
+impl Config {
+    fn set(&mut self, key: String, value: Option<String>) {
+        let changed = match &value {
+            Some(value) => self.values.insert(key.clone(), value.clone()).as_ref() != Some(value),
+            None => self.values.remove(&key).is_some(),
+        };
+
+        if !changed {
+            return;
+        }
+
+        let watching = self
+            .watchers
+            .iter()
+            .filter(|(_, prefixes)| prefixes.iter().any(|prefix| key.starts_with(&**prefix)))
+            .map(|(&cptr, _)| cptr)
+            .collect::<Vec<_>>();
+
+        let change = Response::Changed(ConfigChange { key, value });
+        for cptr in watching {
+            let _ = IpcChannel::new(cptr).send_serialized(&change, &[]);
+        }
+    }
+}
+
+fn main() {
+    let fdt = unsafe { fdt::Fdt::from_ptr(std::env::a2() as *const u8) }.unwrap();
+
+    let mut config = Config::default();
+    let args = fdt.chosen().bootargs().unwrap_or_default();
+    for arg in args.split(' ') {
+        if let Some((key, value)) = arg.strip_prefix(BOOTARGS_PREFIX).and_then(|arg| arg.split_once('=')) {
+            config.values.insert(key.to_string(), value.to_string());
+        }
+    }
+
+    librust::syscalls::task::enable_notifications();
+    loop {
+        if let KernelMessage::NewChannelMessage(cptr) = librust::syscalls::channel::read_kernel_message() {
+            handle_request(&mut config, cptr);
+        }
+    }
+}
+
+fn handle_request(config: &mut Config, cptr: CapabilityPtr) {
+    let channel = IpcChannel::new(cptr);
+    let request = match channel.read_serialized::<Request>(ChannelReadFlags::NONBLOCKING) {
+        Ok((request, _)) => request,
+        Err(_) => return,
+    };
+
+    let response = match request {
+        Request::Get { key } => Response::Value(config.values.get(&key).cloned()),
+        Request::Set { key, value } => {
+            // Reply first so a task watching the key it set sees the change
+            // after its set completes
+            let _ = channel.send_serialized(&Response::Done, &[]);
+            config.set(key, value);
+            return;
+        }
+        Request::Watch { prefix } => {
+            config.watchers.entry(cptr).or_default().push(prefix);
+            Response::Done
+        }
+    };
+
+    let _ = channel.send_serialized(&response, &[]);
+}
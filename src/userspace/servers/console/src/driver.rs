@@ -0,0 +1,324 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use librust::mem::DmaRegion;
+use std::collections::BTreeMap;
+use virtio::{
+    devices::console::{
+        self, ConsoleDeviceFeatures, ControlEvent, ControlMessage, VirtIoConsoleDevice, CONTROL_RECEIVE_QUEUE,
+        CONTROL_TRANSMIT_QUEUE,
+    },
+    splitqueue::{DescriptorFlags, SplitVirtqueue, SplitqueueIndex, VirtqueueDescriptor},
+    MmioTransport, Transport, VirtIoDeviceError,
+};
+
+const QUEUE_SIZE: usize = 16;
+const BUFFER_SIZE: usize = 256;
+/// The most ports queues are set up for, regardless of how many the device
+/// supports
+const MAX_PORTS: u32 = 8;
+
+type Buffer = [u8; BUFFER_SIZE];
+
+#[derive(Debug, Clone, Default)]
+pub struct Port {
+    pub name: Option<String>,
+    /// Whether the host marked this port as a console port
+    pub console: bool,
+    /// Whether something on the host side has the port open
+    pub host_connected: bool,
+}
+
+#[derive(Debug)]
+pub enum Event {
+    Received { port: u32, data: Vec<u8> },
+    HostConnected { port: u32, connected: bool },
+    PortRemoved(u32),
+}
+
+pub struct VirtIoConsole {
+    transport: MmioTransport,
+    control: Option<QueuePair>,
+    /// The queues for each port we were able to set up, indexed by port ID.
+    /// Queues have to be configured before the device is started, so these
+    /// exist whether or not the device has added the port yet.
+    queues: Vec<QueuePair>,
+    ports: BTreeMap<u32, Port>,
+}
+
+impl VirtIoConsole {
+    pub fn new(device: &'static VirtIoConsoleDevice) -> Result<Self, VirtIoDeviceError> {
+        let transport = MmioTransport::new(&device.header)?;
+        transport.begin_init();
+
+        // Without multiport support the device only has a single console port
+        let features = transport.negotiate_features(0, ConsoleDeviceFeatures::MULTIPORT.bits())?;
+        let multiport = features & ConsoleDeviceFeatures::MULTIPORT.bits() != 0;
+
+        let n_ports = match multiport {
+            true => unsafe { device.max_ports() }.min(MAX_PORTS),
+            false => 1,
+        };
+
+        let mut queues = Vec::with_capacity(n_ports as usize);
+        for port in 0..n_ports {
+            queues.push(QueuePair::new(&transport, console::receive_queue(port), console::transmit_queue(port))?);
+        }
+
+        let control = match multiport {
+            true => Some(QueuePair::new(&transport, CONTROL_RECEIVE_QUEUE, CONTROL_TRANSMIT_QUEUE)?),
+            false => None,
+        };
+
+        transport.finish_init()?;
+
+        let mut ports = BTreeMap::new();
+        if !multiport {
+            ports.insert(0, Port { name: None, console: true, host_connected: true });
+        }
+
+        let mut this = Self { transport, control, queues, ports };
+        // The device announces its ports with `DEVICE_ADD` messages once it
+        // knows we're ready for them
+        this.send_control(0, ControlEvent::DEVICE_READY, 1);
+
+        Ok(this)
+    }
+
+    pub fn ports(&self) -> impl Iterator<Item = (u32, &Port)> + '_ {
+        self.ports.iter().map(|(id, port)| (*id, port))
+    }
+
+    pub fn port(&self, id: u32) -> Option<&Port> {
+        self.ports.get(&id)
+    }
+
+    /// Queue `data` to be sent out of `port`, any data that doesn't fit in the
+    /// transmit queue right now is sent once the device frees up buffers
+    pub fn write(&mut self, port: u32, data: &[u8]) {
+        if !self.ports.contains_key(&port) {
+            return;
+        }
+
+        if let Some(queues) = self.queues.get_mut(port as usize) {
+            queues.transmit(&self.transport, data);
+        }
+    }
+
+    /// Tell the host whether the port has been opened or closed by a client
+    pub fn set_port_open(&mut self, port: u32, open: bool) {
+        self.send_control(port, ControlEvent::PORT_OPEN, open as u16);
+    }
+
+    pub fn process_interrupt(&mut self) -> Vec<Event> {
+        self.transport.acknowledge_interrupt();
+
+        let control_messages = match &mut self.control {
+            Some(control) => {
+                control.flush(&self.transport);
+                control.receive(&self.transport)
+            }
+            None => Vec::new(),
+        };
+
+        let mut events = Vec::new();
+        for message in control_messages {
+            self.handle_control_message(&message, &mut events);
+        }
+
+        for (id, queues) in self.queues.iter_mut().enumerate() {
+            queues.flush(&self.transport);
+
+            // Data can arrive on a port before the device has announced it,
+            // which there isn't anyone to deliver to yet
+            for data in queues.receive(&self.transport) {
+                if self.ports.contains_key(&(id as u32)) {
+                    events.push(Event::Received { port: id as u32, data });
+                }
+            }
+        }
+
+        events
+    }
+
+    fn handle_control_message(&mut self, raw: &[u8], events: &mut Vec<Event>) {
+        let message = match ControlMessage::from_bytes(raw) {
+            Some(message) => message,
+            None => return,
+        };
+
+        match message.event {
+            ControlEvent::DEVICE_ADD => {
+                let ready = (message.port as usize) < self.queues.len();
+                if ready {
+                    self.ports.insert(message.port, Port::default());
+                }
+
+                self.send_control(message.port, ControlEvent::PORT_READY, ready as u16);
+            }
+            ControlEvent::DEVICE_REMOVE => {
+                if self.ports.remove(&message.port).is_some() {
+                    events.push(Event::PortRemoved(message.port));
+                }
+            }
+            ControlEvent::CONSOLE_PORT => {
+                if let Some(port) = self.ports.get_mut(&message.port) {
+                    port.console = true;
+                    // The spec requires console ports to be opened right away
+                    self.send_control(message.port, ControlEvent::PORT_OPEN, 1);
+                }
+            }
+            ControlEvent::PORT_NAME => {
+                if let Some(port) = self.ports.get_mut(&message.port) {
+                    let name = &raw[ControlMessage::SIZE..];
+                    let name = name.split(|b| *b == 0).next().unwrap_or_default();
+                    port.name = Some(String::from_utf8_lossy(name).into_owned());
+                }
+            }
+            ControlEvent::PORT_OPEN => {
+                if let Some(port) = self.ports.get_mut(&message.port) {
+                    port.host_connected = message.value == 1;
+                    events.push(Event::HostConnected { port: message.port, connected: port.host_connected });
+                }
+            }
+            // Console size changes aren't used
+            _ => {}
+        }
+    }
+
+    fn send_control(&mut self, port: u32, event: ControlEvent, value: u16) {
+        if let Some(control) = &mut self.control {
+            control.transmit(&self.transport, &ControlMessage { port, event, value }.to_bytes());
+        }
+    }
+}
+
+/// The receive and transmit queues used by a port or the control messages
+struct QueuePair {
+    rx_queue: u32,
+    rx: SplitVirtqueue,
+    rx_buffers: DmaRegion<[Buffer]>,
+    rx_buffer_map: BTreeMap<SplitqueueIndex<VirtqueueDescriptor>, usize>,
+    tx_queue: u32,
+    tx: SplitVirtqueue,
+    tx_buffers: DmaRegion<[Buffer]>,
+    tx_buffer_map: BTreeMap<SplitqueueIndex<VirtqueueDescriptor>, usize>,
+    free_tx_buffers: VecDeque<usize>,
+    /// Data waiting on a free transmit buffer, each of which is sent in its own
+    /// buffer since control messages can't be split or merged
+    pending: VecDeque<Vec<u8>>,
+}
+
+impl QueuePair {
+    fn new(transport: &MmioTransport, rx_queue: u32, tx_queue: u32) -> Result<Self, VirtIoDeviceError> {
+        let mut rx = SplitVirtqueue::new(QUEUE_SIZE).unwrap();
+        let tx = SplitVirtqueue::new(QUEUE_SIZE).unwrap();
+        let mut rx_buffers: DmaRegion<[Buffer]> = unsafe { DmaRegion::zeroed_many(QUEUE_SIZE).unwrap().assume_init() };
+        let tx_buffers = unsafe { DmaRegion::zeroed_many(QUEUE_SIZE).unwrap().assume_init() };
+        let mut rx_buffer_map = BTreeMap::new();
+
+        for index in 0..QUEUE_SIZE {
+            let descriptor = rx.alloc_descriptor().unwrap();
+            rx.descriptors.write(
+                descriptor,
+                VirtqueueDescriptor {
+                    address: rx_buffers.get(index).unwrap().physical_address(),
+                    length: BUFFER_SIZE as u32,
+                    flags: DescriptorFlags::WRITE,
+                    next: SplitqueueIndex::new(0),
+                },
+            );
+            rx.available.push(descriptor);
+            rx_buffer_map.insert(descriptor, index);
+        }
+
+        transport.configure_queue(rx_queue, &rx)?;
+        transport.configure_queue(tx_queue, &tx)?;
+
+        Ok(Self {
+            rx_queue,
+            rx,
+            rx_buffers,
+            rx_buffer_map,
+            tx_queue,
+            tx,
+            tx_buffers,
+            tx_buffer_map: BTreeMap::new(),
+            free_tx_buffers: (0..QUEUE_SIZE).collect(),
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// Copy out everything the device has written, handing the buffers back to
+    /// the device afterwards
+    fn receive(&mut self, transport: &MmioTransport) -> Vec<Vec<u8>> {
+        let mut received = Vec::new();
+
+        while let Some(used) = self.rx.used.pop() {
+            let descriptor = SplitqueueIndex::new(used.start_index as u16);
+            let index = self.rx_buffer_map[&descriptor];
+            let buffer = self.rx_buffers.get(index).unwrap();
+
+            received.push(buffer.get()[..(used.length as usize).min(BUFFER_SIZE)].to_vec());
+            self.rx.available.push(descriptor);
+        }
+
+        if !received.is_empty() {
+            transport.notify(self.rx_queue);
+        }
+
+        received
+    }
+
+    fn transmit(&mut self, transport: &MmioTransport, data: &[u8]) {
+        self.pending.extend(data.chunks(BUFFER_SIZE).map(<[u8]>::to_vec));
+        self.flush(transport);
+    }
+
+    /// Reclaim the buffers the device is done with, then send as much pending
+    /// data as there are free buffers for
+    fn flush(&mut self, transport: &MmioTransport) {
+        while let Some(used) = self.tx.used.pop() {
+            let descriptor = SplitqueueIndex::new(used.start_index as u16);
+            let index = self.tx_buffer_map.remove(&descriptor).unwrap();
+
+            self.tx.free_descriptor(descriptor);
+            self.free_tx_buffers.push_back(index);
+        }
+
+        let mut sent_any = false;
+        while !self.pending.is_empty() {
+            let index = match self.free_tx_buffers.pop_front() {
+                Some(index) => index,
+                None => break,
+            };
+            // There's a descriptor for every buffer, so this can't fail
+            let descriptor = self.tx.alloc_descriptor().unwrap();
+
+            let data = self.pending.pop_front().unwrap();
+            let mut buffer = self.tx_buffers.get(index).unwrap();
+            buffer.get_mut()[..data.len()].copy_from_slice(&data);
+
+            self.tx.descriptors.write(
+                descriptor,
+                VirtqueueDescriptor {
+                    address: buffer.physical_address(),
+                    length: data.len() as u32,
+                    flags: DescriptorFlags::NONE,
+                    next: SplitqueueIndex::new(0),
+                },
+            );
+            self.tx.available.push(descriptor);
+            self.tx_buffer_map.insert(descriptor, index);
+            sent_any = true;
+        }
+
+        if sent_any {
+            transport.notify(self.tx_queue);
+        }
+    }
+}
@@ -0,0 +1,316 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+mod driver;
+mod pty;
+
+use driver::{Event, VirtIoConsole};
+use interfaces::console::{PortSelector, Request, Response};
+use librust::{
+    capabilities::{Capability, CapabilityPtr, CapabilityWithDescription},
+    syscalls::{
+        channel::{ChannelMessage, KernelMessage},
+        io::ConsoleSinks,
+        job::{self, JobSignal},
+    },
+};
+use pty::Ptys;
+use std::{
+    collections::BTreeMap,
+    ipc::{ChannelReadFlags, IpcChannel},
+};
+
+const KERNEL_OUTPUT_TIMER: usize = 0;
+/// How often the kernel's console output is copied to the console port
+const KERNEL_OUTPUT_INTERVAL: core::time::Duration = core::time::Duration::from_millis(50);
+
+json::derive! {
+    #[derive(Debug, Clone)]
+    struct Device {
+        name: String,
+        compatible: Vec<String>,
+        interrupts: Vec<usize>,
+    }
+}
+
+json::derive! {
+    Serialize,
+    struct VirtIoDeviceRequest {
+        ty: u32,
+    }
+}
+
+json::derive! {
+    Deserialize,
+    #[derive(Debug)]
+    struct VirtIoDeviceResponse {
+        devices: Vec<Device>,
+    }
+}
+
+/// Which client channel each port is bound to
+#[derive(Debug, Default)]
+struct Clients {
+    by_port: BTreeMap<u32, CapabilityPtr>,
+    by_channel: BTreeMap<CapabilityPtr, u32>,
+    /// The task group of each port's foreground job
+    foreground: BTreeMap<u32, CapabilityPtr>,
+}
+
+impl Clients {
+    fn bind(&mut self, port: u32, channel: CapabilityPtr) {
+        self.by_port.insert(port, channel);
+        self.by_channel.insert(channel, port);
+    }
+
+    fn unbind_port(&mut self, port: u32) -> Option<CapabilityPtr> {
+        let channel = self.by_port.remove(&port)?;
+        self.by_channel.remove(&channel);
+        self.foreground.remove(&port);
+        Some(channel)
+    }
+
+    fn unbind_channel(&mut self, channel: CapabilityPtr) -> Option<u32> {
+        let port = self.by_channel.remove(&channel)?;
+        self.by_port.remove(&port);
+        self.foreground.remove(&port);
+        Some(port)
+    }
+
+    fn channel(&self, port: u32) -> Option<IpcChannel> {
+        self.by_port.get(&port).copied().map(IpcChannel::new)
+    }
+
+    fn port(&self, channel: CapabilityPtr) -> Option<u32> {
+        self.by_channel.get(&channel).copied()
+    }
+}
+
+/// Copies the kernel's console output from its console ring to the console
+/// port, when init has handed over the kernel console capability
+struct KernelOutput {
+    cptr: CapabilityPtr,
+    position: u64,
+}
+
+impl KernelOutput {
+    fn new(cptr: CapabilityPtr) -> Self {
+        // Add the ring to whatever sinks were chosen on the command line, the
+        // kernel only fills it when it's one of them
+        if let Ok(sinks) = librust::syscalls::io::set_console_sinks(cptr, ConsoleSinks::RING) {
+            let _ = librust::syscalls::io::set_console_sinks(cptr, sinks | ConsoleSinks::RING);
+        }
+
+        Self { cptr, position: 0 }
+    }
+
+    fn forward(&mut self, console: &mut VirtIoConsole) {
+        let port = find_port(console, &PortSelector::Console);
+        let mut buffer = [0; 1024];
+
+        while let Ok((read @ 1.., next)) =
+            librust::syscalls::io::read_console_ring(self.cptr, &mut buffer, self.position)
+        {
+            self.position = next;
+            if let Some(port) = port {
+                console.write(port, &buffer[..read]);
+            }
+        }
+    }
+}
+
+fn main() {
+    let virtiomgr_cptr = std::env::lookup_capability("virtiomgr").unwrap().capability.cptr;
+    let virtiomgr = IpcChannel::new(virtiomgr_cptr);
+
+    virtiomgr
+        .temp_send_json(ChannelMessage::default(), &VirtIoDeviceRequest { ty: virtio::DeviceType::Console as u32 }, &[])
+        .unwrap();
+
+    let (response, _, capabilities): (VirtIoDeviceResponse, _, _) =
+        virtiomgr.temp_read_json(ChannelReadFlags::NONE).unwrap();
+
+    if response.devices.is_empty() {
+        return;
+    }
+
+    let (CapabilityWithDescription { capability: Capability { cptr: mmio_cap, .. }, .. }, device) =
+        (capabilities[0], &response.devices[0]);
+    let (info, _) = librust::syscalls::io::query_mmio_cap(mmio_cap, &mut []).unwrap();
+
+    let interrupt_id = device.interrupts[0];
+    let mut console =
+        VirtIoConsole::new(unsafe { &*(info.address() as *const virtio::devices::console::VirtIoConsoleDevice) })
+            .unwrap();
+
+    let mut clients = Clients::default();
+    let mut ptys = Ptys::default();
+    let mut kernel_output =
+        std::env::lookup_capability("kconsole").map(|kconsole| KernelOutput::new(kconsole.capability.cptr));
+
+    if kernel_output.is_some() {
+        librust::syscalls::task::set_timer(KERNEL_OUTPUT_TIMER, KERNEL_OUTPUT_INTERVAL).unwrap();
+    }
+
+    librust::syscalls::task::enable_notifications();
+    loop {
+        match librust::syscalls::channel::read_kernel_message() {
+            KernelMessage::TimerExpired(KERNEL_OUTPUT_TIMER) => {
+                if let Some(kernel_output) = &mut kernel_output {
+                    kernel_output.forward(&mut console);
+                    librust::syscalls::task::set_timer(KERNEL_OUTPUT_TIMER, KERNEL_OUTPUT_INTERVAL).unwrap();
+                }
+            }
+            KernelMessage::InterruptOccurred(id) if id == interrupt_id => {
+                for event in console.process_interrupt() {
+                    handle_event(&mut clients, event);
+                }
+
+                librust::syscalls::io::complete_interrupt(id).unwrap();
+            }
+            KernelMessage::NewChannelMessage(cptr) if cptr != virtiomgr_cptr => {
+                handle_request(&mut console, &mut clients, &mut ptys, cptr);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn handle_event(clients: &mut Clients, event: Event) {
+    let (channel, response) = match event {
+        // Data for ports without a client is dropped, since the host side is
+        // expected to only talk to ports once they've been opened
+        Event::Received { port, mut data } => {
+            if let Some(&group) = clients.foreground.get(&port) {
+                take_job_signals(group, &mut data);
+                if data.is_empty() {
+                    return;
+                }
+            }
+
+            (clients.channel(port), Response::Data(data))
+        }
+        Event::HostConnected { port, connected } => (clients.channel(port), Response::HostConnected(connected)),
+        Event::PortRemoved(port) => (clients.unbind_port(port).map(IpcChannel::new), Response::Removed),
+    };
+
+    if let Some(channel) = channel {
+        let _ = channel.send_serialized(&response, &[]);
+    }
+}
+
+fn handle_request(console: &mut VirtIoConsole, clients: &mut Clients, ptys: &mut Ptys, cptr: CapabilityPtr) {
+    let channel = IpcChannel::new(cptr);
+    let (request, caps) = match channel.read_serialized::<Request>(ChannelReadFlags::NONBLOCKING) {
+        Ok(read) => read,
+        Err(_) => return,
+    };
+
+    let response = match request {
+        Request::Open(PortSelector::Pty(id)) => match ptys.can_open(id, cptr) {
+            Err(response) => response,
+            Ok(()) => {
+                if ptys.side(cptr) != Some((id, pty::Side::Slave)) {
+                    release(console, clients, ptys, cptr);
+                }
+
+                ptys.open(id, cptr)
+            }
+        },
+        Request::Open(selector) => match find_port(console, &selector) {
+            None => Response::NotFound,
+            Some(port) => match clients.channel(port) {
+                Some(_) if clients.port(cptr) != Some(port) => Response::InUse,
+                _ => {
+                    release(console, clients, ptys, cptr);
+                    clients.bind(port, cptr);
+                    console.set_port_open(port, true);
+
+                    Response::Opened { id: port, name: console.port(port).and_then(|port| port.name.clone()) }
+                }
+            },
+        },
+        Request::CreatePty(size) => {
+            release(console, clients, ptys, cptr);
+            Response::PtyCreated { id: ptys.create(cptr, size) }
+        }
+        request if ptys.side(cptr).is_some() => match ptys.handle_request(cptr, request, &caps) {
+            Some(response) => response,
+            None => return,
+        },
+        Request::Write(data) => match clients.port(cptr) {
+            Some(port) => {
+                console.write(port, &data);
+                return;
+            }
+            None => Response::NotOpen,
+        },
+        Request::SetForeground => match clients.port(cptr) {
+            Some(port) => {
+                match caps.first() {
+                    Some(group) => clients.foreground.insert(port, group.capability.cptr),
+                    None => clients.foreground.remove(&port),
+                };
+
+                return;
+            }
+            None => Response::NotOpen,
+        },
+        Request::Close => {
+            release(console, clients, ptys, cptr);
+            return;
+        }
+        Request::Resize(_) | Request::WindowSize => Response::NotAPty,
+    };
+
+    let _ = channel.send_serialized(&response, &[]);
+}
+
+/// Unbind `channel` from whichever port or pseudo-terminal it's bound to
+fn release(console: &mut VirtIoConsole, clients: &mut Clients, ptys: &mut Ptys, channel: CapabilityPtr) {
+    if let Some(port) = clients.unbind_channel(channel) {
+        console.set_port_open(port, false);
+    }
+
+    ptys.close(channel);
+}
+
+/// Send the job control signals for any control characters in `data` to
+/// `group`, removing them from the data
+fn take_job_signals(group: CapabilityPtr, data: &mut Vec<u8>) {
+    data.retain(|&byte| match job_signal(byte) {
+        Some(signal) => {
+            let _ = job::signal_group(group, signal);
+            false
+        }
+        None => true,
+    });
+}
+
+/// The job control signal a control character received from the terminal
+/// stands for
+fn job_signal(byte: u8) -> Option<JobSignal> {
+    match byte {
+        // Ctrl-C
+        0x03 => Some(JobSignal::Terminate),
+        // Ctrl-Z
+        0x1A => Some(JobSignal::Suspend),
+        _ => None,
+    }
+}
+
+fn find_port(console: &VirtIoConsole, selector: &PortSelector) -> Option<u32> {
+    console
+        .ports()
+        .find(|(id, port)| match selector {
+            PortSelector::Console => port.console,
+            PortSelector::Name(name) => port.name.as_ref() == Some(name),
+            PortSelector::Id(wanted) => id == wanted,
+            PortSelector::Pty(_) => false,
+        })
+        .map(|(id, _)| id)
+}
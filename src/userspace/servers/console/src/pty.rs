@@ -0,0 +1,158 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Pseudo-terminals, which pass data between the channel that created them
+//! (the master side, e.g. a terminal emulator) and the channel that opened
+//! them like a port (the slave side, e.g. a shell)
+
+use interfaces::console::{Request, Response, WindowSize};
+use librust::capabilities::{CapabilityPtr, CapabilityWithDescription};
+use std::{collections::BTreeMap, ipc::IpcChannel};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Master,
+    Slave,
+}
+
+#[derive(Debug)]
+struct Pty {
+    master: CapabilityPtr,
+    slave: Option<CapabilityPtr>,
+    size: WindowSize,
+    /// The task group of the slave side's foreground job
+    foreground: Option<CapabilityPtr>,
+}
+
+#[derive(Debug, Default)]
+pub struct Ptys {
+    ptys: BTreeMap<u32, Pty>,
+    by_channel: BTreeMap<CapabilityPtr, (u32, Side)>,
+    next_id: u32,
+}
+
+impl Ptys {
+    /// Create a pseudo-terminal with `master` as its master side, returning its
+    /// ID
+    pub fn create(&mut self, master: CapabilityPtr, size: WindowSize) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.ptys.insert(id, Pty { master, slave: None, size, foreground: None });
+        self.by_channel.insert(master, (id, Side::Master));
+
+        id
+    }
+
+    /// Check whether `slave` can be bound to the slave side of the
+    /// pseudo-terminal `id`, returning the response to send back if not
+    pub fn can_open(&self, id: u32, slave: CapabilityPtr) -> Result<(), Response> {
+        match self.ptys.get(&id) {
+            None => Err(Response::NotFound),
+            Some(pty) if pty.master == slave => Err(Response::InUse),
+            Some(Pty { slave: Some(bound), .. }) if *bound != slave => Err(Response::InUse),
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// Bind `slave` to the slave side of the pseudo-terminal `id`, letting the
+    /// master side know. [`Ptys::can_open`] must have said it could be.
+    pub fn open(&mut self, id: u32, slave: CapabilityPtr) -> Response {
+        let pty = self.ptys.get_mut(&id).unwrap();
+        if pty.slave.is_none() {
+            pty.slave = Some(slave);
+            self.by_channel.insert(slave, (id, Side::Slave));
+            send(pty.master, Response::HostConnected(true));
+        }
+
+        Response::Opened { id, name: Some(format!("pty{}", id)) }
+    }
+
+    /// Which pseudo-terminal `channel` is bound to, and which side of it
+    pub fn side(&self, channel: CapabilityPtr) -> Option<(u32, Side)> {
+        self.by_channel.get(&channel).copied()
+    }
+
+    /// Unbind `channel` from whichever side of a pseudo-terminal it's bound
+    /// to. Closing the master side removes the pseudo-terminal, and the slave
+    /// side is told it's gone the same way as for a removed port.
+    pub fn close(&mut self, channel: CapabilityPtr) {
+        let (id, side) = match self.by_channel.remove(&channel) {
+            Some(bound) => bound,
+            None => return,
+        };
+
+        match side {
+            Side::Master => {
+                let pty = self.ptys.remove(&id).unwrap();
+                if let Some(slave) = pty.slave {
+                    self.by_channel.remove(&slave);
+                    send(slave, Response::Removed);
+                }
+            }
+            Side::Slave => {
+                let pty = self.ptys.get_mut(&id).unwrap();
+                pty.slave = None;
+                pty.foreground = None;
+                send(pty.master, Response::HostConnected(false));
+            }
+        }
+    }
+
+    /// Handle a request from a channel bound to either side of a
+    /// pseudo-terminal, returning the response to send back, if any
+    pub fn handle_request(
+        &mut self,
+        channel: CapabilityPtr,
+        request: Request,
+        caps: &[CapabilityWithDescription],
+    ) -> Option<Response> {
+        let (id, side) = self.side(channel)?;
+        let pty = self.ptys.get_mut(&id).unwrap();
+
+        match (request, side) {
+            (Request::Write(mut data), Side::Master) => {
+                let slave = pty.slave?;
+                if let Some(group) = pty.foreground {
+                    crate::take_job_signals(group, &mut data);
+                }
+
+                if !data.is_empty() {
+                    send(slave, Response::Data(data));
+                }
+
+                None
+            }
+            (Request::Write(data), Side::Slave) => {
+                send(pty.master, Response::Data(data));
+                None
+            }
+            (Request::SetForeground, _) => {
+                pty.foreground = caps.first().map(|group| group.capability.cptr);
+                None
+            }
+            (Request::Resize(size), Side::Master) => {
+                pty.size = size;
+                if let Some(slave) = pty.slave {
+                    send(slave, Response::Resized(size));
+                }
+
+                None
+            }
+            (Request::WindowSize, _) => Some(Response::Resized(pty.size)),
+            (Request::Close, _) => {
+                self.close(channel);
+                None
+            }
+            _ => Some(Response::NotAPty),
+        }
+    }
+}
+
+fn send(channel: CapabilityPtr, response: Response) {
+    let _ = IpcChannel::new(channel).send_serialized(&response, &[]);
+}
@@ -0,0 +1,77 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use librust::syscalls::channel::KernelMessage;
+use std::{
+    crash::Minidump,
+    ipc::{ChannelReadFlags, IpcChannel},
+};
+
+const REGISTER_NAMES: [&str; 31] = [
+    "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7", "s2", "s3",
+    "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+fn main() {
+    librust::syscalls::task::enable_notifications();
+    loop {
+        if let KernelMessage::NewChannelMessage(cptr) = librust::syscalls::channel::read_kernel_message() {
+            let channel = IpcChannel::new(cptr);
+            if let Ok((minidump, _)) = channel.read_serialized::<Minidump>(ChannelReadFlags::NONBLOCKING) {
+                report(&minidump);
+            }
+        }
+    }
+}
+
+fn report(minidump: &Minidump) {
+    let in_image = |addr: u64| (minidump.image_base..minidump.image_end).contains(&addr);
+
+    println!(
+        "[crashcollector] Task {} ({}) crashed: {} @ {:#x}",
+        minidump.task_name,
+        minidump.tid,
+        minidump.cause_name(),
+        minidump.address
+    );
+    println!("    crash {} in boot {}", minidump.id(), minidump.boot_id());
+
+    match in_image(minidump.pc) {
+        true => println!("    pc: {:#018x} (image offset {:#x})", minidump.pc, minidump.pc - minidump.image_base),
+        false => println!("    pc: {:#018x}", minidump.pc),
+    }
+
+    for (names, values) in REGISTER_NAMES.chunks(4).zip(minidump.registers.chunks(4)) {
+        let mut line = String::from("   ");
+        for (name, value) in names.iter().zip(values) {
+            line += &format!(" {:>3}: {:#018x}", name, value);
+        }
+
+        println!("{}", line);
+    }
+
+    println!("    backtrace:");
+    for (i, &ra) in minidump.backtrace.iter().enumerate() {
+        match in_image(ra) {
+            true => println!("    {:>2}: {:#018x} (image offset {:#x})", i, ra, ra - minidump.image_base),
+            false => println!("    {:>2}: {:#018x}", i, ra),
+        }
+    }
+
+    let sp = minidump.stack_pointer();
+    println!("    stack ({} bytes):", minidump.stack.len());
+    for (i, bytes) in minidump.stack.chunks(8).enumerate() {
+        let addr = sp + i as u64 * 8;
+        let word = bytes.iter().rev().fold(0, |word, byte| word << 8 | *byte as u64);
+
+        // Anything pointing into the executable is likely a return address
+        match in_image(word) {
+            true => println!("    {:#018x}: {:#018x} (image offset {:#x})", addr, word, word - minidump.image_base),
+            false => println!("    {:#018x}: {:#018x}", addr, word),
+        }
+    }
+}
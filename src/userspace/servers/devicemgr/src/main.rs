@@ -5,34 +5,68 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
-use librust::{
-    capabilities::{Capability, CapabilityDescription, CapabilityRights},
-    syscalls::channel::{ChannelMessage, KernelMessage},
-};
-use std::ipc::{ChannelReadFlags, IpcChannel};
+use interfaces::{devicemgr, Device, HotplugEvent};
+use librust::capabilities::{Capability, CapabilityRights, CapabilityWithDescription};
+use std::ipc::Topic;
 
-json::derive! {
-    Serialize,
-    #[derive(Debug)]
-    struct Device {
-        name: String,
+struct Devicemgr {
+    fdt: fdt::Fdt<'static>,
+    hotplug: Topic,
+}
+
+impl devicemgr::Server for Devicemgr {
+    fn request_devices(
+        &mut self,
         compatible: Vec<String>,
-        interrupts: Vec<usize>,
+        _: Vec<CapabilityWithDescription>,
+    ) -> (Vec<Device>, Vec<Capability>) {
+        let all_compatible = self
+            .fdt
+            .all_nodes()
+            .filter_map(|n| {
+                Some({
+                    n.compatible()?.all().find(|c| compatible.iter().any(|c2| c2 == c))?;
+                    n
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let devices = all_compatible
+            .iter()
+            .map(|n| Device {
+                name: n.name.into(),
+                compatible: n.compatible().unwrap().all().map(ToString::to_string).collect(),
+                interrupts: n.interrupts().map(|ints| ints.collect()).unwrap_or_default(),
+            })
+            .collect();
+
+        let mut caps = Vec::with_capacity(all_compatible.len());
+        for device in all_compatible {
+            let cptr = librust::syscalls::io::claim_device(device.name).unwrap();
+            caps.push(Capability::new(
+                cptr,
+                CapabilityRights::READ | CapabilityRights::WRITE | CapabilityRights::GRANT,
+            ));
+        }
+
+        (devices, caps)
     }
-}
 
-json::derive! {
-    Serialize,
-    #[derive(Debug)]
-    struct Devices {
-        devices: Vec<Device>,
+    fn hotplug_events(&mut self, _: Vec<CapabilityWithDescription>) -> ((), Vec<Capability>) {
+        // Only devicemgr publishes to the topic, so everyone else is only
+        // allowed to subscribe to it
+        ((), vec![Capability::new(self.hotplug.cptr(), CapabilityRights::READ)])
     }
-}
 
-json::derive! {
-    Deserialize,
-    struct WantedCompatible {
-        compatible: Vec<String>,
+    fn report_hotplug(&mut self, event: HotplugEvent) {
+        match &event {
+            HotplugEvent::Added(device) => log::info!("Device added: {}", device.name),
+            HotplugEvent::Removed(device) => log::info!("Device removed: {}", device.name),
+        }
+
+        if let Err(e) = self.hotplug.publish_serialized(&event, &[]) {
+            log::warn!("Failed to publish hotplug event: {:?}", e);
+        }
     }
 }
 
@@ -54,66 +88,7 @@ fn main() {
             }
         }
     }
-    librust::syscalls::task::enable_notifications();
-    loop {
-        // println!("[devicemgr] Waiting for new kernel message");
-        let cptr = match librust::syscalls::channel::read_kernel_message() {
-            KernelMessage::NewChannelMessage(cptr) => cptr,
-            _ => continue,
-        };
-
-        // println!("[devicemgr] New channel message on {cptr:?}");
-
-        let channel = IpcChannel::new(cptr);
-        let (_, caps) = match channel.read_with_all_caps(ChannelReadFlags::NONBLOCKING) {
-            Ok(data) => data,
-            Err(_) => continue,
-        };
-
-        let mem = match &caps[0].description {
-            CapabilityDescription::Memory { ptr, len, permissions: _ } => unsafe {
-                core::slice::from_raw_parts(*ptr, *len)
-            },
-            _ => continue,
-        };
-
-        let compatible = json::deserialize::<WantedCompatible>(mem).unwrap().compatible;
-
-        let all_compatible = fdt
-            .all_nodes()
-            .filter_map(|n| {
-                Some({
-                    n.compatible()?.all().find(|c| compatible.iter().any(|c2| c2 == c))?;
-                    n
-                })
-            })
-            .collect::<Vec<_>>();
-
-        match all_compatible.len() {
-            0 => channel.temp_send_json(ChannelMessage::default(), &Devices { devices: vec![] }, &[]).unwrap(),
-            _ => {
-                let devices = Devices {
-                    devices: all_compatible
-                        .iter()
-                        .map(|n| Device {
-                            name: n.name.into(),
-                            compatible: n.compatible().unwrap().all().map(ToString::to_string).collect(),
-                            interrupts: n.interrupts().map(|ints| ints.collect()).unwrap_or_default(),
-                        })
-                        .collect(),
-                };
 
-                let mut caps = Vec::with_capacity(devices.devices.len());
-                for device in all_compatible {
-                    let cptr = librust::syscalls::io::claim_device(device.name).unwrap();
-                    caps.push(Capability::new(
-                        cptr,
-                        CapabilityRights::READ | CapabilityRights::WRITE | CapabilityRights::GRANT,
-                    ));
-                }
-
-                channel.temp_send_json(ChannelMessage::default(), &devices, &caps[..]).unwrap();
-            }
-        }
-    }
+    let hotplug = Topic::new().expect("failed to create hotplug topic");
+    devicemgr::serve(&mut Devicemgr { fdt, hotplug })
 }
@@ -11,7 +11,7 @@ use virtio::devices::block::{Command, CommandError, CommandKind, CommandStatus};
 use virtio::{
     devices::block::VirtIoBlockDevice,
     splitqueue::{DescriptorFlags, SplitVirtqueue, SplitqueueIndex, VirtqueueDescriptor},
-    StatusFlag, VirtIoDeviceError,
+    MmioTransport, Transport, VirtIoDeviceError,
 };
 
 #[derive(Debug, Clone, Copy)]
@@ -39,7 +39,7 @@ impl From<CommandError> for Error {
 }
 
 pub struct BlockDevice {
-    device: &'static VirtIoBlockDevice,
+    transport: MmioTransport,
     // TODO: allow for multiple queues
     queue: SplitVirtqueue,
     command_buffer: CommandBuffer,
@@ -53,40 +53,14 @@ impl BlockDevice {
         let command_buffer = CommandBuffer::new(512);
         let data_buffer = DataBuffer::new(512);
 
-        device.header.status.reset();
-
-        device.header.status.set_flag(StatusFlag::Acknowledge);
-        device.header.status.set_flag(StatusFlag::Driver);
-
+        let transport = MmioTransport::new(&device.header)?;
+        transport.begin_init();
         // TODO: maybe use feature bits at some point
-        let _ = device.header.features();
-
-        device.header.driver_features_select.write(0);
-        device.header.device_features_select.write(0);
-
-        device.header.driver_features.write(0);
-
-        device.header.status.set_flag(StatusFlag::FeaturesOk);
-
-        if !device.header.status.is_set(StatusFlag::FeaturesOk) {
-            return Err(VirtIoDeviceError::FeaturesNotRecognized);
-        }
+        transport.negotiate_features(0, 0)?;
+        transport.configure_queue(0, &queue)?;
+        transport.finish_init()?;
 
-        device.header.queue_select.write(0);
-        device.header.queue_size.write(queue.queue_size());
-        device.header.queue_descriptor.set(queue.descriptors.physical_address());
-        device.header.queue_available.set(queue.available.physical_address());
-        device.header.queue_used.set(queue.used.physical_address());
-
-        device.header.queue_ready.ready();
-
-        device.header.status.set_flag(StatusFlag::DriverOk);
-
-        if device.header.status.failed() {
-            return Err(VirtIoDeviceError::DeviceError);
-        }
-
-        Ok(Self { device, queue, command_buffer, data_buffer, issued_commands: BTreeMap::new() })
+        Ok(Self { transport, queue, command_buffer, data_buffer, issued_commands: BTreeMap::new() })
     }
 
     fn queue_command(&mut self, operation: OperationRequest<'_>) {
@@ -148,12 +122,7 @@ impl BlockDevice {
         self.queue.available.push(desc1);
 
         self.issued_commands.insert(desc1, (command_index, data_index));
-
-        // Fence the MMIO register write since its not guaranteed to be in the
-        // same order relative to RAM read/writes
-        librust::mem::fence(librust::mem::FenceMode::Write);
-
-        self.device.header.queue_notify.notify(0);
+        self.transport.notify(0);
     }
 
     pub fn queue_read(&mut self, sector: u64) {
@@ -170,7 +139,7 @@ impl BlockDevice {
         let desc3 = self.queue.descriptors.read(desc2).next;
 
         librust::mem::fence(librust::mem::FenceMode::Full);
-        self.device.header.interrupt_ack.acknowledge_buffer_used();
+        self.transport.acknowledge_interrupt();
 
         let (command_idx, data_idx) = self.issued_commands.remove(&desc1).unwrap();
         let command = self.command_buffer.get(command_idx).unwrap();
@@ -6,9 +6,17 @@
 // obtain one at https://mozilla.org/MPL/2.0/.
 
 mod drivers;
+mod memfs;
 
-use librust::capabilities::{Capability, CapabilityPtr};
-use std::ipc::IpcChannel;
+use librust::capabilities::CapabilityPtr;
+use memfs::{Inode, MemoryFilesystem, OpenFile};
+use std::{
+    collections::BTreeMap,
+    fs::protocol::{filesystem, DirEntry, FsError, Metadata, OpenFlags, SeekFrom},
+};
+
+/// The most data returned from a single read
+const MAX_READ: usize = 64 * 1024;
 
 json::derive! {
     #[derive(Debug, Clone)]
@@ -51,4 +59,116 @@ fn main() {
     // // println!("[filesystem] Sent device request");
     // let (message, capabilities) = virtiomgr.read_with_all_caps().unwrap();
     // let response: VirtIoDeviceResponse = json::deserialize(message.as_bytes()).unwrap();
+
+    let mut server = Server { fs: MemoryFilesystem::new(), clients: BTreeMap::new(), caller: None };
+    filesystem::serve(&mut server)
+}
+
+/// The files a client has open, which are only valid on the channel they were
+/// opened on
+#[derive(Default)]
+struct Client {
+    files: BTreeMap<u64, OpenFile>,
+    next_handle: u64,
+}
+
+impl Client {
+    fn file(&mut self, handle: u64) -> Result<&mut OpenFile, FsError> {
+        self.files.get_mut(&handle).ok_or(FsError::InvalidHandle)
+    }
+}
+
+struct Server {
+    fs: MemoryFilesystem,
+    clients: BTreeMap<CapabilityPtr, Client>,
+    /// The channel the request being handled arrived on
+    caller: Option<CapabilityPtr>,
+}
+
+impl Server {
+    /// The filesystem along with the state of the client making the request
+    fn split(&mut self) -> (&mut MemoryFilesystem, &mut Client) {
+        let caller = self.caller.expect("request handled without a caller");
+        (&mut self.fs, self.clients.entry(caller).or_default())
+    }
+}
+
+impl filesystem::Server for Server {
+    fn caller(&mut self, cptr: CapabilityPtr) {
+        self.caller = Some(cptr);
+    }
+
+    fn disconnected(&mut self, cptr: CapabilityPtr) {
+        if let Some(client) = self.clients.remove(&cptr) {
+            for file in client.files.values() {
+                self.fs.close(file);
+            }
+        }
+    }
+
+    fn open(&mut self, path: String, flags: OpenFlags) -> Result<u64, FsError> {
+        let (fs, client) = self.split();
+        let file = fs.open(&path, flags)?;
+        let handle = client.next_handle;
+        client.next_handle += 1;
+        client.files.insert(handle, file);
+
+        Ok(handle)
+    }
+
+    fn read(&mut self, handle: u64, len: u64) -> Result<Vec<u8>, FsError> {
+        let (fs, client) = self.split();
+        fs.read(client.file(handle)?, (len as usize).min(MAX_READ))
+    }
+
+    fn write(&mut self, handle: u64, data: Vec<u8>) -> Result<u64, FsError> {
+        let (fs, client) = self.split();
+        Ok(fs.write(client.file(handle)?, &data)? as u64)
+    }
+
+    fn seek(&mut self, handle: u64, from: SeekFrom) -> Result<u64, FsError> {
+        let (fs, client) = self.split();
+        fs.seek(client.file(handle)?, from)
+    }
+
+    fn close(&mut self, handle: u64) -> Result<(), FsError> {
+        let (fs, client) = self.split();
+        let file = client.files.remove(&handle).ok_or(FsError::InvalidHandle)?;
+        fs.close(&file);
+
+        // Clients only have state while they have files open, so there's
+        // nothing left behind for ones which opened a file once and went away
+        if client.files.is_empty() {
+            let caller = self.caller.expect("request handled without a caller");
+            self.clients.remove(&caller);
+        }
+
+        Ok(())
+    }
+
+    fn handle_metadata(&mut self, handle: u64) -> Result<Metadata, FsError> {
+        let (fs, client) = self.split();
+        Ok(fs.file_metadata(client.file(handle)?))
+    }
+
+    fn metadata(&mut self, path: String) -> Result<Metadata, FsError> {
+        self.fs.metadata(&path)
+    }
+
+    fn read_dir(&mut self, path: String) -> Result<Vec<DirEntry>, FsError> {
+        self.fs.read_dir(&path)
+    }
+
+    fn create_dir(&mut self, path: String) -> Result<(), FsError> {
+        self.fs.create_dir(&path)
+    }
+
+    fn remove_file(&mut self, path: String) -> Result<(), FsError> {
+        let clients = &self.clients;
+        let open_handles = |inode: Inode| {
+            clients.values().flat_map(|client| client.files.values()).filter(|file| file.inode == inode).count()
+        };
+
+        self.fs.remove_file(&path, open_handles)
+    }
 }
@@ -0,0 +1,344 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A filesystem kept entirely in memory, which is what's served until there's
+//! an on-disk format for the block devices
+
+use std::{
+    collections::BTreeMap,
+    fs::protocol::{DirEntry, FileKind, FsError, Metadata, OpenFlags, SeekFrom},
+    path::Path,
+};
+
+pub type Inode = u64;
+
+const ROOT: Inode = 0;
+/// The largest a file can grow to, since every byte of it is kept in memory
+pub const MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+enum Node {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Inode>),
+}
+
+impl Node {
+    fn metadata(&self) -> Metadata {
+        match self {
+            Node::File(contents) => Metadata { kind: FileKind::File, len: contents.len() as u64 },
+            Node::Directory(entries) => Metadata { kind: FileKind::Directory, len: entries.len() as u64 },
+        }
+    }
+}
+
+/// A file opened by a client
+#[derive(Debug, Clone, Copy)]
+pub struct OpenFile {
+    pub inode: Inode,
+    pub flags: OpenFlags,
+    pub position: u64,
+}
+
+pub struct MemoryFilesystem {
+    nodes: BTreeMap<Inode, Node>,
+    next_inode: Inode,
+    /// Files removed while they were still open, which are freed once the last
+    /// handle to them is closed
+    orphans: BTreeMap<Inode, usize>,
+}
+
+impl MemoryFilesystem {
+    pub fn new() -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(ROOT, Node::Directory(BTreeMap::new()));
+
+        Self { nodes, next_inode: ROOT + 1, orphans: BTreeMap::new() }
+    }
+
+    /// Split a path into the names leading to it from the root, where a
+    /// leading `/` is optional and `..` may not be used to escape the root
+    fn components(path: &str) -> Result<Vec<String>, FsError> {
+        let confined = Path::new(path).confine().ok_or(FsError::InvalidPath)?;
+        Ok(confined.components().map(|component| component.as_str().into()).collect())
+    }
+
+    fn lookup(&self, path: &str) -> Result<Inode, FsError> {
+        self.walk(ROOT, &Self::components(path)?)
+    }
+
+    /// Look up the directory containing the last component of `path`,
+    /// returning it along with the last component
+    fn lookup_parent(&self, path: &str) -> Result<(Inode, String), FsError> {
+        let mut components = Self::components(path)?;
+        let name = components.pop().ok_or(FsError::InvalidPath)?;
+
+        let parent = self.walk(ROOT, &components)?;
+        match &self.nodes[&parent] {
+            Node::Directory(_) => Ok((parent, name)),
+            Node::File(_) => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn walk(&self, mut inode: Inode, components: &[String]) -> Result<Inode, FsError> {
+        for component in components {
+            inode = match &self.nodes[&inode] {
+                Node::Directory(entries) => *entries.get(component).ok_or(FsError::NotFound)?,
+                Node::File(_) => return Err(FsError::NotADirectory),
+            };
+        }
+
+        Ok(inode)
+    }
+
+    fn entries_mut(&mut self, inode: Inode) -> &mut BTreeMap<String, Inode> {
+        match self.nodes.get_mut(&inode) {
+            Some(Node::Directory(entries)) => entries,
+            _ => unreachable!("parent lookups only return directories"),
+        }
+    }
+
+    fn insert(&mut self, parent: Inode, name: &str, node: Node) -> Inode {
+        let inode = self.next_inode;
+        self.next_inode += 1;
+
+        self.nodes.insert(inode, node);
+        self.entries_mut(parent).insert(name.into(), inode);
+
+        inode
+    }
+
+    pub fn open(&mut self, path: &str, flags: OpenFlags) -> Result<OpenFile, FsError> {
+        let (parent, name) = self.lookup_parent(path)?;
+        let existing = match &self.nodes[&parent] {
+            Node::Directory(entries) => entries.get(&name).copied(),
+            Node::File(_) => unreachable!(),
+        };
+
+        let inode = match existing {
+            Some(_) if flags.create_new => return Err(FsError::AlreadyExists),
+            Some(inode) => inode,
+            None if flags.create || flags.create_new => {
+                if !flags.write {
+                    return Err(FsError::PermissionDenied);
+                }
+
+                self.insert(parent, &name, Node::File(Vec::new()))
+            }
+            None => return Err(FsError::NotFound),
+        };
+
+        match self.nodes.get_mut(&inode) {
+            Some(Node::Directory(_)) => return Err(FsError::IsADirectory),
+            Some(Node::File(contents)) if flags.truncate => {
+                if !flags.write {
+                    return Err(FsError::PermissionDenied);
+                }
+
+                contents.clear();
+            }
+            _ => {}
+        }
+
+        Ok(OpenFile { inode, flags, position: 0 })
+    }
+
+    /// Forget about a handle to `file`, freeing the file if it was removed and
+    /// this was the last handle to it
+    pub fn close(&mut self, file: &OpenFile) {
+        if let Some(handles) = self.orphans.get_mut(&file.inode) {
+            *handles -= 1;
+            if *handles == 0 {
+                self.orphans.remove(&file.inode);
+                self.nodes.remove(&file.inode);
+            }
+        }
+    }
+
+    pub fn read(&self, file: &mut OpenFile, len: usize) -> Result<Vec<u8>, FsError> {
+        if !file.flags.read {
+            return Err(FsError::PermissionDenied);
+        }
+
+        let contents = match &self.nodes[&file.inode] {
+            Node::File(contents) => contents,
+            Node::Directory(_) => return Err(FsError::IsADirectory),
+        };
+
+        let start = (file.position as usize).min(contents.len());
+        let end = start.saturating_add(len).min(contents.len());
+        file.position = end as u64;
+
+        Ok(contents[start..end].to_vec())
+    }
+
+    pub fn write(&mut self, file: &mut OpenFile, data: &[u8]) -> Result<usize, FsError> {
+        if !file.flags.write {
+            return Err(FsError::PermissionDenied);
+        }
+
+        let contents = match self.nodes.get_mut(&file.inode) {
+            Some(Node::File(contents)) => contents,
+            _ => return Err(FsError::IsADirectory),
+        };
+
+        if file.flags.append {
+            file.position = contents.len() as u64;
+        }
+
+        // Writing past the end leaves a hole of zeroes
+        let end = file.position.checked_add(data.len() as u64).filter(|&end| end <= MAX_FILE_SIZE);
+        let (start, end) = (file.position as usize, end.ok_or(FsError::FileTooLarge)? as usize);
+        if contents.len() < end {
+            contents.resize(end, 0);
+        }
+
+        contents[start..end].copy_from_slice(data);
+        file.position = end as u64;
+
+        Ok(data.len())
+    }
+
+    pub fn seek(&self, file: &mut OpenFile, from: SeekFrom) -> Result<u64, FsError> {
+        let len = self.nodes[&file.inode].metadata().len;
+        let offset_from = |base: u64, offset: i64| match offset < 0 {
+            true => base.checked_sub(offset.unsigned_abs()),
+            false => base.checked_add(offset as u64),
+        };
+
+        let position = match from {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => offset_from(len, offset),
+            SeekFrom::Current(offset) => offset_from(file.position, offset),
+        };
+
+        file.position = match position.ok_or(FsError::InvalidSeek)? {
+            position if position > MAX_FILE_SIZE => return Err(FsError::FileTooLarge),
+            position => position,
+        };
+
+        Ok(file.position)
+    }
+
+    pub fn file_metadata(&self, file: &OpenFile) -> Metadata {
+        self.nodes[&file.inode].metadata()
+    }
+
+    pub fn metadata(&self, path: &str) -> Result<Metadata, FsError> {
+        Ok(self.nodes[&self.lookup(path)?].metadata())
+    }
+
+    pub fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        match &self.nodes[&self.lookup(path)?] {
+            Node::Directory(entries) => Ok(entries
+                .iter()
+                .map(|(name, inode)| DirEntry { name: name.clone(), metadata: self.nodes[inode].metadata() })
+                .collect()),
+            Node::File(_) => Err(FsError::NotADirectory),
+        }
+    }
+
+    pub fn create_dir(&mut self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = self.lookup_parent(path)?;
+        if self.entries_mut(parent).contains_key(&name) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        self.insert(parent, &name, Node::Directory(BTreeMap::new()));
+        Ok(())
+    }
+
+    /// Remove a file, which stays around until `open_handles` are closed
+    pub fn remove_file(&mut self, path: &str, open_handles: impl Fn(Inode) -> usize) -> Result<(), FsError> {
+        let (parent, name) = self.lookup_parent(path)?;
+        let inode = *self.entries_mut(parent).get(&name).ok_or(FsError::NotFound)?;
+
+        if let Node::Directory(_) = self.nodes[&inode] {
+            return Err(FsError::IsADirectory);
+        }
+
+        self.entries_mut(parent).remove(&name);
+        match open_handles(inode) {
+            0 => {
+                self.nodes.remove(&inode);
+            }
+            handles => {
+                self.orphans.insert(inode, handles);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_write() -> OpenFlags {
+        OpenFlags { read: true, write: true, create: true, ..OpenFlags::default() }
+    }
+
+    #[test]
+    fn write_past_end_leaves_a_hole() {
+        let mut fs = MemoryFilesystem::new();
+        let mut file = fs.open("/hole", read_write()).unwrap();
+
+        assert_eq!(fs.seek(&mut file, SeekFrom::Start(4)), Ok(4));
+        assert_eq!(fs.write(&mut file, b"ab"), Ok(2));
+        assert_eq!(fs.file_metadata(&file).len, 6);
+
+        fs.seek(&mut file, SeekFrom::Start(0)).unwrap();
+        assert_eq!(fs.read(&mut file, 16).unwrap(), b"\0\0\0\0ab");
+    }
+
+    #[test]
+    fn seek_past_end_doesnt_grow_the_file() {
+        let mut fs = MemoryFilesystem::new();
+        let mut file = fs.open("/file", read_write()).unwrap();
+        fs.write(&mut file, b"abc").unwrap();
+
+        assert_eq!(fs.seek(&mut file, SeekFrom::End(10)), Ok(13));
+        assert_eq!(fs.file_metadata(&file).len, 3);
+        assert_eq!(fs.read(&mut file, 4).unwrap(), b"");
+    }
+
+    #[test]
+    fn seek_before_start_is_rejected() {
+        let mut fs = MemoryFilesystem::new();
+        let mut file = fs.open("/file", read_write()).unwrap();
+        fs.write(&mut file, b"abc").unwrap();
+
+        assert_eq!(fs.seek(&mut file, SeekFrom::End(-4)), Err(FsError::InvalidSeek));
+        assert_eq!(fs.seek(&mut file, SeekFrom::Current(i64::MIN)), Err(FsError::InvalidSeek));
+        assert_eq!(file.position, 3);
+    }
+
+    #[test]
+    fn seek_past_max_size_is_rejected() {
+        let mut fs = MemoryFilesystem::new();
+        let mut file = fs.open("/file", read_write()).unwrap();
+
+        assert_eq!(fs.seek(&mut file, SeekFrom::Start(MAX_FILE_SIZE)), Ok(MAX_FILE_SIZE));
+        assert_eq!(fs.seek(&mut file, SeekFrom::Start(MAX_FILE_SIZE + 1)), Err(FsError::FileTooLarge));
+        assert_eq!(fs.seek(&mut file, SeekFrom::Current(i64::MAX)), Err(FsError::FileTooLarge));
+        assert_eq!(fs.seek(&mut file, SeekFrom::Start(u64::MAX)), Err(FsError::FileTooLarge));
+        assert_eq!(file.position, MAX_FILE_SIZE);
+    }
+
+    #[test]
+    fn write_past_max_size_is_rejected() {
+        let mut fs = MemoryFilesystem::new();
+        let mut file = fs.open("/file", read_write()).unwrap();
+
+        fs.seek(&mut file, SeekFrom::Start(MAX_FILE_SIZE - 1)).unwrap();
+        assert_eq!(fs.write(&mut file, b"ab"), Err(FsError::FileTooLarge));
+        assert_eq!(fs.file_metadata(&file).len, 0);
+
+        // A position that overflows when the write's length is added to it
+        file.position = u64::MAX;
+        assert_eq!(fs.write(&mut file, b"a"), Err(FsError::FileTooLarge));
+    }
+}
@@ -0,0 +1,86 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use core::sync::atomic::Ordering;
+use librust::{
+    capabilities::{CapabilityDescription, CapabilityPtr},
+    syscalls::{channel::KernelMessage, mem::MemoryPermissions},
+};
+use std::{
+    collections::BTreeMap,
+    ipc::{ChannelReadFlags, IpcChannel},
+    logging::{MalformedFrame, Ring},
+};
+
+/// A task logging through a ring it shared with us, see [`std::logging`]
+struct Producer {
+    ring: Ring,
+    /// Dropped records which have already been reported
+    dropped: usize,
+    /// The target of the last record read, to say where dropped records came
+    /// from
+    last_target: Option<String>,
+}
+
+fn main() {
+    librust::syscalls::task::enable_notifications();
+    let mut producers = BTreeMap::<CapabilityPtr, Producer>::new();
+
+    loop {
+        if let KernelMessage::NewChannelMessage(cptr) = librust::syscalls::channel::read_kernel_message() {
+            let channel = IpcChannel::new(cptr);
+
+            // Every doorbell is read before the ring is, so one rung while the
+            // ring is being read makes sure it gets read again
+            while let Ok((_, caps)) = channel.read_with_all_caps(ChannelReadFlags::NONBLOCKING) {
+                let ring = caps.iter().find_map(|cap| match cap.description {
+                    CapabilityDescription::Memory { ptr, len, permissions }
+                        if permissions & MemoryPermissions::WRITE =>
+                    {
+                        // SAFETY: the memory is mapped for as long as we hold
+                        // the capability, which is forever
+                        unsafe { Ring::new(ptr, len) }
+                    }
+                    _ => None,
+                });
+
+                if let Some(ring) = ring {
+                    // A doorbell already waiting is as good as any number more
+                    let _ = channel.set_queue_depth(1);
+                    producers.insert(cptr, Producer { ring, dropped: 0, last_target: None });
+                }
+            }
+
+            if let Some(producer) = producers.get_mut(&cptr) {
+                drain(producer);
+            }
+        }
+    }
+}
+
+fn drain(producer: &mut Producer) {
+    loop {
+        match producer.ring.read() {
+            Ok(Some(frame)) => {
+                println!("[{}] {}: {}", frame.target, frame.level, frame.message);
+                producer.last_target = Some(frame.target);
+            }
+            Ok(None) => break,
+            Err(MalformedFrame) => println!("[logger] Discarded malformed log frames from {}", source(producer)),
+        }
+    }
+
+    let dropped = producer.ring.header().dropped.load(Ordering::Relaxed);
+    if dropped != producer.dropped {
+        println!("[logger] {} records from {} were dropped", dropped.wrapping_sub(producer.dropped), source(producer));
+        producer.dropped = dropped;
+    }
+}
+
+fn source(producer: &Producer) -> &str {
+    producer.last_target.as_deref().unwrap_or("a task which hasn't logged anything yet")
+}
@@ -0,0 +1,193 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! HMAC-SHA256 (RFC 2104, FIPS 180-4), which is all the handshake needs
+
+pub const MAC_LEN: usize = 32;
+const BLOCK_LEN: usize = 64;
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5, 0xd807aa98,
+    0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786,
+    0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8,
+    0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13,
+    0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819,
+    0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a,
+    0x5b9cca4f, 0x682e6ff3, 0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7,
+    0xc67178f2,
+];
+
+const INITIAL_STATE: [u32; 8] =
+    [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19];
+
+/// The HMAC-SHA256 of `message` with `key`
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; MAC_LEN] {
+    let mut block_key = [0; BLOCK_LEN];
+    match key.len() > BLOCK_LEN {
+        true => block_key[..MAC_LEN].copy_from_slice(&sha256(&[key])),
+        false => block_key[..key.len()].copy_from_slice(key),
+    }
+
+    let inner_pad = block_key.map(|b| b ^ 0x36);
+    let outer_pad = block_key.map(|b| b ^ 0x5c);
+
+    sha256(&[&outer_pad, &sha256(&[&inner_pad, message])])
+}
+
+/// Compare two MACs without giving away how much of them matched through how
+/// long it took
+pub fn verify(expected: &[u8; MAC_LEN], received: &[u8]) -> bool {
+    received.len() == MAC_LEN && expected.iter().zip(received).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// The SHA-256 of `parts` concatenated together
+fn sha256(parts: &[&[u8]]) -> [u8; MAC_LEN] {
+    let mut state = INITIAL_STATE;
+    let mut block = [0; BLOCK_LEN];
+    let mut filled = 0;
+    let mut len = 0u64;
+
+    for &byte in parts.iter().flat_map(|part| part.iter()) {
+        block[filled] = byte;
+        filled += 1;
+        len += 8;
+
+        if filled == BLOCK_LEN {
+            compress(&mut state, &block);
+            filled = 0;
+        }
+    }
+
+    // Padding is a single 1 bit, then 0s up to the last 8 bytes of a block,
+    // which hold the length of the message in bits
+    block[filled] = 0x80;
+    block[filled + 1..].fill(0);
+    if filled >= BLOCK_LEN - 8 {
+        compress(&mut state, &block);
+        block.fill(0);
+    }
+
+    block[BLOCK_LEN - 8..].copy_from_slice(&len.to_be_bytes());
+    compress(&mut state, &block);
+
+    let mut digest = [0; MAC_LEN];
+    for (bytes, word) in digest.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+
+    digest
+}
+
+fn compress(state: &mut [u32; 8], block: &[u8; BLOCK_LEN]) {
+    let mut w = [0u32; 64];
+    for (word, bytes) in w.iter_mut().zip(block.chunks_exact(4)) {
+        *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+
+    for i in 16..64 {
+        let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+        let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+        w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+    for (k, w) in K.iter().zip(w) {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let ch = (e & f) ^ (!e & g);
+        let t1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(*k).wrapping_add(w);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0.wrapping_add(maj);
+
+        h = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(t1);
+        d = c;
+        c = b;
+        b = a;
+        a = t1.wrapping_add(t2);
+    }
+
+    for (word, new) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+        *word = word.wrapping_add(new);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn sha256_padding_boundaries() {
+        // FIPS 180-4 examples, the last of which needs an extra block for the
+        // length
+        let cases: [(&[u8], &str); 3] = [
+            (b"", "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"),
+            (b"abc", "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+            (
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+                "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+            ),
+        ];
+
+        for (message, digest) in cases {
+            assert_eq!(sha256(&[message]).to_vec(), hex(digest));
+        }
+    }
+
+    /// RFC 4231 test cases 1 through 4, 6, and 7. Case 5 is for truncated MACs,
+    /// which aren't used.
+    #[test]
+    fn hmac_sha256_known_answers() {
+        let cases: [(Vec<u8>, &[u8], &str); 6] = [
+            (
+                vec![0x0b; 20],
+                b"Hi There",
+                "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+            ),
+            (
+                b"Jefe".to_vec(),
+                b"what do ya want for nothing?",
+                "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            ),
+            (vec![0xaa; 20], &[0xdd; 50], "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe"),
+            ((1..=25).collect(), &[0xcd; 50], "82558a389a443c0ea4cc819899f2083a85f0faa3e578f8077a2e3ff46729665b"),
+            (
+                vec![0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First",
+                "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+            ),
+            (
+                vec![0xaa; 131],
+                b"This is a test using a larger than block-size key and a larger than block-size data. The key needs to \
+                  be hashed before being used by the HMAC algorithm.",
+                "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2",
+            ),
+        ];
+
+        for (key, message, mac) in cases {
+            assert_eq!(hmac_sha256(&key, message).to_vec(), hex(mac));
+        }
+    }
+
+    #[test]
+    fn verify_rejects_wrong_and_truncated_macs() {
+        let mac = hmac_sha256(b"key", b"message");
+        assert!(verify(&mac, &mac));
+
+        let mut flipped = mac;
+        flipped[MAC_LEN - 1] ^= 1;
+        assert!(!verify(&mac, &flipped));
+        assert!(!verify(&mac, &mac[..MAC_LEN - 1]));
+        assert!(!verify(&mac, &[mac.as_slice(), &[0]].concat()));
+    }
+}
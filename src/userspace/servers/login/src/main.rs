@@ -0,0 +1,258 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Remote login
+//!
+//! Accepts a connection at a time on the vsock port `login.port`, and bridges
+//! it to a pseudo-terminal once the peer proves it knows the shared key given
+//! as `login.key=<key>` on the kernel command line. Nothing is accepted if
+//! there's no key. The network stack doesn't do TCP yet, so vsock is the only
+//! stream transport there is to listen on.
+//!
+//! The key is read straight out of the device tree init hands us rather than
+//! from the config server, which any task can read and write. It isn't under
+//! the `config.` prefix, so the config server never picks it up.
+//!
+//! The handshake goes:
+//!
+//! 1. The server sends `VLOGIN1\n` followed by a 32 byte random challenge
+//! 2. The peer replies with the HMAC-SHA256 of the challenge keyed with the
+//!    shared key, followed by its window size as big endian `u16`s, rows then
+//!    columns
+//! 3. The server sends `OK\n` and starts the session, or closes the connection
+//!    if the MAC is wrong or the reply doesn't arrive within 10 seconds
+//!
+//! Only the login is authenticated, the session itself isn't encrypted.
+
+mod hmac;
+
+use core::time::Duration;
+use interfaces::{
+    console::{self, WindowSize},
+    login,
+};
+use librust::capabilities::CapabilityPtr;
+use present::{
+    ipc::{IpcChannel, NewChannelListener},
+    sync::mpsc::{Receiver, Sender},
+    vsock::{VsockError, VsockStream},
+};
+use std::ipc::IpcError;
+
+const DEFAULT_PORT: u32 = 2323;
+const GREETING: &[u8] = b"VLOGIN1\n";
+const CHALLENGE_LEN: usize = 32;
+const REPLY_LEN: usize = hmac::MAC_LEN + 4;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const KEY_BOOTARG: &str = "login.key=";
+
+#[derive(Debug)]
+enum SessionError {
+    Vsock(VsockError),
+    Console(IpcError),
+}
+
+impl From<VsockError> for SessionError {
+    fn from(e: VsockError) -> Self {
+        Self::Vsock(e)
+    }
+}
+
+impl From<IpcError> for SessionError {
+    fn from(e: IpcError) -> Self {
+        Self::Console(e)
+    }
+}
+
+async fn real_main() {
+    let key = match bootargs_key() {
+        Some(key) => key,
+        None => {
+            log::warn!("No login.key on the kernel command line, not accepting logins");
+            return;
+        }
+    };
+
+    let port = match std::env::config("login.port") {
+        Ok(Some(port)) => port.parse().unwrap_or(DEFAULT_PORT),
+        _ => DEFAULT_PORT,
+    };
+
+    let vsock = std::env::lookup_capability("vsock").unwrap().capability.cptr;
+    let console_cptr = std::env::lookup_capability("console").unwrap().capability.cptr;
+    let console = IpcChannel::new(console_cptr);
+
+    let (shells_tx, shells) = present::sync::mpsc::unbounded();
+    present::spawn(wait_for_shells(shells_tx, [vsock, console_cptr]));
+
+    loop {
+        let stream = match VsockStream::accept(vsock, port).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::error!("Couldn't listen on port {}: {:?}", port, e);
+                return;
+            }
+        };
+
+        let peer = stream.peer_address();
+        let size = match present::time::timeout(HANDSHAKE_TIMEOUT, handshake(&stream, key.as_bytes())).await {
+            Ok(Some(size)) => size,
+            _ => {
+                log::warn!("Rejected login from {}:{}", peer.cid, peer.port);
+                let _ = stream.shutdown();
+                continue;
+            }
+        };
+
+        // Ties together everything logged about the session
+        let id = librust::uuid::generate_uuid().unwrap_or(librust::uuid::Uuid::NIL);
+        log::info!("Accepted login from {}:{}, session {}", peer.cid, peer.port, id);
+        match session(&stream, &console, &shells, size).await {
+            Ok(()) => log::info!("Session {} ended", id),
+            Err(e) => log::warn!("Session {} ended with an error: {:?}", id, e),
+        }
+
+        let _ = stream.shutdown();
+    }
+}
+
+/// The login key from the kernel command line, which only tasks init gives the
+/// device tree to can see
+fn bootargs_key() -> Option<String> {
+    let fdt = unsafe { fdt::Fdt::from_ptr(std::env::a2() as *const u8) }.ok()?;
+    let args = fdt.chosen().bootargs()?;
+
+    args.split(' ').find_map(|arg| arg.strip_prefix(KEY_BOOTARG)).filter(|key| !key.is_empty()).map(String::from)
+}
+
+/// Challenge the peer to prove it knows `key`, returning the window size it
+/// asked for if it does
+async fn handshake(stream: &VsockStream, key: &[u8]) -> Option<WindowSize> {
+    let mut challenge = [0; CHALLENGE_LEN];
+    librust::syscalls::io::get_random(&mut challenge).ok()?;
+
+    let mut greeting = GREETING.to_vec();
+    greeting.extend_from_slice(&challenge);
+    stream.send(&greeting).ok()?;
+
+    let mut reply = Vec::new();
+    while reply.len() < REPLY_LEN {
+        reply.extend(stream.recv().await.ok()?);
+    }
+
+    // The peer has to wait for `OK` before sending anything else
+    if reply.len() != REPLY_LEN {
+        return None;
+    }
+
+    let (mac, size) = reply.split_at(hmac::MAC_LEN);
+    match hmac::verify(&hmac::hmac_sha256(key, &challenge), mac) {
+        true => Some(WindowSize {
+            rows: u16::from_be_bytes([size[0], size[1]]),
+            columns: u16::from_be_bytes([size[2], size[3]]),
+        }),
+        false => None,
+    }
+}
+
+/// Bridge `stream` to a new pseudo-terminal, handed to the next shell asking
+/// for a session, until either the peer or the shell goes away
+async fn session(
+    stream: &VsockStream,
+    console: &IpcChannel,
+    shells: &Receiver<CapabilityPtr>,
+    size: WindowSize,
+) -> Result<(), SessionError> {
+    console.send_serialized(&console::Request::CreatePty(size), &[]).map_err(IpcError::from)?;
+    let pty = match console.read_serialized::<console::Response>().await?.0 {
+        console::Response::PtyCreated { id } => id,
+        _ => return Err(SessionError::Console(IpcError::UnexpectedMessage)),
+    };
+
+    stream.send(b"OK\n")?;
+
+    // Anything the peer sends before a shell takes the session is held on to,
+    // since there's nothing on the other side of the pseudo-terminal yet
+    let mut pending = Vec::new();
+    loop {
+        present::select! {
+            shell = shells.recv() => {
+                let shell = std::ipc::IpcChannel::new(shell);
+                // The shell could have exited while it was waiting
+                if shell.send_serialized(&login::Response::Session { pty }, &[]).is_ok() {
+                    break;
+                }
+            }
+            data = stream.recv() => {
+                match data {
+                    Ok(data) => pending.extend(data),
+                    Err(e) => {
+                        console.send_serialized(&console::Request::Close, &[]).map_err(IpcError::from)?;
+                        return match e {
+                            VsockError::Closed => Ok(()),
+                            e => Err(e.into()),
+                        };
+                    }
+                }
+            }
+        }
+    }
+
+    if !pending.is_empty() {
+        console.send_serialized(&console::Request::Write(pending), &[]).map_err(IpcError::from)?;
+    }
+
+    let result = loop {
+        present::select! {
+            data = stream.recv() => {
+                match data {
+                    Ok(data) => console.send_serialized(&console::Request::Write(data), &[]).map_err(IpcError::from)?,
+                    Err(VsockError::Closed) => break Ok(()),
+                    Err(e) => break Err(e.into()),
+                }
+            }
+            response = console.read_serialized::<console::Response>() => {
+                match response?.0 {
+                    console::Response::Data(data) => stream.send(&data)?,
+                    // The shell closed its side
+                    console::Response::HostConnected(false) => break Ok(()),
+                    _ => {}
+                }
+            }
+        }
+    };
+
+    // Closing the master side removes the pseudo-terminal, which the shell
+    // sees the same way as a removed port
+    console.send_serialized(&console::Request::Close, &[]).map_err(IpcError::from)?;
+
+    result
+}
+
+/// Queue up every request for a session from whatever runs the shell, to be
+/// answered once someone logs in
+async fn wait_for_shells(shells: Sender<CapabilityPtr>, ignore: [CapabilityPtr; 2]) {
+    let listener = NewChannelListener::new();
+    loop {
+        let cptr = listener.recv().await;
+        // Replies from the vsock and console servers show up as new channels
+        // the first time they arrive
+        if ignore.contains(&cptr) {
+            continue;
+        }
+
+        let shells = shells.clone();
+        present::spawn(async move {
+            let channel = IpcChannel::new(cptr);
+            while let Ok((login::Request::NextSession, _)) = channel.read_serialized::<login::Request>().await {
+                shells.send(cptr);
+            }
+        });
+    }
+}
+
+present::main!({ real_main().await });
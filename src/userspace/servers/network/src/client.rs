@@ -5,78 +5,37 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
-use crate::{ClientMessage, ControlMessage, PortType};
-use librust::{capabilities::CapabilityPtr, syscalls::channel::ChannelMessage};
-use netstack::ipv4::IpV4Socket;
+use crate::{ClientMessage, ControlMessage};
+use interfaces::{
+    network::{Request, Response},
+    BindError, Datagram,
+};
+use librust::capabilities::{Capability, CapabilityPtr, CapabilityRights};
+use netstack::ipv4::{IpV4Address, IpV4Socket};
 use present::{ipc::IpcChannel, sync::mpsc::Sender};
 
-json::derive! {
-    #[derive(Debug, Clone)]
-    struct BindRequest {
-        port: u16,
-        port_type: String,
-    }
-}
-
-json::derive! {
-    #[derive(Debug, Clone)]
-    struct BindResponse {
-        msg: String,
-        port: Option<u16>,
-    }
-}
-
-json::derive! {
-    #[derive(Debug, Clone)]
-    struct SendRequest {
-        // FIXME: this should be an IpV4Socket
-        to_ip: String,
-        to_port: u16,
-        data: Vec<u8>,
-    }
-}
-
-json::derive! {
-    #[derive(Debug, Clone)]
-    struct SendResponse {
-        msg: String,
-        ok: bool,
-    }
-}
-
-json::derive! {
-    #[derive(Debug, Clone)]
-    struct Received {
-        // FIXME: this should be an IpV4Socket
-        from_ip: String,
-        from_port: u16,
-        data: Vec<u8>,
-    }
-}
-
 pub async fn handle_client(
     control_tx: Sender<ControlMessage>,
     packet_tx: Sender<(u16, IpV4Socket, Vec<u8>)>,
+    time_status: CapabilityPtr,
     cptr: CapabilityPtr,
 ) {
     let ipc_channel = IpcChannel::new(cptr);
-    let msg = ipc_channel.temp_read_json().await;
-
-    let (request, _msg, _): (BindRequest, _, _) = match msg {
-        Ok(msg) => msg,
+    let request = match ipc_channel.read_serialized::<Request>().await {
+        Ok((request, _)) => request,
         Err(e) => {
-            println!("Error reading from IPC channel: {:?}", e);
+            log::warn!("Error reading from IPC channel: {:?}", e);
             return;
         }
     };
 
-    let port = request.port;
-    let port_type = match &*request.port_type {
-        "udp" => PortType::Udp,
-        "raw" => PortType::Raw,
-        _ => {
-            let _ = ipc_channel
-                .temp_send_json(ChannelMessage::default(), &BindResponse { msg: String::from("unknown port type"), port: None }, &[]);
+    let (port, port_type) = match request {
+        Request::bind { port, port_type } => (port, port_type),
+        // Not a port at all, but a way to get at the topic the SNTP client
+        // publishes to, which only the network server gets to publish to
+        Request::time_status {} => {
+            let topic = Capability::new(time_status, CapabilityRights::READ);
+            let _ = ipc_channel.send_serialized(&Response::time_status(()), &[topic]);
             return;
         }
     };
@@ -86,15 +45,11 @@ pub async fn handle_client(
 
     match client_rx.recv().await {
         ClientMessage::PortInUse => {
-            let _ = ipc_channel
-                .temp_send_json(ChannelMessage::default(), &BindResponse { msg: String::from("port in use"), port: None }, &[]);
+            let _ = ipc_channel.send_serialized(&Response::bind(Err(BindError::PortInUse)), &[]);
             return;
         }
         ClientMessage::PortBound => {
-            if ipc_channel
-                .temp_send_json(ChannelMessage::default(), &BindResponse { msg: String::new(), port: Some(port) }, &[])
-                .is_err()
-            {
+            if ipc_channel.send_serialized(&Response::bind(Ok(port)), &[]).is_err() {
                 control_tx.send(ControlMessage::ClientDisconnect { port });
                 return;
             }
@@ -107,15 +62,8 @@ pub async fn handle_client(
             msg = client_rx.recv() => {
                 match msg {
                     ClientMessage::Received { from, data } => {
-                        if ipc_channel.temp_send_json(
-                            ChannelMessage::default(),
-                            &Received {
-                                from_ip: from.ip.to_string(),
-                                from_port: from.port,
-                                data,
-                            },
-                            &[]
-                        ).is_err() {
+                        let datagram = Datagram { ip: from.ip.to_bytes(), port: from.port, data };
+                        if ipc_channel.send_serialized(&datagram, &[]).is_err() {
                             control_tx.send(ControlMessage::ClientDisconnect { port });
                             break;
                         }
@@ -123,24 +71,17 @@ pub async fn handle_client(
                     _ => {}
                 }
             }
-            msg = ipc_channel.temp_read_json() => {
-                let (request, msg, _): (SendRequest, _, _) = match msg {
-                    Ok(msg) => msg,
+            msg = ipc_channel.read_serialized::<Datagram>() => {
+                let datagram = match msg {
+                    Ok((datagram, _)) => datagram,
                     Err(_) => {
                         control_tx.send(ControlMessage::ClientDisconnect { port });
                         break;
                     },
                 };
 
-                let ip = match request.to_ip.parse() {
-                    Ok(ip) => ip,
-                    Err(_) => {
-                        control_tx.send(ControlMessage::ClientDisconnect { port });
-                        break;
-                    }
-                };
-
-                packet_tx.send((port, IpV4Socket::new(ip, request.to_port), request.data));
+                let to = IpV4Socket::new(IpV4Address::from(datagram.ip), datagram.port);
+                packet_tx.send((port, to, datagram.data));
             }
         }
     }
@@ -10,12 +10,9 @@ use std::collections::BTreeMap;
 use librust::mem::{DmaElement, DmaRegion};
 use netstack::MacAddress;
 use virtio::{
-    devices::net::{
-        GsoType, HeaderFlags, LinkStatus, NetDeviceFeatures, NetDeviceFeaturesSplit, VirtIoNetHeaderRx,
-        VirtIoNetHeaderTx,
-    },
+    devices::net::{GsoType, HeaderFlags, LinkStatus, NetDeviceFeatures, VirtIoNetHeaderRx, VirtIoNetHeaderTx},
     splitqueue::{DescriptorFlags, SplitVirtqueue, SplitqueueIndex, VirtqueueDescriptor},
-    StatusFlag, VirtIoDeviceError,
+    MmioTransport, Transport, VirtIoDeviceError,
 };
 
 use crate::drivers::DriverError;
@@ -27,6 +24,7 @@ unsafe impl Sync for VirtIoNetDevice {}
 
 pub struct VirtIoNetDevice {
     device: &'static virtio::devices::net::VirtIoNetDevice,
+    transport: MmioTransport,
     receive_queue: SplitVirtqueue,
     transmit_queue: SplitVirtqueue,
     rx_data_buffer: RxDataBuffer,
@@ -61,96 +59,31 @@ impl VirtIoNetDevice {
             rx_buffer_map.insert(descriptor, index);
         }
 
-        device.header.status.reset();
-
-        device.header.status.set_flag(StatusFlag::Acknowledge);
-        device.header.status.set_flag(StatusFlag::Driver);
-        device.header.device_features_select.write(0);
-
-        let mut available_features = device.header.features() as u64;
-        device.header.device_features_select.write(1);
-        available_features |= (device.header.features() as u64) << 32;
-
-        let available_features = NetDeviceFeatures::new(available_features);
-        let mut selected_features = NetDeviceFeatures::none();
-
-        // We require a valid MAC address
-        selected_features |= NetDeviceFeatures::MAC_ADDRESS;
-        if !(available_features & NetDeviceFeatures::MAC_ADDRESS) {
-            return Err(VirtIoDeviceError::FeaturesNotRecognized);
-        }
-
-        // We require checksum offloading (for now)
-        // selected_features |= NetDeviceFeatures::CHKSUM_OFFLOAD;
-        // if !(available_features & NetDeviceFeatures::CHKSUM_OFFLOAD) {
-        //     return Err(VirtIoDeviceError::FeaturesNotRecognized);
-        // }
-
-        // We require the status information
-        selected_features |= NetDeviceFeatures::STATUS;
-        if !(available_features & NetDeviceFeatures::STATUS) {
-            return Err(VirtIoDeviceError::FeaturesNotRecognized);
-        }
-
-        // We require the max MTU information
-        // selected_features |= NetDeviceFeatures::MAX_MTU;
-        // if !(available_features & NetDeviceFeatures::MAX_MTU) {
-        //     return Err(VirtIoDeviceError::FeaturesNotRecognized);
-        // }
-
-        // We require speed and duplex information
-        // selected_features |= NetDeviceFeatures::SPEED_DUPLEX;
-        // if !(available_features & NetDeviceFeatures::SPEED_DUPLEX) {
-        //     return Err(VirtIoDeviceError::FeaturesNotRecognized);
-        // }
-
-        let NetDeviceFeaturesSplit { low, high } = selected_features.split();
-        device.header.driver_features_select.write(0);
-        device.header.driver_features.write(low);
-        device.header.driver_features_select.write(1);
-        device.header.driver_features.write(high);
-
-        device.header.status.set_flag(StatusFlag::FeaturesOk);
-
-        if !device.header.status.is_set(StatusFlag::FeaturesOk) {
-            return Err(VirtIoDeviceError::FeaturesNotRecognized);
-        }
-
-        // Receive Queue
-        device.header.queue_select.write(0);
-        librust::mem::fence(librust::mem::FenceMode::Write);
-        assert!(device.header.queue_size_max.read() > 0);
-        device.header.queue_size.write(receive_queue.queue_size());
-        device.header.queue_available.set(receive_queue.available.physical_address());
-        device.header.queue_descriptor.set(receive_queue.descriptors.physical_address());
-        device.header.queue_used.set(receive_queue.used.physical_address());
-        device.header.queue_ready.ready();
-
-        librust::mem::fence(librust::mem::FenceMode::Write);
-
-        // Transmit Queue
-        device.header.queue_select.write(1);
-        librust::mem::fence(librust::mem::FenceMode::Write);
-        assert!(device.header.queue_size_max.read() > 0);
-        device.header.queue_size.write(transmit_queue.queue_size());
-        device.header.queue_available.set(transmit_queue.available.physical_address());
-        device.header.queue_descriptor.set(transmit_queue.descriptors.physical_address());
-        device.header.queue_used.set(transmit_queue.used.physical_address());
-        device.header.queue_ready.ready();
-
-        librust::mem::fence(librust::mem::FenceMode::Write);
-
-        device.header.status.set_flag(StatusFlag::DriverOk);
-
-        librust::mem::fence(librust::mem::FenceMode::Write);
-
-        if device.header.status.failed() {
-            return Err(VirtIoDeviceError::DeviceError);
-        }
-
-        device.header.queue_notify.notify(0);
-
-        Ok(Self { device, receive_queue, transmit_queue, rx_data_buffer, rx_buffer_map, tx_data_buffer, tx_buffer_map })
+        let transport = MmioTransport::new(&device.header)?;
+        transport.begin_init();
+
+        // We require a valid MAC address and the link status information
+        // (checksum offloading, the max MTU, and speed and duplex information
+        // aren't used yet)
+        let required = NetDeviceFeatures::MAC_ADDRESS | NetDeviceFeatures::STATUS;
+        transport.negotiate_features(required.bits(), 0)?;
+
+        transport.configure_queue(0, &receive_queue)?;
+        transport.configure_queue(1, &transmit_queue)?;
+        transport.finish_init()?;
+
+        transport.notify(0);
+
+        Ok(Self {
+            device,
+            transport,
+            receive_queue,
+            transmit_queue,
+            rx_data_buffer,
+            rx_buffer_map,
+            tx_data_buffer,
+            tx_buffer_map,
+        })
     }
 
     pub fn mac_address(&self) -> MacAddress {
@@ -226,7 +159,7 @@ impl super::NetworkDriver for VirtIoNetDevice {
     }
 
     fn process_interrupt(&mut self, _: usize) -> Result<Option<&[u8]>, super::DriverError> {
-        self.device.header.interrupt_ack.acknowledge_buffer_used();
+        self.transport.acknowledge_interrupt();
 
         if let Some(used) = self.transmit_queue.used.pop() {
             let descr = SplitqueueIndex::new(used.start_index as u16);
@@ -277,10 +210,7 @@ impl super::NetworkDriver for VirtIoNetDevice {
         );
         self.transmit_queue.available.push(descr);
         self.tx_buffer_map.insert(descr, index);
-
-        librust::mem::fence(librust::mem::FenceMode::Write);
-
-        self.device.header.queue_notify.notify(1);
+        self.transport.notify(1);
 
         Ok(())
     }
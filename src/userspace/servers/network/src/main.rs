@@ -11,10 +11,12 @@ mod arp;
 mod client;
 mod dhcp_helpers;
 mod drivers;
+mod sntp;
 
 use crate::{arp::ARP_CACHE, drivers::NetworkDriver};
 use alchemy::PackedStruct;
 use dhcp::{options::DhcpMessageType, DhcpMessageParser, DhcpOption};
+use interfaces::PortType;
 use librust::{capabilities::{Capability, CapabilityWithDescription}, syscalls::channel::ChannelMessage};
 use netstack::{
     arp::{ArpHeader, ArpOperation, ArpPacket, HardwareType},
@@ -27,7 +29,10 @@ use present::{
     ipc::{IpcChannel},
     sync::{mpsc::Sender, oneshot::OneshotTx},
 };
-use std::{collections::BTreeMap, ipc::ChannelReadFlags};
+use std::{
+    collections::BTreeMap,
+    ipc::{ChannelReadFlags, Topic},
+};
 
 json::derive! {
     #[derive(Debug, Clone)]
@@ -69,12 +74,6 @@ pub enum ClientMessage {
     Received { from: IpV4Socket, data: Vec<u8> },
 }
 
-#[derive(Debug)]
-pub enum PortType {
-    Udp,
-    Raw,
-}
-
 async fn real_main() {
     let virtiomgr = std::ipc::IpcChannel::new(std::env::lookup_capability("virtiomgr").unwrap().capability.cptr);
 
@@ -117,7 +116,11 @@ async fn real_main() {
     let mut default_gateway = None;
     let (control_tx, control_rx) = present::sync::mpsc::unbounded();
 
+    let time_status = Topic::new().expect("failed to create time status topic");
+    let time_status_cptr = time_status.cptr();
+
     let dhcp_control_tx = control_tx.clone();
+    let sntp_packet_tx = packet_tx.clone();
     present::spawn(async move {
         let mut our_ip;
         let mut router_ip;
@@ -135,13 +138,13 @@ async fn real_main() {
                     match response.message_type() {
                         Ok(DhcpMessageType::OFFER) if mac == this_mac => response,
                         _ => {
-                            println!("Not offer!");
+                            log::debug!("Ignoring a DHCP response which isn't an offer to us");
                             continue;
                         }
                     }
                 }
                 Err(e) => {
-                    println!("Error: {:?}", e);
+                    log::warn!("Bad DHCP response: {:?}", e);
                     continue;
                 }
             };
@@ -215,6 +218,7 @@ async fn real_main() {
         });
 
         ARP_CACHE.resolve_and_cache(router_ip).await;
+        present::spawn(sntp::run(dhcp_control_tx, sntp_packet_tx, time_status));
     });
 
     let channel_listener = present::ipc::NewChannelListener::new();
@@ -242,12 +246,12 @@ async fn real_main() {
                                     }
                                 }
                                 protocol => {
-                                    println!("got an IPv4 protocol we don't deal with yet: {:?}", protocol);
+                                    log::debug!("Got an IPv4 protocol we don't deal with yet: {:?}", protocol);
                                 },
                             }
                         }
                         frame_type => {
-                            println!("got an ethernet frame type we don't deal with yet: {:?}", frame_type);
+                            log::debug!("Got an ethernet frame type we don't deal with yet: {:?}", frame_type);
                         }
                     }
                 }
@@ -255,7 +259,7 @@ async fn real_main() {
                 librust::syscalls::io::complete_interrupt(interrupt_id).unwrap();
             }
             cptr = channel_listener.recv() => {
-                present::spawn(client::handle_client(control_tx.clone(), packet_tx.clone(), cptr));
+                present::spawn(client::handle_client(control_tx.clone(), packet_tx.clone(), time_status_cptr, cptr));
             }
             (outgoing_port, dst_socket, pkt_data) = packet_recv.recv() => {
                 if !interface_ips.is_empty() && default_gateway.is_some() {
@@ -320,7 +324,7 @@ async fn real_main() {
                     ControlMessage::ClientDisconnect { port } => drop(ports.remove(&port)),
                     ControlMessage::NewInterfaceIp(ip) => {
                         interface_ips.push(ip);
-                        println!("New IP on network interface: {}", ip);
+                        log::info!("New IP on network interface: {}", ip);
                     }
                     ControlMessage::NewDefaultGateway(ip) => default_gateway = Some(ip),
                     ControlMessage::NewClient { port, port_type, tx } => {
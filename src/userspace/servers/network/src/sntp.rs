@@ -0,0 +1,207 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! An SNTP (RFC 4330) client keeping the wall clock in line with the NTP server
+//! at `sntp.server`, polled every `sntp.interval` seconds
+//!
+//! Offsets under [`STEP_THRESHOLD`] are slewed in by the kernel so the clock
+//! never jumps, anything larger steps it, like when there's no RTC and the
+//! clock started from the epoch. The outcome of every poll is published as a
+//! [`TimeSyncStatus`] on the topic handed out to clients which ask for it with
+//! [`interfaces::network::Request::time_status`].
+//!
+//! Setting the clock needs the `clock` capability, without which the clock
+//! isn't synchronized at all.
+
+use crate::{ClientMessage, ControlMessage, PortType};
+use core::time::Duration;
+use interfaces::TimeSyncStatus;
+use librust::syscalls::time::{self, TimeAdjustment};
+use netstack::ipv4::{IpV4Address, IpV4Socket};
+use present::sync::mpsc::{Receiver, Sender};
+use std::ipc::Topic;
+
+const NTP_PORT: u16 = 123;
+/// The port requests are sent from, which NTP servers don't care about
+const CLIENT_PORT: u16 = 12300;
+const PACKET_LEN: usize = 48;
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(1024);
+/// How soon to try again after a poll fails
+const RETRY_INTERVAL: Duration = Duration::from_secs(16);
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Offsets at least this large step the clock rather than slew it, the same
+/// threshold as the reference implementation
+const STEP_THRESHOLD: Duration = Duration::from_millis(128);
+/// Seconds from the NTP epoch, 1900, to the Unix epoch
+const UNIX_EPOCH_OFFSET: u64 = 2_208_988_800;
+
+const VERSION: u8 = 4;
+const MODE_CLIENT: u8 = 3;
+const MODE_SERVER: u8 = 4;
+const LEAP_UNSYNCHRONIZED: u8 = 3;
+
+/// The parts of a server's response needed to find the offset
+struct Response {
+    stratum: u8,
+    /// When the server received the request
+    received: Duration,
+    /// When the server sent the response
+    transmitted: Duration,
+}
+
+pub async fn run(control_tx: Sender<ControlMessage>, packet_tx: Sender<(u16, IpV4Socket, Vec<u8>)>, status: Topic) {
+    let server = match std::env::config("sntp.server") {
+        Ok(Some(server)) => match server.parse::<IpV4Address>() {
+            Ok(ip) => IpV4Socket::new(ip, NTP_PORT),
+            Err(_) => {
+                log::warn!("sntp.server isn't an IPv4 address, not synchronizing the clock");
+                return;
+            }
+        },
+        _ => return,
+    };
+
+    let clock = match std::env::lookup_capability("clock") {
+        Some(clock) => clock.capability.cptr,
+        None => {
+            log::info!("No clock capability, not synchronizing the clock");
+            return;
+        }
+    };
+
+    let interval = match std::env::config("sntp.interval") {
+        Ok(Some(interval)) => interval.parse().map(Duration::from_secs).unwrap_or(DEFAULT_INTERVAL),
+        _ => DEFAULT_INTERVAL,
+    };
+
+    let (tx, rx) = present::sync::mpsc::unbounded();
+    control_tx.send(ControlMessage::NewClient { port: CLIENT_PORT, port_type: PortType::Udp, tx });
+    if !matches!(rx.recv().await, ClientMessage::PortBound) {
+        log::warn!("SNTP port {} is in use, not synchronizing the clock", CLIENT_PORT);
+        return;
+    }
+
+    loop {
+        let next_poll = match poll(&packet_tx, &rx, server).await {
+            Some((offset_nanos, round_trip, stratum)) => {
+                let stepped = offset_nanos.unsigned_abs() as u128 >= STEP_THRESHOLD.as_nanos();
+                let adjustment = match stepped {
+                    true => {
+                        let now = time::wall_clock().since_epoch.as_nanos() as i128;
+                        TimeAdjustment::Step(Duration::from_nanos((now + offset_nanos as i128).max(0) as u64))
+                    }
+                    false => TimeAdjustment::Slew(offset_nanos),
+                };
+
+                if let Err(e) = time::set_time(clock, adjustment) {
+                    log::warn!("Failed to adjust the clock: {:?}", e);
+                }
+
+                if stepped {
+                    log::info!("Stepped the clock by {}ms to match {}", offset_nanos / 1_000_000, server.ip);
+                }
+
+                let _ = status.publish_serialized(
+                    &TimeSyncStatus::Synchronized {
+                        server: server.ip.to_string(),
+                        offset_nanos,
+                        round_trip_nanos: round_trip.as_nanos() as u64,
+                        stratum,
+                        stepped,
+                    },
+                    &[],
+                );
+
+                interval
+            }
+            None => {
+                let _ = status.publish_serialized(&TimeSyncStatus::Unreachable { server: server.ip.to_string() }, &[]);
+                RETRY_INTERVAL
+            }
+        };
+
+        present::time::sleep(next_poll).await;
+    }
+}
+
+/// Ask `server` for the time, returning how far off the wall clock is from it
+/// in nanoseconds, the round trip time, and the server's stratum
+async fn poll(
+    packet_tx: &Sender<(u16, IpV4Socket, Vec<u8>)>,
+    rx: &Receiver<ClientMessage>,
+    server: IpV4Socket,
+) -> Option<(i64, Duration, u8)> {
+    // The transmit timestamp is only echoed back by the server, so a random one
+    // makes spoofed responses harder to pass off and the real time is kept
+    // locally instead (RFC 4330 section 5)
+    let mut nonce = [0; 8];
+    librust::syscalls::io::get_random(&mut nonce).ok()?;
+
+    let mut request = vec![0; PACKET_LEN];
+    request[0] = (VERSION << 3) | MODE_CLIENT;
+    request[40..48].copy_from_slice(&nonce);
+
+    let sent = time::wall_clock().since_epoch;
+    packet_tx.send((CLIENT_PORT, server, request));
+
+    let response = present::time::timeout(RESPONSE_TIMEOUT, async {
+        loop {
+            if let ClientMessage::Received { from, data } = rx.recv().await {
+                if from == server {
+                    if let Some(response) = parse_response(&data, &nonce) {
+                        break response;
+                    }
+                }
+            }
+        }
+    })
+    .await
+    .ok()?;
+
+    let received = time::wall_clock().since_epoch;
+    let round_trip = received.saturating_sub(sent);
+
+    // offset = ((T2 - T1) + (T3 - T4)) / 2
+    let nanos = |d: Duration| d.as_nanos() as i128;
+    let offset = ((nanos(response.received) - nanos(sent)) + (nanos(response.transmitted) - nanos(received))) / 2;
+    let server_time = response.transmitted.saturating_sub(response.received);
+
+    Some((offset as i64, round_trip.saturating_sub(server_time), response.stratum))
+}
+
+fn parse_response(data: &[u8], nonce: &[u8; 8]) -> Option<Response> {
+    let data = data.get(..PACKET_LEN)?;
+    let (leap, mode, stratum) = (data[0] >> 6, data[0] & 0b111, data[1]);
+
+    // A stratum of 0 is a "kiss-o'-death" telling the client to back off or go
+    // elsewhere, and above 15 the server isn't synchronized to anything
+    if mode != MODE_SERVER || leap == LEAP_UNSYNCHRONIZED || !(1..=15).contains(&stratum) || &data[24..32] != nonce {
+        return None;
+    }
+
+    Some(Response { stratum, received: ntp_timestamp(&data[32..40])?, transmitted: ntp_timestamp(&data[40..48])? })
+}
+
+/// Convert an NTP timestamp to the time since the Unix epoch, or `None` if it's
+/// zero, which means the server didn't fill it in
+fn ntp_timestamp(bytes: &[u8]) -> Option<Duration> {
+    let seconds = u32::from_be_bytes(bytes[..4].try_into().unwrap()) as u64;
+    let fraction = u32::from_be_bytes(bytes[4..8].try_into().unwrap()) as u64;
+
+    if seconds == 0 && fraction == 0 {
+        return None;
+    }
+
+    // Timestamps with the top bit clear are from after the seconds wrap around
+    // in 2036 (RFC 4330 section 3)
+    let seconds = match seconds & 0x8000_0000 {
+        0 => seconds + (1 << 32),
+        _ => seconds,
+    };
+
+    Some(Duration::new(seconds.saturating_sub(UNIX_EPOCH_OFFSET), ((fraction * 1_000_000_000) >> 32) as u32))
+}
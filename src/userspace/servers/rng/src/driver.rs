@@ -0,0 +1,75 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use librust::mem::DmaRegion;
+use virtio::{
+    devices::rng::{VirtIoRngDevice, REQUEST_QUEUE},
+    splitqueue::{DescriptorFlags, SplitVirtqueue, SplitqueueIndex, VirtqueueDescriptor},
+    MmioTransport, Transport, VirtIoDeviceError,
+};
+
+const QUEUE_SIZE: usize = 2;
+/// How many random bytes are asked for at a time, which is enough to reseed
+/// the kernel's generator on its own
+const REQUEST_SIZE: usize = 64;
+
+pub struct VirtIoRng {
+    transport: MmioTransport,
+    queue: SplitVirtqueue,
+    buffer: DmaRegion<[u8; REQUEST_SIZE]>,
+    /// The descriptor the device currently has, if any
+    in_flight: Option<SplitqueueIndex<VirtqueueDescriptor>>,
+}
+
+impl VirtIoRng {
+    pub fn new(device: &'static VirtIoRngDevice) -> Result<Self, VirtIoDeviceError> {
+        let transport = MmioTransport::new(&device.header)?;
+        transport.begin_init();
+        transport.negotiate_features(0, 0)?;
+
+        let queue = SplitVirtqueue::new(QUEUE_SIZE).unwrap();
+        transport.configure_queue(REQUEST_QUEUE, &queue)?;
+        transport.finish_init()?;
+
+        Ok(Self { transport, queue, buffer: unsafe { DmaRegion::zeroed().unwrap().assume_init() }, in_flight: None })
+    }
+
+    /// Ask the device for more random bytes, unless it's still working on the
+    /// last request
+    pub fn request(&mut self) {
+        if self.in_flight.is_some() {
+            return;
+        }
+
+        let descriptor = self.queue.alloc_descriptor().unwrap();
+        self.queue.descriptors.write(
+            descriptor,
+            VirtqueueDescriptor {
+                address: self.buffer.physical_address(),
+                length: REQUEST_SIZE as u32,
+                flags: DescriptorFlags::WRITE,
+                next: SplitqueueIndex::new(0),
+            },
+        );
+        self.queue.available.push(descriptor);
+        self.in_flight = Some(descriptor);
+
+        self.transport.notify(REQUEST_QUEUE);
+    }
+
+    /// Returns the random bytes the device filled in, if it finished the
+    /// outstanding request
+    pub fn process_interrupt(&mut self) -> Option<&[u8]> {
+        self.transport.acknowledge_interrupt();
+
+        let used = self.queue.used.pop()?;
+        let descriptor = self.in_flight.take()?;
+        self.queue.free_descriptor(descriptor);
+
+        Some(&self.buffer[..(used.length as usize).min(REQUEST_SIZE)])
+    }
+}
@@ -0,0 +1,93 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+mod driver;
+
+use driver::VirtIoRng;
+use librust::{
+    capabilities::{Capability, CapabilityWithDescription},
+    syscalls::channel::{ChannelMessage, KernelMessage},
+};
+use std::ipc::{ChannelReadFlags, IpcChannel};
+
+/// How often more bytes are fetched from the device, which is often enough that
+/// each of the kernel's reseeds has some mixed in
+const REFRESH_INTERVAL: core::time::Duration = core::time::Duration::from_secs(60);
+const REFRESH_TIMER: usize = 0;
+
+json::derive! {
+    #[derive(Debug, Clone)]
+    struct Device {
+        name: String,
+        compatible: Vec<String>,
+        interrupts: Vec<usize>,
+    }
+}
+
+json::derive! {
+    Serialize,
+    struct VirtIoDeviceRequest {
+        ty: u32,
+    }
+}
+
+json::derive! {
+    Deserialize,
+    #[derive(Debug)]
+    struct VirtIoDeviceResponse {
+        devices: Vec<Device>,
+    }
+}
+
+fn main() {
+    let virtiomgr = IpcChannel::new(std::env::lookup_capability("virtiomgr").unwrap().capability.cptr);
+
+    virtiomgr
+        .temp_send_json(
+            ChannelMessage::default(),
+            &VirtIoDeviceRequest { ty: virtio::DeviceType::EntropySource as u32 },
+            &[],
+        )
+        .unwrap();
+
+    let (response, _, capabilities): (VirtIoDeviceResponse, _, _) =
+        virtiomgr.temp_read_json(ChannelReadFlags::NONE).unwrap();
+
+    if response.devices.is_empty() {
+        log::info!("No entropy devices found, the kernel will make do without");
+        return;
+    }
+
+    let (CapabilityWithDescription { capability: Capability { cptr: mmio_cap, .. }, .. }, device) =
+        (capabilities[0], &response.devices[0]);
+    let (info, _) = librust::syscalls::io::query_mmio_cap(mmio_cap, &mut []).unwrap();
+
+    let interrupt_id = device.interrupts[0];
+    let device_registers = unsafe { &*(info.address() as *const virtio::devices::rng::VirtIoRngDevice) };
+    let mut driver = VirtIoRng::new(device_registers).unwrap();
+
+    librust::syscalls::task::enable_notifications();
+    driver.request();
+
+    loop {
+        match librust::syscalls::channel::read_kernel_message() {
+            KernelMessage::InterruptOccurred(id) if id == interrupt_id => {
+                if let Some(bytes) = driver.process_interrupt() {
+                    if let Err(e) = librust::syscalls::io::add_entropy(bytes) {
+                        log::warn!("Failed to add entropy: {:?}", e);
+                    }
+
+                    librust::syscalls::task::set_timer(REFRESH_TIMER, REFRESH_INTERVAL).unwrap();
+                }
+
+                librust::syscalls::io::complete_interrupt(id).unwrap();
+            }
+            KernelMessage::TimerExpired(REFRESH_TIMER) => driver.request(),
+            _ => {}
+        }
+    }
+}
@@ -7,45 +7,20 @@
 
 mod ns16550;
 
-use std::ipc::ChannelReadFlags;
-
-use librust::{
-    capabilities::{CapabilityDescription, CapabilityWithDescription},
-    syscalls::channel::{ChannelMessage, KernelMessage},
-};
+use interfaces::devicemgr;
+use librust::{capabilities::CapabilityPtr, syscalls::channel::KernelMessage};
 use ns16550::Uart16550;
-
-json::derive! {
-    #[derive(Debug)]
-    struct Device {
-        name: String,
-        compatible: Vec<String>,
-        interrupts: Vec<usize>,
-    }
-}
-
-json::derive! {
-    #[derive(Debug)]
-    struct Devices {
-        devices: Vec<Device>,
-    }
-}
-
-json::derive! {
-    Serialize,
-    struct WantedCompatible {
-        compatible: Vec<String>,
-    }
-}
+use std::{
+    collections::BTreeMap,
+    ipc::{ChannelReadFlags, FrameError, Framed, IpcChannel},
+};
 
 fn main() {
     let devicemgr = std::env::lookup_capability("devicemgr").unwrap();
-    let devicemgr = std::ipc::IpcChannel::new(devicemgr.capability.cptr);
+    let devicemgr = devicemgr::Client::new(devicemgr.capability.cptr);
 
-    let msg = &WantedCompatible { compatible: vec![String::from("ns16550"), String::from("ns16550a")] };
-    devicemgr.temp_send_json(ChannelMessage::default(), msg, &[]).unwrap();
-
-    let (_devices, _message, caps) = devicemgr.temp_read_json::<Devices>(ChannelReadFlags::NONE).unwrap();
+    let (_devices, caps) =
+        devicemgr.request_devices(vec![String::from("ns16550"), String::from("ns16550a")], &[]).unwrap();
     let mut interrupt_buffer = [0];
     let (uart_info, _) =
         librust::syscalls::io::query_mmio_cap(caps[0].capability.cptr, &mut interrupt_buffer[..]).unwrap();
@@ -60,6 +35,9 @@ fn main() {
     //     uart.write_str(&format!("    {:?}\n", device));
     // }
 
+    // Clients write to us with `std::handle::write`, which sends each write as
+    // frames
+    let mut clients = BTreeMap::<CapabilityPtr, Framed>::new();
     let mut input = Vec::new();
     librust::syscalls::task::enable_notifications();
     loop {
@@ -77,17 +55,14 @@ fn main() {
             _ => continue,
         };
 
-        let msg = std::ipc::IpcChannel::new(cptr);
-        let (msg, caps) = match msg.read_with_all_caps(ChannelReadFlags::NONBLOCKING) {
-            Ok(data) => data,
-            Err(_) => continue,
-        };
-
-        if let Some(CapabilityWithDescription { description: CapabilityDescription::Memory { ptr, len, .. }, .. }) =
-            caps.get(0)
-        {
-            for b in unsafe { core::slice::from_raw_parts(*ptr, (*len).min(msg.0[0])) } {
-                uart.write(*b);
+        let framed = clients.entry(cptr).or_insert_with(|| Framed::new(IpcChannel::new(cptr)));
+        loop {
+            match framed.recv(ChannelReadFlags::NONBLOCKING) {
+                Ok(frame) => frame.data.into_iter().for_each(|b| uart.write(b)),
+                // Nothing left to read, or only part of a frame so far
+                Err(FrameError::Syscall(_)) => break,
+                // Whatever was wrong with the frame, it's been discarded
+                Err(_) => continue,
             }
         }
     }
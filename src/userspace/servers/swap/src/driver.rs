@@ -0,0 +1,140 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use librust::{
+    mem::{DmaRegion, PhysicalAddress},
+    syscalls::swap::PAGE_SIZE,
+};
+use std::collections::BTreeMap;
+use virtio::{
+    devices::block::{Command, CommandError, CommandKind, CommandStatus, VirtIoBlockDevice},
+    splitqueue::{DescriptorFlags, SplitVirtqueue, SplitqueueIndex, VirtqueueDescriptor},
+    MmioTransport, Transport, VirtIoDeviceError,
+};
+
+/// Each request takes up three descriptors
+const QUEUE_SIZE: usize = 64;
+/// The most pages read or written at once
+pub const BATCH: usize = QUEUE_SIZE / 3;
+
+/// A virtio block device which reads and writes a page at a time, with one
+/// page buffer for each request which can be in flight
+pub struct SwapDevice {
+    device: &'static VirtIoBlockDevice,
+    transport: MmioTransport,
+    queue: SplitVirtqueue,
+    commands: DmaRegion<[Command]>,
+    pub pages: DmaRegion<[[u8; PAGE_SIZE]]>,
+    /// The buffer used by the request starting at each descriptor
+    in_flight: BTreeMap<SplitqueueIndex<VirtqueueDescriptor>, usize>,
+}
+
+impl SwapDevice {
+    pub fn new(device: &'static VirtIoBlockDevice) -> Result<Self, VirtIoDeviceError> {
+        let queue = SplitVirtqueue::new(QUEUE_SIZE).unwrap();
+
+        let transport = MmioTransport::new(&device.header)?;
+        transport.begin_init();
+        transport.negotiate_features(0, 0)?;
+        transport.configure_queue(0, &queue)?;
+        transport.finish_init()?;
+
+        Ok(Self {
+            device,
+            transport,
+            queue,
+            commands: unsafe { DmaRegion::zeroed_many(BATCH).unwrap().assume_init() },
+            pages: unsafe { DmaRegion::zeroed_many(BATCH).unwrap().assume_init() },
+            in_flight: BTreeMap::new(),
+        })
+    }
+
+    /// The size of the disk in sectors
+    pub fn capacity(&self) -> u64 {
+        self.device.capacity()
+    }
+
+    /// Read the page starting at `sector` into page buffer `buffer`
+    pub fn read(&mut self, sector: u64, buffer: usize) {
+        self.submit(CommandKind::Read, sector, buffer);
+    }
+
+    /// Write the contents of page buffer `buffer` to the page starting at
+    /// `sector`
+    pub fn write(&mut self, sector: u64, buffer: usize) {
+        self.submit(CommandKind::Write, sector, buffer);
+    }
+
+    pub fn idle(&self) -> bool {
+        self.in_flight.is_empty()
+    }
+
+    fn submit(&mut self, kind: CommandKind, sector: u64, buffer: usize) {
+        let mut command = self.commands.get(buffer).unwrap();
+        *command.get_mut() = Command { kind, _reserved: 0, sector, status: 0 };
+        let command_address = command.physical_address();
+        let page_address = self.pages.get(buffer).unwrap().physical_address();
+
+        let data_flags = match kind {
+            CommandKind::Read => DescriptorFlags::NEXT | DescriptorFlags::WRITE,
+            _ => DescriptorFlags::NEXT,
+        };
+
+        let header = self.queue.alloc_descriptor().unwrap();
+        let data = self.queue.alloc_descriptor().unwrap();
+        let status = self.queue.alloc_descriptor().unwrap();
+
+        self.queue.descriptors.write(
+            header,
+            VirtqueueDescriptor { address: command_address, length: 16, flags: DescriptorFlags::NEXT, next: data },
+        );
+        self.queue.descriptors.write(
+            data,
+            VirtqueueDescriptor { address: page_address, length: PAGE_SIZE as u32, flags: data_flags, next: status },
+        );
+        self.queue.descriptors.write(
+            status,
+            VirtqueueDescriptor {
+                address: PhysicalAddress::new(command_address.as_usize() + 16),
+                length: 1,
+                flags: DescriptorFlags::WRITE,
+                ..Default::default()
+            },
+        );
+
+        self.queue.available.push(header);
+        self.in_flight.insert(header, buffer);
+        self.transport.notify(0);
+    }
+
+    /// Take the next request the device has finished, returning the page
+    /// buffer it used and whether it succeeded
+    pub fn complete(&mut self) -> Option<(usize, Result<(), CommandError>)> {
+        let header = SplitqueueIndex::new(self.queue.used.pop()?.start_index as u16);
+        let data = self.queue.descriptors.read(header).next;
+        let status = self.queue.descriptors.read(data).next;
+
+        librust::mem::fence(librust::mem::FenceMode::Full);
+
+        self.queue.free_descriptor(header);
+        self.queue.free_descriptor(data);
+        self.queue.free_descriptor(status);
+
+        let buffer = self.in_flight.remove(&header).unwrap();
+        let status = self.commands.get(buffer).unwrap().get().status;
+        let result = match CommandStatus::from_u8(status) {
+            Some(status) => status.into_result(),
+            None => Err(CommandError::UnknownStatusCode(status)),
+        };
+
+        Some((buffer, result))
+    }
+
+    pub fn acknowledge_interrupt(&self) {
+        self.transport.acknowledge_interrupt();
+    }
+}
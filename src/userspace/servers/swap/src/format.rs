@@ -0,0 +1,52 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The layout of a swap space on disk
+//!
+//! The first sector holds a header identifying the disk as a swap space, and
+//! the page sized slots start at the first page boundary after it. Nothing on
+//! the disk is kept across boots, the header is only there so a disk which
+//! wasn't meant to be swapped to isn't overwritten.
+
+use librust::syscalls::swap::PAGE_SIZE;
+
+pub const SECTOR_SIZE: usize = 512;
+pub const MAGIC: [u8; 8] = *b"VSWAPSPC";
+pub const VERSION: u32 = 1;
+
+const SECTORS_PER_SLOT: u64 = (PAGE_SIZE / SECTOR_SIZE) as u64;
+const FIRST_SLOT_SECTOR: u64 = SECTORS_PER_SLOT;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub n_slots: u64,
+}
+
+impl Header {
+    /// Read the header from the first sector of a disk, returning `None` if it
+    /// isn't a swap space this knows how to use
+    pub fn parse(sector: &[u8]) -> Option<Self> {
+        let u32_at = |offset: usize| u32::from_le_bytes(sector[offset..offset + 4].try_into().unwrap());
+
+        if sector.len() < 24 || sector[..8] != MAGIC || u32_at(8) != VERSION || u32_at(12) != PAGE_SIZE as u32 {
+            return None;
+        }
+
+        Some(Self { n_slots: u64::from_le_bytes(sector[16..24].try_into().unwrap()) })
+    }
+
+    /// The number of slots which fit on a disk of `capacity` sectors, which
+    /// may be fewer than the header claims
+    pub fn usable_slots(&self, capacity: u64) -> u64 {
+        self.n_slots.min(capacity.saturating_sub(FIRST_SLOT_SECTOR) / SECTORS_PER_SLOT)
+    }
+}
+
+/// The first sector of `slot`
+pub fn slot_sector(slot: u32) -> u64 {
+    FIRST_SLOT_SECTOR + u64::from(slot) * SECTORS_PER_SLOT
+}
@@ -0,0 +1,183 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+mod driver;
+mod format;
+
+use driver::{SwapDevice, BATCH};
+use format::Header;
+use librust::{
+    capabilities::{Capability, CapabilityWithDescription},
+    syscalls::{
+        channel::{ChannelMessage, KernelMessage},
+        swap,
+    },
+};
+use std::ipc::{ChannelReadFlags, IpcChannel};
+
+json::derive! {
+    #[derive(Debug, Clone)]
+    struct Device {
+        name: String,
+        compatible: Vec<String>,
+        interrupts: Vec<usize>,
+    }
+}
+
+json::derive! {
+    Serialize,
+    struct VirtIoDeviceRequest {
+        ty: u32,
+    }
+}
+
+json::derive! {
+    Deserialize,
+    #[derive(Debug)]
+    struct VirtIoDeviceResponse {
+        devices: Vec<Device>,
+    }
+}
+
+struct Server {
+    device: SwapDevice,
+    interrupt_id: usize,
+    /// The slot each page buffer is being used for
+    slots: [u32; BATCH],
+}
+
+impl Server {
+    /// Wait for the device to finish everything it's been given, returning the
+    /// page buffers of the requests which failed
+    fn finish(&mut self) -> Vec<usize> {
+        let mut failed = Vec::new();
+
+        while !self.device.idle() {
+            match librust::syscalls::channel::read_kernel_message() {
+                KernelMessage::InterruptOccurred(id) if id == self.interrupt_id => {
+                    self.device.acknowledge_interrupt();
+                    while let Some((buffer, result)) = self.device.complete() {
+                        if let Err(e) = result {
+                            log::warn!("Request for slot {} failed: {:?}", self.slots[buffer], e);
+                            failed.push(buffer);
+                        }
+                    }
+
+                    librust::syscalls::io::complete_interrupt(id).unwrap();
+                }
+                // More work is picked up once this batch is done anyway
+                _ => {}
+            }
+        }
+
+        failed
+    }
+
+    /// Write out the next batch of pages which were swapped out, returning
+    /// how many there were
+    fn write_batch(&mut self) -> usize {
+        let n = swap::take_writes(&mut self.slots, &mut self.device.pages).unwrap();
+        for buffer in 0..n {
+            self.device.write(format::slot_sector(self.slots[buffer]), buffer);
+        }
+
+        // There's nothing to be done about a page which couldn't be written,
+        // other than hoping it's never needed again
+        self.finish();
+
+        n
+    }
+
+    /// Read in the next batch of pages tasks are waiting on, returning how
+    /// many there were
+    fn read_batch(&mut self) -> usize {
+        let n = swap::take_reads(&mut self.slots).unwrap();
+        for buffer in 0..n {
+            self.device.read(format::slot_sector(self.slots[buffer]), buffer);
+        }
+
+        // Whatever was waiting on a page which couldn't be read is left blocked
+        // rather than carrying on with the wrong contents
+        let failed = self.finish();
+        for buffer in (0..n).filter(|buffer| !failed.contains(buffer)) {
+            swap::complete_read(self.slots[buffer], &self.device.pages[buffer]).unwrap();
+        }
+
+        n
+    }
+}
+
+/// Find a block device with a swap space on it
+fn probe(virtiomgr: &IpcChannel) -> Option<(Server, Header)> {
+    virtiomgr
+        .temp_send_json(
+            ChannelMessage::default(),
+            &VirtIoDeviceRequest { ty: virtio::DeviceType::BlockDevice as u32 },
+            &[],
+        )
+        .unwrap();
+
+    let (response, _, capabilities): (VirtIoDeviceResponse, _, _) =
+        virtiomgr.temp_read_json(ChannelReadFlags::NONE).unwrap();
+
+    for (CapabilityWithDescription { capability: Capability { cptr: mmio_cap, .. }, .. }, device) in
+        capabilities.into_iter().zip(response.devices)
+    {
+        let (info, _) = librust::syscalls::io::query_mmio_cap(mmio_cap, &mut []).unwrap();
+        let driver =
+            match SwapDevice::new(unsafe { &*(info.address() as *const virtio::devices::block::VirtIoBlockDevice) }) {
+                Ok(driver) => driver,
+                Err(e) => {
+                    log::warn!("Failed to initialize block device {}: {:?}", device.name, e);
+                    continue;
+                }
+            };
+
+        let mut server = Server { device: driver, interrupt_id: device.interrupts[0], slots: [0; BATCH] };
+        server.device.read(0, 0);
+        if !server.finish().is_empty() {
+            continue;
+        }
+
+        if let Some(header) = Header::parse(&server.device.pages[0][..format::SECTOR_SIZE]) {
+            log::info!("Found a swap space on {}", device.name);
+            return Some((server, header));
+        }
+    }
+
+    None
+}
+
+fn main() {
+    let virtiomgr = IpcChannel::new(std::env::lookup_capability("virtiomgr").unwrap().capability.cptr);
+    let kswap = std::env::lookup_capability("kswap").unwrap().capability.cptr;
+
+    librust::syscalls::task::enable_notifications();
+    let (mut server, header) = match probe(&virtiomgr) {
+        Some(found) => found,
+        None => return,
+    };
+
+    let n_slots = header.usable_slots(server.device.capacity()).min(u64::from(u32::MAX));
+    if n_slots == 0 {
+        log::warn!("Swap space is too small to hold any pages");
+        return;
+    }
+
+    swap::enable(kswap, n_slots as usize).unwrap();
+
+    loop {
+        let written = server.write_batch();
+        let read = server.read_batch();
+
+        // The kernel only says there's more to do once everything it had
+        // queued up has been taken
+        if written == 0 && read == 0 {
+            librust::syscalls::channel::read_kernel_message();
+        }
+    }
+}
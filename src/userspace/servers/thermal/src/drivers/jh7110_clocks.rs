@@ -0,0 +1,130 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The StarFive JH7110's system clock generator, along with the PLLs it's fed
+//! from, which live in the system syscon. Only the clocks leading to the cores
+//! are worked out, from however the firmware configured them.
+
+use super::ClockController;
+use volatile::Volatile;
+
+pub const CRG_COMPATIBLE: &[&str] = &["starfive,jh7110-syscrg"];
+pub const SYSCON_COMPATIBLE: &[&str] = &["starfive,jh7110-sys-syscon"];
+
+// Each clock has a single register, indexed by its ID in the device tree
+// bindings
+const CPU_ROOT: u32 = 0;
+const CPU_CORE: u32 = 1;
+const CPU_BUS: u32 = 2;
+
+const CLOCK_MUX_SHIFT: u32 = 24;
+const CLOCK_MUX_MASK: u32 = 0x3F;
+const CLOCK_DIVIDER_MASK: u32 = 0xFF_FFFF;
+
+/// Where each PLL's fields are in the syscon
+struct PllLayout {
+    dacpd: (usize, u32),
+    dsmpd: (usize, u32),
+    fbdiv: (usize, u32),
+    frac: usize,
+    prediv: usize,
+}
+
+const PLLS: [PllLayout; 3] = [
+    PllLayout { dacpd: (0x18, 24), dsmpd: (0x18, 25), fbdiv: (0x1C, 0), frac: 0x20, prediv: 0x24 },
+    PllLayout { dacpd: (0x24, 15), dsmpd: (0x24, 16), fbdiv: (0x24, 17), frac: 0x28, prediv: 0x2C },
+    PllLayout { dacpd: (0x2C, 15), dsmpd: (0x2C, 16), fbdiv: (0x2C, 17), frac: 0x30, prediv: 0x34 },
+];
+
+const PLL_FBDIV_MASK: u32 = 0xFFF;
+const PLL_FRAC_MASK: u32 = 0xFF_FFFF;
+const PLL_POSTDIV1_SHIFT: u32 = 28;
+const PLL_POSTDIV1_MASK: u32 = 0x3;
+const PLL_PREDIV_MASK: u32 = 0x3F;
+
+pub struct Jh7110Clocks {
+    crg: *mut u8,
+    syscon: *mut u8,
+    osc_hz: u64,
+}
+
+impl Jh7110Clocks {
+    /// # Safety
+    ///
+    /// `crg` and `syscon` must be the mapped MMIO of the system clock generator
+    /// and the system syscon
+    pub unsafe fn new(crg: *mut u8, syscon: *mut u8, osc_hz: u64) -> Self {
+        Self { crg, syscon, osc_hz }
+    }
+
+    fn read(base: *mut u8, offset: usize) -> u32 {
+        unsafe { (*base.add(offset).cast::<Volatile<u32>>()).read() }
+    }
+
+    fn clock(&self, id: u32) -> u32 {
+        Self::read(self.crg, id as usize * 4)
+    }
+
+    fn divided(&self, parent: u64, id: u32) -> u64 {
+        parent / u64::from((self.clock(id) & CLOCK_DIVIDER_MASK).max(1))
+    }
+
+    /// The output of PLL `n`, which is `osc * (fbdiv + frac / 2^24) / prediv /
+    /// 2^postdiv1`, or zero if it's in a mode the driver doesn't understand
+    fn pll(&self, n: usize) -> u64 {
+        let layout = &PLLS[n];
+        let bit = |(offset, shift): (usize, u32)| (Self::read(self.syscon, offset) >> shift) & 1;
+
+        let frac_word = Self::read(self.syscon, layout.frac);
+        let frac = match (bit(layout.dacpd), bit(layout.dsmpd)) {
+            // Integer mode
+            (1, 1) => 0,
+            // Fractional mode
+            (0, 0) => frac_word & PLL_FRAC_MASK,
+            _ => return 0,
+        };
+
+        let fbdiv = (Self::read(self.syscon, layout.fbdiv.0) >> layout.fbdiv.1) & PLL_FBDIV_MASK;
+        let prediv = Self::read(self.syscon, layout.prediv) & PLL_PREDIV_MASK;
+        let postdiv1 = (frac_word >> PLL_POSTDIV1_SHIFT) & PLL_POSTDIV1_MASK;
+
+        let multiplied = (self.osc_hz * ((u64::from(fbdiv) << 24) + u64::from(frac))) >> 24;
+        (multiplied / u64::from(prediv.max(1))) >> postdiv1
+    }
+
+    fn cpu_root(&self) -> u64 {
+        match (self.clock(CPU_ROOT) >> CLOCK_MUX_SHIFT) & CLOCK_MUX_MASK {
+            0 => self.osc_hz,
+            _ => self.pll(0),
+        }
+    }
+
+    fn cpu_core(&self) -> u64 {
+        self.divided(self.cpu_root(), CPU_CORE)
+    }
+}
+
+impl ClockController for Jh7110Clocks {
+    fn rates(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            ("cpu_core", self.cpu_core()),
+            ("cpu_bus", self.divided(self.cpu_core(), CPU_BUS)),
+            ("pll0", self.pll(0)),
+            ("pll1", self.pll(1)),
+            ("pll2", self.pll(2)),
+        ]
+    }
+
+    fn rate(&self, index: u32) -> Option<u64> {
+        match index {
+            CPU_ROOT => Some(self.cpu_root()),
+            CPU_CORE => Some(self.cpu_core()),
+            CPU_BUS => Some(self.divided(self.cpu_core(), CPU_BUS)),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,69 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The temperature sensor on the StarFive JH7110's die, which converts
+//! continuously once it's running. Its clocks and reset are left however the
+//! firmware set them up.
+
+use super::{delay, TemperatureSensor};
+use core::time::Duration;
+use volatile::Volatile;
+
+pub const COMPATIBLE: &[&str] = &["starfive,jh7110-temp"];
+
+const RESET_N: u32 = 1 << 0;
+const POWER_DOWN: u32 = 1 << 1;
+const RUN: u32 = 1 << 2;
+const DOUT_SHIFT: u32 = 16;
+const DOUT_MASK: u32 = 0xFFF;
+
+// The output is converted to millidegrees with `DOUT * Y / Z - K`
+const Y1000: i64 = 237_500;
+const Z: i64 = 4094;
+const K1000: i64 = 81_100;
+
+pub struct Jh7110Temp {
+    register: &'static Volatile<u32>,
+}
+
+impl Jh7110Temp {
+    /// # Safety
+    ///
+    /// `address` must be the sensor's mapped MMIO
+    pub unsafe fn new(address: *mut u8) -> Self {
+        let this = Self { register: &*address.cast::<Volatile<u32>>() };
+
+        // Power cycle the sensor and take it out of reset, after which it
+        // keeps converting until it's powered down again
+        this.register.write(POWER_DOWN);
+        delay(Duration::from_micros(1));
+        this.register.write(0);
+        delay(Duration::from_micros(60));
+        this.register.write(RESET_N);
+        delay(Duration::from_micros(1));
+        this.register.write(RESET_N | RUN);
+
+        this
+    }
+}
+
+impl TemperatureSensor for Jh7110Temp {
+    fn channels(&self) -> &'static [&'static str] {
+        &["die"]
+    }
+
+    fn read(&mut self) -> Vec<Option<i32>> {
+        let dout = (self.register.read() >> DOUT_SHIFT) & DOUT_MASK;
+
+        // Nothing's been converted yet
+        if dout == 0 {
+            return vec![None];
+        }
+
+        vec![Some((dout as i64 * Y1000 / Z - K1000) as i32)]
+    }
+}
@@ -0,0 +1,40 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use librust::syscalls::channel::KernelMessage;
+
+pub mod jh7110_clocks;
+pub mod jh7110_temp;
+pub mod ocores_i2c;
+pub mod sifive_prci;
+pub mod tmp451;
+
+const DELAY_TIMER: usize = 0;
+
+pub trait TemperatureSensor {
+    /// The names of the channels [`TemperatureSensor::read`] reads, in order
+    fn channels(&self) -> &'static [&'static str];
+    /// Read every channel in millidegrees Celsius, with `None` for any which
+    /// couldn't be read
+    fn read(&mut self) -> Vec<Option<i32>>;
+}
+
+pub trait ClockController {
+    /// The rate in Hz of each of the controller's clocks worth reporting
+    fn rates(&self) -> Vec<(&'static str, u64)>;
+    /// The rate of the clock consumers refer to with `index` in their `clocks`
+    /// property, if it's one the driver knows about
+    fn rate(&self, index: u32) -> Option<u64>;
+}
+
+/// Wait for at least `duration`. Any other kernel messages which arrive in the
+/// meantime are dropped, so this is only for while drivers are starting up,
+/// before anyone has been handed a channel to the server.
+pub fn delay(duration: core::time::Duration) {
+    librust::syscalls::task::set_timer(DELAY_TIMER, duration).unwrap();
+    while !matches!(librust::syscalls::channel::read_kernel_message(), KernelMessage::TimerExpired(DELAY_TIMER)) {}
+}
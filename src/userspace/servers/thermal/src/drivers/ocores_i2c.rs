@@ -0,0 +1,122 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The OpenCores I2C master, which SiFive's SoCs use for their I2C controllers.
+//! Transfers are polled, since only a few bytes are moved at a time.
+
+use volatile::Volatile;
+
+pub const COMPATIBLE: &[&str] = &["sifive,i2c0", "opencores,i2c-ocores"];
+
+const PRESCALE_LOW: usize = 0;
+const PRESCALE_HIGH: usize = 1;
+const CONTROL: usize = 2;
+/// Transmit on write, receive on read
+const DATA: usize = 3;
+/// Command on write, status on read
+const COMMAND_STATUS: usize = 4;
+
+const CONTROL_ENABLE: u8 = 1 << 7;
+
+const COMMAND_START: u8 = 1 << 7;
+const COMMAND_STOP: u8 = 1 << 6;
+const COMMAND_READ: u8 = 1 << 5;
+const COMMAND_WRITE: u8 = 1 << 4;
+/// Answer the byte being read with a NACK instead of an ACK
+const COMMAND_NACK: u8 = 1 << 3;
+
+const STATUS_RECEIVED_NACK: u8 = 1 << 7;
+const STATUS_ARBITRATION_LOST: u8 = 1 << 5;
+const STATUS_TRANSFER_IN_PROGRESS: u8 = 1 << 1;
+
+/// How many times the status is polled before a transfer is given up on, which
+/// is plenty for a byte at any bus speed the controller supports
+const MAX_POLLS: usize = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum I2cError {
+    /// Nothing acknowledged the address or a byte written
+    Nack,
+    ArbitrationLost,
+    Timeout,
+}
+
+pub struct OcoresI2c {
+    base: *mut u8,
+    reg_shift: usize,
+}
+
+impl OcoresI2c {
+    /// Set the controller up to run the bus at `bus_hz` from an input clock of
+    /// `input_hz`
+    ///
+    /// # Safety
+    ///
+    /// `base` must be the controller's mapped MMIO, with registers `1 <<
+    /// reg_shift` bytes apart
+    pub unsafe fn new(base: *mut u8, reg_shift: usize, input_hz: u64, bus_hz: u64) -> Self {
+        let this = Self { base, reg_shift };
+        let prescale = (input_hz / (5 * bus_hz)).saturating_sub(1).min(u16::MAX as u64) as u16;
+
+        // The prescaler can only be changed with the core disabled
+        this.register(CONTROL).write(0);
+        this.register(PRESCALE_LOW).write(prescale as u8);
+        this.register(PRESCALE_HIGH).write((prescale >> 8) as u8);
+        this.register(CONTROL).write(CONTROL_ENABLE);
+
+        this
+    }
+
+    /// Read the 8-bit `register` of the device at `address`
+    pub fn read_register(&mut self, address: u8, register: u8) -> Result<u8, I2cError> {
+        self.write_byte(address << 1, COMMAND_START)?;
+        self.write_byte(register, 0)?;
+        self.write_byte(address << 1 | 1, COMMAND_START)?;
+
+        self.register(COMMAND_STATUS).write(COMMAND_READ | COMMAND_NACK | COMMAND_STOP);
+        self.wait()?;
+
+        Ok(self.register(DATA).read())
+    }
+
+    fn write_byte(&mut self, byte: u8, extra_command: u8) -> Result<(), I2cError> {
+        self.register(DATA).write(byte);
+        self.register(COMMAND_STATUS).write(COMMAND_WRITE | extra_command);
+        self.wait()?;
+
+        if self.register(COMMAND_STATUS).read() & STATUS_RECEIVED_NACK != 0 {
+            self.register(COMMAND_STATUS).write(COMMAND_STOP);
+            // The bus is released either way
+            let _ = self.wait();
+            return Err(I2cError::Nack);
+        }
+
+        Ok(())
+    }
+
+    fn wait(&self) -> Result<(), I2cError> {
+        for _ in 0..MAX_POLLS {
+            let status = self.register(COMMAND_STATUS).read();
+
+            if status & STATUS_ARBITRATION_LOST != 0 {
+                return Err(I2cError::ArbitrationLost);
+            }
+
+            if status & STATUS_TRANSFER_IN_PROGRESS == 0 {
+                return Ok(());
+            }
+
+            core::hint::spin_loop();
+        }
+
+        Err(I2cError::Timeout)
+    }
+
+    fn register(&self, register: usize) -> &Volatile<u8> {
+        unsafe { &*self.base.add(register << self.reg_shift).cast::<Volatile<u8>>() }
+    }
+}
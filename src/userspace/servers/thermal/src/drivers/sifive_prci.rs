@@ -0,0 +1,136 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The power, reset, clock and interrupt block of SiFive's FU540 and FU740,
+//! which generates every clock on the SoC from the `hfclk` oscillator. The
+//! rates are worked out from how the firmware configured the PLLs, nothing is
+//! ever reconfigured.
+
+use super::ClockController;
+use volatile::Volatile;
+
+pub const FU540_COMPATIBLE: &str = "sifive,fu540-c000-prci";
+pub const FU740_COMPATIBLE: &str = "sifive,fu740-c000-prci";
+pub const COMPATIBLE: &[&str] = &[FU540_COMPATIBLE, FU740_COMPATIBLE];
+
+const CORE_PLL: usize = 0x04;
+const DDR_PLL: usize = 0x0C;
+const GEMGXL_PLL: usize = 0x1C;
+/// Whether the core runs straight from `hfclk` rather than a PLL
+const CORE_CLOCK_SELECT: usize = 0x24;
+const CLOCK_MUX_STATUS: usize = 0x2C;
+const CLTX_PLL: usize = 0x30;
+const DVFS_CORE_PLL: usize = 0x38;
+/// Whether the core's PLL is the DVFS one
+const CORE_PLL_SELECT: usize = 0x40;
+const HFPCLK_PLL: usize = 0x50;
+const HFPCLK_DIVIDER: usize = 0x5C;
+
+const PLL_DIVR_MASK: u32 = 0x3F;
+const PLL_DIVF_SHIFT: u32 = 6;
+const PLL_DIVF_MASK: u32 = 0x1FF;
+const PLL_DIVQ_SHIFT: u32 = 15;
+const PLL_DIVQ_MASK: u32 = 0x7;
+const PLL_BYPASS: u32 = 1 << 24;
+
+/// Set when the TileLink clock runs at the core clock rather than half of it
+const TLCLK_SELECT: u32 = 1 << 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    Fu540,
+    Fu740,
+}
+
+pub struct SifivePrci {
+    base: *mut u8,
+    variant: Variant,
+    hfclk_hz: u64,
+}
+
+impl SifivePrci {
+    /// # Safety
+    ///
+    /// `base` must be the PRCI's mapped MMIO
+    pub unsafe fn new(base: *mut u8, variant: Variant, hfclk_hz: u64) -> Self {
+        Self { base, variant, hfclk_hz }
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { (*self.base.add(offset).cast::<Volatile<u32>>()).read() }
+    }
+
+    /// The output of the PLL configured at `offset`, which is
+    /// `hfclk * 2 * (divf + 1) / (divr + 1) / 2^divq`
+    fn pll(&self, offset: usize) -> u64 {
+        let config = self.read(offset);
+        if config & PLL_BYPASS != 0 {
+            return self.hfclk_hz;
+        }
+
+        let divr = u64::from(config & PLL_DIVR_MASK);
+        let divf = u64::from((config >> PLL_DIVF_SHIFT) & PLL_DIVF_MASK);
+        let divq = (config >> PLL_DIVQ_SHIFT) & PLL_DIVQ_MASK;
+
+        (self.hfclk_hz * 2 * (divf + 1) / (divr + 1)) >> divq
+    }
+
+    fn core(&self) -> u64 {
+        if self.read(CORE_CLOCK_SELECT) & 1 != 0 {
+            return self.hfclk_hz;
+        }
+
+        match self.variant {
+            Variant::Fu740 if self.read(CORE_PLL_SELECT) & 1 != 0 => self.pll(DVFS_CORE_PLL),
+            _ => self.pll(CORE_PLL),
+        }
+    }
+
+    fn tlclk(&self) -> u64 {
+        match self.read(CLOCK_MUX_STATUS) & TLCLK_SELECT {
+            0 => self.core() / 2,
+            _ => self.core(),
+        }
+    }
+
+    /// The clock for most of the FU740's peripherals
+    fn pclk(&self) -> u64 {
+        self.pll(HFPCLK_PLL) / (u64::from(self.read(HFPCLK_DIVIDER)) + 2)
+    }
+}
+
+impl ClockController for SifivePrci {
+    fn rates(&self) -> Vec<(&'static str, u64)> {
+        let mut rates = vec![
+            ("core", self.core()),
+            ("tlclk", self.tlclk()),
+            ("ddrpll", self.pll(DDR_PLL)),
+            ("gemgxlpll", self.pll(GEMGXL_PLL)),
+        ];
+
+        if self.variant == Variant::Fu740 {
+            rates.extend([("pclk", self.pclk()), ("cltxpll", self.pll(CLTX_PLL))]);
+        }
+
+        rates
+    }
+
+    fn rate(&self, index: u32) -> Option<u64> {
+        // From the FU540 and FU740 device tree bindings
+        match (self.variant, index) {
+            (_, 0) => Some(self.pll(CORE_PLL)),
+            (_, 1) => Some(self.pll(DDR_PLL)),
+            (_, 2) => Some(self.pll(GEMGXL_PLL)),
+            (Variant::Fu540, 3) | (Variant::Fu740, 6) => Some(self.tlclk()),
+            (Variant::Fu740, 3) => Some(self.pll(DVFS_CORE_PLL)),
+            (Variant::Fu740, 4) => Some(self.pll(HFPCLK_PLL)),
+            (Variant::Fu740, 5) => Some(self.pll(CLTX_PLL)),
+            (Variant::Fu740, 7) => Some(self.pclk()),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,72 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The TI TMP451, which the HiFive Unmatched uses to measure its own
+//! temperature and, through an external diode, the FU740's
+
+use super::{
+    ocores_i2c::{I2cError, OcoresI2c},
+    TemperatureSensor,
+};
+use core::cell::RefCell;
+use std::rc::Rc;
+
+pub const COMPATIBLE: &[&str] = &["ti,tmp451"];
+
+const LOCAL_HIGH: u8 = 0x00;
+const REMOTE_HIGH: u8 = 0x01;
+const CONFIGURATION: u8 = 0x03;
+const REMOTE_LOW: u8 = 0x10;
+const LOCAL_LOW: u8 = 0x15;
+
+/// Readings are offset by 64 degrees to allow negative temperatures
+const CONFIGURATION_EXTENDED_RANGE: u8 = 1 << 2;
+
+pub struct Tmp451 {
+    bus: Rc<RefCell<OcoresI2c>>,
+    address: u8,
+}
+
+impl Tmp451 {
+    pub fn new(bus: Rc<RefCell<OcoresI2c>>, address: u8) -> Self {
+        Self { bus, address }
+    }
+
+    fn read_channel(&self, extended_range: bool, high: u8, low: u8) -> Result<i32, I2cError> {
+        let mut bus = self.bus.borrow_mut();
+        let high = bus.read_register(self.address, high)?;
+        let low = bus.read_register(self.address, low)?;
+
+        // The top four bits of the low byte are sixteenths of a degree
+        let sixteenths = (u16::from(high) << 4 | u16::from(low) >> 4) as i32;
+        let millicelsius = sixteenths * 1000 / 16;
+
+        Ok(match extended_range {
+            true => millicelsius - 64_000,
+            false => millicelsius,
+        })
+    }
+}
+
+impl TemperatureSensor for Tmp451 {
+    fn channels(&self) -> &'static [&'static str] {
+        &["local", "remote"]
+    }
+
+    fn read(&mut self) -> Vec<Option<i32>> {
+        let configuration = self.bus.borrow_mut().read_register(self.address, CONFIGURATION);
+        let extended_range = match configuration {
+            Ok(configuration) => configuration & CONFIGURATION_EXTENDED_RANGE != 0,
+            Err(_) => return vec![None, None],
+        };
+
+        vec![
+            self.read_channel(extended_range, LOCAL_HIGH, LOCAL_LOW).ok(),
+            self.read_channel(extended_range, REMOTE_HIGH, REMOTE_LOW).ok(),
+        ]
+    }
+}
@@ -0,0 +1,244 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Temperature and clock frequency telemetry
+//!
+//! Drives whichever temperature sensors and clock controllers the FDT
+//! describes that there's a driver for, and hands out readings of them over the
+//! [`thermal`] interface. There's no policy here yet, it's the groundwork for
+//! throttling based on the readings.
+
+mod drivers;
+
+use core::cell::RefCell;
+use drivers::{
+    jh7110_clocks::{self, Jh7110Clocks},
+    jh7110_temp::{self, Jh7110Temp},
+    ocores_i2c::{self, OcoresI2c},
+    sifive_prci::{self, SifivePrci, Variant},
+    tmp451::{self, Tmp451},
+    ClockController, TemperatureSensor,
+};
+use fdt::{node::FdtNode, Fdt};
+use interfaces::{devicemgr, thermal, ClockReading, Device, Telemetry, TemperatureReading};
+use std::rc::Rc;
+
+/// The bus speed for I2C controllers which don't have a `clock-frequency`
+const DEFAULT_I2C_HZ: u64 = 100_000;
+
+struct Sensor {
+    name: String,
+    driver: Box<dyn TemperatureSensor>,
+}
+
+struct Clocks {
+    name: String,
+    /// How the controller's consumers refer to it
+    phandle: Option<u32>,
+    driver: Box<dyn ClockController>,
+}
+
+struct Thermal {
+    sensors: Vec<Sensor>,
+    clocks: Vec<Clocks>,
+}
+
+impl thermal::Server for Thermal {
+    fn telemetry(&mut self) -> Telemetry {
+        let mut temperatures = Vec::new();
+        for sensor in &mut self.sensors {
+            let channels = sensor.driver.channels();
+            for (channel, millicelsius) in channels.iter().zip(sensor.driver.read()) {
+                temperatures.push(TemperatureReading {
+                    sensor: sensor.name.clone(),
+                    channel: String::from(*channel),
+                    millicelsius,
+                });
+            }
+        }
+
+        let clocks = self
+            .clocks
+            .iter()
+            .flat_map(|clocks| {
+                clocks.driver.rates().into_iter().map(|(clock, hz)| ClockReading {
+                    controller: clocks.name.clone(),
+                    clock: String::from(clock),
+                    hz,
+                })
+            })
+            .collect();
+
+        Telemetry { temperatures, clocks }
+    }
+}
+
+fn main() {
+    librust::syscalls::task::enable_notifications();
+
+    let fdt = unsafe { Fdt::from_ptr(std::env::a2() as *const u8) }.unwrap();
+    let devicemgr = devicemgr::Client::new(std::env::lookup_capability("devicemgr").unwrap().capability.cptr);
+
+    let mut thermal = Thermal { sensors: Vec::new(), clocks: Vec::new() };
+
+    // Clock controllers come first, since they can be where other devices get
+    // their clocks from
+    for (device, base) in request(&devicemgr, sifive_prci::COMPATIBLE) {
+        let node = match node_named(&fdt, &device.name) {
+            Some(node) => node,
+            None => continue,
+        };
+
+        let variant = match device.compatible.iter().any(|c| c == sifive_prci::FU740_COMPATIBLE) {
+            true => Variant::Fu740,
+            false => Variant::Fu540,
+        };
+
+        // `hfclk` is always the first of the PRCI's clocks
+        match clocks(&fdt, node).first().and_then(|&clock| clock_rate(&fdt, &thermal.clocks, clock)) {
+            Some(hfclk_hz) => thermal.clocks.push(Clocks {
+                name: device.name,
+                phandle: phandle(node),
+                driver: Box::new(unsafe { SifivePrci::new(base, variant, hfclk_hz) }),
+            }),
+            None => log::warn!("Couldn't find the rate of hfclk for {}", device.name),
+        }
+    }
+
+    let syscons = request(&devicemgr, jh7110_clocks::SYSCON_COMPATIBLE);
+    for ((device, crg), (_, syscon)) in request(&devicemgr, jh7110_clocks::CRG_COMPATIBLE).into_iter().zip(syscons) {
+        let node = match node_named(&fdt, &device.name) {
+            Some(node) => node,
+            None => continue,
+        };
+
+        match named_clock(&fdt, node, "osc").and_then(|clock| clock_rate(&fdt, &thermal.clocks, clock)) {
+            Some(osc_hz) => thermal.clocks.push(Clocks {
+                name: device.name,
+                phandle: phandle(node),
+                driver: Box::new(unsafe { Jh7110Clocks::new(crg, syscon, osc_hz) }),
+            }),
+            None => log::warn!("Couldn't find the rate of osc for {}", device.name),
+        }
+    }
+
+    for (device, base) in request(&devicemgr, ocores_i2c::COMPATIBLE) {
+        let node = match node_named(&fdt, &device.name) {
+            Some(node) => node,
+            None => continue,
+        };
+
+        let input_hz = match clocks(&fdt, node).first().and_then(|&clock| clock_rate(&fdt, &thermal.clocks, clock)) {
+            Some(input_hz) => input_hz,
+            None => {
+                log::warn!("Couldn't find the input clock rate for {}", device.name);
+                continue;
+            }
+        };
+
+        let bus_hz = node.property("clock-frequency").and_then(|p| p.as_usize()).map_or(DEFAULT_I2C_HZ, |hz| hz as u64);
+        let reg_shift = property_u32(node, "reg-shift").unwrap_or(0) as usize;
+        let bus = Rc::new(RefCell::new(unsafe { OcoresI2c::new(base, reg_shift, input_hz, bus_hz) }));
+
+        for child in node.children() {
+            let compatible = child.compatible().map_or(false, |c| c.all().any(|c| tmp451::COMPATIBLE.contains(&c)));
+            if let (true, Some(address)) = (compatible, property_u32(child, "reg")) {
+                thermal.sensors.push(Sensor {
+                    name: String::from(child.name),
+                    driver: Box::new(Tmp451::new(Rc::clone(&bus), address as u8)),
+                });
+            }
+        }
+    }
+
+    for (device, base) in request(&devicemgr, jh7110_temp::COMPATIBLE) {
+        thermal.sensors.push(Sensor { name: device.name, driver: Box::new(unsafe { Jh7110Temp::new(base) }) });
+    }
+
+    for sensor in &thermal.sensors {
+        log::info!("Found temperature sensor {}", sensor.name);
+    }
+
+    for clocks in &thermal.clocks {
+        log::info!("Found clock controller {}", clocks.name);
+    }
+
+    thermal::serve(&mut thermal)
+}
+
+/// Claim every device compatible with one of `compatible`, along with where its
+/// MMIO is mapped
+fn request(devicemgr: &devicemgr::Client, compatible: &[&str]) -> Vec<(Device, *mut u8)> {
+    let (devices, caps) =
+        devicemgr.request_devices(compatible.iter().map(|&c| String::from(c)).collect(), &[]).unwrap();
+
+    devices
+        .into_iter()
+        .zip(caps)
+        .map(|(device, cap)| {
+            let (info, _) = librust::syscalls::io::query_mmio_cap(cap.capability.cptr, &mut []).unwrap();
+            (device, info.address() as *mut u8)
+        })
+        .collect()
+}
+
+fn node_named<'b>(fdt: &'b Fdt<'static>, name: &str) -> Option<FdtNode<'b, 'static>> {
+    fdt.all_nodes().find(|node| node.name == name)
+}
+
+fn property_u32(node: FdtNode<'_, '_>, name: &str) -> Option<u32> {
+    let value = node.property(name)?.value;
+    Some(u32::from_be_bytes(value.get(..4)?.try_into().unwrap()))
+}
+
+fn phandle(node: FdtNode<'_, '_>) -> Option<u32> {
+    property_u32(node, "phandle")
+}
+
+/// The phandle of the provider of each of `node`'s clocks, along with the index
+/// of the clock for providers which have more than one
+fn clocks(fdt: &Fdt<'static>, node: FdtNode<'_, '_>) -> Vec<(u32, Option<u32>)> {
+    let cells = match node.property("clocks") {
+        Some(clocks) => {
+            clocks.value.chunks_exact(4).map(|cell| u32::from_be_bytes(cell.try_into().unwrap())).collect::<Vec<_>>()
+        }
+        None => return Vec::new(),
+    };
+
+    let mut clocks = Vec::new();
+    let mut i = 0;
+    while i < cells.len() {
+        let phandle = cells[i];
+        let n_cells =
+            fdt.find_phandle(phandle).and_then(|provider| property_u32(provider, "#clock-cells")).unwrap_or(0);
+
+        clocks.push((phandle, cells.get(i + 1).copied().filter(|_| n_cells > 0)));
+        i += 1 + n_cells as usize;
+    }
+
+    clocks
+}
+
+/// The clock `node` calls `name` in its `clock-names`
+fn named_clock(fdt: &Fdt<'static>, node: FdtNode<'_, '_>, name: &str) -> Option<(u32, Option<u32>)> {
+    let names = node.property("clock-names")?.value;
+    let index = names.split(|&b| b == 0).position(|n| n == name.as_bytes())?;
+
+    clocks(fdt, node).get(index).copied()
+}
+
+/// The rate of a clock, which is either a fixed clock or comes from one of the
+/// controllers which have been found already
+fn clock_rate(fdt: &Fdt<'static>, controllers: &[Clocks], (phandle, index): (u32, Option<u32>)) -> Option<u64> {
+    let provider = fdt.find_phandle(phandle)?;
+    if let Some(hz) = provider.property("clock-frequency").and_then(|p| p.as_usize()) {
+        return Some(hz as u64);
+    }
+
+    let controller = controllers.iter().find(|controller| controller.phandle == Some(phandle))?;
+    controller.driver.rate(index?)
+}
@@ -5,14 +5,19 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
-#![feature(drain_filter)]
-
+use interfaces::{devicemgr, HotplugEvent};
 use librust::{
-    capabilities::{Capability, CapabilityRights, CapabilityWithDescription},
+    capabilities::{Capability, CapabilityPtr, CapabilityRights, CapabilityWithDescription},
     syscalls::channel::KernelMessage,
 };
 use std::ipc::{ChannelMessage, ChannelReadFlags, IpcChannel};
-use virtio::DeviceType;
+use virtio::{DeviceType, VirtIoHeader};
+
+/// How often the slots are checked for devices being added or removed. The
+/// virtio MMIO transport has no way of announcing either, but a slot's device
+/// ID changes between zero and the device's type when it happens.
+const PRESENCE_INTERVAL: core::time::Duration = core::time::Duration::from_secs(1);
+const PRESENCE_TIMER: usize = 0;
 
 json::derive! {
     #[derive(Debug, Clone)]
@@ -25,62 +30,90 @@ json::derive! {
 
 json::derive! {
     Deserialize,
-    #[derive(Debug)]
-    struct Devices {
-        devices: Vec<Device>,
+    struct VirtIoDeviceRequest {
+        ty: u32,
     }
 }
 
 json::derive! {
     Serialize,
-    struct WantedCompatible {
-        compatible: Vec<String>,
+    struct VirtIoDeviceResponse {
+        devices: Vec<Device>,
     }
 }
 
-json::derive! {
-    Deserialize,
-    struct VirtIoDeviceRequest {
-        ty: u32,
+/// A virtio MMIO slot, which may or may not have a device in it
+struct Slot {
+    mmio_cap: CapabilityPtr,
+    header: &'static VirtIoHeader,
+    dev_type: DeviceType,
+    device: Device,
+    /// Whether the device in the slot has been handed to a driver already
+    claimed: bool,
+}
+
+impl Slot {
+    fn hotplug_device(&self) -> interfaces::Device {
+        let Device { name, compatible, interrupts } = self.device.clone();
+        interfaces::Device { name, compatible, interrupts }
     }
 }
 
-json::derive! {
-    Serialize,
-    struct VirtIoDeviceResponse {
-        devices: Vec<Device>,
+/// Report any slots whose device has come or gone since they were last checked
+fn check_presence(slots: &mut [Slot], devicemgr: &devicemgr::Client) {
+    for slot in slots {
+        let dev_type = slot.header.device_type().unwrap_or(DeviceType::Reserved);
+        if dev_type as u32 == slot.dev_type as u32 {
+            continue;
+        }
+
+        if !matches!(slot.dev_type, DeviceType::Reserved) {
+            let _ = devicemgr.report_hotplug(HotplugEvent::Removed(slot.hotplug_device()));
+            slot.claimed = false;
+        }
+
+        slot.dev_type = dev_type;
+
+        if !matches!(dev_type, DeviceType::Reserved) {
+            let _ = devicemgr.report_hotplug(HotplugEvent::Added(slot.hotplug_device()));
+        }
     }
 }
 
 fn main() {
     let devicemgr_cptr = std::env::lookup_capability("devicemgr").unwrap().capability.cptr;
-    let devicemgr = IpcChannel::new(devicemgr_cptr);
-    devicemgr
-        .temp_send_json(ChannelMessage::default(), &WantedCompatible { compatible: vec!["virtio,mmio".into()] }, &[])
-        .unwrap();
-
-    let (devices, _, capabilities): (Devices, _, _) = devicemgr.temp_read_json(ChannelReadFlags::NONE).unwrap();
+    let devicemgr = devicemgr::Client::new(devicemgr_cptr);
+    let (devices, capabilities) = devicemgr.request_devices(vec!["virtio,mmio".into()], &[]).unwrap();
     let _ = librust::syscalls::channel::read_kernel_message();
-    let mut virtio_devices = Vec::new();
+    let mut slots = Vec::new();
 
     for (device, CapabilityWithDescription { capability: Capability { cptr: mmio_cap, .. }, .. }) in
-        devices.devices.into_iter().zip(capabilities.into_iter())
+        devices.into_iter().zip(capabilities.into_iter())
     {
+        let interfaces::Device { name, compatible, interrupts } = device;
+        let device = Device { name, compatible, interrupts };
         let (info, _) = librust::syscalls::io::query_mmio_cap(mmio_cap, &mut []).unwrap();
 
-        let header = unsafe { &*(info.address() as *const virtio::VirtIoHeader) };
+        let header = unsafe { &*(info.address() as *const VirtIoHeader) };
         let dev_type = header.device_type().unwrap();
 
         // if !matches!(dev_type, DeviceType::Reserved) {
         //     println!("[virtiomgr] We have a VirtIO {:?} device: {:?}", dev_type, device);
         // }
 
-        virtio_devices.push((mmio_cap, info, dev_type, header, device));
+        slots.push(Slot { mmio_cap, header, dev_type, device, claimed: false });
     }
 
+    librust::syscalls::task::set_timer(PRESENCE_TIMER, PRESENCE_INTERVAL).unwrap();
+
     loop {
         let cptr = match librust::syscalls::channel::read_kernel_message() {
-            KernelMessage::NewChannelMessage(cptr) => cptr,
+            KernelMessage::NewChannelMessage(cptr) if cptr != devicemgr_cptr => cptr,
+            KernelMessage::TimerExpired(PRESENCE_TIMER) => {
+                check_presence(&mut slots, &devicemgr);
+                librust::syscalls::task::set_timer(PRESENCE_TIMER, PRESENCE_INTERVAL).unwrap();
+                continue;
+            }
             _ => continue,
         };
 
@@ -94,20 +127,17 @@ fn main() {
 
         // println!("[virtiomgr] Got request for device type: {:?}", DeviceType::from_u32(dev_type));
 
-        let devices: Vec<_> = virtio_devices.drain_filter(|device| device.2 as u32 == dev_type).collect();
-        let caps: Vec<_> = devices
-            .iter()
-            .map(|(cap, _, _, _, _)| {
-                Capability::new(*cap, CapabilityRights::READ | CapabilityRights::WRITE | CapabilityRights::GRANT)
-            })
-            .collect();
-
-        channel
-            .temp_send_json(
-                ChannelMessage::default(),
-                &VirtIoDeviceResponse { devices: devices.iter().map(|dev| dev.4.clone()).collect() },
-                &caps,
-            )
-            .unwrap();
+        let mut devices = Vec::new();
+        let mut caps = Vec::new();
+        for slot in slots.iter_mut().filter(|slot| !slot.claimed && slot.dev_type as u32 == dev_type) {
+            slot.claimed = true;
+            devices.push(slot.device.clone());
+            caps.push(Capability::new(
+                slot.mmio_cap,
+                CapabilityRights::READ | CapabilityRights::WRITE | CapabilityRights::GRANT,
+            ));
+        }
+
+        channel.temp_send_json(ChannelMessage::default(), &VirtIoDeviceResponse { devices }, &caps).unwrap();
     }
 }
@@ -0,0 +1,228 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use librust::mem::DmaRegion;
+use std::collections::BTreeMap;
+use virtio::{
+    devices::vsock::{EventId, VirtIoVsockDevice, VsockEvent, VsockHeader, EVENT_QUEUE, RECEIVE_QUEUE, TRANSMIT_QUEUE},
+    splitqueue::{DescriptorFlags, SplitVirtqueue, SplitqueueIndex, VirtqueueDescriptor},
+    MmioTransport, Transport, VirtIoDeviceError,
+};
+
+const QUEUE_SIZE: usize = 32;
+const BUFFER_SIZE: usize = 4096;
+/// The most data that fits in a single packet
+pub const MAX_PAYLOAD: usize = BUFFER_SIZE - VsockHeader::SIZE;
+
+#[derive(Debug)]
+pub enum Event {
+    Packet(VsockHeader, Vec<u8>),
+    /// Every connection was reset by the device
+    TransportReset,
+}
+
+pub struct VirtIoVsock {
+    device: &'static VirtIoVsockDevice,
+    transport: MmioTransport,
+    receive_queue: ReceiveQueue<BUFFER_SIZE>,
+    transmit_queue: TransmitQueue,
+    event_queue: ReceiveQueue<{ VsockEvent::SIZE }>,
+}
+
+impl VirtIoVsock {
+    pub fn new(device: &'static VirtIoVsockDevice) -> Result<Self, VirtIoDeviceError> {
+        let transport = MmioTransport::new(&device.header)?;
+        transport.begin_init();
+
+        // Stream sockets are always supported, the `STREAM` feature only exists
+        // so devices which only support seqpacket sockets can say so
+        transport.negotiate_features(0, 0)?;
+
+        let receive_queue = ReceiveQueue::new(&transport, RECEIVE_QUEUE)?;
+        let transmit_queue = TransmitQueue::new(&transport, TRANSMIT_QUEUE)?;
+        let event_queue = ReceiveQueue::new(&transport, EVENT_QUEUE)?;
+        transport.finish_init()?;
+
+        transport.notify(RECEIVE_QUEUE);
+        transport.notify(EVENT_QUEUE);
+
+        Ok(Self { device, transport, receive_queue, transmit_queue, event_queue })
+    }
+
+    pub fn guest_cid(&self) -> u64 {
+        self.device.guest_cid()
+    }
+
+    /// Send a packet, `data` must be no longer than [`MAX_PAYLOAD`] and
+    /// `header.len` must match its length
+    pub fn send(&mut self, header: VsockHeader, data: &[u8]) {
+        assert!(data.len() <= MAX_PAYLOAD && header.len as usize == data.len());
+
+        let mut packet = Vec::with_capacity(VsockHeader::SIZE + data.len());
+        packet.extend_from_slice(&header.to_bytes());
+        packet.extend_from_slice(data);
+        self.transmit_queue.send(&self.transport, packet);
+    }
+
+    pub fn process_interrupt(&mut self) -> Vec<Event> {
+        self.transport.acknowledge_interrupt();
+        self.transmit_queue.flush(&self.transport);
+
+        let mut events = Vec::new();
+        for event in self.event_queue.receive(&self.transport) {
+            if let Some(VsockEvent { id: EventId::TRANSPORT_RESET }) = VsockEvent::from_bytes(&event) {
+                events.push(Event::TransportReset);
+            }
+        }
+
+        for packet in self.receive_queue.receive(&self.transport) {
+            let header = match VsockHeader::from_bytes(&packet) {
+                Some(header) => header,
+                None => continue,
+            };
+
+            let data = match packet.get(VsockHeader::SIZE..VsockHeader::SIZE + header.len as usize) {
+                Some(data) => data.to_vec(),
+                None => continue,
+            };
+
+            events.push(Event::Packet(header, data));
+        }
+
+        events
+    }
+}
+
+/// A queue the device writes into, which has all of its buffers made
+/// available to the device up front
+struct ReceiveQueue<const N: usize> {
+    queue_number: u32,
+    queue: SplitVirtqueue,
+    buffers: DmaRegion<[[u8; N]]>,
+    buffer_map: BTreeMap<SplitqueueIndex<VirtqueueDescriptor>, usize>,
+}
+
+impl<const N: usize> ReceiveQueue<N> {
+    fn new(transport: &MmioTransport, queue_number: u32) -> Result<Self, VirtIoDeviceError> {
+        let mut queue = SplitVirtqueue::new(QUEUE_SIZE).unwrap();
+        let mut buffers: DmaRegion<[[u8; N]]> = unsafe { DmaRegion::zeroed_many(QUEUE_SIZE).unwrap().assume_init() };
+        let mut buffer_map = BTreeMap::new();
+
+        for index in 0..QUEUE_SIZE {
+            let descriptor = queue.alloc_descriptor().unwrap();
+            queue.descriptors.write(
+                descriptor,
+                VirtqueueDescriptor {
+                    address: buffers.get(index).unwrap().physical_address(),
+                    length: N as u32,
+                    flags: DescriptorFlags::WRITE,
+                    next: SplitqueueIndex::new(0),
+                },
+            );
+            queue.available.push(descriptor);
+            buffer_map.insert(descriptor, index);
+        }
+
+        transport.configure_queue(queue_number, &queue)?;
+
+        Ok(Self { queue_number, queue, buffers, buffer_map })
+    }
+
+    /// Copy out everything the device has written, handing the buffers back to
+    /// the device afterwards
+    fn receive(&mut self, transport: &MmioTransport) -> Vec<Vec<u8>> {
+        let mut received = Vec::new();
+
+        while let Some(used) = self.queue.used.pop() {
+            let descriptor = SplitqueueIndex::new(used.start_index as u16);
+            let buffer = self.buffers.get(self.buffer_map[&descriptor]).unwrap();
+
+            received.push(buffer.get()[..(used.length as usize).min(N)].to_vec());
+            self.queue.available.push(descriptor);
+        }
+
+        if !received.is_empty() {
+            transport.notify(self.queue_number);
+        }
+
+        received
+    }
+}
+
+struct TransmitQueue {
+    queue_number: u32,
+    queue: SplitVirtqueue,
+    buffers: DmaRegion<[[u8; BUFFER_SIZE]]>,
+    buffer_map: BTreeMap<SplitqueueIndex<VirtqueueDescriptor>, usize>,
+    free_buffers: VecDeque<usize>,
+    /// Packets waiting for the device to free up a buffer
+    pending: VecDeque<Vec<u8>>,
+}
+
+impl TransmitQueue {
+    fn new(transport: &MmioTransport, queue_number: u32) -> Result<Self, VirtIoDeviceError> {
+        let queue = SplitVirtqueue::new(QUEUE_SIZE).unwrap();
+        transport.configure_queue(queue_number, &queue)?;
+
+        Ok(Self {
+            queue_number,
+            queue,
+            buffers: unsafe { DmaRegion::zeroed_many(QUEUE_SIZE).unwrap().assume_init() },
+            buffer_map: BTreeMap::new(),
+            free_buffers: (0..QUEUE_SIZE).collect(),
+            pending: VecDeque::new(),
+        })
+    }
+
+    fn send(&mut self, transport: &MmioTransport, packet: Vec<u8>) {
+        self.pending.push_back(packet);
+        self.flush(transport);
+    }
+
+    /// Reclaim the buffers the device is done with, then send as many pending
+    /// packets as there are free buffers for
+    fn flush(&mut self, transport: &MmioTransport) {
+        while let Some(used) = self.queue.used.pop() {
+            let descriptor = SplitqueueIndex::new(used.start_index as u16);
+            let index = self.buffer_map.remove(&descriptor).unwrap();
+
+            self.queue.free_descriptor(descriptor);
+            self.free_buffers.push_back(index);
+        }
+
+        let mut sent_any = false;
+        while !self.pending.is_empty() {
+            let index = match self.free_buffers.pop_front() {
+                Some(index) => index,
+                None => break,
+            };
+            // There's a descriptor for every buffer, so this can't fail
+            let descriptor = self.queue.alloc_descriptor().unwrap();
+
+            let packet = self.pending.pop_front().unwrap();
+            let mut buffer = self.buffers.get(index).unwrap();
+            buffer.get_mut()[..packet.len()].copy_from_slice(&packet);
+
+            self.queue.descriptors.write(
+                descriptor,
+                VirtqueueDescriptor {
+                    address: buffer.physical_address(),
+                    length: packet.len() as u32,
+                    flags: DescriptorFlags::NONE,
+                    next: SplitqueueIndex::new(0),
+                },
+            );
+            self.queue.available.push(descriptor);
+            self.buffer_map.insert(descriptor, index);
+            sent_any = true;
+        }
+
+        if sent_any {
+            transport.notify(self.queue_number);
+        }
+    }
+}
@@ -0,0 +1,384 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+mod driver;
+
+use driver::{Event, VirtIoVsock, MAX_PAYLOAD};
+use interfaces::vsock::{Request, Response, VsockAddress};
+use librust::{
+    capabilities::{Capability, CapabilityPtr, CapabilityWithDescription},
+    syscalls::channel::{ChannelMessage, KernelMessage},
+};
+use std::{
+    collections::BTreeMap,
+    ipc::{ChannelReadFlags, IpcChannel},
+};
+use virtio::devices::vsock::{ShutdownFlags, VsockHeader, VsockOp, VsockType};
+
+/// The size of the receive buffer advertised to peers. Received data is handed
+/// to clients as soon as it arrives, so this only limits how much data can be
+/// in flight at once.
+const BUFFER_ALLOC: u32 = 64 * 1024;
+/// Local ports used for outgoing connections
+const EPHEMERAL_PORTS: core::ops::Range<u32> = 49152..65536;
+
+json::derive! {
+    #[derive(Debug, Clone)]
+    struct Device {
+        name: String,
+        compatible: Vec<String>,
+        interrupts: Vec<usize>,
+    }
+}
+
+json::derive! {
+    Serialize,
+    struct VirtIoDeviceRequest {
+        ty: u32,
+    }
+}
+
+json::derive! {
+    Deserialize,
+    #[derive(Debug)]
+    struct VirtIoDeviceResponse {
+        devices: Vec<Device>,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct ConnectionKey {
+    local_port: u32,
+    peer_cid: u64,
+    peer_port: u32,
+}
+
+impl ConnectionKey {
+    fn peer(self) -> VsockAddress {
+        VsockAddress { cid: self.peer_cid, port: self.peer_port }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnectionState {
+    Connecting,
+    Connected,
+}
+
+#[derive(Debug)]
+struct Connection {
+    channel: CapabilityPtr,
+    state: ConnectionState,
+    /// Data from the client waiting for the peer to have room for it
+    pending: VecDeque<u8>,
+    /// Total bytes sent to the peer
+    tx_cnt: u32,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+    /// Total bytes received from the peer and handed to the client
+    fwd_cnt: u32,
+    /// The `fwd_cnt` last sent to the peer
+    reported_fwd_cnt: u32,
+}
+
+impl Connection {
+    fn new(channel: CapabilityPtr, state: ConnectionState) -> Self {
+        Self {
+            channel,
+            state,
+            pending: VecDeque::new(),
+            tx_cnt: 0,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+            fwd_cnt: 0,
+            reported_fwd_cnt: 0,
+        }
+    }
+
+    /// How many bytes the peer has room for
+    fn peer_credit(&self) -> u32 {
+        self.peer_buf_alloc.saturating_sub(self.tx_cnt.wrapping_sub(self.peer_fwd_cnt))
+    }
+}
+
+struct Server {
+    device: VirtIoVsock,
+    connections: BTreeMap<ConnectionKey, Connection>,
+    channels: BTreeMap<CapabilityPtr, ConnectionKey>,
+    /// Channels waiting for a peer to connect to a port
+    listeners: BTreeMap<u32, CapabilityPtr>,
+    next_ephemeral_port: u32,
+}
+
+impl Server {
+    fn handle_request(&mut self, channel: CapabilityPtr, request: Request) {
+        let in_use = self.channels.contains_key(&channel) || self.listeners.values().any(|c| *c == channel);
+
+        match request {
+            Request::Connect(_) | Request::Accept { .. } if in_use => reply(channel, Response::InvalidState),
+            Request::Connect(peer) => {
+                let local_port = match self.alloc_ephemeral_port(peer) {
+                    Some(port) => port,
+                    None => return reply(channel, Response::PortInUse),
+                };
+
+                let key = ConnectionKey { local_port, peer_cid: peer.cid, peer_port: peer.port };
+                self.connections.insert(key, Connection::new(channel, ConnectionState::Connecting));
+                self.channels.insert(channel, key);
+                self.send(key, VsockOp::REQUEST, 0, &[]);
+            }
+            Request::Accept { port } => match self.listeners.contains_key(&port) {
+                true => reply(channel, Response::PortInUse),
+                false => {
+                    self.listeners.insert(port, channel);
+                }
+            },
+            Request::Send(data) => match self.channels.get(&channel).copied() {
+                Some(key) if self.connections[&key].state == ConnectionState::Connected => {
+                    self.connections.get_mut(&key).unwrap().pending.extend(data);
+                    self.flush(key);
+                }
+                _ => reply(channel, Response::InvalidState),
+            },
+            Request::Shutdown => {
+                if let Some(key) = self.channels.get(&channel).copied() {
+                    // Anything the peer hasn't had room for yet is lost
+                    self.send(key, VsockOp::SHUTDOWN, (ShutdownFlags::RECEIVE | ShutdownFlags::SEND).bits(), &[]);
+                    self.remove(key);
+                }
+
+                self.listeners.retain(|_, c| *c != channel);
+            }
+        }
+    }
+
+    fn handle_packet(&mut self, header: VsockHeader, data: Vec<u8>) {
+        let key = ConnectionKey { local_port: header.dst_port, peer_cid: header.src_cid, peer_port: header.src_port };
+
+        if header.ty != VsockType::STREAM || header.dst_cid != self.device.guest_cid() {
+            return self.reset(key, header.op);
+        }
+
+        let connection = match self.connections.get_mut(&key) {
+            Some(connection) => connection,
+            None if header.op == VsockOp::REQUEST => return self.accept(key, header),
+            None => return self.reset(key, header.op),
+        };
+
+        // Every packet carries the peer's latest credit information
+        connection.peer_buf_alloc = header.buf_alloc;
+        connection.peer_fwd_cnt = header.fwd_cnt;
+
+        match (header.op, connection.state) {
+            (VsockOp::RESPONSE, ConnectionState::Connecting) => {
+                connection.state = ConnectionState::Connected;
+                let local = VsockAddress { cid: self.device.guest_cid(), port: key.local_port };
+                reply(connection.channel, Response::Connected { local, peer: key.peer() });
+                self.flush(key);
+            }
+            (VsockOp::RW, ConnectionState::Connected) => {
+                connection.fwd_cnt = connection.fwd_cnt.wrapping_add(data.len() as u32);
+                reply(connection.channel, Response::Data(data));
+
+                // Let the peer know it has room again if it hasn't heard from
+                // us in a while
+                if connection.fwd_cnt.wrapping_sub(connection.reported_fwd_cnt) >= BUFFER_ALLOC / 2 {
+                    self.send(key, VsockOp::CREDIT_UPDATE, 0, &[]);
+                }
+            }
+            (VsockOp::CREDIT_UPDATE, _) => self.flush(key),
+            (VsockOp::CREDIT_REQUEST, _) => self.send(key, VsockOp::CREDIT_UPDATE, 0, &[]),
+            // Half-closed connections aren't supported, so any shutdown
+            // closes the connection entirely
+            (VsockOp::SHUTDOWN, _) => {
+                reply(connection.channel, Response::Closed);
+                self.send(key, VsockOp::RST, 0, &[]);
+                self.remove(key);
+            }
+            (VsockOp::RST, state) => {
+                reply(
+                    connection.channel,
+                    match state {
+                        ConnectionState::Connecting => Response::Refused,
+                        ConnectionState::Connected => Response::Closed,
+                    },
+                );
+                self.remove(key);
+            }
+            (op, _) => {
+                reply(connection.channel, Response::Closed);
+                self.remove(key);
+                self.reset(key, op);
+            }
+        }
+    }
+
+    /// Every connection was dropped by the device, though listeners are left
+    /// alone since they're still waiting for a new connection
+    fn handle_transport_reset(&mut self) {
+        for (_, connection) in core::mem::take(&mut self.connections) {
+            reply(connection.channel, Response::Closed);
+        }
+
+        self.channels.clear();
+    }
+
+    fn accept(&mut self, key: ConnectionKey, header: VsockHeader) {
+        let channel = match self.listeners.remove(&key.local_port) {
+            Some(channel) => channel,
+            None => return self.reset(key, header.op),
+        };
+
+        let mut connection = Connection::new(channel, ConnectionState::Connected);
+        connection.peer_buf_alloc = header.buf_alloc;
+        connection.peer_fwd_cnt = header.fwd_cnt;
+        self.connections.insert(key, connection);
+        self.channels.insert(channel, key);
+
+        self.send(key, VsockOp::RESPONSE, 0, &[]);
+        let local = VsockAddress { cid: self.device.guest_cid(), port: key.local_port };
+        reply(channel, Response::Connected { local, peer: key.peer() });
+    }
+
+    /// Send as much pending data as the peer has room for
+    fn flush(&mut self, key: ConnectionKey) {
+        loop {
+            let connection = match self.connections.get_mut(&key) {
+                Some(connection) => connection,
+                None => return,
+            };
+
+            let len = connection.pending.len().min(connection.peer_credit() as usize).min(MAX_PAYLOAD);
+            if len == 0 {
+                return;
+            }
+
+            let data: Vec<u8> = connection.pending.drain(..len).collect();
+            connection.tx_cnt = connection.tx_cnt.wrapping_add(len as u32);
+            self.send(key, VsockOp::RW, 0, &data);
+        }
+    }
+
+    fn send(&mut self, key: ConnectionKey, op: VsockOp, flags: u32, data: &[u8]) {
+        let fwd_cnt = match self.connections.get_mut(&key) {
+            Some(connection) => {
+                connection.reported_fwd_cnt = connection.fwd_cnt;
+                connection.fwd_cnt
+            }
+            None => 0,
+        };
+
+        let header = VsockHeader {
+            src_cid: self.device.guest_cid(),
+            dst_cid: key.peer_cid,
+            src_port: key.local_port,
+            dst_port: key.peer_port,
+            len: data.len() as u32,
+            ty: VsockType::STREAM,
+            op,
+            flags,
+            buf_alloc: BUFFER_ALLOC,
+            fwd_cnt,
+        };
+
+        self.device.send(header, data);
+    }
+
+    /// Reset the connection in response to a packet with `op`, which is never
+    /// done for a reset since that could have the two sides resetting each
+    /// other forever
+    fn reset(&mut self, key: ConnectionKey, op: VsockOp) {
+        if op != VsockOp::RST {
+            self.send(key, VsockOp::RST, 0, &[]);
+        }
+    }
+
+    fn remove(&mut self, key: ConnectionKey) {
+        if let Some(connection) = self.connections.remove(&key) {
+            self.channels.remove(&connection.channel);
+        }
+    }
+
+    fn alloc_ephemeral_port(&mut self, peer: VsockAddress) -> Option<u32> {
+        for _ in EPHEMERAL_PORTS {
+            let port = self.next_ephemeral_port;
+            self.next_ephemeral_port = match port + 1 {
+                next if EPHEMERAL_PORTS.contains(&next) => next,
+                _ => EPHEMERAL_PORTS.start,
+            };
+
+            let key = ConnectionKey { local_port: port, peer_cid: peer.cid, peer_port: peer.port };
+            if !self.connections.contains_key(&key) && !self.listeners.contains_key(&port) {
+                return Some(port);
+            }
+        }
+
+        None
+    }
+}
+
+fn reply(channel: CapabilityPtr, response: Response) {
+    let _ = IpcChannel::new(channel).send_serialized(&response, &[]);
+}
+
+fn main() {
+    let virtiomgr_cptr = std::env::lookup_capability("virtiomgr").unwrap().capability.cptr;
+    let virtiomgr = IpcChannel::new(virtiomgr_cptr);
+
+    virtiomgr
+        .temp_send_json(
+            ChannelMessage::default(),
+            &VirtIoDeviceRequest { ty: virtio::DeviceType::SocketDevice as u32 },
+            &[],
+        )
+        .unwrap();
+
+    let (response, _, capabilities): (VirtIoDeviceResponse, _, _) =
+        virtiomgr.temp_read_json(ChannelReadFlags::NONE).unwrap();
+
+    if response.devices.is_empty() {
+        return;
+    }
+
+    let (CapabilityWithDescription { capability: Capability { cptr: mmio_cap, .. }, .. }, device) =
+        (capabilities[0], &response.devices[0]);
+    let (info, _) = librust::syscalls::io::query_mmio_cap(mmio_cap, &mut []).unwrap();
+
+    let interrupt_id = device.interrupts[0];
+    let device =
+        VirtIoVsock::new(unsafe { &*(info.address() as *const virtio::devices::vsock::VirtIoVsockDevice) }).unwrap();
+
+    let mut server = Server {
+        device,
+        connections: BTreeMap::new(),
+        channels: BTreeMap::new(),
+        listeners: BTreeMap::new(),
+        next_ephemeral_port: EPHEMERAL_PORTS.start,
+    };
+
+    librust::syscalls::task::enable_notifications();
+    loop {
+        match librust::syscalls::channel::read_kernel_message() {
+            KernelMessage::InterruptOccurred(id) if id == interrupt_id => {
+                for event in server.device.process_interrupt() {
+                    match event {
+                        Event::Packet(header, data) => server.handle_packet(header, data),
+                        Event::TransportReset => server.handle_transport_reset(),
+                    }
+                }
+
+                librust::syscalls::io::complete_interrupt(id).unwrap();
+            }
+            KernelMessage::NewChannelMessage(cptr) if cptr != virtiomgr_cptr => {
+                if let Ok((request, _)) = IpcChannel::new(cptr).read_serialized(ChannelReadFlags::NONBLOCKING) {
+                    server.handle_request(cptr, request);
+                }
+            }
+            _ => {}
+        }
+    }
+}
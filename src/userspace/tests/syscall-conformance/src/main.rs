@@ -0,0 +1,631 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks that syscalls fail the way they're documented to when they're handed
+//! bad capabilities, bad pointers, bad flags and sizes just past what's
+//! allowed, so changes to the kernel can't quietly change what the ABI means.
+//! Every check either fails before the syscall has any effect or only touches
+//! objects the suite made for itself.
+//!
+//! `xtask test` has init start this once everything else is up, and it ends
+//! the run through QEMU's test finisher with whether every check passed.
+
+use core::{sync::atomic::AtomicU32, time::Duration};
+use interfaces::devicemgr;
+use librust::{
+    capabilities::{Capability, CapabilityPtr, CapabilityRights},
+    error::{
+        RawSyscallError,
+        SyscallError::{self, InsufficientRights, InvalidArgument, QuotaExceeded, UnknownSyscall, WouldBlock},
+    },
+    syscalls::{
+        channel::MAX_MESSAGE_CAPS,
+        job,
+        mem::{self, AllocationOptions, MemoryPermissions, SealFlags},
+        stats::{KernelStats, ALL_HARTS},
+        task::MAX_PENDING_TIMERS,
+        topic, vmspace, Syscall,
+    },
+    units::Bytes,
+};
+
+/// Nothing is ever minted at this cptr
+const BAD_CPTR: usize = 0xDEAD_BEEF;
+/// The start of the kernel's half of the address space
+const KERNEL_PTR: usize = 0xFFFF_FFC0_0000_0000;
+const PAGE_SIZE: usize = 4096;
+
+const FINISHER_COMPATIBLE: &str = "sifive,test0";
+const FINISHER_PASS: u32 = 0x5555;
+const FINISHER_FAIL: u32 = 0x3333;
+
+struct Suite {
+    passed: usize,
+    failed: usize,
+}
+
+impl Suite {
+    /// Make `syscall` with `args` in `a1` through `a6` and check it returns
+    /// `expected`
+    fn check(&mut self, name: &str, syscall: usize, args: [usize; 6], expected: Result<(), SyscallError>) {
+        let result = raw_syscall(syscall, args).map(|_| ());
+        match result == expected {
+            true => self.passed += 1,
+            false => {
+                self.failed += 1;
+                println!("[syscall-conformance] FAILED {}: expected {:?}, got {:?}", name, expected, result);
+            }
+        }
+    }
+
+    fn expect(&mut self, name: &str, syscall: Syscall, args: [usize; 6], expected: SyscallError) {
+        self.check(name, syscall as usize, args, Err(expected));
+    }
+
+    /// Check something built on top of syscalls rather than a single one
+    fn assert(&mut self, name: &str, passed: bool) {
+        match passed {
+            true => self.passed += 1,
+            false => {
+                self.failed += 1;
+                println!("[syscall-conformance] FAILED {}", name);
+            }
+        }
+    }
+}
+
+/// Make syscall number `syscall` with `args` in `a1` through `a6` and every `t`
+/// register zeroed, returning `a1` when it succeeds
+fn raw_syscall(syscall: usize, args: [usize; 6]) -> Result<usize, SyscallError> {
+    let error: usize;
+    let a1: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") syscall => error,
+            inlateout("a1") args[0] => a1,
+            inlateout("a2") args[1] => _,
+            inlateout("a3") args[2] => _,
+            inlateout("a4") args[3] => _,
+            inlateout("a5") args[4] => _,
+            inlateout("a6") args[5] => _,
+            inlateout("t0") 0usize => _,
+            inlateout("t1") 0usize => _,
+            inlateout("t2") 0usize => _,
+            inlateout("t3") 0usize => _,
+            inlateout("t4") 0usize => _,
+            inlateout("t5") 0usize => _,
+            inlateout("t6") 0usize => _,
+        );
+    }
+
+    match RawSyscallError::optional(error) {
+        Some(error) => Err(error.cook()),
+        None => Ok(a1),
+    }
+}
+
+fn main() {
+    let mut suite = Suite { passed: 0, failed: 0 };
+
+    suite.check("unknown syscall", usize::MAX, [0; 6], Err(UnknownSyscall));
+
+    channels(&mut suite);
+    memory(&mut suite);
+    shared_memory(&mut suite);
+    io(&mut suite);
+    misc(&mut suite);
+    futexes(&mut suite);
+    mutexes(&mut suite);
+    task_groups(&mut suite);
+    topics(&mut suite);
+    vmspaces(&mut suite);
+
+    println!("[syscall-conformance] {} passed, {} failed", suite.passed, suite.failed);
+    finish(suite.failed == 0);
+}
+
+fn channels(suite: &mut Suite) {
+    let stdio = std::env::lookup_capability("stdio").unwrap().capability.cptr.value();
+    let bad_caps = [Capability { cptr: CapabilityPtr::new(BAD_CPTR), rights: CapabilityRights::READ }];
+    let too_many_caps =
+        [Capability { cptr: CapabilityPtr::new(BAD_CPTR), rights: CapabilityRights::READ }; MAX_MESSAGE_CAPS + 1];
+
+    suite.expect("write to bad cptr", Syscall::WriteChannel, [BAD_CPTR, 0, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect(
+        "write with bad caps pointer",
+        Syscall::WriteChannel,
+        [stdio, KERNEL_PTR, 1, 0, 0, 0],
+        InvalidArgument(3),
+    );
+    suite.expect(
+        "write with too many caps",
+        Syscall::WriteChannel,
+        [stdio, too_many_caps.as_ptr() as usize, too_many_caps.len(), 0, 0, 0],
+        InvalidArgument(2),
+    );
+    suite.expect(
+        "write with bad cptr in caps",
+        Syscall::WriteChannel,
+        [stdio, bad_caps.as_ptr() as usize, bad_caps.len(), 0, 0, 0],
+        InvalidArgument(2),
+    );
+    suite.expect("read from bad cptr", Syscall::ReadChannel, [BAD_CPTR, 0, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect(
+        "queue depth of bad cptr",
+        Syscall::SetChannelQueueDepth,
+        [BAD_CPTR, 1, 0, 0, 0, 0],
+        InvalidArgument(0),
+    );
+    suite.expect("zero queue depth", Syscall::SetChannelQueueDepth, [stdio, 0, 0, 0, 0, 0], InvalidArgument(1));
+}
+
+fn memory(suite: &mut Suite) {
+    let none = AllocationOptions::NONE.value();
+    let private = AllocationOptions::PRIVATE.value();
+    let read = MemoryPermissions::READ.value();
+    let mut local = [0u8; 16];
+
+    suite.expect("alloc zero bytes", Syscall::AllocVirtualMemory, [0, none, read, 0, 0, 0], InvalidArgument(0));
+    suite.expect(
+        "alloc zero private bytes",
+        Syscall::AllocVirtualMemory,
+        [0, private, read, 0, 0, 0],
+        InvalidArgument(0),
+    );
+
+    // Shared read-execute memory used to hit an `unreachable!` in the kernel
+    let read_execute = (MemoryPermissions::READ | MemoryPermissions::EXECUTE).value();
+    suite.check(
+        "alloc shared read-execute",
+        Syscall::AllocVirtualMemory as usize,
+        [PAGE_SIZE, none, read_execute, 0, 0, 0],
+        Ok(()),
+    );
+
+    suite.expect("alloc zero DMA bytes", Syscall::AllocDmaMemory, [0, 0, 0, 0, 0, 0], InvalidArgument(0));
+
+    let ptr = local.as_mut_ptr() as usize;
+    suite.expect("sync DMA bad direction", Syscall::SyncDmaMemory, [ptr, 1, 3, 0, 0, 0], InvalidArgument(2));
+    suite.expect("sync DMA overflowing", Syscall::SyncDmaMemory, [usize::MAX, 2, 0, 0, 0, 0], InvalidArgument(1));
+    suite.expect("sync non-DMA memory", Syscall::SyncDmaMemory, [ptr, 1, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect("free non-DMA memory", Syscall::DeallocDmaMemory, [ptr, 0, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect("pin zero DMA bytes", Syscall::PinDmaMemory, [ptr, 0, 0, 0, 0, 0], InvalidArgument(1));
+    suite.expect("pin DMA bad direction", Syscall::PinDmaMemory, [ptr, 1, 3, 0, 0, 0], InvalidArgument(2));
+    suite.expect("pin kernel memory", Syscall::PinDmaMemory, [KERNEL_PTR, 1, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect(
+        "pin DMA bad segment buffer",
+        Syscall::PinDmaMemory,
+        [ptr, 1, 0, KERNEL_PTR, 1, 0],
+        InvalidArgument(3),
+    );
+    // Pins the memory and unpins it again once it's found not to fit
+    suite.expect("pin DMA too few segments", Syscall::PinDmaMemory, [ptr, 1, 0, 0, 0, 0], InvalidArgument(3));
+    suite.expect("unpin missing DMA pin", Syscall::UnpinDmaMemory, [usize::MAX, 0, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect(
+        "resize kernel memory",
+        Syscall::ResizeMemory,
+        [KERNEL_PTR, PAGE_SIZE, 0, 0, 0, 0],
+        InvalidArgument(0),
+    );
+    suite.expect("resize to zero bytes", Syscall::ResizeMemory, [ptr, 0, 0, 0, 0, 0], InvalidArgument(1));
+    suite.expect("resize the stack", Syscall::ResizeMemory, [ptr, PAGE_SIZE, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect(
+        "subscribe bad pressure cptr",
+        Syscall::SubscribeMemoryPressure,
+        [BAD_CPTR, 0, 0, 0, 0, 0],
+        InvalidArgument(0),
+    );
+
+    suite.expect("inflate bad balloon cptr", Syscall::InflateBalloon, [BAD_CPTR, 0, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect("deflate bad balloon cptr", Syscall::DeflateBalloon, [BAD_CPTR, 0, 0, 0, 0, 0], InvalidArgument(0));
+
+    suite.expect("enable swap bad cptr", Syscall::EnableSwap, [BAD_CPTR, 1, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect(
+        "take swap writes bad slots",
+        Syscall::TakeSwapWrites,
+        [KERNEL_PTR, 1, 0, 0, 0, 0],
+        InvalidArgument(0),
+    );
+    suite.expect("take swap reads bad slots", Syscall::TakeSwapReads, [KERNEL_PTR, 1, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect("take swap reads not owner", Syscall::TakeSwapReads, [ptr, 1, 0, 0, 0, 0], InsufficientRights(0));
+    suite.expect(
+        "complete swap read bad slot",
+        Syscall::CompleteSwapRead,
+        [u32::MAX as usize + 1, ptr, 0, 0, 0, 0],
+        InvalidArgument(0),
+    );
+    suite.expect(
+        "complete swap read bad page",
+        Syscall::CompleteSwapRead,
+        [0, KERNEL_PTR, 0, 0, 0, 0],
+        InvalidArgument(1),
+    );
+}
+
+fn shared_memory(suite: &mut Suite) {
+    let read_write = MemoryPermissions::READ | MemoryPermissions::WRITE;
+    let (shared, _) = mem::alloc_virtual_memory(Bytes(PAGE_SIZE), AllocationOptions::NONE, read_write).unwrap();
+    let (sealed, _) = mem::alloc_virtual_memory(Bytes(PAGE_SIZE), AllocationOptions::NONE, read_write).unwrap();
+    let (read_only, _) =
+        mem::alloc_virtual_memory(Bytes(PAGE_SIZE), AllocationOptions::NONE, MemoryPermissions::READ).unwrap();
+    let (no_upgrade, _) = mem::alloc_virtual_memory(Bytes(PAGE_SIZE), AllocationOptions::NONE, read_write).unwrap();
+    mem::seal(sealed, SealFlags::GROW).unwrap();
+    mem::seal(no_upgrade, SealFlags::UPGRADE).unwrap();
+    let (read_only_at, ..) = mem::query_memory_capability(read_only).unwrap();
+    let (no_upgrade_at, ..) = mem::query_memory_capability(no_upgrade).unwrap();
+
+    let (shared, sealed, read_only, no_upgrade) =
+        (shared.value(), sealed.value(), read_only.value(), no_upgrade.value());
+    let (read_only_at, no_upgrade_at) = (read_only_at as usize, no_upgrade_at as usize);
+    let mut interrupts = [0usize; 4];
+
+    suite.expect("query memory in kernel", Syscall::QueryMemory, [KERNEL_PTR, 0, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect(
+        "query memory bad cptr",
+        Syscall::QueryMemoryCapability,
+        [BAD_CPTR, 0, 0, 0, 0, 0],
+        InvalidArgument(0),
+    );
+    suite.expect(
+        "query MMIO bad buffer",
+        Syscall::QueryMmioCapability,
+        [BAD_CPTR, KERNEL_PTR, 1, 0, 0, 0],
+        InvalidArgument(1),
+    );
+    suite.expect(
+        "query MMIO bad cptr",
+        Syscall::QueryMmioCapability,
+        [BAD_CPTR, interrupts.as_mut_ptr() as usize, interrupts.len(), 0, 0, 0],
+        InvalidArgument(0),
+    );
+    suite.expect("size of bad cptr", Syscall::SharedMemorySize, [BAD_CPTR, 0, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect("map bad cptr", Syscall::MapSharedMemory, [BAD_CPTR, 0, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect("grow bad cptr", Syscall::GrowSharedMemory, [BAD_CPTR, PAGE_SIZE, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect(
+        "grow past userspace",
+        Syscall::GrowSharedMemory,
+        [shared, KERNEL_PTR, 0, 0, 0, 0],
+        InvalidArgument(1),
+    );
+    suite.expect("grow sealed", Syscall::GrowSharedMemory, [sealed, 2 * PAGE_SIZE, 0, 0, 0, 0], InsufficientRights(0));
+    suite.expect(
+        "grow read-only",
+        Syscall::GrowSharedMemory,
+        [read_only, 2 * PAGE_SIZE, 0, 0, 0, 0],
+        InsufficientRights(0),
+    );
+    suite.expect("seal unknown flags", Syscall::SealMemory, [shared, 1 << 4, 0, 0, 0, 0], InvalidArgument(1));
+    suite.expect(
+        "seal bad cptr",
+        Syscall::SealMemory,
+        [BAD_CPTR, SealFlags::GROW.value(), 0, 0, 0, 0],
+        InvalidArgument(0),
+    );
+    suite.expect(
+        "seal read-only against writes",
+        Syscall::SealMemory,
+        [read_only, SealFlags::WRITE.value(), 0, 0, 0, 0],
+        InsufficientRights(0),
+    );
+    suite.expect(
+        "modify permissions unknown flags",
+        Syscall::ModifyMemoryPermissions,
+        [read_only, read_only_at, PAGE_SIZE, 1 << 2, 0, 0],
+        InvalidArgument(3),
+    );
+    suite.expect(
+        "modify permissions bad cptr",
+        Syscall::ModifyMemoryPermissions,
+        [BAD_CPTR, read_only_at, PAGE_SIZE, 0, 0, 0],
+        InvalidArgument(0),
+    );
+    suite.expect(
+        "modify permissions unaligned",
+        Syscall::ModifyMemoryPermissions,
+        [read_only, read_only_at + 1, PAGE_SIZE, 0, 0, 0],
+        InvalidArgument(1),
+    );
+    suite.expect(
+        "make read-only writable",
+        Syscall::ModifyMemoryPermissions,
+        [read_only, read_only_at, PAGE_SIZE, MemoryPermissions::WRITE.value(), 0, 0],
+        InsufficientRights(0),
+    );
+    suite.check(
+        "downgrade upgrade-sealed",
+        Syscall::ModifyMemoryPermissions as usize,
+        [no_upgrade, no_upgrade_at, PAGE_SIZE, MemoryPermissions::READ.value(), 0, 0],
+        Ok(()),
+    );
+    suite.expect(
+        "upgrade upgrade-sealed",
+        Syscall::ModifyMemoryPermissions,
+        [no_upgrade, no_upgrade_at, PAGE_SIZE, read_write.value(), 0, 0],
+        InsufficientRights(0),
+    );
+}
+
+fn io(suite: &mut Suite) {
+    let invalid_utf8 = [0xFFu8, 0xFE];
+    let missing = "/this-node-does-not-exist";
+
+    suite.expect("claim bad pointer", Syscall::ClaimDevice, [KERNEL_PTR, 4, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect(
+        "claim invalid UTF-8",
+        Syscall::ClaimDevice,
+        [invalid_utf8.as_ptr() as usize, invalid_utf8.len(), 0, 0, 0, 0],
+        InvalidArgument(0),
+    );
+    suite.expect(
+        "claim missing node",
+        Syscall::ClaimDevice,
+        [missing.as_ptr() as usize, missing.len(), 0, 0, 0, 0],
+        InvalidArgument(0),
+    );
+    suite.expect(
+        "complete unclaimed interrupt",
+        Syscall::CompleteInterrupt,
+        [usize::MAX, 0, 0, 0, 0, 0],
+        InvalidArgument(0),
+    );
+    suite.expect("print bad pointer", Syscall::DebugPrint, [KERNEL_PTR, 4, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect("random bad buffer", Syscall::GetRandom, [KERNEL_PTR, 4, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect("entropy bad buffer", Syscall::AddEntropy, [KERNEL_PTR, 4, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect("console sinks bad cptr", Syscall::SetConsoleSinks, [BAD_CPTR, 0, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect("console ring bad cptr", Syscall::ReadConsoleRing, [BAD_CPTR, 0, 0, 0, 0, 0], InvalidArgument(0));
+}
+
+fn misc(suite: &mut Suite) {
+    let mut stats = KernelStats::new();
+    let stats = &mut stats as *mut KernelStats as usize;
+    let entry = misc as usize;
+
+    suite.expect("fault handler in kernel", Syscall::SetFaultHandler, [KERNEL_PTR, 0, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect(
+        "fault stack in kernel",
+        Syscall::SetFaultHandler,
+        [entry, KERNEL_PTR, 0, 0, 0, 0],
+        InvalidArgument(1),
+    );
+    suite.expect("fault stack misaligned", Syscall::SetFaultHandler, [entry, 0x1008, 0, 0, 0, 0], InvalidArgument(1));
+
+    for (name, syscall) in [
+        ("shutdown", Syscall::SystemShutdown),
+        ("reboot", Syscall::SystemReboot),
+        ("suspend", Syscall::SystemSuspend),
+        ("hart offline", Syscall::HartOffline),
+        ("hart online", Syscall::HartOnline),
+        ("arm watchdog", Syscall::ArmWatchdog),
+    ] {
+        suite.expect(&format!("{} bad power cptr", name), syscall, [BAD_CPTR, 0, 0, 0, 0, 0], InvalidArgument(0));
+    }
+
+    suite.expect("set time bad clock cptr", Syscall::SetTime, [BAD_CPTR, 0, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect("memory map bad debug cptr", Syscall::ReadMemoryMap, [BAD_CPTR, 0, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect("snapshot bad debug cptr", Syscall::SnapshotTask, [BAD_CPTR, 0, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect("restore bad debug cptr", Syscall::RestoreTask, [BAD_CPTR, 0, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect(
+        "read missing checkpoint",
+        Syscall::ReadCheckpointRegisters,
+        [usize::MAX, 0, 0, 0, 0, 0],
+        InvalidArgument(0),
+    );
+    suite.expect(
+        "discard missing checkpoint",
+        Syscall::DiscardCheckpoint,
+        [usize::MAX, 0, 0, 0, 0, 0],
+        InvalidArgument(0),
+    );
+
+    suite.expect("stats bad pointer", Syscall::KernelStats, [ALL_HARTS, KERNEL_PTR, 0, 0, 0, 0], InvalidArgument(1));
+    suite.expect("stats bad hart", Syscall::KernelStats, [4096, stats, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect("memory stats bad pointer", Syscall::MemoryStats, [0, KERNEL_PTR, 0, 0, 0, 0], InvalidArgument(1));
+
+    suite.expect("start profiler bad cptr", Syscall::StartProfiler, [BAD_CPTR, 1, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect("stop profiler bad cptr", Syscall::StopProfiler, [BAD_CPTR, 0, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect("drain bad cptr", Syscall::DrainProfileSamples, [BAD_CPTR, 0, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect("resolve bad cptr", Syscall::ResolveKernelSymbol, [BAD_CPTR, 0, 0, 0, 0, 0], InvalidArgument(0));
+
+    // Timers set as far out as they go never fire, so they stay pending
+    for _ in 0..MAX_PENDING_TIMERS {
+        let _ = raw_syscall(Syscall::SetTimer as usize, [0, usize::MAX, 0, 0, 0, 0]);
+    }
+    suite.expect("too many pending timers", Syscall::SetTimer, [0, usize::MAX, 0, 0, 0, 0], QuotaExceeded);
+}
+
+fn futexes(suite: &mut Suite) {
+    let words = [AtomicU32::new(0), AtomicU32::new(0)];
+    let word = &words[0] as *const AtomicU32 as usize;
+
+    suite.expect("wait on bad address", Syscall::FutexWait, [KERNEL_PTR, 0, usize::MAX, 0, 0, 0], InvalidArgument(0));
+    suite.expect(
+        "wait on misaligned address",
+        Syscall::FutexWait,
+        [word + 1, 0, usize::MAX, 0, 0, 0],
+        InvalidArgument(0),
+    );
+    suite.expect("wait on changed value", Syscall::FutexWait, [word, 1, usize::MAX, 0, 0, 0], WouldBlock);
+    suite.expect("wake bad address", Syscall::FutexWake, [KERNEL_PTR, 1, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect("wake misaligned address", Syscall::FutexWake, [word + 1, 1, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect("requeue from bad address", Syscall::FutexRequeue, [KERNEL_PTR, 0, word, 1, 1, 0], InvalidArgument(0));
+    suite.expect(
+        "requeue to misaligned address",
+        Syscall::FutexRequeue,
+        [word, 0, word + 1, 1, 1, 0],
+        InvalidArgument(2),
+    );
+    suite.expect("requeue to bad address", Syscall::FutexRequeue, [word, 0, KERNEL_PTR, 1, 1, 0], InvalidArgument(2));
+    suite.expect("requeue changed value", Syscall::FutexRequeue, [word, 1, word + 4, 1, 1, 0], WouldBlock);
+}
+
+/// `std`'s mutex waits on a futex once spinning doesn't get it the lock, which
+/// only the kernel can exercise
+fn mutexes(suite: &mut Suite) {
+    let mutex = std::sync::Mutex::new(0);
+    let guard = mutex.lock();
+
+    suite.assert("try_lock_for times out on a held mutex", mutex.try_lock_for(Duration::from_millis(10)).is_none());
+    suite.assert("try_lock fails on a contended mutex", mutex.try_lock().is_none());
+
+    // Timing out leaves the mutex marked contended, so unlocking has to wake
+    // the futex even though nothing is waiting on it anymore
+    drop(guard);
+    suite.assert("try_lock_for takes a released mutex", mutex.try_lock_for(Duration::from_millis(10)).is_some());
+    suite.assert("lock takes a released mutex", *mutex.lock() == 0);
+}
+
+fn task_groups(suite: &mut Suite) {
+    let group = job::create_group().unwrap().value();
+
+    suite.expect("join bad cptr", Syscall::JoinTaskGroup, [BAD_CPTR, 0, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect("signal bad cptr", Syscall::SignalTaskGroup, [BAD_CPTR, 0, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect("signal unknown signal", Syscall::SignalTaskGroup, [group, 3, 0, 0, 0, 0], InvalidArgument(1));
+}
+
+fn topics(suite: &mut Suite) {
+    let topic = topic::create_topic().unwrap().value();
+    let too_many_caps =
+        [Capability { cptr: CapabilityPtr::new(BAD_CPTR), rights: CapabilityRights::READ }; MAX_MESSAGE_CAPS + 1];
+
+    suite.expect("subscribe bad cptr", Syscall::SubscribeTopic, [BAD_CPTR, 0, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect("subscribe unknown policy", Syscall::SubscribeTopic, [topic, 3, 0, 0, 0, 0], InvalidArgument(1));
+    suite.expect("publish bad cptr", Syscall::PublishTopic, [BAD_CPTR, 0, 0, 0, 0, 0], InvalidArgument(0));
+    suite.expect(
+        "publish bad caps pointer",
+        Syscall::PublishTopic,
+        [topic, KERNEL_PTR, 1, 0, 0, 0],
+        InvalidArgument(1),
+    );
+    suite.expect(
+        "publish too many caps",
+        Syscall::PublishTopic,
+        [topic, too_many_caps.as_ptr() as usize, too_many_caps.len(), 0, 0, 0],
+        InvalidArgument(1),
+    );
+}
+
+fn vmspaces(suite: &mut Suite) {
+    let id = vmspace::create_vmspace().unwrap().value();
+    let bad_id = usize::MAX;
+    let read = MemoryPermissions::READ.value();
+    let read_write = (MemoryPermissions::READ | MemoryPermissions::WRITE).value();
+    let (_, ours) =
+        mem::alloc_virtual_memory(Bytes(PAGE_SIZE), AllocationOptions::PRIVATE, MemoryPermissions::READ).unwrap();
+    let ours = ours as *mut u8 as usize;
+
+    let name = "conformance";
+    let (name_ptr, name_len) = (name.as_ptr() as usize, name.len());
+    let invalid_utf8 = [0xFFu8, 0xFE];
+    let message = [0usize; 7];
+    let message = message.as_ptr() as usize;
+
+    suite.expect(
+        "alloc object bad id",
+        Syscall::AllocVmspaceObject,
+        [bad_id, 0, PAGE_SIZE, read, 0, 0],
+        InvalidArgument(0),
+    );
+    suite.expect(
+        "alloc object misaligned",
+        Syscall::AllocVmspaceObject,
+        [id, 0x1008, PAGE_SIZE, read, 0, 0],
+        InvalidArgument(1),
+    );
+    suite.expect(
+        "alloc object in kernel",
+        Syscall::AllocVmspaceObject,
+        [id, KERNEL_PTR, PAGE_SIZE, read, 0, 0],
+        InvalidArgument(1),
+    );
+    suite.expect(
+        "alloc object zero bytes",
+        Syscall::AllocVmspaceObject,
+        [id, 0x1000, 0, read, 0, 0],
+        InvalidArgument(2),
+    );
+
+    suite.expect(
+        "share bad id",
+        Syscall::ShareVmspaceObject,
+        [bad_id, ours, PAGE_SIZE, 0, read, 0],
+        InvalidArgument(0),
+    );
+    suite.expect(
+        "share misaligned",
+        Syscall::ShareVmspaceObject,
+        [id, ours + 8, PAGE_SIZE, 0, read, 0],
+        InvalidArgument(1),
+    );
+    suite.expect("share zero bytes", Syscall::ShareVmspaceObject, [id, ours, 0, 0, read, 0], InvalidArgument(2));
+    suite.expect(
+        "share to kernel",
+        Syscall::ShareVmspaceObject,
+        [id, ours, PAGE_SIZE, KERNEL_PTR, read, 0],
+        InvalidArgument(3),
+    );
+    suite.expect(
+        "share writable",
+        Syscall::ShareVmspaceObject,
+        [id, ours, PAGE_SIZE, 0, read_write, 0],
+        InvalidArgument(4),
+    );
+
+    for (verb, syscall) in [("spawn", Syscall::SpawnVmspace), ("exec", Syscall::ExecVmspace)] {
+        suite.expect(
+            &format!("{} bad id", verb),
+            syscall,
+            [bad_id, name_ptr, name_len, 0, 0, message],
+            InvalidArgument(0),
+        );
+        suite.expect(
+            &format!("{} bad name", verb),
+            syscall,
+            [id, KERNEL_PTR, name_len, 0, 0, message],
+            InvalidArgument(1),
+        );
+        suite.expect(
+            &format!("{} invalid UTF-8 name", verb),
+            syscall,
+            [id, invalid_utf8.as_ptr() as usize, invalid_utf8.len(), 0, 0, message],
+            InvalidArgument(1),
+        );
+        suite.expect(
+            &format!("{} bad caps", verb),
+            syscall,
+            [id, name_ptr, name_len, KERNEL_PTR, 1, message],
+            InvalidArgument(3),
+        );
+        suite.expect(
+            &format!("{} bad message", verb),
+            syscall,
+            [id, name_ptr, name_len, 0, 0, KERNEL_PTR],
+            InvalidArgument(5),
+        );
+    }
+}
+
+/// End the run through QEMU's test finisher, which exits QEMU with a non-zero
+/// status if anything failed. Other platforms don't have one, so the summary
+/// printed before this is all there is.
+fn finish(passed: bool) {
+    let devicemgr = devicemgr::Client::new(std::env::lookup_capability("devicemgr").unwrap().capability.cptr);
+    let (_, caps) = devicemgr.request_devices(vec![String::from(FINISHER_COMPATIBLE)], &[]).unwrap();
+
+    let finisher = match caps.first() {
+        Some(cap) => librust::syscalls::io::query_mmio_cap(cap.capability.cptr, &mut []).unwrap().0.address(),
+        None => return println!("[syscall-conformance] No test finisher to report the result to"),
+    };
+
+    let value = match passed {
+        true => FINISHER_PASS,
+        false => FINISHER_FAIL | 1 << 16,
+    };
+
+    unsafe { core::ptr::write_volatile(finisher.cast::<u32>(), value) };
+}
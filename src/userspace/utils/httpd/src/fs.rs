@@ -0,0 +1,129 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Filesystem access which doesn't block the executor
+//!
+//! `std::fs` waits on the filesystem server synchronously, stalling every
+//! other connection while it does. Requests are instead queued up for a single
+//! task which owns the channel to the filesystem server, since it answers the
+//! requests on a channel in order and handles are only valid on the channel
+//! they were opened on anyway.
+
+use librust::capabilities::CapabilityPtr;
+use present::{
+    ipc::IpcChannel,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        oneshot::{self, OneshotTx},
+    },
+};
+use std::{
+    fs::protocol::{
+        filesystem::{Request, Response},
+        DirEntry, FsError, Metadata, OpenFlags,
+    },
+    ipc::IpcError,
+};
+
+/// The most data the filesystem server returns from a single read
+pub const MAX_READ: u64 = 64 * 1024;
+
+type Call = (Request, OneshotTx<Result<Response, IpcError>>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    Ipc(IpcError),
+    Filesystem(FsError),
+    /// The filesystem server sent a response which doesn't fit the request
+    UnexpectedResponse,
+}
+
+impl From<FsError> for Error {
+    fn from(e: FsError) -> Self {
+        Self::Filesystem(e)
+    }
+}
+
+#[derive(Clone)]
+pub struct Filesystem {
+    calls: Sender<Call>,
+}
+
+impl Filesystem {
+    /// Start the task making requests over `filesystem`, a channel to the
+    /// filesystem server
+    pub fn new(filesystem: CapabilityPtr) -> Self {
+        let (calls, queued) = mpsc::unbounded();
+        present::spawn(make_calls(IpcChannel::new(filesystem), queued));
+
+        Self { calls }
+    }
+
+    pub async fn metadata(&self, path: &str) -> Result<Metadata, Error> {
+        match self.call(Request::metadata { path: path.into() }).await? {
+            Response::metadata(metadata) => Ok(metadata?),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, Error> {
+        match self.call(Request::read_dir { path: path.into() }).await? {
+            Response::read_dir(entries) => Ok(entries?),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Open `path` for reading, returning its handle
+    pub async fn open(&self, path: &str) -> Result<u64, Error> {
+        let flags = OpenFlags { read: true, ..Default::default() };
+        match self.call(Request::open { path: path.into(), flags }).await? {
+            Response::open(handle) => Ok(handle?),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn handle_metadata(&self, handle: u64) -> Result<Metadata, Error> {
+        match self.call(Request::handle_metadata { handle }).await? {
+            Response::handle_metadata(metadata) => Ok(metadata?),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Read up to `len` bytes, which is only short at the end of the file
+    pub async fn read(&self, handle: u64, len: u64) -> Result<Vec<u8>, Error> {
+        match self.call(Request::read { handle, len: len.min(MAX_READ) }).await? {
+            Response::read(data) => Ok(data?),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn close(&self, handle: u64) -> Result<(), Error> {
+        match self.call(Request::close { handle }).await? {
+            Response::close(result) => Ok(result?),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    async fn call(&self, request: Request) -> Result<Response, Error> {
+        let (tx, rx) = oneshot::oneshot();
+        self.calls.send((request, tx));
+
+        rx.recv().await.map_err(Error::Ipc)
+    }
+}
+
+async fn make_calls(filesystem: IpcChannel, queued: Receiver<Call>) {
+    loop {
+        let (request, reply) = queued.recv().await;
+        let response = match filesystem.send_serialized(&request, &[]) {
+            Ok(()) => filesystem.read_serialized::<Response>().await.map(|(response, _)| response),
+            Err(e) => Err(e.into()),
+        };
+
+        reply.send(response);
+    }
+}
@@ -0,0 +1,354 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Just enough of HTTP/1.1 (RFC 9112) to serve static files
+
+use core::fmt::Write;
+
+/// The longest request line and headers accepted, together
+pub const MAX_HEAD_LEN: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub target: String,
+    /// Whether the client wants the connection kept open after the response
+    pub keep_alive: bool,
+    pub content_length: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    MovedPermanently,
+    BadRequest,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    HeaderFieldsTooLarge,
+    InternalServerError,
+    NotImplemented,
+    VersionNotSupported,
+}
+
+impl Status {
+    pub fn code(self) -> u16 {
+        match self {
+            Status::Ok => 200,
+            Status::MovedPermanently => 301,
+            Status::BadRequest => 400,
+            Status::Forbidden => 403,
+            Status::NotFound => 404,
+            Status::MethodNotAllowed => 405,
+            Status::HeaderFieldsTooLarge => 431,
+            Status::InternalServerError => 500,
+            Status::NotImplemented => 501,
+            Status::VersionNotSupported => 505,
+        }
+    }
+
+    pub fn reason(self) -> &'static str {
+        match self {
+            Status::Ok => "OK",
+            Status::MovedPermanently => "Moved Permanently",
+            Status::BadRequest => "Bad Request",
+            Status::Forbidden => "Forbidden",
+            Status::NotFound => "Not Found",
+            Status::MethodNotAllowed => "Method Not Allowed",
+            Status::HeaderFieldsTooLarge => "Request Header Fields Too Large",
+            Status::InternalServerError => "Internal Server Error",
+            Status::NotImplemented => "Not Implemented",
+            Status::VersionNotSupported => "HTTP Version Not Supported",
+        }
+    }
+}
+
+/// Parse the request line and headers at the start of `buffer`, returning the
+/// request and how many bytes it took up, or `None` if the blank line ending
+/// them hasn't arrived yet. Errors are the status to reply with before closing
+/// the connection.
+pub fn parse_request(buffer: &[u8]) -> Result<Option<(Request, usize)>, Status> {
+    let end = match buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(end) if end <= MAX_HEAD_LEN => end,
+        None if buffer.len() <= MAX_HEAD_LEN => return Ok(None),
+        _ => return Err(Status::HeaderFieldsTooLarge),
+    };
+
+    let head = core::str::from_utf8(&buffer[..end]).map_err(|_| Status::BadRequest)?;
+    let mut lines = head.split("\r\n");
+
+    let mut parts = lines.next().unwrap_or_default().split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) if !method.is_empty() && !target.is_empty() => {
+            (method, target, version)
+        }
+        _ => return Err(Status::BadRequest),
+    };
+
+    let version = match version {
+        "HTTP/1.1" => Version::Http11,
+        "HTTP/1.0" => Version::Http10,
+        _ if version.starts_with("HTTP/") => return Err(Status::VersionNotSupported),
+        _ => return Err(Status::BadRequest),
+    };
+
+    // Connections are only persistent by default from HTTP/1.1 on
+    let mut keep_alive = version == Version::Http11;
+    let mut content_length = None;
+    for line in lines {
+        let (name, value) = line.split_once(':').ok_or(Status::BadRequest)?;
+        // Whitespace before the colon isn't allowed, since proxies disagree on
+        // what it means (RFC 9112 section 5.1)
+        if name.is_empty() || name.ends_with([' ', '\t']) {
+            return Err(Status::BadRequest);
+        }
+
+        let value = value.trim_matches([' ', '\t']);
+        if name.eq_ignore_ascii_case("connection") {
+            for option in value.split(',').map(str::trim) {
+                if option.eq_ignore_ascii_case("close") {
+                    keep_alive = false;
+                } else if option.eq_ignore_ascii_case("keep-alive") {
+                    keep_alive = true;
+                }
+            }
+        } else if name.eq_ignore_ascii_case("content-length") {
+            let len = value.parse().map_err(|_| Status::BadRequest)?;
+            // Differing lengths would leave where the next request starts
+            // ambiguous
+            if content_length.replace(len).map_or(false, |previous| previous != len) {
+                return Err(Status::BadRequest);
+            }
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            return Err(Status::NotImplemented);
+        }
+    }
+
+    let request = Request {
+        method: method.into(),
+        target: target.into(),
+        keep_alive,
+        content_length: content_length.unwrap_or(0),
+    };
+
+    Ok(Some((request, end + 4)))
+}
+
+/// The status line and headers of a response, including the blank line ending
+/// them
+pub fn response_head(status: Status, headers: &[(&str, &str)], content_length: u64, keep_alive: bool) -> Vec<u8> {
+    let mut head = String::new();
+    let _ = write!(head, "HTTP/1.1 {} {}\r\n", status.code(), status.reason());
+    let _ = write!(head, "Server: vanadinite-httpd\r\nContent-Length: {}\r\n", content_length);
+
+    for (name, value) in headers {
+        let _ = write!(head, "{}: {}\r\n", name, value);
+    }
+
+    if !keep_alive {
+        head.push_str("Connection: close\r\n");
+    }
+
+    head.push_str("\r\n");
+    head.into_bytes()
+}
+
+/// Decode the `%XX` escapes in a request path, returning `None` if they're
+/// malformed or don't decode to UTF-8
+pub fn percent_decode(path: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(path.len());
+    let mut bytes = path.bytes();
+
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let high = (bytes.next()? as char).to_digit(16)?;
+                let low = (bytes.next()? as char).to_digit(16)?;
+                decoded.push((high << 4 | low) as u8);
+            }
+            byte => decoded.push(byte),
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+/// Escape everything in `name` which can't appear as is in a path segment
+pub fn percent_encode(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            byte => {
+                let _ = write!(encoded, "%{:02X}", byte);
+            }
+        }
+    }
+
+    encoded
+}
+
+/// The absolute path to redirect to for the directory at `relative`, a path
+/// relative to the root with no `.` or `..` components, with a trailing `/`
+pub fn directory_location(relative: &str) -> String {
+    let mut location = String::from("/");
+    for segment in relative.split('/').filter(|segment| !segment.is_empty()) {
+        location.push_str(&percent_encode(segment));
+        location.push('/');
+    }
+
+    location
+}
+
+/// Escape the characters with special meaning in HTML text and attributes
+pub fn html_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Guess the media type of a file from its extension
+pub fn content_type(file_name: &str) -> &'static str {
+    let extension = match file_name.rsplit_once('.') {
+        Some((_, extension)) => extension,
+        None => return "application/octet-stream",
+    };
+
+    match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "txt" | "md" | "rs" => "text/plain; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(head: &str) -> Result<Option<(Request, usize)>, Status> {
+        parse_request(head.as_bytes())
+    }
+
+    #[test]
+    fn parses_a_request() {
+        let head = "GET /a%20b?x=1 HTTP/1.1\r\nHost: example\r\nContent-Length: 3\r\n\r\nabc";
+        let (request, len) = parse(head).unwrap().unwrap();
+
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.target, "/a%20b?x=1");
+        assert!(request.keep_alive);
+        assert_eq!(request.content_length, 3);
+        assert_eq!(len, head.len() - 3);
+    }
+
+    #[test]
+    fn incomplete_head_needs_more() {
+        assert!(matches!(parse("GET / HTTP/1.1\r\nHost: example\r\n"), Ok(None)));
+        assert!(matches!(parse(""), Ok(None)));
+    }
+
+    #[test]
+    fn connection_header_overrides_the_version_default() {
+        let (request, _) = parse("GET / HTTP/1.0\r\n\r\n").unwrap().unwrap();
+        assert!(!request.keep_alive);
+
+        let (request, _) = parse("GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n").unwrap().unwrap();
+        assert!(request.keep_alive);
+
+        let (request, _) = parse("GET / HTTP/1.1\r\nconnection: foo, close\r\n\r\n").unwrap().unwrap();
+        assert!(!request.keep_alive);
+    }
+
+    #[test]
+    fn malformed_request_lines_are_rejected() {
+        assert_eq!(parse("GET /\r\n\r\n").unwrap_err(), Status::BadRequest);
+        assert_eq!(parse("GET  / HTTP/1.1\r\n\r\n").unwrap_err(), Status::BadRequest);
+        assert_eq!(parse("GET / HTTP/1.1 extra\r\n\r\n").unwrap_err(), Status::BadRequest);
+        assert_eq!(parse("GET / FTP/1.0\r\n\r\n").unwrap_err(), Status::BadRequest);
+        assert_eq!(parse("GET / HTTP/2.0\r\n\r\n").unwrap_err(), Status::VersionNotSupported);
+    }
+
+    #[test]
+    fn malformed_headers_are_rejected() {
+        assert_eq!(parse("GET / HTTP/1.1\r\nno colon\r\n\r\n").unwrap_err(), Status::BadRequest);
+        assert_eq!(parse("GET / HTTP/1.1\r\nHost : example\r\n\r\n").unwrap_err(), Status::BadRequest);
+        assert_eq!(parse("GET / HTTP/1.1\r\nContent-Length: x\r\n\r\n").unwrap_err(), Status::BadRequest);
+        assert_eq!(
+            parse("GET / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n").unwrap_err(),
+            Status::BadRequest
+        );
+        assert_eq!(parse("GET / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n").unwrap_err(), Status::NotImplemented);
+    }
+
+    #[test]
+    fn repeated_equal_content_lengths_are_allowed() {
+        let (request, _) = parse("GET / HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 2\r\n\r\n").unwrap().unwrap();
+        assert_eq!(request.content_length, 2);
+    }
+
+    #[test]
+    fn oversized_heads_are_rejected() {
+        let mut head = String::from("GET / HTTP/1.1\r\nX: ");
+        head.push_str(&"a".repeat(MAX_HEAD_LEN));
+
+        assert_eq!(parse(&head).unwrap_err(), Status::HeaderFieldsTooLarge);
+        head.push_str("\r\n\r\n");
+        assert_eq!(parse(&head).unwrap_err(), Status::HeaderFieldsTooLarge);
+    }
+
+    #[test]
+    fn percent_decode_escapes() {
+        assert_eq!(percent_decode("/a%20b").as_deref(), Some("/a b"));
+        assert_eq!(percent_decode("%2e%2E%2f").as_deref(), Some("../"));
+        assert_eq!(percent_decode("%C3%A9").as_deref(), Some("\u{e9}"));
+        assert_eq!(percent_decode("plain").as_deref(), Some("plain"));
+    }
+
+    #[test]
+    fn percent_decode_rejects_malformed_escapes() {
+        assert_eq!(percent_decode("%"), None);
+        assert_eq!(percent_decode("%2"), None);
+        assert_eq!(percent_decode("%zz"), None);
+        assert_eq!(percent_decode("%FF"), None);
+    }
+
+    #[test]
+    fn directory_location_stays_on_this_site() {
+        assert_eq!(directory_location(""), "/");
+        assert_eq!(directory_location("a/b c"), "/a/b%20c/");
+        // What `//example.com` confines to
+        assert_eq!(directory_location("example.com"), "/example.com/");
+        assert_eq!(directory_location("a//b"), "/a/b/");
+    }
+}
@@ -0,0 +1,366 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A static file server
+//!
+//! Serves the files under `httpd.root` from the filesystem server over
+//! HTTP/1.1 on the vsock port `httpd.port`. The network stack doesn't do TCP
+//! yet, so vsock is the only stream transport there is to listen on.
+//!
+//! Only `GET` and `HEAD` are supported. Directories are answered with their
+//! `index.html` if they have one, or a listing of their entries otherwise.
+//! Connections are kept open between requests unless the client asks
+//! otherwise, and closed after sitting idle for 30 seconds.
+//!
+//! The vsock server treats each channel as a single socket, so as many clients
+//! are served at once as there are channels to it, granted as `vsock`,
+//! `vsock.1`, `vsock.2` and so on. Clients connecting while every channel is
+//! busy are refused.
+
+mod fs;
+mod http;
+
+use core::time::Duration;
+use fs::Filesystem;
+use http::{Request, Status};
+use librust::capabilities::CapabilityPtr;
+use present::vsock::{VsockError, VsockStream};
+use std::{
+    fs::protocol::{DirEntry, FileKind, FsError},
+    path::{Path, PathBuf},
+};
+
+const DEFAULT_PORT: u32 = 8080;
+const DEFAULT_ROOT: &str = "/";
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+const TEXT_PLAIN: (&str, &str) = ("Content-Type", "text/plain; charset=utf-8");
+const TEXT_HTML: (&str, &str) = ("Content-Type", "text/html; charset=utf-8");
+
+/// What was sent in reply to a request
+struct Sent {
+    status: Status,
+    /// The length of the body, which isn't sent in reply to `HEAD`
+    len: u64,
+    /// Whether the whole body made it out, since the connection has to be
+    /// closed to tell the client otherwise once the head is sent
+    complete: bool,
+}
+
+impl Sent {
+    fn new(status: Status, len: u64) -> Self {
+        Self { status, len, complete: true }
+    }
+}
+
+async fn real_main() {
+    let port = match std::env::config("httpd.port") {
+        Ok(Some(port)) => port.parse().unwrap_or(DEFAULT_PORT),
+        _ => DEFAULT_PORT,
+    };
+
+    let root = match std::env::config("httpd.root") {
+        Ok(Some(root)) => PathBuf::from(root),
+        _ => PathBuf::from(DEFAULT_ROOT),
+    };
+
+    let fs = match std::env::lookup_capability("filesystem") {
+        Some(filesystem) => Filesystem::new(filesystem.capability.cptr),
+        None => {
+            println!("[httpd] No filesystem capability, nothing to serve");
+            return;
+        }
+    };
+
+    let vsock = vsock_channels();
+    if vsock.is_empty() {
+        println!("[httpd] No vsock capability, nowhere to listen");
+        return;
+    }
+
+    println!("[httpd] Serving {} on port {}, up to {} connections at once", root, port, vsock.len());
+
+    let (idle_tx, idle) = present::sync::mpsc::unbounded();
+    for cptr in vsock {
+        idle_tx.send(cptr);
+    }
+
+    loop {
+        let vsock = idle.recv().await;
+        let stream = match VsockStream::accept(vsock, port).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("[httpd] Couldn't listen on port {}: {:?}", port, e);
+                return;
+            }
+        };
+
+        let (fs, root, idle_tx) = (fs.clone(), root.clone(), idle_tx.clone());
+        present::spawn(async move {
+            let peer = stream.peer_address();
+            match serve(&stream, &fs, &root).await {
+                Ok(()) | Err(VsockError::Closed) => {}
+                Err(e) => println!("[httpd] Connection from {}:{} ended with an error: {:?}", peer.cid, peer.port, e),
+            }
+
+            let _ = stream.shutdown();
+            idle_tx.send(vsock);
+        });
+    }
+}
+
+/// Every channel to the vsock server this task was granted
+fn vsock_channels() -> Vec<CapabilityPtr> {
+    core::iter::once(String::from("vsock"))
+        .chain((1..).map(|n| format!("vsock.{}", n)))
+        .map_while(|name| std::env::lookup_capability(&name))
+        .map(|vsock| vsock.capability.cptr)
+        .collect()
+}
+
+/// Answer requests on `stream` until the client closes it, asks for it to be
+/// closed, or leaves it idle for too long
+async fn serve(stream: &VsockStream, fs: &Filesystem, root: &Path) -> Result<(), VsockError> {
+    let peer = stream.peer_address();
+    let mut buffer = Vec::new();
+
+    loop {
+        let (request, len) = loop {
+            match http::parse_request(&buffer) {
+                Ok(Some(parsed)) => break parsed,
+                Ok(None) => {}
+                Err(status) => {
+                    let body = error_body(status);
+                    stream.send(&http::response_head(status, &[TEXT_PLAIN], body.len() as u64, false))?;
+                    return stream.send(body.as_bytes());
+                }
+            }
+
+            if !receive(stream, &mut buffer).await? {
+                return Ok(());
+            }
+        };
+
+        buffer.drain(..len);
+
+        // Neither `GET` nor `HEAD` have a use for a body, but it has to be
+        // skipped to get to the next request
+        let mut body_left = request.content_length;
+        while body_left > 0 {
+            if buffer.is_empty() && !receive(stream, &mut buffer).await? {
+                return Ok(());
+            }
+
+            let skipped = body_left.min(buffer.len());
+            buffer.drain(..skipped);
+            body_left -= skipped;
+        }
+
+        let sent = respond(stream, fs, root, &request).await?;
+        println!(
+            "[httpd] {}:{} \"{} {}\" {} {}",
+            peer.cid,
+            peer.port,
+            request.method,
+            request.target,
+            sent.status.code(),
+            sent.len
+        );
+
+        if !request.keep_alive || !sent.complete {
+            return Ok(());
+        }
+    }
+}
+
+/// Wait for more data from the client, returning `false` if it doesn't send
+/// any before the idle timeout
+async fn receive(stream: &VsockStream, buffer: &mut Vec<u8>) -> Result<bool, VsockError> {
+    match present::time::timeout(IDLE_TIMEOUT, stream.recv()).await {
+        Ok(data) => {
+            buffer.extend(data?);
+            Ok(true)
+        }
+        Err(_) => Ok(false),
+    }
+}
+
+async fn respond(stream: &VsockStream, fs: &Filesystem, root: &Path, request: &Request) -> Result<Sent, VsockError> {
+    let head_only = match request.method.as_str() {
+        "GET" => false,
+        "HEAD" => true,
+        _ => {
+            let allow = ("Allow", "GET, HEAD");
+            return send_error(stream, Status::MethodNotAllowed, &[allow], request);
+        }
+    };
+
+    // The query doesn't mean anything for static files
+    let target = request.target.split(['?', '#']).next().unwrap_or_default();
+    let path = match http::percent_decode(target) {
+        Some(path) if target.starts_with('/') => path,
+        _ => return send_error(stream, Status::BadRequest, &[], request),
+    };
+
+    let relative = match Path::new(&path).confine() {
+        Some(relative) => relative,
+        None => return send_error(stream, Status::Forbidden, &[], request),
+    };
+    let full_path = root.join(&relative);
+
+    let metadata = match fs.metadata(full_path.as_str()).await {
+        Ok(metadata) => metadata,
+        Err(e) => return send_error(stream, error_status(e), &[], request),
+    };
+
+    if metadata.kind == FileKind::File {
+        return send_file(stream, fs, full_path.as_str(), request, head_only).await;
+    }
+
+    // Relative links in the page are resolved against the directory's parent
+    // otherwise. The location is built from the confined path rather than the
+    // target, which could be e.g. `//example.com` and send the client off to
+    // another site.
+    if !target.ends_with('/') {
+        let location = http::directory_location(relative.as_str());
+        return send_error(stream, Status::MovedPermanently, &[("Location", location.as_str())], request);
+    }
+
+    let index = full_path.join("index.html");
+    if let Ok(metadata) = fs.metadata(index.as_str()).await {
+        if metadata.kind == FileKind::File {
+            return send_file(stream, fs, index.as_str(), request, head_only).await;
+        }
+    }
+
+    let mut entries = match fs.read_dir(full_path.as_str()).await {
+        Ok(entries) => entries,
+        Err(e) => return send_error(stream, error_status(e), &[], request),
+    };
+
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    let page = listing(&path, &entries);
+
+    stream.send(&http::response_head(Status::Ok, &[TEXT_HTML], page.len() as u64, request.keep_alive))?;
+    if !head_only {
+        stream.send(page.as_bytes())?;
+    }
+
+    Ok(Sent::new(Status::Ok, page.len() as u64))
+}
+
+/// An HTML page linking to each of the entries of the directory at `path`
+fn listing(path: &str, entries: &[DirEntry]) -> String {
+    let title = format!("Index of {}", http::html_escape(path));
+    let mut page = format!("<!DOCTYPE html>\n<html>\n<head><title>{0}</title></head>\n<body>\n<h1>{0}</h1>\n", title);
+
+    page.push_str("<ul>\n");
+    if path != "/" {
+        page.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+
+    for entry in entries {
+        let slash = if entry.metadata.is_dir() { "/" } else { "" };
+        let href = http::percent_encode(&entry.name);
+        page.push_str(&format!(
+            "<li><a href=\"{}{}\">{}{}</a></li>\n",
+            href,
+            slash,
+            http::html_escape(&entry.name),
+            slash
+        ));
+    }
+
+    page.push_str("</ul>\n</body>\n</html>\n");
+    page
+}
+
+async fn send_file(
+    stream: &VsockStream,
+    fs: &Filesystem,
+    path: &str,
+    request: &Request,
+    head_only: bool,
+) -> Result<Sent, VsockError> {
+    let handle = match fs.open(path).await {
+        Ok(handle) => handle,
+        Err(e) => return send_error(stream, error_status(e), &[], request),
+    };
+
+    let result = send_contents(stream, fs, handle, path, request, head_only).await;
+    let _ = fs.close(handle).await;
+
+    result
+}
+
+async fn send_contents(
+    stream: &VsockStream,
+    fs: &Filesystem,
+    handle: u64,
+    path: &str,
+    request: &Request,
+    head_only: bool,
+) -> Result<Sent, VsockError> {
+    // The length from the handle rather than the path, in case the file was
+    // replaced in between
+    let len = match fs.handle_metadata(handle).await {
+        Ok(metadata) => metadata.len,
+        Err(e) => return send_error(stream, error_status(e), &[], request),
+    };
+
+    let content_type = ("Content-Type", http::content_type(path));
+    stream.send(&http::response_head(Status::Ok, &[content_type], len, request.keep_alive))?;
+
+    let mut left = len;
+    while !head_only && left > 0 {
+        match fs.read(handle, left).await {
+            Ok(data) if !data.is_empty() => {
+                // The file could have grown since, but only `len` bytes were
+                // promised
+                let n = data.len().min(left as usize);
+                stream.send(&data[..n])?;
+                left -= n as u64;
+            }
+            // The file shrank, or the filesystem server went away
+            _ => return Ok(Sent { status: Status::Ok, len, complete: false }),
+        }
+    }
+
+    Ok(Sent::new(Status::Ok, len))
+}
+
+fn send_error(
+    stream: &VsockStream,
+    status: Status,
+    headers: &[(&str, &str)],
+    request: &Request,
+) -> Result<Sent, VsockError> {
+    let body = error_body(status);
+    let mut all_headers = vec![TEXT_PLAIN];
+    all_headers.extend_from_slice(headers);
+
+    stream.send(&http::response_head(status, &all_headers, body.len() as u64, request.keep_alive))?;
+    if request.method != "HEAD" {
+        stream.send(body.as_bytes())?;
+    }
+
+    Ok(Sent::new(status, body.len() as u64))
+}
+
+fn error_body(status: Status) -> String {
+    format!("{} {}\n", status.code(), status.reason())
+}
+
+fn error_status(error: fs::Error) -> Status {
+    match error {
+        fs::Error::Filesystem(FsError::NotFound | FsError::NotADirectory) => Status::NotFound,
+        fs::Error::Filesystem(FsError::InvalidPath) => Status::BadRequest,
+        fs::Error::Filesystem(FsError::PermissionDenied) => Status::Forbidden,
+        _ => Status::InternalServerError,
+    }
+}
+
+present::main!({ real_main().await });
@@ -0,0 +1,96 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Prints the messages going back and forth between two tasks as they're sent,
+//! by snooping on their channels with the debug capability. Nothing is taken
+//! away from the tasks themselves.
+//!
+//! The task to snoop on is the `ipc-trace.task` config key, and only its
+//! channels to `ipc-trace.peer` are snooped on if that's set, both of which are
+//! task IDs. `ipc-trace.mode` is either `copy` to print the data of each
+//! message, which is the default, or `metadata` to only print who sent what to
+//! whom. Nothing is traced unless `ipc-trace.task` is set.
+
+use core::num::NonZeroUsize;
+use librust::{
+    syscalls::{
+        channel::{self, ChannelReadFlags},
+        debug::{self, SnoopDirection, SnoopMode, SnoopRecord},
+    },
+    task::Tid,
+};
+
+fn main() {
+    let task = match config::<NonZeroUsize>("ipc-trace.task") {
+        Some(task) => Tid::new(task),
+        None => return,
+    };
+
+    let peer = config::<NonZeroUsize>("ipc-trace.peer").map(Tid::new);
+    let mode = match std::env::config("ipc-trace.mode").ok().flatten().as_deref() {
+        None | Some("copy") => SnoopMode::Copy,
+        Some("metadata") => SnoopMode::Metadata,
+        Some(mode) => return println!("[ipc-trace] Unknown mode {:?}, expected `copy` or `metadata`", mode),
+    };
+
+    let debug = std::env::lookup_capability("debug").unwrap().capability.cptr;
+    let (snooped, n_channels) = match debug::snoop_channels(debug, task, peer, mode) {
+        Ok(snooped) => snooped,
+        Err(e) => return println!("[ipc-trace] Couldn't snoop on task {}: {:?}", task, e),
+    };
+
+    // Tags the trace so it can be told apart from others once it's saved
+    let trace_id = librust::uuid::generate_uuid().unwrap_or(librust::uuid::Uuid::NIL);
+    println!(
+        "[ipc-trace] Tracing {} channels of task {} (trace {}, boot {})",
+        n_channels,
+        task,
+        trace_id,
+        librust::syscalls::io::boot_id()
+    );
+
+    loop {
+        let record = SnoopRecord::construct(read(snooped));
+        let data = match record.has_data {
+            true => Some(read(snooped)),
+            false => None,
+        };
+
+        if record.missed > 0 {
+            println!("[ipc-trace] ... {} messages missed", record.missed);
+        }
+
+        let peer = record.peer.map_or_else(|| String::from("?"), |peer| peer.to_string());
+        let (from, to) = match record.direction {
+            SnoopDirection::Sent => (task.to_string(), peer),
+            SnoopDirection::Received => (peer, task.to_string()),
+        };
+
+        let mut line = format!("[ipc-trace] {} -> {} (cptr {})", from, to, record.cptr.value());
+        if record.urgent {
+            line.push_str(" urgent");
+        }
+
+        if record.caps > 0 {
+            line.push_str(&format!(" +{} caps", record.caps));
+        }
+
+        if let Some(data) = data {
+            line.push_str(&format!(" {:#x?}", data));
+        }
+
+        println!("{}", line);
+    }
+}
+
+fn read(snooped: librust::capabilities::CapabilityPtr) -> [usize; 7] {
+    channel::read_message(snooped, &mut [], ChannelReadFlags::NONE).unwrap().message.0
+}
+
+fn config<T: core::str::FromStr>(key: &str) -> Option<T> {
+    std::env::config(key).ok().flatten()?.parse().ok()
+}
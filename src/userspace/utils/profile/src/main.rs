@@ -0,0 +1,118 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Profiles the whole system for a while with the kernel's sampling profiler,
+//! then prints the samples as folded stacks, one stack and the number of times
+//! it was seen per line, which flamegraph tools take as input. Each stack
+//! starts with the task it was taken in, or `[idle]` for harts which had
+//! nothing to run. Kernel frames are named, userspace frames are left as
+//! addresses.
+//!
+//! The frequency, stack depth and duration come from the `profile.hz`,
+//! `profile.depth` and `profile.seconds` config keys. The profiler needs the
+//! `debug` capability.
+
+use core::time::Duration;
+use librust::{
+    capabilities::CapabilityPtr,
+    syscalls::profiler::{self, ProfileSample, PROFILER_BUFFER_SAMPLES, PROFILER_MAX_DEPTH},
+};
+use std::collections::BTreeMap;
+
+const DEFAULT_HZ: usize = 1000;
+const DEFAULT_SECONDS: u64 = 10;
+/// Often enough that the kernel's buffers don't fill up at the highest
+/// frequency
+const DRAIN_INTERVAL: Duration = Duration::from_millis(100);
+
+struct Folder {
+    debug: CapabilityPtr,
+    stacks: BTreeMap<String, u64>,
+    kernel_symbols: BTreeMap<usize, String>,
+}
+
+impl Folder {
+    fn add(&mut self, sample: &ProfileSample) {
+        let mut stack = match sample.tid {
+            0 => String::from("[idle]"),
+            tid => format!("[tid {}]", tid),
+        };
+
+        // Frames are innermost first, folded stacks are outermost first
+        for &address in sample.frames().iter().rev() {
+            stack.push(';');
+            match sample.kernel {
+                true => stack.push_str(self.kernel_symbol(address)),
+                false => stack.push_str(&format!("{:#x}", address)),
+            }
+        }
+
+        *self.stacks.entry(stack).or_default() += 1;
+    }
+
+    fn kernel_symbol(&mut self, address: usize) -> &str {
+        self.kernel_symbols.entry(address).or_insert_with(|| {
+            let mut name = [0; 256];
+            match profiler::resolve_kernel_symbol(self.debug, address, &mut name) {
+                Ok(Some((len, _))) => String::from_utf8_lossy(&name[..len]).into_owned(),
+                _ => format!("[kernel] {:#x}", address),
+            }
+        })
+    }
+}
+
+async fn real_main() {
+    let hz = config("profile.hz").unwrap_or(DEFAULT_HZ);
+    let depth = config("profile.depth").unwrap_or(PROFILER_MAX_DEPTH);
+    let seconds = config("profile.seconds").unwrap_or(DEFAULT_SECONDS);
+    let debug = std::env::lookup_capability("debug").unwrap().capability.cptr;
+
+    if let Err(e) = profiler::start(debug, hz, depth) {
+        println!("Couldn't start the profiler: {:?}", e);
+        return;
+    }
+
+    let mut folder = Folder { debug, stacks: BTreeMap::new(), kernel_symbols: BTreeMap::new() };
+    let mut samples = vec![ProfileSample::new(); PROFILER_BUFFER_SAMPLES];
+    let mut total = 0;
+    let mut dropped = 0;
+
+    let drains = Duration::from_secs(seconds).as_millis() / DRAIN_INTERVAL.as_millis();
+    for i in 0..=drains {
+        match i == drains {
+            true => profiler::stop(debug).unwrap(),
+            false => present::time::sleep(DRAIN_INTERVAL).await,
+        }
+
+        loop {
+            let (drained, newly_dropped) = profiler::drain(debug, &mut samples).unwrap();
+            samples[..drained].iter().for_each(|sample| folder.add(sample));
+            total += drained;
+            dropped += newly_dropped;
+
+            if drained < samples.len() {
+                break;
+            }
+        }
+    }
+
+    // Comments are skipped by flamegraph tools, and tell saved profiles apart
+    let profile_id = librust::uuid::generate_uuid().unwrap_or(librust::uuid::Uuid::NIL);
+    println!("# profile {} of boot {}", profile_id, librust::syscalls::io::boot_id());
+
+    for (stack, count) in &folder.stacks {
+        println!("{} {}", stack, count);
+    }
+
+    println!("# {} samples, {} dropped", total, dropped);
+}
+
+fn config<T: core::str::FromStr>(key: &str) -> Option<T> {
+    std::env::config(key).ok().flatten()?.parse().ok()
+}
+
+present::main!({ real_main().await });
@@ -0,0 +1,18 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A starting point for programs which only need `librust`, without `std` or a
+//! heap
+
+#![no_std]
+#![no_main]
+
+librust::entry!(main);
+
+fn main() {
+    librust::println!("hello world from {} args", librust::rt::args().len());
+}
@@ -5,13 +5,18 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
-use crate::{Result, VanadiniteBuildOptions};
+use crate::{symbols, Result, VanadiniteBuildOptions};
 use anyhow::Context;
 use clap::{ArgEnum, Subcommand};
-use std::fs;
+use std::{fs, io::Read, path::Path};
 use tar::{Builder, Header};
 use xshell::{cmd, cp, mkdir_p, pushd, pushenv, rm_rf};
 
+/// Where the kernel's symbol table is written for it to embed
+const SYMBOL_TABLE: &str = "build/kernel.symbols";
+const TAR_BLOCK: u64 = 512;
+const PAGE_SIZE: u64 = 4096;
+
 #[derive(ArgEnum, Clone, Copy)]
 #[clap(rename_all = "snake_case")]
 pub enum Platform {
@@ -58,10 +63,18 @@ impl BuildTarget {
     pub fn env(&self) -> Vec<xshell::Pushenv> {
         match self {
             BuildTarget::Userspace => vec![],
-            BuildTarget::Vanadinite(opts) => vec![pushenv(
-                "RUSTFLAGS",
-                format!("-C code-model=medium -C link-arg=-Tvanadinite/lds/{}.lds", opts.platform),
-            )],
+            BuildTarget::Vanadinite(opts) => {
+                let mut rustflags = format!(
+                    "-C code-model=medium -C force-frame-pointers=yes -C link-arg=-Tvanadinite/lds/{}.lds",
+                    opts.platform
+                );
+
+                if opts.shadow_call_stack {
+                    rustflags.push_str(" -Zsanitizer=shadow-call-stack");
+                }
+
+                vec![pushenv("RUSTFLAGS", rustflags)]
+            }
             BuildTarget::Vanadium(opts) => {
                 vec![pushenv("RUSTFLAGS", format!("-C code-model=medium -C link-arg=-Tlds/{}.lds", opts.platform))]
             }
@@ -92,6 +105,7 @@ pub fn build(target: BuildTarget) -> Result<()> {
 
             let out = fs::File::create(init_tar)?;
             let mut archive = Builder::new(out);
+            let mut offset = 0;
 
             for (bin, path) in walkdir::WalkDir::new("target/riscv64gc-unknown-none-elf/release/")
                 .max_depth(1)
@@ -112,7 +126,9 @@ pub fn build(target: BuildTarget) -> Result<()> {
                 header.set_metadata(&metadata);
                 header.set_cksum();
 
+                offset = pad_to_page(&mut archive, offset)?;
                 archive.append_data(&mut header, filename, bin)?;
+                offset += TAR_BLOCK + round_up(metadata.len(), TAR_BLOCK);
             }
 
             archive.finish()?;
@@ -122,7 +138,22 @@ pub fn build(target: BuildTarget) -> Result<()> {
             cp("target/riscv64gc-unknown-none-elf/release/init", "../../../build/init")?;
         }
         BuildTarget::Vanadinite(build_opts) => {
-            let features = format!("platform.{} {}", build_opts.platform, build_opts.kernel_features);
+            let mut features = format!("platform.{} {}", build_opts.platform, build_opts.kernel_features);
+
+            // The kernel includes the trace whether or not it's replaying, and
+            // records a new one when it's empty
+            let trace = match &build_opts.replay {
+                Some(log) => {
+                    features.push_str(" debug.replay");
+                    read_replay_trace(log)?
+                }
+                None => Vec::new(),
+            };
+            fs::write("build/replay.trace", trace).context("failed to write replay trace")?;
+
+            if build_opts.shadow_call_stack {
+                features.push_str(" hardening.shadow_call_stack");
+            }
 
             let opt_level = if build_opts.debug_build { "--profile=dev" } else { "--release" };
             let opt_level = &[opt_level][..];
@@ -131,16 +162,44 @@ pub fn build(target: BuildTarget) -> Result<()> {
                 false => ("build", opt_level),
             };
 
+            // The kernel needs a symbol table to build, which can only be made
+            // from the kernel, so it starts out with whatever table is left
+            // over from the last build
+            if !Path::new(SYMBOL_TABLE).exists() {
+                fs::write(SYMBOL_TABLE, symbols::MAGIC).context("failed to write symbol table")?;
+            }
+
             let _dir = pushd("./src/kernel");
-            #[rustfmt::skip]
-            cmd!("
-                cargo {subcmd}
-                    -p vanadinite
-                    --target riscv64gc-unknown-none-elf
-                    --no-default-features
-                    --features {features}
-                    {test...}
-            ").run()?;
+            let build_kernel = || {
+                #[rustfmt::skip]
+                cmd!("
+                    cargo {subcmd}
+                        -p vanadinite
+                        --target riscv64gc-unknown-none-elf
+                        --no-default-features
+                        --features {features}
+                        {test...}
+                ").run()
+            };
+
+            build_kernel()?;
+
+            // The table is linked in after everything else, so building again
+            // with the right one doesn't move any of the functions in it
+            if !build_opts.test {
+                let profile = if build_opts.debug_build { "debug" } else { "release" };
+                let table =
+                    symbols::symbol_table(&format!("target/riscv64gc-unknown-none-elf/{}/vanadinite", profile))?;
+                let table_path = Path::new("../..").join(SYMBOL_TABLE);
+
+                if fs::read(&table_path).ok().as_ref() != Some(&table) {
+                    fs::write(&table_path, table).context("failed to write symbol table")?;
+                    build_kernel()?;
+                }
+
+                // The kernel's build script reports what it was configured with
+                cp(format!("target/riscv64gc-unknown-none-elf/{}/vanadinite.config", profile), "../../build/")?;
+            }
         }
         BuildTarget::Vanadium(build_opts) => {
             let features = format!("platform.{}", build_opts.platform);
@@ -189,3 +248,56 @@ pub fn build(target: BuildTarget) -> Result<()> {
 
     Ok(())
 }
+
+/// Init maps the read-only segments of servers straight out of its copy of the
+/// archive when their pages line up, so add an empty file before the next one
+/// if needed to start its contents on a page boundary. Takes and returns how
+/// far into the archive it is.
+fn pad_to_page(archive: &mut Builder<fs::File>, offset: u64) -> Result<u64> {
+    // The contents start after the header
+    if (offset + TAR_BLOCK) % PAGE_SIZE == 0 {
+        return Ok(offset);
+    }
+
+    let padding = (PAGE_SIZE - (offset + 2 * TAR_BLOCK) % PAGE_SIZE) % PAGE_SIZE;
+    let mut header = Header::new_ustar();
+    header.set_size(padding);
+    header.set_mode(0o644);
+    header.set_cksum();
+    archive.append_data(&mut header, ".pad", std::io::repeat(0).take(padding))?;
+
+    Ok(offset + TAR_BLOCK + padding)
+}
+
+fn round_up(n: u64, to: u64) -> u64 {
+    (n + to - 1) / to * to
+}
+
+/// Pull the trace a `debug.replay` kernel printed out of a log of its console
+/// output
+fn read_replay_trace(log: &Path) -> Result<Vec<u8>> {
+    let log = fs::read_to_string(log).with_context(|| format!("failed to read {}", log.display()))?;
+    let mut trace = Vec::new();
+
+    for line in log.lines() {
+        let hex = match line.split_once("replay-trace: ") {
+            Some((_, hex)) => hex.trim(),
+            None => continue,
+        };
+
+        if hex.len() != 48 {
+            anyhow::bail!("malformed replay trace line: {}", line);
+        }
+
+        for i in 0..3 {
+            let word = u64::from_str_radix(&hex[i * 16..][..16], 16).context("malformed replay trace line")?;
+            trace.extend_from_slice(&word.to_le_bytes());
+        }
+    }
+
+    if trace.is_empty() {
+        anyhow::bail!("no replay trace found in the log");
+    }
+
+    Ok(trace)
+}
@@ -0,0 +1,353 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Ready to boot images of `vanadinite`
+//!
+//! Everything is built the same way `cargo xtask build opensbi` does, then
+//! packaged up in the output directory as:
+//!
+//! - `qemu.sh`, which boots the kernel in QEMU from the files next to it
+//! - `vanadinite.itb`, a U-Boot FIT image of the kernel, for `bootm`
+//! - `vanadinite.efi`, the kernel as an EFI application, for EDK2 or U-Boot's
+//!   `bootefi`
+//! - `sdcard.img`, a GPT disk image with OpenSBI and the kernel in the
+//!   partition the HiFive Unleashed's first stage bootloader loads, followed
+//!   by a partition holding the FIT image for boards booting through U-Boot
+
+use crate::{
+    build::{self, BuildTarget, Platform},
+    Result, VanadiniteBuildOptions,
+};
+use anyhow::Context;
+use clap::Parser;
+use std::{fs, path::PathBuf};
+use xshell::{cmd, cp, mkdir_p};
+
+/// The kernel runs from anywhere it's loaded, but it has to be megapage aligned
+const KERNEL_ALIGN: u64 = 0x20_0000;
+const SECTOR_SIZE: u64 = 512;
+/// Partitions start on 1 MiB boundaries
+const PARTITION_ALIGN: u64 = 2048;
+/// The partition type the HiFive Unleashed's FSBL loads to the start of RAM
+/// and jumps to
+const PAYLOAD_PARTITION_TYPE: &str = "2E54B353-1271-4842-806F-E436D6AF6985";
+/// Linux filesystem data, which U-Boot can read from with `mmc read`
+const FIT_PARTITION_TYPE: &str = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
+
+#[derive(Parser)]
+pub struct DistOptions {
+    #[clap(flatten)]
+    vanadinite_options: VanadiniteBuildOptions,
+
+    /// Where to put the images
+    #[clap(long, default_value = "build/dist")]
+    out: PathBuf,
+
+    /// Where U-Boot loads the kernel from the FIT image, which has to be 2 MiB
+    /// aligned. The default is where OpenSBI expects it on QEMU and the HiFive
+    /// Unleashed, RAM on the VisionFive 2 starts at 0x40000000 so use
+    /// 0x40200000 there.
+    #[clap(long, default_value = "0x80200000", parse(try_from_str = parse_address))]
+    load_address: u64,
+}
+
+pub fn dist(options: DistOptions) -> Result<()> {
+    if options.vanadinite_options.debug_build {
+        anyhow::bail!("only release builds can be packaged, since OpenSBI is always built with the release kernel");
+    }
+
+    build::build(BuildTarget::OpenSBI(options.vanadinite_options.clone()))?;
+
+    let out = &options.out;
+    mkdir_p(out)?;
+
+    let kernel_dir = "src/kernel/target/riscv64gc-unknown-none-elf/release";
+    cp(format!("{}/vanadinite", kernel_dir), out)?;
+    cp(format!("{}/vanadinite.bin", kernel_dir), out)?;
+    // The flat binary is also a PE/COFF image
+    cp(format!("{}/vanadinite.bin", kernel_dir), out.join("vanadinite.efi"))?;
+    cp(format!("{}/vanadinite.config", kernel_dir), out)?;
+    cp("build/opensbi-riscv64-generic-fw_jump.elf", out.join("fw_jump.elf"))?;
+    cp("build/opensbi-riscv64-generic-fw_payload.bin", out.join("fw_payload.bin"))?;
+
+    write_qemu_script(options.vanadinite_options.platform, out)?;
+    build_fit_image(out, options.load_address)?;
+    build_sd_card_image(out)?;
+
+    println!("Images written to {}", out.display());
+
+    Ok(())
+}
+
+fn write_qemu_script(platform: Platform, out: &std::path::Path) -> Result<()> {
+    let path = out.join("qemu.sh");
+    let script = format!(
+        "#!/bin/sh\n\
+         # Boot vanadinite on the QEMU `{platform}` machine, any arguments are passed on to QEMU\n\
+         cd \"$(dirname \"$0\")\"\n\
+         exec qemu-system-riscv64 \\\n    \
+             -machine {platform} \\\n    \
+             -cpu rv64 \\\n    \
+             -smp 4 \\\n    \
+             -m 512M \\\n    \
+             -global virtio-mmio.force-legacy=false \\\n    \
+             -serial mon:stdio \\\n    \
+             -nographic \\\n    \
+             -bios fw_jump.elf \\\n    \
+             -kernel vanadinite \\\n    \
+             \"$@\"\n"
+    );
+
+    fs::write(&path, script).with_context(|| format!("failed to write {}", path.display()))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+    }
+
+    Ok(())
+}
+
+/// Package the kernel as a FIT image with `mkimage`, which comes with U-Boot
+fn build_fit_image(out: &std::path::Path, load_address: u64) -> Result<()> {
+    // Booting it as Linux gets U-Boot to pass the hart ID and device tree in
+    // `a0` and `a1`, which is what the kernel expects from any SBI
+    let its = format!(
+        "/dts-v1/;\n\
+         \n\
+         / {{\n    \
+             description = \"vanadinite\";\n    \
+             #address-cells = <1>;\n\
+             \n    \
+             images {{\n        \
+                 kernel {{\n            \
+                     description = \"vanadinite\";\n            \
+                     data = /incbin/(\"vanadinite.bin\");\n            \
+                     type = \"kernel\";\n            \
+                     arch = \"riscv\";\n            \
+                     os = \"linux\";\n            \
+                     compression = \"none\";\n            \
+                     load = <{load:#x}>;\n            \
+                     entry = <{load:#x}>;\n            \
+                     hash-1 {{\n                \
+                         algo = \"sha256\";\n            \
+                     }};\n        \
+                 }};\n    \
+             }};\n\
+             \n    \
+             configurations {{\n        \
+                 default = \"conf-1\";\n        \
+                 conf-1 {{\n            \
+                     description = \"vanadinite\";\n            \
+                     kernel = \"kernel\";\n        \
+                 }};\n    \
+             }};\n\
+         }};\n",
+        load = load_address,
+    );
+
+    fs::write(out.join("vanadinite.its"), its).context("failed to write FIT image source")?;
+
+    let its = out.join("vanadinite.its");
+    let itb = out.join("vanadinite.itb");
+    cmd!("mkimage -f {its} {itb}").run().context("failed to run `mkimage`, is U-Boot's tools package installed?")?;
+
+    Ok(())
+}
+
+fn parse_address(address: &str) -> Result<u64> {
+    let address = match address.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16)?,
+        None => address.parse()?,
+    };
+
+    if address % KERNEL_ALIGN != 0 {
+        anyhow::bail!("{:#x} isn't 2 MiB aligned", address);
+    }
+
+    Ok(address)
+}
+
+/// Lay out the SD card image, with the OpenSBI payload and FIT image each in
+/// their own partition
+fn build_sd_card_image(out: &std::path::Path) -> Result<()> {
+    let payload = fs::read(out.join("fw_payload.bin"))?;
+    let fit = fs::read(out.join("vanadinite.itb"))?;
+
+    let mut partitions = Vec::new();
+    let mut next = PARTITION_ALIGN;
+    for (name, kind, contents) in [("payload", PAYLOAD_PARTITION_TYPE, &payload), ("fit", FIT_PARTITION_TYPE, &fit)] {
+        let sectors = round_up(contents.len() as u64, PARTITION_ALIGN * SECTOR_SIZE) / SECTOR_SIZE;
+        partitions.push(Partition { name, kind, first: next, last: next + sectors - 1 });
+        next += sectors;
+    }
+
+    // Leave room for the backup GPT at the end
+    let total_sectors = next + PARTITION_ALIGN;
+    let mut image = vec![0; (total_sectors * SECTOR_SIZE) as usize];
+
+    for (partition, contents) in partitions.iter().zip([&payload, &fit]) {
+        let start = (partition.first * SECTOR_SIZE) as usize;
+        image[start..][..contents.len()].copy_from_slice(contents);
+    }
+
+    gpt::write(&mut image, total_sectors, &partitions);
+
+    let path = out.join("sdcard.img");
+    fs::write(&path, image).with_context(|| format!("failed to write {}", path.display()))?;
+
+    Ok(())
+}
+
+struct Partition {
+    name: &'static str,
+    kind: &'static str,
+    first: u64,
+    /// Inclusive, like GPT stores it
+    last: u64,
+}
+
+fn round_up(n: u64, to: u64) -> u64 {
+    (n + to - 1) / to * to
+}
+
+mod gpt {
+    use super::{Partition, SECTOR_SIZE};
+
+    const ENTRIES: u64 = 128;
+    const ENTRY_SIZE: u64 = 128;
+    /// The header plus every entry
+    const TABLE_SECTORS: u64 = 1 + ENTRIES * ENTRY_SIZE / SECTOR_SIZE;
+
+    /// Write a protective MBR and the primary and backup GPTs describing
+    /// `partitions` to `image`
+    pub fn write(image: &mut [u8], total_sectors: u64, partitions: &[Partition]) {
+        let mbr = &mut image[446..512];
+        mbr[..16].copy_from_slice(&protective_partition(total_sectors));
+        mbr[64..].copy_from_slice(&[0x55, 0xAA]);
+
+        // Unique GUIDs are derived from the layout, so the same build always
+        // gives the same image
+        let disk_guid = derived_guid(b"vanadinite disk", total_sectors);
+        let mut entries = vec![0; (ENTRIES * ENTRY_SIZE) as usize];
+        for (entry, partition) in entries.chunks_mut(ENTRY_SIZE as usize).zip(partitions) {
+            entry[0..16].copy_from_slice(&guid(partition.kind));
+            entry[16..32].copy_from_slice(&derived_guid(partition.name.as_bytes(), partition.first));
+            entry[32..40].copy_from_slice(&partition.first.to_le_bytes());
+            entry[40..48].copy_from_slice(&partition.last.to_le_bytes());
+            for (i, c) in partition.name.encode_utf16().enumerate() {
+                entry[56 + i * 2..][..2].copy_from_slice(&c.to_le_bytes());
+            }
+        }
+
+        let entries_crc = crc32(&entries);
+        let last = total_sectors - 1;
+        let first_usable = 1 + TABLE_SECTORS;
+        let last_usable = last - TABLE_SECTORS;
+
+        let primary = header(1, last, 2, first_usable, last_usable, disk_guid, entries_crc);
+        let backup = header(last, 1, last - TABLE_SECTORS + 1, first_usable, last_usable, disk_guid, entries_crc);
+
+        let sector = |lba: u64| (lba * SECTOR_SIZE) as usize;
+        image[sector(1)..][..primary.len()].copy_from_slice(&primary);
+        image[sector(2)..][..entries.len()].copy_from_slice(&entries);
+        image[sector(last - TABLE_SECTORS + 1)..][..entries.len()].copy_from_slice(&entries);
+        image[sector(last)..][..backup.len()].copy_from_slice(&backup);
+    }
+
+    fn header(
+        current: u64,
+        backup: u64,
+        entries_lba: u64,
+        first_usable: u64,
+        last_usable: u64,
+        disk_guid: [u8; 16],
+        entries_crc: u32,
+    ) -> [u8; 92] {
+        let mut header = [0; 92];
+        header[0..8].copy_from_slice(b"EFI PART");
+        header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        header[24..32].copy_from_slice(&current.to_le_bytes());
+        header[32..40].copy_from_slice(&backup.to_le_bytes());
+        header[40..48].copy_from_slice(&first_usable.to_le_bytes());
+        header[48..56].copy_from_slice(&last_usable.to_le_bytes());
+        header[56..72].copy_from_slice(&disk_guid);
+        header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&(ENTRIES as u32).to_le_bytes());
+        header[84..88].copy_from_slice(&(ENTRY_SIZE as u32).to_le_bytes());
+        header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+
+        // The header's CRC is calculated with its own field zeroed
+        let crc = crc32(&header);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+
+        header
+    }
+
+    /// The single MBR partition covering the whole disk which keeps tools that
+    /// don't know about GPT from thinking it's empty
+    fn protective_partition(total_sectors: u64) -> [u8; 16] {
+        let mut entry = [0; 16];
+        entry[1..4].copy_from_slice(&[0x00, 0x02, 0x00]);
+        entry[4] = 0xEE;
+        entry[5..8].copy_from_slice(&[0xFF, 0xFF, 0xFF]);
+        entry[8..12].copy_from_slice(&1u32.to_le_bytes());
+        entry[12..16].copy_from_slice(&u32::try_from(total_sectors - 1).unwrap_or(u32::MAX).to_le_bytes());
+
+        entry
+    }
+
+    /// Parse a GUID written out the usual way into the mixed endian form GPT
+    /// stores it in
+    fn guid(s: &str) -> [u8; 16] {
+        let hex = s.replace('-', "");
+        let mut bytes = [0; 16];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..][..2], 16).expect("malformed GUID");
+        }
+
+        bytes[0..4].reverse();
+        bytes[4..6].reverse();
+        bytes[6..8].reverse();
+
+        bytes
+    }
+
+    /// A version 4 GUID made from `seed` and `salt` rather than randomness
+    fn derived_guid(seed: &[u8], salt: u64) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        for (i, chunk) in bytes.chunks_mut(4).enumerate() {
+            let mut input = seed.to_vec();
+            input.extend_from_slice(&salt.to_le_bytes());
+            input.push(i as u8);
+            chunk.copy_from_slice(&crc32(&input).to_le_bytes());
+        }
+
+        bytes[7] = (bytes[7] & 0x0F) | 0x40;
+        bytes[8] = (bytes[8] & 0x3F) | 0x80;
+
+        bytes
+    }
+
+    /// The CRC-32 used by GPT, which is the same one zlib and Ethernet use
+    fn crc32(data: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for &byte in data {
+            crc ^= u32::from(byte);
+            for _ in 0..8 {
+                crc = match crc & 1 {
+                    1 => (crc >> 1) ^ 0xEDB8_8320,
+                    _ => crc >> 1,
+                };
+            }
+        }
+
+        !crc
+    }
+}
@@ -6,12 +6,18 @@
 // obtain one at https://mozilla.org/MPL/2.0/.
 
 pub mod build;
+pub mod dist;
 pub mod runner;
+pub mod symbols;
 
 use build::{BuildTarget, Platform};
 use clap::{AppSettings, ArgEnum, Parser};
+use dist::DistOptions;
 use runner::RunOptions;
-use std::sync::{atomic::AtomicBool, Arc};
+use std::{
+    path::PathBuf,
+    sync::{atomic::AtomicBool, Arc},
+};
 use xshell::{pushd, rm_rf};
 
 pub type Result<T> = anyhow::Result<T>;
@@ -33,6 +39,8 @@ enum Arguments {
     Run(RunOptions),
     /// Test `vanadinite`
     Test(RunOptions),
+    /// Build everything and package it up as ready to boot images
+    Dist(DistOptions),
 }
 
 #[derive(ArgEnum, Clone, Copy)]
@@ -62,6 +70,16 @@ pub struct VanadiniteBuildOptions {
 
     #[clap(long)]
     debug_build: bool,
+
+    /// Replay the trace printed in a log of a run with the `debug.replay`
+    /// kernel feature, which is enabled by this
+    #[clap(long)]
+    replay: Option<PathBuf>,
+
+    /// Build the kernel with shadow call stacks, keeping return addresses
+    /// somewhere overwriting the regular stack can't reach
+    #[clap(long)]
+    shadow_call_stack: bool,
 }
 
 #[derive(ArgEnum, Clone, Copy)]
@@ -93,6 +111,7 @@ fn main() -> Result<()> {
         Arguments::Clean { target } => clean(target)?,
         Arguments::Run(target) => runner::run(target)?,
         Arguments::Test(target) => runner::test(target)?,
+        Arguments::Dist(options) => dist::dist(options)?,
     }
 
     Ok(())
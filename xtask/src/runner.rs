@@ -10,9 +10,12 @@ use crate::{
     Result, SbiImpl, Simulator, VanadiniteBuildOptions,
 };
 use clap::Parser;
-use std::path::PathBuf;
+use std::{io::Write, path::PathBuf};
 use xshell::cmd;
 
+/// Userspace test suites `xtask test` runs after the kernel's own tests
+const USERSPACE_TESTS: &[&str] = &["syscall-conformance"];
+
 #[derive(Parser)]
 pub struct RunOptions {
     /// Number of CPUs
@@ -40,6 +43,26 @@ pub struct RunOptions {
     #[clap(long)]
     no_build: bool,
 
+    /// Attach a multiport virtio-console with `console`, `log`, and `debug`
+    /// ports, listening on TCP ports starting at the given port
+    #[clap(long)]
+    virtio_console: Option<u16>,
+
+    /// Attach a virtio-vsock device with the given guest context ID, which
+    /// requires the host's `vhost_vsock` module
+    #[clap(long)]
+    vsock_cid: Option<u32>,
+
+    /// Attach a virtio-balloon device, which can be resized from the QEMU
+    /// monitor with the `balloon` command
+    #[clap(long)]
+    balloon: bool,
+
+    /// Attach a swap space of the given size in MiB as another virtio-blk
+    /// device, which is created in `build/` if it doesn't exist yet
+    #[clap(long)]
+    swap: Option<usize>,
+
     /// RAM size in MiB
     #[clap(long, default_value = "512")]
     ram: usize,
@@ -66,11 +89,17 @@ impl Default for RunOptions {
             kernel_args: String::new(),
             no_build: false,
             ram: 512,
+            virtio_console: None,
+            vsock_cid: None,
+            balloon: false,
+            swap: None,
             vanadinite_options: VanadiniteBuildOptions {
                 platform: Platform::Virt,
                 kernel_features: String::new(),
                 test: false,
                 debug_build: false,
+                replay: None,
+                shadow_call_stack: false,
             },
             with: Simulator::Qemu,
             sbi: SbiImpl::OpenSbi,
@@ -101,6 +130,55 @@ pub fn run(options: RunOptions) -> Result<()> {
         _ => vec![],
     };
 
+    let enable_virtio_console = match (options.vanadinite_options.platform, options.virtio_console) {
+        (Platform::Virt, Some(base_port)) => {
+            let mut args = vec![String::from("-device"), String::from("virtio-serial-device")];
+            for (i, (device, name)) in
+                [("virtconsole", "console"), ("virtserialport", "log"), ("virtserialport", "debug")].iter().enumerate()
+            {
+                let port = base_port + i as u16;
+                args.extend([
+                    String::from("-chardev"),
+                    format!("socket,id=vcon{i},host=127.0.0.1,port={port},server=on,wait=off"),
+                    String::from("-device"),
+                    format!("{device},chardev=vcon{i},name={name}"),
+                ]);
+            }
+
+            args
+        }
+        _ => vec![],
+    };
+
+    let enable_virtio_vsock = match (options.vanadinite_options.platform, options.vsock_cid) {
+        (Platform::Virt, Some(cid)) => vec![String::from("-device"), format!("vhost-vsock-device,guest-cid={cid}")],
+        _ => vec![],
+    };
+
+    let enable_virtio_balloon = match (options.vanadinite_options.platform, options.balloon) {
+        (Platform::Virt, true) => vec![String::from("-device"), String::from("virtio-balloon-device")],
+        _ => vec![],
+    };
+
+    // Always attached on `virt` so the kernel's RNG has a hardware source
+    let enable_virtio_rng = match options.vanadinite_options.platform {
+        Platform::Virt => vec![String::from("-device"), String::from("virtio-rng-device")],
+        _ => vec![],
+    };
+
+    let enable_swap = match (options.vanadinite_options.platform, options.swap) {
+        (Platform::Virt, Some(mib)) => {
+            let path = create_swap_image(mib)?;
+            vec![
+                String::from("-drive"),
+                format!("file={},if=none,format=raw,id=swap", path.display()),
+                String::from("-device"),
+                String::from("virtio-blk-device,drive=swap"),
+            ]
+        }
+        _ => vec![],
+    };
+
     let kernel_path = match options.vanadinite_options.debug_build {
         true => "src/kernel/target/riscv64gc-unknown-none-elf/debug/vanadinite",
         false => "src/kernel/target/riscv64gc-unknown-none-elf/release/vanadinite",
@@ -140,6 +218,11 @@ pub fn run(options: RunOptions) -> Result<()> {
                     -append {kernel_args}
                     -global virtio-mmio.force-legacy=false
                     {enable_virtio_block_device...}
+                    {enable_virtio_console...}
+                    {enable_virtio_vsock...}
+                    {enable_virtio_balloon...}
+                    {enable_virtio_rng...}
+                    {enable_swap...}
                     -netdev user,id=net1,hostfwd=udp:127.0.0.1:1111-10.0.2.15:1337
                     -device virtio-net-device,netdev=net1
                     -object filter-dump,id=f1,netdev=net1,file=testing_files/nettraffic.dat
@@ -170,6 +253,32 @@ pub fn run(options: RunOptions) -> Result<()> {
     Ok(())
 }
 
+/// Create a swap space of `mib` MiB, which is laid out as the swap server
+/// expects: a header in the first sector, with the page sized slots starting at
+/// the first page boundary after it
+fn create_swap_image(mib: usize) -> Result<PathBuf> {
+    const PAGE_SIZE: u64 = 4096;
+
+    let path = PathBuf::from(format!("build/swap-{mib}M.img"));
+    if path.exists() {
+        return Ok(path);
+    }
+
+    let n_slots = mib as u64 * 1024 * 1024 / PAGE_SIZE;
+    let mut header = Vec::with_capacity(24);
+    header.extend_from_slice(b"VSWAPSPC");
+    header.extend_from_slice(&1u32.to_le_bytes());
+    header.extend_from_slice(&(PAGE_SIZE as u32).to_le_bytes());
+    header.extend_from_slice(&n_slots.to_le_bytes());
+
+    std::fs::create_dir_all("build")?;
+    let mut file = std::fs::File::create(&path)?;
+    file.write_all(&header)?;
+    file.set_len(PAGE_SIZE * (n_slots + 1))?;
+
+    Ok(path)
+}
+
 pub fn test(mut options: RunOptions) -> Result<()> {
     options.vanadinite_options.test = true;
     options.vanadinite_options.platform = Platform::Virt;
@@ -177,6 +286,11 @@ pub fn test(mut options: RunOptions) -> Result<()> {
     let build_opts = &options.vanadinite_options;
     build::build(BuildTarget::Vanadinite(build_opts.clone()))?;
 
+    // init starts the userspace test suites on a regular kernel once
+    // everything else is up, and they end the run through QEMU's test finisher
+    // with how it went
+    let suite_args = format!("{} config.init.tests={}", options.kernel_args, USERSPACE_TESTS.join(","));
+
     let _dir = xshell::pushd("./src");
 
     let platform = options.vanadinite_options.platform.to_string();
@@ -209,5 +323,11 @@ pub fn test(mut options: RunOptions) -> Result<()> {
             {debug_log...}
     ").run()?;
 
-    Ok(())
+    drop(_dir);
+
+    run(RunOptions {
+        kernel_args: suite_args,
+        vanadinite_options: VanadiniteBuildOptions { test: false, ..options.vanadinite_options },
+        ..options
+    })
 }
@@ -0,0 +1,166 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The symbol table embedded into the kernel
+//!
+//! The table starts with [`MAGIC`], followed by an entry for each function
+//! sorted by address:
+//!
+//! * The distance from the previous function's address, as a LEB128 number
+//! * The size of the function, as a LEB128 number
+//! * How many bytes its name has in common with the start of the previous
+//!   function's name, as a single byte
+//! * How many bytes of the name follow, as a single byte, and then those bytes
+//!
+//! Functions next to each other usually live in the same module, so sharing
+//! the start of their names cuts the table down to a fraction of the size of
+//! the names on their own.
+
+use crate::Result;
+use anyhow::Context;
+use xshell::cmd;
+
+pub const MAGIC: &[u8] = b"VSYM";
+pub const MAX_NAME_LEN: usize = 255;
+
+/// Build the symbol table for the kernel ELF at `kernel`
+pub fn symbol_table(kernel: &str) -> Result<Vec<u8>> {
+    let nm = cmd!("riscv64-unknown-elf-nm --defined-only --print-size --numeric-sort {kernel}")
+        .read()
+        .context("failed to read kernel symbols")?;
+
+    let mut symbols = Vec::new();
+    for line in nm.lines() {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let (address, size, kind, name) = match fields[..] {
+            [address, size, kind, name] => (address, Some(size), kind, name),
+            [address, kind, name] => (address, None, kind, name),
+            _ => continue,
+        };
+
+        if !matches!(kind, "t" | "T" | "w" | "W") {
+            continue;
+        }
+
+        let address = u64::from_str_radix(address, 16).context("malformed symbol address")?;
+        let size = size.map(|size| u64::from_str_radix(size, 16)).transpose().context("malformed symbol size")?;
+        symbols.push((address, size, demangle(name)));
+    }
+
+    // Symbols defined in assembly don't have a size, so they're taken to run
+    // up to the next symbol
+    let ends = symbols.iter().skip(1).map(|(address, ..)| Some(*address)).chain([None]).collect::<Vec<_>>();
+    let symbols = symbols.into_iter().zip(ends).filter_map(|((address, size, name), end)| match size {
+        Some(size) => Some((address, size, name)),
+        None => Some((address, end? - address, name)),
+    });
+
+    Ok(encode(symbols))
+}
+
+fn encode(symbols: impl Iterator<Item = (u64, u64, String)>) -> Vec<u8> {
+    let mut table = MAGIC.to_vec();
+    let mut last: Option<(u64, Vec<u8>)> = None;
+
+    for (address, size, name) in symbols {
+        let (last_address, last_name) = match &last {
+            // Aliases of the same function share an address, only the first
+            // one is kept
+            Some((last_address, _)) if *last_address == address => continue,
+            Some((last_address, last_name)) => (*last_address, &last_name[..]),
+            None => (0, &[][..]),
+        };
+
+        let mut name = name.into_bytes();
+        name.truncate(MAX_NAME_LEN);
+        let shared = name.iter().zip(last_name).take_while(|(a, b)| a == b).count();
+
+        leb128(&mut table, address - last_address);
+        leb128(&mut table, size);
+        table.push(shared as u8);
+        table.push((name.len() - shared) as u8);
+        table.extend_from_slice(&name[shared..]);
+
+        last = Some((address, name));
+    }
+
+    table
+}
+
+fn leb128(out: &mut Vec<u8>, mut n: u64) {
+    loop {
+        let byte = (n & 0x7F) as u8;
+        n >>= 7;
+
+        match n {
+            0 => return out.push(byte),
+            _ => out.push(byte | 0x80),
+        }
+    }
+}
+
+/// Turn a legacy mangled Rust symbol like
+/// `_ZN10vanadinite4main17h0123456789abcdefE` into `vanadinite::main`, leaving
+/// anything which isn't one as it is
+fn demangle(symbol: &str) -> String {
+    let mut rest = match symbol.strip_prefix("_ZN") {
+        Some(rest) => rest,
+        None => return symbol.to_string(),
+    };
+
+    let mut parts = Vec::new();
+    while let Some(digits) = rest.find(|c: char| !c.is_ascii_digit()).filter(|&digits| digits > 0) {
+        let len = rest[..digits].parse::<usize>().unwrap();
+        match rest.get(digits..digits + len) {
+            Some(part) => parts.push(part),
+            None => return symbol.to_string(),
+        }
+
+        rest = &rest[digits + len..];
+    }
+
+    if rest != "E" || parts.is_empty() {
+        return symbol.to_string();
+    }
+
+    // The last part is a hash of the function's signature
+    if let Some(hash) = parts.last() {
+        if hash.len() == 17 && hash.starts_with('h') && hash[1..].chars().all(|c| c.is_ascii_hexdigit()) {
+            parts.pop();
+        }
+    }
+
+    parts.into_iter().map(unescape).collect::<Vec<_>>().join("::")
+}
+
+fn unescape(part: &str) -> String {
+    const ESCAPES: &[(&str, &str)] = &[
+        ("$SP$", "@"),
+        ("$BP$", "*"),
+        ("$RF$", "&"),
+        ("$LT$", "<"),
+        ("$GT$", ">"),
+        ("$LP$", "("),
+        ("$RP$", ")"),
+        ("$C$", ","),
+        ("$u20$", " "),
+        ("$u27$", "'"),
+        ("$u5b$", "["),
+        ("$u5d$", "]"),
+        ("$u7b$", "{"),
+        ("$u7d$", "}"),
+        ("$u7e$", "~"),
+        ("..", "::"),
+    ];
+
+    let mut part = part.strip_prefix("_$").map(|part| format!("${}", part)).unwrap_or_else(|| part.to_string());
+    for (escape, c) in ESCAPES {
+        part = part.replace(escape, c);
+    }
+
+    part
+}